@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate kytan;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = kytan::packet::destination(data);
+});
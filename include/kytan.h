@@ -0,0 +1,50 @@
+/*
+ * Copyright 2016-2017 Chang Lan
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* C interface to the kytan client. Build with `cargo build --features ffi`. */
+
+#ifndef KYTAN_H
+#define KYTAN_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define KYTAN_CONNECTING 0
+#define KYTAN_CONNECTED 1
+#define KYTAN_STOPPED 2
+#define KYTAN_FAILED (-1)
+
+typedef struct kytan_client kytan_client;
+
+/* Connects to host:port, with credential unless it is NULL, and tunnels the
+ * packets of the TUN device tun_fd on a new thread. Returns NULL on failure. */
+kytan_client *kytan_client_start(const char *host, uint16_t port, const char *credential,
+                                 int tun_fd);
+
+/* One of the KYTAN_* states. */
+int kytan_client_status(const kytan_client *client);
+
+/* Stops the client and frees it. Returns 0, or -1 if the client had failed. */
+int kytan_client_stop(kytan_client *client);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
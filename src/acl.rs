@@ -0,0 +1,259 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Filtering of packets that clients send into the tunnel.
+//!
+//! Rules are written as `ACTION PROTO[:PORT[-PORT]] [from ID]`, for example
+//! `deny tcp:25`, `allow udp:53`, `allow tcp:443 from 7` or `deny any`. The
+//! first matching rule decides; packets that match no rule are allowed.
+
+use std::fmt;
+use std::str::FromStr;
+use packet;
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    pub action: Action,
+    /// `None` matches every protocol.
+    pub protocol: Option<u8>,
+    /// Inclusive destination port range. `None` matches every packet.
+    pub ports: Option<(u16, u16)>,
+    /// `None` applies the rule to every client.
+    pub client: Option<u8>,
+}
+
+impl Rule {
+    /// Whether the rule covers a packet of `client` with this transport
+    /// protocol and destination port.
+    pub fn matches(&self, client: u8, protocol: u8, port: Option<u16>) -> bool {
+        if self.client.map_or(false, |id| id != client) {
+            return false;
+        }
+        if self.protocol.map_or(false, |p| p != protocol) {
+            return false;
+        }
+        match (self.ports, port) {
+            (None, _) => true,
+            (Some((low, high)), Some(port)) => low <= port && port <= high,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+fn parse_protocol(name: &str) -> Result<Option<u8>> {
+    match name {
+        "any" => Ok(None),
+        "tcp" => Ok(Some(packet::IPPROTO_TCP)),
+        "udp" => Ok(Some(packet::IPPROTO_UDP)),
+        "icmp" => Ok(Some(packet::IPPROTO_ICMP)),
+        "icmpv6" => Ok(Some(packet::IPPROTO_ICMPV6)),
+        _ => Err(Error::Config(format!("unknown protocol {:?}", name))),
+    }
+}
+
+fn parse_number<T: FromStr>(s: &str, rule: &str) -> Result<T> {
+    s.parse().map_err(|_| Error::Config(format!("invalid number {:?} in rule {:?}", s, rule)))
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rule> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let (action, target, client) = match words.len() {
+            2 => (words[0], words[1], None),
+            4 if words[2] == "from" => (words[0], words[1], Some(words[3])),
+            _ => return Err(Error::Config(format!("malformed rule {:?}", s))),
+        };
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            _ => return Err(Error::Config(format!("unknown action {:?}", action))),
+        };
+        let mut parts = target.splitn(2, ':');
+        let protocol = try!(parse_protocol(parts.next().unwrap()));
+        let ports = match parts.next() {
+            None => None,
+            Some(range) => {
+                if protocol != Some(packet::IPPROTO_TCP) && protocol != Some(packet::IPPROTO_UDP) {
+                    return Err(Error::Config(format!("ports need tcp or udp in rule {:?}", s)));
+                }
+                let mut bounds = range.splitn(2, '-');
+                let low = try!(parse_number(bounds.next().unwrap(), s));
+                let high = match bounds.next() {
+                    Some(high) => try!(parse_number(high, s)),
+                    None => low,
+                };
+                Some((low, high))
+            }
+        };
+        let client = match client {
+            Some(id) => Some(try!(parse_number(id, s))),
+            None => None,
+        };
+        Ok(Rule {
+            action: action,
+            protocol: protocol,
+            ports: ports,
+            client: client,
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        };
+        let protocol = match self.protocol {
+            None => "any",
+            Some(packet::IPPROTO_TCP) => "tcp",
+            Some(packet::IPPROTO_UDP) => "udp",
+            Some(packet::IPPROTO_ICMP) => "icmp",
+            Some(_) => "icmpv6",
+        };
+        try!(write!(f, "{} {}", action, protocol));
+        match self.ports {
+            Some((low, high)) if low == high => try!(write!(f, ":{}", low)),
+            Some((low, high)) => try!(write!(f, ":{}-{}", low, high)),
+            None => {}
+        }
+        match self.client {
+            Some(id) => write!(f, " from {}", id),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<Rule>) -> Acl {
+        Acl { rules: rules }
+    }
+
+    /// Parses one rule per string.
+    pub fn parse<S: AsRef<str>>(rules: &[S]) -> Result<Acl> {
+        let mut parsed = Vec::with_capacity(rules.len());
+        for rule in rules {
+            parsed.push(try!(rule.as_ref().parse()));
+        }
+        Ok(Acl::new(parsed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Inserts `rule` before the rule at `index`, or appends it.
+    pub fn insert(&mut self, index: Option<usize>, rule: Rule) {
+        match index {
+            Some(index) if index < self.rules.len() => self.rules.insert(index, rule),
+            _ => self.rules.push(rule),
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Rule> {
+        if index < self.rules.len() {
+            Some(self.rules.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Decides what to do with a packet sent by `client`. Packets that cannot
+    /// be parsed are only allowed when no rule exists.
+    pub fn check(&self, client: u8, data: &[u8]) -> Action {
+        if self.rules.is_empty() {
+            return Action::Allow;
+        }
+        let (protocol, port) = match packet::transport(data) {
+            Ok(t) => t,
+            Err(_) => return Action::Deny,
+        };
+        self.decide(client, protocol, port)
+    }
+
+    /// Decides on traffic of `client` to a port without a packet at hand.
+    pub fn decide(&self, client: u8, protocol: u8, port: Option<u16>) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(client, protocol, port))
+            .map_or(Action::Allow, |rule| rule.action)
+    }
+}
+
+#[test]
+fn acl_test() {
+    let acl = Acl::parse(&["deny tcp:25", "allow tcp:443 from 7", "allow udp:53", "deny tcp"])
+        .unwrap();
+    let mut tcp = [0u8; 40];
+    tcp[0] = 0x45;
+    tcp[9] = packet::IPPROTO_TCP;
+    tcp[22..24].clone_from_slice(&[0, 25]);
+    assert_eq!(acl.check(7, &tcp), Action::Deny);
+    tcp[22..24].clone_from_slice(&[1, 187]);
+    assert_eq!(acl.check(7, &tcp), Action::Allow);
+    assert_eq!(acl.check(8, &tcp), Action::Deny);
+
+    let mut icmp = [0u8; 28];
+    icmp[0] = 0x45;
+    icmp[9] = packet::IPPROTO_ICMP;
+    assert_eq!(acl.check(8, &icmp), Action::Allow);
+    assert_eq!(Acl::default().check(8, &[]), Action::Allow);
+    assert_eq!(acl.check(8, &[]), Action::Deny);
+}
+
+#[test]
+fn rule_parse_test() {
+    assert_eq!("allow tcp:8000-8080 from 3".parse::<Rule>().unwrap(),
+               Rule {
+                   action: Action::Allow,
+                   protocol: Some(packet::IPPROTO_TCP),
+                   ports: Some((8000, 8080)),
+                   client: Some(3),
+               });
+    assert!("deny icmp:1".parse::<Rule>().is_err());
+    assert!("drop tcp".parse::<Rule>().is_err());
+    assert!("deny tcp from".parse::<Rule>().is_err());
+    for rule in &["allow tcp:8000-8080 from 3", "deny udp:53", "allow any"] {
+        assert_eq!(rule.parse::<Rule>().unwrap().to_string(), *rule);
+    }
+}
+
+#[test]
+fn edit_test() {
+    let mut acl = Acl::default();
+    acl.insert(None, "deny tcp".parse().unwrap());
+    acl.insert(Some(0), "allow tcp:443".parse().unwrap());
+    assert_eq!(acl.rules()[0].to_string(), "allow tcp:443");
+    assert_eq!(acl.remove(1).unwrap().to_string(), "deny tcp");
+    assert!(acl.remove(1).is_none());
+    assert_eq!(acl.rules().len(), 1);
+}
@@ -0,0 +1,121 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Turning compression off while the CPU is the bottleneck.
+//!
+//! Every second the governor compares the CPU time the event loop used with
+//! the time that passed. When the loop was nearly always busy while moving a
+//! lot of traffic, compressing is what holds throughput back, so frames go
+//! out uncompressed until the load drops again. Peers that cannot take raw
+//! frames keep getting compressed ones.
+
+use std::mem;
+use std::time::{Duration, Instant};
+use libc;
+
+const INTERVAL: u64 = 1;
+/// Share of the interval the loop must be busy to stop compressing.
+const BUSY: f64 = 0.9;
+/// Share below which compression resumes.
+const IDLE: f64 = 0.6;
+/// Below this many bytes per second, compression is not what keeps the CPU
+/// busy.
+const MIN_RATE: u64 = 12500000;
+
+/// CPU time used by the calling thread, or the whole process where threads
+/// are not accounted separately.
+pub fn cpu_time() -> Duration {
+    #[cfg(target_os = "linux")]
+    const WHO: libc::c_int = libc::RUSAGE_THREAD;
+    #[cfg(not(target_os = "linux"))]
+    const WHO: libc::c_int = libc::RUSAGE_SELF;
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    unsafe { libc::getrusage(WHO, &mut usage) };
+    let micros = |tv: libc::timeval| tv.tv_sec as u64 * 1000000 + tv.tv_usec as u64;
+    let total = micros(usage.ru_utime) + micros(usage.ru_stime);
+    Duration::new(total / 1000000, (total % 1000000) as u32 * 1000)
+}
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+pub struct Governor {
+    compress: bool,
+    since: Instant,
+    cpu: Duration,
+    bytes: u64,
+}
+
+impl Governor {
+    pub fn new(now: Instant, cpu: Duration, bytes: u64) -> Governor {
+        Governor {
+            compress: true,
+            since: now,
+            cpu: cpu,
+            bytes: bytes,
+        }
+    }
+
+    /// Whether frames should be compressed at the moment.
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    /// Takes a measurement once per interval, given the CPU time used and
+    /// the bytes sent so far. Returns the new setting when it changes.
+    pub fn update(&mut self, now: Instant, cpu: Duration, bytes: u64) -> Option<bool> {
+        let elapsed = now.duration_since(self.since);
+        if elapsed < Duration::from_secs(INTERVAL) {
+            return None;
+        }
+        let load = seconds(cpu - self.cpu) / seconds(elapsed);
+        let rate = ((bytes - self.bytes) as f64 / seconds(elapsed)) as u64;
+        self.since = now;
+        self.cpu = cpu;
+        self.bytes = bytes;
+        let compress = if self.compress {
+            !(load >= BUSY && rate >= MIN_RATE)
+        } else {
+            load < IDLE
+        };
+        if compress == self.compress {
+            return None;
+        }
+        self.compress = compress;
+        Some(compress)
+    }
+}
+
+#[test]
+fn governor_test() {
+    let start = Instant::now();
+    let second = |n| start + Duration::from_secs(n);
+    let mut governor = Governor::new(start, Duration::from_secs(0), 0);
+    assert_eq!(governor.update(start + Duration::from_millis(500),
+                               Duration::from_millis(500),
+                               50000000),
+               None);
+    // Busy but with little traffic: something else is to blame.
+    assert_eq!(governor.update(second(1), Duration::from_millis(950), 1000), None);
+    // Busy moving 50 MB/s.
+    assert_eq!(governor.update(second(2), Duration::from_millis(1900), 50001000),
+               Some(false));
+    assert!(!governor.compress());
+    // Still fairly busy: hysteresis keeps compression off.
+    assert_eq!(governor.update(second(3), Duration::from_millis(2600), 90001000), None);
+    assert_eq!(governor.update(second(4), Duration::from_millis(3000), 99001000),
+               Some(true));
+    assert!(governor.compress());
+}
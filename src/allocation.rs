@@ -0,0 +1,166 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How a new client's id, and so its address in the subnet, is chosen.
+//!
+//! The server settles reserved devices and resumed sessions itself and only
+//! asks its `Allocator` when a client needs a fresh id. The built-in
+//! strategies hand out the lowest free id, a random one, or one derived
+//! from the client's identity so that a device tends to keep its address
+//! without a reservation. An external IPAM plugs in by implementing
+//! `Allocator`: it may pick the id itself and is told about every lease.
+
+use std::collections::BTreeSet;
+use rand;
+use identity;
+use network::Id;
+use error::{Error, Result};
+
+/// The network address, the server's own and the broadcast address are
+/// never handed out.
+const FIRST_ID: Id = 2;
+const LAST_ID: Id = 253;
+
+pub trait Allocator: Send {
+    /// Picks one of `free`, which is sorted and never empty, for a new
+    /// client. `key` is the identity the client proved, if any. `None`
+    /// turns the client away.
+    fn pick(&mut self, free: &[Id], key: Option<&identity::Key>) -> Option<Id>;
+
+    /// Called whenever `id` goes to a client, including one resuming its
+    /// session, so the same id may be assigned again without a release.
+    fn assigned(&mut self, _id: Id, _key: Option<&identity::Key>) {}
+
+    /// Called when `id` returns to the pool.
+    fn released(&mut self, _id: Id) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// The lowest free id.
+    Sequential,
+    /// Any free id, so addresses say nothing about the order of arrival.
+    Random,
+    /// The first free id at or after one derived from the client's
+    /// identity. Clients without one get the lowest free id.
+    Hashed,
+}
+
+impl Strategy {
+    pub fn parse(s: &str) -> Result<Strategy> {
+        match s {
+            "sequential" => Ok(Strategy::Sequential),
+            "random" => Ok(Strategy::Random),
+            "hash" => Ok(Strategy::Hashed),
+            _ => Err(Error::Config(format!("unknown allocation strategy {:?}", s))),
+        }
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::Sequential
+    }
+}
+
+impl Allocator for Strategy {
+    fn pick(&mut self, free: &[Id], key: Option<&identity::Key>) -> Option<Id> {
+        match (*self, key) {
+            (Strategy::Random, _) => Some(free[rand::random::<usize>() % free.len()]),
+            (Strategy::Hashed, Some(key)) => {
+                let span = (LAST_ID - FIRST_ID) as u16 + 1;
+                let start = FIRST_ID + (((key[0] as u16) << 8 | key[1] as u16) % span) as Id;
+                free.iter().find(|&&id| id >= start).or(free.first()).cloned()
+            }
+            _ => free.first().cloned(),
+        }
+    }
+}
+
+/// The free ids and the allocator that chooses among them.
+pub struct Pool {
+    free: BTreeSet<Id>,
+    allocator: Box<Allocator>,
+}
+
+impl Pool {
+    pub fn new(allocator: Box<Allocator>) -> Pool {
+        Pool {
+            free: (FIRST_ID..LAST_ID + 1).collect(),
+            allocator: allocator,
+        }
+    }
+
+    /// Hands out a free id that `usable` accepts, as the allocator sees fit.
+    pub fn next<F>(&mut self, key: Option<&identity::Key>, usable: F) -> Option<Id>
+        where F: Fn(Id) -> bool
+    {
+        let free: Vec<Id> = self.free.iter().cloned().filter(|&id| usable(id)).collect();
+        if free.is_empty() {
+            return None;
+        }
+        let id = match self.allocator.pick(&free, key) {
+            Some(id) => id,
+            None => return None,
+        };
+        if !free.contains(&id) {
+            warn!("Allocator picked id {}, which is not free.", id);
+            return None;
+        }
+        self.free.remove(&id);
+        Some(id)
+    }
+
+    /// Removes `id` from the pool, if it is there, for a client that claims it.
+    pub fn take(&mut self, id: Id) {
+        self.free.remove(&id);
+    }
+
+    /// Returns `id` to the pool.
+    pub fn put(&mut self, id: Id) {
+        if self.free.insert(id) {
+            self.allocator.released(id);
+        }
+    }
+
+    /// Tells the allocator that `id` went to a client.
+    pub fn assigned(&mut self, id: Id, key: Option<&identity::Key>) {
+        self.allocator.assigned(id, key);
+    }
+}
+
+#[test]
+fn strategy_test() {
+    let free = [2, 7, 200];
+    assert_eq!(Strategy::Sequential.pick(&free, None), Some(2));
+    assert!(free.contains(&Strategy::Random.pick(&free, None).unwrap()));
+    let mut key = [0; 32];
+    assert_eq!(Strategy::Hashed.pick(&free, None), Some(2));
+    key[1] = 5;
+    assert_eq!(Strategy::Hashed.pick(&free, Some(&key)), Some(7));
+    key[1] = 250;
+    assert_eq!(Strategy::Hashed.pick(&free, Some(&key)), Some(2));
+    assert!(Strategy::parse("hash").is_ok() && Strategy::parse("lowest").is_err());
+}
+
+#[test]
+fn pool_test() {
+    let mut pool = Pool::new(Box::new(Strategy::Sequential));
+    pool.take(2);
+    assert_eq!(pool.next(None, |id| id != 3), Some(4));
+    assert_eq!(pool.next(None, |_| true), Some(3));
+    pool.put(2);
+    assert_eq!(pool.next(None, |_| true), Some(2));
+    assert_eq!(pool.next(None, |id| id > 253), None);
+}
@@ -0,0 +1,44 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authentication of individual users against an external directory.
+//!
+//! Without an authenticator, a client's credential only selects its
+//! isolation group. With one, a credential of the form `USER:PASSWORD` that
+//! matches no group is checked with the authenticator instead, and clients
+//! without a credential are turned away.
+
+use error::Result;
+
+pub trait Authenticator: Send {
+    /// Whether `user` may connect with `password`. Errors count as a
+    /// rejection.
+    fn authenticate(&mut self, user: &str, password: &str) -> Result<bool>;
+}
+
+/// Splits a `USER:PASSWORD` credential.
+pub fn split(credential: &str) -> Option<(&str, &str)> {
+    match credential.find(':') {
+        Some(i) if i > 0 => Some((&credential[..i], &credential[i + 1..])),
+        _ => None,
+    }
+}
+
+#[test]
+fn split_test() {
+    assert_eq!(split("alice:pa:ss"), Some(("alice", "pa:ss")));
+    assert_eq!(split("alice:"), Some(("alice", "")));
+    assert_eq!(split(":secret"), None);
+    assert_eq!(split("secret"), None);
+}
@@ -0,0 +1,107 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coalescing of small packets into one datagram.
+//!
+//! Packets shorter than `SMALL` that the server sends to a client wait up to
+//! a short window for company and then travel together in a
+//! `Message::Batch`. ACKs, DNS answers and voice frames then cost one outer
+//! datagram per window instead of one each.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use network::Id;
+
+/// Longer packets are sent on their own.
+pub const SMALL: usize = 256;
+/// Bytes each packet adds to a batch: its length as a `u64`.
+const FRAMING: usize = 8;
+
+struct Pending {
+    packets: Vec<Vec<u8>>,
+    size: usize,
+    since: Instant,
+}
+
+pub struct Coalescer {
+    window: Duration,
+    pending: HashMap<Id, Pending>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Coalescer {
+        Coalescer {
+            window: window,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Queues `packet` for client `id`, whose batches may not exceed `limit`
+    /// bytes. Returns the queued packets if they have to go first to make
+    /// room.
+    pub fn push(&mut self, id: Id, packet: &[u8], limit: usize, now: Instant)
+                -> Option<Vec<Vec<u8>>> {
+        let size = packet.len() + FRAMING;
+        let full = self.pending.get(&id).map_or(false, |pending| pending.size + size > limit);
+        let flushed = if full { self.take(id) } else { None };
+        let pending = self.pending.entry(id).or_insert_with(|| {
+            Pending {
+                packets: Vec::new(),
+                size: 0,
+                since: now,
+            }
+        });
+        pending.packets.push(packet.to_vec());
+        pending.size += size;
+        flushed
+    }
+
+    /// Takes what is queued for `id`, e.g. so a large packet does not
+    /// overtake it.
+    pub fn take(&mut self, id: Id) -> Option<Vec<Vec<u8>>> {
+        self.pending.remove(&id).map(|pending| pending.packets)
+    }
+
+    /// Takes every batch whose window has passed.
+    pub fn due(&mut self, now: Instant) -> Vec<(Id, Vec<Vec<u8>>)> {
+        let window = self.window;
+        let ids: Vec<Id> = self.pending
+            .iter()
+            .filter(|&(_, pending)| now >= pending.since + window)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.into_iter().filter_map(|id| self.take(id).map(|packets| (id, packets))).collect()
+    }
+
+    /// When the oldest batch is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.since + self.window).min()
+    }
+}
+
+#[test]
+fn coalescer_test() {
+    let start = Instant::now();
+    let mut coalescer = Coalescer::new(Duration::from_millis(2));
+    assert!(coalescer.deadline().is_none());
+    assert!(coalescer.push(2, &[1; 40], 100, start).is_none());
+    assert!(coalescer.push(2, &[2; 40], 100, start).is_none());
+    assert_eq!(coalescer.push(2, &[3; 40], 100, start).unwrap().len(), 2);
+    assert!(coalescer.push(3, &[4; 10], 100, start + Duration::from_millis(1)).is_none());
+    assert_eq!(coalescer.deadline(), Some(start + Duration::from_millis(2)));
+    let due = coalescer.due(start + Duration::from_millis(2));
+    assert_eq!(due, vec![(2, vec![vec![3; 40]])]);
+    assert_eq!(coalescer.take(3), Some(vec![vec![4; 10]]));
+    assert!(coalescer.due(start + Duration::from_secs(1)).is_empty());
+}
@@ -0,0 +1,134 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Address ranges such as `10.0.0.0/8` or `fd00::/8`, and the source
+//! filter the server applies before looking at a datagram.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+/// Treats IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, as IPv4.
+fn unmap(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        let s = v6.segments();
+        if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+            return IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8,
+                                            s[6] as u8,
+                                            (s[7] >> 8) as u8,
+                                            s[7] as u8));
+        }
+    }
+    addr
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (net, addr): (Vec<u8>, Vec<u8>) = match (self.addr, unmap(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => (net.octets().to_vec(), addr.octets().to_vec()),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => (net.octets().to_vec(), addr.octets().to_vec()),
+            _ => return false,
+        };
+        let prefix = self.prefix as usize;
+        let whole = prefix / 8;
+        if net[..whole] != addr[..whole] {
+            return false;
+        }
+        let rest = prefix % 8;
+        rest == 0 || (net[whole] ^ addr[whole]) & !(0xffu8 >> rest) == 0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let invalid = || Error::Config(format!("expected ADDR/PREFIX, got {:?}", s));
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = try!(parts.next().unwrap().parse().map_err(|_| invalid()));
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => try!(prefix.parse().map_err(|_| invalid())),
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Sources in a `deny` range are refused; when `allow` is not empty, so are
+/// sources outside all of its ranges.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn admits(&self, addr: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(addr)) &&
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr)))
+    }
+
+    /// Removes `range` from both lists. Returns whether it was in either.
+    pub fn remove(&mut self, range: &Cidr) -> bool {
+        let before = self.allow.len() + self.deny.len();
+        self.allow.retain(|cidr| cidr != range);
+        self.deny.retain(|cidr| cidr != range);
+        self.allow.len() + self.deny.len() != before
+    }
+}
+
+#[test]
+fn filter_test() {
+    let mut filter = Filter::default();
+    assert!(filter.admits("203.0.113.9".parse().unwrap()));
+    filter.allow.push("10.0.0.0/8".parse().unwrap());
+    filter.allow.push("fd00::/8".parse().unwrap());
+    filter.deny.push("10.66.0.0/17".parse().unwrap());
+    assert!(filter.admits("10.1.2.3".parse().unwrap()));
+    assert!(filter.admits("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(filter.admits("fd12::1".parse().unwrap()));
+    assert!(!filter.admits("10.66.127.1".parse().unwrap()));
+    assert!(filter.admits("10.66.128.1".parse().unwrap()));
+    assert!(!filter.admits("203.0.113.9".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("192.0.2.7".parse::<Cidr>().unwrap().contains("192.0.2.7".parse().unwrap()));
+    assert_eq!("fd00::/8".parse::<Cidr>().unwrap().to_string(), "fd00::/8");
+    assert!(filter.remove(&"10.66.0.0/17".parse().unwrap()));
+    assert!(!filter.remove(&"10.66.0.0/17".parse().unwrap()));
+    assert!(filter.admits("10.66.127.1".parse().unwrap()));
+}
@@ -0,0 +1,2487 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{Write, Read};
+use std::time::{Duration, Instant, SystemTime};
+use mio;
+use rand;
+use relay;
+use device;
+use push;
+use replay;
+use resolver;
+use identity;
+use keepalive;
+use instance;
+use utils;
+use packet;
+use pcap;
+use netwatch;
+use roaming;
+use telemetry;
+use stats;
+#[cfg(feature = "metrics")]
+use health;
+use netem;
+#[cfg(feature = "obfuscation")]
+use cover;
+#[cfg(feature = "obfuscation")]
+use obfs;
+#[cfg(feature = "admin")]
+use control;
+use proxy;
+use stream;
+use transcript;
+use clock::{Clock, SleepDetector, SystemClock};
+use signal;
+#[cfg(feature = "admin")]
+use verbosity;
+use network::*;
+use error::{Code, Error, Result};
+
+/// Requests sent to a server before failing over to the next by default.
+const HANDSHAKE_ATTEMPTS: u32 = 5;
+/// Seconds of silence from the server before probing it.
+const SERVER_IDLE_TIMEOUT: u64 = 30;
+const PROBE_ATTEMPTS: u32 = 3;
+const PROBE_INTERVAL: u64 = 5;
+/// How often to measure the round trip to the server by default.
+const RTT_INTERVAL: u64 = 30;
+/// Suspends shorter than this are ridden out; the server would not have
+/// expired the session yet.
+const SLEEP_THRESHOLD: u64 = 30;
+/// How often to look the server's name up again, to follow dynamic DNS.
+const DNS_REFRESH: u64 = 300;
+/// Seconds between attempts to repair a routing loop.
+const LOOP_HOLDOFF: u64 = 1;
+/// Repairs within `LOOP_WINDOW` seconds before the client gives up.
+const LOOP_REPAIRS: u32 = 3;
+const LOOP_WINDOW: u64 = 60;
+#[cfg(feature = "admin")]
+const CONTROL: mio::Token = mio::Token(5);
+/// Tokens of the SOCKS5 proxy start here.
+const PROXY_BASE: usize = 8;
+
+pub struct ClientBuilder {
+    host: Option<String>,
+    port: u16,
+    servers: Vec<(String, u16)>,
+    select_by_latency: bool,
+    relays: Vec<(String, u16)>,
+    default_route: bool,
+    timeout: Duration,
+    handshake_attempts: u32,
+    connect_deadline: Option<Duration>,
+    rtt_interval: Duration,
+    keepalive: keepalive::Config,
+    compression: bool,
+    compression_threshold: usize,
+    clamp_mss: bool,
+    verify_checksums: bool,
+    drop_corrupt: bool,
+    propagate_dscp: bool,
+    outer: OuterOptions,
+    bind_address: Option<IpAddr>,
+    family: resolver::Family,
+    dns_cache: Duration,
+    credential: Option<String>,
+    stamp_credential: bool,
+    identity: Option<PathBuf>,
+    tap: bool,
+    tun_fd: Option<RawFd>,
+    follow_network: bool,
+    tracer: Option<telemetry::Tracer>,
+    #[cfg(feature = "metrics")]
+    statsd: Option<stats::StatsdConfig>,
+    #[cfg(feature = "metrics")]
+    health: Option<SocketAddr>,
+    netem: Option<netem::Config>,
+    #[cfg(feature = "obfuscation")]
+    cover: Option<cover::Config>,
+    #[cfg(feature = "obfuscation")]
+    obfs: Option<obfs::Config>,
+    rebind: Option<Duration>,
+    #[cfg(feature = "admin")]
+    control: Option<PathBuf>,
+    socks: Option<SocketAddr>,
+    forwards: Vec<(SocketAddr, String)>,
+    clock: Box<Clock>,
+    callback: Option<Callback>,
+    pcap: Option<pcap::Config>,
+    session_file: Option<PathBuf>,
+    session_port: Option<u16>,
+    route_mtu: bool,
+    lock: Option<PathBuf>,
+    transcript: Option<PathBuf>,
+}
+
+impl ClientBuilder {
+    /// Remote server to connect to. Required.
+    pub fn host(mut self, host: &str) -> ClientBuilder {
+        self.host = Some(String::from(host));
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> ClientBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Another server to fail over to when the current one stops
+    /// responding. Servers are tried in the order given, after `host`.
+    pub fn server(mut self, host: &str, port: u16) -> ClientBuilder {
+        self.servers.push((String::from(host), port));
+        self
+    }
+
+    /// With several servers, probe them all before each handshake and pick
+    /// the first to answer instead of going in order. Routes are restored to
+    /// the host's while probing.
+    pub fn select_by_latency(mut self, select_by_latency: bool) -> ClientBuilder {
+        self.select_by_latency = select_by_latency;
+        self
+    }
+
+    /// Reach the server through the relay at `host:port`, so that the server
+    /// never sees this client's address. Relays added later are further
+    /// along the path. See the `relay` module.
+    pub fn via(mut self, host: &str, port: u16) -> ClientBuilder {
+        self.relays.push((String::from(host), port));
+        self
+    }
+
+    /// Route all traffic through the tunnel.
+    pub fn default_route(mut self, default_route: bool) -> ClientBuilder {
+        self.default_route = default_route;
+        self
+    }
+
+    /// How long to wait for a `Response` before retransmitting the `Request`.
+    pub fn timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many `Request`s a server gets before failing over to the next,
+    /// and, once every server has had its turn, giving up. With 0, servers
+    /// get 5 each and the client keeps trying until `connect_deadline`.
+    pub fn handshake_attempts(mut self, attempts: u32) -> ClientBuilder {
+        self.handshake_attempts = attempts;
+        self
+    }
+
+    /// Give up if a handshake does not complete within `deadline`, however
+    /// many attempts are left. Applies to every (re)connect.
+    pub fn connect_deadline(mut self, deadline: Duration) -> ClientBuilder {
+        self.connect_deadline = Some(deadline);
+        self
+    }
+
+    /// Send handshakes, liveness probes and roaming to this port of the
+    /// servers rather than along with data, for servers listening there.
+    /// Not through relays.
+    pub fn session_port(mut self, port: u16) -> ClientBuilder {
+        self.session_port = Some(port);
+        self
+    }
+
+    /// Measure the round trip to the server every `interval` rather than
+    /// every 30 seconds, for finer latency histograms.
+    pub fn rtt_interval(mut self, interval: Duration) -> ClientBuilder {
+        self.rtt_interval = interval;
+        self
+    }
+
+    /// Ping the server often enough for the NAT in front of the client. See
+    /// the `keepalive` module.
+    pub fn keepalive(mut self, config: keepalive::Config) -> ClientBuilder {
+        self.keepalive = config;
+        self
+    }
+
+    /// Offer snappy compression to the server. Enabled by default.
+    pub fn compression(mut self, compression: bool) -> ClientBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Send packets shorter than `bytes` uncompressed, where snappy would
+    /// only make them longer. Needs a server that accepts raw frames.
+    pub fn compression_threshold(mut self, bytes: usize) -> ClientBuilder {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// Rewrite the MSS option of TCP SYNs crossing the tunnel so that TCP
+    /// segments fit into the tunnel MTU.
+    pub fn clamp_mss(mut self, clamp_mss: bool) -> ClientBuilder {
+        self.clamp_mss = clamp_mss;
+        self
+    }
+
+    /// Verify the IPv4, TCP and UDP checksums of packets coming out of the
+    /// tunnel and count those that are wrong, to catch bugs in compression
+    /// or transport. Costs a pass over every packet.
+    pub fn verify_checksums(mut self, verify: bool) -> ClientBuilder {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Drop packets with wrong checksums instead of delivering them. Implies
+    /// `verify_checksums`.
+    pub fn drop_corrupt(mut self, drop: bool) -> ClientBuilder {
+        self.drop_corrupt = drop;
+        self
+    }
+
+    /// Set the tunnel MTU, and the TCP MSS that goes with it, on the routes
+    /// into the tunnel, so that local applications send segments that fit
+    /// from the start rather than after path MTU discovery. Linux and macOS.
+    pub fn route_mtu(mut self, route_mtu: bool) -> ClientBuilder {
+        self.route_mtu = route_mtu;
+        self
+    }
+
+    /// Copy the DSCP of tunneled packets onto the outer UDP packets so that
+    /// upstream QoS still applies.
+    pub fn propagate_dscp(mut self, propagate_dscp: bool) -> ClientBuilder {
+        self.propagate_dscp = propagate_dscp;
+        self
+    }
+
+    /// TTL of the outer UDP packets. Defaults to the system setting.
+    pub fn outer_ttl(mut self, ttl: u8) -> ClientBuilder {
+        self.outer.ttl = Some(ttl);
+        self
+    }
+
+    /// Set or clear the DF bit on the outer UDP packets. Defaults to the
+    /// system setting.
+    pub fn dont_fragment(mut self, df: bool) -> ClientBuilder {
+        self.outer.dont_fragment = Some(df);
+        self
+    }
+
+    /// Set this fwmark (SO_MARK) on the outer UDP packets, so that policy
+    /// routing can keep them off the tunnel. Linux only.
+    pub fn fwmark(mut self, mark: u32) -> ClientBuilder {
+        self.outer.fwmark = Some(mark);
+        self
+    }
+
+    /// Use a TAP device, which carries Ethernet frames, instead of TUN.
+    /// Linux only.
+    pub fn tap(mut self, tap: bool) -> ClientBuilder {
+        self.tap = tap;
+        self
+    }
+
+    /// Use the already open TUN device `fd` instead of creating one. Its
+    /// owner configures it.
+    pub fn tun_fd(mut self, fd: RawFd) -> ClientBuilder {
+        self.tun_fd = Some(fd);
+        self
+    }
+
+    /// Send tunnel traffic only through this network interface.
+    pub fn bind_interface(mut self, interface: &str) -> ClientBuilder {
+        self.outer.interface = Some(String::from(interface));
+        self
+    }
+
+    /// Local address the outer UDP socket binds to. Defaults to any address.
+    pub fn bind_address(mut self, addr: IpAddr) -> ClientBuilder {
+        self.bind_address = Some(addr);
+        self
+    }
+
+    /// Which of a server's addresses to use when its name has both IPv4 and
+    /// IPv6 ones. Defaults to the first the system lists.
+    pub fn address_family(mut self, family: resolver::Family) -> ClientBuilder {
+        self.family = family;
+        self
+    }
+
+    /// Remember server addresses for `ttl` rather than a minute, so that
+    /// reconnects need no lookups. Zero looks names up every time.
+    pub fn dns_cache(mut self, ttl: Duration) -> ClientBuilder {
+        self.dns_cache = ttl;
+        self
+    }
+
+    /// Secret presented to the server, which uses it to place the client in
+    /// an isolation group.
+    pub fn credential(mut self, credential: &str) -> ClientBuilder {
+        self.credential = Some(String::from(credential));
+        self
+    }
+
+    /// Prove the credential with a timestamped HMAC instead of sending it,
+    /// so captured handshakes cannot be replayed. Only works for group
+    /// secrets, and only with servers that support it.
+    pub fn stamp_credential(mut self, stamp: bool) -> ClientBuilder {
+        self.stamp_credential = stamp;
+        self
+    }
+
+    /// Prove the identity key kept at `path` in handshakes, creating it on
+    /// first use, so the server recognises this device across sessions.
+    pub fn identity<P: AsRef<Path>>(mut self, path: P) -> ClientBuilder {
+        self.identity = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Watch for the host moving to another network, and then re-resolve the
+    /// server, rebind the socket and reinstall routes. Enabled by default.
+    pub fn follow_network(mut self, follow_network: bool) -> ClientBuilder {
+        self.follow_network = follow_network;
+        self
+    }
+
+    /// Trace handshakes and sessions.
+    pub fn tracer(mut self, tracer: telemetry::Tracer) -> ClientBuilder {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Send traffic counters to a statsd server.
+    #[cfg(feature = "metrics")]
+    pub fn statsd(mut self, config: stats::StatsdConfig) -> ClientBuilder {
+        self.statsd = Some(config);
+        self
+    }
+
+    /// Answer HTTP health checks on `addr`, usually a localhost port.
+    #[cfg(feature = "metrics")]
+    pub fn health_check(mut self, addr: SocketAddr) -> ClientBuilder {
+        self.health = Some(addr);
+        self
+    }
+
+    /// Impair outgoing datagrams to emulate a poor network. For testing.
+    pub fn netem(mut self, config: netem::Config) -> ClientBuilder {
+        self.netem = Some(config);
+        self
+    }
+
+    /// Keep a steady floor of traffic to and from the server, sending a
+    /// frame of `size` bytes every `interval` when there is no real traffic.
+    /// Costs bandwidth; only for threat models that include traffic analysis.
+    #[cfg(feature = "obfuscation")]
+    pub fn cover_traffic(mut self, interval: Duration, size: usize) -> ClientBuilder {
+        self.cover = Some(cover::Config {
+            interval: interval,
+            size: size,
+        });
+        self
+    }
+
+    /// Move to a fresh local UDP port every `interval`, telling the server
+    /// with a `Roam`, so that middleboxes do not see one long-lived flow.
+    pub fn rebind_interval(mut self, interval: Duration) -> ClientBuilder {
+        self.rebind = Some(interval);
+        self
+    }
+
+    /// Blur the size and timing of handshake frames, and send `decoys` in
+    /// `config` ahead of each request.
+    #[cfg(feature = "obfuscation")]
+    pub fn obfuscate_handshake(mut self, config: obfs::Config) -> ClientBuilder {
+        self.obfs = Some(config);
+        self
+    }
+
+    /// Accept `status` and `disconnect` commands on a Unix socket at `path`.
+    #[cfg(feature = "admin")]
+    pub fn control_socket<P: AsRef<Path>>(mut self, path: P) -> ClientBuilder {
+        self.control = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Instead of a TUN device, run a SOCKS5 proxy on `addr` and relay its
+    /// connections through the server. No routes change and no privileges
+    /// are needed. The server must allow streams.
+    pub fn socks_proxy(mut self, addr: SocketAddr) -> ClientBuilder {
+        self.socks = Some(addr);
+        self
+    }
+
+    /// Relay connections to `addr` through the server to `target`, a
+    /// `HOST:PORT` as seen from the server, like `ssh -L`. Works with or
+    /// without a TUN device. The server must allow streams.
+    pub fn local_forward(mut self, addr: SocketAddr, target: &str) -> ClientBuilder {
+        self.forwards.push((addr, String::from(target)));
+        self
+    }
+
+    /// Time source for timeouts. Defaults to the system clock.
+    pub fn clock<C>(mut self, clock: C) -> ClientBuilder
+        where C: Clock + 'static
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Record tunnel traffic to a pcap file.
+    pub fn pcap(mut self, config: pcap::Config) -> ClientBuilder {
+        self.pcap = Some(config);
+        self
+    }
+
+    /// Describe each session in a JSON file at `path` once it is up, for
+    /// up-scripts and orchestration: the device, addresses, server, MTU and
+    /// DNS servers pushed. The file is removed when the client stops.
+    pub fn session_file<P: AsRef<Path>>(mut self, path: P) -> ClientBuilder {
+        self.session_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Refuse to start while another client holds the lock file at `path`,
+    /// and hold it until this one stops. See the `instance` module.
+    pub fn instance_lock<P: AsRef<Path>>(mut self, path: P) -> ClientBuilder {
+        self.lock = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Append the session's handshakes, roams and expiries to the file at
+    /// `path`, without tokens or payload. See the `transcript` module.
+    pub fn transcript<P: AsRef<Path>>(mut self, path: P) -> ClientBuilder {
+        self.transcript = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn on_event<F>(mut self, callback: F) -> ClientBuilder
+        where F: Fn(&Event) + Send + 'static
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        let mut servers = self.servers;
+        if let Some(host) = self.host {
+            servers.insert(0, (host, self.port));
+        }
+        if servers.is_empty() {
+            return Err(Error::Config(String::from("no remote host given")));
+        }
+        let mut resolver = resolver::Resolver::new(self.family, self.dns_cache);
+        let (current, remote_addr) = match resolve_next(&mut resolver,
+                                                        &servers,
+                                                        0,
+                                                        self.clock.now()) {
+            Some(found) => found,
+            None => return Err(Error::Config(String::from("no remote host resolves"))),
+        };
+
+        if !self.relays.is_empty() && self.select_by_latency {
+            return Err(Error::Config(String::from("latency probes cannot go through relays")));
+        }
+        if !self.relays.is_empty() && self.session_port.is_some() {
+            return Err(Error::Config(String::from("the session port cannot be reached through \
+                                                   relays")));
+        }
+        let lock = match self.lock {
+            Some(ref path) => Some(try!(instance::Lock::acquire(path))),
+            None => None,
+        };
+        let mut relays = Vec::new();
+        for &(ref host, port) in &self.relays {
+            let ip = try!(resolver.resolve(host, self.clock.now()));
+            relays.push((SocketAddr::new(ip, port), rand::random::<u64>()));
+        }
+
+        let outer_ip = relays.first().map_or(remote_addr, |&(relay, _)| relay).ip();
+        let local_ip = self.bind_address.unwrap_or(unspecified(outer_ip));
+        let sockfd = try!(bind_outer(local_ip, &self.outer));
+        let local_addr = try!(sockfd.local_addr());
+        let identity = match self.identity {
+            Some(ref path) => {
+                let identity = try!(identity::Identity::load_or_create(path));
+                info!("Using identity {}.", identity::fingerprint(&identity.public()));
+                Some(identity)
+            }
+            None => None,
+        };
+        let capture = match self.pcap {
+            Some(config) => Some(try!(pcap::Capture::open(config))),
+            None => None,
+        };
+        let transcript = match self.transcript {
+            Some(ref path) => Some(try!(transcript::Transcript::open(path))),
+            None => None,
+        };
+
+        let poll = try!(mio::Poll::new());
+        try!(poll.register(&sockfd, SOCK, mio::Ready::readable(), mio::PollOpt::level()));
+
+        let (shutdown, registration) = ShutdownHandle::new();
+        try!(poll.register(&registration,
+                      SHUTDOWN,
+                      mio::Ready::readable(),
+                      mio::PollOpt::edge()));
+        try!(register_signal(&poll));
+        let watcher = if self.follow_network {
+            let watcher = try!(netwatch::Watcher::open());
+            try!(watcher.register(&poll, NETWATCH));
+            Some(watcher)
+        } else {
+            None
+        };
+
+        let now = self.clock.now();
+        #[cfg(feature = "metrics")]
+        let statsd = match self.statsd {
+            Some(config) => Some(try!(stats::Statsd::open(config, now))),
+            None => None,
+        };
+        #[cfg(feature = "metrics")]
+        let health = match self.health {
+            Some(addr) => Some(try!(health::Endpoint::open(&poll, HEALTH, &addr))),
+            None => None,
+        };
+        #[cfg(feature = "admin")]
+        let control = match self.control {
+            Some(path) => Some(try!(control::Listener::open(&poll, CONTROL, &path))),
+            None => None,
+        };
+        #[cfg(feature = "obfuscation")]
+        let (cover_caps, delay) = (if self.cover.is_some() { CAP_COVER } else { 0 },
+                                   self.obfs.map_or(Duration::from_secs(0),
+                                                    |obfs| obfs::delay(obfs.max_delay)));
+        #[cfg(not(feature = "obfuscation"))]
+        let (cover_caps, delay) = (0, Duration::from_secs(0));
+        let proxy = if self.socks.is_some() || !self.forwards.is_empty() {
+            let mut proxy = proxy::Proxy::new(PROXY_BASE);
+            if let Some(addr) = self.socks {
+                try!(proxy.listen(&poll, &addr));
+            }
+            for &(addr, ref target) in &self.forwards {
+                try!(proxy.forward(&poll, &addr, target));
+            }
+            Some(proxy)
+        } else {
+            None
+        };
+        Ok(Client {
+            servers: servers,
+            current: current,
+            failures: current,
+            select_by_latency: self.select_by_latency && servers.len() > 1,
+            needs_probe: self.select_by_latency && servers.len() > 1,
+            probe: None,
+            remote_addr: remote_addr,
+            relays: relays,
+            local_ip: local_ip,
+            fixed_local_ip: self.bind_address.is_some(),
+            resolver: resolver,
+            outer: self.outer,
+            watcher: watcher,
+            default_route: self.default_route,
+            timeout: self.timeout,
+            handshake_attempts: self.handshake_attempts,
+            connect_deadline: self.connect_deadline,
+            connecting_since: None,
+            rtt_interval: self.rtt_interval,
+            keepalive: keepalive::Keepalive::new(self.keepalive),
+            silent_from: None,
+            caps: Capabilities::new(if self.compression {
+                CAP_COMPRESSION | CAP_KEEPALIVE
+            } else {
+                CAP_KEEPALIVE
+            } | cover_caps | CAP_SUBNET |
+                                         CAP_OPTIONS | CAP_BATCH |
+                                         if proxy.is_some() { CAP_STREAMS } else { 0 }),
+            compression_threshold: self.compression_threshold,
+            clamp_mss: if self.clamp_mss {
+                Some(device::MTU)
+            } else {
+                None
+            },
+            verify_checksums: if self.verify_checksums || self.drop_corrupt {
+                Some(self.drop_corrupt)
+            } else {
+                None
+            },
+            dscp: if self.propagate_dscp {
+                Some(DscpMarker::new())
+            } else {
+                None
+            },
+            credential: self.credential,
+            stamp_credential: self.stamp_credential,
+            identity: identity,
+            tap: self.tap,
+            tun_fd: self.tun_fd,
+            tracer: self.tracer,
+            handshake_span: None,
+            session_span: None,
+            failure: None,
+            counters: stats::Counters::default(),
+            #[cfg(feature = "metrics")]
+            statsd: statsd,
+            #[cfg(feature = "metrics")]
+            health: health,
+            netem: self.netem.map(netem::Emulator::new),
+            #[cfg(feature = "obfuscation")]
+            cover: self.cover.map(|config| cover::Cover::new(config, now)),
+            #[cfg(feature = "obfuscation")]
+            obfs: self.obfs,
+            rebind: self.rebind.map(|interval| (interval, now + interval)),
+            #[cfg(feature = "admin")]
+            control: control,
+            proxy: proxy,
+            proxy_only: self.socks.is_some(),
+            session_file: self.session_file,
+            session_port: self.session_port,
+            route_mtu: self.route_mtu,
+            pushed_dns: Vec::new(),
+            clock: self.clock,
+            callback: self.callback,
+            poll: poll,
+            sockfd: sockfd,
+            local_addr: local_addr,
+            capture: capture,
+            transcript: transcript,
+            shutdown: shutdown,
+            _registration: registration,
+            tun: None,
+            _gw: None,
+            pushed_routes: Vec::new(),
+            _lock: lock,
+            dns: None,
+            subnet: device::Subnet::default(),
+            delegated: None,
+            session: None,
+            offer: None,
+            roamer: None,
+            resume: None,
+            attempt: 0,
+            deadline: now + delay,
+            last_heard: now,
+            probes_sent: 0,
+            last_probe: now,
+            ping_sent: None,
+            last_rtt: now,
+            rtt: stats::Rtt::default(),
+            latency: stats::Histogram::default(),
+            last_loop: None,
+            loop_repairs: 0,
+            resolved: Some(now),
+            sleep: SleepDetector::new(Duration::from_secs(SLEEP_THRESHOLD)),
+            encoder: Encoder::new(),
+            // One spare byte in each buffer to detect truncation. The TUN buffer
+            // is sized once the device exists.
+            sock_buf: vec![0u8; frame_capacity(device::MTU) + 1],
+            tun_buf: Vec::new(),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Session {
+    id: Id,
+    token: Token,
+    caps: Capabilities,
+    since: Instant,
+}
+
+/// What a client is connected to and how the link is doing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub server: SocketAddr,
+    /// Tunnel address, once a session is established.
+    pub address: Option<Ipv4Addr>,
+    /// IPv6 prefix the server delegated for downstream networks.
+    pub prefix: Option<(Ipv6Addr, u8)>,
+    /// Time since the session was established.
+    pub uptime: Duration,
+    pub counters: stats::Counters,
+    pub rtt: stats::Rtt,
+    pub latency: stats::Histogram,
+    /// Negotiated MTU and compression, once a session is established.
+    pub mtu: Option<u16>,
+    pub compression: bool,
+    /// Why the last handshake failed, while there is no session.
+    pub failure: Option<Code>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "server:      {}", self.server));
+        match self.address {
+            Some(address) => try!(writeln!(f, "address:     {}", address)),
+            None => try!(writeln!(f, "address:     (connecting)")),
+        }
+        if let Some((net, prefix)) = self.prefix {
+            try!(writeln!(f, "prefix:      {}/{}", net, prefix));
+        }
+        try!(writeln!(f, "uptime:      {}s", self.uptime.as_secs()));
+        try!(writeln!(f,
+                      "rx:          {} bytes in {} packets",
+                      self.counters.rx_bytes,
+                      self.counters.rx_packets));
+        try!(writeln!(f,
+                      "tx:          {} bytes in {} packets",
+                      self.counters.tx_bytes,
+                      self.counters.tx_packets));
+        try!(writeln!(f,
+                      "frames:      {} compressed, {} raw",
+                      self.counters.tx_compressed,
+                      self.counters.tx_raw));
+        match self.rtt.srtt() {
+            Some(srtt) => {
+                try!(writeln!(f,
+                              "rtt:         {}ms (+/- {}ms)",
+                              stats::millis(srtt),
+                              stats::millis(self.rtt.rttvar())))
+            }
+            None => try!(writeln!(f, "rtt:         (not measured yet)")),
+        }
+        if let (Some(p50), Some(p99)) = (self.latency.quantile(0.5), self.latency.quantile(0.99)) {
+            try!(writeln!(f,
+                          "latency:     p50 {}ms, p99 {}ms over {} samples",
+                          p50,
+                          p99,
+                          self.latency.count()));
+        }
+        if let Some(mtu) = self.mtu {
+            try!(writeln!(f, "mtu:         {}", mtu));
+        }
+        if let Some(code) = self.failure {
+            try!(writeln!(f, "failure:     {}", code));
+        }
+        write!(f,
+               "compression: {}",
+               if self.compression { "snappy" } else { "off" })
+    }
+}
+
+pub struct Client {
+    servers: Vec<(String, u16)>,
+    // Index into `servers` of the one in use, and how many servers in a row
+    // have failed to answer.
+    current: usize,
+    failures: usize,
+    select_by_latency: bool,
+    needs_probe: bool,
+    // The outstanding latency probe: its nonce, when it was sent and to whom.
+    probe: Option<(u64, Instant, Vec<(usize, SocketAddr)>)>,
+    remote_addr: SocketAddr,
+    // The relays frames go through, each with the circuit through it.
+    relays: Vec<(SocketAddr, u64)>,
+    local_ip: IpAddr,
+    // Whether `local_ip` was given, rather than following the server's
+    // address family.
+    fixed_local_ip: bool,
+    resolver: resolver::Resolver,
+    outer: OuterOptions,
+    watcher: Option<netwatch::Watcher>,
+    default_route: bool,
+    timeout: Duration,
+    // Attempts per server, or 0 to keep trying.
+    handshake_attempts: u32,
+    connect_deadline: Option<Duration>,
+    // When the client last started handshaking with no session.
+    connecting_since: Option<Instant>,
+    rtt_interval: Duration,
+    keepalive: keepalive::Keepalive,
+    // The server that stopped answering, if the session was lost to silence.
+    silent_from: Option<SocketAddr>,
+    caps: Capabilities,
+    compression_threshold: usize,
+    // TUN MTU to clamp TCP MSS to, if enabled.
+    clamp_mss: Option<u16>,
+    // Whether corrupt packets are dropped, if checksums are verified.
+    verify_checksums: Option<bool>,
+    dscp: Option<DscpMarker>,
+    callback: Option<Callback>,
+    poll: mio::Poll,
+    sockfd: mio::udp::UdpSocket,
+    local_addr: SocketAddr,
+    capture: Option<pcap::Capture>,
+    transcript: Option<transcript::Transcript>,
+    shutdown: ShutdownHandle,
+    _registration: mio::Registration,
+    // The TUN device and the default route are only set up once the first
+    // handshake succeeds, and are kept across re-handshakes.
+    tun: Option<Box<device::VirtualInterface>>,
+    // RAII so ignore unused variable warning
+    _gw: Option<utils::DefaultGateway>,
+    // Routes the server pushed, removed on drop.
+    pushed_routes: Vec<utils::InterfaceRoute>,
+    // Released only once the routes above are gone.
+    _lock: Option<instance::Lock>,
+    // DNS servers the server pushed, in effect while the tunnel carries all
+    // traffic on macOS.
+    dns: Option<utils::DnsOverride>,
+    // The server's subnet, as of the last handshake.
+    subnet: device::Subnet,
+    // The IPv6 prefix the server delegated in the last handshake.
+    delegated: Option<(Ipv6Addr, u8)>,
+    session: Option<Session>,
+    // Our half of the roaming key until the server answers, then the key.
+    offer: Option<roaming::Offer>,
+    roamer: Option<roaming::Roamer>,
+    credential: Option<String>,
+    stamp_credential: bool,
+    identity: Option<identity::Identity>,
+    tap: bool,
+    tun_fd: Option<RawFd>,
+    // The last session the server expired, offered back on re-handshake.
+    resume: Option<(Id, Token)>,
+    attempt: u32,
+    tracer: Option<telemetry::Tracer>,
+    handshake_span: Option<telemetry::Span>,
+    session_span: Option<telemetry::Span>,
+    // Why the last handshake failed, until one succeeds.
+    failure: Option<Code>,
+    counters: stats::Counters,
+    #[cfg(feature = "metrics")]
+    statsd: Option<stats::Statsd>,
+    #[cfg(feature = "metrics")]
+    health: Option<health::Endpoint>,
+    netem: Option<netem::Emulator>,
+    #[cfg(feature = "obfuscation")]
+    cover: Option<cover::Cover>,
+    #[cfg(feature = "obfuscation")]
+    obfs: Option<obfs::Config>,
+    // Interval between port changes and when the next is due.
+    rebind: Option<(Duration, Instant)>,
+    #[cfg(feature = "admin")]
+    control: Option<control::Listener>,
+    // The SOCKS5 proxy and local forwards.
+    proxy: Option<proxy::Proxy>,
+    // Whether the SOCKS5 proxy replaces the TUN device.
+    proxy_only: bool,
+    session_file: Option<PathBuf>,
+    // The servers' port for session maintenance, if not the data port.
+    session_port: Option<u16>,
+    route_mtu: bool,
+    // DNS servers the server pushed in the last handshake.
+    pushed_dns: Vec<IpAddr>,
+    clock: Box<Clock>,
+    deadline: Instant,
+    last_heard: Instant,
+    probes_sent: u32,
+    last_probe: Instant,
+    // When the newest unanswered `Ping` went out, and the last measurement.
+    ping_sent: Option<Instant>,
+    last_rtt: Instant,
+    rtt: stats::Rtt,
+    latency: stats::Histogram,
+    // When the tunnel's own traffic last came out of the TUN device, and how
+    // often the host route was repaired since.
+    last_loop: Option<Instant>,
+    loop_repairs: u32,
+    // When the server's name was last looked up; `None` when due.
+    resolved: Option<Instant>,
+    sleep: SleepDetector,
+    encoder: Encoder,
+    sock_buf: Vec<u8>,
+    tun_buf: Vec<u8>,
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            host: None,
+            port: 8964,
+            servers: Vec::new(),
+            relays: Vec::new(),
+            select_by_latency: false,
+            default_route: false,
+            timeout: Duration::from_secs(5),
+            handshake_attempts: HANDSHAKE_ATTEMPTS,
+            connect_deadline: None,
+            rtt_interval: Duration::from_secs(RTT_INTERVAL),
+            keepalive: keepalive::Config::default(),
+            compression: true,
+            compression_threshold: COMPRESSION_THRESHOLD,
+            clamp_mss: false,
+            verify_checksums: false,
+            drop_corrupt: false,
+            propagate_dscp: false,
+            outer: OuterOptions::default(),
+            bind_address: None,
+            family: resolver::Family::Any,
+            dns_cache: Duration::from_secs(resolver::DEFAULT_TTL),
+            credential: None,
+            stamp_credential: false,
+            identity: None,
+            tap: false,
+            tun_fd: None,
+            follow_network: true,
+            tracer: None,
+            #[cfg(feature = "metrics")]
+            statsd: None,
+            #[cfg(feature = "metrics")]
+            health: None,
+            netem: None,
+            #[cfg(feature = "obfuscation")]
+            cover: None,
+            #[cfg(feature = "obfuscation")]
+            obfs: None,
+            rebind: None,
+            #[cfg(feature = "admin")]
+            control: None,
+            socks: None,
+            forwards: Vec::new(),
+            clock: Box::new(SystemClock),
+            callback: None,
+            pcap: None,
+            session_file: None,
+            session_port: None,
+            route_mtu: false,
+            lock: None,
+            transcript: None,
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Traffic through the tunnel since the client started.
+    pub fn counters(&self) -> stats::Counters {
+        self.counters
+    }
+
+    /// Round trip to the current server.
+    pub fn rtt(&self) -> stats::Rtt {
+        self.rtt
+    }
+
+    pub fn status(&self) -> Status {
+        let now = self.clock.now();
+        Status {
+            server: self.remote_addr,
+            address: self.session.map(|session| self.subnet.addr(session.id)),
+            prefix: self.session.and(self.delegated),
+            uptime: self.session.map_or(Duration::from_secs(0),
+                                        |session| now.duration_since(session.since)),
+            counters: self.counters,
+            rtt: self.rtt,
+            latency: self.latency,
+            mtu: self.session.map(|session| session.caps.mtu),
+            compression: self.session.map_or(false, |session| session.caps.has(CAP_SNAPPY)),
+            failure: self.failure,
+        }
+    }
+
+    /// Runs the client until it is shut down or the handshake fails.
+    pub fn run(&mut self) -> Result<()> {
+        info!("Working in client mode.");
+        info!("Remote server: {}", self.remote_addr);
+
+        let result = self.run_loop();
+        for span in vec![self.handshake_span.take(), self.session_span.take()] {
+            if let Some(mut span) = span {
+                match result {
+                    Ok(()) => span.set("end.reason", "shutdown"),
+                    Err(ref e) => span.fail(e),
+                }
+                self.end_span(Some(span));
+            }
+        }
+        if let Some(ref path) = self.session_file {
+            let _ = fs::remove_file(path);
+        }
+        match result {
+            Ok(()) => self.note("Stopped."),
+            Err(ref e) => self.note(&format!("Stopped: {}", e)),
+        }
+        try!(result);
+
+        self.emit(Event::Disconnected);
+        Ok(())
+    }
+
+    fn run_loop(&mut self) -> Result<()> {
+        let mut events = mio::Events::with_capacity(1024);
+
+        loop {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+
+            if let Some(slept) = self.sleep.check(self.clock.now(), SystemTime::now()) {
+                if let Some(session) = self.session {
+                    warn!("Host slept for {}s. Renewing session {}.",
+                          slept.as_secs(),
+                          session.id);
+                    self.renew(session, "suspended");
+                }
+            }
+
+            if self.session.is_none() && self.clock.now() >= self.deadline {
+                try!(self.send_request());
+            }
+
+            try!(self.check_server());
+            try!(self.check_network());
+            try!(self.check_dns());
+            try!(self.rebind_if_due());
+            #[cfg(feature = "obfuscation")]
+            try!(self.send_cover());
+            try!(self.tick_proxy());
+
+            let now = self.clock.now();
+            #[cfg(feature = "metrics")]
+            {
+                if let Some(ref mut statsd) = self.statsd {
+                    let mut gauges: Vec<(String, u64)> = match self.rtt.srtt() {
+                        Some(srtt) => {
+                            vec![(String::from("rtt_ms"), stats::millis(srtt)),
+                                 (String::from("rttvar_ms"), stats::millis(self.rtt.rttvar()))]
+                        }
+                        None => Vec::new(),
+                    };
+                    gauges.extend(self.latency.gauges(""));
+                    statsd.flush(&self.counters, &gauges, now);
+                }
+            }
+            #[cfg(feature = "metrics")]
+            let statsd_deadline = self.statsd.as_ref().map(|s| s.deadline());
+            #[cfg(not(feature = "metrics"))]
+            let statsd_deadline = None;
+            #[cfg(feature = "obfuscation")]
+            let cover_deadline = self.cover.as_ref().map(|c| c.deadline());
+            #[cfg(not(feature = "obfuscation"))]
+            let cover_deadline = None;
+            let deadline = [self.next_deadline(),
+                            self.watcher.as_ref().and_then(|w| w.deadline()),
+                            statsd_deadline,
+                            self.netem.as_ref().and_then(|n| n.deadline()),
+                            cover_deadline,
+                            self.proxy.as_ref().and_then(|p| if p.is_empty() {
+                                None
+                            } else {
+                                Some(now + Duration::from_millis(stream::RETRANSMIT))
+                            })]
+                .iter()
+                .filter_map(|&deadline| deadline)
+                .min();
+            try!(self.poll.poll(&mut events, deadline.map(|d| remaining(d, now))));
+            if let Some(ref mut tun) = self.tun {
+                tun.set_now(self.clock.now());
+            }
+            if let Some(ref mut netem) = self.netem {
+                try!(netem.release(&self.sockfd, self.clock.now()));
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    SOCK => try!(self.handle_socket()),
+                    TUN => try!(self.handle_tun()),
+                    NETWATCH => {
+                        let now = self.clock.now();
+                        if let Some(ref mut watcher) = self.watcher {
+                            watcher.drain(now);
+                        }
+                    }
+                    #[cfg(feature = "metrics")]
+                    HEALTH => self.answer_health_check(),
+                    #[cfg(feature = "admin")]
+                    CONTROL => self.answer_control(),
+                    STATUS => {
+                        if signal::take_status_request() {
+                            let status = self.status();
+                            self.emit(Event::Status(status));
+                        }
+                    }
+                    SHUTDOWN | SIGNAL => {}
+                    token if self.proxy.as_ref().map_or(false, |p| p.owns(token)) => {
+                        try!(self.handle_proxy(token))
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    fn answer_health_check(&self) {
+        if let Some(ref health) = self.health {
+            let status = if self.session.is_some() { "connected" } else { "connecting" };
+            let mut status = vec![("status", String::from(status)),
+                                  ("tun",
+                                   self.tun.as_ref().map_or(String::from("none"),
+                                                            |tun| String::from(tun.name()))),
+                                  ("server", self.remote_addr.to_string())];
+            if let Some(srtt) = self.rtt.srtt() {
+                status.push(("rtt",
+                             format!("{}ms {}ms",
+                                     stats::millis(srtt),
+                                     stats::millis(self.rtt.rttvar()))));
+            }
+            health.respond(&status);
+        }
+    }
+
+    /// Sends a cover frame if one is due and the server takes them.
+    #[cfg(feature = "obfuscation")]
+    fn send_cover(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let padding = match self.cover.as_mut().and_then(|cover| cover.due(now)) {
+            Some(padding) => padding,
+            None => return Ok(()),
+        };
+        match self.session {
+            Some(session) if session.caps.has(CAP_COVER) => {
+                self.send(&Message::Cover {
+                    id: session.id,
+                    token: session.token,
+                    padding: padding,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "admin")]
+    fn answer_control(&self) {
+        let requests = match self.control {
+            Some(ref control) => control.accept(),
+            None => return,
+        };
+        for (mut stream, command) in requests {
+            let reply = match &command[..] {
+                "status" => self.status().to_string(),
+                "disconnect" => {
+                    info!("Disconnecting on request.");
+                    self.shutdown.shutdown();
+                    String::from("Disconnecting.\n")
+                }
+                _ if command.split_whitespace().next() == Some("log") => {
+                    verbosity::control(&command)
+                }
+                _ => format!("Unknown command: {}\n", command),
+            };
+            if let Err(e) = stream.write_all(reply.as_bytes()) {
+                debug!("Failed to answer control request: {}", e);
+            }
+        }
+    }
+
+    fn end_span(&self, span: Option<telemetry::Span>) {
+        if let (Some(tracer), Some(span)) = (self.tracer.as_ref(), span) {
+            tracer.end(span);
+        }
+    }
+
+    fn end_session_span(&mut self, reason: &str) {
+        let mut span = self.session_span.take();
+        if let Some(ref mut span) = span {
+            span.set("end.reason", reason);
+        }
+        self.end_span(span);
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(ref callback) = self.callback {
+            callback(&event);
+        }
+    }
+
+    /// Adds a line to the transcript, if one is kept.
+    fn note(&mut self, what: &str) {
+        if let Some(ref mut transcript) = self.transcript {
+            transcript.note(what);
+        }
+    }
+
+    fn record_sent(&mut self, msg: &Message) {
+        if let Some(ref mut transcript) = self.transcript {
+            transcript.sent(&self.remote_addr, msg);
+        }
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        self.record_sent(msg);
+        let buf = try!(encode_message(msg));
+        self.send_buf(buf)
+    }
+
+    fn send_buf(&mut self, buf: Vec<u8>) -> Result<()> {
+        let outer = self.outer_addr();
+        self.send_buf_to(buf, outer)
+    }
+
+    /// Sends session maintenance to the server's session port, if it has
+    /// one, and otherwise along with data.
+    fn send_session(&mut self, msg: &Message) -> Result<()> {
+        self.record_sent(msg);
+        let buf = try!(encode_message(msg));
+        self.send_session_buf(buf)
+    }
+
+    fn send_session_buf(&mut self, buf: Vec<u8>) -> Result<()> {
+        match self.session_addr() {
+            Some(addr) => self.send_buf_to(buf, addr),
+            None => self.send_buf(buf),
+        }
+    }
+
+    fn send_buf_to(&mut self, buf: Vec<u8>, outer: SocketAddr) -> Result<()> {
+        let buf = try!(relay::wrap(&self.relays, self.remote_addr, buf));
+        if let Some(ref mut capture) = self.capture {
+            capture.outer(&self.local_addr, &outer, &buf);
+        }
+        match self.netem {
+            Some(ref mut netem) => {
+                netem.submit(buf, outer, self.clock.now());
+                Ok(())
+            }
+            None => send_raw(&self.sockfd, &buf, &outer),
+        }
+    }
+
+    /// Where datagrams go: the first relay, if any, or else the server.
+    fn outer_addr(&self) -> SocketAddr {
+        self.relays.first().map_or(self.remote_addr, |&(relay, _)| relay)
+    }
+
+    /// The server's session port, if it has one.
+    fn session_addr(&self) -> Option<SocketAddr> {
+        self.session_port.map(|port| SocketAddr::new(self.remote_addr.ip(), port))
+    }
+
+    /// Pings measure the round trip and keep the NAT mapping alive.
+    fn ping_interval(&self) -> Duration {
+        cmp::min(self.rtt_interval, self.keepalive.interval())
+    }
+
+    /// When the run loop next has work to do without any events.
+    fn next_deadline(&self) -> Option<Instant> {
+        match self.session {
+            None => Some(self.deadline),
+            Some(session) if session.caps.has(CAP_KEEPALIVE) => {
+                let probe = if self.probes_sent == 0 {
+                    self.last_heard + Duration::from_secs(SERVER_IDLE_TIMEOUT)
+                } else {
+                    self.last_probe + Duration::from_secs(PROBE_INTERVAL)
+                };
+                Some(cmp::min(probe, self.last_rtt + self.ping_interval()))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Probes a silent server, and fails over once it stops answering.
+    fn check_server(&mut self) -> Result<()> {
+        let session = match self.session {
+            Some(session) if session.caps.has(CAP_KEEPALIVE) => session,
+            _ => return Ok(()),
+        };
+        let now = self.clock.now();
+        if now.duration_since(self.last_rtt) >= self.ping_interval() {
+            self.last_rtt = now;
+            self.ping_sent = Some(now);
+            debug!("Measuring round trip to {}.", self.remote_addr);
+            return self.send_session(&Message::Ping {
+                id: session.id,
+                token: session.token,
+            });
+        }
+        if now.duration_since(self.last_heard) < Duration::from_secs(SERVER_IDLE_TIMEOUT) {
+            return Ok(());
+        }
+        if self.probes_sent > 0 &&
+           now.duration_since(self.last_probe) < Duration::from_secs(PROBE_INTERVAL) {
+            return Ok(());
+        }
+        if self.probes_sent == PROBE_ATTEMPTS {
+            warn!("Server {} stopped responding.", self.remote_addr);
+            let note = format!("Server {} stopped responding.", self.remote_addr);
+            self.note(&note);
+            self.silent_from = Some(self.remote_addr);
+            self.failures = 0;
+            return self.fail_over();
+        }
+        self.probes_sent += 1;
+        self.last_probe = now;
+        self.ping_sent = Some(now);
+        debug!("Probing silent server {} ({}/{}).",
+               self.remote_addr,
+               self.probes_sent,
+               PROBE_ATTEMPTS);
+        self.send_session(&Message::Ping {
+            id: session.id,
+            token: session.token,
+        })
+    }
+
+    /// Moves on to the next server that resolves and starts a handshake
+    /// with it. The TUN device stays up.
+    fn fail_over(&mut self) -> Result<()> {
+        let next = (self.current + 1) % self.servers.len();
+        let now = self.clock.now();
+        let (current, remote_addr) = match resolve_next(&mut self.resolver,
+                                                        &self.servers,
+                                                        next,
+                                                        now) {
+            Some(found) => found,
+            None => return Err(Error::Config(String::from("no remote host resolves"))),
+        };
+        self.failures += (current + self.servers.len() - next) % self.servers.len();
+        if self.servers.len() > 1 {
+            info!("Failing over to {}.", remote_addr);
+        }
+        self.note(&format!("Failing over to {}.", remote_addr));
+        self.end_session_span("failover");
+        self.current = current;
+        self.remote_addr = remote_addr;
+        self.resolved = Some(self.clock.now());
+        if self.follow_family() {
+            try!(self.rebind_socket());
+        }
+        self.session = None;
+        self.resume = None;
+        self.attempt = 0;
+        self.deadline = self.clock.now();
+        if self.select_by_latency {
+            // The probes must not go into the tunnel; establish() puts the
+            // routes back.
+            self.needs_probe = true;
+            self._gw = None;
+        } else if self._gw.is_some() {
+            try!(self.install_gateway());
+        }
+        Ok(())
+    }
+
+    /// Sends a latency probe to every server that resolves.
+    fn send_probes(&mut self) -> Result<()> {
+        let nonce = rand::random::<u64>();
+        let now = self.clock.now();
+        let mut targets = Vec::new();
+        for (index, &(ref host, port)) in self.servers.iter().enumerate() {
+            match self.resolver.resolve(host, now) {
+                Ok(ip) => targets.push((index, SocketAddr::new(ip, port))),
+                Err(e) => warn!("Failed to resolve {}: {}", host, e),
+            }
+        }
+        let buf = try!(encode_message(&Message::Probe { nonce: nonce }));
+        for &(_, addr) in &targets {
+            if let Some(ref mut capture) = self.capture {
+                capture.outer(&self.local_addr, &addr, &buf);
+            }
+            if let Err(e) = send_raw(&self.sockfd, &buf, &addr) {
+                warn!("Failed to probe {}: {}", addr, e);
+            }
+        }
+        info!("Probing {} servers for latency.", targets.len());
+        let now = self.clock.now();
+        self.probe = Some((nonce, now, targets));
+        self.deadline = now + self.timeout;
+        Ok(())
+    }
+
+    /// Picks the server that answered a probe first.
+    fn handle_probe(&mut self, addr: SocketAddr, len: usize) -> Result<()> {
+        let answered = match decode_message(&self.sock_buf[0..len]) {
+            Ok(Message::Probe { nonce }) => {
+                match self.probe {
+                    Some((expected, _, ref targets)) if expected == nonce => {
+                        targets.iter().find(|&&(_, target)| target == addr).map(|&(i, _)| i)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let index = match answered {
+            Some(index) => index,
+            None => {
+                debug!("Ignoring {} bytes from {} while probing.", len, addr);
+                return Ok(());
+            }
+        };
+        let (_, sent, _) = self.probe.take().unwrap();
+        let rtt = self.clock.now().duration_since(sent);
+        info!("Selected {} with a round trip of {} ms.",
+              addr,
+              rtt.as_secs() * 1000 + rtt.subsec_nanos() as u64 / 1000000);
+        self.current = index;
+        self.remote_addr = addr;
+        self.deadline = self.clock.now();
+        Ok(())
+    }
+
+    fn send_request(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let since = *self.connecting_since.get_or_insert(now);
+        if let Some(limit) = self.connect_deadline {
+            if now.duration_since(since) >= limit {
+                let mut span = self.handshake_span.take();
+                if let Some(ref mut span) = span {
+                    span.fail(String::from("connect deadline passed"));
+                }
+                self.end_span(span);
+                return Err(Error::Failure(Code::NoResponse,
+                                          format!("no session within {} seconds",
+                                                  limit.as_secs())));
+            }
+        }
+        if self.needs_probe {
+            self.needs_probe = false;
+            return self.send_probes();
+        }
+        if self.probe.take().is_some() {
+            warn!("No server answered the latency probe. Trying {}.",
+                  self.remote_addr);
+        }
+        let attempts = match self.handshake_attempts {
+            0 => HANDSHAKE_ATTEMPTS,
+            n => n,
+        };
+        if self.attempt >= attempts {
+            let mut span = self.handshake_span.take();
+            if let Some(ref mut span) = span {
+                span.fail(format!("no answer after {} attempts", attempts));
+            }
+            self.end_span(span);
+            self.failures += 1;
+            self.failure = Some(Code::NoResponse);
+            if self.handshake_attempts > 0 && self.failures >= self.servers.len() {
+                return Err(Error::Failure(Code::NoResponse,
+                                          format!("{} did not respond after {} attempts",
+                                                  self.remote_addr,
+                                                  attempts)));
+            }
+            warn!("[{}] {} did not respond after {} attempts.",
+                  Code::NoResponse,
+                  self.remote_addr,
+                  attempts);
+            try!(self.fail_over());
+        }
+        self.attempt += 1;
+        if self.attempt == 1 {
+            self.handshake_span = self.tracer.as_ref().map(|tracer| {
+                let mut span = tracer.span("handshake");
+                span.set("server", self.remote_addr);
+                span
+            });
+        }
+        // Only a client that follows the network or changes ports ever roams,
+        // so only it offers a roaming key. Retransmissions repeat the offer.
+        if (self.watcher.is_some() || self.rebind.is_some()) && self.offer.is_none() {
+            self.offer = Some(roaming::Offer::new());
+        }
+        let roam_key = self.offer.as_ref().map_or(Vec::new(), |offer| offer.public());
+        let msg = match (&self.credential, &self.identity) {
+            (credential, &Some(ref identity)) => {
+                let stamp = match *credential {
+                    Some(ref secret) if self.stamp_credential => {
+                        Some(replay::Stamp::new(secret, rand::random(), self.resume))
+                    }
+                    _ => None,
+                };
+                Message::Identified {
+                    caps: self.caps,
+                    resume: self.resume,
+                    credential: if stamp.is_some() { None } else { credential.clone() },
+                    stamp: stamp,
+                    proof: identity.prove(self.resume),
+                    roam_key: roam_key,
+                }
+            }
+            (&Some(ref secret), &None) if self.stamp_credential => {
+                Message::Stamped {
+                    caps: self.caps,
+                    resume: self.resume,
+                    stamp: replay::Stamp::new(secret, rand::random(), self.resume),
+                    roam_key: roam_key,
+                }
+            }
+            _ => {
+                Message::Request {
+                    caps: self.caps,
+                    resume: self.resume,
+                    credential: self.credential.clone(),
+                    roam_key: roam_key,
+                }
+            }
+        };
+        self.record_sent(&msg);
+        let mut buf = try!(encode_message(&msg));
+        let wait = self.timeout + try!(self.obfuscate(&mut buf));
+        try!(self.send_session_buf(buf));
+        info!("Request sent to {} (attempt {}/{}).",
+              self.remote_addr,
+              self.attempt,
+              attempts);
+        let retry = self.clock.now() + wait;
+        self.deadline = match self.connect_deadline {
+            Some(limit) => cmp::min(retry, since + limit),
+            None => retry,
+        };
+        Ok(())
+    }
+
+    /// Sends the decoys ahead of a handshake frame and pads it. Returns how
+    /// much longer to wait for the answer.
+    #[cfg(feature = "obfuscation")]
+    fn obfuscate(&mut self, buf: &mut Vec<u8>) -> Result<Duration> {
+        match self.obfs {
+            Some(obfs) => {
+                for _ in 0..obfs.decoys {
+                    try!(self.send_session_buf(obfs::decoy(obfs.max_padding)));
+                }
+                obfs::pad(buf, obfs.max_padding);
+                Ok(obfs::delay(obfs.max_delay))
+            }
+            None => Ok(Duration::from_secs(0)),
+        }
+    }
+
+    #[cfg(not(feature = "obfuscation"))]
+    fn obfuscate(&mut self, _: &mut Vec<u8>) -> Result<Duration> {
+        Ok(Duration::from_secs(0))
+    }
+
+    fn establish(&mut self,
+                 id: Id,
+                 token: Token,
+                 caps: Capabilities,
+                 subnet: device::Subnet)
+                 -> Result<()> {
+        if subnet.id_of(self.remote_addr.ip()).is_some() {
+            // Reaching the server through the tunnel would need the tunnel.
+            return Err(Error::Route(format!("server {} is inside the tunnel subnet {}",
+                                            self.remote_addr.ip(),
+                                            subnet)));
+        }
+        if subnet != self.subnet && self._gw.is_some() {
+            // The routes point at the old subnet's gateway.
+            self._gw = None;
+        }
+        self.subnet = subnet;
+        info!("Session established with token {}. Assigned IP address: {}.",
+              token,
+              subnet.addr(id));
+        info!("Negotiated protocol version {}, flags {:#x}, MTU {}.",
+              caps.version,
+              caps.flags,
+              caps.mtu);
+        if let Some((old_id, _)) = self.resume.take() {
+            if old_id != id {
+                warn!("Could not resume address {}; the server assigned a new one.",
+                      subnet.addr(old_id));
+            }
+        }
+
+        if self.proxy.is_some() && !caps.has(CAP_STREAMS) {
+            warn!("Server does not relay streams; the proxy and forwards will not work.");
+        }
+        if self.tun.is_none() && !self.proxy_only {
+            info!("Bringing up TUN device.");
+            let tun = try!(open_interface(self.tun_fd.take(), self.tap, None));
+            info!("Setting up TUN device for polling.");
+            try!(self.poll.register(&mio::unix::EventedFd(&tun.as_raw_fd()),
+                                    TUN,
+                                    mio::Ready::readable(),
+                                    mio::PollOpt::level()));
+            self.tun = Some(tun);
+        }
+        let mtu = if let Some(ref tun) = self.tun {
+            try!(tun.up(&subnet, id));
+            if caps.mtu < device::MTU {
+                try!(tun.set_mtu(caps.mtu));
+            }
+            let mtu = try!(tun.mtu());
+            info!("TUN device {} initialized. Internal IP: {}/24. MTU: {}.",
+                  tun.name(),
+                  subnet.addr(id),
+                  mtu);
+            Some(mtu)
+        } else {
+            None
+        };
+        if let Some(mtu) = mtu {
+            self.size_buffers(mtu);
+        }
+
+        if self.default_route && self._gw.is_none() && !self.proxy_only {
+            try!(self.install_gateway());
+        }
+
+        self.session = Some(Session {
+            id: id,
+            token: token,
+            caps: caps,
+            since: self.clock.now(),
+        });
+        if self.silent_from.take() == Some(self.remote_addr) && self.attempt == 1 {
+            if let Some(interval) = self.keepalive.lost() {
+                info!("{} went silent yet answered at once, so the NAT dropped the mapping. \
+                       Pinging every {:?}.",
+                      self.remote_addr,
+                      interval);
+            }
+        }
+        let mut span = self.handshake_span.take();
+        if let Some(ref mut span) = span {
+            span.set("attempts", self.attempt);
+            span.set("id", id);
+        }
+        self.end_span(span);
+        self.session_span = self.tracer.as_ref().map(|tracer| {
+            let mut span = tracer.span("session");
+            span.set("server", self.remote_addr);
+            span.set("id", id);
+            span
+        });
+        self.attempt = 0;
+        self.failures = 0;
+        self.failure = None;
+        self.connecting_since = None;
+        self.last_heard = self.clock.now();
+        self.probes_sent = 0;
+        self.ping_sent = None;
+        self.last_rtt = self.last_heard;
+        self.rtt = stats::Rtt::default();
+        info!("Ready for transmission.");
+        let server = self.remote_addr;
+        self.emit(Event::Connected {
+            id: id,
+            server: server,
+        });
+        Ok(())
+    }
+
+    /// Fits the buffers and MSS clamping to a TUN MTU of `mtu`.
+    fn size_buffers(&mut self, mtu: u16) {
+        self.tun_buf = vec![0u8; mtu as usize + 1];
+        if self.clamp_mss.is_some() {
+            self.clamp_mss = Some(mtu);
+        }
+        let capacity = frame_capacity(cmp::max(mtu, device::MTU)) + 1;
+        if self.sock_buf.len() < capacity {
+            self.sock_buf = vec![0u8; capacity];
+        }
+    }
+
+    /// Applies the settings the server pushed in the handshake.
+    fn apply(&mut self, options: &[push::PushOption]) -> Result<()> {
+        self.pushed_routes.clear();
+        self.delegated = None;
+        self.pushed_dns.clear();
+        let tun = match self.tun {
+            Some(ref tun) => tun,
+            None => return Ok(()),
+        };
+        let mut dns = Vec::new();
+        let mut raised = None;
+        for option in options {
+            match *option {
+                push::PushOption::Address(..) => {}
+                push::PushOption::Route(net, prefix) => {
+                    let net = format!("{}/{}", net, prefix);
+                    match utils::InterfaceRoute::create(&net, tun.name()) {
+                        Ok(route) => {
+                            info!("Routing {} through the tunnel as the server asks.", net);
+                            self.pushed_routes.push(route);
+                        }
+                        Err(e) => warn!("Failed to add pushed route to {}: {}", net, e),
+                    }
+                }
+                push::PushOption::Dns(server) => {
+                    info!("The server offers {} as DNS server.", server);
+                    dns.push(server);
+                }
+                push::PushOption::Mtu(mtu) => {
+                    let current = try!(tun.mtu());
+                    if mtu < current {
+                        info!("Lowering MTU of {} to {} as the server asks.", tun.name(), mtu);
+                        try!(tun.set_mtu(mtu));
+                    } else if mtu > current && mtu <= self.caps.mtu {
+                        // Never above what we offered in the handshake.
+                        info!("Raising MTU of {} to {} as the server asks.", tun.name(), mtu);
+                        try!(tun.set_mtu(mtu));
+                        raised = Some(mtu);
+                    }
+                }
+                push::PushOption::Banner(ref text) => info!("Server says: {}", text),
+                push::PushOption::Custom(ref key, ref value) => {
+                    debug!("Server pushed {} = {}.", key, value);
+                }
+                push::PushOption::Prefix(net, prefix) => {
+                    info!("The server delegated {}/{} for downstream networks.", net, prefix);
+                    self.delegated = Some((net, prefix));
+                }
+            }
+        }
+        // With the default route in the tunnel, the host's own DNS servers
+        // may no longer be reachable.
+        self.dns = None;
+        if !dns.is_empty() && self._gw.is_some() && cfg!(target_os = "macos") {
+            match utils::DnsOverride::create(&dns) {
+                Ok(guard) => self.dns = Some(guard),
+                Err(e) => warn!("Failed to set DNS servers {:?}: {}", dns, e),
+            }
+        }
+        self.pushed_dns = dns;
+        if let Some(mtu) = raised {
+            self.size_buffers(mtu);
+            if let Some(ref mut session) = self.session {
+                session.caps.mtu = mtu;
+            }
+        }
+        self.clamp_route_mtu();
+        Ok(())
+    }
+
+    /// Points the default route into the tunnel, in place of any earlier
+    /// one, which is restored first.
+    fn install_gateway(&mut self) -> Result<()> {
+        self._gw = None;
+        let remote = self.outer_addr().ip().to_string();
+        self._gw = Some(try!(utils::DefaultGateway::create(&self.subnet, &remote)));
+        self.clamp_route_mtu();
+        Ok(())
+    }
+
+    /// Puts the tunnel MTU on the routes into the tunnel, if asked to.
+    fn clamp_route_mtu(&self) {
+        if !self.route_mtu {
+            return;
+        }
+        let tun = match self.tun {
+            Some(ref tun) => tun,
+            None => return,
+        };
+        let mtu = match tun.mtu() {
+            Ok(mtu) => mtu,
+            Err(e) => {
+                warn!("Failed to query the MTU of {}: {}", tun.name(), e);
+                return;
+            }
+        };
+        if let Some(ref gw) = self._gw {
+            if let Err(e) = gw.set_mtu(&self.subnet, mtu) {
+                warn!("Failed to set the MTU of the default route: {}", e);
+            }
+        }
+        for route in &self.pushed_routes {
+            if let Err(e) = route.set_mtu(tun.name(), mtu) {
+                warn!("Failed to set the MTU of a pushed route: {}", e);
+            }
+        }
+    }
+
+    /// Describes the session in the session file, if there is one.
+    fn write_session_file(&self) {
+        let (path, session) = match (&self.session_file, self.session) {
+            (&Some(ref path), Some(session)) => (path, session),
+            _ => return,
+        };
+        let quote = |value: &fmt::Display| telemetry::quote(&value.to_string());
+        let device = self.tun.as_ref().map_or(String::from("null"), |tun| quote(&tun.name()));
+        let mtu = self.tun
+            .as_ref()
+            .and_then(|tun| tun.mtu().ok())
+            .unwrap_or(session.caps.mtu);
+        let dns: Vec<String> = self.pushed_dns.iter().map(|server| quote(server)).collect();
+        let prefix = self.delegated
+            .map_or(String::from("null"), |(net, len)| quote(&format!("{}/{}", net, len)));
+        let json = format!("{{\"id\":{},\"device\":{},\"address\":{},\"address6\":{},\
+                            \"prefix_len\":24,\"server\":{},\"mtu\":{},\"dns\":[{}],\
+                            \"delegated\":{}}}\n",
+                           session.id,
+                           device,
+                           quote(&self.subnet.addr(session.id)),
+                           quote(&self.subnet.ipv6_addr(session.id)),
+                           quote(&self.remote_addr),
+                           mtu,
+                           dns.join(","),
+                           prefix);
+        // Renamed into place so that readers never see half a file.
+        let mut partial = path.clone().into_os_string();
+        partial.push(".tmp");
+        let result = fs::File::create(&partial)
+            .and_then(|mut file| file.write_all(json.as_bytes()))
+            .and_then(|_| fs::rename(&partial, path));
+        if let Err(e) = result {
+            warn!("Failed to write session file {}: {}", path.display(), e);
+        }
+    }
+
+    /// Moves the tunnel over once the host has settled on a new network.
+    fn check_network(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let tun_name = self.tun.as_ref().map(|tun| String::from(tun.name()));
+        let changed = match self.watcher {
+            Some(ref mut watcher) => watcher.changed(now, tun_name.as_ref().map(|n| n.as_ref())),
+            None => false,
+        };
+        if !changed {
+            return Ok(());
+        }
+        info!("Host network changed. Moving the tunnel over.");
+        self.note("Host network changed.");
+
+        // Answers from the old network's resolver may not hold here.
+        self.resolver.flush();
+        self.re_resolve();
+        self.follow_family();
+        try!(self.rebind_socket());
+
+        if self._gw.is_some() {
+            // Restore the old routes first so that the new default gateway is
+            // the host's and not the tunnel.
+            try!(self.install_gateway());
+            info!("Routes reinstalled.");
+        }
+
+        let roam = match self.session {
+            Some(session) => self.roam_message(session),
+            None => None,
+        };
+        match roam {
+            Some(msg) => self.send_session(&msg),
+            None => {
+                // The server only moves a session for the holder of its
+                // roaming key, so without one start a new session instead.
+                if self.session.take().is_some() {
+                    info!("No roaming key agreed with the server. Reconnecting.");
+                }
+                self.attempt = 0;
+                self.deadline = now;
+                Ok(())
+            }
+        }
+    }
+
+    /// Looks the current server's name up again. True if its address changed.
+    fn re_resolve(&mut self) -> bool {
+        let now = self.clock.now();
+        self.resolved = Some(now);
+        let host = self.servers[self.current].0.clone();
+        match self.resolver.resolve(&host, now) {
+            Ok(ip) if ip != self.remote_addr.ip() => {
+                info!("{} now resolves to {}.", host, ip);
+                self.remote_addr = SocketAddr::new(ip, self.remote_addr.port());
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                warn!("Failed to re-resolve {}: {}", host, e);
+                false
+            }
+        }
+    }
+
+    /// Follows the server to a new address when its name resolves
+    /// differently, checking every `DNS_REFRESH` seconds and before
+    /// handshaking again.
+    fn check_dns(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let due = self.resolved
+            .map_or(true, |at| now.duration_since(at) >= Duration::from_secs(DNS_REFRESH));
+        if !due || self.needs_probe || self.probe.is_some() || !self.re_resolve() {
+            return Ok(());
+        }
+        if self._gw.is_some() {
+            try!(self.install_gateway());
+        }
+        if !self.follow_family() {
+            return Ok(());
+        }
+        try!(self.rebind_socket());
+        let roam = match self.session {
+            Some(session) => self.roam_message(session),
+            None => return Ok(()),
+        };
+        match roam {
+            Some(msg) => self.send_session(&msg),
+            None => {
+                info!("No roaming key agreed with the server. Reconnecting.");
+                self.session = None;
+                self.attempt = 0;
+                self.deadline = now;
+                Ok(())
+            }
+        }
+    }
+
+    /// Moves the socket to the address family of the server or first relay,
+    /// unless the local address was given. True if it must be rebound.
+    fn follow_family(&mut self) -> bool {
+        let outer = self.outer_addr().ip();
+        if self.fixed_local_ip || self.local_ip.is_ipv4() == outer.is_ipv4() {
+            return false;
+        }
+        self.local_ip = unspecified(outer);
+        true
+    }
+
+    /// Replaces the tunnel socket with one on a fresh local port.
+    fn rebind_socket(&mut self) -> Result<()> {
+        let sockfd = try!(bind_outer(self.local_ip, &self.outer));
+        try!(self.poll.deregister(&self.sockfd));
+        try!(self.poll.register(&sockfd, SOCK, mio::Ready::readable(), mio::PollOpt::level()));
+        self.sockfd = sockfd;
+        self.local_addr = try!(self.sockfd.local_addr());
+        if let Some(ref mut dscp) = self.dscp {
+            *dscp = DscpMarker::new();
+        }
+        info!("Tunnel socket rebound to {}.", self.local_addr);
+        Ok(())
+    }
+
+    /// Changes the local port when the rebind interval is up.
+    fn rebind_if_due(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let (interval, due) = match self.rebind {
+            Some(rebind) => rebind,
+            None => return Ok(()),
+        };
+        let session = match self.session {
+            Some(session) if now >= due => session,
+            _ => return Ok(()),
+        };
+        self.rebind = Some((interval, now + interval));
+        let roam = match self.roam_message(session) {
+            Some(roam) => roam,
+            None => {
+                debug!("No roaming key agreed with the server. Keeping the local port.");
+                return Ok(());
+            }
+        };
+        try!(self.rebind_socket());
+        let note = format!("Rebound the socket to {}.", self.local_addr);
+        self.note(&note);
+        self.send_session(&roam)
+    }
+
+    /// The `Roam` that moves `session` to our current address, or `None` if
+    /// the server agreed on no roaming key.
+    fn roam_message(&mut self, session: Session) -> Option<Message> {
+        self.roamer.as_mut().map(|roamer| {
+            let (sequence, mac) = roamer.roam(session.id, session.token);
+            Message::Roam {
+                id: session.id,
+                token: session.token,
+                sequence: sequence,
+                mac: mac,
+            }
+        })
+    }
+
+    /// Drops `session` and handshakes again, asking for the same address.
+    fn renew(&mut self, session: Session, reason: &str) {
+        self.end_session_span(reason);
+        self.note(&format!("Renewing session {}: {}.", session.id, reason));
+        self.session = None;
+        if let Some(ref mut proxy) = self.proxy {
+            // The server forgets the streams with the session.
+            proxy.reset(&self.poll);
+        }
+        self.resume = Some((session.id, session.token));
+        self.resolved = None;
+        self.attempt = 0;
+        self.deadline = self.clock.now();
+    }
+
+    fn handle_socket(&mut self) -> Result<()> {
+        let (mut len, mut addr) = match try!(self.sockfd
+            .recv_from(&mut self.sock_buf)) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        if len == self.sock_buf.len() {
+            warn!("Dropping oversized datagram from {}.", addr);
+            self.counters.error();
+            return Ok(());
+        }
+        if self.probe.is_some() {
+            return self.handle_probe(addr, len);
+        }
+        if addr != self.outer_addr() && Some(addr) != self.session_addr() {
+            warn!("Message from unknown endpoint {}. Expected: {}",
+                  addr,
+                  self.outer_addr());
+            return Ok(());
+        }
+        if !self.relays.is_empty() {
+            match relay::unwrap(&self.relays, self.remote_addr, &self.sock_buf[0..len]) {
+                Ok(frame) => {
+                    len = frame.len();
+                    self.sock_buf[0..len].copy_from_slice(&frame);
+                    addr = self.remote_addr;
+                }
+                Err(e) => {
+                    warn!("Bad relayed frame from {}: {}", addr, e);
+                    self.counters.error();
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(ref mut capture) = self.capture {
+            capture.outer(&addr, &self.local_addr, &self.sock_buf[0..len]);
+        }
+        self.last_heard = self.clock.now();
+        self.probes_sent = 0;
+        let msg: Message = match decode_message(&self.sock_buf[0..len]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Undecodable message from {}: {}", addr, e);
+                self.counters.error();
+                self.note(&format!("Undecodable message from {}: {}", addr, e));
+                return Ok(());
+            }
+        };
+        if let Some(ref mut transcript) = self.transcript {
+            transcript.received(&addr, &msg);
+        }
+        // A raw frame is a data frame whose payload skipped compression.
+        let (msg, raw) = match msg {
+            Message::RawData { id, token, data } => {
+                (Message::Data {
+                    id: id,
+                    token: token,
+                    data: data,
+                },
+                 true)
+            }
+            msg => (msg, false),
+        };
+        match msg {
+            Message::RawData { .. } => unreachable!(),
+            Message::Request { .. } |
+            Message::Stamped { .. } |
+            Message::Identified { .. } |
+            Message::Relay { .. } |
+            Message::Roam { .. } |
+            Message::StreamOpen { .. } |
+            Message::Probe { .. } => {
+                warn!("Invalid message {:?} from {}", msg, addr);
+            }
+            Message::StreamReply { token, stream, error, .. } => {
+                if self.valid_stream_token(token) {
+                    let now = self.clock.now();
+                    let out = match self.proxy {
+                        Some(ref mut proxy) => proxy.reply(&self.poll, stream, error, now),
+                        None => Vec::new(),
+                    };
+                    try!(self.send_streams(out));
+                }
+            }
+            Message::Segment { token, segment, .. } => {
+                if self.valid_stream_token(token) {
+                    let now = self.clock.now();
+                    let out = match self.proxy {
+                        Some(ref mut proxy) => proxy.segment(&self.poll, segment, now),
+                        None => Vec::new(),
+                    };
+                    try!(self.send_streams(out));
+                }
+            }
+            Message::StreamClose { token, stream, .. } => {
+                if self.valid_stream_token(token) {
+                    if let Some(ref mut proxy) = self.proxy {
+                        proxy.close(&self.poll, stream);
+                    }
+                }
+            }
+            Message::Pong { id, .. } => {
+                debug!("Server answered liveness probe for {}.", id);
+                if let Some(sent) = self.ping_sent.take() {
+                    let rtt = self.clock.now().duration_since(sent);
+                    self.rtt.sample(rtt);
+                    self.latency.record(rtt);
+                }
+            }
+            Message::Ping { id, token } => {
+                match self.session {
+                    Some(session) if session.id == id && session.token == token => {
+                        debug!("Answering liveness probe from {}.", addr);
+                        try!(self.send_session(&Message::Pong {
+                            id: id,
+                            token: token,
+                        }));
+                    }
+                    _ => warn!("Probe for unknown session {} from {}.", id, addr),
+                }
+            }
+            Message::Cover { .. } => trace_packet!("sock len={} cover", len),
+            Message::Expired { id, token } => {
+                match self.session {
+                    Some(session) if session.id == id && session.token == token => {
+                        warn!("Server expired session {}. Re-establishing.", id);
+                        self.renew(session, "expired");
+                    }
+                    _ => warn!("Expiry notice for unknown session {} from {}.", id, addr),
+                }
+            }
+            Message::Refused { code } => {
+                if self.session.is_some() {
+                    warn!("Unexpected refusal from {}", addr);
+                    return Ok(());
+                }
+                let mut span = self.handshake_span.take();
+                if let Some(ref mut span) = span {
+                    span.fail(code.as_str());
+                }
+                self.end_span(span);
+                self.failures += 1;
+                self.failure = Some(code);
+                let reason = match code {
+                    Code::AuthFailed => "did not accept the credential",
+                    Code::PoolExhausted => "has no address left",
+                    Code::VersionMismatch => "needs a newer protocol version",
+                    _ => "takes no new sessions",
+                };
+                let exhausted = self.failures >= self.servers.len();
+                if exhausted && self.handshake_attempts > 0 {
+                    return Err(Error::Failure(code, format!("{} {}", self.remote_addr, reason)));
+                }
+                warn!("[{}] {} {}. Trying another server.",
+                      code,
+                      self.remote_addr,
+                      reason);
+                try!(self.fail_over());
+                if exhausted {
+                    // Keeping on trying, but not at once.
+                    self.deadline = self.clock.now() + self.timeout;
+                }
+            }
+            Message::Response { id, token, caps, ref roam_key } => {
+                if self.session.is_some() {
+                    warn!("Unexpected response {:?} from {}", msg, addr);
+                } else {
+                    self.roamer = self.offer.take().and_then(|offer| offer.accept(roam_key));
+                    try!(self.establish(id, token, caps, device::Subnet::default()));
+                    self.write_session_file();
+                }
+            }
+            Message::Assigned { id, token, caps, subnet, ref roam_key } => {
+                if self.session.is_some() {
+                    warn!("Unexpected response {:?} from {}", msg, addr);
+                } else {
+                    self.roamer = self.offer.take().and_then(|offer| offer.accept(roam_key));
+                    try!(self.establish(id, token, caps, subnet));
+                    self.write_session_file();
+                }
+            }
+            Message::Configured { id, token, caps, ref options, ref roam_key } => {
+                let current = self.session.map(|session| (session.id, session.token));
+                if current == Some((id, token)) {
+                    // The server changed what it pushes while connected.
+                    info!("The server updated its options.");
+                    try!(self.apply(&push::decode_all(options)));
+                } else if self.session.is_some() {
+                    warn!("Unexpected response {:?} from {}", msg, addr);
+                } else {
+                    let options = push::decode_all(options);
+                    let subnet = options.iter()
+                        .filter_map(|option| match *option {
+                            push::PushOption::Address(addr, 24) => {
+                                let o = addr.octets();
+                                Some(device::Subnet([o[0], o[1], o[2]]))
+                            }
+                            _ => None,
+                        })
+                        .next()
+                        .unwrap_or_default();
+                    self.roamer = self.offer.take().and_then(|offer| offer.accept(roam_key));
+                    try!(self.establish(id, token, caps, subnet));
+                    try!(self.apply(&options));
+                    self.write_session_file();
+                }
+            }
+            Message::Data { id: _, token, data } => {
+                try!(self.receive_data(addr, token, data, raw));
+            }
+            Message::Batch { id: _, token, packets } => {
+                trace_packet!("sock len={} batch of {}", len, packets.len());
+                for data in packets {
+                    try!(self.receive_data(addr, token, data, true));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the payload of a data frame to the TUN device. `raw` frames
+    /// skipped compression.
+    fn receive_data(&mut self,
+                    addr: SocketAddr,
+                    server_token: Token,
+                    data: Vec<u8>,
+                    raw: bool)
+                    -> Result<()> {
+        let (tun, session) = match (self.tun.as_mut(), self.session) {
+            (Some(tun), Some(session)) => (tun, session),
+            _ => {
+                warn!("Data from {} before session is established.", addr);
+                trace_packet!("sock->tun compressed={} dropped: no session",
+                              data.len());
+                return Ok(());
+            }
+        };
+        if session.token == server_token {
+            let _compressed_len = data.len();
+            let mut decompressed_data = if session.caps.has(CAP_SNAPPY) && !raw {
+                match decompress(&data) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("Undecompressable data from {}: {}", addr, e);
+                        self.counters.error();
+                        trace_packet!("sock->tun compressed={} dropped: {}",
+                                      data.len(),
+                                      e);
+                        return Ok(());
+                    }
+                }
+            } else {
+                data
+            };
+            self.counters.rx(decompressed_data.len());
+            if let Some(drop) = self.verify_checksums {
+                if let Some(what) = packet::bad_checksum(&decompressed_data) {
+                    warn!("Packet from {} fails {} checksum.", addr, what);
+                    self.counters.corrupt();
+                    if drop {
+                        trace_packet!("sock->tun len={} dropped: corrupt",
+                                      decompressed_data.len());
+                        return Ok(());
+                    }
+                }
+            }
+            if let Some(mtu) = self.clamp_mss {
+                packet::clamp_mss(&mut decompressed_data, mtu);
+            }
+            if let Some(ref mut capture) = self.capture {
+                capture.inner(&decompressed_data);
+            }
+            let data_len = decompressed_data.len();
+            let mut sent_len = 0;
+            while sent_len < data_len {
+                match tun.write(&decompressed_data[sent_len..data_len]) {
+                    Ok(len) => sent_len += len,
+                    Err(e) => {
+                        trace_packet!("sock->tun len={} dropped: {}", data_len, e);
+                        self.counters.error();
+                        return Ok(());
+                    }
+                }
+            }
+            trace_packet!("sock->tun compressed={} len={} forwarded",
+                          _compressed_len,
+                          data_len);
+        } else {
+            warn!("Token mismatched. Received: {}. Expected: {}",
+                  server_token,
+                  session.token);
+            self.counters.error();
+            trace_packet!("sock->tun compressed={} dropped: token mismatch",
+                          data.len());
+        }
+        Ok(())
+    }
+
+    /// Whether stream traffic carries the current session's token.
+    fn valid_stream_token(&mut self, token: Token) -> bool {
+        match self.session {
+            Some(session) if session.token == token => true,
+            Some(session) => {
+                warn!("Token mismatched. Received: {}. Expected: {}",
+                      token,
+                      session.token);
+                self.counters.error();
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn handle_proxy(&mut self, token: mio::Token) -> Result<()> {
+        let now = self.clock.now();
+        let out = match self.proxy {
+            Some(ref mut proxy) => proxy.ready(&self.poll, token, now),
+            None => return Ok(()),
+        };
+        self.send_streams(out)
+    }
+
+    /// Resends what the server has not acknowledged.
+    fn tick_proxy(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let out = match self.proxy {
+            Some(ref mut proxy) => proxy.tick(&self.poll, now),
+            None => return Ok(()),
+        };
+        self.send_streams(out)
+    }
+
+    /// Sends stream traffic, or drops it without a session; it is
+    /// retransmitted once there is one.
+    fn send_streams(&mut self, out: Vec<stream::Outgoing>) -> Result<()> {
+        let session = match self.session {
+            Some(session) if session.caps.has(CAP_STREAMS) => session,
+            _ => return Ok(()),
+        };
+        for out in out {
+            try!(self.send(&stream_message(session.id, session.token, out)));
+        }
+        Ok(())
+    }
+
+    /// Handles a datagram of the tunnel itself coming out of the TUN device,
+    /// which means the route to the server now points into the tunnel.
+    /// Restores the host route, and stops the client if that does not help
+    /// rather than feeding the tunnel its own traffic.
+    fn routing_loop(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        if let Some(last) = self.last_loop {
+            let since = now.duration_since(last);
+            if since < Duration::from_secs(LOOP_HOLDOFF) {
+                return Ok(());
+            }
+            if since >= Duration::from_secs(LOOP_WINDOW) {
+                self.loop_repairs = 0;
+            }
+        }
+        self.last_loop = Some(now);
+        self.loop_repairs += 1;
+        if self.loop_repairs > LOOP_REPAIRS {
+            return Err(Error::Route(format!("routing loop: traffic to the server {} keeps \
+                                             entering the tunnel",
+                                            self.remote_addr)));
+        }
+        match self._gw {
+            Some(ref gw) => {
+                warn!("Traffic to the server {} is routed into the tunnel. Restoring its \
+                       host route.",
+                      self.remote_addr);
+                if let Err(e) = gw.repair() {
+                    warn!("Failed to restore host route to {}: {}", self.remote_addr, e);
+                }
+            }
+            None => {
+                warn!("Traffic to the server {} is routed into the tunnel. Route it outside \
+                       the tunnel.",
+                      self.remote_addr)
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_tun(&mut self) -> Result<()> {
+        let outer = self.outer_addr();
+        let (tun, session) = match (self.tun.as_mut(), self.session) {
+            (Some(tun), Some(session)) => (tun, session),
+            (Some(tun), None) => {
+                // Drain the device while re-handshaking; there is nowhere
+                // to send the packet yet.
+                let _len = tun.read(&mut self.tun_buf);
+                trace_packet!("tun->sock len={:?} dropped: no session", _len);
+                return Ok(());
+            }
+            _ => unreachable!(),
+        };
+        let len: usize = try!(tun.read(&mut self.tun_buf));
+        if len == 0 {
+            // The device swallowed the frame, such as ARP on a TAP device.
+            return Ok(());
+        }
+        if len == self.tun_buf.len() {
+            warn!("Dropping truncated packet from TUN (MTU {}).",
+                  self.tun_buf.len() - 1);
+            trace_packet!("tun->sock len={} dropped: truncated", len);
+            return Ok(());
+        }
+        let data = &mut self.tun_buf[0..len];
+        let looped = packet::parse_udp(data).map(|(_, dst, _)| dst == outer);
+        if looped.unwrap_or(false) {
+            trace_packet!("tun->sock len={} dropped: routing loop", len);
+            return self.routing_loop();
+        }
+        if let Some(mtu) = self.clamp_mss {
+            packet::clamp_mss(data, mtu);
+        }
+        if let Some(ref mut capture) = self.capture {
+            capture.inner(data);
+        }
+        let (msg, compressed) = try!(data_message(&mut self.encoder,
+                                                  session.id,
+                                                  session.token,
+                                                  &session.caps,
+                                                  self.compression_threshold,
+                                                  data));
+        trace_packet!("tun->sock id={} len={} compressed={} forwarded",
+                      session.id,
+                      len,
+                      compressed);
+        if let Some(ref mut dscp) = self.dscp {
+            try!(dscp.mark(&self.sockfd, data));
+        }
+        self.counters.tx(len);
+        self.counters.frame(compressed);
+        #[cfg(feature = "obfuscation")]
+        {
+            if let Some(ref mut cover) = self.cover {
+                cover.sent();
+            }
+        }
+        self.send(&msg)
+    }
+}
+
+/// The first server from `start` on, wrapping around, whose name resolves.
+fn resolve_next(resolver: &mut resolver::Resolver,
+                servers: &[(String, u16)],
+                start: usize,
+                now: Instant)
+                -> Option<(usize, SocketAddr)> {
+    for i in 0..servers.len() {
+        let index = (start + i) % servers.len();
+        let (ref host, port) = servers[index];
+        match resolver.resolve(host, now) {
+            Ok(ip) => return Some((index, SocketAddr::new(ip, port))),
+            Err(e) => warn!("Failed to resolve {}: {}", host, e),
+        }
+    }
+    None
+}
+
+/// The any-address of `ip`'s family, to bind to.
+fn unspecified(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+    }
+}
+
+/// Opens the outer UDP socket on an ephemeral port.
+fn bind_outer(ip: IpAddr, outer: &OuterOptions) -> Result<mio::udp::UdpSocket> {
+    let sockfd = try!(mio::udp::UdpSocket::bind(&SocketAddr::new(ip, 0)));
+    try!(configure_outer(&sockfd, outer));
+    Ok(sockfd)
+}
+
+#[test]
+fn client_builder_test() {
+    assert!(Client::builder().build().is_err());
+}
@@ -0,0 +1,120 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Time sources for session expiry and handshake retransmission.
+//!
+//! Clients and servers read the time only through a `Clock`, so tests can
+//! substitute a `ManualClock` and step through timeouts without sleeping.
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock. Used unless a builder is given another one.
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Clone)]
+pub struct ManualClock {
+    base: Instant,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            base: Instant::now(),
+            offset: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+}
+
+/// Notices that the host was suspended. The monotonic clock stands still
+/// during suspend while the wall clock does not, so the gap between the two
+/// grows by the time spent asleep.
+pub struct SleepDetector {
+    threshold: Duration,
+    last: Option<(Instant, SystemTime)>,
+}
+
+impl SleepDetector {
+    /// Reports sleeps longer than `threshold`, which should be well above
+    /// any wall-clock step NTP would make.
+    pub fn new(threshold: Duration) -> SleepDetector {
+        SleepDetector {
+            threshold: threshold,
+            last: None,
+        }
+    }
+
+    /// How long the host slept since the previous call, if noticeably.
+    pub fn check(&mut self, now: Instant, wall: SystemTime) -> Option<Duration> {
+        let last = mem::replace(&mut self.last, Some((now, wall)));
+        let (then, then_wall) = match last {
+            Some(last) => last,
+            None => return None,
+        };
+        let elapsed = now.duration_since(then);
+        match wall.duration_since(then_wall) {
+            Ok(wall_elapsed) if wall_elapsed > elapsed + self.threshold => {
+                Some(wall_elapsed - elapsed)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn manual_clock_test() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    assert_eq!(clock.now(), start);
+    clock.clone().advance(Duration::from_secs(61));
+    assert_eq!(clock.now() - start, Duration::from_secs(61));
+}
+
+#[test]
+fn sleep_detector_test() {
+    let mut detector = SleepDetector::new(Duration::from_secs(30));
+    let now = Instant::now();
+    let wall = SystemTime::now();
+    assert_eq!(detector.check(now, wall), None);
+    let now = now + Duration::from_secs(5);
+    let wall = wall + Duration::from_secs(6);
+    assert_eq!(detector.check(now, wall), None);
+    let now = now + Duration::from_secs(1);
+    let wall = wall + Duration::from_secs(3601);
+    assert_eq!(detector.check(now, wall), Some(Duration::from_secs(3600)));
+}
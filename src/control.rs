@@ -0,0 +1,102 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Control socket of a running client or server.
+//!
+//! `kytan status` and `kytan disconnect` talk to a client in the background,
+//! and `kytan bans`, `kytan unban`, `kytan traffic`, `kytan latency`,
+//! `kytan clients`, `kytan refusals`, `kytan acl`, `kytan sources`,
+//! `kytan rate`, `kytan egress` and `kytan tun` to a server, over a Unix socket: one
+//! command per connection, answered with text.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use mio;
+use error::Result;
+
+/// Where the client listens unless told otherwise.
+pub const DEFAULT_PATH: &'static str = "/var/run/kytan/client.sock";
+/// Where the server listens unless told otherwise.
+pub const SERVER_PATH: &'static str = "/var/run/kytan/server.sock";
+
+/// How long a controller may take to send its command.
+const READ_TIMEOUT: u64 = 1;
+
+pub struct Listener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Listener {
+    /// Listens on `path`, replacing a socket left behind by a dead client.
+    /// Only root may connect.
+    pub fn open(poll: &mio::Poll, token: mio::Token, path: &Path) -> Result<Listener> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(::error::Error::Config(format!("{} is in use by another client",
+                                                      path.display())));
+        }
+        let _ = fs::remove_file(path);
+        let listener = try!(UnixListener::bind(path));
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+        try!(listener.set_nonblocking(true));
+        try!(poll.register(&mio::unix::EventedFd(&listener.as_raw_fd()),
+                           token,
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+        Ok(Listener {
+            listener: listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Pending connections with the command each sent.
+    pub fn accept(&self) -> Vec<(UnixStream, String)> {
+        let mut requests = Vec::new();
+        while let Ok((stream, _)) = self.listener.accept() {
+            let mut command = String::new();
+            let read = stream.set_nonblocking(false)
+                .and_then(|_| stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT))))
+                .and_then(|_| BufReader::new(&stream).read_line(&mut command));
+            match read {
+                Ok(_) => requests.push((stream, String::from(command.trim()))),
+                Err(e) => debug!("Dropping control connection: {}", e),
+            }
+        }
+        requests
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Sends `command` to the client listening on `path` and returns its answer.
+pub fn request(path: &Path, command: &str) -> Result<String> {
+    let mut stream = try!(UnixStream::connect(path));
+    try!(stream.write_all(format!("{}\n", command).as_bytes()));
+    let mut reply = String::new();
+    try!(stream.read_to_string(&mut reply));
+    Ok(reply)
+}
@@ -0,0 +1,106 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Constant-rate cover traffic.
+//!
+//! When enabled, the client sends a frame every interval: real traffic when
+//! there is some, otherwise a `Cover` frame of a fixed size filled with
+//! random bytes. The server answers each `Cover` frame with one of its own
+//! unless it sent the client data since the last one. An observer of the
+//! outer link then sees a floor of steady traffic instead of bursts that
+//! follow the user's activity. Busy periods still show above the floor.
+
+use std::time::{Duration, Instant};
+use rand::{self, Rng};
+
+/// Bytes of a `Cover` frame other than its padding: the variant index, id,
+/// token and padding length.
+pub const OVERHEAD: usize = 4 + 1 + 8 + 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub interval: Duration,
+    /// Size of each cover frame on the wire, before the UDP header.
+    pub size: usize,
+}
+
+pub struct Cover {
+    config: Config,
+    next: Instant,
+    // Whether real traffic went out in the current interval.
+    busy: bool,
+}
+
+/// Random padding that makes a `Cover` frame `size` bytes long.
+pub fn padding(size: usize) -> Vec<u8> {
+    let mut padding = vec![0u8; size.saturating_sub(OVERHEAD)];
+    rand::thread_rng().fill_bytes(&mut padding);
+    padding
+}
+
+impl Cover {
+    pub fn new(config: Config, now: Instant) -> Cover {
+        Cover {
+            config: config,
+            next: now + config.interval,
+            busy: false,
+        }
+    }
+
+    /// Notes that real traffic went out.
+    pub fn sent(&mut self) {
+        self.busy = true;
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// The padding of a cover frame to send now, if an interval ended
+    /// without real traffic.
+    pub fn due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if now < self.next {
+            return None;
+        }
+        self.next = now + self.config.interval;
+        let idle = !self.busy;
+        self.busy = false;
+        if idle { Some(padding(self.config.size)) } else { None }
+    }
+}
+
+#[test]
+fn cover_test() {
+    use network::{encode_message, Message};
+    let start = Instant::now();
+    let interval = Duration::from_millis(100);
+    let mut cover = Cover::new(Config {
+                                   interval: interval,
+                                   size: 500,
+                               },
+                               start);
+    assert!(cover.due(start).is_none());
+    let padding = cover.due(start + interval).unwrap();
+    let frame = encode_message(&Message::Cover {
+            id: 2,
+            token: 7,
+            padding: padding,
+        })
+        .unwrap();
+    assert_eq!(frame.len(), 500);
+    cover.sent();
+    assert!(cover.due(start + interval * 2).is_none());
+    assert!(cover.due(start + interval * 3).is_some());
+}
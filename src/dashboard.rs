@@ -0,0 +1,168 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Web dashboard of a running server.
+//!
+//! A page on a localhost port lists the connected clients, graphs the
+//! tunnel throughput and shows recent events, polling `/status` for the
+//! data. Lifting bans with `POST /unban?ip=ADDR` (or `ip=all`) goes through
+//! the same commands as the control socket and is only allowed when the
+//! dashboard is writable.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use mio;
+use network::Event;
+use telemetry::quote;
+use error::Result;
+
+/// How long a browser may take to send its request.
+const READ_TIMEOUT: u64 = 1;
+/// Events kept for the page.
+const EVENTS: usize = 50;
+
+pub const PAGE: &'static str = include_str!("dashboard.html");
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Page,
+    Status,
+    /// A control socket command.
+    Command(String),
+    Forbidden,
+    NotFound,
+}
+
+/// Maps a request line such as `GET /status HTTP/1.1` to what it asks for.
+fn parse(line: &str, writable: bool) -> Request {
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Request::NotFound,
+    };
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap();
+    let query = target.next().unwrap_or("");
+    match (method, path) {
+        ("GET", "/") => Request::Page,
+        ("GET", "/status") => Request::Status,
+        ("POST", "/unban") if !writable => Request::Forbidden,
+        ("POST", "/unban") => {
+            match query.split('&').find(|pair| pair.starts_with("ip=")) {
+                Some(pair) => Request::Command(format!("unban {}", &pair[3..])),
+                None => Request::NotFound,
+            }
+        }
+        _ => Request::NotFound,
+    }
+}
+
+pub struct Dashboard {
+    listener: TcpListener,
+    writable: bool,
+    // Seconds since the epoch and description, oldest first.
+    events: VecDeque<(u64, String)>,
+}
+
+impl Dashboard {
+    pub fn open(poll: &mio::Poll,
+                token: mio::Token,
+                addr: &SocketAddr,
+                writable: bool)
+                -> Result<Dashboard> {
+        let listener = try!(TcpListener::bind(addr));
+        try!(listener.set_nonblocking(true));
+        try!(poll.register(&mio::unix::EventedFd(&listener.as_raw_fd()),
+                           token,
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+        info!("Serving the dashboard on http://{}/.", addr);
+        Ok(Dashboard {
+            listener: listener,
+            writable: writable,
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Pending connections with what each asked for.
+    pub fn accept(&self) -> Vec<(TcpStream, Request)> {
+        let mut requests = Vec::new();
+        while let Ok((stream, addr)) = self.listener.accept() {
+            let mut line = String::new();
+            let read = stream.set_nonblocking(false)
+                .and_then(|_| stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT))))
+                .and_then(|_| BufReader::new(&stream).read_line(&mut line));
+            match read {
+                Ok(_) => requests.push((stream, parse(&line, self.writable))),
+                Err(e) => debug!("Dropping dashboard connection from {}: {}", addr, e),
+            }
+        }
+        requests
+    }
+
+    pub fn record(&mut self, event: &Event) {
+        let description = match *event {
+            Event::ClientConnected { id, addr } => {
+                format!("Client {} connected from {}.", id, addr)
+            }
+            Event::ClientRoamed { id, addr } => format!("Client {} moved to {}.", id, addr),
+            Event::ClientExpired { id } => format!("Session of client {} expired.", id),
+            _ => return,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if self.events.len() == EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back((now, description));
+    }
+
+    /// The recent events as a JSON array.
+    pub fn events_json(&self) -> String {
+        let events: Vec<String> = self.events
+            .iter()
+            .map(|&(time, ref text)| format!("{{\"time\":{},\"text\":{}}}", time, quote(text)))
+            .collect();
+        format!("[{}]", events.join(","))
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+}
+
+pub fn reply(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    let response = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                            Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+                           status,
+                           content_type,
+                           body.len(),
+                           body);
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        debug!("Failed to answer dashboard request: {}", e);
+    }
+}
+
+#[test]
+fn parse_test() {
+    assert_eq!(parse("GET / HTTP/1.1\r\n", false), Request::Page);
+    assert_eq!(parse("GET /status?t=1 HTTP/1.1\r\n", false), Request::Status);
+    assert_eq!(parse("POST /unban?ip=192.0.2.1 HTTP/1.1\r\n", false), Request::Forbidden);
+    assert_eq!(parse("POST /unban?ip=192.0.2.1 HTTP/1.1\r\n", true),
+               Request::Command(String::from("unban 192.0.2.1")));
+    assert_eq!(parse("GET /etc/passwd HTTP/1.1\r\n", true), Request::NotFound);
+    assert_eq!(parse("", true), Request::NotFound);
+}
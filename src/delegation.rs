@@ -0,0 +1,116 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! IPv6 prefix delegation: a routed prefix per client, carved out of a pool,
+//! that the client hands on to its own downstream networks.
+//!
+//! The prefix of client `id` is the pool's address with `id` in the eight
+//! bits just above the delegated length, so a /56 pool yields a /64 per
+//! client. The server routes the whole pool into the TUN device and sends
+//! packets for a delegated prefix to its client.
+
+use std::net::{IpAddr, Ipv6Addr};
+use cidr::Cidr;
+use network::Id;
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delegation {
+    pool: Ipv6Addr,
+    pool_prefix: u8,
+    prefix: u8,
+}
+
+impl Delegation {
+    /// Delegates prefixes of length `prefix` from `pool`, which must leave
+    /// room for 256 of them.
+    pub fn new(pool: Cidr, prefix: u8) -> Result<Delegation> {
+        let addr = match pool.addr {
+            IpAddr::V6(addr) => addr,
+            IpAddr::V4(_) => {
+                return Err(Error::Config(format!("delegation pool {} is not IPv6", pool)))
+            }
+        };
+        if prefix > 128 || pool.prefix + 8 > prefix {
+            return Err(Error::Config(format!("{} has no room for 256 /{} prefixes", pool, prefix)));
+        }
+        Ok(Delegation {
+            pool: addr,
+            pool_prefix: pool.prefix,
+            prefix: prefix,
+        })
+    }
+
+    pub fn pool(&self) -> Cidr {
+        Cidr {
+            addr: IpAddr::V6(self.pool),
+            prefix: self.pool_prefix,
+        }
+    }
+
+    /// The prefix delegated to `id`.
+    pub fn prefix_of(&self, id: Id) -> (Ipv6Addr, u8) {
+        let mut octets = self.pool.octets();
+        // Clear the host part, then write the id above it.
+        for bit in self.pool_prefix as usize..128 {
+            octets[bit / 8] &= !(0x80 >> (bit % 8));
+        }
+        for i in 0..8 {
+            if id & (1 << i) != 0 {
+                let bit = self.prefix as usize - 1 - i;
+                octets[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+        (Ipv6Addr::from(octets), self.prefix)
+    }
+
+    /// The client whose delegated prefix holds `addr`.
+    pub fn owner(&self, addr: Ipv6Addr) -> Option<Id> {
+        if !self.pool().contains(IpAddr::V6(addr)) {
+            return None;
+        }
+        let octets = addr.octets();
+        let mut id = 0;
+        for i in 0..8 {
+            let bit = self.prefix as usize - 1 - i;
+            if octets[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+                id |= 1 << i;
+            }
+        }
+        // Addresses between the delegated prefixes belong to no one.
+        for bit in self.pool_prefix as usize..self.prefix as usize - 8 {
+            if octets[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+                return None;
+            }
+        }
+        Some(id)
+    }
+}
+
+#[test]
+fn delegation_test() {
+    let delegation = Delegation::new("2001:db8:100::/56".parse().unwrap(), 64).unwrap();
+    assert_eq!(delegation.prefix_of(5), ("2001:db8:100:5::".parse().unwrap(), 64));
+    assert_eq!(delegation.prefix_of(255), ("2001:db8:100:ff::".parse().unwrap(), 64));
+    assert_eq!(delegation.owner("2001:db8:100:5::1".parse().unwrap()), Some(5));
+    assert_eq!(delegation.owner("2001:db8:101:5::1".parse().unwrap()), None);
+
+    let wide = Delegation::new("2001:db8::/48".parse().unwrap(), 64).unwrap();
+    assert_eq!(wide.prefix_of(2), ("2001:db8:0:2::".parse().unwrap(), 64));
+    assert_eq!(wide.owner("2001:db8:0:2::9".parse().unwrap()), Some(2));
+    assert_eq!(wide.owner("2001:db8:0:102::9".parse().unwrap()), None);
+
+    assert!(Delegation::new("2001:db8::/60".parse().unwrap(), 64).is_err());
+    assert!(Delegation::new("10.0.0.0/8".parse().unwrap(), 24).is_err());
+}
@@ -12,13 +12,88 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fs, process, io};
+use std::{fmt, fs, io};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 use libc;
 use libc::c_ulong;
 use std::os::unix::io::{RawFd, AsRawFd};
 use std::io::{Write, Read};
+use std::time::Instant;
+use error::{Error, Result};
+use neighbour::{self, ETHERNET_HEADER};
+#[cfg(target_os = "linux")]
+use netlink;
+#[cfg(not(target_os = "linux"))]
+use std::process;
+
+pub const MTU: u16 = 1380;
+pub const IPV6_PREFIX: &'static str = "fd10:10:10::";
+
+/// The /24 tunnel addresses come from; a client's id is the last octet of
+/// its address. Each subnet has a /64 of unique local IPv6 addresses to
+/// match, `IPV6_PREFIX` for the default 10.10.10.0/24.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Subnet(pub [u8; 3]);
+
+impl Default for Subnet {
+    fn default() -> Subnet {
+        Subnet([10, 10, 10])
+    }
+}
+
+impl Subnet {
+    pub fn addr(&self, id: u8) -> Ipv4Addr {
+        Ipv4Addr::new(self.0[0], self.0[1], self.0[2], id)
+    }
+
+    pub fn ipv6_prefix(&self) -> String {
+        if *self == Subnet::default() {
+            String::from(IPV6_PREFIX)
+        } else {
+            format!("fd6b:{:x}:{:x}:{:x}::", self.0[0], self.0[1], self.0[2])
+        }
+    }
+
+    pub fn ipv6_addr(&self, id: u8) -> Ipv6Addr {
+        format!("{}{:x}", self.ipv6_prefix(), id).parse().unwrap()
+    }
+
+    /// The id an address inside the subnet belongs to.
+    pub fn id_of(&self, addr: IpAddr) -> Option<u8> {
+        let (prefix, id) = match addr {
+            IpAddr::V4(addr) => {
+                let octets = addr.octets();
+                (octets[0..3] == self.0, octets[3])
+            }
+            IpAddr::V6(addr) => {
+                let octets = addr.octets();
+                (octets[0..15] == self.ipv6_addr(0).octets()[0..15], octets[15])
+            }
+        };
+        if prefix { Some(id) } else { None }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/24", self.addr(0))
+    }
+}
 
-const MTU: &'static str = "1380";
+impl FromStr for Subnet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Subnet> {
+        let invalid = || Error::Config(format!("expected A.B.C.0/24, got {:?}", s));
+        let addr: Ipv4Addr = try!(s.trim_right_matches("/24").parse().map_err(|_| invalid()));
+        let octets = addr.octets();
+        if octets[3] != 0 {
+            return Err(invalid());
+        }
+        Ok(Subnet([octets[0], octets[1], octets[2]]))
+    }
+}
 
 #[cfg(target_os = "linux")]
 use libc::c_short;
@@ -29,9 +104,17 @@ const IFNAMSIZ: usize = 16;
 #[cfg(target_os = "linux")]
 const IFF_TUN: c_short = 0x0001;
 #[cfg(target_os = "linux")]
+const IFF_TAP: c_short = 0x0002;
+#[cfg(target_os = "linux")]
 const IFF_NO_PI: c_short = 0x1000;
 #[cfg(target_os = "linux")]
 const TUNSETIFF: c_ulong = 0x400454ca; // TODO: use _IOW('T', 202, int)
+#[cfg(target_os = "linux")]
+const TUNGETIFF: c_ulong = 0x800454d2; // _IOR('T', 210, unsigned int)
+#[cfg(target_os = "linux")]
+const SIOCGIFMTU: c_ulong = 0x8921;
+#[cfg(target_os = "macos")]
+const SIOCGIFMTU: c_ulong = 0xc0206933; // _IOWR('i', 51, struct ifreq)
 
 #[cfg(target_os = "macos")]
 use nix;
@@ -63,6 +146,16 @@ const UTUN_CONTROL_NAME: &'static str = "com.apple.net.utun_control";
 pub struct ioctl_flags_data {
     pub ifr_name: [u8; IFNAMSIZ],
     pub ifr_flags: c_short,
+    // Pad to the size of struct ifreq, which the kernel copies in full.
+    pub _pad: [u8; 22],
+}
+
+#[repr(C)]
+pub struct ifreq_mtu {
+    pub ifr_name: [u8; 16],
+    pub ifr_mtu: libc::c_int,
+    // Pad to the size of the largest member of the ifreq union.
+    pub _pad: [u8; 20],
 }
 
 #[cfg(target_os = "macos")]
@@ -83,9 +176,100 @@ pub struct sockaddr_ctl {
     pub sc_reserved: [u32; 5],
 }
 
+#[cfg(not(target_os = "linux"))]
+fn check_status(status: &process::ExitStatus) -> Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Device(format!("ifconfig: {}", status)))
+    }
+}
+
+/// The header a platform puts in front of each packet read from or written
+/// to a TUN device. `Tun` strips and adds it, so the rest of the code sees
+/// bare IP packets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketInfo {
+    /// No header, as on Linux with `IFF_NO_PI`.
+    None,
+    /// Linux's `struct tun_pi`: two bytes of flags, then the EtherType.
+    TunPi,
+    /// The address family as a big-endian u32, as macOS utun uses.
+    Family,
+}
+
+impl PacketInfo {
+    pub fn size(&self) -> usize {
+        match *self {
+            PacketInfo::None => 0,
+            PacketInfo::TunPi | PacketInfo::Family => 4,
+        }
+    }
+
+    /// The IP packet in a frame read from the device. Empty if the frame is
+    /// too short.
+    pub fn strip<'a>(&self, frame: &'a [u8]) -> &'a [u8] {
+        if frame.len() > self.size() {
+            &frame[self.size()..]
+        } else {
+            &[]
+        }
+    }
+
+    /// Replaces `frame` with `packet` and the header in front of it.
+    pub fn wrap(&self, packet: &[u8], frame: &mut Vec<u8>) {
+        frame.clear();
+        let v6 = packet.first().map_or(false, |byte| byte >> 4 == 6);
+        match *self {
+            PacketInfo::None => {}
+            PacketInfo::TunPi => {
+                frame.extend_from_slice(&[0, 0]);
+                frame.extend_from_slice(&ethertype(packet));
+            }
+            PacketInfo::Family => {
+                let family = (if v6 { libc::AF_INET6 } else { libc::AF_INET }) as u32;
+                frame.extend_from_slice(&[(family >> 24) as u8,
+                                          (family >> 16) as u8,
+                                          (family >> 8) as u8,
+                                          family as u8]);
+            }
+        }
+        frame.extend_from_slice(packet);
+    }
+}
+
+/// A virtual network device the tunnel reads IP packets from and writes
+/// them to. The event loops only go through this trait, so new kinds of
+/// devices and platforms need no changes there.
+pub trait VirtualInterface: Read + Write + AsRawFd + Send {
+    fn name(&self) -> &str;
+
+    /// Assigns the addresses of `self_id` in `subnet` and brings the device
+    /// up.
+    fn up(&self, subnet: &Subnet, self_id: u8) -> Result<()>;
+
+    fn down(&self) -> Result<()>;
+
+    /// Queries the MTU currently configured on the device.
+    fn mtu(&self) -> Result<u16>;
+
+    fn set_mtu(&self, mtu: u16) -> Result<()>;
+
+    /// Tells the device which addresses belong to which client, for an
+    /// Ethernet device to answer neighbour discovery for them.
+    fn set_clients(&mut self, _: &[(IpAddr, u8)]) {}
+
+    /// Tells the device the time of the current turn of the event loop, so
+    /// that writes need not read the clock per packet.
+    fn set_now(&mut self, _: Instant) {}
+}
+
 pub struct Tun {
     handle: fs::File,
     if_name: String,
+    info: PacketInfo,
+    // Scratch space for frames with a packet info header.
+    frame: Vec<u8>,
 }
 
 impl AsRawFd for Tun {
@@ -96,18 +280,41 @@ impl AsRawFd for Tun {
 
 impl Tun {
     #[cfg(target_os = "linux")]
-    pub fn create(name: u8) -> Result<Tun, io::Error> {
+    pub fn create(name: u8) -> io::Result<Tun> {
+        Tun::create_named(&format!("tun{}", name))
+    }
+
+    /// Creates the device `name`, so that instances sharing a host can be
+    /// told apart. Linux only.
+    #[cfg(target_os = "linux")]
+    pub fn create_named(name: &str) -> io::Result<Tun> {
+        Tun::open(name, IFF_TUN)
+    }
+
+    /// Creates the Ethernet device `name`, which carries frames instead of
+    /// bare IP packets. Linux only.
+    #[cfg(target_os = "linux")]
+    pub fn create_tap(name: &str) -> io::Result<Tun> {
+        Tun::open(name, IFF_TAP)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open(name: &str, kind: c_short) -> io::Result<Tun> {
+        if name.is_empty() || name.len() >= IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid device name {:?}", name)));
+        }
         let path = path::Path::new("/dev/net/tun");
         let file = try!(fs::OpenOptions::new().read(true).write(true).open(&path));
 
         let mut req = ioctl_flags_data {
             ifr_name: {
                 let mut buffer = [0u8; IFNAMSIZ];
-                let full_name = format!("tun{}", name);
-                buffer[..full_name.len()].clone_from_slice(full_name.as_bytes());
+                buffer[..name.len()].clone_from_slice(name.as_bytes());
                 buffer
             },
-            ifr_flags: IFF_TUN | IFF_NO_PI,
+            ifr_flags: kind | IFF_NO_PI,
+            _pad: [0u8; 22],
         };
 
         let res = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) }; // TUNSETIFF
@@ -119,12 +326,14 @@ impl Tun {
         let tun = Tun {
             handle: file,
             if_name: String::from_utf8(req.ifr_name[..size].to_vec()).unwrap(),
+            info: PacketInfo::None,
+            frame: Vec::new(),
         };
         Ok(tun)
     }
 
     #[cfg(target_os = "macos")]
-    pub fn create(name: u8) -> Result<Tun, io::Error> {
+    pub fn create(name: u8) -> io::Result<Tun> {
         let handle = {
             let fd = unsafe { libc::socket(PF_SYSTEM, libc::SOCK_DGRAM, SYSPROTO_CONTROL) };
             if fd < 0 {
@@ -169,124 +378,480 @@ impl Tun {
             return Err(io::Error::last_os_error());
         }
 
-        let mut name_buf = [0u8; 64];
-        let mut name_length: socklen_t = 64;
-        let res = unsafe {
-            libc::getsockopt(handle.as_raw_fd(),
-                             SYSPROTO_CONTROL,
-                             UTUN_OPT_IFNAME,
-                             &mut name_buf as *mut _ as *mut c_void,
-                             &mut name_length as *mut socklen_t)
-        };
-        if res != 0 {
-            return Err(io::Error::last_os_error());
-        }
+        let if_name = try!(utun_name(handle.as_raw_fd()));
 
         try!(fcntl(handle.as_raw_fd(), FcntlArg::F_SETFL(O_NONBLOCK)));
         try!(fcntl(handle.as_raw_fd(), FcntlArg::F_SETFD(FD_CLOEXEC)));
 
         let tun = Tun {
             handle: handle,
-            if_name: {
-                let len = name_buf.iter().position(|&r| r == 0).unwrap();
-                String::from_utf8(name_buf[..len].to_vec()).unwrap()
-            },
+            if_name: if_name,
+            info: PacketInfo::Family,
+            frame: Vec::new(),
         };
         Ok(tun)
     }
 
-    pub fn name(&self) -> &str {
+    /// The header this device puts in front of packets.
+    pub fn packet_info(&self) -> PacketInfo {
+        self.info
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn utun_name(fd: RawFd) -> io::Result<String> {
+    let mut name_buf = [0u8; 64];
+    let mut name_length: socklen_t = 64;
+    let res = unsafe {
+        libc::getsockopt(fd,
+                         SYSPROTO_CONTROL,
+                         UTUN_OPT_IFNAME,
+                         &mut name_buf as *mut _ as *mut c_void,
+                         &mut name_length as *mut socklen_t)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let len = name_buf.iter().position(|&r| r == 0).unwrap();
+    Ok(String::from_utf8_lossy(&name_buf[..len]).into_owned())
+}
+
+/// Assigns the addresses of `self_id` in `subnet` to the device `name`, and
+/// brings it up.
+#[cfg(target_os = "linux")]
+fn configure(name: &str, subnet: &Subnet, self_id: u8) -> Result<()> {
+    with_link(name, |link, index| {
+        // Replacing rather than adding lets a device be brought up again.
+        try!(link.replace_address(index, &IpAddr::V4(subnet.addr(self_id)), 24));
+        try!(link.replace_address(index, &IpAddr::V6(subnet.ipv6_addr(self_id)), 64));
+        try!(link.set_mtu(index, MTU));
+        link.set_flags(index, libc::IFF_UP as u32, libc::IFF_UP as u32)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn configure(name: &str, subnet: &Subnet, self_id: u8) -> Result<()> {
+    if !cfg!(target_os = "macos") {
+        return Err(Error::Device(String::from("configuring devices needs Linux or macOS")));
+    }
+    try!(ifconfig(&[name, &subnet.addr(self_id).to_string(), &subnet.addr(1).to_string()]));
+    try!(ifconfig(&[name, "inet6", &subnet.ipv6_addr(self_id).to_string(), "prefixlen", "64"]));
+    ifconfig(&[name, "mtu", &MTU.to_string(), "up"])
+}
+
+/// Runs `f` with a netlink socket and the index of the device `name`.
+#[cfg(target_os = "linux")]
+fn with_link<F>(name: &str, f: F) -> Result<()>
+    where F: FnOnce(&mut netlink::Netlink, u32) -> Result<()>
+{
+    let index = try!(netlink::interface_index(name));
+    let mut link = try!(netlink::Netlink::open());
+    f(&mut link, index).map_err(|e| Error::Device(format!("{}: {}", name, e)))
+}
+
+#[cfg(target_os = "linux")]
+fn bring_down(name: &str) -> Result<()> {
+    with_link(name, |link, index| link.set_flags(index, 0, libc::IFF_UP as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bring_down(name: &str) -> Result<()> {
+    ifconfig(&[name, "down"])
+}
+
+#[cfg(target_os = "linux")]
+fn set_interface_mtu(name: &str, mtu: u16) -> Result<()> {
+    with_link(name, |link, index| link.set_mtu(index, mtu))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_interface_mtu(name: &str, mtu: u16) -> Result<()> {
+    ifconfig(&[name, "mtu", &mtu.to_string()])
+}
+
+/// Stops the device `name` from resolving neighbours itself.
+#[cfg(target_os = "linux")]
+fn disable_arp(name: &str) -> Result<()> {
+    with_link(name, |link, index| {
+        link.set_flags(index, libc::IFF_NOARP as u32, libc::IFF_NOARP as u32)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn disable_arp(name: &str) -> Result<()> {
+    ifconfig(&[name, "-arp"])
+}
+
+/// Queries the MTU currently configured on the device `name`.
+fn interface_mtu(name: &str) -> Result<u16> {
+    let mut req = ifreq_mtu {
+        ifr_name: [0u8; 16],
+        ifr_mtu: 0,
+        _pad: [0u8; 20],
+    };
+    req.ifr_name[..name.len()].clone_from_slice(name.as_bytes());
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let res = unsafe { libc::ioctl(fd, SIOCGIFMTU, &mut req) };
+    let err = io::Error::last_os_error();
+    unsafe {
+        libc::close(fd);
+    }
+    if res < 0 {
+        return Err(Error::Device(format!("SIOCGIFMTU on {}: {}", name, err)));
+    }
+    Ok(req.ifr_mtu as u16)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ifconfig(args: &[&str]) -> Result<()> {
+    let status = try!(process::Command::new("ifconfig").args(args).status());
+    check_status(&status)
+}
+
+impl VirtualInterface for Tun {
+    fn name(&self) -> &str {
         &self.if_name
     }
 
-    pub fn up(&self, self_id: u8) {
-        let mut status = if cfg!(target_os = "linux") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg(format!("10.10.10.{}/24", self_id))
-                .status()
-                .unwrap()
-        } else if cfg!(target_os = "macos") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg(format!("10.10.10.{}", self_id))
-                .arg("10.10.10.1")
-                .status()
-                .unwrap()
-        } else {
-            unimplemented!()
-        };
+    fn up(&self, subnet: &Subnet, self_id: u8) -> Result<()> {
+        configure(&self.if_name, subnet, self_id)
+    }
 
-        assert!(status.success());
-
-        status = if cfg!(target_os = "linux") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg("mtu")
-                .arg(MTU)
-                .arg("up")
-                .status()
-                .unwrap()
-        } else if cfg!(target_os = "macos") {
-            process::Command::new("ifconfig")
-                .arg(self.if_name.clone())
-                .arg("mtu")
-                .arg(MTU)
-                .arg("up")
-                .status()
-                .unwrap()
-        } else {
-            unimplemented!()
-        };
+    fn down(&self) -> Result<()> {
+        bring_down(&self.if_name)
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        interface_mtu(&self.if_name)
+    }
 
-        assert!(status.success());
+    fn set_mtu(&self, mtu: u16) -> Result<()> {
+        set_interface_mtu(&self.if_name, mtu)
     }
 }
 
 impl Read for Tun {
-    #[cfg(target_os = "linux")]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.handle.read(buf)
+        if self.info == PacketInfo::None {
+            return self.handle.read(buf);
+        }
+        self.frame.resize(buf.len() + self.info.size(), 0);
+        let len = try!(self.handle.read(&mut self.frame));
+        let packet = self.info.strip(&self.frame[..len]);
+        buf[..packet.len()].clone_from_slice(packet);
+        Ok(packet.len())
     }
+}
 
+impl Write for Tun {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.info == PacketInfo::None {
+            return self.handle.write(buf);
+        }
+        self.info.wrap(buf, &mut self.frame);
+        let len = try!(self.handle.write(&self.frame));
+        Ok(len.saturating_sub(self.info.size()))
+    }
 
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.flush()
+    }
+}
 
-    #[cfg(target_os = "macos")]
+/// The EtherType of an IP packet.
+fn ethertype(packet: &[u8]) -> [u8; 2] {
+    if packet.first().map_or(false, |byte| byte >> 4 == 6) {
+        [0x86, 0xdd]
+    } else {
+        [0x08, 0x00]
+    }
+}
+
+/// An Ethernet device carrying the tunnel's IP packets, for bridging and
+/// other L2 setups. Each client has a MAC address of its own, and ARP and
+/// neighbour solicitations for clients are answered by the device (see
+/// `neighbour`). Frames that do not carry IP for the tunnel read as empty.
+pub struct Tap {
+    tun: Tun,
+    neighbours: neighbour::Table,
+    frame: Vec<u8>,
+    // The time of the current turn of the event loop.
+    now: Instant,
+}
+
+impl Tap {
+    /// Creates the TAP device `name`. Linux only.
+    #[cfg(target_os = "linux")]
+    pub fn create(name: &str) -> io::Result<Tap> {
+        Ok(Tap {
+            tun: try!(Tun::create_tap(name)),
+            neighbours: neighbour::Table::default(),
+            frame: Vec::new(),
+            now: Instant::now(),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn create(_: &str) -> io::Result<Tap> {
+        Err(io::Error::new(io::ErrorKind::Other, "TAP devices need Linux"))
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tun.as_raw_fd()
+    }
+}
+
+impl Read for Tap {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut data = [0u8; 1600];
-        let result = self.handle.read(&mut data);
-        match result {
-            Ok(len) => {
-                buf[..len - 4].clone_from_slice(&data[4..len]);
-                Ok(if len > 4 { len - 4 } else { 0 })
+        self.frame.resize(buf.len() + ETHERNET_HEADER, 0);
+        let len = try!(self.tun.read(&mut self.frame));
+        match self.neighbours.inbound(&self.frame[..len]) {
+            neighbour::Inbound::Packet => {}
+            neighbour::Inbound::Reply(reply) => {
+                try!(self.tun.write(&reply));
+                return Ok(0);
             }
-            Err(e) => Err(e),
+            neighbour::Inbound::Ignore => return Ok(0),
         }
+        let packet = &self.frame[ETHERNET_HEADER..len];
+        buf[..packet.len()].clone_from_slice(packet);
+        Ok(packet.len())
     }
 }
 
-impl Write for Tun {
-    #[cfg(target_os = "linux")]
+impl Write for Tap {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.handle.write(buf)
+        let (to, from, solicit) = self.neighbours.outbound(buf, self.now);
+        if let Some(solicit) = solicit {
+            try!(self.tun.write(&solicit));
+        }
+        self.frame.clear();
+        self.frame.extend_from_slice(&to);
+        self.frame.extend_from_slice(&from);
+        self.frame.extend_from_slice(&ethertype(buf));
+        self.frame.extend_from_slice(buf);
+        let len = try!(self.tun.write(&self.frame));
+        Ok(len.saturating_sub(ETHERNET_HEADER))
     }
 
-    #[cfg(target_os = "macos")]
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let ip_v = buf[0] & 0xf;
-        let mut data: Vec<u8> = if ip_v == 6 {
-            vec![0, 0, 0, 10]
-        } else {
-            vec![0, 0, 0, 2]
+    fn flush(&mut self) -> io::Result<()> {
+        self.tun.flush()
+    }
+}
+
+impl VirtualInterface for Tap {
+    fn name(&self) -> &str {
+        self.tun.name()
+    }
+
+    fn up(&self, subnet: &Subnet, self_id: u8) -> Result<()> {
+        try!(self.tun.up(subnet, self_id));
+        disable_arp(self.tun.name())
+    }
+
+    fn down(&self) -> Result<()> {
+        self.tun.down()
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        self.tun.mtu()
+    }
+
+    fn set_mtu(&self, mtu: u16) -> Result<()> {
+        self.tun.set_mtu(mtu)
+    }
+
+    fn set_clients(&mut self, clients: &[(IpAddr, u8)]) {
+        self.neighbours.set_clients(clients);
+    }
+
+    fn set_now(&mut self, now: Instant) {
+        self.now = now;
+    }
+}
+
+/// A TUN device opened, and usually configured, by someone else, such as a
+/// privileged helper or the platform's VPN service, and handed over as a
+/// file descriptor. Its owner brings it up and down.
+pub struct Passed {
+    tun: Tun,
+}
+
+impl Passed {
+    /// Takes over the TUN device open at `fd`, which then belongs to the
+    /// `Passed` and is closed with it.
+    #[cfg(target_os = "linux")]
+    pub fn open(fd: RawFd) -> io::Result<Passed> {
+        use std::os::unix::io::FromRawFd;
+        let handle = unsafe { fs::File::from_raw_fd(fd) };
+        let mut req = ioctl_flags_data {
+            ifr_name: [0u8; IFNAMSIZ],
+            ifr_flags: 0,
+            _pad: [0u8; 22],
         };
-        data.write_all(buf).unwrap();
-        match self.handle.write(&data) {
-            Ok(len) => Ok(if len > 4 { len - 4 } else { 0 }),
-            Err(e) => Err(e),
+        if unsafe { libc::ioctl(fd, TUNGETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if req.ifr_flags & IFF_TAP != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "passed device is TAP, not TUN"));
         }
+        let size = req.ifr_name.iter().position(|&r| r == 0).unwrap_or(IFNAMSIZ);
+        Ok(Passed {
+            tun: Tun {
+                handle: handle,
+                if_name: String::from_utf8_lossy(&req.ifr_name[..size]).into_owned(),
+                info: if req.ifr_flags & IFF_NO_PI != 0 {
+                    PacketInfo::None
+                } else {
+                    PacketInfo::TunPi
+                },
+                frame: Vec::new(),
+            },
+        })
+    }
+
+    /// Takes over the utun socket open at `fd`, which then belongs to the
+    /// `Passed` and is closed with it.
+    #[cfg(target_os = "macos")]
+    pub fn open(fd: RawFd) -> io::Result<Passed> {
+        let handle = unsafe { fs::File::from_raw_fd(fd) };
+        Ok(Passed {
+            tun: Tun {
+                if_name: try!(utun_name(handle.as_raw_fd())),
+                handle: handle,
+                info: PacketInfo::Family,
+                frame: Vec::new(),
+            },
+        })
+    }
+}
+
+impl AsRawFd for Passed {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tun.as_raw_fd()
+    }
+}
+
+impl Read for Passed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tun.read(buf)
+    }
+}
+
+impl Write for Passed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tun.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.handle.flush()
+        self.tun.flush()
+    }
+}
+
+impl VirtualInterface for Passed {
+    fn name(&self) -> &str {
+        self.tun.name()
+    }
+
+    fn up(&self, _: &Subnet, _: u8) -> Result<()> {
+        debug!("Leaving {} configured by its owner.", self.tun.name());
+        Ok(())
     }
+
+    fn down(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        self.tun.mtu()
+    }
+
+    fn set_mtu(&self, mtu: u16) -> Result<()> {
+        self.tun.set_mtu(mtu)
+    }
+}
+
+/// Stands in for the TUN device of a server that only relays between its
+/// clients. Packets written to it vanish and it never has any to read; it
+/// must not be polled.
+pub struct Null;
+
+impl AsRawFd for Null {
+    fn as_raw_fd(&self) -> RawFd {
+        -1
+    }
+}
+
+impl Read for Null {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no device"))
+    }
+}
+
+impl Write for Null {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl VirtualInterface for Null {
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn up(&self, _: &Subnet, _: u8) -> Result<()> {
+        Ok(())
+    }
+
+    fn down(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(MTU)
+    }
+
+    fn set_mtu(&self, _: u16) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn subnet_test() {
+    let subnet: Subnet = "10.20.30.0/24".parse().unwrap();
+    assert_eq!(subnet.addr(7), Ipv4Addr::new(10, 20, 30, 7));
+    assert_eq!(subnet.to_string(), "10.20.30.0/24");
+    assert_eq!(subnet.ipv6_addr(1), "fd6b:a:14:1e::1".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(subnet.id_of(IpAddr::V4(Ipv4Addr::new(10, 20, 30, 7))), Some(7));
+    assert_eq!(subnet.id_of(IpAddr::V4(Ipv4Addr::new(10, 10, 10, 7))), None);
+    assert_eq!(Subnet::default().ipv6_addr(42),
+               "fd10:10:10::2a".parse::<Ipv6Addr>().unwrap());
+    assert!("10.20.30.1/24".parse::<Subnet>().is_err());
+}
+
+#[test]
+fn packet_info_test() {
+    let v4 = [0x45, 0, 0, 20];
+    let v6 = [0x60, 0, 0, 0];
+    let mut frame = Vec::new();
+    PacketInfo::None.wrap(&v4, &mut frame);
+    assert_eq!(frame, v4);
+    PacketInfo::TunPi.wrap(&v6, &mut frame);
+    assert_eq!(frame, [0, 0, 0x86, 0xdd, 0x60, 0, 0, 0]);
+    assert_eq!(PacketInfo::TunPi.strip(&frame), v6);
+    PacketInfo::Family.wrap(&v4, &mut frame);
+    assert_eq!(frame[..4], [0, 0, 0, libc::AF_INET as u8]);
+    assert_eq!(PacketInfo::Family.strip(&frame), v4);
+    assert!(PacketInfo::Family.strip(&frame[..4]).is_empty());
 }
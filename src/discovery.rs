@@ -0,0 +1,143 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relays mDNS and SSDP between the server's LAN and the clients.
+//!
+//! The proxy joins both multicast groups on the server. Announcements heard
+//! on the LAN are wrapped into packets addressed to the group and replicated
+//! to every client; queries from clients are repeated on the LAN. Unicast
+//! SSDP search responses go back to the client that searched last.
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use libc;
+use mio;
+use packet;
+use error::Result;
+
+struct Service {
+    name: &'static str,
+    group: SocketAddrV4,
+    socket: mio::udp::UdpSocket,
+    last_query: Option<SocketAddr>,
+}
+
+pub struct Proxy {
+    base: usize,
+    services: Vec<Service>,
+}
+
+/// Binds a UDP socket to `port` on all interfaces, shared with any mDNS or
+/// SSDP daemon already running on the host.
+fn bind_shared(port: u16) -> io::Result<UdpSocket> {
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = UdpSocket::from_raw_fd(fd);
+        let one: libc::c_int = 1;
+        for &opt in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            if libc::setsockopt(fd,
+                                libc::SOL_SOCKET,
+                                opt,
+                                &one as *const _ as *const libc::c_void,
+                                mem::size_of::<libc::c_int>() as libc::socklen_t) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let mut addr: libc::sockaddr_in = mem::zeroed();
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_port = port.to_be();
+        if libc::bind(socket.as_raw_fd(),
+                      &addr as *const _ as *const libc::sockaddr,
+                      mem::size_of::<libc::sockaddr_in>() as libc::socklen_t) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+}
+
+impl Proxy {
+    /// Joins the mDNS and SSDP groups and registers the sockets with tokens
+    /// from `base` upwards.
+    pub fn open(poll: &mio::Poll, base: usize) -> Result<Proxy> {
+        let groups = [("mDNS", SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353)),
+                      ("SSDP", SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900))];
+        let mut services = Vec::new();
+        for (i, &(name, group)) in groups.iter().enumerate() {
+            let socket = try!(mio::udp::UdpSocket::from_socket(try!(bind_shared(group.port()))));
+            try!(socket.join_multicast_v4(group.ip(), &Ipv4Addr::new(0, 0, 0, 0)));
+            // Our own queries must not come back as announcements.
+            try!(socket.set_multicast_loop_v4(false));
+            try!(poll.register(&socket,
+                               mio::Token(base + i),
+                               mio::Ready::readable(),
+                               mio::PollOpt::level()));
+            services.push(Service {
+                name: name,
+                group: group,
+                socket: socket,
+                last_query: None,
+            });
+        }
+        Ok(Proxy {
+            base: base,
+            services: services,
+        })
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        token.0 >= self.base && token.0 < self.base + self.services.len()
+    }
+
+    /// Repeats a client's packet on the LAN if it is addressed to one of the
+    /// proxied groups.
+    pub fn outbound(&mut self, data: &[u8]) -> Result<()> {
+        let (src, dst, payload) = match packet::parse_udp(data) {
+            Ok(udp) => udp,
+            Err(_) => return Ok(()),
+        };
+        for service in self.services.iter_mut() {
+            if dst == SocketAddr::V4(service.group) {
+                debug!("Relaying {} query from {} to the LAN.", service.name, src);
+                service.last_query = Some(src);
+                try!(service.socket.send_to(payload, &dst));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a datagram heard on the LAN and wraps it into a packet for the
+    /// clients.
+    pub fn inbound(&mut self, token: mio::Token, buf: &mut [u8]) -> Result<Option<Vec<u8>>> {
+        let service = &mut self.services[token.0 - self.base];
+        let (len, from) = match try!(service.socket.recv_from(buf)) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if len == buf.len() {
+            warn!("Dropping oversized {} datagram from {}.", service.name, from);
+            return Ok(None);
+        }
+        let payload = &buf[0..len];
+        let to = match service.last_query {
+            Some(querier) if payload.starts_with(b"HTTP/1.1 200") => querier,
+            _ => SocketAddr::V4(service.group),
+        };
+        Ok(Some(packet::build_udp(&from, &to, payload)))
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DNS forwarder on the server's tunnel address.
+//!
+//! Clients can always reach the server's end of the tunnel, even when the
+//! host's own resolvers only answer their local network. Queries arriving
+//! there are relayed to the configured upstream resolvers under a fresh
+//! transaction id, and the answers are handed back to whoever asked.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use mio;
+use error::{Error, Result};
+
+/// How long an upstream may take to answer.
+const QUERY_TIMEOUT: u64 = 5;
+/// Upper bound on queries awaiting an answer.
+const MAX_PENDING: usize = 1024;
+/// Larger than any UDP DNS message with EDNS0.
+const BUFFER_SIZE: usize = 4096;
+
+struct Query {
+    id: u16,
+    client: SocketAddr,
+    upstream: SocketAddr,
+    sent: Instant,
+}
+
+pub struct Forwarder {
+    listener: mio::udp::UdpSocket,
+    relay: mio::udp::UdpSocket,
+    upstreams: Vec<SocketAddr>,
+    next_upstream: usize,
+    next_id: u16,
+    pending: HashMap<u16, Query>,
+    buf: Vec<u8>,
+}
+
+fn transaction_id(message: &[u8]) -> Option<u16> {
+    if message.len() < 12 {
+        return None;
+    }
+    Some((message[0] as u16) << 8 | message[1] as u16)
+}
+
+fn set_transaction_id(message: &mut [u8], id: u16) {
+    message[0] = (id >> 8) as u8;
+    message[1] = id as u8;
+}
+
+impl Forwarder {
+    /// Answers queries on `addr`, registered with `listener`, relaying them
+    /// to `upstreams` from a socket registered with `relay`.
+    pub fn open(poll: &mio::Poll,
+                listener: mio::Token,
+                relay: mio::Token,
+                addr: &SocketAddr,
+                upstreams: Vec<SocketAddr>)
+                -> Result<Forwarder> {
+        if upstreams.is_empty() {
+            return Err(Error::Config(String::from("the DNS forwarder needs an upstream")));
+        }
+        let socket = try!(mio::udp::UdpSocket::bind(addr));
+        try!(poll.register(&socket, listener, mio::Ready::readable(), mio::PollOpt::level()));
+        let bind = match upstreams[0] {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let relay_socket = try!(mio::udp::UdpSocket::bind(&bind));
+        try!(poll.register(&relay_socket, relay, mio::Ready::readable(), mio::PollOpt::level()));
+        info!("Forwarding DNS queries on {} to {:?}.", addr, upstreams);
+        Ok(Forwarder {
+            listener: socket,
+            relay: relay_socket,
+            upstreams: upstreams,
+            next_upstream: 0,
+            next_id: 0,
+            pending: HashMap::new(),
+            buf: vec![0; BUFFER_SIZE],
+        })
+    }
+
+    fn allocate_id(&mut self) -> u16 {
+        // Pending queries are bounded well below the id space.
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.pending.contains_key(&self.next_id) {
+                return self.next_id;
+            }
+        }
+    }
+
+    /// Relays a query from a client.
+    pub fn query(&mut self, now: Instant) -> Result<()> {
+        let (len, client) = match try!(self.listener.recv_from(&mut self.buf)) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let id = match transaction_id(&self.buf[..len]) {
+            Some(id) if len < self.buf.len() => id,
+            _ => {
+                debug!("Dropping malformed DNS query from {}.", client);
+                return Ok(());
+            }
+        };
+        if self.pending.len() >= MAX_PENDING {
+            warn!("Too many pending DNS queries. Dropping query from {}.", client);
+            return Ok(());
+        }
+        let relayed = self.allocate_id();
+        let upstream = self.upstreams[self.next_upstream];
+        self.next_upstream = (self.next_upstream + 1) % self.upstreams.len();
+        set_transaction_id(&mut self.buf[..len], relayed);
+        self.pending.insert(relayed,
+                            Query {
+                                id: id,
+                                client: client,
+                                upstream: upstream,
+                                sent: now,
+                            });
+        if let Err(e) = self.relay.send_to(&self.buf[..len], &upstream) {
+            debug!("Failed to relay DNS query to {}: {}", upstream, e);
+            self.pending.remove(&relayed);
+        }
+        Ok(())
+    }
+
+    /// Hands an upstream answer back to the client that asked.
+    pub fn answer(&mut self) -> Result<()> {
+        let (len, from) = match try!(self.relay.recv_from(&mut self.buf)) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let relayed = transaction_id(&self.buf[..len]);
+        let expected = relayed.and_then(|id| self.pending.get(&id))
+            .map_or(false, |query| query.upstream == from);
+        if !expected {
+            debug!("Dropping unexpected DNS answer from {}.", from);
+            return Ok(());
+        }
+        let query = self.pending.remove(&relayed.unwrap()).unwrap();
+        set_transaction_id(&mut self.buf[..len], query.id);
+        if let Err(e) = self.listener.send_to(&self.buf[..len], &query.client) {
+            debug!("Failed to answer DNS query from {}: {}", query.client, e);
+        }
+        Ok(())
+    }
+
+    /// Forgets queries that upstreams never answered.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = Duration::from_secs(QUERY_TIMEOUT);
+        self.pending.retain(|_, query| now.duration_since(query.sent) < timeout);
+    }
+}
+
+#[test]
+fn transaction_id_test() {
+    let mut message = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    assert_eq!(transaction_id(&message), Some(0x1234));
+    set_transaction_id(&mut message, 0xbeef);
+    assert_eq!(&message[..3], &[0xbe, 0xef, 1]);
+    assert_eq!(transaction_id(&message[..11]), None);
+}
@@ -0,0 +1,267 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! `kytan doctor`: checks for the usual reasons a tunnel does not come up.
+//!
+//! Each check prints one finding, with a hint when something is wrong. The
+//! server checks send latency probes, which any kytan server answers
+//! without a session.
+
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+use mio;
+use rand;
+use netwatch;
+use utils;
+use network::*;
+
+/// How long to wait for each probe.
+const PROBE_TIMEOUT: u64 = 2;
+/// Payload sizes tried when looking for the path MTU, largest first. With
+/// 28 bytes of IPv4 and UDP headers they make packets of 1500, 1420, 1280
+/// and 576 bytes.
+const MTU_STEPS: &'static [usize] = &[1472, 1392, 1252, 548];
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Level {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Report {
+    worst: Level,
+}
+
+impl Report {
+    fn finding(&mut self, level: Level, message: &str, hint: Option<&str>) {
+        let tag = match level {
+            Level::Ok => "ok",
+            Level::Warn => "warn",
+            Level::Fail => "FAIL",
+        };
+        println!("[{:>4}] {}", tag, message);
+        if let Some(hint) = hint {
+            println!("       {}", hint);
+        }
+        if level > self.worst {
+            self.worst = level;
+        }
+    }
+}
+
+/// Runs every check, including those against `server` if given. Whether
+/// nothing failed.
+pub fn run(server: Option<(String, u16)>) -> bool {
+    let mut report = Report { worst: Level::Ok };
+    check_tun(&mut report);
+    check_forwarding(&mut report);
+    check_routes(&mut report);
+    if let Some((host, port)) = server {
+        match resolve(&host) {
+            Ok(ip) => {
+                let addr = SocketAddr::new(ip, port);
+                if check_reachability(&mut report, &addr) {
+                    check_path_mtu(&mut report, &addr);
+                }
+            }
+            Err(e) => {
+                report.finding(Level::Fail,
+                               &format!("Cannot resolve {}: {}", host, e),
+                               Some("Check the server name and the DNS configuration."))
+            }
+        }
+    }
+    report.worst != Level::Fail
+}
+
+#[cfg(target_os = "linux")]
+fn check_tun(report: &mut Report) {
+    match OpenOptions::new().read(true).write(true).open("/dev/net/tun") {
+        Ok(_) => report.finding(Level::Ok, "/dev/net/tun can be opened.", None),
+        Err(e) => {
+            report.finding(Level::Fail,
+                           &format!("Cannot open /dev/net/tun: {}", e),
+                           Some("Load the tun module (modprobe tun) and run kytan as root; \
+                                 in a container, pass the device with --device /dev/net/tun \
+                                 and grant CAP_NET_ADMIN."))
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn check_tun(report: &mut Report) {
+    report.finding(Level::Ok, "utun devices are built into macOS.", None);
+}
+
+fn check_forwarding(report: &mut Report) {
+    let mut value = String::new();
+    let read = File::open("/proc/sys/net/ipv4/ip_forward")
+        .and_then(|mut file| file.read_to_string(&mut value));
+    match (read, value.trim()) {
+        (Ok(_), "1") => report.finding(Level::Ok, "IPv4 forwarding is enabled.", None),
+        (Ok(_), _) => {
+            report.finding(Level::Warn,
+                           "IPv4 forwarding is disabled.",
+                           Some("Only matters in server mode, which enables it on start \
+                                 unless --userspace-nat is given."))
+        }
+        (Err(_), _) => {
+            report.finding(Level::Ok,
+                           "IPv4 forwarding state unknown on this system.",
+                           None)
+        }
+    }
+}
+
+fn in_tunnel_subnet(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[0..3] == [10, 10, 10],
+        IpAddr::V6(_) => false,
+    }
+}
+
+fn check_routes(report: &mut Report) {
+    match netwatch::fingerprint(None) {
+        Ok(addrs) => {
+            let conflicts: Vec<String> = addrs.iter()
+                .filter(|&&(_, ip)| in_tunnel_subnet(ip))
+                .map(|&(ref name, ip)| format!("{} ({})", name, ip))
+                .collect();
+            if conflicts.is_empty() {
+                report.finding(Level::Ok, "No interface uses 10.10.10.0/24.", None);
+            } else {
+                report.finding(Level::Fail,
+                               &format!("10.10.10.0/24 is already in use: {}",
+                                        conflicts.join(", ")),
+                               Some("Stop the other kytan instance, or move the conflicting \
+                                     network elsewhere."));
+            }
+        }
+        Err(e) => report.finding(Level::Warn, &format!("Cannot list interfaces: {}", e), None),
+    }
+    match utils::get_default_gateway() {
+        Ok(ref gateway) if gateway.parse().map(in_tunnel_subnet).unwrap_or(false) => {
+            report.finding(Level::Warn,
+                           &format!("The default route already points into a tunnel ({}).",
+                                    gateway),
+                           Some("A previous kytan client may have crashed; restore the \
+                                 default route before connecting."))
+        }
+        Ok(gateway) => {
+            report.finding(Level::Ok, &format!("Default gateway is {}.", gateway), None)
+        }
+        Err(e) => {
+            report.finding(Level::Fail,
+                           &format!("No IPv4 default route: {}", e),
+                           Some("Connect to a network first; the client needs a route to \
+                                 the server."))
+        }
+    }
+}
+
+/// Sends a probe padded to `padding` extra bytes and waits for its echo.
+fn probe(socket: &mio::udp::UdpSocket, addr: &SocketAddr, padding: usize) -> Option<Duration> {
+    let nonce = rand::random::<u64>();
+    let mut buf = match encode_message(&Message::Probe { nonce: nonce }) {
+        Ok(buf) => buf,
+        Err(_) => return None,
+    };
+    let len = buf.len();
+    buf.resize(len + padding, 0);
+    let start = Instant::now();
+    if send_raw(socket, &buf, addr).is_err() {
+        return None;
+    }
+    let mut reply = [0u8; 64];
+    while start.elapsed() < Duration::from_secs(PROBE_TIMEOUT) {
+        match socket.recv_from(&mut reply) {
+            Ok(Some((len, from))) if from == *addr => {
+                if decode_message(&reply[..len]).ok() == Some(Message::Probe { nonce: nonce }) {
+                    return Some(start.elapsed());
+                }
+            }
+            Ok(_) => thread::sleep(Duration::from_millis(10)),
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+fn bind(addr: &SocketAddr) -> Option<mio::udp::UdpSocket> {
+    let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    mio::udp::UdpSocket::bind(&local.parse().unwrap()).ok()
+}
+
+fn check_reachability(report: &mut Report, addr: &SocketAddr) -> bool {
+    let answered = bind(addr).and_then(|socket| probe(&socket, addr, 0));
+    match answered {
+        Some(rtt) => {
+            report.finding(Level::Ok,
+                           &format!("{} answers over UDP in {}ms.", addr, ::stats::millis(rtt)),
+                           None);
+            true
+        }
+        None => {
+            report.finding(Level::Fail,
+                           &format!("{} does not answer over UDP.", addr),
+                           Some("Check that the server runs and that firewalls on both \
+                                 ends let the port through."));
+            false
+        }
+    }
+}
+
+fn check_path_mtu(report: &mut Report, addr: &SocketAddr) {
+    let socket = match bind(addr) {
+        Some(socket) => socket,
+        None => return,
+    };
+    if addr.is_ipv4() && set_dont_fragment(&socket, true).is_err() {
+        report.finding(Level::Warn, "Cannot set DF; skipping the path MTU check.", None);
+        return;
+    }
+    let probe_len = encode_message(&Message::Probe { nonce: 0 }).map(|buf| buf.len()).unwrap_or(0);
+    let fits = MTU_STEPS.iter().find(|&&payload| probe(&socket, addr, payload - probe_len).is_some());
+    match fits {
+        Some(&payload) if payload == MTU_STEPS[0] => {
+            report.finding(Level::Ok, "Full-size 1500-byte packets reach the server.", None)
+        }
+        Some(&payload) => {
+            report.finding(Level::Warn,
+                           &format!("Only packets up to about {} bytes reach the server.",
+                                    payload + 28),
+                           Some("Large transfers may stall; try --clamp-mss."))
+        }
+        None => {
+            report.finding(Level::Warn,
+                           "Even 576-byte packets do not reach the server unfragmented.",
+                           Some("Something on the path drops DF packets; try --clamp-mss."))
+        }
+    }
+}
+
+#[test]
+fn report_test() {
+    let mut report = Report { worst: Level::Ok };
+    report.finding(Level::Warn, "a warning", None);
+    report.finding(Level::Ok, "fine", None);
+    assert_eq!(report.worst, Level::Warn);
+    assert!(in_tunnel_subnet("10.10.10.7".parse().unwrap()));
+    assert!(!in_tunnel_subnet("10.10.11.7".parse().unwrap()));
+}
@@ -0,0 +1,187 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{error, fmt, io, result};
+
+#[derive(Debug)]
+pub enum Error {
+    /// A socket, file or process operation failed.
+    Io(io::Error),
+    /// The remote host could not be resolved.
+    Dns(String),
+    /// The session could not be established.
+    Handshake(String),
+    /// A frame or packet could not be parsed.
+    Decode(String),
+    /// The TUN device could not be created or configured.
+    Device(String),
+    /// The routing table could not be read or changed.
+    Route(String),
+    /// The builder was given an invalid or incomplete configuration.
+    Config(String),
+    /// A failure users run into often enough to have a code of its own.
+    Failure(Code, String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Stable names for the failures users run into. Wrappers and support
+/// tooling match on these, or on the exit status, instead of on messages,
+/// which may change or be translated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    /// The server did not accept the credential.
+    AuthFailed,
+    /// The server has no address left for another client.
+    PoolExhausted,
+    /// The peer speaks a protocol version without something this side needs.
+    VersionMismatch,
+    /// Another process has the UDP port.
+    PortInUse,
+    /// There is no `/dev/net/tun` to create the TUN device with.
+    TunMissing,
+    /// Creating the device or changing routes needs more privileges.
+    PermissionDenied,
+    /// No server answered the handshake.
+    NoResponse,
+    /// The server takes no new sessions, such as while it drains.
+    Refused,
+    /// Anything else.
+    Other,
+}
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Code::AuthFailed => "auth-failed",
+            Code::PoolExhausted => "pool-exhausted",
+            Code::VersionMismatch => "version-mismatch",
+            Code::PortInUse => "port-in-use",
+            Code::TunMissing => "tun-missing",
+            Code::PermissionDenied => "permission-denied",
+            Code::NoResponse => "no-response",
+            Code::Refused => "refused",
+            Code::Other => "error",
+        }
+    }
+
+    /// Exit status of the binary when it stops with this failure.
+    pub fn exit_status(&self) -> i32 {
+        match *self {
+            Code::Other => 1,
+            Code::AuthFailed => 10,
+            Code::PoolExhausted => 11,
+            Code::VersionMismatch => 12,
+            Code::PortInUse => 13,
+            Code::TunMissing => 14,
+            Code::PermissionDenied => 15,
+            Code::NoResponse => 16,
+            Code::Refused => 17,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> Code {
+        match *self {
+            Error::Failure(code, _) => code,
+            Error::Io(ref e) if e.kind() == io::ErrorKind::AddrInUse => Code::PortInUse,
+            Error::Io(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                Code::PermissionDenied
+            }
+            _ => Code::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Dns(ref s) => write!(f, "DNS error: {}", s),
+            Error::Handshake(ref s) => write!(f, "Handshake error: {}", s),
+            Error::Decode(ref s) => write!(f, "Decode error: {}", s),
+            Error::Device(ref s) => write!(f, "Device error: {}", s),
+            Error::Route(ref s) => write!(f, "Route error: {}", s),
+            Error::Config(ref s) => write!(f, "Configuration error: {}", s),
+            Error::Failure(_, ref s) => f.write_str(s),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.description(),
+            Error::Dns(ref s) |
+            Error::Handshake(ref s) |
+            Error::Decode(ref s) |
+            Error::Device(ref s) |
+            Error::Route(ref s) |
+            Error::Config(ref s) |
+            Error::Failure(_, ref s) => s,
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[test]
+fn error_display_test() {
+    assert_eq!(format!("{}", Error::Route(String::from("route: exit code 7"))),
+               "Route error: route: exit code 7");
+    let e: Error = io::Error::new(io::ErrorKind::Other, "boom").into();
+    assert_eq!(format!("{}", e), "I/O error: boom");
+}
+
+#[test]
+fn code_test() {
+    let e = Error::Failure(Code::Refused, String::from("192.0.2.1:9527 takes no new sessions"));
+    assert_eq!((e.code(), e.to_string()),
+               (Code::Refused, String::from("192.0.2.1:9527 takes no new sessions")));
+    let e: Error = io::Error::new(io::ErrorKind::AddrInUse, "in use").into();
+    assert_eq!(e.code().as_str(), "port-in-use");
+    assert_eq!(Error::Config(String::from("bad")).code().exit_status(), 1);
+    let codes = [Code::AuthFailed,
+                 Code::PoolExhausted,
+                 Code::VersionMismatch,
+                 Code::PortInUse,
+                 Code::TunMissing,
+                 Code::PermissionDenied,
+                 Code::NoResponse,
+                 Code::Refused,
+                 Code::Other];
+    for (i, a) in codes.iter().enumerate() {
+        for b in &codes[i + 1..] {
+            assert!(a.as_str() != b.as_str() && a.exit_status() != b.exit_status());
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A C ABI for embedding the client, e.g. in mobile apps.
+//!
+//! `kytan_client_start()` runs a client on its own thread and returns an
+//! opaque handle, `kytan_client_status()` reports how it is doing and
+//! `kytan_client_stop()` shuts it down and frees the handle. The app owns the
+//! TUN device and passes its descriptor in; the client sets up no routes.
+//! The declarations are in `include/kytan.h`.
+
+use std::ffi::CStr;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use libc::{c_char, c_int};
+use network::{Event, ShutdownHandle};
+use client::Client;
+use error::{Error, Result};
+
+pub const KYTAN_CONNECTING: c_int = 0;
+pub const KYTAN_CONNECTED: c_int = 1;
+pub const KYTAN_STOPPED: c_int = 2;
+pub const KYTAN_FAILED: c_int = -1;
+
+/// A client running on its own thread.
+pub struct Handle {
+    shutdown: ShutdownHandle,
+    state: Arc<AtomicIsize>,
+    thread: Option<thread::JoinHandle<Result<()>>>,
+}
+
+unsafe fn string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok().map(String::from)
+    }
+}
+
+fn start(host: String, port: u16, credential: Option<String>, tun_fd: c_int) -> Result<Handle> {
+    let state = Arc::new(AtomicIsize::new(KYTAN_CONNECTING as isize));
+    let (tx, rx) = mpsc::channel();
+    let shared = state.clone();
+    let thread = thread::spawn(move || {
+        let events = shared.clone();
+        let mut builder = Client::builder()
+            .host(&host)
+            .port(port)
+            .tun_fd(tun_fd)
+            .on_event(move |event| match *event {
+                Event::Connected { .. } => {
+                    events.store(KYTAN_CONNECTED as isize, Ordering::Relaxed)
+                }
+                Event::Disconnected => events.store(KYTAN_STOPPED as isize, Ordering::Relaxed),
+                _ => {}
+            });
+        if let Some(ref credential) = credential {
+            builder = builder.credential(credential);
+        }
+        let mut client = match builder.build() {
+            Ok(client) => client,
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                return Ok(());
+            }
+        };
+        let _ = tx.send(Ok(client.shutdown_handle()));
+        let result = client.run();
+        let state = if result.is_ok() { KYTAN_STOPPED } else { KYTAN_FAILED };
+        shared.store(state as isize, Ordering::Relaxed);
+        result
+    });
+    let shutdown = match rx.recv() {
+        Ok(Ok(shutdown)) => shutdown,
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(Error::Config(String::from("client thread panicked"))),
+    };
+    Ok(Handle {
+        shutdown: shutdown,
+        state: state,
+        thread: Some(thread),
+    })
+}
+
+/// Connects to `host:port` with the optional `credential` and tunnels the
+/// packets of the TUN device `tun_fd`. Returns null if the client could not
+/// be set up.
+#[no_mangle]
+pub unsafe extern "C" fn kytan_client_start(host: *const c_char,
+                                            port: u16,
+                                            credential: *const c_char,
+                                            tun_fd: c_int)
+                                            -> *mut Handle {
+    let host = match string(host) {
+        Some(host) => host,
+        None => return ptr::null_mut(),
+    };
+    match start(host, port, string(credential), tun_fd) {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(e) => {
+            error!("Failed to start client: {}", e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// One of `KYTAN_CONNECTING`, `KYTAN_CONNECTED`, `KYTAN_STOPPED` and
+/// `KYTAN_FAILED`.
+#[no_mangle]
+pub unsafe extern "C" fn kytan_client_status(handle: *const Handle) -> c_int {
+    match handle.as_ref() {
+        Some(handle) => handle.state.load(Ordering::Relaxed) as c_int,
+        None => KYTAN_FAILED,
+    }
+}
+
+/// Stops the client, waits for its thread and frees `handle`. Returns 0, or
+/// -1 if the client had failed.
+#[no_mangle]
+pub unsafe extern "C" fn kytan_client_stop(handle: *mut Handle) -> c_int {
+    if handle.is_null() {
+        return -1;
+    }
+    let mut handle = Box::from_raw(handle);
+    handle.shutdown.shutdown();
+    match handle.thread.take().map(|thread| thread.join()) {
+        Some(Ok(Ok(()))) => 0,
+        _ => -1,
+    }
+}
+
+#[test]
+fn ffi_test() {
+    unsafe {
+        assert!(kytan_client_start(ptr::null(), 9527, ptr::null(), -1).is_null());
+        assert_eq!(kytan_client_status(ptr::null()), KYTAN_FAILED);
+        assert_eq!(kytan_client_stop(ptr::null_mut()), -1);
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Firewall rules that let clients reach the internet through the server.
+//!
+//! Hosts either run nftables, possibly behind the `iptables-nft` shim, or
+//! the legacy iptables. kytan talks to whichever the host uses and removes
+//! its rules again on exit.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use error::{Error, Result};
+
+/// Name of the nftables table holding the rules for device `tun`, so that
+/// instances on one host keep out of each other's way.
+fn table(tun: &str) -> String {
+    let suffix: String = tun.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("kytan_{}", suffix)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Nftables,
+    Iptables,
+}
+
+impl Backend {
+    /// Prefers nftables unless only the legacy iptables is in use.
+    pub fn detect() -> Result<Backend> {
+        let nft = Command::new("nft")
+            .args(&["list", "tables"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false);
+        let iptables = Command::new("iptables").arg("-V").output().ok().and_then(|output| {
+            if output.status.success() {
+                Some(String::from_utf8_lossy(&output.stdout).into_owned())
+            } else {
+                None
+            }
+        });
+        match (nft, iptables) {
+            (true, Some(ref version)) if !version.contains("nf_tables") => {
+                if legacy_rules_present() {
+                    Ok(Backend::Iptables)
+                } else {
+                    Ok(Backend::Nftables)
+                }
+            }
+            (true, _) => Ok(Backend::Nftables),
+            (false, Some(_)) => Ok(Backend::Iptables),
+            (false, None) => Err(Error::Config(String::from("neither nft nor iptables works"))),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Backend> {
+        match s {
+            "nftables" | "nft" => Ok(Backend::Nftables),
+            "iptables" => Ok(Backend::Iptables),
+            _ => Err(Error::Config(format!("unknown firewall backend {:?}", s))),
+        }
+    }
+}
+
+/// Whether the legacy iptables has rules beyond the default policies, in
+/// which case it is the firewall the administrator actually uses.
+fn legacy_rules_present() -> bool {
+    Command::new("iptables")
+        .arg("-S")
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout).lines().any(|line| !line.starts_with("-P "))
+        })
+        .unwrap_or(false)
+}
+
+fn nft_script(net: &str, tun: &str, out: &str) -> String {
+    format!("table ip {table} {{\n\
+             \tchain postrouting {{\n\
+             \t\ttype nat hook postrouting priority 100; policy accept;\n\
+             \t\tip saddr {net} oifname \"{out}\" masquerade\n\
+             \t}}\n\
+             \tchain forward {{\n\
+             \t\ttype filter hook forward priority 0; policy accept;\n\
+             \t\tiifname \"{out}\" oifname \"{tun}\" ct state established,related accept\n\
+             \t\tip saddr {net} oifname \"{out}\" accept\n\
+             \t}}\n\
+             }}\n",
+            table = table(tun),
+            net = net,
+            tun = tun,
+            out = out)
+}
+
+/// The iptables rules as in the README, without the `-A`/`-D` action.
+fn iptables_rules(net: &str, tun: &str, out: &str) -> Vec<Vec<String>> {
+    let rule = |args: &[&str]| args.iter().map(|arg| String::from(*arg)).collect();
+    vec![rule(&["-t", "nat", "POSTROUTING", "-s", net, "-o", out, "-j", "MASQUERADE"]),
+         rule(&["FORWARD", "-i", out, "-o", tun, "-m", "state", "--state",
+                "ESTABLISHED,RELATED", "-j", "ACCEPT"]),
+         rule(&["FORWARD", "-s", net, "-o", out, "-j", "ACCEPT"])]
+}
+
+fn iptables(action: &str, rule: &[String]) -> Result<()> {
+    // The table option has to come before the chain.
+    let (table, chain) = if rule[0] == "-t" {
+        (&rule[..2], &rule[2..])
+    } else {
+        (&rule[..0], rule)
+    };
+    let status = try!(Command::new("iptables")
+        .args(table)
+        .arg(action)
+        .args(chain)
+        .status());
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Route(format!("iptables: {}", status)))
+    }
+}
+
+fn nft(script: &str) -> Result<()> {
+    let mut child = try!(Command::new("nft").args(&["-f", "-"]).stdin(Stdio::piped()).spawn());
+    try!(child.stdin.take().unwrap().write_all(script.as_bytes()));
+    let status = try!(child.wait());
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Route(format!("nft: {}", status)))
+    }
+}
+
+/// Masquerades traffic from `net` leaving through `out` and lets it be
+/// forwarded. The rules are removed on drop.
+pub struct Masquerade {
+    backend: Backend,
+    table: String,
+    rules: Vec<Vec<String>>,
+}
+
+impl Masquerade {
+    pub fn create(backend: Backend, net: &str, tun: &str, out: &str) -> Result<Masquerade> {
+        let mut masquerade = Masquerade {
+            backend: backend,
+            table: table(tun),
+            rules: Vec::new(),
+        };
+        match backend {
+            Backend::Nftables => {
+                // A leftover table from a crash would otherwise make the
+                // rules apply twice.
+                let _ = nft(&format!("delete table ip {}\n", masquerade.table));
+                try!(nft(&nft_script(net, tun, out)));
+                masquerade.rules.push(Vec::new());
+            }
+            Backend::Iptables => {
+                for rule in iptables_rules(net, tun, out) {
+                    try!(iptables("-A", &rule));
+                    masquerade.rules.push(rule);
+                }
+            }
+        }
+        Ok(masquerade)
+    }
+}
+
+impl Drop for Masquerade {
+    fn drop(&mut self) {
+        let result = match self.backend {
+            Backend::Nftables if !self.rules.is_empty() => {
+                nft(&format!("delete table ip {}\n", self.table))
+            }
+            Backend::Nftables => Ok(()),
+            Backend::Iptables => {
+                self.rules.iter().rev().map(|rule| iptables("-D", rule)).fold(Ok(()), Result::and)
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to remove firewall rules: {}", e);
+        }
+    }
+}
+
+#[test]
+fn rules_test() {
+    let script = nft_script("10.10.10.0/24", "tun0", "eth0");
+    assert!(script.starts_with("table ip kytan_tun0 {"));
+    assert!(script.contains("ip saddr 10.10.10.0/24 oifname \"eth0\" masquerade"));
+    let rules = iptables_rules("10.10.10.0/24", "tun0", "eth0");
+    assert_eq!(rules[0].join(" "),
+               "-t nat POSTROUTING -s 10.10.10.0/24 -o eth0 -j MASQUERADE");
+    assert_eq!("nft".parse::<Backend>().unwrap(), Backend::Nftables);
+    assert!("pf".parse::<Backend>().is_err());
+}
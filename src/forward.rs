@@ -0,0 +1,271 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ports of the server forwarded to services on clients.
+//!
+//! A forward such as `2222=10.10.10.5:22` accepts TCP connections on the
+//! server's port 2222 and connects each to port 22 of the client at
+//! 10.10.10.5, so that a client behind NAT can expose a service. The
+//! connection to the client is an ordinary socket: the kernel routes it into
+//! the TUN device like any other traffic for the subnet.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::str::FromStr;
+use mio;
+use error::{Error, Result};
+
+/// Tokens from the base up to this are for listeners.
+const MAX_LISTENERS: usize = 64;
+/// Upper bound on connections relayed at once.
+const MAX_SPLICES: usize = 1024;
+/// Bytes buffered per direction before reading stops.
+const BUFFER: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Forward {
+    pub listen: SocketAddr,
+    pub target: SocketAddr,
+}
+
+impl FromStr for Forward {
+    type Err = Error;
+
+    /// Parses `[ADDRESS:]PORT=ADDRESS:PORT`. The listening address defaults
+    /// to all of the server's.
+    fn from_str(s: &str) -> Result<Forward> {
+        let invalid = || Error::Config(format!("expected [ADDR:]PORT=ADDR:PORT, got {:?}", s));
+        let mut parts = s.splitn(2, '=');
+        let listen = parts.next().unwrap();
+        let listen = match listen.parse() {
+            Ok(port) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port),
+            Err(_) => try!(listen.parse().map_err(|_| invalid())),
+        };
+        let target = try!(parts.next().ok_or_else(|| invalid()));
+        Ok(Forward {
+            listen: listen,
+            target: try!(target.parse().map_err(|_| invalid())),
+        })
+    }
+}
+
+/// Bytes on their way from one socket to the other.
+#[derive(Default)]
+struct Half {
+    buf: Vec<u8>,
+    eof: bool,
+    shut: bool,
+}
+
+impl Half {
+    /// Moves what it can from `from` to `to`. Shuts down the write side of
+    /// `to` once `from` ended and everything was written.
+    fn pump(&mut self, from: &mut mio::tcp::TcpStream, to: &mut mio::tcp::TcpStream)
+            -> io::Result<()> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            while !self.eof && self.buf.len() < BUFFER {
+                match from.read(&mut chunk) {
+                    Ok(0) => self.eof = true,
+                    Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.buf.is_empty() {
+                break;
+            }
+            match to.write(&self.buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "socket closed")),
+                Ok(len) => {
+                    self.buf.drain(..len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        if self.eof && self.buf.is_empty() && !self.shut {
+            self.shut = true;
+            try!(to.shutdown(Shutdown::Write));
+        }
+        Ok(())
+    }
+}
+
+struct Splice {
+    inbound: mio::tcp::TcpStream,
+    outbound: mio::tcp::TcpStream,
+    connected: bool,
+    up: Half,
+    down: Half,
+}
+
+impl Splice {
+    fn pump(&mut self) -> io::Result<()> {
+        if !self.connected {
+            match self.outbound.take_error() {
+                Ok(None) if self.outbound.peer_addr().is_ok() => self.connected = true,
+                // Not connected yet.
+                Ok(None) => return Ok(()),
+                Ok(Some(e)) | Err(e) => return Err(e),
+            }
+        }
+        try!(self.up.pump(&mut self.inbound, &mut self.outbound));
+        self.down.pump(&mut self.outbound, &mut self.inbound)
+    }
+
+    fn finished(&self) -> bool {
+        self.up.shut && self.down.shut
+    }
+}
+
+pub struct Forwarder {
+    base: usize,
+    listeners: Vec<(mio::tcp::TcpListener, SocketAddr)>,
+    next: usize,
+    splices: HashMap<usize, Splice>,
+}
+
+impl Forwarder {
+    /// Sockets are registered with tokens from `base` upwards.
+    pub fn new(base: usize) -> Forwarder {
+        Forwarder {
+            base: base,
+            listeners: Vec::new(),
+            next: 0,
+            splices: HashMap::new(),
+        }
+    }
+
+    pub fn listen(&mut self, poll: &mio::Poll, forward: &Forward) -> Result<()> {
+        if self.listeners.len() >= MAX_LISTENERS {
+            return Err(Error::Config(format!("at most {} forwards", MAX_LISTENERS)));
+        }
+        let listener = try!(mio::tcp::TcpListener::bind(&forward.listen));
+        try!(poll.register(&listener,
+                           mio::Token(self.base + self.listeners.len()),
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+        info!("Forwarding {} to {}.", forward.listen, forward.target);
+        self.listeners.push((listener, forward.target));
+        Ok(())
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        token.0 >= self.base
+    }
+
+    /// Handles readiness of a listener or of either socket of a connection.
+    pub fn ready(&mut self, poll: &mio::Poll, token: mio::Token) {
+        let index = token.0 - self.base;
+        if index < MAX_LISTENERS {
+            return self.accept(poll, index);
+        }
+        let slot = (index - MAX_LISTENERS) / 2;
+        let result = match self.splices.get_mut(&slot) {
+            Some(splice) => splice.pump().map(|_| splice.finished()),
+            None => return,
+        };
+        match result {
+            Ok(false) => {}
+            Ok(true) => self.remove(poll, slot),
+            Err(e) => {
+                debug!("Forwarded connection failed: {}", e);
+                self.remove(poll, slot);
+            }
+        }
+    }
+
+    fn accept(&mut self, poll: &mio::Poll, index: usize) {
+        loop {
+            let (inbound, from, target) = match self.listeners.get(index) {
+                Some(&(ref listener, target)) => {
+                    match listener.accept() {
+                        Ok((inbound, from)) => (inbound, from, target),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                        Err(e) => {
+                            warn!("Failed to accept forwarded connection: {}", e);
+                            return;
+                        }
+                    }
+                }
+                None => return,
+            };
+            if self.splices.len() >= MAX_SPLICES {
+                warn!("Too many forwarded connections. Refusing {}.", from);
+                continue;
+            }
+            let outbound = match mio::tcp::TcpStream::connect(&target) {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    debug!("Failed to connect {} to {}: {}", from, target, e);
+                    continue;
+                }
+            };
+            let slot = self.allocate_slot();
+            let token = self.base + MAX_LISTENERS + slot * 2;
+            let interest = mio::Ready::readable() | mio::Ready::writable();
+            let edge = mio::PollOpt::edge();
+            let registered = poll.register(&inbound, mio::Token(token), interest, edge)
+                .and_then(|_| poll.register(&outbound, mio::Token(token + 1), interest, edge));
+            if let Err(e) = registered {
+                warn!("Failed to register forwarded connection: {}", e);
+                let _ = poll.deregister(&inbound);
+                continue;
+            }
+            debug!("Forwarding connection from {} to {}.", from, target);
+            self.splices.insert(slot,
+                                Splice {
+                                    inbound: inbound,
+                                    outbound: outbound,
+                                    connected: false,
+                                    up: Half::default(),
+                                    down: Half::default(),
+                                });
+        }
+    }
+
+    fn allocate_slot(&mut self) -> usize {
+        // There are more slots than connections, so a free one always exists.
+        loop {
+            let slot = self.next;
+            self.next = (self.next + 1) % (MAX_SPLICES * 2);
+            if !self.splices.contains_key(&slot) {
+                return slot;
+            }
+        }
+    }
+
+    fn remove(&mut self, poll: &mio::Poll, slot: usize) {
+        if let Some(splice) = self.splices.remove(&slot) {
+            let _ = poll.deregister(&splice.inbound);
+            let _ = poll.deregister(&splice.outbound);
+        }
+    }
+}
+
+#[test]
+fn forward_parse_test() {
+    assert_eq!("2222=10.10.10.5:22".parse::<Forward>().unwrap(),
+               Forward {
+                   listen: "0.0.0.0:2222".parse().unwrap(),
+                   target: "10.10.10.5:22".parse().unwrap(),
+               });
+    assert_eq!("127.0.0.1:8080=10.10.10.7:80".parse::<Forward>().unwrap().listen,
+               "127.0.0.1:8080".parse().unwrap());
+    assert!("2222".parse::<Forward>().is_err());
+    assert!("2222=10.10.10.5".parse::<Forward>().is_err());
+    assert!("ssh=10.10.10.5:22".parse::<Forward>().is_err());
+}
@@ -0,0 +1,375 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Admission by country and network operator.
+//!
+//! Source addresses are looked up in MaxMind databases (`.mmdb`), such as
+//! GeoLite2-Country for countries and GeoLite2-ASN for autonomous systems.
+//! Only the parts of the format needed for those lookups are implemented.
+
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::{self, FromStr};
+use error::{Error, Result};
+
+const METADATA_MARKER: &'static [u8] = b"\xab\xcd\xefMaxMind.com";
+/// Maps, arrays and pointers nested in one value at most, so that a
+/// malformed database cannot exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    UInt(u64),
+    Map(Vec<(String, Value)>),
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Map(ref entries) => entries.iter().find(|e| e.0 == key).map(|e| &e.1),
+            _ => None,
+        }
+    }
+}
+
+fn invalid() -> Error {
+    Error::Decode(String::from("malformed MaxMind database"))
+}
+
+/// Decodes the value at `offset` of `section`, returning it and the offset
+/// after it.
+fn decode(section: &[u8], offset: usize) -> Result<(Value, usize)> {
+    decode_nested(section, offset, 0, false)
+}
+
+/// Decodes a value `depth` levels down. A pointer may not lead to another
+/// pointer.
+fn decode_nested(section: &[u8], offset: usize, depth: usize, pointed: bool)
+                 -> Result<(Value, usize)> {
+    if depth > MAX_DEPTH {
+        return Err(invalid());
+    }
+    let byte = |i: usize| section.get(i).cloned().ok_or_else(invalid);
+    let ctrl = try!(byte(offset));
+    let mut pos = offset + 1;
+    let mut kind = ctrl >> 5;
+    if kind == 1 && pointed {
+        return Err(invalid());
+    }
+    if kind == 1 {
+        let vvv = (ctrl & 0x7) as usize;
+        let (len, base) = match (ctrl >> 3) & 0x3 {
+            0 => (1, 0),
+            1 => (2, 2048),
+            2 => (3, 526336),
+            _ => (4, 0),
+        };
+        if section.len() < pos + len {
+            return Err(invalid());
+        }
+        let mut target = if len == 4 { 0 } else { vvv };
+        for &b in &section[pos..pos + len] {
+            target = target << 8 | b as usize;
+        }
+        let (value, _) = try!(decode_nested(section, target + base, depth + 1, true));
+        return Ok((value, pos + len));
+    }
+    if kind == 0 {
+        kind = 7 + try!(byte(pos));
+        pos += 1;
+    }
+    let mut size = (ctrl & 0x1f) as usize;
+    if size >= 29 {
+        let extra = size - 28;
+        if section.len() < pos + extra {
+            return Err(invalid());
+        }
+        let n = section[pos..pos + extra].iter().fold(0, |n, &b| n << 8 | b as usize);
+        size = match extra {
+            1 => 29 + n,
+            2 => 285 + n,
+            _ => 65821 + n,
+        };
+        pos += extra;
+    }
+    match kind {
+        7 => {
+            let mut entries = Vec::with_capacity(size);
+            for _ in 0..size {
+                let (key, next) = try!(decode_nested(section, pos, depth + 1, false));
+                let (value, next) = try!(decode_nested(section, next, depth + 1, false));
+                pos = next;
+                if let Value::Str(key) = key {
+                    entries.push((key, value));
+                }
+            }
+            Ok((Value::Map(entries), pos))
+        }
+        11 => {
+            for _ in 0..size {
+                pos = try!(decode_nested(section, pos, depth + 1, false)).1;
+            }
+            Ok((Value::Other, pos))
+        }
+        14 => Ok((Value::Other, pos)),
+        _ => {
+            if section.len() < pos + size {
+                return Err(invalid());
+            }
+            let bytes = &section[pos..pos + size];
+            let value = match kind {
+                2 => Value::Str(try!(str::from_utf8(bytes).map_err(|_| invalid())).to_owned()),
+                5 | 6 | 9 if size <= 8 => {
+                    Value::UInt(bytes.iter().fold(0, |n, &b| n << 8 | b as u64))
+                }
+                _ => Value::Other,
+            };
+            Ok((value, pos + size))
+        }
+    }
+}
+
+/// What a database knows about an address.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Record {
+    /// ISO 3166-1 code, such as `DE`.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+pub struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    // Start of the data section.
+    data_start: usize,
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database> {
+        let mut data = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut data));
+        Database::parse(data)
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Database> {
+        let marker = try!(data.windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(invalid));
+        let metadata = &data[marker + METADATA_MARKER.len()..];
+        let (metadata, _) = try!(decode(metadata, 0));
+        let field = |key| match metadata.get(key) {
+            Some(&Value::UInt(n)) => Ok(n),
+            _ => Err(Error::Decode(format!("MaxMind database lacks {}", key))),
+        };
+        let node_count = try!(field("node_count")) as usize;
+        let record_size = try!(field("record_size")) as usize;
+        let ip_version = try!(field("ip_version"));
+        if record_size != 24 && record_size != 28 && record_size != 32 {
+            return Err(Error::Decode(format!("unsupported record size {}", record_size)));
+        }
+        let data_start = match node_count.checked_mul(record_size / 4) {
+            Some(tree) if tree + 16 <= marker => tree + 16,
+            _ => return Err(invalid()),
+        };
+        Ok(Database {
+            data: data,
+            node_count: node_count,
+            record_size: record_size,
+            ip_version: ip_version,
+            data_start: data_start,
+        })
+    }
+
+    fn record(&self, node: usize, right: bool) -> Result<usize> {
+        let at = node * self.record_size / 4;
+        let bytes = try!(self.data.get(at..at + self.record_size / 4).ok_or_else(invalid));
+        let b = |i: usize| bytes[i] as usize;
+        Ok(match (self.record_size, right) {
+            (24, false) => b(0) << 16 | b(1) << 8 | b(2),
+            (24, true) => b(3) << 16 | b(4) << 8 | b(5),
+            (28, false) => (b(3) & 0xf0) << 20 | b(0) << 16 | b(1) << 8 | b(2),
+            (28, true) => (b(3) & 0x0f) << 24 | b(4) << 16 | b(5) << 8 | b(6),
+            (_, false) => b(0) << 24 | b(1) << 16 | b(2) << 8 | b(3),
+            (_, true) => b(4) << 24 | b(5) << 16 | b(6) << 8 | b(7),
+        })
+    }
+
+    pub fn lookup(&self, addr: IpAddr) -> Result<Record> {
+        let bits: Vec<bool> = match addr {
+            IpAddr::V4(addr) => {
+                let mut bits = if self.ip_version == 6 { vec![false; 96] } else { Vec::new() };
+                bits.extend((0..32).map(|i| addr.octets()[i / 8] & (0x80 >> (i % 8)) != 0));
+                bits
+            }
+            IpAddr::V6(_) if self.ip_version != 6 => return Ok(Record::default()),
+            IpAddr::V6(addr) => {
+                (0..128).map(|i| addr.octets()[i / 8] & (0x80 >> (i % 8)) != 0).collect()
+            }
+        };
+        let mut node = 0;
+        for bit in bits {
+            if node >= self.node_count {
+                break;
+            }
+            node = try!(self.record(node, bit));
+        }
+        if node <= self.node_count {
+            return Ok(Record::default());
+        }
+        // Records past the tree point into the data section, which starts
+        // after 16 bytes of zeros.
+        let offset = try!((node - self.node_count).checked_sub(16).ok_or_else(invalid));
+        let (value, _) = try!(decode(&self.data[self.data_start..], offset));
+        let country = value.get("country")
+            .or_else(|| value.get("registered_country"))
+            .and_then(|country| country.get("iso_code"));
+        Ok(Record {
+            country: match country {
+                Some(&Value::Str(ref code)) => Some(code.clone()),
+                _ => None,
+            },
+            asn: match value.get("autonomous_system_number") {
+                Some(&Value::UInt(asn)) => Some(asn as u32),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// A country code such as `DE`, or an autonomous system such as `AS13335`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    Country(String),
+    Asn(u32),
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rule> {
+        let upper = s.to_uppercase();
+        if upper.starts_with("AS") {
+            if let Ok(asn) = upper[2..].parse() {
+                return Ok(Rule::Asn(asn));
+            }
+        }
+        if upper.len() == 2 && upper.chars().all(|c| c >= 'A' && c <= 'Z') {
+            return Ok(Rule::Country(upper));
+        }
+        Err(Error::Config(format!("expected a country code or ASn, got {:?}", s)))
+    }
+}
+
+impl Rule {
+    fn matches(&self, record: &Record) -> bool {
+        match *self {
+            Rule::Country(ref code) => record.country.as_ref() == Some(code),
+            Rule::Asn(asn) => record.asn == Some(asn),
+        }
+    }
+}
+
+/// Databases plus allow and deny lists. Addresses matching a deny rule are
+/// refused; with allow rules, so are addresses matching none of them.
+pub struct Policy {
+    pub databases: Vec<Database>,
+    pub allow: Vec<Rule>,
+    pub deny: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn admits(&self, addr: IpAddr) -> bool {
+        let mut record = Record::default();
+        for database in &self.databases {
+            match database.lookup(addr) {
+                Ok(found) => {
+                    record.country = record.country.or(found.country);
+                    record.asn = record.asn.or(found.asn);
+                }
+                Err(e) => warn!("GeoIP lookup of {} failed: {}", addr, e),
+            }
+        }
+        !self.deny.iter().any(|rule| rule.matches(&record)) &&
+        (self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(&record)))
+    }
+}
+
+#[test]
+fn decode_test() {
+    // A pointer to itself, and a pointer to a pointer.
+    assert!(decode(&[0x20, 0x00], 0).is_err());
+    assert!(decode(&[0x20, 0x02, 0x42, b'D', b'E'], 0).is_ok());
+    assert!(decode(&[0x20, 0x02, 0x20, 0x04, 0x42, b'D', b'E'], 0).is_err());
+    // Arrays of one element, nested beyond the limit around an empty string.
+    let nested = |depth: usize| {
+        let mut data: Vec<u8> = (0..depth).flat_map(|_| vec![0x01, 0x04]).collect();
+        data.push(0x40);
+        data
+    };
+    assert!(decode(&nested(MAX_DEPTH + 1), 0).is_err());
+    let shallow = nested(3);
+    assert_eq!(decode(&shallow, 0).unwrap(), (Value::Other, shallow.len()));
+}
+
+#[test]
+fn lookup_test() {
+    // One node: 0.0.0.0/1 maps to {country: {iso_code: "DE"}}, the rest to
+    // nothing.
+    let mut data = vec![0, 0, 17, 0, 0, 1];
+    data.extend_from_slice(&[0; 16]);
+    data.push(0xe1);
+    data.push(0x47);
+    data.extend_from_slice(b"country");
+    data.push(0xe1);
+    data.push(0x48);
+    data.extend_from_slice(b"iso_code");
+    data.push(0x42);
+    data.extend_from_slice(b"DE");
+    data.extend_from_slice(METADATA_MARKER);
+    data.push(0xe3);
+    data.push(0x4a);
+    data.extend_from_slice(b"node_count");
+    data.extend_from_slice(&[0xc1, 1]);
+    data.push(0x4b);
+    data.extend_from_slice(b"record_size");
+    data.extend_from_slice(&[0xa1, 24]);
+    data.push(0x4a);
+    data.extend_from_slice(b"ip_version");
+    data.extend_from_slice(&[0xa1, 4]);
+    let db = Database::parse(data).unwrap();
+    assert_eq!(db.lookup("1.2.3.4".parse().unwrap()).unwrap().country,
+               Some(String::from("DE")));
+    assert_eq!(db.lookup("200.2.3.4".parse().unwrap()).unwrap(), Record::default());
+    // A record pointing into the separator between tree and data.
+    let mut broken = db.data.clone();
+    broken[2] = 5;
+    let broken = Database::parse(broken).unwrap();
+    assert!(broken.lookup("1.2.3.4".parse().unwrap()).is_err());
+
+    let policy = Policy {
+        databases: vec![db],
+        allow: vec!["de".parse().unwrap()],
+        deny: vec![],
+    };
+    assert!(policy.admits("1.2.3.4".parse().unwrap()));
+    assert!(!policy.admits("200.2.3.4".parse().unwrap()));
+    assert_eq!("AS13335".parse::<Rule>().unwrap(), Rule::Asn(13335));
+    assert!("Germany".parse::<Rule>().is_err());
+}
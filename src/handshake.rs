@@ -0,0 +1,398 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Handshake processing off the event loop.
+//!
+//! Requests are checked, issued tokens and answered with a roaming key on a
+//! worker thread, so a burst of handshakes does not delay packets of
+//! established clients. The loop only hands out an address once the worker's
+//! verdict comes back. The queue is bounded by the `handshakes` limit;
+//! requests beyond it are dropped and the clients retry.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use mio;
+use rand::Rng;
+use auth::{self, Authenticator};
+use replay;
+use limits::Limits;
+use identity;
+use network::{Capabilities, Id, Token};
+use roaming;
+use error::Result;
+
+pub struct Job {
+    /// Whether the request was padded, so the response should be too.
+    pub padded: bool,
+    /// Whether the request came in on the session port, where the response
+    /// goes out too.
+    pub session_port: bool,
+    pub addr: SocketAddr,
+    pub caps: Capabilities,
+    pub resume: Option<(Id, Token)>,
+    pub credential: Option<String>,
+    /// Proof of a group secret, in place of `credential`.
+    pub stamp: Option<replay::Stamp>,
+    pub proof: Option<identity::Proof>,
+    /// The client's half of the roaming key.
+    pub roam_key: Vec<u8>,
+}
+
+pub struct Verdict {
+    pub padded: bool,
+    pub session_port: bool,
+    pub addr: SocketAddr,
+    /// What the client offered.
+    pub caps: Capabilities,
+    pub resume: Option<(Id, Token)>,
+    pub token: Token,
+    /// Index of the client's group plus one, zero when it has none, or
+    /// `None` when it presented a credential no group knows.
+    pub group: Option<usize>,
+    /// The key the client proved it holds, if any.
+    pub identity: Option<identity::Key>,
+    /// The user the authenticator admitted, if any.
+    pub user: Option<String>,
+    pub roaming: Option<roaming::Binding>,
+    /// The server's half of the roaming key, empty without `roaming`.
+    pub roam_key: Vec<u8>,
+}
+
+pub struct Worker {
+    jobs: SyncSender<Job>,
+    verdicts: Receiver<Verdict>,
+    readiness: mio::SetReadiness,
+    _registration: mio::Registration,
+}
+
+/// Identity proofs seen recently.
+struct Proofs {
+    /// Keys of the devices the server has addresses reserved for.
+    devices: HashSet<identity::Key>,
+    of_devices: replay::Cache,
+    // Anyone can make a key and prove it, so proofs of other keys are kept
+    // apart, where they cannot crowd out those of devices.
+    of_others: replay::Cache,
+}
+
+impl Proofs {
+    fn new(devices: HashSet<identity::Key>, capacity: usize) -> Proofs {
+        Proofs {
+            devices: devices,
+            of_devices: replay::Cache::new(capacity),
+            of_others: replay::Cache::new(capacity),
+        }
+    }
+
+    /// The key `proof` was made with, if it is valid, fresh and new.
+    fn check(&mut self,
+             proof: &identity::Proof,
+             resume: Option<(Id, Token)>)
+             -> Option<identity::Key> {
+        let key = match proof.key() {
+            Some(key) if proof.verify(&key, resume) => key,
+            _ => return None,
+        };
+        let seen = if self.devices.contains(&key) {
+            &mut self.of_devices
+        } else {
+            &mut self.of_others
+        };
+        if seen.check(proof.timestamp, proof.nonce, replay::unix_secs()) {
+            Some(key)
+        } else {
+            None
+        }
+    }
+}
+
+fn process(job: Job,
+           groups: &[(String, String)],
+           require_stamps: bool,
+           authenticator: &mut Option<Box<Authenticator>>,
+           stamps: &mut [replay::Cache],
+           proofs: &mut Proofs,
+           rng: &mut Box<Rng + Send>,
+           backend_id: Option<u8>)
+           -> Verdict {
+    let identity = match job.proof {
+        Some(ref proof) => {
+            match proofs.check(proof, job.resume) {
+                Some(key) => Some(key),
+                None => {
+                    warn!("Invalid, stale or replayed identity from {}.", job.addr);
+                    return Verdict {
+                        padded: job.padded,
+                        session_port: job.session_port,
+                        addr: job.addr,
+                        caps: job.caps,
+                        resume: job.resume,
+                        token: 0,
+                        group: None,
+                        identity: None,
+                        user: None,
+                        roaming: None,
+                        roam_key: Vec::new(),
+                    };
+                }
+            }
+        }
+        None => None,
+    };
+    let mut user = None;
+    let group = match (job.credential, job.stamp) {
+        (_, Some(stamp)) => {
+            match groups.iter().position(|&(_, ref secret)| stamp.verify(secret, job.resume)) {
+                Some(i) if stamps[i].check(stamp.timestamp, stamp.nonce, replay::unix_secs()) => {
+                    Some(i + 1)
+                }
+                Some(_) => {
+                    warn!("Stale or replayed handshake from {}.", job.addr);
+                    None
+                }
+                None if groups.is_empty() && authenticator.is_none() => Some(0),
+                None => None,
+            }
+        }
+        (Some(ref credential), None) => {
+            match groups.iter().position(|&(_, ref secret)| secret == credential) {
+                Some(_) if require_stamps => {
+                    warn!("Refusing the bare group credential from {}; stamps are required.",
+                          job.addr);
+                    None
+                }
+                Some(i) => Some(i + 1),
+                None if authenticator.is_some() => {
+                    let accepted = auth::split(credential).map_or(false, |(user, password)| {
+                        match authenticator.as_mut().unwrap().authenticate(user, password) {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                warn!("Failed to authenticate {}: {}", user, e);
+                                false
+                            }
+                        }
+                    });
+                    if accepted {
+                        user = auth::split(credential).map(|(user, _)| String::from(user));
+                        Some(0)
+                    } else {
+                        None
+                    }
+                }
+                None if groups.is_empty() => Some(0),
+                None => None,
+            }
+        }
+        (None, None) if authenticator.is_some() => None,
+        (None, None) => Some(0),
+    };
+    let mut token: Token = rng.gen::<Token>();
+    if let Some(backend) = backend_id {
+        token = token & !(0xff << 56) | (backend as Token) << 56;
+    }
+    let (roaming, roam_key) = match roaming::Binding::answer(&job.roam_key) {
+        Some((binding, public)) => (Some(binding), public),
+        None => (None, Vec::new()),
+    };
+    Verdict {
+        padded: job.padded,
+        session_port: job.session_port,
+        addr: job.addr,
+        caps: job.caps,
+        resume: job.resume,
+        token: token,
+        group: group,
+        identity: identity,
+        user: user,
+        roaming: roaming,
+        roam_key: roam_key,
+    }
+}
+
+impl Worker {
+    /// Starts the worker. `poll` becomes readable on `token` when verdicts
+    /// are waiting.
+    pub fn spawn(poll: &mio::Poll,
+                 token: mio::Token,
+                 groups: Vec<(String, String)>,
+                 require_stamps: bool,
+                 mut authenticator: Option<Box<Authenticator>>,
+                 mut rng: Box<Rng + Send>,
+                 backend_id: Option<u8>,
+                 devices: HashSet<identity::Key>,
+                 limits: &Limits)
+                 -> Result<Worker> {
+        let (registration, readiness) = mio::Registration::new2();
+        try!(poll.register(&registration, token, mio::Ready::readable(), mio::PollOpt::level()));
+        let (jobs, queue) = mpsc::sync_channel::<Job>(limits.handshakes);
+        let (results, verdicts) = mpsc::channel();
+        let wakeup = readiness.clone();
+        // One cache for each group, so that no group can lock out another.
+        let mut stamps: Vec<replay::Cache> =
+            groups.iter().map(|_| replay::Cache::new(limits.replay_entries)).collect();
+        let mut proofs = Proofs::new(devices, limits.replay_entries);
+        try!(thread::Builder::new()
+            .name(String::from("kytan-handshake"))
+            .spawn(move || for job in queue {
+                let verdict = process(job,
+                                      &groups,
+                                      require_stamps,
+                                      &mut authenticator,
+                                      &mut stamps,
+                                      &mut proofs,
+                                      &mut rng,
+                                      backend_id);
+                if results.send(verdict).is_err() {
+                    break;
+                }
+                let _ = wakeup.set_readiness(mio::Ready::readable());
+            }));
+        Ok(Worker {
+            jobs: jobs,
+            verdicts: verdicts,
+            readiness: readiness,
+            _registration: registration,
+        })
+    }
+
+    /// Queues a request. False if the queue is full.
+    pub fn submit(&self, job: Job) -> bool {
+        match self.jobs.try_send(job) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) |
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Verdicts reached since the last call.
+    pub fn verdicts(&self) -> Vec<Verdict> {
+        // Clear first: a verdict arriving meanwhile sets it again.
+        let _ = self.readiness.set_readiness(mio::Ready::empty());
+        self.verdicts.try_iter().collect()
+    }
+}
+
+#[test]
+fn process_test() {
+    let job = |credential: Option<&str>| {
+        Job {
+            padded: false,
+            session_port: false,
+            addr: "192.0.2.1:4000".parse().unwrap(),
+            caps: Capabilities::new(0),
+            resume: None,
+            credential: credential.map(String::from),
+            stamp: None,
+            proof: None,
+            roam_key: Vec::new(),
+        }
+    };
+    let stamped = |secret: &str| {
+        Job {
+            stamp: Some(replay::Stamp::new(secret, 1, None)),
+            ..job(None)
+        }
+    };
+    let groups = vec![(String::from("office"), String::from("s3cret"))];
+    let mut rng: Box<Rng + Send> = Box::new(::rand::StdRng::new().unwrap());
+    let mut stamps = vec![replay::Cache::new(16), replay::Cache::new(16)];
+    let device = identity::Identity::generate();
+    let mut proofs = Proofs::new(vec![device.public()].into_iter().collect(), 16);
+    let mut none = None;
+    let verdict = process(job(None),
+                          &groups,
+                          false,
+                          &mut none,
+                          &mut stamps,
+                          &mut proofs,
+                          &mut rng,
+                          Some(7));
+    assert_eq!(::network::token_backend(verdict.token), 7);
+
+    struct Alice;
+    impl Authenticator for Alice {
+        fn authenticate(&mut self, user: &str, password: &str) -> Result<bool> {
+            Ok(user == "alice" && password == "hunter2")
+        }
+    }
+    let mut alice: Option<Box<Authenticator>> = Some(Box::new(Alice));
+    let mut required = |job: Job, groups: &[(String, String)], with_alice: bool| {
+        let authenticator = if with_alice { &mut alice } else { &mut none };
+        process(job, groups, true, authenticator, &mut stamps, &mut proofs, &mut rng, None).group
+    };
+    assert_eq!(required(job(Some("s3cret")), &groups, false), None);
+    let fresh = Job { stamp: Some(replay::Stamp::new("s3cret", 2, None)), ..job(None) };
+    assert_eq!(required(fresh, &groups, false), Some(1));
+    assert_eq!(required(job(Some("alice:hunter2")), &groups, true), Some(0));
+    assert_eq!(required(stamped("alice:hunter2"), &[], true), None);
+    let mut group = |job: Job, groups: &[(String, String)], with_alice: bool| {
+        let authenticator = if with_alice { &mut alice } else { &mut none };
+        process(job, groups, false, authenticator, &mut stamps, &mut proofs, &mut rng, None)
+            .group
+    };
+    assert_eq!(group(job(Some("s3cret")), &groups, false), Some(1));
+    assert_eq!(group(job(Some("guess")), &groups, false), None);
+    assert_eq!(group(job(Some("guess")), &[], false), Some(0));
+    assert_eq!(group(job(None), &groups, false), Some(0));
+    assert_eq!(group(job(Some("alice:hunter2")), &groups, true), Some(0));
+    assert_eq!(group(job(Some("alice:guess")), &groups, true), None);
+    assert_eq!(group(job(Some("s3cret")), &groups, true), Some(1));
+    assert_eq!(group(job(None), &groups, true), None);
+
+    let stamp = stamped("s3cret");
+    let replayed = Job { stamp: stamp.stamp.clone(), ..job(None) };
+    assert_eq!(group(stamp, &groups, false), Some(1));
+    assert_eq!(group(replayed, &groups, false), None);
+    assert_eq!(group(stamped("guess"), &groups, false), None);
+    // A group that fills its cache leaves the others alone.
+    let two = vec![groups[0].clone(), (String::from("lab"), String::from("l4b"))];
+    for nonce in 0..32 {
+        let flood = Job { stamp: Some(replay::Stamp::new("l4b", nonce, None)), ..job(None) };
+        group(flood, &two, false);
+    }
+    let office = Job { stamp: Some(replay::Stamp::new("s3cret", 99, None)), ..job(None) };
+    assert_eq!(group(office, &two, false), Some(1));
+
+    let admitted = process(job(Some("alice:hunter2")),
+                           &groups,
+                           false,
+                           &mut alice,
+                           &mut stamps,
+                           &mut proofs,
+                           &mut rng,
+                           None);
+    assert_eq!(admitted.user, Some(String::from("alice")));
+
+    let proved = Job { proof: Some(device.prove(None)), ..job(None) };
+    let replayed = Job { proof: proved.proof.clone(), ..job(None) };
+    let forged = Job { proof: Some(device.prove(Some((3, 7)))), ..job(None) };
+    let mut verdict = |job: Job| {
+        let verdict =
+            process(job, &groups, false, &mut none, &mut stamps, &mut proofs, &mut rng, None);
+        (verdict.group, verdict.identity)
+    };
+    assert_eq!(verdict(proved), (Some(0), Some(device.public())));
+    assert_eq!(verdict(replayed), (None, None));
+    assert_eq!(verdict(forged), (None, None));
+    // Proofs of unknown keys, however many, leave room for the device's.
+    for _ in 0..32 {
+        let stranger = identity::Identity::generate();
+        verdict(Job { proof: Some(stranger.prove(None)), ..job(None) });
+    }
+    let again = Job { proof: Some(device.prove(None)), ..job(None) };
+    assert_eq!(verdict(again), (Some(0), Some(device.public())));
+}
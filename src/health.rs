@@ -0,0 +1,61 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Liveness endpoint for container probes and monitoring.
+//!
+//! Every connection gets a one-shot HTTP response describing the tunnel and
+//! is closed. The response is written from the event loop itself, so an
+//! answer at all proves the loop is alive.
+
+use std::io::Write;
+use std::net::SocketAddr;
+use mio;
+use error::Result;
+
+pub struct Endpoint {
+    listener: mio::tcp::TcpListener,
+}
+
+impl Endpoint {
+    pub fn open(poll: &mio::Poll, token: mio::Token, addr: &SocketAddr) -> Result<Endpoint> {
+        let listener = try!(mio::tcp::TcpListener::bind(addr));
+        try!(poll.register(&listener, token, mio::Ready::readable(), mio::PollOpt::level()));
+        info!("Serving health checks on {}.", addr);
+        Ok(Endpoint { listener: listener })
+    }
+
+    /// Answers every pending connection with `status`, one `key value` pair
+    /// per line.
+    pub fn respond(&self, status: &[(&str, String)]) {
+        let body: String = status.iter()
+            .map(|&(key, ref value)| format!("{} {}\n", key, value))
+            .collect();
+        let response = format!("HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\
+                                Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                               body.len(),
+                               body);
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, addr)) => {
+                    // A fresh socket has room for this small response.
+                    if let Err(e) = stream.write_all(response.as_bytes()) {
+                        debug!("Failed to answer health check from {}: {}", addr, e);
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    }
+}
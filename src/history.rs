@@ -0,0 +1,278 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection history in an SQLite database.
+//!
+//! Every session becomes a row with its client id, identity, endpoint,
+//! start and end times and byte totals. Rows are written on a thread of
+//! their own, so a slow disk never stalls the tunnel, and every value goes
+//! in as a bound parameter. `kytan history` prints the most recent sessions.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rusqlite::{self, Connection, OpenFlags};
+use error::{Error, Result};
+
+/// Where the server keeps its history unless told otherwise.
+pub const DEFAULT_PATH: &'static str = "/var/lib/kytan/history.db";
+
+const SCHEMA: &'static str = "CREATE TABLE IF NOT EXISTS sessions (
+    client INTEGER NOT NULL,
+    identity TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    ended INTEGER,
+    reason TEXT,
+    rx_bytes INTEGER NOT NULL DEFAULT 0,
+    tx_bytes INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS sessions_started ON sessions (started);";
+
+/// The columns `query` prints.
+const COLUMNS: &'static [&'static str] = &["client", "identity", "endpoint", "started",
+                                          "duration", "reason", "rx_bytes", "tx_bytes"];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub path: PathBuf,
+    /// Sessions that started longer ago are deleted. `None` keeps them all.
+    pub retention: Option<Duration>,
+}
+
+impl Config {
+    pub fn new<P: AsRef<Path>>(path: P) -> Config {
+        Config {
+            path: path.as_ref().to_path_buf(),
+            retention: Some(Duration::from_secs(90 * 24 * 3600)),
+        }
+    }
+}
+
+fn unix_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+fn failed(path: &Path, e: rusqlite::Error) -> Error {
+    Error::Config(format!("{}: {}", path.display(), e))
+}
+
+/// A change to the history, sent to the writer thread.
+enum Change {
+    Start {
+        client: u8,
+        identity: String,
+        endpoint: String,
+        at: i64,
+    },
+    Roam { client: u8, endpoint: String },
+    End {
+        client: u8,
+        reason: String,
+        rx_bytes: i64,
+        tx_bytes: i64,
+        at: i64,
+    },
+}
+
+fn retain(conn: &Connection, retention: Option<Duration>) -> rusqlite::Result<()> {
+    if let Some(retention) = retention {
+        let oldest = unix_secs() - retention.as_secs() as i64;
+        try!(conn.execute("DELETE FROM sessions WHERE started < ?1", params![oldest]));
+    }
+    Ok(())
+}
+
+fn apply(conn: &Connection, change: &Change, retention: Option<Duration>) -> rusqlite::Result<()> {
+    match *change {
+        Change::Start { client, ref identity, ref endpoint, at } => {
+            try!(conn.execute("UPDATE sessions SET ended = ?1, reason = 'replaced' \
+                               WHERE client = ?2 AND ended IS NULL",
+                              params![at, client]));
+            try!(conn.execute("INSERT INTO sessions (client, identity, endpoint, started) \
+                               VALUES (?1, ?2, ?3, ?4)",
+                              params![client, identity, endpoint, at]));
+        }
+        Change::Roam { client, ref endpoint } => {
+            try!(conn.execute("UPDATE sessions SET endpoint = ?1 \
+                               WHERE client = ?2 AND ended IS NULL",
+                              params![endpoint, client]));
+        }
+        Change::End { client, ref reason, rx_bytes, tx_bytes, at } => {
+            try!(conn.execute("UPDATE sessions SET ended = ?1, reason = ?2, rx_bytes = ?3, \
+                               tx_bytes = ?4 WHERE client = ?5 AND ended IS NULL",
+                              params![at, reason, rx_bytes, tx_bytes, client]));
+            try!(retain(conn, retention));
+        }
+    }
+    Ok(())
+}
+
+/// Writes `first` and whatever else is already waiting in one transaction,
+/// to catch up after a slow write.
+fn record(conn: &mut Connection,
+          first: Change,
+          receiver: &mpsc::Receiver<Change>,
+          retention: Option<Duration>)
+          -> rusqlite::Result<()> {
+    let transaction = try!(conn.transaction());
+    try!(apply(&transaction, &first, retention));
+    while let Ok(next) = receiver.try_recv() {
+        try!(apply(&transaction, &next, retention));
+    }
+    transaction.commit()
+}
+
+pub struct History {
+    sender: Option<mpsc::Sender<Change>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl History {
+    /// Creates the database if needed. Sessions a previous run left open
+    /// are closed as interrupted.
+    pub fn open(config: Config) -> Result<History> {
+        if let Some(dir) = config.path.parent() {
+            try!(::std::fs::create_dir_all(dir));
+        }
+        let path = config.path;
+        let retention = config.retention;
+        let mut conn = try!(Connection::open(&path).map_err(|e| failed(&path, e)));
+        try!(conn.execute_batch(SCHEMA)
+            .and_then(|_| {
+                conn.execute("UPDATE sessions SET ended = started, reason = 'interrupted' \
+                              WHERE ended IS NULL",
+                             params![])
+            })
+            .and_then(|_| retain(&conn, retention))
+            .map_err(|e| failed(&path, e)));
+        info!("Recording connection history in {}.", path.display());
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || {
+            while let Ok(change) = receiver.recv() {
+                if let Err(e) = record(&mut conn, change, &receiver, retention) {
+                    warn!("Failed to record connection history: {}", failed(&path, e));
+                }
+            }
+        });
+        Ok(History {
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    fn send(&self, change: Change) {
+        if let Some(ref sender) = self.sender {
+            // The writer only goes away with the history.
+            let _ = sender.send(change);
+        }
+    }
+
+    pub fn start(&self, client: u8, identity: &str, endpoint: &SocketAddr) {
+        self.send(Change::Start {
+            client: client,
+            identity: String::from(identity),
+            endpoint: endpoint.to_string(),
+            at: unix_secs(),
+        });
+    }
+
+    /// Notes that the client moved to `endpoint`.
+    pub fn roam(&self, client: u8, endpoint: &SocketAddr) {
+        self.send(Change::Roam {
+            client: client,
+            endpoint: endpoint.to_string(),
+        });
+    }
+
+    pub fn end(&self, client: u8, reason: &str, rx_bytes: u64, tx_bytes: u64) {
+        self.send(Change::End {
+            client: client,
+            reason: String::from(reason),
+            rx_bytes: rx_bytes as i64,
+            tx_bytes: tx_bytes as i64,
+            at: unix_secs(),
+        });
+    }
+}
+
+impl Drop for History {
+    /// Waits for pending statements to be written.
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Lays `rows` out in left-aligned columns under `COLUMNS`.
+fn table(rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = COLUMNS.iter().map(|name| name.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = ::std::cmp::max(*width, cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<String>| {
+        let padded: Vec<String> = cells.iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:1$}", cell, width))
+            .collect();
+        format!("{}\n", padded.join("  ").trim_end())
+    };
+    let mut out = line(COLUMNS.iter().map(|name| String::from(*name)).collect());
+    out.push_str(&line(widths.iter().map(|&width| "-".repeat(width)).collect()));
+    for row in rows {
+        out.push_str(&line(row.clone()));
+    }
+    out
+}
+
+/// The `limit` most recent sessions in the database at `path`, as a table.
+pub fn query(path: &Path, limit: usize) -> Result<String> {
+    let conn = try!(Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| failed(path, e)));
+    let mut statement = try!(conn.prepare("SELECT CAST(client AS TEXT), identity, endpoint, \
+                       datetime(started, 'unixepoch'), \
+                       CASE WHEN ended IS NULL THEN 'active' \
+                       ELSE (ended - started) || 's' END, \
+                       coalesce(reason, ''), CAST(rx_bytes AS TEXT), \
+                       CAST(tx_bytes AS TEXT) \
+                       FROM sessions ORDER BY started DESC, rowid DESC LIMIT ?1")
+        .map_err(|e| failed(path, e)));
+    let rows = try!(statement.query_map(params![limit as i64],
+                   |row| -> rusqlite::Result<Vec<String>> {
+                       (0..COLUMNS.len()).map(|i| row.get(i)).collect()
+                   })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<Vec<String>>>>())
+        .map_err(|e| failed(path, e)));
+    Ok(table(&rows))
+}
+
+#[test]
+fn table_test() {
+    let row = vec!["2", "office", "203.0.113.5:40000", "2017-01-01 00:00:00", "60s", "expired",
+                   "1500", "3000"];
+    let out = table(&[row.iter().map(|cell| String::from(*cell)).collect()]);
+    assert_eq!(out.lines().collect::<Vec<_>>(),
+               vec!["client  identity  endpoint           started              duration  \
+                     reason   rx_bytes  tx_bytes",
+                    "------  --------  -----------------  -------------------  --------  \
+                     -------  --------  --------",
+                    "2       office    203.0.113.5:40000  2017-01-01 00:00:00  60s       \
+                     expired  1500      3000"]);
+}
@@ -0,0 +1,208 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Persistent client identities.
+//!
+//! A client may keep an Ed25519 key pair in a file, generated on first use.
+//! Its handshakes then carry a proof: a signature over the time, a nonce and
+//! the session being resumed. The server checks it like a stamp and can hand
+//! the device the same address, or a fixed one, whatever its session token.
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use rand::{self, Rng};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+use x25519_dalek::x25519;
+use replay;
+use network::{Id, Token};
+use utils;
+use error::{Error, Result};
+
+/// An Ed25519 public key.
+pub type Key = [u8; 32];
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Proof {
+    pub public: Vec<u8>,
+    /// Seconds since the epoch.
+    pub timestamp: u64,
+    pub nonce: u64,
+    pub signature: Vec<u8>,
+}
+
+fn signed(timestamp: u64, nonce: u64, resume: Option<(Id, Token)>) -> Vec<u8> {
+    let mut data = b"kytan-identity".to_vec();
+    for value in &[timestamp, nonce, resume.map_or(0, |(_, token)| token)] {
+        data.extend((0..8).map(|i| (value >> (56 - 8 * i)) as u8));
+    }
+    data.push(resume.map_or(0, |(id, _)| id));
+    data
+}
+
+impl Proof {
+    /// The key the proof claims to be made with. Only `verify` tells whether
+    /// it was.
+    pub fn key(&self) -> Option<Key> {
+        parse_bytes(&self.public)
+    }
+
+    /// Whether the proof was made with `key`. Freshness is left to a
+    /// `replay::Cache`.
+    pub fn verify(&self, key: &Key, resume: Option<(Id, Token)>) -> bool {
+        if self.public[..] != key[..] {
+            return false;
+        }
+        let (public, signature) = match (VerifyingKey::from_bytes(key),
+                                         Signature::from_slice(&self.signature)) {
+            (Ok(public), Ok(signature)) => (public, signature),
+            _ => return false,
+        };
+        let data = signed(self.timestamp, self.nonce, resume);
+        public.verify_strict(&data, &signature).is_ok()
+    }
+}
+
+fn parse_bytes(bytes: &[u8]) -> Option<Key> {
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(bytes);
+    Some(key)
+}
+
+/// A key in hex, as logged and as given to `--device`.
+pub fn fingerprint(key: &Key) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a key printed by `fingerprint()`.
+pub fn parse_key(s: &str) -> Result<Key> {
+    let bytes: Vec<u8> = (0..s.len() / 2)
+        .filter_map(|i| s.get(2 * i..2 * i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect();
+    match parse_bytes(&bytes) {
+        Some(key) if s.len() == 64 => Ok(key),
+        _ => Err(Error::Config(format!("invalid identity key {}", s))),
+    }
+}
+
+pub struct Identity {
+    seed: [u8; 32],
+    public: Key,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        let mut seed = [0; 32];
+        rand::thread_rng().fill_bytes(&mut seed);
+        Identity::from_seed(seed)
+    }
+
+    fn from_seed(seed: [u8; 32]) -> Identity {
+        Identity {
+            seed: seed,
+            public: SigningKey::from_bytes(&seed).verifying_key().to_bytes(),
+        }
+    }
+
+    /// Reads the identity kept at `path`, creating it readable only by its
+    /// owner if it does not exist yet.
+    pub fn load_or_create(path: &Path) -> Result<Identity> {
+        if path.exists() {
+            let hex = try!(utils::read_secret(path));
+            let seed = try!(parse_key(hex.trim())
+                .map_err(|_| Error::Config(format!("{} is not a kytan identity", path.display()))));
+            return Ok(Identity::from_seed(seed));
+        }
+        let identity = Identity::generate();
+        let mut file = try!(fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path));
+        try!(writeln!(file, "{}", fingerprint(&identity.seed)));
+        info!("Created identity {} in {}.",
+              fingerprint(&identity.public),
+              path.display());
+        Ok(identity)
+    }
+
+    pub fn public(&self) -> Key {
+        self.public
+    }
+
+    /// Proves the identity for a handshake resuming `resume`, if any.
+    pub fn prove(&self, resume: Option<(Id, Token)>) -> Proof {
+        let timestamp = replay::unix_secs();
+        let nonce = rand::random();
+        Proof {
+            public: self.public.to_vec(),
+            timestamp: timestamp,
+            nonce: nonce,
+            signature: SigningKey::from_bytes(&self.seed)
+                .sign(&signed(timestamp, nonce, resume))
+                .to_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// The token of a static link with the holder of `peer`. Both ends
+    /// derive the same one without talking to each other.
+    pub fn link_token(&self, peer: &Key) -> Result<Token> {
+        let invalid = || Error::Config(format!("{} is not a valid key", fingerprint(peer)));
+        // X25519 between the two keys, converted to Montgomery form.
+        let point = try!(VerifyingKey::from_bytes(peer).map_err(|_| invalid())).to_montgomery();
+        let scalar = SigningKey::from_bytes(&self.seed).to_scalar_bytes();
+        let secret = x25519(scalar, point.to_bytes());
+        if secret == [0; 32] {
+            return Err(invalid());
+        }
+        let mut hash = Sha512::new();
+        hash.update(b"kytan-link");
+        hash.update(&secret);
+        Ok(hash.finalize()[..8].iter().fold(0, |token, &b| token << 8 | b as Token))
+    }
+}
+
+#[test]
+fn identity_test() {
+    let path = ::std::env::temp_dir().join("kytan-identity-test");
+    let _ = fs::remove_file(&path);
+    let identity = Identity::load_or_create(&path).unwrap();
+    assert_eq!(Identity::load_or_create(&path).unwrap().public(), identity.public());
+    fs::remove_file(&path).unwrap();
+
+    let proof = identity.prove(Some((3, 7)));
+    assert_eq!(proof.key(), Some(identity.public()));
+    assert!(proof.verify(&identity.public(), Some((3, 7))));
+    assert!(!proof.verify(&identity.public(), None));
+    // A proof only counts for the key it is checked against.
+    let other = Identity::generate().public();
+    assert!(!proof.verify(&other, Some((3, 7))));
+    let mut forged = proof.clone();
+    forged.public = other.to_vec();
+    assert!(!forged.verify(&other, Some((3, 7))));
+
+    let peer = Identity::generate();
+    assert_eq!(identity.link_token(&peer.public()).unwrap(),
+               peer.link_token(&identity.public()).unwrap());
+
+    let key = identity.public();
+    assert_eq!(parse_key(&fingerprint(&key)).unwrap(), key);
+    assert!(parse_key("abcd").is_err());
+}
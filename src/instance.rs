@@ -0,0 +1,119 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keeps two clients on one host from fighting over routes.
+//!
+//! A client holds an exclusive `flock` on a lock file for as long as it runs
+//! and keeps its pid there. The kernel drops the lock when the process dies,
+//! so a file left behind by a crash blocks nobody.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use libc;
+use error::{Error, Result};
+
+/// Where the client takes its lock unless told otherwise.
+pub const DEFAULT_PATH: &'static str = "/var/run/kytan/client.lock";
+
+/// The client that holds a lock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Holder {
+    /// `None` if it had not written its pid yet.
+    pub pid: Option<u32>,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "another kytan client (pid {}) is running", pid),
+            None => write!(f, "another kytan client is running"),
+        }
+    }
+}
+
+/// Held for the life of a client. The lock is released when it is dropped.
+/// The file itself stays, since removing it would let a second client lock
+/// a fresh file while a third still waits on the old one.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Takes the lock at `path`, or fails naming the client that has it.
+    pub fn acquire(path: &Path) -> Result<Lock> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        let mut file = try!(OpenOptions::new().read(true).write(true).create(true).open(path));
+        if !try!(try_lock(&file, libc::LOCK_EX)) {
+            let holder = Holder { pid: read_pid(&mut file) };
+            return Err(Error::Config(format!("{}; it holds {}", holder, path.display())));
+        }
+        try!(file.set_len(0));
+        try!(file.seek(SeekFrom::Start(0)));
+        try!(writeln!(file, "{}", process::id()));
+        Ok(Lock { _file: file })
+    }
+}
+
+/// The client holding the lock at `path`, if one is running.
+pub fn holder(path: &Path) -> Option<Holder> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    match try_lock(&file, libc::LOCK_SH) {
+        Ok(false) => Some(Holder { pid: read_pid(&mut file) }),
+        _ => None,
+    }
+}
+
+/// Whether a non-blocking `flock` with `operation` succeeded.
+fn try_lock(file: &File, operation: libc::c_int) -> Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(Error::from(e))
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok().and_then(|_| contents.trim().parse().ok())
+}
+
+#[test]
+fn lock_test() {
+    let path = ::std::env::temp_dir().join(format!("kytan-lock-test-{}", process::id()));
+    let _ = fs::remove_file(&path);
+    assert_eq!(holder(&path), None);
+    {
+        let _lock = Lock::acquire(&path).unwrap();
+        assert_eq!(holder(&path), Some(Holder { pid: Some(process::id()) }));
+        // flock locks belong to the open file, so a second open conflicts
+        // even within one process.
+        assert!(Lock::acquire(&path).is_err());
+    }
+    assert_eq!(holder(&path), None);
+    drop(Lock::acquire(&path).unwrap());
+    fs::remove_file(&path).unwrap();
+}
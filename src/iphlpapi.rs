@@ -0,0 +1,198 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Minimal IP Helper API bindings for managing IPv4 routes on Windows.
+
+use std::cmp;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::ptr;
+use error::{Error, Result};
+
+const NO_ERROR: u32 = 0;
+const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+const MIB_IPROUTE_TYPE_DIRECT: u32 = 3;
+const MIB_IPROUTE_TYPE_INDIRECT: u32 = 4;
+const MIB_IPPROTO_NETMGMT: u32 = 3;
+
+/// `MIB_IPFORWARDROW`. Addresses and masks are in network byte order.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ForwardRow {
+    dest: u32,
+    mask: u32,
+    policy: u32,
+    next_hop: u32,
+    if_index: u32,
+    kind: u32,
+    proto: u32,
+    age: u32,
+    next_hop_as: u32,
+    metric: [u32; 5],
+}
+
+#[link(name = "iphlpapi")]
+extern "system" {
+    fn GetIpForwardTable(table: *mut u8, size: *mut u32, order: i32) -> u32;
+    fn GetBestRoute(dest: u32, source: u32, route: *mut ForwardRow) -> u32;
+    fn CreateIpForwardEntry(route: *mut ForwardRow) -> u32;
+    fn DeleteIpForwardEntry(route: *mut ForwardRow) -> u32;
+    fn ConvertInterfaceAliasToLuid(alias: *const u16, luid: *mut u64) -> u32;
+    fn ConvertInterfaceLuidToIndex(luid: *const u64, index: *mut u32) -> u32;
+}
+
+fn check(call: &str, code: u32) -> Result<()> {
+    if code == NO_ERROR {
+        Ok(())
+    } else {
+        Err(Error::Route(format!("{}: {}", call, io::Error::from_raw_os_error(code as i32))))
+    }
+}
+
+fn to_raw(addr: Ipv4Addr) -> u32 {
+    u32::from(addr).to_be()
+}
+
+fn from_raw(addr: u32) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from_be(addr))
+}
+
+fn mask(prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { (!0u32 << (32 - prefix as u32)).to_be() }
+}
+
+/// An IPv4 route. An unspecified gateway sends the network straight into
+/// the interface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+    pub dst: Ipv4Addr,
+    pub prefix: u8,
+    pub gateway: Ipv4Addr,
+    pub if_index: u32,
+    /// Includes the interface metric, which Windows adds to every route.
+    pub metric: u32,
+}
+
+impl Route {
+    fn from_row(row: &ForwardRow) -> Route {
+        Route {
+            dst: from_raw(row.dest),
+            prefix: u32::from_be(row.mask).count_ones() as u8,
+            gateway: from_raw(row.next_hop),
+            if_index: row.if_index,
+            metric: row.metric[0],
+        }
+    }
+}
+
+fn table() -> Result<Vec<ForwardRow>> {
+    let mut size = 0u32;
+    let code = unsafe { GetIpForwardTable(ptr::null_mut(), &mut size, 0) };
+    if code != ERROR_INSUFFICIENT_BUFFER {
+        try!(check("GetIpForwardTable", code));
+    }
+    // A u32 count followed by the rows, which are u32s too. Leave some
+    // slack in case routes were added in between.
+    let words = mem::size_of::<ForwardRow>() / 4;
+    let mut buf = vec![0u32; size as usize / 4 + 8 * words];
+    size = (buf.len() * 4) as u32;
+    try!(check("GetIpForwardTable",
+               unsafe { GetIpForwardTable(buf.as_mut_ptr() as *mut u8, &mut size, 0) }));
+    let count = cmp::min(buf[0] as usize, (buf.len() - 1) / words);
+    Ok((0..count)
+        .map(|i| unsafe { ptr::read(buf[1 + i * words..].as_ptr() as *const ForwardRow) })
+        .collect())
+}
+
+/// The route Windows would use to reach `dst`.
+pub fn best_route(dst: Ipv4Addr) -> Result<Route> {
+    let mut row = ForwardRow::default();
+    try!(check("GetBestRoute", unsafe { GetBestRoute(to_raw(dst), 0, &mut row) }));
+    Ok(Route::from_row(&row))
+}
+
+pub fn add_route(route: &Route) -> Result<()> {
+    let mut row = ForwardRow {
+        dest: to_raw(route.dst),
+        mask: mask(route.prefix),
+        next_hop: to_raw(route.gateway),
+        if_index: route.if_index,
+        kind: if route.gateway.is_unspecified() {
+            MIB_IPROUTE_TYPE_DIRECT
+        } else {
+            MIB_IPROUTE_TYPE_INDIRECT
+        },
+        proto: MIB_IPPROTO_NETMGMT,
+        ..ForwardRow::default()
+    };
+    row.metric = [route.metric, !0, !0, !0, !0];
+    check("CreateIpForwardEntry",
+          unsafe { CreateIpForwardEntry(&mut row) })
+}
+
+/// Deletes every route to `dst`/`prefix`, whatever its gateway.
+pub fn delete_routes(dst: Ipv4Addr, prefix: u8) -> Result<()> {
+    let mut found = false;
+    for mut row in try!(table()) {
+        if row.dest == to_raw(dst) && row.mask == mask(prefix) {
+            try!(check("DeleteIpForwardEntry",
+                       unsafe { DeleteIpForwardEntry(&mut row) }));
+            found = true;
+        }
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(Error::Route(format!("no route to {}/{}", dst, prefix)))
+    }
+}
+
+/// The index of the interface called `alias`, such as "Ethernet" or the
+/// name of a wintun adapter.
+pub fn interface_index(alias: &str) -> Result<u32> {
+    let wide: Vec<u16> = alias.encode_utf16().chain(Some(0)).collect();
+    let mut luid = 0u64;
+    let mut index = 0u32;
+    try!(check("ConvertInterfaceAliasToLuid",
+               unsafe { ConvertInterfaceAliasToLuid(wide.as_ptr(), &mut luid) }));
+    try!(check("ConvertInterfaceLuidToIndex",
+               unsafe { ConvertInterfaceLuidToIndex(&luid, &mut index) }));
+    Ok(index)
+}
+
+/// The metric of interface `index`, going by the routes Windows made for
+/// its own addresses, which carry no route metric of their own.
+pub fn interface_metric(index: u32) -> Result<u32> {
+    try!(table())
+        .iter()
+        .filter(|row| row.if_index == index)
+        .map(|row| row.metric[0])
+        .min()
+        .ok_or_else(|| Error::Route(format!("no routes on interface {}", index)))
+}
+
+#[test]
+fn mask_test() {
+    assert_eq!(mask(0), 0);
+    assert_eq!(from_raw(mask(24)), Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(from_raw(mask(32)), Ipv4Addr::new(255, 255, 255, 255));
+    let row = ForwardRow {
+        dest: to_raw(Ipv4Addr::new(10, 0, 0, 0)),
+        mask: mask(8),
+        ..ForwardRow::default()
+    };
+    assert_eq!(Route::from_row(&row).prefix, 8);
+}
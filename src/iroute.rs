@@ -0,0 +1,74 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Networks that live behind a client, for site-to-site tunnels.
+//!
+//! A route is written `NET/PREFIX=GROUP`, for example
+//! `192.168.50.0/24=office`: packets for 192.168.50.0/24 go to the client
+//! that joined the isolation group `office` with its credential.
+
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Iroute {
+    pub net: Ipv4Addr,
+    pub prefix: u8,
+    /// Name of the group whose client owns the network.
+    pub group: String,
+}
+
+impl Iroute {
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix) };
+        u32::from(addr) & mask == u32::from(self.net) & mask
+    }
+}
+
+impl FromStr for Iroute {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Iroute> {
+        let invalid = || Error::Config(format!("expected NET/PREFIX=GROUP, got {:?}", s));
+        let mut parts = s.splitn(2, '=');
+        let net = parts.next().unwrap();
+        let group = try!(parts.next().ok_or_else(&invalid));
+        let mut net = net.splitn(2, '/');
+        let addr: Ipv4Addr = try!(net.next().unwrap().parse().map_err(|_| invalid()));
+        let prefix: u8 = try!(net.next().ok_or_else(&invalid).and_then(|p| {
+            p.parse().map_err(|_| invalid())
+        }));
+        if prefix > 32 || group.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Iroute {
+            net: addr,
+            prefix: prefix,
+            group: String::from(group),
+        })
+    }
+}
+
+#[test]
+fn iroute_test() {
+    let route: Iroute = "192.168.50.0/24=office".parse().unwrap();
+    assert_eq!(route.group, "office");
+    assert!(route.contains(Ipv4Addr::new(192, 168, 50, 7)));
+    assert!(!route.contains(Ipv4Addr::new(192, 168, 51, 7)));
+    assert!("192.168.50.0/24".parse::<Iroute>().is_err());
+    assert!("192.168.50.0/33=office".parse::<Iroute>().is_err());
+    assert!("0.0.0.0/0=all".parse::<Iroute>().unwrap().contains(Ipv4Addr::new(8, 8, 8, 8)));
+}
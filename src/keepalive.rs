@@ -0,0 +1,123 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How often a client pings to keep its NAT mapping alive.
+//!
+//! A NAT forgets an idle UDP mapping after a while, and replies from the
+//! server then go nowhere. Clients ping more often than the NAT's timeout;
+//! `Preset` has values for common networks. When the server goes silent yet
+//! takes a new handshake at once, it was the NAT that dropped the mapping,
+//! so the interval is halved, down to `MIN_INTERVAL`.
+
+use std::cmp;
+use std::time::Duration;
+use error::{Error, Result};
+
+/// Pings are never sent more often than this, in seconds.
+pub const MIN_INTERVAL: u64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    /// Carrier-grade NAT, which may drop a mapping after 30 seconds.
+    Mobile,
+    /// Consumer routers, which usually keep one for a minute or more.
+    Home,
+    /// Public addresses or stateful firewalls with long timeouts.
+    Datacenter,
+}
+
+impl Preset {
+    pub fn parse(s: &str) -> Result<Preset> {
+        match s {
+            "mobile" => Ok(Preset::Mobile),
+            "home" => Ok(Preset::Home),
+            "datacenter" => Ok(Preset::Datacenter),
+            _ => Err(Error::Config(format!("unknown keepalive preset {:?}", s))),
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        let (interval, nat_timeout) = match *self {
+            Preset::Mobile => (15, 30),
+            Preset::Home => (25, 60),
+            Preset::Datacenter => (60, 300),
+        };
+        Config {
+            interval: Duration::from_secs(interval),
+            nat_timeout: Duration::from_secs(nat_timeout),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    /// How often to ping the server.
+    pub interval: Duration,
+    /// How long the NAT is expected to keep an idle mapping. Pings go out at
+    /// least twice as often.
+    pub nat_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            interval: Duration::from_secs(30),
+            nat_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+pub struct Keepalive {
+    interval: Duration,
+}
+
+impl Keepalive {
+    pub fn new(config: Config) -> Keepalive {
+        let interval = cmp::min(config.interval, config.nat_timeout / 2);
+        Keepalive { interval: cmp::max(interval, Duration::from_secs(MIN_INTERVAL)) }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Notes that the NAT dropped the mapping between two pings. Returns the
+    /// new interval if it shrank.
+    pub fn lost(&mut self) -> Option<Duration> {
+        let interval = cmp::max(self.interval / 2, Duration::from_secs(MIN_INTERVAL));
+        if interval == self.interval {
+            return None;
+        }
+        self.interval = interval;
+        Some(interval)
+    }
+}
+
+#[test]
+fn keepalive_test() {
+    let mut keepalive = Keepalive::new(Preset::parse("home").unwrap().config());
+    assert_eq!(keepalive.interval(), Duration::from_secs(25));
+    assert_eq!(keepalive.lost(), Some(Duration::new(12, 500000000)));
+    assert_eq!(keepalive.lost(), Some(Duration::new(6, 250000000)));
+    assert_eq!(keepalive.lost(), Some(Duration::from_secs(MIN_INTERVAL)));
+    assert_eq!(keepalive.lost(), None);
+
+    let config = Config {
+        interval: Duration::from_secs(60),
+        nat_timeout: Duration::from_secs(40),
+    };
+    assert_eq!(Keepalive::new(config).interval(), Duration::from_secs(20));
+    assert_eq!(Keepalive::new(Config::default()).interval(), Duration::from_secs(30));
+    assert!(Preset::parse("satellite").is_err());
+}
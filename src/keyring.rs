@@ -0,0 +1,271 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Client secrets in the platform keyring.
+//!
+//! A profile can say `credential = keyring:office` instead of holding the
+//! secret, which `kytan keyring set office` stores beforehand. Linux uses
+//! the Secret Service through `secret-tool`, macOS the login keychain
+//! through `security`, and Windows the Credential Manager. Since kytan runs
+//! as root, lookups under sudo are made as the invoking user, whose keyring
+//! holds the secret.
+
+#[cfg(unix)]
+use std::env;
+#[cfg(unix)]
+use std::io::Write;
+#[cfg(unix)]
+use std::process::{Command, Output, Stdio};
+#[cfg(unix)]
+use libc;
+use error::{Error, Result};
+
+/// The service secrets are filed under.
+pub const SERVICE: &'static str = "kytan";
+
+/// Runs `program` as the user who invoked sudo, if any, so that it reaches
+/// their keyring rather than root's.
+#[cfg(unix)]
+fn command(program: &str) -> Command {
+    let user = env::var("SUDO_USER").ok();
+    match (unsafe { libc::geteuid() }, user) {
+        (0, Some(user)) => {
+            let mut command = Command::new("sudo");
+            command.args(&["-u", &user, "env"]);
+            if let Ok(uid) = env::var("SUDO_UID") {
+                // The Secret Service lives on the user's session bus.
+                command.arg(format!("DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/{}/bus", uid));
+            }
+            command.arg(program);
+            command
+        }
+        _ => Command::new(program),
+    }
+}
+
+#[cfg(unix)]
+fn run(mut command: Command, input: &str) -> Result<Output> {
+    let mut child = try!(command.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn());
+    try!(child.stdin.take().unwrap().write_all(input.as_bytes()));
+    Ok(try!(child.wait_with_output()))
+}
+
+#[cfg(unix)]
+fn failed(program: &str, output: &Output) -> Error {
+    Error::Config(format!("{}: {}",
+                          program,
+                          String::from_utf8_lossy(&output.stderr).trim()))
+}
+
+/// The secret stored for `account`, if there is one.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn get(account: &str) -> Result<Option<String>> {
+    let mut lookup = command("secret-tool");
+    lookup.args(&["lookup", "service", SERVICE, "account", account]);
+    let output = try!(run(lookup, ""));
+    // secret-tool fails without a word when nothing matches.
+    if !output.status.success() && !output.stderr.is_empty() {
+        return Err(failed("secret-tool", &output));
+    }
+    let secret = String::from_utf8_lossy(&output.stdout).into_owned();
+    Ok(if secret.is_empty() { None } else { Some(secret) })
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn set(account: &str, secret: &str) -> Result<()> {
+    let mut store = command("secret-tool");
+    store.args(&["store",
+                 &format!("--label=kytan {}", account),
+                 "service",
+                 SERVICE,
+                 "account",
+                 account]);
+    let output = try!(run(store, secret));
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(failed("secret-tool", &output))
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn delete(account: &str) -> Result<()> {
+    let mut clear = command("secret-tool");
+    clear.args(&["clear", "service", SERVICE, "account", account]);
+    let output = try!(run(clear, ""));
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(failed("secret-tool", &output))
+    }
+}
+
+/// Quotes `s` for the command line of `security -i`.
+#[cfg(any(target_os = "macos", test))]
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// `security` exits with this when no item matches.
+#[cfg(target_os = "macos")]
+const ERR_SEC_ITEM_NOT_FOUND: i32 = 44;
+
+#[cfg(target_os = "macos")]
+pub fn get(account: &str) -> Result<Option<String>> {
+    let mut find = command("security");
+    find.args(&["find-generic-password", "-s", SERVICE, "-a", account, "-w"]);
+    let output = try!(run(find, ""));
+    match output.status.code() {
+        Some(0) => {
+            let secret = String::from_utf8_lossy(&output.stdout);
+            Ok(Some(String::from(secret.trim_right_matches('\n'))))
+        }
+        Some(ERR_SEC_ITEM_NOT_FOUND) => Ok(None),
+        _ => Err(failed("security", &output)),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn set(account: &str, secret: &str) -> Result<()> {
+    // Commands on standard input keep the secret out of the process list.
+    let script = format!("add-generic-password -U -s {} -a {} -w {}\n",
+                         quote(SERVICE),
+                         quote(account),
+                         quote(secret));
+    let mut security = command("security");
+    security.arg("-i");
+    let output = try!(run(security, &script));
+    if output.status.success() && output.stderr.is_empty() {
+        Ok(())
+    } else {
+        Err(failed("security", &output))
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn delete(account: &str) -> Result<()> {
+    let mut delete = command("security");
+    delete.args(&["delete-generic-password", "-s", SERVICE, "-a", account]);
+    let output = try!(run(delete, ""));
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(failed("security", &output))
+    }
+}
+
+#[cfg(windows)]
+mod credman {
+    use std::io;
+    use std::ptr;
+    use std::slice;
+    use error::{Error, Result};
+
+    const CRED_TYPE_GENERIC: u32 = 1;
+    const CRED_PERSIST_LOCAL_MACHINE: u32 = 2;
+    const ERROR_NOT_FOUND: i32 = 1168;
+
+    /// `CREDENTIALW`.
+    #[repr(C)]
+    struct Credential {
+        flags: u32,
+        kind: u32,
+        target_name: *mut u16,
+        comment: *mut u16,
+        last_written: [u32; 2],
+        blob_size: u32,
+        blob: *mut u8,
+        persist: u32,
+        attribute_count: u32,
+        attributes: *mut u8,
+        target_alias: *mut u16,
+        user_name: *mut u16,
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn CredReadW(target: *const u16, kind: u32, flags: u32, cred: *mut *mut Credential) -> i32;
+        fn CredWriteW(cred: *const Credential, flags: u32) -> i32;
+        fn CredDeleteW(target: *const u16, kind: u32, flags: u32) -> i32;
+        fn CredFree(buffer: *mut Credential);
+    }
+
+    fn target(account: &str) -> Vec<u16> {
+        format!("{}:{}", super::SERVICE, account).encode_utf16().chain(Some(0)).collect()
+    }
+
+    fn error(call: &str) -> Error {
+        Error::Config(format!("{}: {}", call, io::Error::last_os_error()))
+    }
+
+    pub fn get(account: &str) -> Result<Option<String>> {
+        let mut cred = ptr::null_mut();
+        if unsafe { CredReadW(target(account).as_ptr(), CRED_TYPE_GENERIC, 0, &mut cred) } == 0 {
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(ERROR_NOT_FOUND) => Ok(None),
+                _ => Err(error("CredReadW")),
+            };
+        }
+        let secret = unsafe {
+            let blob = slice::from_raw_parts((*cred).blob, (*cred).blob_size as usize);
+            let secret = String::from_utf8_lossy(blob).into_owned();
+            CredFree(cred);
+            secret
+        };
+        Ok(Some(secret))
+    }
+
+    pub fn set(account: &str, secret: &str) -> Result<()> {
+        let mut name = target(account);
+        let mut blob = secret.as_bytes().to_vec();
+        let cred = Credential {
+            flags: 0,
+            kind: CRED_TYPE_GENERIC,
+            target_name: name.as_mut_ptr(),
+            comment: ptr::null_mut(),
+            last_written: [0; 2],
+            blob_size: blob.len() as u32,
+            blob: blob.as_mut_ptr(),
+            persist: CRED_PERSIST_LOCAL_MACHINE,
+            attribute_count: 0,
+            attributes: ptr::null_mut(),
+            target_alias: ptr::null_mut(),
+            user_name: ptr::null_mut(),
+        };
+        if unsafe { CredWriteW(&cred, 0) } == 0 {
+            return Err(error("CredWriteW"));
+        }
+        Ok(())
+    }
+
+    pub fn delete(account: &str) -> Result<()> {
+        if unsafe { CredDeleteW(target(account).as_ptr(), CRED_TYPE_GENERIC, 0) } == 0 {
+            return Err(error("CredDeleteW"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+pub use self::credman::{get, set, delete};
+
+#[test]
+fn quote_test() {
+    assert_eq!(quote("s3cret"), "\"s3cret\"");
+    assert_eq!(quote("a \"b\" \\c"), "\"a \\\"b\\\" \\\\c\"");
+}
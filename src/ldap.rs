@@ -0,0 +1,278 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! LDAP authentication (RFC 4511), built with the `ldap` feature.
+//!
+//! A user is authenticated by binding as them, and authorized if their entry
+//! is a `member` of the configured group, `vpn-users` by default. Each check
+//! uses a fresh connection, as a bind changes the connection's identity. The
+//! connection is not encrypted; point it at a directory on a trusted network
+//! or a local TLS proxy.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use auth::Authenticator;
+use error::{Error, Result};
+
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_ENTRY: u8 = 0x64;
+const SEARCH_DONE: u8 = 0x65;
+const UNBIND_REQUEST: u8 = 0x42;
+
+const SUCCESS: u8 = 0;
+const INVALID_CREDENTIALS: u8 = 49;
+
+pub struct Config {
+    pub server: SocketAddr,
+    /// DN of a user, with `{}` standing for the user name.
+    pub user_dn: String,
+    /// DN of the group users must be members of.
+    pub group_dn: String,
+    pub timeout: Duration,
+}
+
+impl Config {
+    /// Users are `uid=USER,ou=people,BASE` and must be in
+    /// `cn=vpn-users,ou=groups,BASE`.
+    pub fn new(server: SocketAddr, base: &str) -> Config {
+        Config {
+            server: server,
+            user_dn: format!("uid={{}},ou=people,{}", base),
+            group_dn: format!("cn=vpn-users,ou=groups,{}", base),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+pub struct Ldap {
+    config: Config,
+}
+
+impl Ldap {
+    pub fn new(config: Config) -> Ldap {
+        Ldap { config: config }
+    }
+}
+
+impl Authenticator for Ldap {
+    fn authenticate(&mut self, user: &str, password: &str) -> Result<bool> {
+        // An empty password would make an unauthenticated bind, which
+        // servers accept for anyone.
+        if password.is_empty() {
+            return Ok(false);
+        }
+        let dn = self.config.user_dn.replace("{}", &escape_dn(user));
+        let mut stream = try!(TcpStream::connect_timeout(&self.config.server,
+                                                         self.config.timeout));
+        try!(stream.set_read_timeout(Some(self.config.timeout)));
+        try!(stream.set_write_timeout(Some(self.config.timeout)));
+
+        try!(stream.write_all(&message(1, BIND_REQUEST, &bind_request(&dn, password))));
+        let (id, op, body) = try!(read_message(&mut stream));
+        if id != 1 || op != BIND_RESPONSE {
+            return Err(Error::Handshake(String::from("unexpected LDAP bind response")));
+        }
+        match try!(result_code(&body)) {
+            SUCCESS => {}
+            INVALID_CREDENTIALS => return Ok(false),
+            code => return Err(Error::Handshake(format!("LDAP bind failed with code {}", code))),
+        }
+
+        let search = search_request(&self.config.group_dn, "member", &dn);
+        try!(stream.write_all(&message(2, SEARCH_REQUEST, &search)));
+        let mut member = false;
+        loop {
+            let (id, op, body) = try!(read_message(&mut stream));
+            if id != 2 {
+                continue;
+            }
+            match op {
+                SEARCH_ENTRY => member = true,
+                SEARCH_DONE => {
+                    // noSuchObject: the group does not exist.
+                    let code = try!(result_code(&body));
+                    if code != SUCCESS && code != 32 {
+                        return Err(Error::Handshake(format!("LDAP search failed with code {}",
+                                                            code)));
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let _ = stream.write_all(&message(3, UNBIND_REQUEST, &[]));
+        if !member {
+            info!("{} is not a member of {}.", user, self.config.group_dn);
+        }
+        Ok(member)
+    }
+}
+
+/// Escapes a user name for use as an RDN value (RFC 4514).
+fn escape_dn(value: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => escaped.push('\\'),
+            '#' | ' ' if i == 0 => escaped.push('\\'),
+            ' ' if i == value.chars().count() - 1 => escaped.push('\\'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else if len < 0x100 {
+        out.push(0x81);
+        out.push(len as u8);
+    } else {
+        out.push(0x82);
+        out.push((len >> 8) as u8);
+        out.push(len as u8);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn integer(tag: u8, value: u8) -> Vec<u8> {
+    // Values of 0x80 and above would need a leading zero.
+    assert!(value < 0x80);
+    tlv(tag, &[value])
+}
+
+fn message(id: u8, op: u8, body: &[u8]) -> Vec<u8> {
+    let mut content = integer(0x02, id);
+    content.extend(tlv(op, body));
+    tlv(0x30, &content)
+}
+
+fn bind_request(dn: &str, password: &str) -> Vec<u8> {
+    let mut body = integer(0x02, 3);
+    body.extend(tlv(0x04, dn.as_bytes()));
+    body.extend(tlv(0x80, password.as_bytes()));
+    body
+}
+
+/// A base-scoped search of `base` for `attribute=value`, returning no
+/// attributes.
+fn search_request(base: &str, attribute: &str, value: &str) -> Vec<u8> {
+    let mut body = tlv(0x04, base.as_bytes());
+    body.extend(integer(0x0a, 0));
+    body.extend(integer(0x0a, 0));
+    body.extend(integer(0x02, 1));
+    body.extend(integer(0x02, 5));
+    body.extend(tlv(0x01, &[0]));
+    let mut filter = tlv(0x04, attribute.as_bytes());
+    filter.extend(tlv(0x04, value.as_bytes()));
+    body.extend(tlv(0xa3, &filter));
+    body.extend(tlv(0x30, &tlv(0x04, b"1.1")));
+    body
+}
+
+/// Splits the first element off `data`, returning its tag, content and the
+/// rest.
+fn split_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    let invalid = || Error::Decode(String::from("malformed LDAP message"));
+    if data.len() < 2 {
+        return Err(invalid());
+    }
+    let (len, start) = if data[1] < 0x80 {
+        (data[1] as usize, 2)
+    } else {
+        let n = (data[1] & 0x7f) as usize;
+        if n == 0 || n > 4 || data.len() < 2 + n {
+            return Err(invalid());
+        }
+        (data[2..2 + n].iter().fold(0, |len, &b| len << 8 | b as usize), 2 + n)
+    };
+    if data.len() < start + len {
+        return Err(invalid());
+    }
+    Ok((data[0], &data[start..start + len], &data[start + len..]))
+}
+
+fn read_message<R: Read>(stream: &mut R) -> Result<(u32, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    try!(stream.read_exact(&mut header));
+    let mut raw = header.to_vec();
+    let len = if header[1] < 0x80 {
+        header[1] as usize
+    } else {
+        let mut bytes = vec![0u8; (header[1] & 0x7f) as usize];
+        if bytes.is_empty() || bytes.len() > 4 {
+            return Err(Error::Decode(String::from("malformed LDAP message")));
+        }
+        try!(stream.read_exact(&mut bytes));
+        raw.extend_from_slice(&bytes);
+        bytes.iter().fold(0, |len, &b| len << 8 | b as usize)
+    };
+    if len > 1 << 20 {
+        return Err(Error::Decode(String::from("LDAP message too large")));
+    }
+    let mut content = vec![0u8; len];
+    try!(stream.read_exact(&mut content));
+    raw.extend(content);
+    parse_message(&raw)
+}
+
+fn parse_message(data: &[u8]) -> Result<(u32, u8, Vec<u8>)> {
+    let (_, content, _) = try!(split_tlv(data));
+    let (_, id, rest) = try!(split_tlv(content));
+    let (op, body, _) = try!(split_tlv(rest));
+    let id = id.iter().fold(0, |id, &b| id << 8 | b as u32);
+    Ok((id, op, body.to_vec()))
+}
+
+fn result_code(body: &[u8]) -> Result<u8> {
+    match try!(split_tlv(body)) {
+        (0x0a, code, _) if code.len() == 1 => Ok(code[0]),
+        _ => Err(Error::Decode(String::from("malformed LDAP result"))),
+    }
+}
+
+#[test]
+fn message_test() {
+    let bind = message(1, BIND_REQUEST, &bind_request("uid=alice,dc=example", "pw"));
+    assert_eq!(&bind[..7], &[0x30, 0x22, 0x02, 0x01, 0x01, 0x60, 0x1d]);
+    let (id, op, body) = parse_message(&bind).unwrap();
+    assert_eq!((id, op), (1, BIND_REQUEST));
+    assert_eq!(&body[..3], &[0x02, 0x01, 0x03]);
+
+    let response = [0x30, 0x0c, 0x02, 0x01, 0x01, 0x61, 0x07, 0x0a, 0x01, 0x31, 0x04, 0x00,
+                    0x04, 0x00];
+    let (id, op, body) = read_message(&mut &response[..]).unwrap();
+    assert_eq!((id, op), (1, BIND_RESPONSE));
+    assert_eq!(result_code(&body).unwrap(), INVALID_CREDENTIALS);
+
+    let long = message(2, SEARCH_REQUEST, &search_request(&"x".repeat(200), "member", "y"));
+    assert_eq!(long[1], 0x81);
+    assert_eq!(read_message(&mut &long[..]).unwrap().1, SEARCH_REQUEST);
+
+    assert_eq!(escape_dn("a,b=c"), "a\\,b\\=c");
+    assert_eq!(escape_dn(" #x "), "\\ #x\\ ");
+    assert_eq!(escape_dn("a\0b"), "a\\00b");
+}
@@ -0,0 +1,161 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `kytan` as a library.
+//!
+//! The `kytan` binary is a thin wrapper around [`Client`](struct.Client.html)
+//! and [`Server`](struct.Server.html). Other programs can embed either side
+//! of the tunnel directly:
+//!
+//! ```no_run
+//! let mut client = kytan::Client::builder()
+//!     .host("kytan.info")
+//!     .port(9527)
+//!     .on_event(|event| println!("{:?}", event))
+//!     .build()
+//!     .unwrap();
+//! let handle = client.shutdown_handle();
+//! // Call `handle.shutdown()` from another thread to stop the client.
+//! client.run().unwrap();
+//! ```
+
+extern crate serde as _serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate libc;
+extern crate mio;
+extern crate rustc_serialize;
+extern crate bincode;
+extern crate dns_lookup;
+#[cfg(feature = "compression")]
+extern crate snap;
+extern crate rand;
+extern crate chacha20poly1305;
+extern crate ed25519_dalek;
+extern crate hkdf;
+extern crate hmac;
+extern crate md5;
+extern crate sha2;
+extern crate x25519_dalek;
+#[macro_use]
+extern crate rusqlite;
+
+#[macro_use]
+extern crate nix;
+#[macro_use]
+extern crate log;
+
+/// Logs the fate of a single frame at trace level under the
+/// `kytan::packet_trace` target. Unless the crate is built with the
+/// `packet-trace` feature the call sits behind a constant `false` and is
+/// optimized out, so the hot path pays nothing by default.
+#[cfg(feature = "packet-trace")]
+macro_rules! trace_packet {
+    ($($arg:tt)*) => (trace!(target: "kytan::packet_trace", $($arg)*))
+}
+
+#[cfg(not(feature = "packet-trace"))]
+macro_rules! trace_packet {
+    ($($arg:tt)*) => (if false { trace!(target: "kytan::packet_trace", $($arg)*) })
+}
+
+pub mod error;
+pub mod device;
+pub mod utils;
+pub mod packet;
+pub mod pcap;
+pub mod mirror;
+pub mod signal;
+pub mod clock;
+pub mod acl;
+pub mod telemetry;
+pub mod stats;
+pub mod selftest;
+pub mod doctor;
+pub mod netem;
+pub mod profile;
+pub mod control;
+pub mod iroute;
+pub mod shaper;
+pub mod batch;
+pub mod qos;
+pub mod stream;
+pub mod proxy;
+pub mod forward;
+pub mod delegation;
+pub mod identity;
+pub mod limits;
+pub mod logfile;
+pub mod verbosity;
+pub mod instance;
+pub mod keepalive;
+pub mod resolver;
+pub mod allocation;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod lockout;
+#[cfg(feature = "obfuscation")]
+pub mod obfs;
+pub mod geoip;
+pub mod cidr;
+pub mod history;
+pub mod keyring;
+pub mod push;
+pub mod replay;
+pub mod firewall;
+pub mod auth;
+pub mod radius;
+pub mod oidc;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+mod nat;
+mod neighbour;
+mod dns;
+mod discovery;
+mod netwatch;
+mod replication;
+#[cfg(feature = "metrics")]
+mod health;
+#[cfg(feature = "metrics")]
+pub mod netflow;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod handshake;
+mod loglimit;
+mod workers;
+#[cfg(feature = "obfuscation")]
+mod cover;
+mod adaptive;
+mod transcript;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(windows)]
+mod iphlpapi;
+mod network;
+mod roaming;
+mod client;
+mod server;
+mod peer;
+mod relay;
+
+pub use error::{Code, Error, Result};
+pub use network::{Event, ShutdownHandle, INTERRUPTED};
+pub use network::{Message, Id, Token, decode_message, decompress, decode_frame};
+pub use network::{session_token, token_backend};
+pub use network::{Capabilities, CAP_KEEPALIVE, encode_message};
+pub use roaming::{Offer, Roamer};
+pub use client::{Client, ClientBuilder, Status};
+pub use server::{Server, ServerBuilder};
+pub use peer::{Peer, PeerBuilder};
+pub use relay::{Relay, RelayBuilder};
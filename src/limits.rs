@@ -0,0 +1,102 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Caps on what the server buffers and tracks.
+//!
+//! Everything that clients or a flood of handshakes can make the server hold
+//! is bounded, so overload costs dropped packets rather than memory. What is
+//! dropped at a cap counts as overflow in the statistics. Limits are set as
+//! `NAME=VALUE`, for example `queue-bytes=1048576`.
+
+use stream;
+use error::{Error, Result};
+
+/// Most NAT flows: their poll tokens must stay below the forwarders'.
+pub const MAX_NAT_FLOWS: usize = 1 << 17;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Bytes held back by the shapers for one client.
+    pub queue_bytes: usize,
+    /// Handshakes waiting for the worker.
+    pub handshakes: usize,
+    /// Fresh stamps, and identity proofs, remembered against replays.
+    pub replay_entries: usize,
+    /// Streams relayed at once.
+    pub streams: usize,
+    /// Streams relayed at once for one client.
+    pub client_streams: usize,
+    /// Bytes from a client waiting for one stream's socket.
+    pub stream_bytes: usize,
+    /// Flows the userspace NAT tracks.
+    pub nat_flows: usize,
+    /// Packets waiting for each packet worker.
+    pub worker_packets: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            queue_bytes: 256 * 1024,
+            handshakes: 256,
+            replay_entries: 65536,
+            streams: 4096,
+            client_streams: 256,
+            stream_bytes: stream::BUFFER,
+            nat_flows: 4096,
+            worker_packets: 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Changes the limit named in `setting`, a `NAME=VALUE`.
+    pub fn set(&mut self, setting: &str) -> Result<()> {
+        let mut parts = setting.splitn(2, '=');
+        let name = parts.next().unwrap().trim();
+        let value = match parts.next().and_then(|value| value.trim().parse().ok()) {
+            Some(value) if value > 0 => value,
+            _ => return Err(Error::Config(format!("invalid limit {:?}", setting))),
+        };
+        match name {
+            "queue-bytes" => self.queue_bytes = value,
+            "handshakes" => self.handshakes = value,
+            "replay-entries" => self.replay_entries = value,
+            "streams" => self.streams = value,
+            "client-streams" => self.client_streams = value,
+            "stream-bytes" => self.stream_bytes = value,
+            "nat-flows" if value > MAX_NAT_FLOWS => {
+                return Err(Error::Config(format!("at most {} NAT flows", MAX_NAT_FLOWS)))
+            }
+            "nat-flows" => self.nat_flows = value,
+            "worker-packets" => self.worker_packets = value,
+            _ => return Err(Error::Config(format!("unknown limit {:?}", name))),
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn limits_test() {
+    let mut limits = Limits::default();
+    limits.set("queue-bytes=1024").unwrap();
+    limits.set(" streams = 16").unwrap();
+    assert_eq!(limits.queue_bytes, 1024);
+    assert_eq!(limits.streams, 16);
+    assert!(limits.set("streams=0").is_err());
+    assert!(limits.set("streams").is_err());
+    assert!(limits.set("sockets=16").is_err());
+    assert!(limits.set("nat-flows=1000000").is_err());
+    assert_eq!(limits.nat_flows, Limits::default().nat_flows);
+}
@@ -0,0 +1,138 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Temporary bans of sources that keep failing to authenticate.
+//!
+//! Failed credential checks in handshakes are counted per source address
+//! over a sliding window. Handshakes from a source that reaches the threshold
+//! are ignored until its ban runs out or an operator lifts it through the
+//! control socket. Sessions it already holds carry on: the source of a
+//! datagram is easily forged, and a ban must not let anyone cut a tunnel.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Failures within `window` that trigger a ban.
+    pub threshold: u32,
+    pub window: Duration,
+    /// How long a ban lasts.
+    pub ban: Duration,
+}
+
+impl Config {
+    pub fn new(threshold: u32) -> Config {
+        Config {
+            threshold: threshold,
+            window: Duration::from_secs(60),
+            ban: Duration::from_secs(600),
+        }
+    }
+}
+
+pub struct Lockout {
+    config: Config,
+    // Failures of each source and when its window started.
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    // Banned sources and when their bans end.
+    bans: HashMap<IpAddr, Instant>,
+}
+
+impl Lockout {
+    pub fn new(config: Config) -> Lockout {
+        Lockout {
+            config: config,
+            failures: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Counts a failure from `ip`. Whether it is now banned.
+    pub fn fail(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let window = self.config.window;
+        let entry = self.failures.entry(ip).or_insert((0, now));
+        if now.duration_since(entry.1) > window {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+        if entry.0 < self.config.threshold {
+            return false;
+        }
+        self.failures.remove(&ip);
+        self.bans.insert(ip, now + self.config.ban);
+        true
+    }
+
+    pub fn banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.bans.get(&ip) {
+            Some(&until) if until > now => return true,
+            Some(_) => {}
+            None => return false,
+        }
+        self.bans.remove(&ip);
+        false
+    }
+
+    /// Sources banned at `now` and how long each ban has left.
+    pub fn bans(&self, now: Instant) -> Vec<(IpAddr, Duration)> {
+        let mut bans: Vec<(IpAddr, Duration)> = self.bans
+            .iter()
+            .filter(|&(_, &until)| until > now)
+            .map(|(&ip, &until)| (ip, until.duration_since(now)))
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// Lifts the ban of `ip`, or every ban with `None`. How many were lifted.
+    pub fn unban(&mut self, ip: Option<IpAddr>) -> usize {
+        match ip {
+            Some(ip) => {
+                self.failures.remove(&ip);
+                self.bans.remove(&ip).map_or(0, |_| 1)
+            }
+            None => {
+                self.failures.clear();
+                let lifted = self.bans.len();
+                self.bans.clear();
+                lifted
+            }
+        }
+    }
+}
+
+#[test]
+fn lockout_test() {
+    let start = Instant::now();
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    let mut lockout = Lockout::new(Config::new(3));
+    assert!(!lockout.fail(ip, start));
+    assert!(!lockout.fail(ip, start + Duration::from_secs(1)));
+    // The window restarts after a quiet minute.
+    assert!(!lockout.fail(ip, start + Duration::from_secs(62)));
+    assert!(!lockout.fail(ip, start + Duration::from_secs(63)));
+    assert!(lockout.fail(ip, start + Duration::from_secs(64)));
+    assert!(lockout.banned(ip, start + Duration::from_secs(65)));
+    assert_eq!(lockout.bans(start + Duration::from_secs(64)),
+               vec![(ip, Duration::from_secs(600))]);
+    assert!(!lockout.banned(ip, start + Duration::from_secs(700)));
+    assert!(!lockout.fail(ip, start + Duration::from_secs(701)));
+    lockout.fail(ip, start + Duration::from_secs(702));
+    lockout.fail(ip, start + Duration::from_secs(703));
+    assert_eq!(lockout.unban(None), 1);
+    assert!(!lockout.banned(ip, start + Duration::from_secs(704)));
+}
@@ -0,0 +1,132 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Logging to a file with rotation.
+//!
+//! The logger writes to standard error, so the file simply replaces file
+//! descriptor 2. A watcher thread rotates it by size or on the hour or day,
+//! and reopens it on SIGHUP for external tools such as logrotate.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use libc;
+use signal;
+use utils;
+use error::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Period {
+    pub fn parse(s: &str) -> Result<Period> {
+        match s {
+            "never" => Ok(Period::Never),
+            "hourly" => Ok(Period::Hourly),
+            "daily" => Ok(Period::Daily),
+            _ => Err(Error::Config(format!("unknown rotation period {:?}", s))),
+        }
+    }
+
+    /// Which period `secs` since the epoch falls in, in UTC.
+    fn index(&self, secs: u64) -> u64 {
+        match *self {
+            Period::Never => 0,
+            Period::Hourly => secs / 3600,
+            Period::Daily => secs / 86400,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub path: PathBuf,
+    /// Rotate once the file grows beyond this many bytes. Zero never rotates
+    /// by size.
+    pub max_size: u64,
+    /// Also rotate at the start of every period.
+    pub period: Period,
+    /// Number of rotated files (`FILE.1`, `FILE.2`, ...) to keep.
+    pub max_files: usize,
+}
+
+impl Config {
+    pub fn new(path: &str) -> Config {
+        Config {
+            path: PathBuf::from(path),
+            max_size: 10 * 1024 * 1024,
+            period: Period::Never,
+            max_files: 5,
+        }
+    }
+}
+
+/// How often the watcher checks the file.
+const CHECK_INTERVAL: u64 = 1;
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Opens the file for appending and makes it standard error.
+fn redirect(config: &Config) -> Result<File> {
+    let file = try!(OpenOptions::new().create(true).append(true).open(&config.path));
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+        return Err(::std::io::Error::last_os_error().into());
+    }
+    Ok(file)
+}
+
+/// Sends the log to the file in `config` from now on.
+pub fn start(config: Config) -> Result<()> {
+    let mut file = try!(redirect(&config));
+    let mut period = config.period.index(now_secs());
+    try!(thread::Builder::new()
+        .name(String::from("kytan-logfile"))
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(CHECK_INTERVAL));
+            let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+            let current = config.period.index(now_secs());
+            let rotate = (config.max_size > 0 && size >= config.max_size) || current != period;
+            period = current;
+            if rotate {
+                if let Err(e) = utils::rotate_files(&config.path, config.max_files) {
+                    error!("Failed to rotate {}: {}", config.path.display(), e);
+                }
+            } else if !signal::take_reopen_request() {
+                continue;
+            }
+            match redirect(&config) {
+                Ok(reopened) => file = reopened,
+                Err(e) => error!("Failed to reopen {}: {}", config.path.display(), e),
+            }
+        }));
+    Ok(())
+}
+
+#[test]
+fn period_test() {
+    assert_eq!(Period::parse("daily").unwrap(), Period::Daily);
+    assert!(Period::parse("weekly").is_err());
+    assert_eq!(Period::Hourly.index(7199), 1);
+    assert_eq!(Period::Daily.index(86400 * 3 + 5), 3);
+    assert_eq!(Period::Never.index(86400 * 3), 0);
+}
@@ -0,0 +1,132 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rate limiting of warnings caused by remote peers.
+//!
+//! Malformed and unexpected datagrams are each worth a warning, so a flood
+//! of them would flood the log too. Every category of warning has a token
+//! bucket per source address: a few lines pass at once, then one every
+//! `INTERVAL` seconds. The rest are counted and summed up in one line when
+//! the source is next let through, or once it goes quiet. Past
+//! `MAX_SOURCES`, new sources share a bucket per category.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const BURST: u32 = 5;
+/// Seconds to earn another line.
+const INTERVAL: u64 = 10;
+const MAX_SOURCES: usize = 4096;
+
+type Key = (&'static str, Option<IpAddr>);
+
+struct Bucket {
+    tokens: u32,
+    refilled: Instant,
+    seen: Instant,
+    suppressed: u64,
+}
+
+#[derive(Default)]
+pub struct LogLimiter {
+    buckets: HashMap<Key, Bucket>,
+}
+
+impl LogLimiter {
+    /// Whether a warning of `category` caused by `source` may be logged
+    /// now. Sums up the warnings suppressed before it first.
+    pub fn allow(&mut self, category: &'static str, source: IpAddr, now: Instant) -> bool {
+        let mut key = (category, Some(source));
+        if self.buckets.len() >= MAX_SOURCES && !self.buckets.contains_key(&key) {
+            key = (category, None);
+        }
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: BURST,
+            refilled: now,
+            seen: now,
+            suppressed: 0,
+        });
+        bucket.seen = now;
+        let earned = now.duration_since(bucket.refilled).as_secs() / INTERVAL;
+        if earned > 0 {
+            bucket.tokens = cmp::min(BURST as u64, bucket.tokens as u64 + earned) as u32;
+            bucket.refilled = now;
+        }
+        if bucket.tokens == 0 {
+            bucket.suppressed += 1;
+            return false;
+        }
+        bucket.tokens -= 1;
+        if bucket.suppressed > 0 {
+            report(key, bucket.suppressed);
+            bucket.suppressed = 0;
+        }
+        true
+    }
+
+    /// Sums up and forgets the sources that have gone quiet.
+    pub fn expire(&mut self, now: Instant) {
+        let quiet = Duration::from_secs(INTERVAL * BURST as u64);
+        self.buckets.retain(|&key, bucket| {
+            if now.duration_since(bucket.seen) < quiet {
+                return true;
+            }
+            if bucket.suppressed > 0 {
+                report(key, bucket.suppressed);
+            }
+            false
+        });
+    }
+}
+
+fn report(key: Key, suppressed: u64) {
+    match key {
+        (category, Some(source)) => {
+            warn!("Suppressed {} more {} warnings about {}.",
+                  suppressed,
+                  category,
+                  source)
+        }
+        (category, None) => {
+            warn!("Suppressed {} more {} warnings about other sources.",
+                  suppressed,
+                  category)
+        }
+    }
+}
+
+#[test]
+fn allow_test() {
+    let mut limiter = LogLimiter::default();
+    let now = Instant::now();
+    let source = "192.0.2.1".parse().unwrap();
+    for _ in 0..BURST {
+        assert!(limiter.allow("undecodable", source, now));
+    }
+    assert!(!limiter.allow("undecodable", source, now));
+    assert!(!limiter.allow("undecodable", source, now));
+    // Other categories and sources have buckets of their own.
+    assert!(limiter.allow("invalid", source, now));
+    assert!(limiter.allow("undecodable", "192.0.2.2".parse().unwrap(), now));
+
+    let later = now + Duration::from_secs(INTERVAL);
+    assert!(limiter.allow("undecodable", source, later));
+    assert_eq!(limiter.buckets[&("undecodable", Some(source))].suppressed, 0);
+    assert!(!limiter.allow("undecodable", source, later));
+
+    limiter.expire(later + Duration::from_secs(INTERVAL * BURST as u64));
+    assert!(limiter.buckets.is_empty());
+}
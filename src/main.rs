@@ -12,54 +12,912 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-extern crate serde as _serde;
-#[macro_use]
-extern crate serde_derive;
 extern crate libc;
 extern crate getopts;
-extern crate mio;
-extern crate rustc_serialize;
-extern crate bincode;
 extern crate env_logger;
-extern crate dns_lookup;
-extern crate snap;
-extern crate rand;
-extern crate transient_hashmap;
+extern crate kytan;
 
-#[macro_use]
-extern crate nix;
 #[macro_use]
 extern crate log;
 
-use std::sync::atomic::Ordering;
-
-mod device;
-mod utils;
-mod network;
-mod packet;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 fn print_usage(program: &str, opts: getopts::Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {} [options]\n       {} connect PROFILE [--foreground] \
+                         [options]\n       {} status|disconnect [SOCKET]\n       \
+                         {} bans|traffic|latency|clients|refusals [SOCKET]\n       \
+                         {} unban IP|all [SOCKET]\n       \
+                         {} acl list|add RULE|insert N RULE|del N [SOCKET]\n       \
+                         {} sources list|allow CIDR|deny CIDR|del CIDR [SOCKET]\n       \
+                         {} rate list|ID RATE|ID off [SOCKET]\n       \
+                         {} egress list|ID off|ID on [SOCKET]\n       \
+                         {} tun [mtu BYTES|address add|del CIDR|route add|del CIDR] \
+                         [SOCKET]\n       \
+                         {} drain now|SECONDS|off [SOCKET]\n       \
+                         {} log [LEVEL|default|packets on|packets off] [SOCKET]\n       \
+                         {} doctor [HOST[:PORT]]\n       {} selftest [PORT]",
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program,
+                        program);
     print!("{}", opts.usage(&brief));
 }
 
-extern "C" fn handle_signal(_: i32) {
-    network::INTERRUPTED.store(true, Ordering::Relaxed);
+/// Splits HOST[:PORT], where an IPv6 HOST must be in brackets if a port is
+/// given.
+fn parse_server(server: &str, default_port: u16) -> (String, u16) {
+    if server.starts_with('[') {
+        let end = server.find(']').expect("unterminated IPv6 address");
+        let port = match server[end + 1..].trim_left_matches(':') {
+            "" => default_port,
+            port => port.parse().unwrap(),
+        };
+        return (String::from(&server[1..end]), port);
+    }
+    match server.rfind(':') {
+        Some(i) if server.find(':') == Some(i) => {
+            (String::from(&server[..i]), server[i + 1..].parse().unwrap())
+        }
+        _ => (String::from(server), default_port),
+    }
+}
+
+/// Resolves a secret given on the command line, which may be a `file:PATH`
+/// reference, exiting if the file cannot be used.
+fn secret(option: &str, value: &str) -> String {
+    match kytan::utils::secret(value) {
+        Ok(secret) => secret,
+        Err(e) => {
+            error!("--{}: {}", option, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "ldap")]
+fn ldap(builder: kytan::ServerBuilder, matches: &getopts::Matches) -> kytan::ServerBuilder {
+    let server = match matches.opt_str("ldap") {
+        Some(server) => server.parse().unwrap(),
+        None => return builder,
+    };
+    let base = matches.opt_str("ldap-base").unwrap_or_default();
+    let mut config = kytan::ldap::Config::new(server, &base);
+    if let Some(dn) = matches.opt_str("ldap-user-dn") {
+        config.user_dn = dn;
+    }
+    if let Some(dn) = matches.opt_str("ldap-group-dn") {
+        config.group_dn = dn;
+    }
+    builder.authenticator(kytan::ldap::Ldap::new(config))
+}
+
+#[cfg(not(feature = "ldap"))]
+fn ldap(builder: kytan::ServerBuilder, _: &getopts::Matches) -> kytan::ServerBuilder {
+    builder
+}
+
+#[cfg(feature = "dashboard")]
+fn dashboard(builder: kytan::ServerBuilder, matches: &getopts::Matches) -> kytan::ServerBuilder {
+    match matches.opt_str("dashboard") {
+        Some(addr) => {
+            builder.dashboard(addr.parse().unwrap(),
+                              matches.opt_present("dashboard-writable"))
+        }
+        None => builder,
+    }
+}
+
+#[cfg(not(feature = "dashboard"))]
+fn dashboard(builder: kytan::ServerBuilder, _: &getopts::Matches) -> kytan::ServerBuilder {
+    builder
+}
+
+#[cfg(feature = "metrics")]
+fn statsd(matches: &getopts::Matches) -> Option<kytan::stats::StatsdConfig> {
+    matches.opt_str("statsd").map(|addr| {
+        let mut config = kytan::stats::StatsdConfig::new(addr.parse().unwrap());
+        if let Some(prefix) = matches.opt_str("statsd-prefix") {
+            config.prefix = prefix;
+        }
+        if let Some(interval) = matches.opt_str("statsd-interval") {
+            config.interval = Duration::from_secs(interval.parse().unwrap());
+        }
+        config.tags = matches.opt_strs("statsd-tag");
+        config
+    })
+}
+
+#[cfg(feature = "metrics")]
+fn server_metrics(mut builder: kytan::ServerBuilder,
+                  matches: &getopts::Matches)
+                  -> kytan::ServerBuilder {
+    if let Some(config) = statsd(matches) {
+        builder = builder.statsd(config);
+    }
+    if let Some(addr) = matches.opt_str("health-check") {
+        builder = builder.health_check(addr.parse().unwrap());
+    }
+    if let Some(addr) = matches.opt_str("netflow") {
+        let mut config = kytan::netflow::Config::new(addr.parse().unwrap());
+        if let Some(version) = matches.opt_str("netflow-version") {
+            config.version = kytan::netflow::Version::parse(&version).unwrap();
+        }
+        if let Some(timeout) = matches.opt_str("netflow-active-timeout") {
+            config.active_timeout = Duration::from_secs(timeout.parse().unwrap());
+        }
+        if let Some(timeout) = matches.opt_str("netflow-idle-timeout") {
+            config.idle_timeout = Duration::from_secs(timeout.parse().unwrap());
+        }
+        builder = builder.netflow(config);
+    }
+    builder
+}
+
+#[cfg(not(feature = "metrics"))]
+fn server_metrics(builder: kytan::ServerBuilder, _: &getopts::Matches) -> kytan::ServerBuilder {
+    builder
+}
+
+#[cfg(feature = "metrics")]
+fn client_metrics(mut builder: kytan::ClientBuilder,
+                  matches: &getopts::Matches)
+                  -> kytan::ClientBuilder {
+    if let Some(config) = statsd(matches) {
+        builder = builder.statsd(config);
+    }
+    if let Some(addr) = matches.opt_str("health-check") {
+        builder = builder.health_check(addr.parse().unwrap());
+    }
+    builder
+}
+
+#[cfg(not(feature = "metrics"))]
+fn client_metrics(builder: kytan::ClientBuilder, _: &getopts::Matches) -> kytan::ClientBuilder {
+    builder
+}
+
+/// A preset from `--keepalive`, with `--keepalive-interval` and
+/// `--nat-timeout` taking precedence.
+fn keepalive(matches: &getopts::Matches) -> kytan::keepalive::Config {
+    let mut config = match matches.opt_str("keepalive") {
+        Some(preset) => kytan::keepalive::Preset::parse(&preset).unwrap().config(),
+        None => kytan::keepalive::Config::default(),
+    };
+    if let Some(secs) = matches.opt_str("keepalive-interval") {
+        config.interval = Duration::from_secs(secs.parse()
+            .expect("--keepalive-interval expects seconds"));
+    }
+    if let Some(secs) = matches.opt_str("nat-timeout") {
+        config.nat_timeout = Duration::from_secs(secs.parse()
+            .expect("--nat-timeout expects seconds"));
+    }
+    config
+}
+
+#[cfg(feature = "admin")]
+fn server_control(builder: kytan::ServerBuilder,
+                  matches: &getopts::Matches)
+                  -> kytan::ServerBuilder {
+    if matches.opt_present("daemon") || matches.opt_present("control-socket") {
+        let path = matches.opt_str("control-socket")
+            .unwrap_or(String::from(kytan::control::SERVER_PATH));
+        builder.control_socket(path)
+    } else {
+        builder
+    }
+}
+
+#[cfg(not(feature = "admin"))]
+fn server_control(builder: kytan::ServerBuilder, _: &getopts::Matches) -> kytan::ServerBuilder {
+    builder
+}
+
+#[cfg(feature = "admin")]
+fn client_control(builder: kytan::ClientBuilder,
+                  matches: &getopts::Matches)
+                  -> kytan::ClientBuilder {
+    if matches.opt_present("daemon") || matches.opt_present("control-socket") {
+        let path = matches.opt_str("control-socket")
+            .unwrap_or(String::from(kytan::control::DEFAULT_PATH));
+        builder.control_socket(path)
+    } else {
+        builder
+    }
+}
+
+#[cfg(not(feature = "admin"))]
+fn client_control(builder: kytan::ClientBuilder, _: &getopts::Matches) -> kytan::ClientBuilder {
+    builder
+}
+
+#[cfg(feature = "obfuscation")]
+fn obfuscation(mut builder: kytan::ClientBuilder,
+               matches: &getopts::Matches)
+               -> kytan::ClientBuilder {
+    if matches.opt_present("obfuscate-handshake") || matches.opt_present("handshake-decoys") {
+        let mut config = kytan::obfs::Config::default();
+        if let Some(decoys) = matches.opt_str("handshake-decoys") {
+            config.decoys = decoys.parse().unwrap();
+        }
+        builder = builder.obfuscate_handshake(config);
+    }
+    if let Some(interval) = matches.opt_str("cover-interval") {
+        let size = matches.opt_str("cover-size").map_or(1200, |size| size.parse().unwrap());
+        builder = builder.cover_traffic(Duration::from_millis(interval.parse().unwrap()), size);
+    }
+    builder
+}
+
+#[cfg(not(feature = "obfuscation"))]
+fn obfuscation(builder: kytan::ClientBuilder, _: &getopts::Matches) -> kytan::ClientBuilder {
+    builder
 }
 
 fn main() {
-    env_logger::init().unwrap();
+    let mut logger = env_logger::LogBuilder::new();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse(&filters);
+    }
+    let logger = logger.build();
+    let filter = logger.filter();
+    kytan::verbosity::init(logger, filter).unwrap();
 
-    if unsafe { libc::geteuid() != 0 } {
+    let mut args: Vec<String> = std::env::args().collect();
+    // The doctor reports missing privileges instead of refusing to run,
+    // keyrings belong to users, and neither a SOCKS5 client nor a relay-only
+    // server changes anything on the host.
+    if unsafe { libc::geteuid() != 0 } &&
+       !["doctor", "keyring"].contains(&args.get(1).map_or("", |arg| arg.as_ref())) &&
+       !args.iter().any(|arg| arg == "--socks" || arg.starts_with("--socks=")) &&
+       !args.iter().any(|arg| arg == "--relay-only") {
         panic!("Please run as root");
     }
+    let env = kytan::profile::from_env(std::env::vars());
+    if args.get(1).map(|arg| arg.as_ref()) == Some("connect") {
+        let name = args.get(2).cloned().expect("Usage: kytan connect PROFILE [options]");
+        let profile = match kytan::profile::load(&name) {
+            Ok(profile) => profile,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let mut expanded = vec![args[0].clone(), String::from("-m"), String::from("c")];
+        let rest: Vec<String> = args.drain(3..).collect();
+        if !rest.iter().any(|arg| arg == "--foreground") {
+            expanded.push(String::from("--daemon"));
+        }
+        expanded.extend(rest.into_iter().filter(|arg| arg != "--foreground"));
+        kytan::profile::merge(&mut expanded, env);
+        kytan::profile::merge(&mut expanded, profile);
+        args = expanded;
+    } else if args.get(1).map_or(true, |arg| arg.starts_with('-')) {
+        kytan::profile::merge(&mut args, env);
+    }
+    if let Some(command) = args.get(1).cloned() {
+        let (command, default_path, rest) = match &command[..] {
+            "status" | "disconnect" => (command.clone(), kytan::control::DEFAULT_PATH, 2),
+            "bans" | "traffic" | "latency" | "clients" | "refusals" => {
+                (command.clone(), kytan::control::SERVER_PATH, 2)
+            }
+            "history" => {
+                let path = args.get(2).map_or(kytan::history::DEFAULT_PATH, |path| path.as_ref());
+                match kytan::history::query(std::path::Path::new(path), 50) {
+                    Ok(table) => print!("{}", table),
+                    Err(e) => {
+                        println!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            "keyring" => {
+                let usage = "Usage: kytan keyring set|delete ACCOUNT";
+                let account = args.get(3).cloned().expect(usage);
+                let result = match args.get(2).map(|action| action.as_ref()) {
+                    Some("set") => {
+                        println!("Secret for {} (read from standard input):", account);
+                        let mut secret = String::new();
+                        std::io::stdin().read_line(&mut secret).unwrap();
+                        kytan::keyring::set(&account, secret.trim_right_matches('\n'))
+                    }
+                    Some("delete") => kytan::keyring::delete(&account),
+                    _ => panic!("{}", usage),
+                };
+                if let Err(e) = result {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            "unban" => {
+                let target = args.get(2).cloned().expect("Usage: kytan unban IP|all [SOCKET]");
+                (format!("unban {}", target), kytan::control::SERVER_PATH, 3)
+            }
+            "drain" => {
+                let line = match args.get(2).map(|word| word.as_ref()) {
+                    Some("now") => String::from("drain"),
+                    Some(word) => format!("drain {}", word),
+                    None => panic!("Usage: kytan drain now|SECONDS|off [SOCKET]"),
+                };
+                (line, kytan::control::SERVER_PATH, 3)
+            }
+            "log" => {
+                let words = match args.get(2).map(|word| word.as_ref()) {
+                    Some("packets") => 2,
+                    Some(word) if !word.contains('/') => 1,
+                    _ => 0,
+                };
+                let mut line = vec![command.clone()];
+                line.extend(args.iter().skip(2).take(words).cloned());
+                (line.join(" "), kytan::control::DEFAULT_PATH, 2 + words)
+            }
+            "tun" => {
+                let words = match args.get(2).map(|word| word.as_ref()) {
+                    Some("mtu") => 2,
+                    Some("address") | Some("route") => 3,
+                    _ => 0,
+                };
+                let mut line = vec![command.clone()];
+                line.extend(args.iter().skip(2).take(words).cloned());
+                (line.join(" "), kytan::control::SERVER_PATH, 2 + words)
+            }
+            "acl" | "sources" | "rate" | "egress" => {
+                let words = match (&command[..], args.get(2).map(|word| word.as_ref())) {
+                    (_, Some("list")) => 1,
+                    ("acl", Some("insert")) => 3,
+                    (_, Some(_)) => 2,
+                    (_, None) => panic!("Usage: kytan {} list|... [SOCKET]", command),
+                };
+                let mut line = vec![command.clone()];
+                if words > 1 {
+                    line.extend(args.iter().skip(2).take(words).cloned());
+                }
+                (line.join(" "), kytan::control::SERVER_PATH, 2 + words)
+            }
+            _ => (command.clone(), "", 0),
+        };
+        if rest > 0 {
+            let path = args.get(rest).map_or(default_path, |path| path.as_ref());
+            match kytan::control::request(std::path::Path::new(path), &command) {
+                Ok(reply) => print!("{}", reply),
+                Err(e) => {
+                    println!("Nothing is listening on {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    }
+    if args.get(1).map(|arg| arg.as_ref()) == Some("doctor") {
+        let server = args.get(2).map(|server| parse_server(server, 8964));
+        if !kytan::doctor::run(server) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.get(1).map(|arg| arg.as_ref()) == Some("selftest") {
+        let port = args.get(2).map_or(8964, |port| port.parse().unwrap());
+        match kytan::selftest::run(port) {
+            Ok(()) => println!("selftest: PASS"),
+            Err(e) => {
+                println!("selftest: FAIL ({})", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     let mut opts = getopts::Options::new();
-    opts.reqopt("m", "mode", "mode (server or client)", "[s|c]");
+    opts.reqopt("m", "mode", "mode (server, client, static peer or relay)", "[s|c|p|r]");
     opts.optopt("p", "port", "UDP port to listen/connect", "PORT");
     opts.optopt("h", "host", "remote host to connect (client mode)", "HOST");
+    opts.optopt("",
+                "session-port",
+                "UDP port for handshakes and keepalives, kept apart from data so that a \
+                 saturated data path cannot expire sessions",
+                "PORT");
+    opts.optopt("",
+                "subnet",
+                "addresses for clients (server mode, default: 10.10.10.0/24)",
+                "A.B.C.0/24");
+    opts.optopt("",
+                "allocation",
+                "how clients get addresses: sequential, random or hash of the client's \
+                 identity (server mode, default: sequential)",
+                "STRATEGY");
+    opts.optopt("", "device", "name of the TUN device (server mode)", "NAME");
+    opts.optflag("", "tap", "carry packets over a TAP device instead of TUN (Linux only)");
+    opts.optopt("", "tun-fd", "use this already open and configured TUN device", "FD");
+    opts.optmulti("",
+                  "server",
+                  "server to fail over to, in order after --host (client mode, repeatable)",
+                  "HOST[:PORT]");
+    opts.optmulti("",
+                  "via",
+                  "reach the server through this relay, in order (client mode, repeatable)",
+                  "HOST[:PORT]");
+    opts.optmulti("",
+                  "relay-exit",
+                  "server that circuits may lead to (relay mode, repeatable)",
+                  "IP:PORT");
+    opts.optflag("",
+                 "select-by-latency",
+                 "connect to the server that answers first instead of going in order \
+                  (client mode)");
+    opts.optopt("t",
+                "timeout",
+                "handshake timeout in seconds (client mode)",
+                "SECONDS");
+    opts.optopt("",
+                "handshake-attempts",
+                "requests sent to each server before failing over, and giving up once all \
+                 failed; 0 keeps trying (client mode, default 5)",
+                "N");
+    opts.optopt("",
+                "connect-deadline",
+                "give up if no session is established within SECONDS (client mode)",
+                "SECONDS");
+    opts.optopt("",
+                "session-file",
+                "write the device, addresses, server, MTU and pushed DNS servers of each \
+                 session to PATH as JSON (client mode)",
+                "PATH");
+    opts.optopt("",
+                "rtt-interval",
+                "measure the round trip every SECONDS for latency histograms (default 30)",
+                "SECONDS");
+    opts.optopt("",
+                "keepalive",
+                "keepalive timing for the network in front of the client (client mode)",
+                "mobile|home|datacenter");
+    opts.optopt("", "keepalive-interval", "ping the server every SECONDS", "SECONDS");
+    opts.optopt("",
+                "nat-timeout",
+                "seconds the NAT keeps an idle mapping; pings go out at least twice as often",
+                "SECONDS");
+    opts.optflag("", "no-compression", "disable snappy compression of tunneled packets");
+    opts.optflag("",
+                 "adaptive-compression",
+                 "send uncompressed frames while the CPU is saturated (server mode)");
+    opts.optflag("", "clamp-mss", "clamp the MSS of tunneled TCP connections to the tunnel MTU");
+    opts.optflag("",
+                 "route-mtu",
+                 "set the tunnel MTU and MSS on routes into the tunnel, so that local TCP \
+                  sends segments that fit from the start (client mode)");
+    opts.optflag("",
+                 "verify-checksums",
+                 "count packets out of the tunnel with wrong IP, TCP or UDP checksums");
+    opts.optflag("",
+                 "drop-corrupt",
+                 "drop packets with wrong checksums (implies --verify-checksums)");
+    opts.optflag("",
+                 "userspace-nat",
+                 "translate UDP from clients in userspace instead of enabling IP forwarding \
+                  (server mode)");
+    opts.optflag("",
+                 "relay-only",
+                 "forward packets only between clients, without a TUN device or root \
+                  (server mode)");
+    opts.optmulti("",
+                  "push-route",
+                  "have clients route this network through the tunnel (server mode)",
+                  "NET/PREFIX");
+    opts.optmulti("", "push-dns", "suggest this DNS server to clients (server mode)", "IP");
+    opts.optopt("", "banner", "message shown to clients when they connect (server mode)", "TEXT");
+    opts.optmulti("",
+                  "push",
+                  "push a custom setting to clients (server mode)",
+                  "KEY=VALUE");
+    opts.optmulti("",
+                  "dns-forward",
+                  "answer DNS on the server's tunnel address by relaying to this resolver \
+                   (server mode)",
+                  "IP[:PORT]");
+    opts.optopt("",
+                "masquerade",
+                "add firewall rules that NAT client traffic leaving INTERFACE (server mode)",
+                "INTERFACE");
+    opts.optopt("",
+                "firewall",
+                "firewall for --masquerade (default: detected)",
+                "nftables|iptables");
+    opts.optflag("",
+                 "keep-sysctls",
+                 "leave IP forwarding enabled after exit (server mode)");
+    opts.optflag("",
+                 "discovery-proxy",
+                 "relay mDNS and SSDP between the LAN and clients (server mode)");
+    opts.optmulti("",
+                  "group",
+                  "isolate clients presenting CREDENTIAL in group NAME (server mode, repeatable)",
+                  "NAME:CREDENTIAL|NAME:file:PATH");
+    opts.optflag("",
+                 "allow-bare-credentials",
+                 "accept group credentials that are not stamped (server mode)");
+    opts.optmulti("",
+                  "group-compression",
+                  "turn compression on or off for group NAME (server mode, repeatable)",
+                  "NAME:on|off");
+    opts.optmulti("",
+                  "group-mtu",
+                  "push a lower MTU to the clients of group NAME (server mode, repeatable)",
+                  "NAME:MTU");
+    opts.optopt("",
+                "credential",
+                "secret that places the client in an isolation group (client mode)",
+                "CREDENTIAL|file:PATH|keyring:ACCOUNT");
+    opts.optopt("",
+                "credential-file",
+                "read the credential from FILE, which others must not be able to read \
+                 (client mode)",
+                "FILE");
+    opts.optflag("",
+                 "stamp-credential",
+                 "prove a group credential with a timestamped HMAC instead of sending it \
+                  (client mode)");
+    opts.optopt("",
+                "identity",
+                "prove the device key kept in FILE, created if missing, so the server can \
+                 recognise this device (client and peer mode)",
+                "FILE");
+    opts.optmulti("",
+                  "device",
+                  "always give the client proving this key the address (server mode, \
+                   repeatable)",
+                  "KEY=ADDRESS");
+    opts.optopt("", "peer-key", "public key of the other peer (peer mode)", "KEY");
+    opts.optopt("",
+                "peer-endpoint",
+                "where the other peer listens; optional on one side (peer mode)",
+                "HOST:PORT");
+    opts.optopt("",
+                "address",
+                "tunnel addresses of this peer and the other (peer mode)",
+                "LOCAL,PEER");
+    opts.optopt("",
+                "radius",
+                "check USER:PASSWORD credentials with this RADIUS server (server mode)",
+                "IP:PORT");
+    opts.optopt("", "radius-secret", "shared secret for --radius", "SECRET|file:PATH");
+    opts.optflagopt("",
+                    "history",
+                    "record sessions in an SQLite database, by default /var/lib/kytan/history.db \
+                     (server mode)",
+                    "FILE");
+    opts.optopt("",
+                "history-retention",
+                "delete sessions older than this many days, 0 to keep all (default 90)",
+                "DAYS");
+    opts.optmulti("",
+                  "allow-from",
+                  "only accept clients from this range, e.g. 10.0.0.0/8 (server mode)",
+                  "CIDR");
+    opts.optmulti("", "deny-from", "refuse clients from this range (server mode)", "CIDR");
+    opts.optmulti("",
+                  "geoip-db",
+                  "MaxMind database to look up client countries and ASNs in (server mode)",
+                  "FILE");
+    opts.optmulti("",
+                  "geoip-allow",
+                  "only admit clients from this country or ASN, e.g. DE or AS13335",
+                  "RULE");
+    opts.optmulti("", "geoip-deny", "refuse clients from this country or ASN", "RULE");
+    opts.optopt("",
+                "oidc-issuer",
+                "log in with this OpenID Connect issuer (client mode), or admit clients \
+                 it issued tokens to (server mode)",
+                "URL");
+    opts.optopt("",
+                "oidc-client-id",
+                "OAuth client id for --oidc-issuer, which tokens must be issued for \
+                 (default: kytan)",
+                "ID");
+    opts.optopt("",
+                "oidc-client-secret",
+                "secret the server introspects tokens with as --oidc-client-id (server mode)",
+                "SECRET|file:PATH");
+    opts.optmulti("",
+                  "oidc-allow-subject",
+                  "only admit this subject, or members of --oidc-allow-group (server mode, \
+                   repeatable)",
+                  "SUB");
+    opts.optmulti("",
+                  "oidc-allow-group",
+                  "only admit members of this group, or --oidc-allow-subject (server mode, \
+                   repeatable)",
+                  "GROUP");
+    if cfg!(feature = "ldap") {
+        opts.optopt("",
+                    "ldap",
+                    "check USER:PASSWORD credentials with this LDAP server (server mode)",
+                    "IP:PORT");
+        opts.optopt("",
+                    "ldap-base",
+                    "users are uid=USER,ou=people,BASE and must be in \
+                     cn=vpn-users,ou=groups,BASE",
+                    "BASE");
+        opts.optopt("", "ldap-user-dn", "DN of users, with {} for the user name", "DN");
+        opts.optopt("", "ldap-group-dn", "DN of the group users must be in", "DN");
+    }
+    if cfg!(feature = "dashboard") {
+        opts.optopt("",
+                    "dashboard",
+                    "serve a web dashboard on this address, e.g. 127.0.0.1:8080 (server mode)",
+                    "IP:PORT");
+        opts.optflag("", "dashboard-writable", "let dashboard visitors lift bans");
+    }
+    opts.optopt("",
+                "lockout-threshold",
+                "ban sources after this many failed authentications (server mode)",
+                "N");
+    opts.optopt("",
+                "lockout-window",
+                "seconds over which failures are counted (default: 60)",
+                "SECONDS");
+    opts.optopt("",
+                "lockout-ban",
+                "seconds a ban lasts (default: 600)",
+                "SECONDS");
+    opts.optopt("",
+                "max-session-lifetime",
+                "make clients authenticate again after this many seconds (server mode)",
+                "SECONDS");
+    opts.optopt("",
+                "max-bandwidth",
+                "cap total tunnel traffic, e.g. 200mbit (server mode)",
+                "RATE");
+    opts.optopt("",
+                "coalesce",
+                "send small packets held up to MS milliseconds in one datagram (server mode)",
+                "MS");
+    opts.optflag("",
+                 "allow-streams",
+                 "connect out on behalf of clients running --socks or -L (server mode)");
+    opts.optflag("",
+                 "allow-local-streams",
+                 "let streams reach loopback, link-local and this host's addresses (server \
+                  mode)");
+    opts.optmulti("",
+                  "iroute",
+                  "route NET/PREFIX to the client of GROUP, which must be given with --group \
+                   (server mode, repeatable)",
+                  "NET/PREFIX=GROUP");
+    opts.optopt("",
+                "delegate-ipv6",
+                "delegate a prefix of this IPv6 pool to each client, e.g. 2001:db8:100::/56 \
+                 (server mode)",
+                "POOL/PREFIX");
+    opts.optopt("",
+                "delegated-length",
+                "length of the prefixes delegated from --delegate-ipv6 (default: 64)",
+                "BITS");
+    opts.optmulti("",
+                  "forward",
+                  "relay connections to a port of the server to a client, e.g. \
+                   2222=10.10.10.5:22 (server mode, repeatable)",
+                  "[ADDR:]PORT=ADDR:PORT");
+    opts.optmulti("",
+                  "limit",
+                  "cap what load can make the server hold: queue-bytes, handshakes, \
+                   replay-entries, streams, client-streams, stream-bytes, nat-flows or \
+                   worker-packets, e.g. streams=1024 (server mode, repeatable)",
+                  "NAME=VALUE");
+    opts.optopt("",
+                "workers",
+                "compress and decompress on this many threads (server mode, default: 0, \
+                 on the event loop)",
+                "N");
+    opts.optmulti("",
+                  "acl",
+                  "filter traffic from clients, e.g. \"deny tcp:25\" (server mode, repeatable)",
+                  "RULE");
+    opts.optflag("",
+                 "qos",
+                 "queue traffic held back by rate limits by DSCP priority (server mode)");
+    opts.optmulti("",
+                  "qos-rule",
+                  "priority of traffic to clients, e.g. \"interactive tcp:22\" (server mode, \
+                   repeatable, implies --qos)",
+                  "RULE");
+    opts.optopt("",
+                "drop-policy",
+                "what to drop when a client's queues are full: the new packet (tail, the \
+                 default) or the oldest (head) (server mode)",
+                "tail|head");
+    opts.optflag("",
+                 "propagate-dscp",
+                 "copy the DSCP of tunneled packets onto the outer UDP packets");
+    opts.optopt("", "outer-ttl", "TTL of the outer UDP packets", "TTL");
+    opts.optopt("",
+                "outer-df",
+                "set (on) or clear (off) the DF bit of the outer UDP packets",
+                "on|off");
+    opts.optopt("", "fwmark", "fwmark to set on the outer UDP packets (Linux)", "MARK");
+    opts.optopt("",
+                "bind-interface",
+                "send tunnel traffic only through this interface (client mode)",
+                "IFACE");
+    opts.optopt("",
+                "bind-address",
+                "local address for tunnel traffic; a server bound to none answers each \
+                 client from the address it sent to (Linux)",
+                "ADDRESS");
+    opts.optflag("", "prefer-ipv4", "use a server's IPv4 address if it has one");
+    opts.optflag("", "prefer-ipv6", "use a server's IPv6 address if it has one");
+    opts.optflag("", "only-ipv4", "only connect to servers over IPv4");
+    opts.optflag("", "only-ipv6", "only connect to servers over IPv6");
+    opts.optopt("",
+                "dns-cache",
+                "remember server addresses for SECONDS, 0 to look them up every time \
+                 (default: 60)",
+                "SECONDS");
+    opts.optopt("",
+                "socks",
+                "run a SOCKS5 proxy on this address instead of a TUN device; needs no root \
+                 (client mode)",
+                "ADDR:PORT");
+    opts.optmulti("L",
+                  "local-forward",
+                  "relay connections to a local port to HOST:PORT as seen from the server \
+                   (client mode, repeatable)",
+                  "[ADDR:]PORT:HOST:PORT");
+    opts.optflag("",
+                 "no-follow-network",
+                 "do not move the tunnel over when the host changes networks (client mode)");
+    opts.optopt("",
+                "replicate-to",
+                "send session state to a standby server (server mode)",
+                "ADDR:PORT");
+    opts.optopt("",
+                "standby",
+                "receive session state from a primary server on this address (server mode)",
+                "ADDR:PORT");
+    opts.optopt("",
+                "replication-secret",
+                "secret shared by the primary and the standby (server mode)",
+                "SECRET|file:PATH");
+    opts.optopt("",
+                "backend-id",
+                "stamp this id into session tokens for a load balancer (server mode)",
+                "ID");
+    opts.optopt("",
+                "otlp-endpoint",
+                "export traces of handshakes and sessions to this OTLP/HTTP collector",
+                "HOST:PORT");
+    if cfg!(feature = "metrics") {
+        opts.optopt("",
+                    "health-check",
+                    "answer HTTP health checks on this address, e.g. 127.0.0.1:8965",
+                    "ADDR:PORT");
+        opts.optopt("",
+                    "netflow",
+                    "export flow records to this collector (server mode)",
+                    "ADDR:PORT");
+        opts.optopt("",
+                    "netflow-version",
+                    "flow export format: 9 or ipfix (default: ipfix)",
+                    "VERSION");
+        opts.optopt("",
+                    "netflow-active-timeout",
+                    "export long flows every this many seconds (default: 60)",
+                    "SECONDS");
+        opts.optopt("",
+                    "netflow-idle-timeout",
+                    "export flows idle for this many seconds (default: 15)",
+                    "SECONDS");
+    }
+    opts.optopt("",
+                "compression-threshold",
+                "send packets shorter than this uncompressed (default: 64)",
+                "BYTES");
+    opts.optflag("",
+                 "daemon",
+                 "run in the background; a client then accepts commands on the control \
+                  socket");
+    opts.optopt("",
+                "lock-file",
+                "lock file that keeps a second client from starting (default: \
+                 /var/run/kytan/client.lock)",
+                "PATH");
+    opts.optflag("",
+                 "attach",
+                 "if a client is already running, print its status instead of failing");
+    opts.optopt("",
+                "transcript",
+                "append handshakes, roams and expiries to FILE, without tokens or payload",
+                "FILE");
+    if cfg!(feature = "admin") {
+        opts.optopt("",
+                    "control-socket",
+                    "path of the control socket used by status, disconnect, bans and unban",
+                    "PATH");
+    }
+    if cfg!(feature = "obfuscation") {
+        opts.optflag("",
+                     "obfuscate-handshake",
+                     "randomize the size and timing of handshake frames (client mode)");
+        opts.optopt("",
+                    "handshake-decoys",
+                    "send N random datagrams ahead of each handshake (client mode)",
+                    "N");
+        opts.optopt("",
+                    "cover-interval",
+                    "send a cover frame every MS milliseconds when idle (client mode)",
+                    "MS");
+        opts.optopt("",
+                    "cover-size",
+                    "size of cover frames in bytes (default: 1200)",
+                    "BYTES");
+    }
+    opts.optopt("",
+                "rebind-interval",
+                "move to a new local UDP port every SECONDS (client mode)",
+                "SECONDS");
+    opts.optopt("", "netem-delay", "delay outgoing datagrams (testing)", "MS");
+    opts.optopt("", "netem-jitter", "vary the delay by up to this much (testing)", "MS");
+    opts.optopt("", "netem-loss", "drop this share of outgoing datagrams (testing)", "PERCENT");
+    opts.optopt("",
+                "netem-reorder",
+                "send this share of datagrams ahead of delayed ones (testing)",
+                "PERCENT");
+    if cfg!(feature = "metrics") {
+        opts.optopt("", "statsd", "send traffic counters to this statsd server", "ADDR:PORT");
+        opts.optopt("",
+                    "statsd-prefix",
+                    "prefix of statsd metric names (default: kytan)",
+                    "PREFIX");
+        opts.optopt("",
+                    "statsd-interval",
+                    "seconds between statsd flushes (default: 10)",
+                    "SECONDS");
+        opts.optmulti("",
+                      "statsd-tag",
+                      "DogStatsD tag to attach to every metric (repeatable)",
+                      "TAG");
+    }
+    opts.optopt("", "log-file", "write the log to FILE instead of stderr", "FILE");
+    opts.optopt("",
+                "log-max-size",
+                "rotate the log file after this many MiB, 0 for never (default: 10)",
+                "MIB");
+    opts.optopt("",
+                "log-max-files",
+                "number of rotated log files to keep (default: 5)",
+                "N");
+    opts.optopt("",
+                "log-rotate",
+                "also rotate the log file hourly or daily (default: never)",
+                "PERIOD");
+    opts.optopt("", "pcap-dump", "write tunnel traffic to a pcap file", "FILE");
+    opts.optopt("",
+                "mirror",
+                "copy tunnel traffic to this TAP device for IDS tools (server mode)",
+                "DEVICE");
+    opts.optopt("",
+                "pcap-mode",
+                "traffic to capture: inner, outer or both (default: inner)",
+                "MODE");
+    opts.optopt("",
+                "pcap-max-size",
+                "rotate the capture file after this many MiB (default: 100)",
+                "MIB");
+    opts.optopt("",
+                "pcap-max-files",
+                "number of rotated capture files to keep (default: 5)",
+                "N");
 
-    let args: Vec<String> = std::env::args().collect();
     let program = args[0].clone();
 
     let matches = match opts.parse(&args[1..]) {
@@ -73,23 +931,527 @@ fn main() {
     let mode = matches.opt_str("m").unwrap();
     let port: u16 = matches.opt_str("p").unwrap_or(String::from("8964")).parse().unwrap();
 
-    let sig_action =
-        nix::sys::signal::SigAction::new(nix::sys::signal::SigHandler::Handler(handle_signal),
-                                         nix::sys::signal::SaFlags::empty(),
-                                         nix::sys::signal::SigSet::empty());
-    unsafe {
-        nix::sys::signal::sigaction(nix::sys::signal::SIGINT, &sig_action).unwrap();
-        nix::sys::signal::sigaction(nix::sys::signal::SIGTERM, &sig_action).unwrap();
+    let pcap = match matches.opt_str("pcap-dump") {
+        Some(path) => {
+            let mut config = kytan::pcap::Config::new(&path);
+            if let Some(mode) = matches.opt_str("pcap-mode") {
+                config.mode = kytan::pcap::Mode::parse(&mode).unwrap();
+            }
+            if let Some(size) = matches.opt_str("pcap-max-size") {
+                config.max_size = size.parse::<u64>().unwrap() * 1024 * 1024;
+            }
+            if let Some(files) = matches.opt_str("pcap-max-files") {
+                config.max_files = files.parse().unwrap();
+            }
+            Some(config)
+        }
+        None => None,
+    };
+
+    let netem_opts = ["netem-delay", "netem-jitter", "netem-loss", "netem-reorder"];
+    let netem = if netem_opts.iter().any(|opt| matches.opt_present(opt)) {
+        let millis = |opt| {
+            Duration::from_millis(matches.opt_str(opt).map_or(0, |ms| ms.parse().unwrap()))
+        };
+        let share = |opt| matches.opt_str(opt).map_or(0.0, |pct| pct.parse::<f64>().unwrap() / 100.0);
+        Some(kytan::netem::Config {
+            delay: millis("netem-delay"),
+            jitter: millis("netem-jitter"),
+            loss: share("netem-loss"),
+            reorder: share("netem-reorder"),
+        })
+    } else {
+        None
+    };
+
+    let outer_ttl: Option<u8> = matches.opt_str("outer-ttl").map(|ttl| ttl.parse().unwrap());
+    let outer_df = matches.opt_str("outer-df").map(|df| match df.as_ref() {
+        "on" => true,
+        "off" => false,
+        _ => panic!("--outer-df expects on or off"),
+    });
+    let fwmark: Option<u32> = matches.opt_str("fwmark").map(|mark| mark.parse().unwrap());
+    let acl = kytan::acl::Acl::parse(&matches.opt_strs("acl")).unwrap();
+    let otlp_endpoint = matches.opt_str("otlp-endpoint");
+    let compression_threshold: Option<usize> =
+        matches.opt_str("compression-threshold").map(|bytes| bytes.parse().unwrap());
+    let session_port: Option<u16> = matches.opt_str("session-port")
+        .map(|port| port.parse().expect("--session-port expects a port"));
+    let rtt_interval = matches.opt_str("rtt-interval")
+        .map(|secs| Duration::from_secs(secs.parse().expect("--rtt-interval expects seconds")));
+
+    let lock_file = matches.opt_str("lock-file")
+        .unwrap_or(String::from(kytan::instance::DEFAULT_PATH));
+    if mode == "c" {
+        if let Some(holder) = kytan::instance::holder(std::path::Path::new(&lock_file)) {
+            if !matches.opt_present("attach") {
+                error!("{}. Use --attach to see its status or `kytan disconnect` to stop it.",
+                       holder);
+                std::process::exit(1);
+            }
+            let path = if cfg!(feature = "admin") {
+                matches.opt_str("control-socket")
+            } else {
+                None
+            };
+            let path = path.unwrap_or(String::from(kytan::control::DEFAULT_PATH));
+            match kytan::control::request(std::path::Path::new(&path), "status") {
+                Ok(reply) => print!("{}", reply),
+                Err(e) => {
+                    println!("{}, but nothing answers on {}: {}", holder, path, e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    }
+
+    // The user has to see the login instructions, so log in before detaching.
+    let oidc_credential = match matches.opt_str("oidc-issuer") {
+        Some(ref issuer) if mode == "c" => {
+            let client_id = matches.opt_str("oidc-client-id").unwrap_or(String::from("kytan"));
+            match kytan::oidc::login(issuer, &client_id) {
+                Ok(credential) => Some(credential),
+                Err(e) => {
+                    error!("Failed to log in: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    if matches.opt_present("daemon") {
+        if let Err(e) = kytan::utils::daemonize() {
+            error!("Failed to detach: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = matches.opt_str("log-file") {
+        let mut config = kytan::logfile::Config::new(&path);
+        if let Some(size) = matches.opt_str("log-max-size") {
+            config.max_size = size.parse::<u64>().unwrap() * 1024 * 1024;
+        }
+        if let Some(files) = matches.opt_str("log-max-files") {
+            config.max_files = files.parse().unwrap();
+        }
+        if let Some(period) = matches.opt_str("log-rotate") {
+            config.period = kytan::logfile::Period::parse(&period).unwrap();
+        }
+        kytan::logfile::start(config).unwrap();
     }
 
-    match mode.as_ref() {
-        "s" => network::serve(port),
+    kytan::signal::install().unwrap();
+
+    let result = match mode.as_ref() {
+        "s" => {
+            let mut builder = kytan::Server::builder()
+                .port(port)
+                .compression(!matches.opt_present("no-compression"))
+                .adaptive_compression(matches.opt_present("adaptive-compression"))
+                .clamp_mss(matches.opt_present("clamp-mss"))
+                .verify_checksums(matches.opt_present("verify-checksums"))
+                .drop_corrupt(matches.opt_present("drop-corrupt"))
+                .propagate_dscp(matches.opt_present("propagate-dscp"))
+                .userspace_nat(matches.opt_present("userspace-nat"))
+                .relay_only(matches.opt_present("relay-only"))
+                .restore_sysctls(!matches.opt_present("keep-sysctls"))
+                .discovery_proxy(matches.opt_present("discovery-proxy"))
+                .acl(acl);
+            if let Some(port) = session_port {
+                builder = builder.session_port(port);
+            }
+            if let Some(bytes) = compression_threshold {
+                builder = builder.compression_threshold(bytes);
+            }
+            if let Some(interval) = rtt_interval {
+                builder = builder.rtt_interval(interval);
+            }
+            for group in matches.opt_strs("group") {
+                let (name, credential) =
+                    group.split_at(group.find(':').expect("--group expects NAME:CREDENTIAL"));
+                builder = builder.group(name, &secret("group", &credential[1..]));
+            }
+            if matches.opt_present("allow-bare-credentials") {
+                builder = builder.require_stamps(false);
+            }
+            for setting in matches.opt_strs("group-compression") {
+                let (name, compression) = match setting.rfind(':') {
+                    Some(i) => (&setting[..i], &setting[i + 1..]),
+                    None => panic!("--group-compression expects NAME:on|off"),
+                };
+                builder = builder.group_compression(name, match compression {
+                    "on" => true,
+                    "off" => false,
+                    _ => panic!("--group-compression expects NAME:on|off"),
+                });
+            }
+            for setting in matches.opt_strs("group-mtu") {
+                let (name, mtu) = match setting.rfind(':') {
+                    Some(i) => (&setting[..i], &setting[i + 1..]),
+                    None => panic!("--group-mtu expects NAME:MTU"),
+                };
+                let mtu = mtu.parse().expect("--group-mtu expects NAME:MTU");
+                builder = builder.group_mtu(name, mtu);
+            }
+            if let Some(server) = matches.opt_str("radius") {
+                let secret = secret("radius-secret",
+                                    &matches.opt_str("radius-secret")
+                                        .expect("--radius needs --radius-secret"));
+                let config = kytan::radius::Config::new(server.parse().unwrap(), &secret);
+                builder = builder.authenticator(kytan::radius::Radius::open(config).unwrap());
+            }
+            builder = ldap(builder, &matches);
+            builder = dashboard(builder, &matches);
+            if matches.opt_present("history") {
+                let path = matches.opt_str("history")
+                    .unwrap_or_else(|| String::from(kytan::history::DEFAULT_PATH));
+                let mut config = kytan::history::Config::new(path);
+                if let Some(days) = matches.opt_str("history-retention") {
+                    config.retention = match days.parse().unwrap() {
+                        0 => None,
+                        days => Some(Duration::from_secs(days * 24 * 3600)),
+                    };
+                }
+                builder = builder.history(config);
+            }
+            let upstreams: Vec<SocketAddr> = matches.opt_strs("dns-forward")
+                .iter()
+                .map(|upstream| {
+                    upstream.parse()
+                        .unwrap_or_else(|_| SocketAddr::new(upstream.parse().unwrap(), 53))
+                })
+                .collect();
+            if !upstreams.is_empty() {
+                builder = builder.dns_forwarder(upstreams);
+            }
+            for route in matches.opt_strs("push-route") {
+                let cidr: kytan::cidr::Cidr = route.parse().unwrap();
+                let net = match cidr.addr {
+                    std::net::IpAddr::V4(net) => net,
+                    std::net::IpAddr::V6(_) => panic!("--push-route takes IPv4 networks"),
+                };
+                builder = builder.push(kytan::push::PushOption::Route(net, cidr.prefix));
+            }
+            for server in matches.opt_strs("push-dns") {
+                builder = builder.push(kytan::push::PushOption::Dns(server.parse().unwrap()));
+            }
+            if let Some(text) = matches.opt_str("banner") {
+                builder = builder.push(kytan::push::PushOption::Banner(text));
+            }
+            for setting in matches.opt_strs("push") {
+                let mut parts = setting.splitn(2, '=');
+                let key = String::from(parts.next().unwrap());
+                let value = String::from(parts.next().expect("--push expects KEY=VALUE"));
+                builder = builder.push(kytan::push::PushOption::Custom(key, value));
+            }
+            if let Some(device) = matches.opt_str("mirror") {
+                builder = builder.mirror(&device);
+            }
+            for range in matches.opt_strs("allow-from") {
+                builder = builder.allow_from(range.parse().unwrap());
+            }
+            for range in matches.opt_strs("deny-from") {
+                builder = builder.deny_from(range.parse().unwrap());
+            }
+            let databases = matches.opt_strs("geoip-db");
+            if !databases.is_empty() {
+                let rules = |name| {
+                    matches.opt_strs(name)
+                        .iter()
+                        .map(|rule| rule.parse().unwrap())
+                        .collect()
+                };
+                builder = builder.geoip(kytan::geoip::Policy {
+                    databases: databases.iter()
+                        .map(|path| kytan::geoip::Database::open(path).unwrap())
+                        .collect(),
+                    allow: rules("geoip-allow"),
+                    deny: rules("geoip-deny"),
+                });
+            }
+            if let Some(issuer) = matches.opt_str("oidc-issuer") {
+                let client_id = matches.opt_str("oidc-client-id").unwrap_or(String::from("kytan"));
+                let client_secret = secret("oidc-client-secret",
+                                           &matches.opt_str("oidc-client-secret")
+                                               .expect("--oidc-issuer needs \
+                                                        --oidc-client-secret"));
+                let mut config = kytan::oidc::Config::new(&issuer, &client_id, &client_secret);
+                config.subjects = matches.opt_strs("oidc-allow-subject");
+                config.groups = matches.opt_strs("oidc-allow-group");
+                builder = builder.authenticator(kytan::oidc::Oidc::new(config).unwrap());
+            }
+            if let Some(threshold) = matches.opt_str("lockout-threshold") {
+                let mut config = kytan::lockout::Config::new(threshold.parse().unwrap());
+                if let Some(window) = matches.opt_str("lockout-window") {
+                    config.window = Duration::from_secs(window.parse().unwrap());
+                }
+                if let Some(ban) = matches.opt_str("lockout-ban") {
+                    config.ban = Duration::from_secs(ban.parse().unwrap());
+                }
+                builder = builder.lockout(config);
+            }
+            builder = server_control(builder, &matches);
+            if let Some(subnet) = matches.opt_str("subnet") {
+                builder = builder.subnet(subnet.parse().unwrap());
+            }
+            if let Some(addr) = matches.opt_str("bind-address") {
+                builder = builder.bind_address(addr.parse()
+                    .expect("--bind-address expects an IPv4 address in server mode"));
+            }
+            if let Some(strategy) = matches.opt_str("allocation") {
+                builder = builder.allocator(kytan::allocation::Strategy::parse(&strategy)
+                    .unwrap());
+            }
+            if let Some(name) = matches.opt_str("device") {
+                builder = builder.device_name(&name);
+            }
+            builder = builder.tap(matches.opt_present("tap"));
+            if let Some(fd) = matches.opt_str("tun-fd") {
+                builder = builder.tun_fd(fd.parse().unwrap());
+            }
+            if let Some(out) = matches.opt_str("masquerade") {
+                builder = builder.masquerade(&out);
+            }
+            if let Some(backend) = matches.opt_str("firewall") {
+                builder = builder.firewall(backend.parse().unwrap());
+            }
+            if let Some(lifetime) = matches.opt_str("max-session-lifetime") {
+                builder = builder.max_session_lifetime(Duration::from_secs(lifetime.parse()
+                    .unwrap()));
+            }
+            if let Some(rate) = matches.opt_str("max-bandwidth") {
+                builder = builder.max_bandwidth(kytan::shaper::parse_rate(&rate).unwrap());
+            }
+            if matches.opt_present("qos") || matches.opt_present("qos-rule") {
+                let rules = matches.opt_strs("qos-rule");
+                builder = builder.qos(kytan::qos::Classifier::parse(&rules).unwrap());
+            }
+            if let Some(policy) = matches.opt_str("drop-policy") {
+                builder = builder.drop_policy(policy.parse().unwrap());
+            }
+            builder = builder.allow_streams(matches.opt_present("allow-streams"))
+                .allow_local_streams(matches.opt_present("allow-local-streams"));
+            if let Some(ms) = matches.opt_str("coalesce") {
+                let ms = ms.parse().expect("--coalesce expects milliseconds");
+                builder = builder.coalesce(Duration::from_millis(ms));
+            }
+            for route in matches.opt_strs("iroute") {
+                builder = builder.iroute(route.parse().unwrap());
+            }
+            if let Some(pool) = matches.opt_str("delegate-ipv6") {
+                let prefix = matches.opt_str("delegated-length")
+                    .map_or(64, |bits| bits.parse().expect("--delegated-length expects bits"));
+                builder = builder.delegate_prefixes(pool.parse().unwrap(), prefix);
+            }
+            for forward in matches.opt_strs("forward") {
+                builder = builder.forward_port(forward.parse().unwrap());
+            }
+            let mut limits = kytan::limits::Limits::default();
+            for setting in matches.opt_strs("limit") {
+                limits.set(&setting).unwrap();
+            }
+            builder = builder.limits(limits);
+            if let Some(threads) = matches.opt_str("workers") {
+                builder = builder.workers(threads.parse().unwrap());
+            }
+            for device in matches.opt_strs("device") {
+                let (key, address) = device.split_at(device.find('=')
+                    .expect("--device expects KEY=ADDRESS"));
+                let address = address[1..].parse().expect("--device expects an IPv4 address");
+                builder = builder.device(kytan::identity::parse_key(key).unwrap(), address);
+            }
+            if let Some(ttl) = outer_ttl {
+                builder = builder.outer_ttl(ttl);
+            }
+            if let Some(df) = outer_df {
+                builder = builder.dont_fragment(df);
+            }
+            if let Some(mark) = fwmark {
+                builder = builder.fwmark(mark);
+            }
+            if let Some(ref endpoint) = otlp_endpoint {
+                builder = builder.tracer(kytan::telemetry::Tracer::start(endpoint, "kytan-server"));
+            }
+            if let Some(id) = matches.opt_str("backend-id") {
+                builder = builder.backend_id(id.parse().unwrap());
+            }
+            let secret = matches.opt_str("replication-secret")
+                .map(|value| secret("replication-secret", &value));
+            if let Some(peer) = matches.opt_str("replicate-to") {
+                let secret = secret.as_ref().expect("--replicate-to needs --replication-secret");
+                builder = builder.replicate_to(peer.parse().unwrap(), secret);
+            }
+            if let Some(listen) = matches.opt_str("standby") {
+                let secret = secret.as_ref().expect("--standby needs --replication-secret");
+                builder = builder.standby(listen.parse().unwrap(), secret);
+            }
+            builder = server_metrics(builder, &matches);
+            if let Some(config) = netem {
+                builder = builder.netem(config);
+            }
+            if let Some(config) = pcap {
+                builder = builder.pcap(config);
+            }
+            builder.build().and_then(|mut s| s.run())
+        }
         "c" => {
-            let host = matches.opt_str("h").unwrap();
-            network::connect(&host, port, true)
+            let timeout: u64 = matches.opt_str("t").unwrap_or(String::from("5")).parse().unwrap();
+            let mut builder = kytan::Client::builder()
+                .port(port)
+                .default_route(true)
+                .timeout(Duration::from_secs(timeout))
+                .compression(!matches.opt_present("no-compression"))
+                .clamp_mss(matches.opt_present("clamp-mss"))
+                .verify_checksums(matches.opt_present("verify-checksums"))
+                .drop_corrupt(matches.opt_present("drop-corrupt"))
+                .propagate_dscp(matches.opt_present("propagate-dscp"))
+                .route_mtu(matches.opt_present("route-mtu"))
+                .follow_network(!matches.opt_present("no-follow-network"))
+                .on_event(|event| if let kytan::Event::Status(ref status) = *event {
+                    println!("{}", status);
+                });
+            builder = client_control(builder, &matches).instance_lock(&lock_file);
+            if let Some(path) = matches.opt_str("transcript") {
+                builder = builder.transcript(path);
+            }
+            if let Some(bytes) = compression_threshold {
+                builder = builder.compression_threshold(bytes);
+            }
+            if let Some(interval) = rtt_interval {
+                builder = builder.rtt_interval(interval);
+            }
+            builder = builder.keepalive(keepalive(&matches));
+            if let Some(attempts) = matches.opt_str("handshake-attempts") {
+                builder = builder.handshake_attempts(attempts.parse()
+                    .expect("--handshake-attempts expects a number"));
+            }
+            if let Some(secs) = matches.opt_str("connect-deadline") {
+                builder = builder.connect_deadline(Duration::from_secs(secs.parse()
+                    .expect("--connect-deadline expects seconds")));
+            }
+            builder = obfuscation(builder, &matches);
+            if let Some(interval) = matches.opt_str("rebind-interval") {
+                builder = builder.rebind_interval(Duration::from_secs(interval.parse().unwrap()));
+            }
+            if let Some(host) = matches.opt_str("h") {
+                builder = builder.host(&host);
+            }
+            for server in matches.opt_strs("server") {
+                let (host, server_port) = parse_server(&server, port);
+                builder = builder.server(&host, server_port);
+            }
+            builder = builder.select_by_latency(matches.opt_present("select-by-latency"));
+            if let Some(port) = session_port {
+                builder = builder.session_port(port);
+            }
+            if let Some(path) = matches.opt_str("session-file") {
+                builder = builder.session_file(path);
+            }
+            for relay in matches.opt_strs("via") {
+                let (host, relay_port) = parse_server(&relay, 8964);
+                builder = builder.via(&host, relay_port);
+            }
+            if let Some(ref endpoint) = otlp_endpoint {
+                builder = builder.tracer(kytan::telemetry::Tracer::start(endpoint, "kytan-client"));
+            }
+            if let Some(ref credential) = oidc_credential {
+                builder = builder.credential(credential);
+            } else if let Some(credential) = matches.opt_str("credential") {
+                builder = builder.credential(&secret("credential", &credential));
+            } else if let Some(path) = matches.opt_str("credential-file") {
+                match kytan::utils::read_secret(std::path::Path::new(&path)) {
+                    Ok(credential) => builder = builder.credential(credential.trim()),
+                    Err(e) => {
+                        error!("Failed to read {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            builder = builder.stamp_credential(matches.opt_present("stamp-credential"));
+            if let Some(path) = matches.opt_str("identity") {
+                builder = builder.identity(path);
+            }
+            builder = builder.tap(matches.opt_present("tap"));
+            if let Some(fd) = matches.opt_str("tun-fd") {
+                builder = builder.tun_fd(fd.parse().unwrap());
+            }
+            if let Some(interface) = matches.opt_str("bind-interface") {
+                builder = builder.bind_interface(&interface);
+            }
+            if let Some(addr) = matches.opt_str("bind-address") {
+                builder = builder.bind_address(addr.parse().unwrap());
+            }
+            let families = ["prefer-ipv4", "prefer-ipv6", "only-ipv4", "only-ipv6"];
+            let mut chosen = families.iter().filter(|flag| matches.opt_present(flag));
+            if let Some(flag) = chosen.next() {
+                if chosen.next().is_some() {
+                    panic!("Give at most one of --{}", families.join(", --"));
+                }
+                builder = builder.address_family(kytan::resolver::Family::parse(flag).unwrap());
+            }
+            if let Some(secs) = matches.opt_str("dns-cache") {
+                builder = builder.dns_cache(Duration::from_secs(secs.parse()
+                    .expect("--dns-cache expects seconds")));
+            }
+            if let Some(addr) = matches.opt_str("socks") {
+                builder = builder.socks_proxy(addr.parse().expect("--socks expects ADDR:PORT"));
+            }
+            for forward in matches.opt_strs("local-forward") {
+                let (addr, target) = kytan::proxy::parse_forward(&forward).unwrap();
+                builder = builder.local_forward(addr, &target);
+            }
+            if let Some(ttl) = outer_ttl {
+                builder = builder.outer_ttl(ttl);
+            }
+            if let Some(df) = outer_df {
+                builder = builder.dont_fragment(df);
+            }
+            if let Some(mark) = fwmark {
+                builder = builder.fwmark(mark);
+            }
+            builder = client_metrics(builder, &matches);
+            if let Some(config) = netem {
+                builder = builder.netem(config);
+            }
+            if let Some(config) = pcap {
+                builder = builder.pcap(config);
+            }
+            builder.build().and_then(|mut c| c.run())
+        }
+        "p" => {
+            let key = matches.opt_str("peer-key").expect("peer mode needs --peer-key");
+            let addresses = matches.opt_str("address").expect("peer mode needs --address");
+            let (local, remote) = addresses.split_at(addresses.find(',')
+                .expect("--address expects LOCAL,PEER"));
+            let mut builder = kytan::Peer::builder()
+                .port(port)
+                .identity(matches.opt_str("identity").expect("peer mode needs --identity"))
+                .peer(kytan::identity::parse_key(&key).unwrap())
+                .addresses(local.parse().unwrap(), remote[1..].parse().unwrap());
+            if let Some(endpoint) = matches.opt_str("peer-endpoint") {
+                let (host, port) = parse_server(&endpoint, 8964);
+                builder = builder.endpoint(&host, port);
+            }
+            if let Some(fd) = matches.opt_str("tun-fd") {
+                builder = builder.tun_fd(fd.parse().unwrap());
+            }
+            builder.build().and_then(|mut p| p.run())
+        }
+        "r" => {
+            let mut builder = kytan::Relay::builder().port(port);
+            for exit in matches.opt_strs("relay-exit") {
+                builder = builder.exit(exit.parse().expect("--relay-exit expects IP:PORT"));
+            }
+            builder.build().and_then(|mut r| r.run())
         }
         _ => unreachable!(),
     };
 
+    if let Err(e) = result {
+        error!("[{}] {}", e.code(), e);
+        std::process::exit(e.code().exit_status());
+    }
+
     println!("SIGINT/SIGTERM captured. Exit.");
 }
@@ -0,0 +1,101 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Copies of tunnel traffic for intrusion detection.
+//!
+//! Every packet clients send or receive is also written to a TAP device,
+//! where Suricata, Zeek or tcpdump can watch it without sitting in the
+//! path. Frames carry a destination MAC that is not the device's own, so
+//! the kernel drops them instead of routing them a second time. For
+//! offline inspection, `--pcap-dump` with `--pcap-max-files` keeps a ring of
+//! capture files instead.
+
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+use libc;
+use device;
+use error::{Error, Result};
+
+const DESTINATION: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const SOURCE: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+/// Wraps an IP packet in an Ethernet header.
+fn frame(packet: &[u8]) -> Option<Vec<u8>> {
+    let ethertype = match packet.first().map(|b| b >> 4) {
+        Some(4) => [0x08, 0x00],
+        Some(6) => [0x86, 0xdd],
+        _ => return None,
+    };
+    let mut frame = Vec::with_capacity(14 + packet.len());
+    frame.extend_from_slice(&DESTINATION);
+    frame.extend_from_slice(&SOURCE);
+    frame.extend_from_slice(&ethertype);
+    frame.extend_from_slice(packet);
+    Some(frame)
+}
+
+pub struct Mirror {
+    tap: device::Tun,
+}
+
+impl Mirror {
+    /// Creates the TAP device `name` and brings it up without an address.
+    #[cfg(target_os = "linux")]
+    pub fn open(name: &str) -> Result<Mirror> {
+        let tap = try!(device::Tun::create_tap(name));
+        // Monitoring must never hold up the tunnel.
+        unsafe {
+            let fd = tap.as_raw_fd();
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+        }
+        let status = try!(Command::new("ifconfig").args(&[name, "up"]).status());
+        if !status.success() {
+            return Err(Error::Device(format!("failed to bring up {}", name)));
+        }
+        info!("Mirroring tunnel traffic to {}.", name);
+        Ok(Mirror { tap: tap })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_: &str) -> Result<Mirror> {
+        Err(Error::Device(String::from("traffic mirroring needs Linux")))
+    }
+
+    pub fn copy(&mut self, packet: &[u8]) {
+        if let Some(frame) = frame(packet) {
+            match self.tap.write(&frame) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => debug!("Failed to mirror packet to {}: {}", self.tap.name(), e),
+            }
+        }
+    }
+}
+
+#[test]
+fn frame_test() {
+    let mut packet = vec![0u8; 20];
+    packet[0] = 0x45;
+    let ethernet = frame(&packet).unwrap();
+    assert_eq!(ethernet.len(), 34);
+    assert_eq!(&ethernet[12..14], &[0x08, 0x00]);
+    assert_eq!(&ethernet[14..], &packet[..]);
+    packet[0] = 0x60;
+    assert_eq!(&frame(&packet).unwrap()[12..14], &[0x86, 0xdd]);
+    assert!(frame(&[]).is_none());
+}
@@ -0,0 +1,189 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Userspace NAT for servers that cannot enable forwarding or masquerading.
+//!
+//! Each UDP flow leaving the tunnel gets its own ordinary socket, so the host
+//! kernel sees nothing but local traffic. Replies are wrapped back into IP
+//! packets addressed to the client. Only UDP is translated: TCP would need a
+//! userspace TCP stack.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use mio;
+use packet;
+use error::Result;
+
+/// How long a flow may stay idle before its socket is closed.
+const FLOW_TIMEOUT: u64 = 120;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct Flow {
+    inner: SocketAddr,
+    remote: SocketAddr,
+}
+
+struct Entry {
+    socket: mio::udp::UdpSocket,
+    token: mio::Token,
+    last_used: Instant,
+}
+
+pub struct Nat {
+    base: usize,
+    next: usize,
+    max_flows: usize,
+    flows: HashMap<Flow, Entry>,
+    tokens: HashMap<mio::Token, Flow>,
+}
+
+impl Nat {
+    /// Flow sockets are registered with tokens from `base` upwards, and at
+    /// most `max_flows` are open at once.
+    pub fn new(base: usize, max_flows: usize) -> Nat {
+        Nat {
+            base: base,
+            next: 0,
+            max_flows: max_flows,
+            flows: HashMap::new(),
+            tokens: HashMap::new(),
+        }
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        token.0 >= self.base
+    }
+
+    /// Sends the payload of a UDP packet from the tunnel out of the flow's
+    /// socket, opening one if needed. False if the packet was dropped because
+    /// the table is full.
+    pub fn outbound(&mut self, poll: &mio::Poll, data: &[u8], now: Instant) -> Result<bool> {
+        let (inner, remote, payload) = try!(packet::parse_udp(data));
+        let flow = Flow {
+            inner: inner,
+            remote: remote,
+        };
+        if !self.flows.contains_key(&flow) {
+            if self.flows.len() >= self.max_flows {
+                warn!("NAT table full. Dropping flow {} -> {}.", inner, remote);
+                return Ok(false);
+            }
+            let bind = match remote {
+                SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+                SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+            };
+            let socket = try!(mio::udp::UdpSocket::bind(&bind));
+            let token = self.allocate_token();
+            try!(poll.register(&socket, token, mio::Ready::readable(), mio::PollOpt::level()));
+            debug!("NAT flow {} -> {} opened.", inner, remote);
+            self.tokens.insert(token, flow);
+            self.flows.insert(flow,
+                              Entry {
+                                  socket: socket,
+                                  token: token,
+                                  last_used: now,
+                              });
+        }
+        let entry = self.flows.get_mut(&flow).unwrap();
+        entry.last_used = now;
+        try!(entry.socket.send_to(payload, &remote));
+        Ok(true)
+    }
+
+    /// Reads a reply on the flow socket behind `token` into `buf` and returns
+    /// it as an IP packet for the client, if there was one.
+    pub fn inbound(&mut self,
+                   token: mio::Token,
+                   buf: &mut [u8],
+                   now: Instant)
+                   -> Result<Option<Vec<u8>>> {
+        let flow = match self.tokens.get(&token) {
+            Some(&flow) => flow,
+            None => return Ok(None),
+        };
+        let entry = self.flows.get_mut(&flow).unwrap();
+        let (len, from) = match try!(entry.socket.recv_from(buf)) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if len == buf.len() {
+            warn!("Dropping oversized NAT reply from {}.", from);
+            return Ok(None);
+        }
+        if from != flow.remote {
+            debug!("Dropping NAT reply from unexpected source {}.", from);
+            return Ok(None);
+        }
+        entry.last_used = now;
+        Ok(Some(packet::build_udp(&flow.remote, &flow.inner, &buf[0..len])))
+    }
+
+    fn allocate_token(&mut self) -> mio::Token {
+        // There are more tokens than flows, so a free one always exists.
+        loop {
+            let token = mio::Token(self.base + self.next);
+            self.next = (self.next + 1) % (self.max_flows * 2);
+            if !self.tokens.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+
+    /// Closes the sockets of idle flows.
+    pub fn expire(&mut self, poll: &mio::Poll, now: Instant) {
+        let timeout = Duration::from_secs(FLOW_TIMEOUT);
+        let idle: Vec<Flow> = self.flows
+            .iter()
+            .filter(|&(_, entry)| now.duration_since(entry.last_used) >= timeout)
+            .map(|(&flow, _)| flow)
+            .collect();
+        for flow in idle {
+            let entry = self.flows.remove(&flow).unwrap();
+            let _ = poll.deregister(&entry.socket);
+            self.tokens.remove(&entry.token);
+            debug!("NAT flow {} -> {} expired.", flow.inner, flow.remote);
+        }
+    }
+}
+
+#[test]
+fn nat_test() {
+    use std::net::UdpSocket;
+
+    let poll = mio::Poll::new().unwrap();
+    let mut nat = Nat::new(16, 1);
+    let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let inner: SocketAddr = "10.10.10.2:5353".parse().unwrap();
+    let remote = echo.local_addr().unwrap();
+
+    let now = Instant::now();
+    assert!(nat.outbound(&poll, &packet::build_udp(&inner, &remote, b"ping"), now).unwrap());
+    let mut buf = [0u8; 64];
+    let (len, from) = echo.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[0..len], b"ping");
+    echo.send_to(b"pong", from).unwrap();
+
+    let mut events = mio::Events::with_capacity(4);
+    poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+    let token = events.iter().next().unwrap().token();
+    assert!(nat.owns(token));
+    let reply = nat.inbound(token, &mut buf, now).unwrap().unwrap();
+    assert_eq!(packet::parse_udp(&reply).unwrap(), (remote, inner, &b"pong"[..]));
+    let other: SocketAddr = "10.10.10.3:5353".parse().unwrap();
+    assert!(!nat.outbound(&poll, &packet::build_udp(&other, &remote, b"ping"), now).unwrap());
+
+    nat.expire(&poll, now + Duration::from_secs(FLOW_TIMEOUT));
+    assert!(nat.flows.is_empty());
+}
@@ -0,0 +1,357 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Neighbour discovery for the TAP device.
+//!
+//! On a TAP device bridged to a LAN, other hosts look for clients with ARP
+//! and IPv6 neighbour solicitations. Each client has a MAC address of its
+//! own, derived from its id, and the device answers for it. Hosts are
+//! learned from the frames they send, so packets from clients go straight
+//! to them; an unknown host is solicited while the packet is broadcast.
+//! Unicast frames for anyone else are ignored instead of being carried to
+//! every client. When the device is not bridged, the kernel sends from and
+//! to its own address with ARP off, and everything goes back to it.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+use packet;
+
+pub type Mac = [u8; 6];
+
+pub const ETHERNET_HEADER: usize = 14;
+pub const BROADCAST: Mac = [0xff; 6];
+/// A locally administered address for packets that no client sent.
+pub const PEER: Mac = [0x02, 0x6b, 0x79, 0x74, 0x61, 0x6e];
+
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+const ETHERTYPE_IPV6: [u8; 2] = [0x86, 0xdd];
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u8 = 1;
+const ARP_REPLY: u8 = 2;
+const ND_SOLICIT: u8 = 135;
+const ND_ADVERT: u8 = 136;
+const ND_LEN: usize = 32;
+/// Hosts remembered at most; the table starts over once it is full.
+const MAX_HOSTS: usize = 4096;
+/// Seconds between solicitations for the same address.
+const SOLICIT_INTERVAL: u64 = 1;
+
+/// The MAC address of client `id`.
+pub fn client_mac(id: u8) -> Mac {
+    [0x02, 0x6b, 0x79, 0x74, 0x00, id]
+}
+
+/// What to do with a frame read from the device.
+#[derive(Debug, PartialEq)]
+pub enum Inbound {
+    /// The frame carries an IP packet for the tunnel.
+    Packet,
+    /// Write this frame back to the device instead.
+    Reply(Vec<u8>),
+    Ignore,
+}
+
+#[derive(Default)]
+pub struct Table {
+    clients: HashMap<IpAddr, Mac>,
+    hosts: HashMap<IpAddr, Mac>,
+    solicited: HashMap<IpAddr, Instant>,
+    // The device's own address, once the kernel sent from it.
+    own: Option<Mac>,
+}
+
+impl Table {
+    /// Replaces the addresses of clients with those in `clients`, each with
+    /// the id of its client.
+    pub fn set_clients(&mut self, clients: &[(IpAddr, u8)]) {
+        self.clients = clients.iter().map(|&(ip, id)| (ip, client_mac(id))).collect();
+    }
+
+    /// Learns the sender of a frame read from the device and tells what to
+    /// do with it.
+    pub fn inbound(&mut self, frame: &[u8]) -> Inbound {
+        if frame.len() <= ETHERNET_HEADER {
+            return Inbound::Ignore;
+        }
+        let (dst, src) = (mac_at(frame, 0), mac_at(frame, 6));
+        let ethertype = [frame[12], frame[13]];
+        let payload = &frame[ETHERNET_HEADER..];
+        if ethertype == ETHERTYPE_ARP {
+            return self.arp(src, payload);
+        }
+        if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+            return Inbound::Ignore;
+        }
+        if dst == src {
+            self.own = Some(src);
+        } else if let Ok(ip) = packet::source(payload) {
+            self.learn(ip, src);
+        }
+        if ethertype == ETHERTYPE_IPV6 && payload.len() >= packet::IPV6_HEADER_LEN + ND_LEN &&
+           payload[6] == packet::IPPROTO_ICMPV6 &&
+           payload[packet::IPV6_HEADER_LEN] == ND_SOLICIT {
+            return self.solicitation(src, payload);
+        }
+        if dst[0] & 1 == 1 || dst == PEER || Some(dst) == self.own ||
+           self.clients.values().any(|&mac| mac == dst) {
+            Inbound::Packet
+        } else {
+            Inbound::Ignore
+        }
+    }
+
+    /// The destination and source MAC addresses for a packet from the
+    /// tunnel, and a frame to send first if the destination needs to be
+    /// solicited.
+    pub fn outbound(&mut self, data: &[u8], now: Instant) -> (Mac, Mac, Option<Vec<u8>>) {
+        let src = packet::source(data).ok();
+        let from = src.and_then(|ip| self.clients.get(&ip).cloned()).unwrap_or(PEER);
+        let dst = match packet::destination(data) {
+            Ok(dst) => dst,
+            Err(_) => return (BROADCAST, from, None),
+        };
+        if let Some(mac) = multicast_mac(dst) {
+            return (mac, from, None);
+        }
+        if let Some(&mac) = self.hosts.get(&dst) {
+            return (mac, from, None);
+        }
+        if let Some(own) = self.own {
+            return (own, from, None);
+        }
+        let due = self.solicited
+            .get(&dst)
+            .map_or(true, |&sent| now - sent >= Duration::from_secs(SOLICIT_INTERVAL));
+        let solicit = match src {
+            Some(src) if due => {
+                if self.solicited.len() >= MAX_HOSTS {
+                    self.solicited.clear();
+                }
+                self.solicited.insert(dst, now);
+                solicitation(from, src, dst)
+            }
+            _ => None,
+        };
+        (BROADCAST, from, solicit)
+    }
+
+    fn learn(&mut self, ip: IpAddr, mac: Mac) {
+        if mac[0] & 1 == 1 || ip.is_unspecified() || self.clients.contains_key(&ip) {
+            return;
+        }
+        if self.hosts.len() >= MAX_HOSTS && !self.hosts.contains_key(&ip) {
+            self.hosts.clear();
+        }
+        self.hosts.insert(ip, mac);
+        self.solicited.remove(&ip);
+    }
+
+    fn arp(&mut self, src: Mac, arp: &[u8]) -> Inbound {
+        if arp.len() < ARP_LEN || arp[0..6] != [0, 1, 8, 0, 6, 4] {
+            return Inbound::Ignore;
+        }
+        let sender = ipv4_at(arp, 14);
+        let target = ipv4_at(arp, 24);
+        self.learn(IpAddr::V4(sender), mac_at(arp, 8));
+        match self.clients.get(&IpAddr::V4(target)) {
+            Some(&mac) if arp[7] == ARP_REQUEST => {
+                Inbound::Reply(arp_frame(ARP_REPLY, (mac, target), (mac_at(arp, 8), sender), src))
+            }
+            _ => Inbound::Ignore,
+        }
+    }
+
+    fn solicitation(&mut self, src: Mac, ip: &[u8]) -> Inbound {
+        let target = ipv6_at(ip, packet::IPV6_HEADER_LEN + 8);
+        let mac = match self.clients.get(&IpAddr::V6(target)) {
+            Some(&mac) => mac,
+            None => return Inbound::Ignore,
+        };
+        let sender = ipv6_at(ip, 8);
+        // Unsolicited if the sender has no address yet.
+        let (to, dst, flags) = if sender.is_unspecified() {
+            (multicast_mac(IpAddr::V6(all_nodes())).unwrap(), all_nodes(), 0x20)
+        } else {
+            (src, sender, 0x60)
+        };
+        let mut icmp = vec![ND_ADVERT, 0, 0, 0, flags, 0, 0, 0];
+        icmp.extend_from_slice(&target.octets());
+        icmp.extend_from_slice(&[2, 1]);
+        icmp.extend_from_slice(&mac);
+        Inbound::Reply(icmpv6_frame(to, mac, target, dst, icmp))
+    }
+}
+
+/// A frame asking where `dst` is, on behalf of `src` at `from`.
+fn solicitation(from: Mac, src: IpAddr, dst: IpAddr) -> Option<Vec<u8>> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            Some(arp_frame(ARP_REQUEST, (from, src), ([0; 6], dst), BROADCAST))
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let octets = dst.octets();
+            let group = Ipv6Addr::new(0xff02,
+                                      0,
+                                      0,
+                                      0,
+                                      0,
+                                      1,
+                                      0xff00 | octets[13] as u16,
+                                      (octets[14] as u16) << 8 | octets[15] as u16);
+            let mut icmp = vec![ND_SOLICIT, 0, 0, 0, 0, 0, 0, 0];
+            icmp.extend_from_slice(&octets);
+            icmp.extend_from_slice(&[1, 1]);
+            icmp.extend_from_slice(&from);
+            let to = multicast_mac(IpAddr::V6(group)).unwrap();
+            Some(icmpv6_frame(to, from, src, group, icmp))
+        }
+        _ => None,
+    }
+}
+
+fn arp_frame(op: u8, sender: (Mac, Ipv4Addr), target: (Mac, Ipv4Addr), to: Mac) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER + ARP_LEN);
+    frame.extend_from_slice(&to);
+    frame.extend_from_slice(&sender.0);
+    frame.extend_from_slice(&ETHERTYPE_ARP);
+    frame.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, op]);
+    frame.extend_from_slice(&sender.0);
+    frame.extend_from_slice(&sender.1.octets());
+    frame.extend_from_slice(&target.0);
+    frame.extend_from_slice(&target.1.octets());
+    frame
+}
+
+fn icmpv6_frame(to: Mac, from: Mac, src: Ipv6Addr, dst: Ipv6Addr, mut icmp: Vec<u8>) -> Vec<u8> {
+    let len = icmp.len();
+    let mut pseudo = Vec::with_capacity(40 + len);
+    pseudo.extend_from_slice(&src.octets());
+    pseudo.extend_from_slice(&dst.octets());
+    pseudo.extend_from_slice(&[0, 0, (len >> 8) as u8, len as u8, 0, 0, 0, packet::IPPROTO_ICMPV6]);
+    pseudo.extend_from_slice(&icmp);
+    let cksum = packet::inet_cksum(&pseudo);
+    icmp[2] = (cksum >> 8) as u8;
+    icmp[3] = cksum as u8;
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER + packet::IPV6_HEADER_LEN + len);
+    frame.extend_from_slice(&to);
+    frame.extend_from_slice(&from);
+    frame.extend_from_slice(&ETHERTYPE_IPV6);
+    // Neighbour discovery insists on a hop limit of 255.
+    let header = [0x60, 0, 0, 0, (len >> 8) as u8, len as u8, packet::IPPROTO_ICMPV6, 255];
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(&dst.octets());
+    frame.extend_from_slice(&icmp);
+    frame
+}
+
+/// The Ethernet address of a broadcast or multicast IP address.
+fn multicast_mac(ip: IpAddr) -> Option<Mac> {
+    match ip {
+        IpAddr::V4(ip) if ip.is_broadcast() => Some(BROADCAST),
+        IpAddr::V4(ip) if ip.is_multicast() => {
+            let o = ip.octets();
+            Some([0x01, 0x00, 0x5e, o[1] & 0x7f, o[2], o[3]])
+        }
+        IpAddr::V6(ip) if ip.is_multicast() => {
+            let o = ip.octets();
+            Some([0x33, 0x33, o[12], o[13], o[14], o[15]])
+        }
+        _ => None,
+    }
+}
+
+fn all_nodes() -> Ipv6Addr {
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1)
+}
+
+fn mac_at(data: &[u8], offset: usize) -> Mac {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&data[offset..offset + 6]);
+    mac
+}
+
+fn ipv4_at(data: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(data[offset], data[offset + 1], data[offset + 2], data[offset + 3])
+}
+
+fn ipv6_at(data: &[u8], offset: usize) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&data[offset..offset + 16]);
+    Ipv6Addr::from(octets)
+}
+
+#[test]
+fn neighbour_test() {
+    let host = [0x52, 0x54, 0, 0, 0, 1];
+    let mut table = Table::default();
+    table.set_clients(&[("10.10.10.2".parse().unwrap(), 2),
+                        ("fd00::2".parse().unwrap(), 2)]);
+
+    // A host on the LAN looks for a client and gets the client's address.
+    let request = arp_frame(ARP_REQUEST,
+                            (host, Ipv4Addr::new(10, 10, 10, 50)),
+                            ([0; 6], Ipv4Addr::new(10, 10, 10, 2)),
+                            BROADCAST);
+    let reply = match table.inbound(&request) {
+        Inbound::Reply(reply) => reply,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert_eq!(&reply[0..6], &host);
+    assert_eq!(&reply[6..12], &client_mac(2));
+    assert_eq!(reply[21], ARP_REPLY);
+    assert_eq!(&reply[38..42], &[10, 10, 10, 50]);
+
+    // Packets from the client now go straight to the host.
+    let packet = packet::build_udp(&"10.10.10.2:4000".parse().unwrap(),
+                                   &"10.10.10.50:53".parse().unwrap(),
+                                   b"query");
+    assert_eq!(table.outbound(&packet, Instant::now()), (host, client_mac(2), None));
+
+    // An unknown host is solicited, and the packet broadcast meanwhile.
+    let packet = packet::build_udp(&"10.10.10.2:4000".parse().unwrap(),
+                                   &"10.10.10.60:53".parse().unwrap(),
+                                   b"query");
+    let now = Instant::now();
+    let (to, _, solicit) = table.outbound(&packet, now);
+    assert_eq!(to, BROADCAST);
+    assert_eq!(solicit.unwrap()[21], ARP_REQUEST);
+    assert_eq!(table.outbound(&packet, now).2, None);
+
+    // Unicast frames for others stay out of the tunnel.
+    let mut frame = vec![0x52, 0x54, 0, 0, 0, 9];
+    frame.extend_from_slice(&host);
+    frame.extend_from_slice(&ETHERTYPE_IPV4);
+    frame.extend_from_slice(&packet);
+    assert_eq!(table.inbound(&frame), Inbound::Ignore);
+    frame[0..6].copy_from_slice(&client_mac(2));
+    assert_eq!(table.inbound(&frame), Inbound::Packet);
+
+    // IPv6 hosts get an advertisement with a valid checksum.
+    let src: Ipv6Addr = "fd00::50".parse().unwrap();
+    let solicit = solicitation(host, IpAddr::V6(src), "fd00::2".parse().unwrap()).unwrap();
+    let advert = match table.inbound(&solicit) {
+        Inbound::Reply(advert) => advert,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert_eq!(&advert[0..6], &host);
+    assert_eq!(advert[ETHERNET_HEADER + packet::IPV6_HEADER_LEN], ND_ADVERT);
+    let ip = &advert[ETHERNET_HEADER..];
+    let mut pseudo = ip[8..40].to_vec();
+    pseudo.extend_from_slice(&[0, 0, 0, ND_LEN as u8, 0, 0, 0, packet::IPPROTO_ICMPV6]);
+    pseudo.extend_from_slice(&ip[packet::IPV6_HEADER_LEN..]);
+    assert_eq!(packet::inet_cksum(&pseudo), 0);
+}
@@ -0,0 +1,112 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Emulation of a poor network on the outer transport.
+//!
+//! For testing only: datagrams leaving the socket are dropped, delayed and
+//! reordered the way netem would, without any `tc` setup. Each side only
+//! impairs what it sends, so configure both for symmetric conditions.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use mio;
+use rand;
+use network::send_raw;
+use error::Result;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Config {
+    /// Added to every datagram.
+    pub delay: Duration,
+    /// Maximum random deviation from `delay`, either way.
+    pub jitter: Duration,
+    /// Fraction of datagrams dropped, between 0 and 1.
+    pub loss: f64,
+    /// Fraction of datagrams sent at once, overtaking delayed ones.
+    pub reorder: f64,
+}
+
+pub struct Emulator {
+    config: Config,
+    // Datagrams waiting for their release time, in submission order.
+    queue: Vec<(Instant, Vec<u8>, SocketAddr)>,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1000000.0
+}
+
+impl Emulator {
+    pub fn new(config: Config) -> Emulator {
+        Emulator {
+            config: config,
+            queue: Vec::new(),
+        }
+    }
+
+    /// Release time of a datagram, or `None` to drop it, given random rolls
+    /// in [0, 1).
+    fn schedule(&self, now: Instant, loss: f64, reorder: f64, jitter: f64) -> Option<Instant> {
+        if loss < self.config.loss {
+            return None;
+        }
+        if reorder < self.config.reorder {
+            return Some(now);
+        }
+        let delay = millis(self.config.delay) + (jitter * 2.0 - 1.0) * millis(self.config.jitter);
+        let delay = if delay > 0.0 { delay } else { 0.0 };
+        Some(now + Duration::from_millis(delay as u64))
+    }
+
+    /// Queues a datagram, or drops it.
+    pub fn submit(&mut self, buf: Vec<u8>, addr: SocketAddr, now: Instant) {
+        match self.schedule(now, rand::random(), rand::random(), rand::random()) {
+            Some(at) => self.queue.push((at, buf, addr)),
+            None => trace_packet!("netem dropped {} bytes to {}", buf.len(), addr),
+        }
+    }
+
+    /// Sends every datagram that is due.
+    pub fn release(&mut self, socket: &mio::udp::UdpSocket, now: Instant) -> Result<()> {
+        let (due, held) = self.queue.drain(..).partition(|&(at, _, _)| at <= now);
+        self.queue = held;
+        for (_, buf, addr) in due {
+            try!(send_raw(socket, &buf, &addr));
+        }
+        Ok(())
+    }
+
+    /// When the next queued datagram is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.queue.iter().map(|&(at, _, _)| at).min()
+    }
+}
+
+#[test]
+fn schedule_test() {
+    let emulator = Emulator::new(Config {
+        delay: Duration::from_millis(100),
+        jitter: Duration::from_millis(20),
+        loss: 0.1,
+        reorder: 0.2,
+    });
+    let now = Instant::now();
+    assert_eq!(emulator.schedule(now, 0.05, 0.9, 0.5), None);
+    assert_eq!(emulator.schedule(now, 0.5, 0.1, 0.5), Some(now));
+    assert_eq!(emulator.schedule(now, 0.5, 0.9, 0.5),
+               Some(now + Duration::from_millis(100)));
+    assert_eq!(emulator.schedule(now, 0.5, 0.9, 0.0),
+               Some(now + Duration::from_millis(80)));
+}
@@ -0,0 +1,413 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export of tunneled flows to a NetFlow v9 or IPFIX collector.
+//!
+//! Inner packets are aggregated by client, direction and 5-tuple. A flow is
+//! exported once it has been idle for `idle_timeout`; longer flows are cut
+//! every `active_timeout` so collectors see them while they last. The client
+//! id is reported as the ingress interface of traffic from a client and as
+//! the egress interface of traffic to it.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use error::{Error, Result};
+use packet;
+use stats;
+
+const TEMPLATE_V4: u16 = 256;
+const TEMPLATE_V6: u16 = 257;
+// Templates are resent this often since the transport is unreliable.
+const TEMPLATE_INTERVAL: u64 = 60;
+const MAX_MESSAGE: usize = 1400;
+const HEADER_MAX: usize = 20;
+const MAX_FLOWS: usize = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V9,
+    Ipfix,
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Result<Version> {
+        match s {
+            "9" | "v9" => Ok(Version::V9),
+            "10" | "ipfix" => Ok(Version::Ipfix),
+            _ => Err(Error::Config(format!("unknown flow export version {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub version: Version,
+    pub active_timeout: Duration,
+    pub idle_timeout: Duration,
+    /// Source id (v9) or observation domain (IPFIX) of the exporter.
+    pub domain: u32,
+}
+
+impl Config {
+    pub fn new(addr: SocketAddr) -> Config {
+        Config {
+            addr: addr,
+            version: Version::Ipfix,
+            active_timeout: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(15),
+            domain: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    client: u8,
+    // Whether the packet came from the client.
+    inbound: bool,
+    protocol: u8,
+    src: IpAddr,
+    dst: IpAddr,
+    src_port: u16,
+    dst_port: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Flow {
+    bytes: u64,
+    packets: u64,
+    first: Instant,
+    last: Instant,
+}
+
+pub struct Exporter {
+    config: Config,
+    socket: UdpSocket,
+    flows: HashMap<Key, Flow>,
+    started: Instant,
+    templates_sent: Option<Instant>,
+    // Messages sent (v9) or data records sent (IPFIX).
+    sequence: u32,
+}
+
+impl Exporter {
+    pub fn open(config: Config, now: Instant) -> Result<Exporter> {
+        let local = if config.addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = try!(UdpSocket::bind(local));
+        try!(socket.set_nonblocking(true));
+        info!("Exporting flows to {} ({:?}).", config.addr, config.version);
+        Ok(Exporter {
+            config: config,
+            socket: socket,
+            flows: HashMap::new(),
+            started: now,
+            templates_sent: None,
+            sequence: 0,
+        })
+    }
+
+    /// Accounts an inner packet to its flow. `inbound` is true for packets
+    /// sent by `client`.
+    pub fn record(&mut self, client: u8, inbound: bool, data: &[u8], now: Instant) {
+        let (protocol, ports) = match packet::ports(data) {
+            Ok(transport) => transport,
+            Err(_) => return,
+        };
+        let (src, dst) = match (packet::source(data), packet::destination(data)) {
+            (Ok(src), Ok(dst)) => (src, dst),
+            _ => return,
+        };
+        let (src_port, dst_port) = ports.unwrap_or((0, 0));
+        let key = Key {
+            client: client,
+            inbound: inbound,
+            protocol: protocol,
+            src: src,
+            dst: dst,
+            src_port: src_port,
+            dst_port: dst_port,
+        };
+        if !self.flows.contains_key(&key) && self.flows.len() >= MAX_FLOWS {
+            debug!("Flow cache is full. Exporting every flow early.");
+            let flows = self.flows.drain().collect();
+            self.export(flows, now);
+        }
+        let flow = self.flows.entry(key).or_insert(Flow {
+            bytes: 0,
+            packets: 0,
+            first: now,
+            last: now,
+        });
+        flow.bytes += data.len() as u64;
+        flow.packets += 1;
+        flow.last = now;
+    }
+
+    /// Exports the flows that went idle or have been active for too long.
+    pub fn expire(&mut self, now: Instant) {
+        let (active, idle) = (self.config.active_timeout, self.config.idle_timeout);
+        let expired: Vec<Key> = self.flows
+            .iter()
+            .filter(|&(_, flow)| now - flow.last >= idle || now - flow.first >= active)
+            .map(|(&key, _)| key)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        let flows = expired.into_iter()
+            .map(|key| (key, self.flows.remove(&key).unwrap()))
+            .collect();
+        self.export(flows, now);
+    }
+
+    fn export(&mut self, mut flows: Vec<(Key, Flow)>, now: Instant) {
+        let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let mut templates = self.templates_sent
+            .map_or(true, |sent| now - sent >= Duration::from_secs(TEMPLATE_INTERVAL));
+        if templates {
+            self.templates_sent = Some(now);
+        }
+        flows.sort_by_key(|&(key, _)| template_id(&key));
+        let mut i = 0;
+        while i < flows.len() {
+            let mut body = Vec::new();
+            let mut records = 0;
+            let mut template_records = 0;
+            if templates {
+                body.extend(template_set(self.config.version));
+                template_records = 2;
+                templates = false;
+            }
+            while i < flows.len() {
+                let id = template_id(&flows[i].0);
+                let start = body.len();
+                push_u16(&mut body, id);
+                push_u16(&mut body, 0);
+                let mut count = 0;
+                while i < flows.len() && template_id(&flows[i].0) == id {
+                    let record = self.encode(&flows[i].0, &flows[i].1, wall, now);
+                    if HEADER_MAX + body.len() + record.len() + 3 > MAX_MESSAGE {
+                        break;
+                    }
+                    body.extend(record);
+                    count += 1;
+                    i += 1;
+                }
+                if count == 0 {
+                    body.truncate(start);
+                    break;
+                }
+                finish_set(&mut body, start);
+                records += count;
+            }
+            let message = self.header(&body, records + template_records, wall, now);
+            if let Err(e) = self.socket.send_to(&message, &self.config.addr) {
+                debug!("Failed to export flows to {}: {}", self.config.addr, e);
+            }
+            self.sequence = self.sequence.wrapping_add(match self.config.version {
+                Version::V9 => 1,
+                Version::Ipfix => records as u32,
+            });
+        }
+    }
+
+    fn header(&self, body: &[u8], records: usize, wall: Duration, now: Instant) -> Vec<u8> {
+        let mut message = Vec::with_capacity(HEADER_MAX + body.len());
+        match self.config.version {
+            Version::V9 => {
+                push_u16(&mut message, 9);
+                push_u16(&mut message, records as u16);
+                push_u32(&mut message, stats::millis(now - self.started) as u32);
+                push_u32(&mut message, wall.as_secs() as u32);
+            }
+            Version::Ipfix => {
+                push_u16(&mut message, 10);
+                push_u16(&mut message, (16 + body.len()) as u16);
+                push_u32(&mut message, wall.as_secs() as u32);
+            }
+        }
+        push_u32(&mut message, self.sequence);
+        push_u32(&mut message, self.config.domain);
+        message.extend_from_slice(body);
+        message
+    }
+
+    fn encode(&self, key: &Key, flow: &Flow, wall: Duration, now: Instant) -> Vec<u8> {
+        let mut record = Vec::new();
+        let (input, output) = if key.inbound { (key.client, 0) } else { (0, key.client) };
+        for &(id, _) in &fields(self.config.version, key.src.is_ipv6()) {
+            match id {
+                IE_PROTOCOL => record.push(key.protocol),
+                IE_SRC_PORT => push_u16(&mut record, key.src_port),
+                IE_DST_PORT => push_u16(&mut record, key.dst_port),
+                IE_INPUT => push_u32(&mut record, input as u32),
+                IE_OUTPUT => push_u32(&mut record, output as u32),
+                IE_BYTES => push_u64(&mut record, flow.bytes),
+                IE_PACKETS => push_u64(&mut record, flow.packets),
+                IE_SRC_V4 | IE_SRC_V6 => push_addr(&mut record, key.src),
+                IE_DST_V4 | IE_DST_V6 => push_addr(&mut record, key.dst),
+                IE_FIRST_UPTIME => {
+                    push_u32(&mut record, stats::millis(flow.first - self.started) as u32)
+                }
+                IE_LAST_UPTIME => {
+                    push_u32(&mut record, stats::millis(flow.last - self.started) as u32)
+                }
+                IE_START_MS => push_u64(&mut record, epoch_millis(wall, now, flow.first)),
+                IE_END_MS => push_u64(&mut record, epoch_millis(wall, now, flow.last)),
+                _ => unreachable!(),
+            }
+        }
+        record
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        let flows: Vec<(Key, Flow)> = self.flows.drain().collect();
+        if !flows.is_empty() {
+            let now = flows.iter().map(|&(_, flow)| flow.last).max().unwrap();
+            self.export(flows, now);
+        }
+    }
+}
+
+// Information elements; v9 uses the same numbers for the ones it shares.
+const IE_BYTES: u16 = 1;
+const IE_PACKETS: u16 = 2;
+const IE_PROTOCOL: u16 = 4;
+const IE_SRC_PORT: u16 = 7;
+const IE_SRC_V4: u16 = 8;
+const IE_INPUT: u16 = 10;
+const IE_DST_PORT: u16 = 11;
+const IE_DST_V4: u16 = 12;
+const IE_OUTPUT: u16 = 14;
+const IE_LAST_UPTIME: u16 = 21;
+const IE_FIRST_UPTIME: u16 = 22;
+const IE_SRC_V6: u16 = 27;
+const IE_DST_V6: u16 = 28;
+const IE_START_MS: u16 = 152;
+const IE_END_MS: u16 = 153;
+
+fn fields(version: Version, ipv6: bool) -> Vec<(u16, u16)> {
+    let mut fields = if ipv6 {
+        vec![(IE_SRC_V6, 16), (IE_DST_V6, 16)]
+    } else {
+        vec![(IE_SRC_V4, 4), (IE_DST_V4, 4)]
+    };
+    fields.extend_from_slice(&[(IE_SRC_PORT, 2),
+                               (IE_DST_PORT, 2),
+                               (IE_PROTOCOL, 1),
+                               (IE_INPUT, 4),
+                               (IE_OUTPUT, 4),
+                               (IE_BYTES, 8),
+                               (IE_PACKETS, 8)]);
+    match version {
+        Version::V9 => fields.extend_from_slice(&[(IE_FIRST_UPTIME, 4), (IE_LAST_UPTIME, 4)]),
+        Version::Ipfix => fields.extend_from_slice(&[(IE_START_MS, 8), (IE_END_MS, 8)]),
+    }
+    fields
+}
+
+fn template_id(key: &Key) -> u16 {
+    if key.src.is_ipv6() { TEMPLATE_V6 } else { TEMPLATE_V4 }
+}
+
+fn template_set(version: Version) -> Vec<u8> {
+    let mut set = Vec::new();
+    push_u16(&mut set, if version == Version::V9 { 0 } else { 2 });
+    push_u16(&mut set, 0);
+    for &(id, ipv6) in &[(TEMPLATE_V4, false), (TEMPLATE_V6, true)] {
+        let fields = fields(version, ipv6);
+        push_u16(&mut set, id);
+        push_u16(&mut set, fields.len() as u16);
+        for (field, len) in fields {
+            push_u16(&mut set, field);
+            push_u16(&mut set, len);
+        }
+    }
+    finish_set(&mut set, 0);
+    set
+}
+
+// Pads the set starting at `start` to four bytes and fills in its length.
+fn finish_set(buf: &mut Vec<u8>, start: usize) {
+    while (buf.len() - start) % 4 != 0 {
+        buf.push(0);
+    }
+    let len = buf.len() - start;
+    buf[start + 2] = (len >> 8) as u8;
+    buf[start + 3] = len as u8;
+}
+
+fn epoch_millis(wall: Duration, now: Instant, at: Instant) -> u64 {
+    (wall.as_secs() * 1000 + wall.subsec_nanos() as u64 / 1000000)
+        .saturating_sub(stats::millis(now - at))
+}
+
+fn push_addr(buf: &mut Vec<u8>, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(addr) => buf.extend_from_slice(&addr.octets()),
+        IpAddr::V6(addr) => buf.extend_from_slice(&addr.octets()),
+    }
+}
+
+fn push_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&[(v >> 8) as u8, v as u8]);
+}
+
+fn push_u32(buf: &mut Vec<u8>, v: u32) {
+    push_u16(buf, (v >> 16) as u16);
+    push_u16(buf, v as u16);
+}
+
+fn push_u64(buf: &mut Vec<u8>, v: u64) {
+    push_u32(buf, (v >> 32) as u32);
+    push_u32(buf, v as u32);
+}
+
+#[test]
+fn export_test() {
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let now = Instant::now();
+    let mut config = Config::new(collector.local_addr().unwrap());
+    config.idle_timeout = Duration::from_secs(5);
+    let mut exporter = Exporter::open(config, now).unwrap();
+    let packet = packet::build_udp(&"10.10.10.2:4000".parse().unwrap(),
+                                   &"8.8.8.8:53".parse().unwrap(),
+                                   b"query");
+    exporter.record(2, true, &packet, now);
+    exporter.record(2, true, &packet, now + Duration::from_secs(1));
+    exporter.expire(now + Duration::from_secs(2));
+    assert_eq!(exporter.flows.len(), 1);
+    exporter.expire(now + Duration::from_secs(6));
+    assert!(exporter.flows.is_empty());
+
+    let mut buf = [0u8; 2048];
+    let len = collector.recv(&mut buf).unwrap();
+    let message = &buf[..len];
+    assert_eq!(&message[0..4], &[0, 10, (len >> 8) as u8, len as u8]);
+    // The template set comes first, then one record of 53 bytes padded to 56.
+    let templates = ((message[18] as usize) << 8) | message[19] as usize;
+    let data = &message[16 + templates..];
+    assert_eq!(&data[0..2], &[1, 0]);
+    assert_eq!(((data[2] as usize) << 8) | data[3] as usize, 4 + 56);
+    let record = &data[4..];
+    assert_eq!(&record[0..8], &[10, 10, 10, 2, 8, 8, 8, 8]);
+    assert_eq!(&record[8..13], &[0x0f, 0xa0, 0, 53, 17]);
+    assert_eq!(&record[13..21], &[0, 0, 0, 2, 0, 0, 0, 0]);
+    assert_eq!(record[28], packet.len() as u8 * 2);
+    assert_eq!(record[36], 2);
+}
@@ -0,0 +1,450 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal rtnetlink client for managing routes, addresses and MTUs on
+//! Linux.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use libc;
+use error::{Error, Result};
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_DUMP: u16 = 0x300;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+
+const RTMSG_LEN: usize = 12;
+const IFLA_MTU: u16 = 4;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_METRICS: u16 = 8;
+const RTAX_MTU: u16 = 2;
+const RTAX_ADVMSS: u16 = 8;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_STATIC: u8 = 4;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn push_u16(buf: &mut Vec<u8>, v: u16) {
+    let bytes: [u8; 2] = unsafe { mem::transmute(v) };
+    buf.extend_from_slice(&bytes);
+}
+
+fn push_u32(buf: &mut Vec<u8>, v: u32) {
+    let bytes: [u8; 4] = unsafe { mem::transmute(v) };
+    buf.extend_from_slice(&bytes);
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0u8; 2];
+    bytes.clone_from_slice(&buf[offset..offset + 2]);
+    unsafe { mem::transmute(bytes) }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.clone_from_slice(&buf[offset..offset + 4]);
+    unsafe { mem::transmute(bytes) }
+}
+
+fn push_attr(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    push_u16(buf, (4 + data.len()) as u16);
+    push_u16(buf, kind);
+    buf.extend_from_slice(data);
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+/// Iterates over the `(type, payload)` attributes following a fixed header.
+fn attrs(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= buf.len() {
+        let len = read_u16(buf, offset) as usize;
+        if len < 4 || offset + len > buf.len() {
+            break;
+        }
+        attrs.push((read_u16(buf, offset + 2), &buf[offset + 4..offset + len]));
+        offset += align(len);
+    }
+    attrs
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match *addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+fn parse_addr(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.clone_from_slice(data);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+fn rtmsg(family: u8, dst_len: u8) -> Vec<u8> {
+    let mut msg = vec![family,
+                       dst_len,
+                       0,
+                       0,
+                       RT_TABLE_MAIN,
+                       RTPROT_STATIC,
+                       RT_SCOPE_UNIVERSE,
+                       RTN_UNICAST];
+    push_u32(&mut msg, 0);
+    msg
+}
+
+/// An `ifaddrmsg` for `addr` on interface `index`, with its attributes.
+fn ifaddrmsg(index: u32, addr: &IpAddr, prefix: u8) -> Vec<u8> {
+    let mut msg = vec![family(addr), prefix, 0, RT_SCOPE_UNIVERSE];
+    push_u32(&mut msg, index);
+    push_attr(&mut msg, IFA_LOCAL, &octets(addr));
+    push_attr(&mut msg, IFA_ADDRESS, &octets(addr));
+    msg
+}
+
+/// An `ifinfomsg` for interface `index`: family, padding, type, index,
+/// flags and the mask of flags to change.
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> Vec<u8> {
+    let mut msg = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    push_u32(&mut msg, index);
+    push_u32(&mut msg, flags);
+    push_u32(&mut msg, change);
+    msg
+}
+
+/// Index of the interface called `name`.
+pub fn interface_index(name: &str) -> Result<u32> {
+    let c_name = try!(CString::new(name)
+        .map_err(|_| Error::Config(format!("invalid interface name {:?}", name))));
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(Error::Config(format!("no such interface {}", name)));
+    }
+    Ok(index)
+}
+
+/// A route in the main table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub dst: IpAddr,
+    pub prefix: u8,
+    pub gateway: Option<IpAddr>,
+    /// Index of the outgoing interface, needed for link-local gateways.
+    pub oif: Option<u32>,
+    /// Path MTU of the route, which TCP also derives its MSS from.
+    pub mtu: Option<u16>,
+}
+
+impl Route {
+    pub fn default(gateway: IpAddr) -> Route {
+        let dst = match gateway {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        };
+        Route {
+            dst: dst,
+            prefix: 0,
+            gateway: Some(gateway),
+            oif: None,
+            mtu: None,
+        }
+    }
+
+    pub fn host(dst: IpAddr, via: &Route) -> Route {
+        Route {
+            prefix: match dst {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            },
+            dst: dst,
+            gateway: via.gateway,
+            oif: via.oif,
+            mtu: None,
+        }
+    }
+}
+
+pub struct Netlink {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> Result<Netlink> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK,
+                         libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                         libc::NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let ret = unsafe {
+            libc::bind(fd,
+                       &addr as *const _ as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        let netlink = Netlink { fd: fd, seq: 0 };
+        if ret < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+        Ok(netlink)
+    }
+
+    /// Sends one request and collects the payloads of the replies until the
+    /// kernel acknowledges it or ends the dump.
+    fn request(&mut self, kind: u16, flags: u16, body: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut msg = Vec::with_capacity(NLMSG_HDRLEN + body.len());
+        push_u32(&mut msg, (NLMSG_HDRLEN + body.len()) as u32);
+        push_u16(&mut msg, kind);
+        push_u16(&mut msg, flags | NLM_F_REQUEST);
+        push_u32(&mut msg, self.seq);
+        push_u32(&mut msg, 0);
+        msg.extend_from_slice(body);
+        let ret = unsafe {
+            libc::send(self.fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0)
+        };
+        if ret < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let len = unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+            };
+            if len < 0 {
+                return Err(Error::Io(io::Error::last_os_error()));
+            }
+            let data = &buf[0..len as usize];
+            let mut offset = 0;
+            while offset + NLMSG_HDRLEN <= data.len() {
+                let msg_len = read_u32(data, offset) as usize;
+                if msg_len < NLMSG_HDRLEN || offset + msg_len > data.len() {
+                    return Err(Error::Route(String::from("netlink: malformed reply")));
+                }
+                let msg_kind = read_u16(data, offset + 4);
+                let seq = read_u32(data, offset + 8);
+                let payload = &data[offset + NLMSG_HDRLEN..offset + msg_len];
+                offset += align(msg_len);
+                if seq != self.seq {
+                    continue;
+                }
+                match msg_kind {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => {
+                        let errno = read_u32(payload, 0) as i32;
+                        if errno == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(Error::Route(format!("netlink: {}",
+                                                        io::Error::from_raw_os_error(-errno))));
+                    }
+                    _ => replies.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+
+    fn route(&mut self, kind: u16, flags: u16, route: &Route) -> Result<()> {
+        let mut body = rtmsg(family(&route.dst), route.prefix);
+        if route.prefix > 0 {
+            push_attr(&mut body, RTA_DST, &octets(&route.dst));
+        }
+        if let Some(ref gateway) = route.gateway {
+            push_attr(&mut body, RTA_GATEWAY, &octets(gateway));
+        }
+        if let Some(oif) = route.oif {
+            let mut index = Vec::new();
+            push_u32(&mut index, oif);
+            push_attr(&mut body, RTA_OIF, &index);
+        }
+        if let Some(mtu) = route.mtu {
+            let headers = if route.dst.is_ipv4() { 40 } else { 60 };
+            let (mut mtu_value, mut mss_value) = (Vec::new(), Vec::new());
+            push_u32(&mut mtu_value, mtu as u32);
+            push_u32(&mut mss_value, mtu.saturating_sub(headers) as u32);
+            let mut metrics = Vec::new();
+            push_attr(&mut metrics, RTAX_MTU, &mtu_value);
+            push_attr(&mut metrics, RTAX_ADVMSS, &mss_value);
+            push_attr(&mut body, RTA_METRICS, &metrics);
+        }
+        self.request(kind, flags | NLM_F_ACK, &body).map(|_| ())
+    }
+
+    pub fn add_route(&mut self, route: &Route) -> Result<()> {
+        self.route(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, route)
+    }
+
+    /// Adds the route or atomically replaces an existing one to the same
+    /// destination.
+    pub fn replace_route(&mut self, route: &Route) -> Result<()> {
+        self.route(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE, route)
+    }
+
+    pub fn delete_route(&mut self, route: &Route) -> Result<()> {
+        let route = Route {
+            gateway: None,
+            oif: None,
+            mtu: None,
+            ..route.clone()
+        };
+        self.route(RTM_DELROUTE, 0, &route)
+    }
+
+    pub fn add_address(&mut self, index: u32, addr: &IpAddr, prefix: u8) -> Result<()> {
+        let body = ifaddrmsg(index, addr, prefix);
+        self.request(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL | NLM_F_ACK, &body).map(|_| ())
+    }
+
+    /// Adds the address or updates it if the interface already has it.
+    pub fn replace_address(&mut self, index: u32, addr: &IpAddr, prefix: u8) -> Result<()> {
+        let body = ifaddrmsg(index, addr, prefix);
+        self.request(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE | NLM_F_ACK, &body).map(|_| ())
+    }
+
+    pub fn delete_address(&mut self, index: u32, addr: &IpAddr, prefix: u8) -> Result<()> {
+        let body = ifaddrmsg(index, addr, prefix);
+        self.request(RTM_DELADDR, NLM_F_ACK, &body).map(|_| ())
+    }
+
+    pub fn set_mtu(&mut self, index: u32, mtu: u16) -> Result<()> {
+        let mut body = ifinfomsg(index, 0, 0);
+        let mut value = Vec::new();
+        push_u32(&mut value, mtu as u32);
+        push_attr(&mut body, IFLA_MTU, &value);
+        self.request(RTM_NEWLINK, NLM_F_ACK, &body).map(|_| ())
+    }
+
+    /// Sets the interface flags in `change` to their values in `flags`, as
+    /// `IFF_UP` to bring it up.
+    pub fn set_flags(&mut self, index: u32, flags: u32, change: u32) -> Result<()> {
+        let body = ifinfomsg(index, flags, change);
+        self.request(RTM_NEWLINK, NLM_F_ACK, &body).map(|_| ())
+    }
+
+    /// The default route of the main table for IPv4 or IPv6, if there is one
+    /// through a gateway.
+    pub fn default_route(&mut self, v6: bool) -> Result<Option<Route>> {
+        let family = (if v6 { libc::AF_INET6 } else { libc::AF_INET }) as u8;
+        let mut body = rtmsg(family, 0);
+        body[4] = 0;
+        let replies = try!(self.request(RTM_GETROUTE, NLM_F_DUMP, &body));
+        for reply in replies {
+            if reply.len() < RTMSG_LEN || reply[0] != family || reply[1] != 0 ||
+               reply[4] != RT_TABLE_MAIN {
+                continue;
+            }
+            let mut gateway = None;
+            let mut oif = None;
+            for (kind, data) in attrs(&reply[RTMSG_LEN..]) {
+                match kind {
+                    RTA_GATEWAY => gateway = parse_addr(data),
+                    RTA_OIF if data.len() == 4 => oif = Some(read_u32(data, 0)),
+                    _ => {}
+                }
+            }
+            if let Some(gateway) = gateway {
+                let mut route = Route::default(gateway);
+                route.oif = oif;
+                return Ok(Some(route));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[test]
+fn attrs_test() {
+    let mut buf = Vec::new();
+    push_attr(&mut buf, RTA_DST, &[1, 1, 1, 1]);
+    push_attr(&mut buf, RTA_GATEWAY, &[10, 0, 0]);
+    push_attr(&mut buf, RTA_GATEWAY, &[10, 0, 0, 1]);
+    assert_eq!(buf.len(), 24);
+    let parsed = attrs(&buf);
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[1], (RTA_GATEWAY, &[10u8, 0, 0][..]));
+    assert_eq!(parsed[2], (RTA_GATEWAY, &[10u8, 0, 0, 1][..]));
+}
+
+#[test]
+fn ifaddrmsg_test() {
+    let msg = ifaddrmsg(3, &IpAddr::V4(Ipv4Addr::new(10, 10, 20, 1)), 24);
+    assert_eq!(&msg[..8], &[libc::AF_INET as u8, 24, 0, 0, 3, 0, 0, 0]);
+    let parsed = attrs(&msg[8..]);
+    assert_eq!(parsed,
+               vec![(IFA_LOCAL, &[10u8, 10, 20, 1][..]), (IFA_ADDRESS, &[10u8, 10, 20, 1][..])]);
+}
+
+#[test]
+fn ifinfomsg_test() {
+    let up = libc::IFF_UP as u32;
+    let msg = ifinfomsg(3, 0, up);
+    assert_eq!(msg.len(), 16);
+    assert_eq!(msg[0], libc::AF_UNSPEC as u8);
+    assert_eq!((read_u32(&msg, 4), read_u32(&msg, 8), read_u32(&msg, 12)), (3, 0, up));
+}
@@ -0,0 +1,210 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notices when the host moves to another network.
+//!
+//! A netlink (Linux) or routing (macOS) socket wakes the client up on link,
+//! address and route changes. Events come in bursts, so the client waits for
+//! things to settle and then compares the addresses of the host's interfaces
+//! with what it saw before.
+
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::{Duration, Instant};
+use libc;
+use mio;
+use error::{Error, Result};
+
+/// How long to wait for a burst of events to end.
+const SETTLE: u64 = 2;
+
+/// Interface name and address pairs, sorted.
+type Fingerprint = Vec<(String, IpAddr)>;
+
+#[cfg(target_os = "linux")]
+fn open_socket() -> io::Result<RawFd> {
+    const RTMGRP_LINK: u32 = 0x1;
+    const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+    const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+    const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+    unsafe {
+        let fd = libc::socket(libc::AF_NETLINK,
+                              libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                              libc::NETLINK_ROUTE);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_nl = mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE |
+                         RTMGRP_IPV6_IFADDR;
+        if libc::bind(fd,
+                      &addr as *const _ as *const libc::sockaddr,
+                      mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t) < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+        Ok(fd)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn open_socket() -> io::Result<RawFd> {
+    unsafe {
+        let fd = libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+        Ok(fd)
+    }
+}
+
+/// Addresses of every interface except loopback and `exclude`. IPv6
+/// link-local addresses never change with the network and are left out.
+pub fn fingerprint(exclude: Option<&str>) -> Result<Fingerprint> {
+    let mut result = Vec::new();
+    unsafe {
+        let mut addrs: *mut libc::ifaddrs = ptr::null_mut();
+        if libc::getifaddrs(&mut addrs) < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+        let mut cursor = addrs;
+        while !cursor.is_null() {
+            let ifa = &*cursor;
+            cursor = ifa.ifa_next;
+            if ifa.ifa_addr.is_null() || ifa.ifa_flags & (libc::IFF_LOOPBACK as libc::c_uint) != 0 {
+                continue;
+            }
+            let name = CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned();
+            if Some(name.as_ref()) == exclude {
+                continue;
+            }
+            let addr = match (*ifa.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let sin = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let sin6 = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    let addr = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                    if addr.segments()[0] & 0xffc0 == 0xfe80 {
+                        continue;
+                    }
+                    IpAddr::V6(addr)
+                }
+                _ => continue,
+            };
+            result.push((name, addr));
+        }
+        libc::freeifaddrs(addrs);
+    }
+    result.sort();
+    Ok(result)
+}
+
+pub struct Watcher {
+    fd: RawFd,
+    fingerprint: Fingerprint,
+    settle_at: Option<Instant>,
+}
+
+impl Watcher {
+    pub fn open() -> Result<Watcher> {
+        let fd = try!(open_socket());
+        let mut watcher = Watcher {
+            fd: fd,
+            fingerprint: Vec::new(),
+            settle_at: None,
+        };
+        watcher.fingerprint = try!(fingerprint(None));
+        Ok(watcher)
+    }
+
+    pub fn register(&self, poll: &mio::Poll, token: mio::Token) -> Result<()> {
+        try!(poll.register(&mio::unix::EventedFd(&self.fd),
+                           token,
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+        Ok(())
+    }
+
+    /// Consumes pending notifications and starts waiting for them to settle.
+    pub fn drain(&mut self, now: Instant) {
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+            };
+            if len <= 0 {
+                break;
+            }
+        }
+        self.settle_at = Some(now + Duration::from_secs(SETTLE));
+    }
+
+    /// When `changed()` next needs to be called.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.settle_at
+    }
+
+    /// Whether the interface addresses differ from the last time, once the
+    /// events have settled. `tun` is ignored.
+    pub fn changed(&mut self, now: Instant, tun: Option<&str>) -> bool {
+        match self.settle_at {
+            Some(at) if now >= at => {}
+            _ => return false,
+        }
+        self.settle_at = None;
+        let current = match fingerprint(tun) {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Failed to list interface addresses: {}", e);
+                return false;
+            }
+        };
+        let previous = mem::replace(&mut self.fingerprint, current);
+        let previous: Fingerprint = previous.into_iter()
+            .filter(|&(ref name, _)| Some(name.as_ref()) != tun)
+            .collect();
+        previous != self.fingerprint
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[test]
+fn fingerprint_test() {
+    let all = fingerprint(None).unwrap();
+    let mut sorted = all.clone();
+    sorted.sort();
+    assert_eq!(all, sorted);
+    assert!(all.iter().all(|&(_, addr)| !addr.is_loopback()));
+}
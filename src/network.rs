@@ -12,51 +12,384 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use serde_derive;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr, UdpSocket};
-use std::os::unix::io::AsRawFd;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+#[cfg(test)]
+use std::net::Ipv6Addr;
+use std::cmp;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
-use std::io::{Write, Read};
+use std::time::{Duration, Instant};
+use libc;
 use mio;
-use dns_lookup;
+use resolver;
 use bincode::Infinite;
 use bincode::serialize as encode;
 use bincode::deserialize as decode;
-use device;
-use utils;
+#[cfg(feature = "compression")]
 use snap;
-use rand::{thread_rng, Rng};
-use transient_hashmap::TransientHashMap;
+use device;
+use stream;
+use push;
+use replay;
+use identity;
+use packet;
+use signal;
+use error::{Code, Error, Result};
+use std::io;
 
+/// Process-wide interrupt flag, set by the binary's signal handlers. Every
+/// running client and server stops once it is raised.
 pub static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
 
-type Id = u8;
-type Token = u64;
+pub type Id = u8;
+pub type Token = u64;
+
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Data payloads are compressed with snappy.
+pub const CAP_SNAPPY: u32 = 1 << 0;
+/// The peer answers `Ping` with `Pong`.
+pub const CAP_KEEPALIVE: u32 = 1 << 1;
+/// The peer accepts `RawData`, so small payloads can skip compression.
+pub const CAP_RAW_DATA: u32 = 1 << 2;
+/// The peer understands `Cover` frames and answers them in kind.
+pub const CAP_COVER: u32 = 1 << 3;
+/// The peer takes `Assigned` in place of `Response`, so the server can use a
+/// subnet other than 10.10.10.0/24.
+pub const CAP_SUBNET: u32 = 1 << 4;
+/// The peer takes `Configured`, with pushed options, in place of `Response`
+/// and `Assigned`.
+pub const CAP_OPTIONS: u32 = 1 << 5;
+/// The peer takes `Batch` frames.
+pub const CAP_BATCH: u32 = 1 << 6;
+/// The peer relays streams. See the `stream` module.
+pub const CAP_STREAMS: u32 = 1 << 7;
+
+/// Names of the flags, for status output.
+const FLAG_NAMES: [(u32, &'static str); 8] = [(CAP_SNAPPY, "snappy"),
+                                              (CAP_KEEPALIVE, "keepalive"),
+                                              (CAP_RAW_DATA, "raw"),
+                                              (CAP_COVER, "cover"),
+                                              (CAP_SUBNET, "subnet"),
+                                              (CAP_OPTIONS, "options"),
+                                              (CAP_BATCH, "batch"),
+                                              (CAP_STREAMS, "streams")];
+
+/// The compression capabilities this build offers; none unless it is built
+/// with the `compression` feature.
+#[cfg(feature = "compression")]
+pub const CAP_COMPRESSION: u32 = CAP_SNAPPY | CAP_RAW_DATA;
+#[cfg(not(feature = "compression"))]
+pub const CAP_COMPRESSION: u32 = 0;
+
+#[cfg(feature = "compression")]
+pub use snap::Encoder;
+
+/// Stands in for the snappy encoder in builds without compression.
+#[cfg(not(feature = "compression"))]
+pub struct Encoder;
+
+#[cfg(not(feature = "compression"))]
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder
+    }
+}
+
+/// Packets shorter than this are sent uncompressed by default; snappy would
+/// only make them longer.
+pub const COMPRESSION_THRESHOLD: usize = 64;
+
+/// Optional behaviors a peer supports. The client offers its own in the
+/// `Request` and the server answers with what both sides agreed on.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct Capabilities {
+    pub version: u16,
+    pub flags: u32,
+    pub mtu: u16,
+}
+
+impl Capabilities {
+    pub fn new(flags: u32) -> Capabilities {
+        Capabilities {
+            version: PROTOCOL_VERSION,
+            flags: flags,
+            mtu: device::MTU,
+        }
+    }
+
+    pub fn has(&self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+
+    /// The flags set, such as `snappy,keepalive`, or `none`.
+    pub fn flag_names(&self) -> String {
+        let names: Vec<&str> = FLAG_NAMES.iter()
+            .filter(|&&(flag, _)| self.has(flag))
+            .map(|&(_, name)| name)
+            .collect();
+        if names.is_empty() {
+            String::from("none")
+        } else {
+            names.join(",")
+        }
+    }
+
+    /// How data frames are compressed, `snappy` or `off`.
+    pub fn compression(&self) -> &'static str {
+        if self.has(CAP_SNAPPY) { "snappy" } else { "off" }
+    }
+
+    pub fn negotiate(&self, peer: &Capabilities) -> Capabilities {
+        Capabilities {
+            version: cmp::min(self.version, peer.version),
+            flags: self.flags & peer.flags,
+            mtu: cmp::min(self.mtu, peer.mtu),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
-enum Message {
-    Request,
-    Response { id: Id, token: Token },
+pub enum Message {
+    /// `resume` carries the id and token of the client's previous session so
+    /// the server can hand the same address back once that session has
+    /// ended. `credential` is a secret the server maps to an isolation group.
+    /// `roam_key` is the client's half of the roaming key, or empty. See the
+    /// `roaming` module.
+    Request {
+        caps: Capabilities,
+        resume: Option<(Id, Token)>,
+        credential: Option<String>,
+        roam_key: Vec<u8>,
+    },
+    /// `roam_key` is the server's half of the roaming key, empty if the
+    /// client offered none.
+    Response {
+        id: Id,
+        token: Token,
+        caps: Capabilities,
+        roam_key: Vec<u8>,
+    },
     Data { id: Id, token: Token, data: Vec<u8> },
+    /// Moves the session to the address it came from. `mac` proves the
+    /// roaming key and `sequence` rises with every roam.
+    Roam {
+        id: Id,
+        token: Token,
+        sequence: u64,
+        mac: Vec<u8>,
+    },
+    Ping { id: Id, token: Token },
+    Pong { id: Id, token: Token },
+    /// Sent by the server when it gives up on a session.
+    Expired { id: Id, token: Token },
+    /// Echoed back by the server without a session, so clients can measure
+    /// latency before picking a server.
+    Probe { nonce: u64 },
+    /// A data frame sent uncompressed even though the session uses snappy.
+    RawData { id: Id, token: Token, data: Vec<u8> },
+    /// Dummy traffic, discarded on arrival. See the `cover` module.
+    Cover { id: Id, token: Token, padding: Vec<u8> },
+    /// A `Response` that also names the server's subnet.
+    Assigned {
+        id: Id,
+        token: Token,
+        caps: Capabilities,
+        subnet: device::Subnet,
+        roam_key: Vec<u8>,
+    },
+    /// A `Response` with settings pushed to the client. See the `push` module.
+    Configured {
+        id: Id,
+        token: Token,
+        caps: Capabilities,
+        options: Vec<push::Raw>,
+        roam_key: Vec<u8>,
+    },
+    /// A `Request` that proves a group secret instead of sending it. See the
+    /// `replay` module.
+    Stamped {
+        caps: Capabilities,
+        resume: Option<(Id, Token)>,
+        stamp: replay::Stamp,
+        roam_key: Vec<u8>,
+    },
+    /// Several small packets sent as one datagram, uncompressed. See the
+    /// `batch` module.
+    Batch {
+        id: Id,
+        token: Token,
+        packets: Vec<Vec<u8>>,
+    },
+    /// Asks the server to connect to `target`, a `HOST:PORT`, for a stream.
+    /// See the `stream` module.
+    StreamOpen {
+        id: Id,
+        token: Token,
+        stream: u32,
+        target: String,
+    },
+    /// `None` once the server connected, or why it could not.
+    StreamReply {
+        id: Id,
+        token: Token,
+        stream: u32,
+        error: Option<String>,
+    },
+    Segment {
+        id: Id,
+        token: Token,
+        segment: stream::Segment,
+    },
+    StreamClose { id: Id, token: Token, stream: u32 },
+    /// A `Request` or, with `stamp`, a `Stamped` one from a client that also
+    /// proves its identity key. See the `identity` module.
+    Identified {
+        caps: Capabilities,
+        resume: Option<(Id, Token)>,
+        credential: Option<String>,
+        stamp: Option<replay::Stamp>,
+        proof: identity::Proof,
+        roam_key: Vec<u8>,
+    },
+    /// A frame on its way through a relay to or from `exit`. The relay keeps
+    /// one socket per circuit, so `exit` only ever sees the relay. See the
+    /// `relay` module.
+    Relay {
+        circuit: u64,
+        exit: SocketAddr,
+        frame: Vec<u8>,
+    },
+    /// The server gives the client no session, for instance while it drains
+    /// for maintenance; the client should try another server. It carries
+    /// only the reason so that it is never larger than the request it
+    /// answers.
+    Refused { code: Code },
+}
+
+/// Notifications delivered to the callback registered with `on_event()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The client completed a handshake and was assigned `id`.
+    Connected { id: u8, server: SocketAddr },
+    /// The client stopped.
+    Disconnected,
+    /// The server admitted a client.
+    ClientConnected { id: u8, addr: SocketAddr },
+    /// A client moved to a new endpoint.
+    ClientRoamed { id: u8, addr: SocketAddr },
+    /// A client's session expired and its id was released.
+    ClientExpired { id: u8 },
+    /// The client was asked for its status with SIGUSR1.
+    Status(::client::Status),
+}
+
+pub type Callback = Box<Fn(&Event) + Send>;
+
+/// Stops a running `Client` or `Server` from any thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    readiness: mio::SetReadiness,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> (ShutdownHandle, mio::Registration) {
+        let (registration, readiness) = mio::Registration::new2();
+        let handle = ShutdownHandle {
+            flag: Arc::new(AtomicBool::new(false)),
+            readiness: readiness,
+        };
+        (handle, registration)
+    }
+
+    /// Asks the event loop to exit. The loop is woken up immediately.
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+        let _ = self.readiness.set_readiness(mio::Ready::readable());
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) || INTERRUPTED.load(Ordering::Relaxed)
+    }
+}
+
+pub const TUN: mio::Token = mio::Token(0);
+pub const SOCK: mio::Token = mio::Token(1);
+pub const SHUTDOWN: mio::Token = mio::Token(2);
+pub const SIGNAL: mio::Token = mio::Token(3);
+pub const NETWATCH: mio::Token = mio::Token(4);
+#[cfg(feature = "metrics")]
+pub const HEALTH: mio::Token = mio::Token(6);
+pub const STATUS: mio::Token = mio::Token(7);
+
+/// Lets signals delivered through `signal::install()` wake up `poll`.
+pub fn register_signal(poll: &mio::Poll) -> Result<()> {
+    if let Some(fd) = signal::wakeup_fd() {
+        try!(poll.register(&mio::unix::EventedFd(&fd),
+                           SIGNAL,
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+    }
+    if let Some(fd) = signal::status_fd() {
+        try!(poll.register(&mio::unix::EventedFd(&fd),
+                           STATUS,
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+    }
+    Ok(())
+}
+
+/// The first address `host` resolves to. Clients use a `resolver::Resolver`.
+pub fn resolve(host: &str) -> Result<IpAddr> {
+    resolver::Resolver::new(resolver::Family::Any, Duration::from_secs(0))
+        .resolve(host, Instant::now())
 }
 
-const TUN: mio::Token = mio::Token(0);
-const SOCK: mio::Token = mio::Token(1);
+pub fn route_id(subnet: &device::Subnet, data: &[u8]) -> Result<Id> {
+    let dst = try!(packet::destination(data));
+    match subnet.id_of(dst) {
+        Some(id) => Ok(id),
+        None if dst.is_ipv4() => {
+            Err(Error::Route(format!("destination {} is outside of {}", dst, subnet)))
+        }
+        None => {
+            Err(Error::Route(format!("destination {} is outside of {}/64",
+                                     dst,
+                                     subnet.ipv6_addr(0))))
+        }
+    }
+}
 
-fn resolve(host: &str) -> Result<IpAddr, String> {
-    let mut ip_list = try!(dns_lookup::lookup_host(host).map_err(|_| "dns_lookup::lookup_host"));
-    let ip = ip_list.next().unwrap().unwrap();
-    Ok(ip)
+/// Whether a packet is addressed to the tunnel's broadcast address or to a
+/// multicast group, and so belongs to every client rather than one.
+pub fn is_broadcast(subnet: &device::Subnet, data: &[u8]) -> bool {
+    match packet::destination(data) {
+        Ok(IpAddr::V4(dst)) => {
+            dst.is_multicast() || dst.is_broadcast() || dst == subnet.addr(255)
+        }
+        Ok(IpAddr::V6(dst)) => dst.is_multicast(),
+        Err(_) => false,
+    }
 }
 
-fn create_tun_attempt() -> device::Tun {
-    fn attempt(id: u8) -> device::Tun {
+pub fn create_tun_attempt() -> Result<device::Tun> {
+    fn attempt(id: u8) -> Result<device::Tun> {
         match id {
-            255 => panic!("Unable to create TUN device."),
+            255 => Err(Error::Device(String::from("Unable to create TUN device."))),
             _ => {
                 match device::Tun::create(id) {
-                    Ok(tun) => tun,
-                    Err(_) => attempt(id + 1),
+                    Ok(tun) => Ok(tun),
+                    Err(e) => {
+                        match e.kind() {
+                            // No other name will do any better.
+                            io::ErrorKind::NotFound |
+                            io::ErrorKind::PermissionDenied => Err(device_error(e)),
+                            _ => attempt(id + 1),
+                        }
+                    }
                 }
             }
         }
@@ -64,262 +397,468 @@ fn create_tun_attempt() -> device::Tun {
     attempt(0)
 }
 
-fn initiate(socket: &UdpSocket, addr: &SocketAddr) -> Result<(Id, Token), String> {
-    let req_msg = Message::Request;
-    let encoded_req_msg: Vec<u8> = try!(encode(&req_msg, Infinite)
-        .map_err(|e| e.to_string()));
-
-    let mut remaining_len = encoded_req_msg.len();
-    while remaining_len > 0 {
-        let sent_bytes = try!(socket.send_to(&encoded_req_msg, addr)
-            .map_err(|e| e.to_string()));
-        remaining_len -= sent_bytes;
+/// Gives a missing `/dev/net/tun` its code.
+fn device_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound if cfg!(target_os = "linux") => {
+            Error::Failure(Code::TunMissing,
+                           String::from("/dev/net/tun is missing; load the tun module, or pass \
+                                         the device into the container"))
+        }
+        _ => Error::Io(e),
     }
-    info!("Request sent to {}.", addr);
+}
+
+/// Opens the device the tunnel runs over: the TUN device passed as `fd`, a
+/// TAP device if `tap`, or else a TUN device. `name` names the new device;
+/// without it the first free one is taken.
+pub fn open_interface(fd: Option<RawFd>,
+                      tap: bool,
+                      name: Option<&str>)
+                      -> Result<Box<device::VirtualInterface>> {
+    Ok(match (fd, tap, name) {
+        (Some(fd), _, _) => Box::new(try!(device::Passed::open(fd))),
+        (None, true, name) => {
+            Box::new(try!(device::Tap::create(name.unwrap_or("tap%d")).map_err(device_error)))
+        }
+        (None, false, Some(name)) => {
+            Box::new(try!(device::Tun::create_named(name).map_err(device_error)))
+        }
+        (None, false, None) => Box::new(try!(create_tun_attempt())),
+    })
+}
 
-    let mut buf = [0u8; 1600];
-    let (len, recv_addr) = try!(socket.recv_from(&mut buf).map_err(|e| e.to_string()));
-    assert_eq!(&recv_addr, addr);
-    info!("Response received from {}.", addr);
+/// Upper bound on the bincode framing around a data payload.
+const FRAME_OVERHEAD: usize = 64;
 
-    let resp_msg: Message = try!(decode(&buf[0..len]).map_err(|e| e.to_string()));
-    match resp_msg {
-        Message::Response { id, token } => Ok((id, token)),
-        _ => Err(format!("Invalid message {:?} from {}", resp_msg, addr)),
+/// Size of a receive buffer that can hold any frame carrying a packet of up to
+/// `mtu` bytes, allowing for snappy's worst-case expansion.
+pub fn frame_capacity(mtu: u16) -> usize {
+    let n = mtu as usize;
+    32 + n + n / 6 + FRAME_OVERHEAD
+}
+
+fn set_socket_option(socket: &mio::udp::UdpSocket,
+                     level: libc::c_int,
+                     name: libc::c_int,
+                     value: libc::c_int)
+                     -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+                         level,
+                         name,
+                         &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret < 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
     }
+    Ok(())
 }
 
+/// Sets the TTL of outgoing tunnel datagrams.
+pub fn set_outer_ttl(socket: &mio::udp::UdpSocket, ttl: u8) -> Result<()> {
+    set_socket_option(socket, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+}
 
-pub fn connect(host: &str, port: u16, default: bool) {
-    info!("Working in client mode.");
-    let remote_ip = resolve(host).unwrap();
-    let remote_addr = SocketAddr::new(remote_ip, port);
-    info!("Remote server: {}", remote_addr);
+/// Sets or clears the DF bit on outgoing tunnel datagrams.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &mio::udp::UdpSocket, df: bool) -> Result<()> {
+    let mode = if df {
+        libc::IP_PMTUDISC_DO
+    } else {
+        libc::IP_PMTUDISC_DONT
+    };
+    set_socket_option(socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, mode)
+}
 
-    let local_addr: SocketAddr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
-    let socket = UdpSocket::bind(&local_addr).unwrap();
+#[cfg(target_os = "macos")]
+pub fn set_dont_fragment(socket: &mio::udp::UdpSocket, df: bool) -> Result<()> {
+    // IP_DONTFRAG from <netinet/in.h>.
+    set_socket_option(socket, libc::IPPROTO_IP, 28, df as libc::c_int)
+}
 
-    let (id, token) = initiate(&socket, &remote_addr).unwrap();
-    info!("Session established with token {}. Assigned IP address: 10.10.10.{}.",
-          token,
-          id);
+/// Marks outgoing tunnel datagrams so policy routing can tell them apart.
+#[cfg(target_os = "linux")]
+pub fn set_fwmark(socket: &mio::udp::UdpSocket, mark: u32) -> Result<()> {
+    set_socket_option(socket, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+}
 
-    info!("Bringing up TUN device.");
-    let mut tun = create_tun_attempt();
-    let tun_rawfd = tun.as_raw_fd();
-    tun.up(id);
-    let tunfd = mio::unix::EventedFd(&tun_rawfd);
-    info!("TUN device {} initialized. Internal IP: 10.10.10.{}/24.",
-          tun.name(),
-          id);
+#[cfg(target_os = "macos")]
+pub fn set_fwmark(_: &mio::udp::UdpSocket, _: u32) -> Result<()> {
+    Err(Error::Config(String::from("fwmark is only available in Linux")))
+}
 
-    let poll = mio::Poll::new().unwrap();
-    info!("Setting up TUN device for polling.");
-    poll.register(&tunfd, TUN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+/// Sends and receives tunnel datagrams only through `interface`.
+#[cfg(target_os = "linux")]
+pub fn bind_to_device(socket: &mio::udp::UdpSocket, interface: &str) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_BINDTODEVICE,
+                         interface.as_ptr() as *const libc::c_void,
+                         interface.len() as libc::socklen_t)
+    };
+    if ret < 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
 
-    info!("Setting up socket for polling.");
-    let sockfd = mio::udp::UdpSocket::from_socket(socket).unwrap();
-    poll.register(&sockfd, SOCK, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+#[cfg(target_os = "macos")]
+pub fn bind_to_device(socket: &mio::udp::UdpSocket, interface: &str) -> Result<()> {
+    let name = try!(::std::ffi::CString::new(interface)
+        .map_err(|_| Error::Config(format!("invalid interface name {:?}", interface))));
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(Error::Config(format!("no such interface {}", interface)));
+    }
+    // IP_BOUND_IF from <netinet/in.h>.
+    set_socket_option(socket, libc::IPPROTO_IP, 25, index as libc::c_int)
+}
 
-    let mut events = mio::Events::with_capacity(1024);
-    let mut buf = [0u8; 1600];
+/// Options applied to the outer UDP socket. `None` keeps the system default.
+#[derive(Debug, Clone, Default)]
+pub struct OuterOptions {
+    pub ttl: Option<u8>,
+    pub dont_fragment: Option<bool>,
+    pub fwmark: Option<u32>,
+    pub interface: Option<String>,
+}
 
-    // RAII so ignore unused variable warning
-    let _gw = if default {
-        Some(utils::DefaultGateway::create("10.10.10.1", &format!("{}", remote_addr.ip())))
-    } else {
-        None
-    };
+pub fn configure_outer(socket: &mio::udp::UdpSocket, options: &OuterOptions) -> Result<()> {
+    if let Some(ttl) = options.ttl {
+        try!(set_outer_ttl(socket, ttl));
+    }
+    if let Some(df) = options.dont_fragment {
+        try!(set_dont_fragment(socket, df));
+    }
+    if let Some(mark) = options.fwmark {
+        try!(set_fwmark(socket, mark));
+    }
+    if let Some(ref interface) = options.interface {
+        try!(bind_to_device(socket, interface));
+    }
+    Ok(())
+}
 
-    let mut encoder = snap::Encoder::new();
-    let mut decoder = snap::Decoder::new();
+/// Copies the DSCP of inner packets onto the outer socket, touching the
+/// socket option only when the value changes.
+pub struct DscpMarker {
+    current: u8,
+}
+
+impl DscpMarker {
+    pub fn new() -> DscpMarker {
+        DscpMarker { current: 0 }
+    }
 
-    info!("Ready for transmission.");
+    pub fn mark(&mut self, socket: &mio::udp::UdpSocket, data: &[u8]) -> Result<()> {
+        self.mark_dscp(socket, packet::dscp(data))
+    }
 
-    loop {
-        if INTERRUPTED.load(Ordering::Relaxed) {
-            break;
+    /// Like `mark()`, with the DSCP bits already read from the packet.
+    pub fn mark_dscp(&mut self, socket: &mio::udp::UdpSocket, dscp: Option<u8>) -> Result<()> {
+        let dscp = match dscp {
+            Some(dscp) => dscp,
+            None => return Ok(()),
+        };
+        if dscp == self.current {
+            return Ok(());
         }
+        try!(set_socket_option(socket,
+                               libc::IPPROTO_IP,
+                               libc::IP_TOS,
+                               (dscp as libc::c_int) << 2));
+        self.current = dscp;
+        Ok(())
+    }
+}
 
-        poll.poll(&mut events, None).unwrap();
-
-        for event in events.iter() {
-            match event.token() {
-                SOCK => {
-                    let (len, addr) = sockfd.recv_from(&mut buf).unwrap().unwrap();
-                    let msg: Message = decode(&buf[0..len]).unwrap();
-                    match msg {
-                        Message::Request |
-                        Message::Response { id: _, token: _ } => {
-                            warn!("Invalid message {:?} from {}", msg, addr);
-                        }
-                        Message::Data { id: _, token: server_token, data } => {
-                            if token == server_token {
-                                let decompressed_data = decoder.decompress_vec(&data).unwrap();
-                                let data_len = decompressed_data.len();
-                                let mut sent_len = 0;
-                                while sent_len < data_len {
-                                    sent_len += tun.write(&decompressed_data[sent_len..data_len])
-                                        .unwrap();
-                                }
-                            } else {
-                                warn!("Token mismatched. Received: {}. Expected: {}",
-                                      server_token,
-                                      token);
-                            }
-                        }
-                    }
-                }
-                TUN => {
-                    let len: usize = tun.read(&mut buf).unwrap();
-                    let data = &buf[0..len];
-                    let msg = Message::Data {
-                        id: id,
-                        token: token,
-                        data: encoder.compress_vec(data).unwrap(),
-                    };
-                    let encoded_msg = encode(&msg, Infinite).unwrap();
-                    let data_len = encoded_msg.len();
-                    let mut sent_len = 0;
-                    while sent_len < data_len {
-                        sent_len += sockfd.send_to(&encoded_msg[sent_len..data_len], &remote_addr)
-                            .unwrap()
-                            .unwrap();
-                    }
-                }
-                _ => unreachable!(),
+pub fn encode_message(msg: &Message) -> Result<Vec<u8>> {
+    encode(msg, Infinite).map_err(|e| Error::Decode(e.to_string()))
+}
+
+pub fn send_raw(socket: &mio::udp::UdpSocket,
+                encoded_msg: &[u8],
+                addr: &SocketAddr)
+                -> Result<()> {
+    let data_len = encoded_msg.len();
+    let mut sent_len = 0;
+    while sent_len < data_len {
+        sent_len += match try!(socket.send_to(&encoded_msg[sent_len..data_len], addr)) {
+            Some(len) => len,
+            None => return Err(Error::Io(io::Error::new(io::ErrorKind::WouldBlock,
+                                                        "socket not ready for sending"))),
+        };
+    }
+    Ok(())
+}
+
+/// Asks for the local address each datagram was sent to, so that replies
+/// can leave from it on a host with several. See `recv_with_dst()`.
+#[cfg(target_os = "linux")]
+pub fn set_pktinfo(socket: &mio::udp::UdpSocket) -> Result<()> {
+    set_socket_option(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)
+}
+
+#[cfg(target_os = "macos")]
+pub fn set_pktinfo(_: &mio::udp::UdpSocket) -> Result<()> {
+    Err(Error::Config(String::from("source address selection is only available in Linux")))
+}
+
+/// Like `recv_from()`, and also returns the local address the datagram was
+/// sent to once `set_pktinfo()` is on.
+#[cfg(target_os = "linux")]
+pub fn recv_with_dst(socket: &mio::udp::UdpSocket,
+                     buf: &mut [u8])
+                     -> Result<Option<(usize, SocketAddr, Option<Ipv4Addr>)>> {
+    let mut from: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 keeps the control buffer aligned for cmsghdr.
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut from as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(None);
+        }
+        return Err(Error::Io(err));
+    }
+    if from.sin_family as libc::c_int != libc::AF_INET {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData,
+                                            "datagram from a non-IPv4 peer")));
+    }
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(u32::from_be(from.sin_addr.s_addr))),
+                               u16::from_be(from.sin_port));
+    let mut dst = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::IPPROTO_IP && (*cmsg).cmsg_type == libc::IP_PKTINFO {
+                let info = &*(libc::CMSG_DATA(cmsg) as *const libc::in_pktinfo);
+                dst = Some(Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr)));
             }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
         }
     }
+    Ok(Some((len as usize, addr, dst)))
 }
 
-pub fn serve(port: u16) {
-    if cfg!(not(target_os = "linux")) {
-        panic!("Server mode is only available in Linux!");
+#[cfg(target_os = "macos")]
+pub fn recv_with_dst(socket: &mio::udp::UdpSocket,
+                     buf: &mut [u8])
+                     -> Result<Option<(usize, SocketAddr, Option<Ipv4Addr>)>> {
+    let received = try!(socket.recv_from(buf));
+    Ok(received.map(|(len, addr)| (len, addr, None)))
+}
+
+/// Like `send_raw()`, from the local address `src` of a socket bound to
+/// the wildcard address.
+#[cfg(target_os = "linux")]
+pub fn send_from(socket: &mio::udp::UdpSocket,
+                 encoded_msg: &[u8],
+                 addr: &SocketAddr,
+                 src: Ipv4Addr)
+                 -> Result<()> {
+    let to = match *addr {
+        SocketAddr::V4(to) => to,
+        SocketAddr::V6(_) => return send_raw(socket, encoded_msg, addr),
+    };
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_port = to.port().to_be();
+    sin.sin_addr.s_addr = u32::from(*to.ip()).to_be();
+    let mut iov = libc::iovec {
+        iov_base: encoded_msg.as_ptr() as *mut libc::c_void,
+        iov_len: encoded_msg.len(),
+    };
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut sin as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    let info_len = mem::size_of::<libc::in_pktinfo>() as u32;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(info_len) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::IPPROTO_IP;
+        (*cmsg).cmsg_type = libc::IP_PKTINFO;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(info_len) as _;
+        let info = libc::CMSG_DATA(cmsg) as *mut libc::in_pktinfo;
+        (*info).ipi_spec_dst.s_addr = u32::from(src).to_be();
+    }
+    if unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
     }
-    info!("Working in server mode.");
+    Ok(())
+}
 
-    info!("Enabling kernel's IPv4 forwarding.");
-    utils::enable_ipv4_forwarding().unwrap();
+#[cfg(target_os = "macos")]
+pub fn send_from(socket: &mio::udp::UdpSocket,
+                 encoded_msg: &[u8],
+                 addr: &SocketAddr,
+                 _: Ipv4Addr)
+                 -> Result<()> {
+    send_raw(socket, encoded_msg, addr)
+}
 
-    info!("Bringing up TUN device.");
-    let mut tun = create_tun_attempt();
-    tun.up(1);
+/// Parses a datagram received from a peer. Performs no I/O.
+pub fn decode_message(buf: &[u8]) -> Result<Message> {
+    decode(buf).map_err(|e| Error::Decode(e.to_string()))
+}
 
-    let tun_rawfd = tun.as_raw_fd();
-    let tunfd = mio::unix::EventedFd(&tun_rawfd);
-    info!("TUN device {} initialized. Internal IP: 10.10.10.1/24.",
-          tun.name());
+/// Token of the session an encoded message belongs to, read from its fixed
+/// offset without decoding the whole frame. Lets a UDP load balancer send
+/// every frame of a session to the backend that owns it; see
+/// `token_backend()`. `None` for `Request` and `Probe`.
+pub fn session_token(frame: &[u8]) -> Option<Token> {
+    // A little-endian u32 variant index, then the id byte and the token of
+    // every variant from `Response` to `Expired`, from `RawData` to
+    // `Configured`, and from `Batch` to `StreamClose`.
+    if frame.len() < 13 || frame[1..4] != [0, 0, 0] || frame[0] < 1 || frame[0] == 7 ||
+       frame[0] == 12 || frame[0] > 17 {
+        return None;
+    }
+    Some(frame[5..13].iter().rev().fold(0, |token, &b| token << 8 | b as Token))
+}
 
-    let addr = format!("0.0.0.0:{}", port).parse().unwrap();
-    let sockfd = mio::udp::UdpSocket::bind(&addr).unwrap();
-    info!("Listening on: 0.0.0.0:{}.", port);
+/// The MTU of a session whose client offered `offered`, on a TUN device with
+/// `tun_mtu` and in a group held to `group_mtu`, if any. Derived from the
+/// offer every time, so raising the TUN MTU raises it again.
+pub fn session_mtu(offered: u16, tun_mtu: u16, group_mtu: Option<u16>) -> u16 {
+    cmp::min(cmp::min(offered, tun_mtu), group_mtu.unwrap_or(tun_mtu))
+}
 
-    let poll = mio::Poll::new().unwrap();
-    poll.register(&sockfd, SOCK, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
-    poll.register(&tunfd, TUN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+/// Backend that issued `token`, for servers configured with a backend id.
+pub fn token_backend(token: Token) -> u8 {
+    (token >> 56) as u8
+}
 
-    let mut events = mio::Events::with_capacity(1024);
+/// Wraps a packet from the TUN device for the peer. With snappy agreed it is
+/// compressed, unless it is shorter than `threshold` and the peer takes
+/// `RawData`. Also returns whether it was compressed.
+pub fn data_message(encoder: &mut Encoder,
+                    id: Id,
+                    token: Token,
+                    caps: &Capabilities,
+                    threshold: usize,
+                    data: &[u8])
+                    -> Result<(Message, bool)> {
+    if !caps.has(CAP_SNAPPY) {
+        return Ok((Message::Data {
+            id: id,
+            token: token,
+            data: data.to_vec(),
+        },
+                   false));
+    }
+    if data.len() < threshold && caps.has(CAP_RAW_DATA) {
+        return Ok((Message::RawData {
+            id: id,
+            token: token,
+            data: data.to_vec(),
+        },
+                   false));
+    }
+    let compressed = try!(compress(encoder, data));
+    Ok((Message::Data {
+        id: id,
+        token: token,
+        data: compressed,
+    },
+        true))
+}
 
-    let mut rng = thread_rng();
-    let mut available_ids: Vec<Id> = (2..254).collect();
-    let mut client_info: TransientHashMap<Id, (Token, SocketAddr)> = TransientHashMap::new(60);
+/// Wraps stream traffic for the peer.
+pub fn stream_message(id: Id, token: Token, out: stream::Outgoing) -> Message {
+    match out {
+        stream::Outgoing::Open(stream, target) => {
+            Message::StreamOpen {
+                id: id,
+                token: token,
+                stream: stream,
+                target: target,
+            }
+        }
+        stream::Outgoing::Reply(stream, error) => {
+            Message::StreamReply {
+                id: id,
+                token: token,
+                stream: stream,
+                error: error,
+            }
+        }
+        stream::Outgoing::Segment(segment) => {
+            Message::Segment {
+                id: id,
+                token: token,
+                segment: segment,
+            }
+        }
+        stream::Outgoing::Close(stream) => {
+            Message::StreamClose {
+                id: id,
+                token: token,
+                stream: stream,
+            }
+        }
+    }
+}
 
-    let mut buf = [0u8; 1600];
-    let mut encoder = snap::Encoder::new();
-    let mut decoder = snap::Decoder::new();
-    info!("Ready for transmission.");
+#[cfg(feature = "compression")]
+fn compress(encoder: &mut Encoder, data: &[u8]) -> Result<Vec<u8>> {
+    encoder.compress_vec(data).map_err(|e| Error::Decode(e.to_string()))
+}
 
-    loop {
-        if INTERRUPTED.load(Ordering::Relaxed) {
-            break;
-        }
+#[cfg(not(feature = "compression"))]
+fn compress(_: &mut Encoder, _: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Decode(String::from("built without compression")))
+}
 
-        // Clear expired client info
-        available_ids.append(&mut client_info.prune());
-
-        poll.poll(&mut events, None).unwrap();
-
-        for event in events.iter() {
-            match event.token() {
-                SOCK => {
-                    let (len, addr) = sockfd.recv_from(&mut buf).unwrap().unwrap();
-                    let msg: Message = decode(&buf[0..len]).unwrap();
-                    match msg {
-                        Message::Request => {
-                            let client_id: Id = available_ids.pop().unwrap();
-                            let client_token: Token = rng.gen::<Token>();
-
-                            client_info.insert(client_id, (client_token, addr));
-
-                            info!("Got request from {}. Assigning IP address: 10.10.10.{}.",
-                                  addr,
-                                  client_id);
-
-                            let reply = Message::Response {
-                                id: client_id,
-                                token: client_token,
-                            };
-                            let encoded_reply = encode(&reply, Infinite).unwrap();
-                            let data_len = encoded_reply.len();
-                            let mut sent_len = 0;
-                            while sent_len < data_len {
-                                sent_len +=
-                                    sockfd.send_to(&encoded_reply[sent_len..data_len], &addr)
-                                        .unwrap()
-                                        .unwrap();
-                            }
-                        }
-                        Message::Response { id: _, token: _ } => {
-                            warn!("Invalid message {:?} from {}", msg, addr)
-                        }
-                        Message::Data { id, token, data } => {
-                            match client_info.get(&id) {
-                                None => warn!("Unknown data with token {} from id {}.", token, id),
-                                Some(&(t, _)) => {
-                                    if t != token {
-                                        warn!("Unknown data with mismatched token {} from id {}. \
-                                               Expected: {}",
-                                              token,
-                                              id,
-                                              t);
-                                    } else {
-                                        let decompressed_data = decoder.decompress_vec(&data)
-                                            .unwrap();
-                                        let data_len = decompressed_data.len();
-                                        let mut sent_len = 0;
-                                        while sent_len < data_len {
-                                            sent_len +=
-                                                tun.write(&decompressed_data[sent_len..data_len])
-                                                    .unwrap();
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                TUN => {
-                    let len: usize = tun.read(&mut buf).unwrap();
-                    let data = &buf[0..len];
-                    let client_id: u8 = data[19];
-
-                    match client_info.get(&client_id) {
-                        None => warn!("Unknown IP packet from TUN for client {}.", client_id),
-                        Some(&(token, addr)) => {
-                            let msg = Message::Data {
-                                id: client_id,
-                                token: token,
-                                data: encoder.compress_vec(data).unwrap(),
-                            };
-                            let encoded_msg = encode(&msg, Infinite).unwrap();
-                            sockfd.send_to(&encoded_msg, &addr).unwrap().unwrap();
-                        }
-                    }
-                }
-                _ => unreachable!(),
-            }
+/// Decompresses the payload of a data frame. Performs no I/O.
+#[cfg(feature = "compression")]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    snap::Decoder::new().decompress_vec(data).map_err(|e| Error::Decode(e.to_string()))
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Decode(String::from("built without compression")))
+}
+
+/// Parses a datagram and, for data frames, decompresses the payload, which is
+/// everything a peer does with untrusted input before touching the TUN device.
+pub fn decode_frame(buf: &[u8]) -> Result<Message> {
+    match try!(decode_message(buf)) {
+        Message::Data { id, token, data } => {
+            Ok(Message::Data {
+                id: id,
+                token: token,
+                data: try!(decompress(&data)),
+            })
         }
+        msg => Ok(msg),
+    }
+}
+
+pub fn remaining(deadline: Instant, now: Instant) -> Duration {
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
     }
 }
 
@@ -329,7 +868,199 @@ fn resolve_test() {
                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
 }
 
+#[test]
+fn flag_names_test() {
+    assert_eq!(Capabilities::new(0).flag_names(), "none");
+    let caps = Capabilities::new(CAP_SNAPPY | CAP_KEEPALIVE | CAP_STREAMS | 1 << 31);
+    assert_eq!(caps.flag_names(), "snappy,keepalive,streams");
+    assert_eq!(caps.compression(), "snappy");
+}
+
+#[test]
+fn route_id_test() {
+    let mut data = [0u8; 20];
+    data[0] = 0x45;
+    data[16..20].clone_from_slice(&[10, 10, 10, 42]);
+    let subnet = device::Subnet::default();
+    assert_eq!(route_id(&subnet, &data).unwrap(), 42);
+    data[16..20].clone_from_slice(&[192, 168, 1, 42]);
+    assert!(route_id(&subnet, &data).is_err());
+    assert_eq!(route_id(&"192.168.1.0/24".parse().unwrap(), &data).unwrap(), 42);
+
+    let mut data = [0u8; 40];
+    data[0] = 0x60;
+    data[24..40].clone_from_slice(&"fd10:10:10::2a".parse::<Ipv6Addr>().unwrap().octets());
+    assert_eq!(route_id(&subnet, &data).unwrap(), 42);
+    data[24..40].clone_from_slice(&"2001:db8::2a".parse::<Ipv6Addr>().unwrap().octets());
+    assert!(route_id(&subnet, &data).is_err());
+}
+
+#[test]
+fn is_broadcast_test() {
+    let mut data = [0u8; 20];
+    data[0] = 0x45;
+    data[16..20].clone_from_slice(&[10, 10, 10, 255]);
+    let subnet = device::Subnet::default();
+    assert!(is_broadcast(&subnet, &data));
+    data[16..20].clone_from_slice(&[224, 0, 0, 251]);
+    assert!(is_broadcast(&subnet, &data));
+    data[16..20].clone_from_slice(&[10, 10, 10, 42]);
+    assert!(!is_broadcast(&subnet, &data));
+
+    let mut data = [0u8; 40];
+    data[0] = 0x60;
+    data[24..40].clone_from_slice(&"ff02::fb".parse::<Ipv6Addr>().unwrap().octets());
+    assert!(is_broadcast(&subnet, &data));
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn frame_capacity_test() {
+    // Incompressible input is the worst case for snappy.
+    let mut x: u32 = 1;
+    let payload: Vec<u8> = (0..1380)
+        .map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        })
+        .collect();
+    let msg = Message::Data {
+        id: 254,
+        token: !0,
+        data: snap::Encoder::new().compress_vec(&payload).unwrap(),
+    };
+    assert!(encode(&msg, Infinite).unwrap().len() <= frame_capacity(1380));
+}
+
+#[test]
+fn capabilities_test() {
+    let mut client = Capabilities::new(CAP_SNAPPY);
+    client.mtu = 1280;
+    let server = Capabilities::new(0);
+    let agreed = server.negotiate(&client);
+    assert!(!agreed.has(CAP_SNAPPY));
+    assert_eq!(agreed.mtu, 1280);
+    assert_eq!(agreed.version, PROTOCOL_VERSION);
+    assert!(Capabilities::new(CAP_SNAPPY).negotiate(&client).has(CAP_SNAPPY));
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn decode_frame_test() {
+    let payload = vec![0x45u8; 100];
+    let msg = Message::Data {
+        id: 2,
+        token: 42,
+        data: snap::Encoder::new().compress_vec(&payload).unwrap(),
+    };
+    let buf = encode(&msg, Infinite).unwrap();
+    assert_eq!(decode_frame(&buf).unwrap(),
+               Message::Data {
+                   id: 2,
+                   token: 42,
+                   data: payload,
+               });
+
+    let req = Message::Request {
+        caps: Capabilities::new(CAP_SNAPPY | CAP_KEEPALIVE),
+        resume: Some((2, 42)),
+        credential: Some(String::from("secret")),
+        roam_key: vec![7; 32],
+    };
+    let buf = encode(&req, Infinite).unwrap();
+    assert_eq!(decode_frame(&buf).unwrap(), req);
+
+    assert!(decode_frame(&[]).is_err());
+    assert!(decode_frame(&[0xff; 16]).is_err());
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn data_message_test() {
+    let mut encoder = snap::Encoder::new();
+    let caps = Capabilities::new(CAP_SNAPPY | CAP_RAW_DATA);
+    let payload = vec![0u8; 100];
+    match data_message(&mut encoder, 2, 7, &caps, 64, &payload[0..40]).unwrap() {
+        (Message::RawData { data, .. }, false) => assert_eq!(data.len(), 40),
+        msg => panic!("unexpected {:?}", msg),
+    }
+    match data_message(&mut encoder, 2, 7, &caps, 64, &payload).unwrap() {
+        (Message::Data { data, .. }, true) => assert_eq!(decompress(&data).unwrap(), payload),
+        msg => panic!("unexpected {:?}", msg),
+    }
+    let old = Capabilities::new(CAP_SNAPPY);
+    match data_message(&mut encoder, 2, 7, &old, 64, &payload[0..40]).unwrap() {
+        (Message::Data { .. }, true) => {}
+        msg => panic!("unexpected {:?}", msg),
+    }
+}
+
+#[test]
+fn session_mtu_test() {
+    assert_eq!(session_mtu(1500, 1380, None), 1380);
+    assert_eq!(session_mtu(1500, 1380, Some(1300)), 1300);
+    // Lowering the TUN MTU and raising it again restores the offer.
+    assert_eq!(session_mtu(1500, 1280, None), 1280);
+    assert_eq!(session_mtu(1500, 1500, None), 1500);
+    assert_eq!(session_mtu(1400, 1500, None), 1400);
+    assert_eq!(session_mtu(1500, 1500, Some(1300)), 1300);
+}
+
+#[test]
+fn session_token_test() {
+    let data = encode_message(&Message::Data {
+            id: 2,
+            token: 0x0123456789abcdef,
+            data: vec![1, 2, 3],
+        })
+        .unwrap();
+    assert_eq!(session_token(&data), Some(0x0123456789abcdef));
+    assert_eq!(token_backend(0x0123456789abcdef), 0x01);
+    let probe = encode_message(&Message::Probe { nonce: 1 }).unwrap();
+    assert_eq!(session_token(&probe), None);
+    let raw = encode_message(&Message::RawData {
+            id: 2,
+            token: 7,
+            data: vec![],
+        })
+        .unwrap();
+    assert_eq!(session_token(&raw), Some(7));
+    let assigned = encode_message(&Message::Assigned {
+            id: 2,
+            token: 8,
+            caps: Capabilities::new(0),
+            subnet: device::Subnet([10, 20, 30]),
+            roam_key: Vec::new(),
+        })
+        .unwrap();
+    assert_eq!(session_token(&assigned), Some(8));
+    let configured = encode_message(&Message::Configured {
+            id: 2,
+            token: 9,
+            caps: Capabilities::new(0),
+            options: vec![push::PushOption::Mtu(1280).encode()],
+            roam_key: Vec::new(),
+        })
+        .unwrap();
+    assert_eq!(session_token(&configured), Some(9));
+    let batch = encode_message(&Message::Batch {
+            id: 2,
+            token: 10,
+            packets: vec![vec![1], vec![2, 3]],
+        })
+        .unwrap();
+    assert_eq!(session_token(&batch), Some(10));
+    let close = encode_message(&Message::StreamClose {
+            id: 2,
+            token: 11,
+            stream: 3,
+        })
+        .unwrap();
+    assert_eq!(session_token(&close), Some(11));
+    assert_eq!(session_token(&data[0..12]), None);
+}
+
 #[test]
 fn create_tun_attempt_test() {
-    create_tun_attempt();
+    create_tun_attempt().unwrap();
 }
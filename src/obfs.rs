@@ -0,0 +1,92 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Obfuscation of the handshake's size and timing.
+//!
+//! Fixed-size requests sent at fixed intervals are easy for middleboxes to
+//! classify. When enabled, the client appends random padding to each
+//! `Request`, which decoders ignore as trailing bytes, waits a random extra
+//! time between attempts, and may send a few random decoy datagrams first.
+//! The server pads its `Response` whenever the request was padded.
+//!
+//! This only blurs the fingerprint; the frames themselves are not hidden.
+
+use std::time::Duration;
+use rand::{self, Rng};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Up to this many random bytes are appended to handshake frames.
+    pub max_padding: usize,
+    /// Up to this much extra time is waited before each attempt.
+    pub max_delay: Duration,
+    /// Random datagrams sent ahead of each request.
+    pub decoys: u32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_padding: 256,
+            max_delay: Duration::from_millis(500),
+            decoys: 0,
+        }
+    }
+}
+
+/// Appends up to `max` random bytes to an encoded frame.
+pub fn pad(frame: &mut Vec<u8>, max: usize) {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0, max + 1);
+    let start = frame.len();
+    frame.resize(start + len, 0);
+    rng.fill_bytes(&mut frame[start..]);
+}
+
+/// A random delay of at most `max`.
+pub fn delay(max: Duration) -> Duration {
+    let max_ms = max.as_secs() * 1000 + max.subsec_nanos() as u64 / 1000000;
+    Duration::from_millis(rand::thread_rng().gen_range(0, max_ms + 1))
+}
+
+/// A datagram of random length and content that no peer decodes: its
+/// first byte makes an impossible message variant.
+pub fn decoy(max_len: usize) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut decoy = vec![0u8; rng.gen_range(16, max_len + 17)];
+    rng.fill_bytes(&mut decoy);
+    decoy[0] |= 0x80;
+    decoy
+}
+
+#[test]
+fn obfs_test() {
+    use network::{decode_message, encode_message, Capabilities, Message};
+    let req = Message::Request {
+        caps: Capabilities::new(0),
+        resume: None,
+        credential: None,
+        roam_key: Vec::new(),
+    };
+    let mut frame = encode_message(&req).unwrap();
+    let len = frame.len();
+    pad(&mut frame, 64);
+    assert!(frame.len() >= len && frame.len() <= len + 64);
+    assert_eq!(decode_message(&frame).unwrap(), req);
+    for _ in 0..16 {
+        assert!(decode_message(&decoy(64)).is_err());
+    }
+    assert!(delay(Duration::from_millis(10)) <= Duration::from_millis(10));
+}
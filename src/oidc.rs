@@ -0,0 +1,278 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Single sign-on with OpenID Connect.
+//!
+//! The client logs in with the OAuth 2.0 device authorization grant
+//! (RFC 8628): it prints a URL and a code, the user approves the login in a
+//! browser, and the client receives an access token. It presents the token
+//! as the credential `oidc:TOKEN`. The server asks the issuer's
+//! introspection endpoint (RFC 7662) about the token, authenticating as its
+//! own OAuth client, and admits the client only if the token is active, was
+//! issued for that client and, if an allow-list is configured, names an
+//! allowed subject or group. Tokens the issuer gave to other applications
+//! are refused.
+//!
+//! HTTPS requests are made with `curl`. Access tokens are short-lived, so
+//! reconnecting after the token expired needs a new login.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use rustc_serialize::json::Json;
+use auth::Authenticator;
+use error::{Error, Result};
+
+/// User name of credentials carrying an access token.
+pub const USER: &'static str = "oidc";
+
+/// Characters that need no percent-encoding in form values.
+fn unreserved(b: u8) -> bool {
+    (b as char).is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_' || b == b'~'
+}
+
+fn form(fields: &[(&str, &str)]) -> String {
+    let encode = |s: &str| {
+        s.bytes()
+            .map(|b| if unreserved(b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            })
+            .collect::<String>()
+    };
+    fields.iter()
+        .map(|&(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Runs curl with `args` and parses its output as JSON. With `fail`, HTTP
+/// errors are errors; without, their JSON bodies are returned. `stdin` is
+/// for secrets, which would be visible to other users as arguments.
+fn curl(args: &[&str], fail: bool, stdin: &str) -> Result<Json> {
+    let mut command = Command::new("curl");
+    command.args(&["-sS", "--max-time", "10"]);
+    if fail {
+        command.arg("--fail");
+    }
+    let mut child = try!(command.args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn());
+    try!(child.stdin.take().unwrap().write_all(stdin.as_bytes()));
+    let output = try!(child.wait_with_output());
+    if !output.status.success() {
+        return Err(Error::Handshake(format!("{}: {}",
+                                            args.last().unwrap_or(&""),
+                                            String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Json::from_str(&String::from_utf8_lossy(&output.stdout))
+        .map_err(|e| Error::Decode(format!("{}: {}", args.last().unwrap_or(&""), e)))
+}
+
+fn string(json: &Json, key: &str) -> Result<String> {
+    json.find(key)
+        .and_then(|value| value.as_string())
+        .map(String::from)
+        .ok_or_else(|| Error::Decode(format!("missing {} in {}", key, json)))
+}
+
+/// Looks up the issuer's endpoints.
+fn discover(issuer: &str) -> Result<Json> {
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_right_matches('/'));
+    curl(&[&url], true, "")
+}
+
+/// Logs in with the device flow and returns the credential to present,
+/// printing the instructions for the user to stderr.
+pub fn login(issuer: &str, client_id: &str) -> Result<String> {
+    let metadata = try!(discover(issuer));
+    let device_endpoint = try!(string(&metadata, "device_authorization_endpoint"));
+    let token_endpoint = try!(string(&metadata, "token_endpoint"));
+
+    let body = form(&[("client_id", client_id), ("scope", "openid")]);
+    let device = try!(curl(&["-d", "@-", &device_endpoint], true, &body));
+    let device_code = try!(string(&device, "device_code"));
+    let user_code = try!(string(&device, "user_code"));
+    match string(&device, "verification_uri_complete") {
+        Ok(uri) => eprintln!("To log in, visit {}", uri),
+        Err(_) => {
+            let uri = try!(string(&device, "verification_uri"));
+            eprintln!("To log in, visit {} and enter the code {}", uri, user_code);
+        }
+    }
+    let mut interval = device.find("interval").and_then(|i| i.as_u64()).unwrap_or(5);
+    let expires_in = device.find("expires_in").and_then(|i| i.as_u64()).unwrap_or(600);
+    let deadline = Instant::now() + Duration::from_secs(expires_in);
+
+    let body = form(&[("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                      ("device_code", &device_code),
+                      ("client_id", client_id)]);
+    while Instant::now() < deadline {
+        thread::sleep(Duration::from_secs(interval));
+        let answer = try!(curl(&["-d", "@-", &token_endpoint], false, &body));
+        if let Ok(token) = string(&answer, "access_token") {
+            info!("Logged in with {}.", issuer);
+            return Ok(format!("{}:{}", USER, token));
+        }
+        match string(&answer, "error").as_ref().map(|e| e.as_str()) {
+            Ok("authorization_pending") => {}
+            Ok("slow_down") => interval += 5,
+            Ok(error) => return Err(Error::Handshake(format!("login failed: {}", error))),
+            Err(e) => return Err(Error::Handshake(format!("login failed: {}", e))),
+        }
+    }
+    Err(Error::Handshake(String::from("login timed out")))
+}
+
+pub struct Config {
+    pub issuer: String,
+    /// The OAuth client tokens must be issued for, which the server also
+    /// introspects them as.
+    pub client_id: String,
+    pub client_secret: String,
+    /// Subjects admitted. With no subjects and no groups, anyone the issuer
+    /// vouches for is.
+    pub subjects: Vec<String>,
+    /// Groups, from the `groups` claim, whose members are admitted.
+    pub groups: Vec<String>,
+}
+
+impl Config {
+    pub fn new(issuer: &str, client_id: &str, client_secret: &str) -> Config {
+        Config {
+            issuer: String::from(issuer),
+            client_id: String::from(client_id),
+            client_secret: String::from(client_secret),
+            subjects: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+/// Checks access tokens with an issuer's introspection endpoint.
+pub struct Oidc {
+    config: Config,
+    introspection_endpoint: String,
+}
+
+impl Oidc {
+    pub fn new(config: Config) -> Result<Oidc> {
+        let metadata = try!(discover(&config.issuer));
+        let endpoint = try!(string(&metadata, "introspection_endpoint"));
+        Ok(Oidc {
+            config: config,
+            introspection_endpoint: endpoint,
+        })
+    }
+
+    /// Why the introspected token `claims` is refused, if it is.
+    fn refusal(&self, claims: &Json) -> Option<&'static str> {
+        if claims.find("active").and_then(|active| active.as_boolean()) != Some(true) {
+            return Some("inactive");
+        }
+        let client_id = &self.config.client_id[..];
+        let audience = match claims.find("aud") {
+            Some(&Json::String(ref aud)) => aud == client_id,
+            Some(&Json::Array(ref auds)) => {
+                auds.iter().any(|aud| aud.as_string() == Some(client_id))
+            }
+            _ => false,
+        };
+        if !audience && claims.find("client_id").and_then(|id| id.as_string()) != Some(client_id) {
+            return Some("issued for another client");
+        }
+        if self.config.subjects.is_empty() && self.config.groups.is_empty() {
+            return None;
+        }
+        let subject = claims.find("sub").and_then(|sub| sub.as_string());
+        if subject.map_or(false, |sub| self.config.subjects.iter().any(|allowed| allowed == sub)) {
+            return None;
+        }
+        let groups = claims.find("groups").and_then(|groups| groups.as_array());
+        let member = groups.map_or(false, |groups| {
+            groups.iter()
+                .filter_map(|group| group.as_string())
+                .any(|group| self.config.groups.iter().any(|allowed| allowed == group))
+        });
+        if member { None } else { Some("not allowed") }
+    }
+}
+
+impl Authenticator for Oidc {
+    fn authenticate(&mut self, user: &str, token: &str) -> Result<bool> {
+        if user != USER || token.is_empty() {
+            return Ok(false);
+        }
+        let body = form(&[("token", token),
+                          ("token_type_hint", "access_token"),
+                          ("client_id", &self.config.client_id),
+                          ("client_secret", &self.config.client_secret)]);
+        let claims = match curl(&["-d", "@-", &self.introspection_endpoint], true, &body) {
+            Ok(claims) => claims,
+            Err(e) => {
+                debug!("Token rejected: {}", e);
+                return Ok(false);
+            }
+        };
+        match self.refusal(&claims) {
+            None => {
+                info!("Admitting {}.", string(&claims, "sub").unwrap_or_default());
+                Ok(true)
+            }
+            Some(why) => {
+                debug!("Token of {} rejected: {}.",
+                       string(&claims, "sub").unwrap_or_default(),
+                       why);
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[test]
+fn refusal_test() {
+    let mut oidc = Oidc {
+        config: Config::new("https://id.example", "kytan", "s3cret"),
+        introspection_endpoint: String::new(),
+    };
+    let refusal = |oidc: &Oidc, json: &str| oidc.refusal(&Json::from_str(json).unwrap());
+    assert_eq!(refusal(&oidc, r#"{"active": true, "aud": "kytan"}"#), None);
+    assert_eq!(refusal(&oidc, r#"{"active": true, "aud": ["mail", "kytan"]}"#), None);
+    assert_eq!(refusal(&oidc, r#"{"active": true, "client_id": "kytan"}"#), None);
+    assert_eq!(refusal(&oidc, r#"{"active": false, "aud": "kytan"}"#), Some("inactive"));
+    assert_eq!(refusal(&oidc, r#"{"aud": "kytan"}"#), Some("inactive"));
+    // A token the issuer gave to another application.
+    assert_eq!(refusal(&oidc, r#"{"active": true, "aud": "mail", "client_id": "mail"}"#),
+               Some("issued for another client"));
+
+    oidc.config.subjects.push(String::from("alice"));
+    oidc.config.groups.push(String::from("vpn"));
+    assert_eq!(refusal(&oidc, r#"{"active": true, "aud": "kytan", "sub": "alice"}"#), None);
+    let member = r#"{"active": true, "aud": "kytan", "sub": "bob", "groups": ["vpn"]}"#;
+    assert_eq!(refusal(&oidc, member), None);
+    let stranger = r#"{"active": true, "aud": "kytan", "sub": "bob", "groups": ["staff"]}"#;
+    assert_eq!(refusal(&oidc, stranger), Some("not allowed"));
+    assert_eq!(refusal(&oidc, r#"{"active": true, "aud": "kytan"}"#), Some("not allowed"));
+}
+
+#[test]
+fn form_test() {
+    assert_eq!(form(&[("client_id", "kytan"), ("scope", "openid profile")]),
+               "client_id=kytan&scope=openid%20profile");
+    assert_eq!(form(&[("grant_type", "urn:x")]), "grant_type=urn%3Ax");
+}
@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::mem;
 use std::num::Wrapping;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use error::{Error, Result};
 
 #[repr(packed)]
 pub struct Ipv4Header {
@@ -29,6 +32,8 @@ pub struct Ipv4Header {
     pub destination_address: u32, // Destination Address
 }
 
+pub const IPV6_HEADER_LEN: usize = 40;
+
 #[repr(packed)]
 pub struct UdpHeader {
     pub source_port: u16,
@@ -59,6 +64,431 @@ pub struct IcmpHeader {
     pub icmp_seq_num: u16,
 }
 
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+pub fn ip_version(data: &[u8]) -> Option<u8> {
+    data.first().map(|b| b >> 4)
+}
+
+pub fn ipv4_destination(data: &[u8]) -> Result<Ipv4Addr> {
+    if data.len() < mem::size_of::<Ipv4Header>() {
+        return Err(Error::Decode(format!("truncated IPv4 header ({} bytes)", data.len())));
+    }
+    match ip_version(data) {
+        Some(4) => {}
+        Some(v) => return Err(Error::Decode(format!("unexpected IP version {}", v))),
+        None => unreachable!(),
+    }
+    let ihl = ((data[0] & 0xf) as usize) * 4;
+    if ihl < mem::size_of::<Ipv4Header>() || data.len() < ihl {
+        return Err(Error::Decode(format!("invalid IPv4 header length {}", ihl)));
+    }
+    Ok(Ipv4Addr::new(data[16], data[17], data[18], data[19]))
+}
+
+pub fn ipv6_destination(data: &[u8]) -> Result<Ipv6Addr> {
+    if data.len() < IPV6_HEADER_LEN {
+        return Err(Error::Decode(format!("truncated IPv6 header ({} bytes)", data.len())));
+    }
+    match ip_version(data) {
+        Some(6) => {}
+        Some(v) => return Err(Error::Decode(format!("unexpected IP version {}", v))),
+        None => unreachable!(),
+    }
+    let mut octets = [0u8; 16];
+    octets.clone_from_slice(&data[24..40]);
+    Ok(Ipv6Addr::from(octets))
+}
+
+pub fn destination(data: &[u8]) -> Result<IpAddr> {
+    match ip_version(data) {
+        Some(4) => ipv4_destination(data).map(IpAddr::V4),
+        Some(6) => ipv6_destination(data).map(IpAddr::V6),
+        Some(v) => Err(Error::Decode(format!("unexpected IP version {}", v))),
+        None => Err(Error::Decode(String::from("empty packet"))),
+    }
+}
+
+/// Returns the transport protocol of a packet and, for TCP and UDP, its
+/// destination port. Non-initial IPv4 fragments have no port.
+pub fn transport(data: &[u8]) -> Result<(u8, Option<u16>)> {
+    let (protocol, offset) = try!(transport_offset(data));
+    Ok((protocol, offset.map(|offset| read_u16(data, offset + 2))))
+}
+
+/// Returns the transport protocol of a packet and, for TCP and UDP, its
+/// source and destination ports.
+pub fn ports(data: &[u8]) -> Result<(u8, Option<(u16, u16)>)> {
+    let (protocol, offset) = try!(transport_offset(data));
+    Ok((protocol, offset.map(|offset| (read_u16(data, offset), read_u16(data, offset + 2)))))
+}
+
+// The protocol and, if the packet carries TCP or UDP ports, where the
+// transport header starts.
+fn transport_offset(data: &[u8]) -> Result<(u8, Option<usize>)> {
+    let (protocol, offset, first_fragment) = match ip_version(data) {
+        Some(4) => {
+            try!(ipv4_destination(data));
+            (data[9], ((data[0] & 0xf) as usize) * 4, read_u16(data, 6) & 0x1fff == 0)
+        }
+        Some(6) => {
+            try!(ipv6_destination(data));
+            (data[6], IPV6_HEADER_LEN, true)
+        }
+        Some(v) => return Err(Error::Decode(format!("unexpected IP version {}", v))),
+        None => return Err(Error::Decode(String::from("empty packet"))),
+    };
+    let offset = match protocol {
+        IPPROTO_TCP | IPPROTO_UDP if first_fragment && data.len() >= offset + 4 => Some(offset),
+        _ => None,
+    };
+    Ok((protocol, offset))
+}
+
+/// The DSCP bits of the packet's traffic class, without ECN.
+pub fn dscp(data: &[u8]) -> Option<u8> {
+    match ip_version(data) {
+        Some(4) if data.len() >= 2 => Some(data[1] >> 2),
+        Some(6) if data.len() >= 2 => Some((((data[0] & 0xf) << 4) | (data[1] >> 4)) >> 2),
+        _ => None,
+    }
+}
+
+pub fn source(data: &[u8]) -> Result<IpAddr> {
+    try!(destination(data));
+    match ip_version(data) {
+        Some(4) => Ok(IpAddr::V4(Ipv4Addr::new(data[12], data[13], data[14], data[15]))),
+        _ => {
+            let mut octets = [0u8; 16];
+            octets.clone_from_slice(&data[8..24]);
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+    }
+}
+
+/// Splits an unfragmented UDP packet into its source, destination and
+/// payload.
+pub fn parse_udp(data: &[u8]) -> Result<(SocketAddr, SocketAddr, &[u8])> {
+    let (protocol, port) = try!(transport(data));
+    if protocol != IPPROTO_UDP || port.is_none() {
+        return Err(Error::Decode(format!("not a UDP packet (protocol {})", protocol)));
+    }
+    let (offset, end) = match ip_version(data) {
+        Some(4) => {
+            if read_u16(data, 6) & 0x3fff != 0 {
+                return Err(Error::Decode(String::from("fragmented UDP packet")));
+            }
+            (((data[0] & 0xf) as usize) * 4, read_u16(data, 2) as usize)
+        }
+        _ => (IPV6_HEADER_LEN, IPV6_HEADER_LEN + read_u16(data, 4) as usize),
+    };
+    if data.len() < offset + mem::size_of::<UdpHeader>() || end > data.len() || end < offset {
+        return Err(Error::Decode(format!("truncated UDP packet ({} bytes)", data.len())));
+    }
+    let udp_len = read_u16(data, offset + 4) as usize;
+    if udp_len < mem::size_of::<UdpHeader>() || offset + udp_len > end {
+        return Err(Error::Decode(format!("invalid UDP length {}", udp_len)));
+    }
+    let src = SocketAddr::new(try!(source(data)), read_u16(data, offset));
+    let dst = SocketAddr::new(try!(destination(data)), read_u16(data, offset + 2));
+    Ok((src, dst, &data[offset + mem::size_of::<UdpHeader>()..offset + udp_len]))
+}
+
+/// Internet checksum of `bytes`, as used by IPv4, UDP and TCP.
+pub fn inet_cksum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in bytes.chunks(2) {
+        let word = if chunk.len() == 2 {
+            ((chunk[0] as u32) << 8) | chunk[1] as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+        sum += word;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Wraps `payload` in an IPv4 (or IPv6) and UDP header addressed from `src`
+/// to `dst`.
+pub fn build_udp(src: &SocketAddr, dst: &SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut packet = Vec::with_capacity(40 + udp_len);
+    let mut pseudo = Vec::with_capacity(40);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let total_len = 20 + udp_len;
+            packet.extend_from_slice(&[0x45, 0, (total_len >> 8) as u8, total_len as u8]);
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let cksum = inet_cksum(&packet);
+            write_u16(&mut packet, 10, cksum);
+            pseudo.extend_from_slice(&packet[12..20]);
+            pseudo.extend_from_slice(&[0, IPPROTO_UDP]);
+        }
+        (s, d) => {
+            packet.extend_from_slice(&[0x60,
+                                       0,
+                                       0,
+                                       0,
+                                       (udp_len >> 8) as u8,
+                                       udp_len as u8,
+                                       IPPROTO_UDP,
+                                       64]);
+            packet.extend_from_slice(&to_ipv6(s).octets());
+            packet.extend_from_slice(&to_ipv6(d).octets());
+            pseudo.extend_from_slice(&packet[8..40]);
+            pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+        }
+    }
+    let header = packet.len();
+    pseudo.extend_from_slice(&[(udp_len >> 8) as u8, udp_len as u8]);
+    packet.extend_from_slice(&[(src.port() >> 8) as u8,
+                               src.port() as u8,
+                               (dst.port() >> 8) as u8,
+                               dst.port() as u8,
+                               (udp_len >> 8) as u8,
+                               udp_len as u8,
+                               0,
+                               0]);
+    packet.extend_from_slice(payload);
+    pseudo.extend_from_slice(&packet[header..]);
+    let cksum = match inet_cksum(&pseudo) {
+        0 => 0xffff,
+        c => c,
+    };
+    write_u16(&mut packet, header + 6, cksum);
+    packet
+}
+
+/// Minimum IPv6 MTU, which bounds the size of ICMPv6 error messages.
+const IPV6_MIN_MTU: usize = 1280;
+
+/// Builds the ICMP "fragmentation needed" (IPv4, only for packets with DF
+/// set) or ICMPv6 "packet too big" error telling the sender of `data` to stay
+/// within `mtu`. `src` is the address the error is sent from. ICMP errors
+/// never trigger another error.
+pub fn too_big(data: &[u8], mtu: u16, src: IpAddr) -> Option<Vec<u8>> {
+    let sender = match source(data) {
+        Ok(sender) => sender,
+        Err(_) => return None,
+    };
+    match (sender, src) {
+        (IpAddr::V4(sender), IpAddr::V4(src)) => {
+            let ihl = ((data[0] & 0xf) as usize) * 4;
+            let is_error = data[9] == IPPROTO_ICMP && data.len() > ihl &&
+                           data[ihl] != 0 && data[ihl] != 8;
+            if read_u16(data, 6) & 0x4000 == 0 || is_error {
+                return None;
+            }
+            let quoted = &data[0..cmp::min(data.len(), ihl + 8)];
+            let total_len = 20 + 8 + quoted.len();
+            let mut packet = Vec::with_capacity(total_len);
+            packet.extend_from_slice(&[0x45, 0, (total_len >> 8) as u8, total_len as u8]);
+            packet.extend_from_slice(&[0, 0, 0, 0, 64, IPPROTO_ICMP, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&sender.octets());
+            let cksum = inet_cksum(&packet);
+            write_u16(&mut packet, 10, cksum);
+            packet.extend_from_slice(&[3, 4, 0, 0, 0, 0, (mtu >> 8) as u8, mtu as u8]);
+            packet.extend_from_slice(quoted);
+            let cksum = inet_cksum(&packet[20..]);
+            write_u16(&mut packet, 22, cksum);
+            Some(packet)
+        }
+        (IpAddr::V6(sender), IpAddr::V6(src)) => {
+            let is_error = data[6] == IPPROTO_ICMPV6 && data.len() > IPV6_HEADER_LEN &&
+                           data[IPV6_HEADER_LEN] < 128;
+            if is_error {
+                return None;
+            }
+            let quoted = &data[0..cmp::min(data.len(), IPV6_MIN_MTU - IPV6_HEADER_LEN - 8)];
+            let icmp_len = 8 + quoted.len();
+            let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + icmp_len);
+            packet.extend_from_slice(&[0x60,
+                                       0,
+                                       0,
+                                       0,
+                                       (icmp_len >> 8) as u8,
+                                       icmp_len as u8,
+                                       IPPROTO_ICMPV6,
+                                       64]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&sender.octets());
+            packet.extend_from_slice(&[2, 0, 0, 0, 0, 0, (mtu >> 8) as u8, mtu as u8]);
+            packet.extend_from_slice(quoted);
+            let mut pseudo = Vec::with_capacity(40 + icmp_len);
+            pseudo.extend_from_slice(&packet[8..40]);
+            pseudo.extend_from_slice(&[0, 0, (icmp_len >> 8) as u8, icmp_len as u8]);
+            pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_ICMPV6]);
+            pseudo.extend_from_slice(&packet[IPV6_HEADER_LEN..]);
+            let cksum = inet_cksum(&pseudo);
+            write_u16(&mut packet, IPV6_HEADER_LEN + 2, cksum);
+            Some(packet)
+        }
+        _ => None,
+    }
+}
+
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    ((data[offset] as u16) << 8) | data[offset + 1] as u16
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset] = (value >> 8) as u8;
+    data[offset + 1] = value as u8;
+}
+
+/// Incrementally updates a ones' complement checksum after one 16-bit word
+/// changed from `old` to `new` (RFC 1624).
+fn update_cksum(cksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!cksum as u32) + (!old as u32 & 0xffff) + new as u32;
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+/// Lowers the MSS option of a TCP SYN so that segments fit into a tunnel
+/// with the given MTU. Returns whether the packet was modified.
+pub fn clamp_mss(packet: &mut [u8], mtu: u16) -> bool {
+    let (tcp, max_mss) = match ip_version(packet) {
+        Some(4) => {
+            if packet.len() < mem::size_of::<Ipv4Header>() || packet[9] != IPPROTO_TCP {
+                return false;
+            }
+            // Only the first fragment carries the TCP header.
+            if read_u16(packet, 6) & 0x1fff != 0 {
+                return false;
+            }
+            (((packet[0] & 0xf) as usize) * 4, mtu.saturating_sub(40))
+        }
+        Some(6) => {
+            // Extension headers are rare on SYNs and are left alone.
+            if packet.len() < IPV6_HEADER_LEN || packet[6] != IPPROTO_TCP {
+                return false;
+            }
+            (IPV6_HEADER_LEN, mtu.saturating_sub(60))
+        }
+        _ => return false,
+    };
+    if packet.len() < tcp + mem::size_of::<TcpHeader>() ||
+       packet[tcp + 13] & TCP_FLAG_SYN == 0 {
+        return false;
+    }
+    let tcp_end = tcp + ((packet[tcp + 12] >> 4) as usize) * 4;
+    if tcp_end > packet.len() {
+        return false;
+    }
+
+    let mut opt = tcp + mem::size_of::<TcpHeader>();
+    while opt < tcp_end {
+        match packet[opt] {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => opt += 1,
+            kind => {
+                if opt + 1 >= tcp_end {
+                    break;
+                }
+                let len = packet[opt + 1] as usize;
+                if len < 2 || opt + len > tcp_end {
+                    break;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    let value = opt + 2;
+                    if read_u16(packet, value) <= max_mss {
+                        return false;
+                    }
+                    // The checksum covers 16-bit words aligned to the start of
+                    // the TCP header, which the option may straddle.
+                    let first = tcp + ((value - tcp) & !1);
+                    let words = if (value - tcp) % 2 == 0 {
+                        vec![first]
+                    } else {
+                        vec![first, first + 2]
+                    };
+                    let old: Vec<u16> = words.iter().map(|&w| read_u16(packet, w)).collect();
+                    write_u16(packet, value, max_mss);
+                    let mut cksum = read_u16(packet, tcp + 16);
+                    for (&w, &prev) in words.iter().zip(old.iter()) {
+                        cksum = update_cksum(cksum, prev, read_u16(packet, w));
+                    }
+                    write_u16(packet, tcp + 16, cksum);
+                    return true;
+                }
+                opt += len;
+            }
+        }
+    }
+    false
+}
+
+/// Verifies the IPv4 header checksum and the TCP or UDP checksum of a
+/// packet, returning what is corrupt, if anything. The transport checksum
+/// of fragments and of IPv6 packets with extension headers is not checked.
+pub fn bad_checksum(data: &[u8]) -> Option<&'static str> {
+    let mut pseudo = Vec::with_capacity(40);
+    let (protocol, segment) = match ip_version(data) {
+        Some(4) if data.len() >= 20 => {
+            let ihl = (data[0] & 0xf) as usize * 4;
+            let total = read_u16(data, 2) as usize;
+            if ihl < 20 || total < ihl || total > data.len() {
+                return Some("truncated");
+            }
+            if inet_cksum(&data[..ihl]) != 0 {
+                return Some("IPv4 header");
+            }
+            if read_u16(data, 6) & 0x3fff != 0 {
+                return None;
+            }
+            pseudo.extend_from_slice(&data[12..20]);
+            let len = total - ihl;
+            pseudo.extend_from_slice(&[0, data[9], (len >> 8) as u8, len as u8]);
+            (data[9], &data[ihl..total])
+        }
+        Some(6) if data.len() >= 40 => {
+            let total = 40 + read_u16(data, 4) as usize;
+            if total > data.len() {
+                return Some("truncated");
+            }
+            let len = total - 40;
+            pseudo.extend_from_slice(&data[8..40]);
+            pseudo.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8,
+                                       len as u8, 0, 0, 0, data[6]]);
+            (data[6], &data[40..total])
+        }
+        _ => return Some("truncated"),
+    };
+    let name = match protocol {
+        IPPROTO_TCP if segment.len() < 20 => return Some("truncated"),
+        IPPROTO_UDP if segment.len() < 8 => return Some("truncated"),
+        // A zero UDP checksum means none was computed, which IPv4 allows.
+        IPPROTO_UDP if read_u16(segment, 6) == 0 && data[0] >> 4 == 4 => return None,
+        IPPROTO_TCP => "TCP",
+        IPPROTO_UDP => "UDP",
+        _ => return None,
+    };
+    pseudo.extend_from_slice(segment);
+    if inet_cksum(&pseudo) != 0 { Some(name) } else { None }
+}
+
 fn raw_cksum<T>(buf: *const T, len: usize) -> u16 {
     let mut sum = Wrapping(0);
     let mut remaining_len = len;
@@ -162,3 +592,158 @@ fn udptcp_cksum_test() {
     };
     assert_eq!(udptcp_cksum(&ip, &udp), 0xefff);
 }
+
+#[test]
+fn ipv4_destination_test() {
+    let mut data = [0u8; 24];
+    data[0] = 0x45;
+    data[16..20].clone_from_slice(&[10, 10, 10, 7]);
+    assert_eq!(ipv4_destination(&data).unwrap(), Ipv4Addr::new(10, 10, 10, 7));
+
+    data[0] = 0x46;
+    data[20..24].clone_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(ipv4_destination(&data).unwrap(), Ipv4Addr::new(10, 10, 10, 7));
+    assert!(ipv4_destination(&data[..22]).is_err());
+
+    data[0] = 0x60;
+    assert!(ipv4_destination(&data).is_err());
+    assert!(ipv4_destination(&[]).is_err());
+}
+
+#[test]
+fn destination_test() {
+    let mut data = [0u8; 40];
+    data[0] = 0x60;
+    data[24] = 0xfd;
+    data[39] = 0x2a;
+    let mut octets = [0u8; 16];
+    octets[0] = 0xfd;
+    octets[15] = 0x2a;
+    assert_eq!(destination(&data).unwrap(), IpAddr::V6(Ipv6Addr::from(octets)));
+    assert!(destination(&data[..39]).is_err());
+
+    data[0] = 0x45;
+    data[16..20].clone_from_slice(&[10, 10, 10, 1]);
+    assert_eq!(destination(&data).unwrap(),
+               IpAddr::V4(Ipv4Addr::new(10, 10, 10, 1)));
+}
+
+#[test]
+fn transport_test() {
+    let mut packet = [0u8; 28];
+    packet[0] = 0x45;
+    packet[9] = IPPROTO_UDP;
+    packet[22..24].clone_from_slice(&[0, 53]);
+    assert_eq!(transport(&packet).unwrap(), (IPPROTO_UDP, Some(53)));
+    packet[20..22].clone_from_slice(&[0x30, 0x39]);
+    assert_eq!(ports(&packet).unwrap(), (IPPROTO_UDP, Some((12345, 53))));
+    packet[6] = 0x01;
+    assert_eq!(transport(&packet).unwrap(), (IPPROTO_UDP, None));
+    packet[9] = IPPROTO_ICMP;
+    assert_eq!(transport(&packet).unwrap(), (IPPROTO_ICMP, None));
+    assert!(transport(&packet[0..10]).is_err());
+}
+
+#[test]
+fn dscp_test() {
+    assert_eq!(dscp(&[0x45, 0xb8]), Some(46));
+    assert_eq!(dscp(&[0x6b, 0x80]), Some(46));
+    assert_eq!(dscp(&[0x45]), None);
+}
+
+#[test]
+fn build_udp_test() {
+    let src: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+    let dst: SocketAddr = "10.0.0.2:9527".parse().unwrap();
+    let packet = build_udp(&src, &dst, &[1, 2, 3]);
+    assert_eq!(packet.len(), 20 + 8 + 3);
+    assert_eq!(packet[0], 0x45);
+    assert_eq!(inet_cksum(&packet[..20]), 0);
+    assert_eq!(&packet[20..24], &[0x04, 0xd2, 0x25, 0x37]);
+    assert_eq!(parse_udp(&packet).unwrap(), (src, dst, &[1u8, 2, 3][..]));
+
+    let src: SocketAddr = "[fd10:10:10::2]:1234".parse().unwrap();
+    let dst: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+    let packet = build_udp(&src, &dst, &[1, 2, 3]);
+    assert_eq!(packet.len(), 40 + 8 + 3);
+    assert_eq!(packet[0], 0x60);
+    assert_eq!(parse_udp(&packet).unwrap(), (src, dst, &[1u8, 2, 3][..]));
+    assert!(parse_udp(&packet[..45]).is_err());
+}
+
+#[test]
+fn too_big_test() {
+    let src: SocketAddr = "192.168.1.7:4000".parse().unwrap();
+    let dst: SocketAddr = "10.10.10.2:53".parse().unwrap();
+    let packet = build_udp(&src, &dst, &[0u8; 1400]);
+    let router = "10.10.10.1".parse().unwrap();
+    let icmp = too_big(&packet, 1380, router).unwrap();
+    assert_eq!(icmp.len(), 20 + 8 + 28);
+    assert_eq!(destination(&icmp).unwrap(), src.ip());
+    assert_eq!(inet_cksum(&icmp[..20]), 0);
+    assert_eq!(inet_cksum(&icmp[20..]), 0);
+    assert_eq!((icmp[20], icmp[21]), (3, 4));
+    assert_eq!(&icmp[26..28], &[0x05, 0x64]);
+    // No error about an error.
+    let mut icmp = icmp;
+    icmp[6] = 0x40;
+    assert!(too_big(&icmp, 576, router).is_none());
+
+    let mut no_df = packet.clone();
+    no_df[6] = 0;
+    assert!(too_big(&no_df, 1380, router).is_none());
+}
+
+#[test]
+fn bad_checksum_test() {
+    let src: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+    let dst: SocketAddr = "10.0.0.2:9527".parse().unwrap();
+    let mut packet = build_udp(&src, &dst, &[1, 2, 3]);
+    assert_eq!(bad_checksum(&packet), None);
+    packet[30] ^= 0x40;
+    assert_eq!(bad_checksum(&packet), Some("UDP"));
+    packet[26] = 0;
+    packet[27] = 0;
+    assert_eq!(bad_checksum(&packet), None);
+    packet[8] -= 1;
+    assert_eq!(bad_checksum(&packet), Some("IPv4 header"));
+    assert_eq!(bad_checksum(&packet[..25]), Some("truncated"));
+
+    let src: SocketAddr = "[fd10:10:10::2]:1234".parse().unwrap();
+    let dst: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+    let mut packet = build_udp(&src, &dst, &[1, 2, 3]);
+    assert_eq!(bad_checksum(&packet), None);
+    packet[24] ^= 1;
+    assert_eq!(bad_checksum(&packet), Some("UDP"));
+}
+
+#[test]
+fn clamp_mss_test() {
+    // IPv4 + TCP SYN with NOP, MSS 1460 (odd-aligned) and padding.
+    let mut packet = vec![0u8; 20 + 28];
+    packet[0] = 0x45;
+    packet[9] = IPPROTO_TCP;
+    packet[12..16].clone_from_slice(&[10, 10, 10, 2]);
+    packet[16..20].clone_from_slice(&[1, 2, 3, 4]);
+    packet[20 + 12] = 7 << 4;
+    packet[20 + 13] = TCP_FLAG_SYN;
+    packet[40..48].clone_from_slice(&[TCP_OPT_NOP, TCP_OPT_MSS, 4, 0x05, 0xb4, 0, 0, 0]);
+
+    // Pseudo header + TCP segment with a zero checksum field.
+    let mut pseudo = Vec::new();
+    pseudo.extend_from_slice(&packet[12..20]);
+    pseudo.extend_from_slice(&[0, IPPROTO_TCP, 0, 28]);
+    let cksum = inet_cksum(&[&pseudo[..], &packet[20..]].concat());
+    write_u16(&mut packet, 36, cksum);
+
+    assert!(clamp_mss(&mut packet, 1380));
+    assert_eq!(read_u16(&packet, 43), 1340);
+    assert_eq!(inet_cksum(&[&pseudo[..], &packet[20..]].concat()), 0);
+
+    // Already small enough.
+    assert!(!clamp_mss(&mut packet, 1400));
+
+    // Not a SYN.
+    packet[20 + 13] = 0x10;
+    assert!(!clamp_mss(&mut packet, 1000));
+}
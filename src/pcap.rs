@@ -0,0 +1,164 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capture of tunnel traffic in pcap format for inspection with Wireshark.
+//!
+//! Everything is written with `LINKTYPE_RAW`, so each record is a bare IP
+//! packet. Inner packets are recorded as read from or written to the TUN
+//! device, before compression. Outer datagrams have no IP header of their
+//! own once they reach userspace, so a minimal IP/UDP header is synthesized
+//! around the encoded frame.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use packet;
+use utils;
+use error::{Error, Result};
+
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const GLOBAL_HEADER_LEN: u64 = 24;
+const RECORD_HEADER_LEN: u64 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Inner,
+    Outer,
+    Both,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Result<Mode> {
+        match s {
+            "inner" => Ok(Mode::Inner),
+            "outer" => Ok(Mode::Outer),
+            "both" => Ok(Mode::Both),
+            _ => Err(Error::Config(format!("unknown capture mode {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub path: PathBuf,
+    pub mode: Mode,
+    /// Rotate once the current file grows beyond this many bytes.
+    pub max_size: u64,
+    /// Number of rotated files (`FILE.1`, `FILE.2`, ...) to keep.
+    pub max_files: usize,
+}
+
+impl Config {
+    pub fn new(path: &str) -> Config {
+        Config {
+            path: PathBuf::from(path),
+            mode: Mode::Inner,
+            max_size: 100 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+pub struct Capture {
+    config: Config,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl Capture {
+    pub fn open(config: Config) -> Result<Capture> {
+        let writer = try!(create(&config.path));
+        info!("Capturing {:?} traffic to {}.",
+              config.mode,
+              config.path.display());
+        Ok(Capture {
+            config: config,
+            writer: writer,
+            size: GLOBAL_HEADER_LEN,
+        })
+    }
+
+    /// Records a decompressed packet seen on the TUN side.
+    pub fn inner(&mut self, packet: &[u8]) {
+        if self.config.mode != Mode::Outer {
+            self.record(packet);
+        }
+    }
+
+    /// Records an encoded datagram exchanged between `src` and `dst`.
+    pub fn outer(&mut self, src: &SocketAddr, dst: &SocketAddr, payload: &[u8]) {
+        if self.config.mode != Mode::Inner {
+            let packet = packet::build_udp(src, dst, payload);
+            self.record(&packet);
+        }
+    }
+
+    fn record(&mut self, packet: &[u8]) {
+        if let Err(e) = self.try_record(packet) {
+            warn!("Failed to write to {}: {}", self.config.path.display(), e);
+        }
+    }
+
+    fn try_record(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.size >= self.config.max_size {
+            try!(self.rotate());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let incl_len = if packet.len() > SNAPLEN as usize {
+            SNAPLEN as usize
+        } else {
+            packet.len()
+        };
+        try!(write_u32(&mut self.writer, now.as_secs() as u32));
+        try!(write_u32(&mut self.writer, now.subsec_nanos() / 1000));
+        try!(write_u32(&mut self.writer, incl_len as u32));
+        try!(write_u32(&mut self.writer, packet.len() as u32));
+        try!(self.writer.write_all(&packet[..incl_len]));
+        try!(self.writer.flush());
+        self.size += RECORD_HEADER_LEN + incl_len as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        try!(self.writer.flush());
+        try!(utils::rotate_files(&self.config.path, self.config.max_files));
+        self.writer = try!(create(&self.config.path));
+        self.size = GLOBAL_HEADER_LEN;
+        Ok(())
+    }
+}
+
+fn create(path: &PathBuf) -> io::Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(try!(File::create(path)));
+    try!(write_u32(&mut writer, 0xa1b2c3d4));
+    try!(write_u16(&mut writer, 2));
+    try!(write_u16(&mut writer, 4));
+    try!(write_u32(&mut writer, 0)); // thiszone
+    try!(write_u32(&mut writer, 0)); // sigfigs
+    try!(write_u32(&mut writer, SNAPLEN));
+    try!(write_u32(&mut writer, LINKTYPE_RAW));
+    try!(writer.flush());
+    Ok(writer)
+}
+
+fn write_u16<W: Write>(w: &mut W, v: u16) -> io::Result<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8])
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8])
+}
@@ -0,0 +1,328 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Point-to-point links between two static peers.
+//!
+//! Each peer is configured with its own identity, the other's public key,
+//! both tunnel addresses and, on at least one side, the other's endpoint.
+//! There is no handshake and no id allocation: both ends derive the same
+//! token from their keys (see `Identity::link_token()`), bring up the TUN
+//! device with their fixed address and exchange `Data` frames straight
+//! away. A peer without a configured endpoint answers whoever last sent it a
+//! valid frame, so one side may sit behind NAT. `Ping`s every
+//! `KEEPALIVE` seconds keep the path open.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use mio;
+use device;
+use identity;
+use network::*;
+use error::{Error, Result};
+
+/// Seconds between keepalives.
+pub const KEEPALIVE: u64 = 25;
+
+pub struct PeerBuilder {
+    identity: Option<PathBuf>,
+    peer: Option<identity::Key>,
+    endpoint: Option<(String, u16)>,
+    port: u16,
+    address: Option<Ipv4Addr>,
+    peer_address: Option<Ipv4Addr>,
+    tun_fd: Option<RawFd>,
+    callback: Option<Callback>,
+}
+
+impl PeerBuilder {
+    /// Path of this peer's identity, created on first use.
+    pub fn identity<P: AsRef<Path>>(mut self, path: P) -> PeerBuilder {
+        self.identity = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Public key of the other peer.
+    pub fn peer(mut self, key: identity::Key) -> PeerBuilder {
+        self.peer = Some(key);
+        self
+    }
+
+    /// Where the other peer listens. Optional on one side.
+    pub fn endpoint(mut self, host: &str, port: u16) -> PeerBuilder {
+        self.endpoint = Some((String::from(host), port));
+        self
+    }
+
+    /// Local UDP port. Defaults to 8964.
+    pub fn port(mut self, port: u16) -> PeerBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Tunnel addresses of this peer and of the other, in the same /24.
+    pub fn addresses(mut self, address: Ipv4Addr, peer_address: Ipv4Addr) -> PeerBuilder {
+        self.address = Some(address);
+        self.peer_address = Some(peer_address);
+        self
+    }
+
+    /// Use an already open TUN device.
+    pub fn tun_fd(mut self, fd: RawFd) -> PeerBuilder {
+        self.tun_fd = Some(fd);
+        self
+    }
+
+    pub fn on_event<F>(mut self, callback: F) -> PeerBuilder
+        where F: Fn(&Event) + Send + 'static
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    pub fn build(self) -> Result<Peer> {
+        let config = |what: &str| Error::Config(format!("a static peer needs {}", what));
+        let path = try!(self.identity.ok_or_else(|| config("an identity")));
+        let peer = try!(self.peer.ok_or_else(|| config("the other peer's key")));
+        let address = try!(self.address.ok_or_else(|| config("tunnel addresses")));
+        let peer_address = try!(self.peer_address.ok_or_else(|| config("tunnel addresses")));
+        let (a, b) = (address.octets(), peer_address.octets());
+        if a[0..3] != b[0..3] || a[3] == b[3] {
+            return Err(Error::Config(format!("{} and {} must be distinct addresses in one /24",
+                                             address,
+                                             peer_address)));
+        }
+        let identity = try!(identity::Identity::load_or_create(&path));
+        info!("Using identity {}, peer {}.",
+              identity::fingerprint(&identity.public()),
+              identity::fingerprint(&peer));
+        let token = try!(identity.link_token(&peer));
+        let endpoint = match self.endpoint {
+            Some((ref host, port)) => Some(SocketAddr::new(try!(resolve(host)), port)),
+            None => None,
+        };
+
+        let any = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let sockfd = try!(mio::udp::UdpSocket::bind(&SocketAddr::new(any, self.port)));
+        let subnet = device::Subnet([a[0], a[1], a[2]]);
+        let tun = try!(open_interface(self.tun_fd, false, None));
+        try!(tun.up(&subnet, a[3]));
+        let mtu = try!(tun.mtu());
+        info!("TUN device {} initialized. Internal IP: {}/24, peer {}. MTU: {}.",
+              tun.name(),
+              address,
+              peer_address,
+              mtu);
+
+        let poll = try!(mio::Poll::new());
+        try!(poll.register(&sockfd, SOCK, mio::Ready::readable(), mio::PollOpt::level()));
+        try!(poll.register(&mio::unix::EventedFd(&tun.as_raw_fd()),
+                           TUN,
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+        let (shutdown, registration) = ShutdownHandle::new();
+        try!(poll.register(&registration,
+                           SHUTDOWN,
+                           mio::Ready::readable(),
+                           mio::PollOpt::edge()));
+        try!(register_signal(&poll));
+
+        Ok(Peer {
+            id: a[3],
+            peer_id: b[3],
+            token: token,
+            endpoint: endpoint,
+            fixed: endpoint.is_some(),
+            tun: tun,
+            sockfd: sockfd,
+            poll: poll,
+            shutdown: shutdown,
+            _registration: registration,
+            callback: self.callback,
+            last_sent: None,
+            connected: false,
+            sock_buf: vec![0u8; frame_capacity(mtu) + 1],
+            tun_buf: vec![0u8; mtu as usize + 1],
+        })
+    }
+}
+
+/// One end of a static point-to-point link.
+pub struct Peer {
+    id: Id,
+    peer_id: Id,
+    token: Token,
+    // Where frames go; learned from the other peer unless configured.
+    endpoint: Option<SocketAddr>,
+    fixed: bool,
+    tun: Box<device::VirtualInterface>,
+    sockfd: mio::udp::UdpSocket,
+    poll: mio::Poll,
+    shutdown: ShutdownHandle,
+    _registration: mio::Registration,
+    callback: Option<Callback>,
+    last_sent: Option<Instant>,
+    // Whether a valid frame has come from the other peer.
+    connected: bool,
+    sock_buf: Vec<u8>,
+    tun_buf: Vec<u8>,
+}
+
+impl Peer {
+    pub fn builder() -> PeerBuilder {
+        PeerBuilder {
+            identity: None,
+            peer: None,
+            endpoint: None,
+            port: 8964,
+            address: None,
+            peer_address: None,
+            tun_fd: None,
+            callback: None,
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Runs the link until it is shut down.
+    pub fn run(&mut self) -> Result<()> {
+        info!("Working in static peer mode.");
+        let mut events = mio::Events::with_capacity(1024);
+        let keepalive = Duration::from_secs(KEEPALIVE);
+        while !self.shutdown.is_shutdown() {
+            let now = Instant::now();
+            let due = self.last_sent.map_or(now, |sent| sent + keepalive);
+            if due <= now {
+                try!(self.send(&Message::Ping {
+                    id: self.id,
+                    token: self.token,
+                }));
+            }
+            let wait = self.last_sent.map_or(keepalive, |sent| remaining(sent + keepalive, now));
+            try!(self.poll.poll(&mut events, Some(wait)));
+            for event in events.iter() {
+                match event.token() {
+                    SOCK => try!(self.handle_socket()),
+                    TUN => try!(self.handle_tun()),
+                    SHUTDOWN | SIGNAL | STATUS => {}
+                    _ => unreachable!(),
+                }
+            }
+        }
+        self.emit(Event::Disconnected);
+        Ok(())
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(ref callback) = self.callback {
+            callback(&event);
+        }
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        let endpoint = match self.endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(()),
+        };
+        let buf = try!(encode_message(msg));
+        match send_raw(&self.sockfd, &buf, &endpoint) {
+            Ok(()) => {}
+            Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::WouldBlock => {
+                trace_packet!("sock len={} dropped: socket busy", buf.len());
+            }
+            Err(e) => return Err(e),
+        }
+        self.last_sent = Some(Instant::now());
+        Ok(())
+    }
+
+    fn handle_tun(&mut self) -> Result<()> {
+        let len = try!(self.tun.read(&mut self.tun_buf));
+        if len == 0 || len == self.tun_buf.len() {
+            return Ok(());
+        }
+        let msg = Message::Data {
+            id: self.id,
+            token: self.token,
+            data: self.tun_buf[..len].to_vec(),
+        };
+        self.send(&msg)
+    }
+
+    fn handle_socket(&mut self) -> Result<()> {
+        let (len, addr) = match try!(self.sockfd.recv_from(&mut self.sock_buf)) {
+            Some(received) => received,
+            None => return Ok(()),
+        };
+        let msg = match decode_message(&self.sock_buf[..len]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!("Undecodable frame from {}: {}", addr, e);
+                return Ok(());
+            }
+        };
+        let (id, token) = match msg {
+            Message::Data { id, token, .. } |
+            Message::Ping { id, token } |
+            Message::Pong { id, token } => (id, token),
+            _ => {
+                debug!("Unexpected message from {}.", addr);
+                return Ok(());
+            }
+        };
+        if id != self.peer_id || token != self.token {
+            debug!("Dropping frame from {} with the wrong token.", addr);
+            return Ok(());
+        }
+        if !self.fixed && self.endpoint != Some(addr) {
+            info!("Peer is at {}.", addr);
+            self.endpoint = Some(addr);
+        }
+        if !self.connected {
+            self.connected = true;
+            self.emit(Event::Connected {
+                id: self.id,
+                server: addr,
+            });
+        }
+        match msg {
+            Message::Data { data, .. } => {
+                try!(self.tun.write(&data));
+            }
+            Message::Ping { .. } => {
+                try!(self.send(&Message::Pong {
+                    id: self.id,
+                    token: self.token,
+                }));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn peer_builder_test() {
+    assert!(Peer::builder().build().is_err());
+    assert!(Peer::builder()
+        .identity("/nonexistent")
+        .peer([0; 32])
+        .addresses(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 1, 2))
+        .build()
+        .is_err());
+}
@@ -0,0 +1,186 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Named client profiles for `kytan connect NAME`.
+//!
+//! A profile is a file of long command-line options, one per line and
+//! without the leading dashes, looked up as `NAME.conf` in
+//! `$XDG_CONFIG_HOME/kytan` (or `~/.config/kytan`) and then `/etc/kytan`:
+//!
+//! ```text
+//! # Office VPN
+//! host = vpn.example.com
+//! port = 9527
+//! credential = file:/etc/kytan/office.psk
+//! server = vpn2.example.com:9527
+//! clamp-mss
+//! ```
+//!
+//! Secrets can be referenced as `file:PATH` rather than written into the
+//! profile; such files must not be accessible to other users. Desktops can
+//! keep them in the platform keyring instead, as `keyring:ACCOUNT`.
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use error::{Error, Result};
+
+fn directories() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(config) => dirs.push(PathBuf::from(config).join("kytan")),
+        None => {
+            if let Some(home) = env::home_dir() {
+                dirs.push(home.join(".config").join("kytan"));
+            }
+        }
+    }
+    dirs.push(PathBuf::from("/etc/kytan"));
+    dirs
+}
+
+/// The file of profile `name`, if there is one.
+pub fn find(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return None;
+    }
+    directories().into_iter().map(|dir| dir.join(format!("{}.conf", name))).find(|path| path.is_file())
+}
+
+/// Turns the lines of a profile into command-line arguments.
+pub fn parse(text: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(eq) => (line[..eq].trim(), Some(line[eq + 1..].trim())),
+            None => (line, None),
+        };
+        if key.is_empty() || key.starts_with('-') || key.contains(char::is_whitespace) {
+            return Err(Error::Config(format!("line {}: expected OPTION or OPTION = VALUE", i + 1)));
+        }
+        args.push(format!("--{}", key));
+        if let Some(value) = value {
+            args.push(String::from(value));
+        }
+    }
+    Ok(args)
+}
+
+/// Turns `KYTAN_*` environment variables into command-line arguments.
+/// `KYTAN_CLAMP_MSS=true` becomes `--clamp-mss` and `KYTAN_PORT=9527`
+/// becomes `--port 9527`; `false` leaves an option out. `KYTAN_SERVER` is
+/// `--host` and `KYTAN_PSK_FILE` is `--credential-file`.
+pub fn from_env<I>(vars: I) -> Vec<String>
+    where I: IntoIterator<Item = (String, String)>
+{
+    let mut vars: Vec<(String, String)> =
+        vars.into_iter().filter(|&(ref name, _)| name.starts_with("KYTAN_")).collect();
+    vars.sort();
+    let mut args = Vec::new();
+    for (name, value) in vars {
+        let key = match &name["KYTAN_".len()..] {
+            "SERVER" => String::from("host"),
+            "PSK_FILE" => String::from("credential-file"),
+            key => key.to_lowercase().replace('_', "-"),
+        };
+        match &value[..] {
+            "false" => {}
+            "true" => args.push(format!("--{}", key)),
+            _ => {
+                args.push(format!("--{}", key));
+                args.push(value);
+            }
+        }
+    }
+    args
+}
+
+fn long_name(arg: &str) -> Option<&str> {
+    match arg {
+        "-m" => Some("mode"),
+        "-p" => Some("port"),
+        "-h" => Some("host"),
+        "-t" => Some("timeout"),
+        _ if arg.starts_with("--") => Some(arg[2..].split('=').next().unwrap()),
+        _ => None,
+    }
+}
+
+/// Appends the options of a lower-precedence source to `args`, leaving out
+/// those `args` already sets. Command-line options beat environment variables,
+/// which beat a profile.
+pub fn merge(args: &mut Vec<String>, layer: Vec<String>) {
+    let set: Vec<String> = args.iter().filter_map(|arg| long_name(arg)).map(String::from).collect();
+    let mut skipping = false;
+    for arg in layer {
+        if arg.starts_with("--") {
+            skipping = set.iter().any(|name| Some(&name[..]) == long_name(&arg));
+        }
+        if !skipping {
+            args.push(arg);
+        }
+    }
+}
+
+/// Loads profile `name` as command-line arguments.
+pub fn load(name: &str) -> Result<Vec<String>> {
+    let path = try!(find(name).ok_or(Error::Config(format!("no profile named {}", name))));
+    let mut file = try!(File::open(&path));
+    if try!(file.metadata()).permissions().mode() & 0o077 != 0 {
+        warn!("Profile {} is readable by other users; it may hold secrets.",
+              path.display());
+    }
+    let mut text = String::new();
+    try!(file.read_to_string(&mut text));
+    parse(&text).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+}
+
+#[test]
+fn parse_test() {
+    let args = parse("# comment\n\nhost = vpn.example.com\nclamp-mss\n  server=a:1  \n").unwrap();
+    assert_eq!(args,
+               vec!["--host", "vpn.example.com", "--clamp-mss", "--server", "a:1"]);
+    assert!(parse("--host x").is_err());
+    assert!(parse("bad key = 1").is_err());
+}
+
+#[test]
+fn env_test() {
+    let vars = vec![(String::from("KYTAN_SERVER"), String::from("vpn.example.com")),
+                    (String::from("KYTAN_CLAMP_MSS"), String::from("true")),
+                    (String::from("KYTAN_NO_COMPRESSION"), String::from("false")),
+                    (String::from("HOME"), String::from("/root"))];
+    assert_eq!(from_env(vars),
+               vec!["--clamp-mss", "--host", "vpn.example.com"]);
+}
+
+#[test]
+fn merge_test() {
+    let mut args: Vec<String> = vec!["-m", "c", "-h", "a", "--clamp-mss"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let layer = parse("host = b\nport = 1\nclamp-mss\nserver = c:1\nserver = d:1\n").unwrap();
+    merge(&mut args, layer);
+    assert_eq!(args,
+               vec!["-m", "c", "-h", "a", "--clamp-mss", "--port", "1", "--server", "c:1",
+                    "--server", "d:1"]);
+}
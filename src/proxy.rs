@@ -0,0 +1,494 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local SOCKS5 proxy and port forwards of a client.
+//!
+//! Accepted connections are relayed as streams, see the `stream` module, to
+//! the server, which makes the actual connection. The SOCKS5 proxy only
+//! supports `CONNECT` without authentication; a forward always connects to
+//! the same `HOST:PORT`. Nothing on the host changes, so neither needs
+//! privileges or routes.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use mio;
+use stream::{self, Outgoing, Relay, Segment, StreamId};
+use error::{Error, Result};
+
+/// Tokens from the base up to this are for listeners.
+const MAX_LISTENERS: usize = 16;
+/// Largest SOCKS5 request: a 255 byte host name and the header around it.
+const MAX_REQUEST: usize = 262;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+
+/// Reply codes.
+const SUCCEEDED: u8 = 0;
+const FAILURE: u8 = 1;
+const UNSUPPORTED_COMMAND: u8 = 7;
+const UNSUPPORTED_ADDRESS: u8 = 8;
+
+/// What a parser made of the bytes so far.
+#[derive(Debug, PartialEq)]
+enum Parsed<T> {
+    Incomplete,
+    /// Bytes consumed and the result.
+    Done(usize, T),
+    /// The reply code to fail with.
+    Invalid(u8),
+}
+
+/// Parses the greeting, which lists the authentication methods.
+fn parse_greeting(buf: &[u8]) -> Parsed<()> {
+    if buf.len() < 2 {
+        return Parsed::Incomplete;
+    }
+    let len = 2 + buf[1] as usize;
+    if buf[0] != VERSION {
+        Parsed::Invalid(NO_METHOD)
+    } else if buf.len() < len {
+        Parsed::Incomplete
+    } else if !buf[2..len].contains(&NO_AUTH) {
+        Parsed::Invalid(NO_METHOD)
+    } else {
+        Parsed::Done(len, ())
+    }
+}
+
+/// Parses the request into the `HOST:PORT` to connect to.
+fn parse_request(buf: &[u8]) -> Parsed<String> {
+    if buf.len() < 5 {
+        return Parsed::Incomplete;
+    }
+    if buf[0] != VERSION {
+        return Parsed::Invalid(FAILURE);
+    }
+    if buf[1] != CONNECT {
+        return Parsed::Invalid(UNSUPPORTED_COMMAND);
+    }
+    let addr_len = match buf[3] {
+        1 => 4,
+        3 => 1 + buf[4] as usize,
+        4 => 16,
+        _ => return Parsed::Invalid(UNSUPPORTED_ADDRESS),
+    };
+    let len = 4 + addr_len + 2;
+    if buf.len() < len {
+        return Parsed::Incomplete;
+    }
+    let addr = &buf[4..4 + addr_len];
+    let port = (buf[len - 2] as u16) << 8 | buf[len - 1] as u16;
+    let host = match buf[3] {
+        1 => Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]).to_string(),
+        3 => String::from_utf8_lossy(&addr[1..]).into_owned(),
+        _ => {
+            let mut octets = [0u8; 16];
+            octets.clone_from_slice(addr);
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+    };
+    Parsed::Done(len, format!("{}:{}", host, port))
+}
+
+/// A reply to a request. The bound address is left unspecified.
+fn reply(code: u8) -> [u8; 10] {
+    [VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+enum State {
+    Greeting,
+    Request,
+    /// Waiting for the server to connect to `target`.
+    Opening {
+        target: String,
+        since: Instant,
+        sent: Instant,
+    },
+    Open,
+}
+
+struct Conn {
+    // Whether the connection speaks SOCKS5 rather than being forwarded.
+    socks: bool,
+    state: State,
+    relay: Relay,
+    // SOCKS5 bytes not parsed yet.
+    buf: Vec<u8>,
+}
+
+pub struct Proxy {
+    base: usize,
+    // Listeners with the target of a forward, or `None` for SOCKS5.
+    listeners: Vec<(mio::tcp::TcpListener, Option<String>)>,
+    next_stream: StreamId,
+    conns: HashMap<StreamId, Conn>,
+}
+
+impl Proxy {
+    /// Sockets are registered with tokens from `base` upwards.
+    pub fn new(base: usize) -> Proxy {
+        Proxy {
+            base: base,
+            listeners: Vec::new(),
+            next_stream: 0,
+            conns: HashMap::new(),
+        }
+    }
+
+    /// Accepts SOCKS5 clients on `addr`.
+    pub fn listen(&mut self, poll: &mio::Poll, addr: &SocketAddr) -> Result<()> {
+        try!(self.bind(poll, addr, None));
+        info!("SOCKS5 proxy listening on {}.", addr);
+        Ok(())
+    }
+
+    /// Relays connections to `addr` to `target`, a `HOST:PORT` as seen from
+    /// the server.
+    pub fn forward(&mut self, poll: &mio::Poll, addr: &SocketAddr, target: &str) -> Result<()> {
+        try!(self.bind(poll, addr, Some(String::from(target))));
+        info!("Forwarding {} to {} through the server.", addr, target);
+        Ok(())
+    }
+
+    fn bind(&mut self, poll: &mio::Poll, addr: &SocketAddr, target: Option<String>)
+            -> Result<()> {
+        if self.listeners.len() >= MAX_LISTENERS {
+            return Err(Error::Config(format!("at most {} proxies and forwards", MAX_LISTENERS)));
+        }
+        let listener = try!(mio::tcp::TcpListener::bind(addr));
+        try!(poll.register(&listener,
+                           mio::Token(self.base + self.listeners.len()),
+                           mio::Ready::readable(),
+                           mio::PollOpt::level()));
+        self.listeners.push((listener, target));
+        Ok(())
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        token.0 >= self.base
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    fn token(&self, stream: StreamId) -> mio::Token {
+        mio::Token(self.base + MAX_LISTENERS + stream as usize)
+    }
+
+    /// Handles readiness of a listener or connection. Returns what to send
+    /// to the server.
+    pub fn ready(&mut self, poll: &mio::Poll, token: mio::Token, now: Instant) -> Vec<Outgoing> {
+        let index = token.0 - self.base;
+        if index < MAX_LISTENERS {
+            return self.accept(poll, index, now);
+        }
+        let stream = (index - MAX_LISTENERS) as StreamId;
+        let result = match self.conns.get_mut(&stream) {
+            Some(conn) => {
+                match conn.state {
+                    State::Greeting | State::Request => negotiate(stream, conn, now),
+                    State::Opening { .. } => Ok(Vec::new()),
+                    State::Open => conn.relay.ready(now).map(wrap),
+                }
+            }
+            None => return Vec::new(),
+        };
+        self.settle(poll, stream, result)
+    }
+
+    fn accept(&mut self, poll: &mio::Poll, index: usize, now: Instant) -> Vec<Outgoing> {
+        let mut out = Vec::new();
+        while let Some(&(ref listener, ref target)) = self.listeners.get(index) {
+            let socket = match listener.accept() {
+                Ok((socket, _)) => socket,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept proxy connection: {}", e);
+                    break;
+                }
+            };
+            let stream = self.next_stream;
+            self.next_stream = self.next_stream.wrapping_add(1);
+            if let Err(e) = poll.register(&socket,
+                                          self.token(stream),
+                                          mio::Ready::readable() | mio::Ready::writable(),
+                                          mio::PollOpt::edge()) {
+                warn!("Failed to register proxy connection: {}", e);
+                continue;
+            }
+            // A forward skips the negotiation.
+            let state = match *target {
+                Some(ref target) => {
+                    out.push(Outgoing::Open(stream, target.clone()));
+                    State::Opening {
+                        target: target.clone(),
+                        since: now,
+                        sent: now,
+                    }
+                }
+                None => State::Greeting,
+            };
+            self.conns.insert(stream,
+                              Conn {
+                                  socks: target.is_none(),
+                                  state: state,
+                                  relay: Relay::new(socket, stream, stream::BUFFER, now),
+                                  buf: Vec::new(),
+                              });
+        }
+        out
+    }
+
+    /// The server's answer to an `Open`.
+    pub fn reply(&mut self, poll: &mio::Poll, stream: StreamId, error: Option<String>, now: Instant)
+                 -> Vec<Outgoing> {
+        let result = match self.conns.get_mut(&stream) {
+            Some(conn) => {
+                match (&conn.state, error) {
+                    (&State::Opening { ref target, .. }, Some(error)) => {
+                        info!("Server could not connect to {}: {}", target, error);
+                        if conn.socks {
+                            let _ = conn.relay.socket.write(&reply(FAILURE));
+                        }
+                        Err(io::Error::new(io::ErrorKind::ConnectionRefused, error))
+                    }
+                    (&State::Opening { .. }, None) => Ok(()),
+                    _ => return Vec::new(),
+                }
+            }
+            None => return Vec::new(),
+        };
+        let result = result.and_then(|_| self.opened(stream, now));
+        self.settle(poll, stream, result)
+    }
+
+    /// Tells the SOCKS5 client its connection is up and starts relaying.
+    fn opened(&mut self, stream: StreamId, now: Instant) -> io::Result<Vec<Outgoing>> {
+        let conn = self.conns.get_mut(&stream).unwrap();
+        conn.state = State::Open;
+        if conn.socks {
+            try!(conn.relay.socket.write(&reply(SUCCEEDED)));
+        }
+        conn.relay.ready(now).map(wrap)
+    }
+
+    /// Takes a segment from the server.
+    pub fn segment(&mut self, poll: &mio::Poll, segment: Segment, now: Instant)
+                   -> Vec<Outgoing> {
+        let stream = segment.stream;
+        let opening = match self.conns.get(&stream) {
+            Some(conn) => {
+                match conn.state {
+                    State::Opening { .. } => true,
+                    State::Open => false,
+                    _ => return Vec::new(),
+                }
+            }
+            None => return vec![Outgoing::Close(stream)],
+        };
+        // Data before the reply means the reply was lost.
+        let mut out = Vec::new();
+        if opening {
+            match self.opened(stream, now) {
+                Ok(segments) => out = segments,
+                Err(e) => return self.settle(poll, stream, Err(e)),
+            }
+        }
+        let result = self.conns.get_mut(&stream).unwrap().relay.receive(segment, now).map(wrap);
+        out.extend(self.settle(poll, stream, result));
+        out
+    }
+
+    /// Drops a stream the server aborted.
+    pub fn close(&mut self, poll: &mio::Poll, stream: StreamId) {
+        if let Some(conn) = self.conns.remove(&stream) {
+            let _ = poll.deregister(&conn.relay.socket);
+        }
+    }
+
+    /// Drops every connection, as the server forgets its streams with the
+    /// session.
+    pub fn reset(&mut self, poll: &mio::Poll) {
+        let streams: Vec<StreamId> = self.conns.keys().cloned().collect();
+        for stream in streams {
+            self.close(poll, stream);
+        }
+    }
+
+    fn settle(&mut self, poll: &mio::Poll, stream: StreamId, result: io::Result<Vec<Outgoing>>)
+              -> Vec<Outgoing> {
+        match result {
+            Ok(out) => {
+                if self.conns.get(&stream).map_or(false, |conn| conn.relay.finished()) {
+                    self.close(poll, stream);
+                }
+                out
+            }
+            Err(e) => {
+                debug!("Proxy stream {} failed: {}", stream, e);
+                self.close(poll, stream);
+                vec![Outgoing::Close(stream)]
+            }
+        }
+    }
+
+    /// Retransmissions and acknowledgements that are due, and aborts of
+    /// streams the server stopped answering.
+    pub fn tick(&mut self, poll: &mio::Poll, now: Instant) -> Vec<Outgoing> {
+        let mut out = Vec::new();
+        let mut stalled = Vec::new();
+        let retransmit = Duration::from_millis(stream::RETRANSMIT);
+        let give_up = Duration::from_secs(stream::GIVE_UP);
+        for (&stream, conn) in self.conns.iter_mut() {
+            match conn.state {
+                State::Opening { ref target, since, ref mut sent } => {
+                    if now.duration_since(since) >= give_up {
+                        stalled.push(stream);
+                    } else if now.duration_since(*sent) >= retransmit {
+                        *sent = now;
+                        out.push(Outgoing::Open(stream, target.clone()));
+                    }
+                }
+                State::Open if conn.relay.channel.stalled(now) => stalled.push(stream),
+                State::Open => out.extend(wrap(conn.relay.channel.poll(now))),
+                _ => {}
+            }
+        }
+        for stream in stalled {
+            self.close(poll, stream);
+            out.push(Outgoing::Close(stream));
+        }
+        out
+    }
+}
+
+/// Parses an ssh-style `[ADDRESS:]PORT:HOST:PORT` forward into the address
+/// to listen on, localhost by default, and the target.
+pub fn parse_forward(s: &str) -> Result<(SocketAddr, String)> {
+    let invalid = || Error::Config(format!("expected [ADDR:]PORT:HOST:PORT, got {:?}", s));
+    let (rest, port) = match s.rfind(':') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(invalid()),
+    };
+    let port: u16 = try!(port.parse().map_err(|_| invalid()));
+    // An IPv6 host is in brackets.
+    let host = if rest.ends_with(']') {
+        rest.rfind('[')
+    } else {
+        rest.rfind(':').map(|i| i + 1)
+    };
+    let host = match host {
+        Some(host) if host > 0 && host < rest.len() && rest[..host].ends_with(':') => host,
+        _ => return Err(invalid()),
+    };
+    let listen = &rest[..host - 1];
+    let listen = match listen.parse() {
+        Ok(port) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port),
+        Err(_) => try!(listen.parse().map_err(|_| invalid())),
+    };
+    Ok((listen, format!("{}:{}", &rest[host..], port)))
+}
+
+fn wrap(segments: Vec<Segment>) -> Vec<Outgoing> {
+    segments.into_iter().map(Outgoing::Segment).collect()
+}
+
+/// Reads and answers the SOCKS5 greeting and request. Returns the `Open` for
+/// the server once the request is complete.
+fn negotiate(stream: StreamId, conn: &mut Conn, now: Instant) -> io::Result<Vec<Outgoing>> {
+    let mut buf = [0u8; MAX_REQUEST];
+    loop {
+        match conn.relay.socket.read(&mut buf) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed early")),
+            Ok(len) => conn.buf.extend_from_slice(&buf[..len]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+        if conn.buf.len() > 2 * MAX_REQUEST {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "oversized request"));
+        }
+    }
+    let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+    if let State::Greeting = conn.state {
+        match parse_greeting(&conn.buf) {
+            Parsed::Incomplete => return Ok(Vec::new()),
+            Parsed::Done(len, ()) => {
+                conn.buf.drain(..len);
+                try!(conn.relay.socket.write(&[VERSION, NO_AUTH]));
+                conn.state = State::Request;
+            }
+            Parsed::Invalid(code) => {
+                let _ = conn.relay.socket.write(&[VERSION, code]);
+                return Err(invalid("no acceptable method"));
+            }
+        }
+    }
+    match parse_request(&conn.buf) {
+        Parsed::Incomplete => Ok(Vec::new()),
+        Parsed::Done(_, target) => {
+            debug!("Proxy stream {} connects to {}.", stream, target);
+            conn.buf.clear();
+            conn.state = State::Opening {
+                target: target.clone(),
+                since: now,
+                sent: now,
+            };
+            Ok(vec![Outgoing::Open(stream, target)])
+        }
+        Parsed::Invalid(code) => {
+            let _ = conn.relay.socket.write(&reply(code));
+            Err(invalid("unsupported request"))
+        }
+    }
+}
+
+#[test]
+fn parse_test() {
+    assert_eq!(parse_greeting(&[5, 2, 2]), Parsed::Incomplete);
+    assert_eq!(parse_greeting(&[5, 2, 2, 0]), Parsed::Done(4, ()));
+    assert_eq!(parse_greeting(&[5, 1, 2]), Parsed::Invalid(NO_METHOD));
+    assert_eq!(parse_greeting(&[4, 1, 0]), Parsed::Invalid(NO_METHOD));
+
+    assert_eq!(parse_request(&[5, 1, 0, 1, 10, 0, 0, 1, 0]), Parsed::Incomplete);
+    assert_eq!(parse_request(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 80]),
+               Parsed::Done(10, String::from("10.0.0.1:80")));
+    let mut domain = vec![5, 1, 0, 3, 11];
+    domain.extend_from_slice(b"example.com");
+    domain.extend_from_slice(&[1, 187]);
+    assert_eq!(parse_request(&domain), Parsed::Done(18, String::from("example.com:443")));
+    let mut v6 = vec![5, 1, 0, 4];
+    v6.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 22]);
+    assert_eq!(parse_request(&v6), Parsed::Done(22, String::from("[fd00::1]:22")));
+    assert_eq!(parse_request(&[5, 2, 0, 1, 0]), Parsed::Invalid(UNSUPPORTED_COMMAND));
+    assert_eq!(parse_request(&[5, 1, 0, 9, 0]), Parsed::Invalid(UNSUPPORTED_ADDRESS));
+}
+
+#[test]
+fn parse_forward_test() {
+    assert_eq!(parse_forward("8080:example.com:80").unwrap(),
+               ("127.0.0.1:8080".parse().unwrap(), String::from("example.com:80")));
+    assert_eq!(parse_forward("0.0.0.0:2222:10.0.0.5:22").unwrap(),
+               ("0.0.0.0:2222".parse().unwrap(), String::from("10.0.0.5:22")));
+    assert_eq!(parse_forward("[::1]:5432:[fd00::2]:5432").unwrap(),
+               ("[::1]:5432".parse().unwrap(), String::from("[fd00::2]:5432")));
+    assert!(parse_forward("8080:80").is_err());
+    assert!(parse_forward("example.com:80").is_err());
+    assert!(parse_forward("8080::80").is_err());
+}
@@ -0,0 +1,162 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Settings the server pushes to clients in the handshake.
+//!
+//! Options travel in `Message::Configured` as a list of numbered entries
+//! with opaque bodies. A client decodes the kinds it knows and skips the
+//! rest, so new settings can be pushed without breaking older clients.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str;
+
+const ADDRESS: u16 = 1;
+const ROUTE: u16 = 2;
+const DNS: u16 = 3;
+const MTU: u16 = 4;
+const BANNER: u16 = 5;
+const CUSTOM: u16 = 6;
+const PREFIX: u16 = 7;
+
+/// An option as sent on the wire: its kind and encoded body.
+pub type Raw = (u16, Vec<u8>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PushOption {
+    /// The client's tunnel address and prefix length.
+    Address(Ipv4Addr, u8),
+    /// A network, written like `192.168.50.0/24`, to route into the tunnel.
+    Route(Ipv4Addr, u8),
+    /// A resolver to use while connected.
+    Dns(IpAddr),
+    /// An MTU below the negotiated one.
+    Mtu(u16),
+    /// Text to show when connecting.
+    Banner(String),
+    /// Anything else a deployment wants to tell its clients.
+    Custom(String, String),
+    /// An IPv6 prefix routed to the client, for its downstream networks.
+    Prefix(Ipv6Addr, u8),
+}
+
+fn ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.clone_from_slice(bytes);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+fn ipv4_prefix(body: &[u8]) -> Option<(Ipv4Addr, u8)> {
+    match (body.len(), ip(body.get(..4).unwrap_or(&[]))) {
+        (5, Some(IpAddr::V4(addr))) if body[4] <= 32 => Some((addr, body[4])),
+        _ => None,
+    }
+}
+
+impl PushOption {
+    pub fn encode(&self) -> Raw {
+        match *self {
+            PushOption::Address(addr, prefix) => {
+                let mut body = addr.octets().to_vec();
+                body.push(prefix);
+                (ADDRESS, body)
+            }
+            PushOption::Route(net, prefix) => {
+                let mut body = net.octets().to_vec();
+                body.push(prefix);
+                (ROUTE, body)
+            }
+            PushOption::Dns(IpAddr::V4(addr)) => (DNS, addr.octets().to_vec()),
+            PushOption::Dns(IpAddr::V6(addr)) => (DNS, addr.octets().to_vec()),
+            PushOption::Mtu(mtu) => (MTU, vec![(mtu >> 8) as u8, mtu as u8]),
+            PushOption::Banner(ref text) => (BANNER, text.clone().into_bytes()),
+            PushOption::Custom(ref key, ref value) => {
+                let mut body = key.clone().into_bytes();
+                body.push(0);
+                body.extend_from_slice(value.as_bytes());
+                (CUSTOM, body)
+            }
+            PushOption::Prefix(net, prefix) => {
+                let mut body = net.octets().to_vec();
+                body.push(prefix);
+                (PREFIX, body)
+            }
+        }
+    }
+
+    /// `None` for kinds this version does not know and malformed bodies.
+    pub fn decode(raw: &Raw) -> Option<PushOption> {
+        let body = &raw.1[..];
+        match raw.0 {
+            ADDRESS => ipv4_prefix(body).map(|(addr, prefix)| PushOption::Address(addr, prefix)),
+            ROUTE => ipv4_prefix(body).map(|(net, prefix)| PushOption::Route(net, prefix)),
+            DNS => ip(body).map(PushOption::Dns),
+            MTU if body.len() == 2 => Some(PushOption::Mtu((body[0] as u16) << 8 | body[1] as u16)),
+            BANNER => str::from_utf8(body).ok().map(|text| PushOption::Banner(String::from(text))),
+            CUSTOM => {
+                let split = match body.iter().position(|&b| b == 0) {
+                    Some(split) => split,
+                    None => return None,
+                };
+                match (str::from_utf8(&body[..split]), str::from_utf8(&body[split + 1..])) {
+                    (Ok(key), Ok(value)) => {
+                        Some(PushOption::Custom(String::from(key), String::from(value)))
+                    }
+                    _ => None,
+                }
+            }
+            PREFIX if body.len() == 17 && body[16] <= 128 => {
+                match ip(&body[..16]) {
+                    Some(IpAddr::V6(net)) => Some(PushOption::Prefix(net, body[16])),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Decodes every option this version understands, skipping the others.
+pub fn decode_all(options: &[Raw]) -> Vec<PushOption> {
+    options.iter()
+        .filter_map(|raw| {
+            let option = PushOption::decode(raw);
+            if option.is_none() {
+                debug!("Ignoring pushed option of kind {}.", raw.0);
+            }
+            option
+        })
+        .collect()
+}
+
+#[test]
+fn push_test() {
+    let options = vec![PushOption::Address(Ipv4Addr::new(10, 10, 10, 2), 24),
+                       PushOption::Route(Ipv4Addr::new(192, 168, 50, 0), 24),
+                       PushOption::Dns("10.10.10.1".parse().unwrap()),
+                       PushOption::Dns("fd00::1".parse().unwrap()),
+                       PushOption::Mtu(1280),
+                       PushOption::Banner(String::from("Welcome.")),
+                       PushOption::Custom(String::from("site"), String::from("berlin")),
+                       PushOption::Prefix("2001:db8:100:5::".parse().unwrap(), 64)];
+    let mut raw: Vec<Raw> = options.iter().map(|option| option.encode()).collect();
+    raw.insert(1, (999, vec![1, 2, 3]));
+    raw.push((ROUTE, vec![10, 0, 0]));
+    assert_eq!(decode_all(&raw), options);
+}
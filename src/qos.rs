@@ -0,0 +1,208 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Priority classes for traffic to clients.
+//!
+//! When the bandwidth cap or a client's rate limit holds packets back, they
+//! wait in one queue per class and client, and more urgent queues are
+//! emptied first. Rules are written `CLASS PROTO[:PORT[-PORT]] [from ID]`,
+//! for example `interactive tcp:22` or `bulk tcp:873`, with the targets of
+//! the `acl` module. Packets that match no rule are classified by DSCP: CS4
+//! and above, which covers EF and AF4x, is interactive, and CS1 is bulk.
+//!
+//! A client that cannot keep up fills its queues. Then either the new packet
+//! is dropped (tail drop) or the oldest of the least urgent class makes room
+//! for it (head drop), which favours fresh data over stale.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use acl;
+use packet;
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    Interactive = 0,
+    Normal = 1,
+    Bulk = 2,
+}
+
+/// What goes when a client's queues are full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropPolicy {
+    Tail,
+    Head,
+}
+
+impl FromStr for DropPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DropPolicy> {
+        match s {
+            "tail" => Ok(DropPolicy::Tail),
+            "head" => Ok(DropPolicy::Head),
+            _ => Err(Error::Config(format!("unknown drop policy {:?}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rule {
+    pub class: Class,
+    pub target: acl::Rule,
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rule> {
+        let s = s.trim();
+        let split = s.find(char::is_whitespace).unwrap_or(s.len());
+        let class = match &s[..split] {
+            "interactive" => Class::Interactive,
+            "normal" => Class::Normal,
+            "bulk" => Class::Bulk,
+            class => return Err(Error::Config(format!("unknown class {:?}", class))),
+        };
+        Ok(Rule {
+            class: class,
+            target: try!(format!("allow {}", &s[split..]).parse()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Classifier {
+    rules: Vec<Rule>,
+}
+
+impl Classifier {
+    /// Parses one rule per string.
+    pub fn parse<S: AsRef<str>>(rules: &[S]) -> Result<Classifier> {
+        let mut parsed = Vec::with_capacity(rules.len());
+        for rule in rules {
+            parsed.push(try!(rule.as_ref().parse()));
+        }
+        Ok(Classifier { rules: parsed })
+    }
+
+    /// Class of a packet headed to `client`.
+    pub fn classify(&self, client: u8, data: &[u8]) -> Class {
+        if let Ok((protocol, port)) = packet::transport(data) {
+            let rule = self.rules.iter().find(|rule| rule.target.matches(client, protocol, port));
+            if let Some(rule) = rule {
+                return rule.class;
+            }
+        }
+        match packet::dscp(data) {
+            Some(dscp) if dscp >= 32 => Class::Interactive,
+            Some(8) => Class::Bulk,
+            _ => Class::Normal,
+        }
+    }
+}
+
+/// Packets waiting for one client, by class.
+#[derive(Debug, Default)]
+pub struct Queues {
+    queues: [VecDeque<Vec<u8>>; 3],
+    bytes: usize,
+}
+
+impl Queues {
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0
+    }
+
+    /// Queues `packet` unless that would take the queues over `limit`
+    /// bytes, in which case `policy` decides what is dropped. Returns the
+    /// number of packets dropped.
+    pub fn push(&mut self, class: Class, packet: &[u8], limit: usize, policy: DropPolicy)
+                -> usize {
+        if packet.len() > limit {
+            return 1;
+        }
+        let mut dropped = 0;
+        while self.bytes + packet.len() > limit {
+            if policy == DropPolicy::Tail {
+                return 1;
+            }
+            let oldest = self.queues.iter_mut().rev().filter_map(|queue| queue.pop_front()).next();
+            self.bytes -= oldest.unwrap().len();
+            dropped += 1;
+        }
+        self.bytes += packet.len();
+        self.queues[class as usize].push_back(packet.to_vec());
+        dropped
+    }
+
+    /// The most urgent waiting packet.
+    pub fn front(&self) -> Option<&[u8]> {
+        self.queues.iter().filter_map(|queue| queue.front()).next().map(|packet| &packet[..])
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let packet = self.queues.iter_mut().filter_map(|queue| queue.pop_front()).next();
+        if let Some(ref packet) = packet {
+            self.bytes -= packet.len();
+        }
+        packet
+    }
+}
+
+#[test]
+fn classify_test() {
+    let classifier = Classifier::parse(&["interactive tcp:22", "bulk tcp:873 from 5"]).unwrap();
+    let mut tcp = [0u8; 40];
+    tcp[0] = 0x45;
+    tcp[9] = packet::IPPROTO_TCP;
+    tcp[22..24].clone_from_slice(&[0, 22]);
+    assert_eq!(classifier.classify(2, &tcp), Class::Interactive);
+    tcp[22..24].clone_from_slice(&[3, 105]);
+    assert_eq!(classifier.classify(2, &tcp), Class::Normal);
+    assert_eq!(classifier.classify(5, &tcp), Class::Bulk);
+    tcp[1] = 46 << 2;
+    assert_eq!(classifier.classify(2, &tcp), Class::Interactive);
+    tcp[1] = 8 << 2;
+    assert_eq!(classifier.classify(2, &tcp), Class::Bulk);
+    assert!("urgent tcp:22".parse::<Rule>().is_err());
+    assert!("bulk".parse::<Rule>().is_err());
+}
+
+#[test]
+fn queues_test() {
+    let mut queues = Queues::default();
+    assert!(queues.front().is_none());
+    let tail = DropPolicy::Tail;
+    assert_eq!(queues.push(Class::Bulk, &[1; 100], 1000, tail), 0);
+    assert_eq!(queues.push(Class::Normal, &[2; 100], 1000, tail), 0);
+    assert_eq!(queues.push(Class::Interactive, &[3; 100], 1000, tail), 0);
+    assert_eq!(queues.front(), Some(&[3u8; 100][..]));
+    assert_eq!(queues.pop().unwrap()[0], 3);
+    assert_eq!(queues.pop().unwrap()[0], 2);
+    assert_eq!(queues.push(Class::Normal, &[0; 901], 1000, tail), 1);
+    assert_eq!(queues.pop().unwrap()[0], 1);
+    assert!(queues.is_empty());
+
+    let head = DropPolicy::Head;
+    assert_eq!(queues.push(Class::Bulk, &[1; 400], 1000, head), 0);
+    assert_eq!(queues.push(Class::Interactive, &[3; 400], 1000, head), 0);
+    assert_eq!(queues.push(Class::Normal, &[2; 400], 1000, head), 1);
+    assert_eq!(queues.pop().unwrap()[0], 3);
+    assert_eq!(queues.pop().unwrap()[0], 2);
+    assert!(queues.is_empty());
+    assert_eq!(queues.push(Class::Normal, &[0; 1001], 1000, head), 1);
+    assert_eq!("head".parse::<DropPolicy>().unwrap(), head);
+    assert!("random".parse::<DropPolicy>().is_err());
+}
@@ -0,0 +1,281 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! RADIUS authentication (RFC 2865).
+//!
+//! Users are checked with a PAP Access-Request. Requests carry a
+//! Message-Authenticator (RFC 3579), and replies must carry both a valid
+//! Response Authenticator and a valid Message-Authenticator. The MD5 of the
+//! Response Authenticator alone can be forged by a man in the middle
+//! (Blast-RADIUS, CVE-2024-3596), so a reply without the HMAC is not
+//! believed.
+
+use std::cmp;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::{Rng, StdRng};
+use auth::Authenticator;
+use error::{Error, Result};
+
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+
+const USER_NAME: u8 = 1;
+const USER_PASSWORD: u8 = 2;
+const NAS_IDENTIFIER: u8 = 32;
+const MESSAGE_AUTHENTICATOR: u8 = 80;
+
+pub struct Config {
+    pub server: SocketAddr,
+    pub secret: String,
+    /// How long to wait for each attempt.
+    pub timeout: Duration,
+    pub attempts: u32,
+    pub nas_identifier: String,
+}
+
+impl Config {
+    pub fn new(server: SocketAddr, secret: &str) -> Config {
+        Config {
+            server: server,
+            secret: String::from(secret),
+            timeout: Duration::from_secs(3),
+            attempts: 3,
+            nas_identifier: String::from("kytan"),
+        }
+    }
+}
+
+pub struct Radius {
+    config: Config,
+    socket: UdpSocket,
+    rng: StdRng,
+    id: u8,
+}
+
+impl Radius {
+    pub fn open(config: Config) -> Result<Radius> {
+        let local = if config.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = try!(UdpSocket::bind(local));
+        try!(socket.connect(config.server));
+        try!(socket.set_read_timeout(Some(config.timeout)));
+        Ok(Radius {
+            config: config,
+            socket: socket,
+            rng: try!(StdRng::new()),
+            id: 0,
+        })
+    }
+}
+
+impl Authenticator for Radius {
+    fn authenticate(&mut self, user: &str, password: &str) -> Result<bool> {
+        self.id = self.id.wrapping_add(1);
+        let mut authenticator = [0u8; 16];
+        self.rng.fill_bytes(&mut authenticator);
+        let request = access_request(self.id,
+                                     &authenticator,
+                                     &self.config.secret,
+                                     &self.config.nas_identifier,
+                                     user,
+                                     password);
+        let mut buf = [0u8; 4096];
+        for _ in 0..self.config.attempts {
+            try!(self.socket.send(&request));
+            loop {
+                let len = match self.socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(_) => break,
+                };
+                let reply = &buf[..len];
+                if len < 20 || reply[1] != self.id ||
+                   !response_valid(reply, &authenticator, &self.config.secret) {
+                    // A late reply to an earlier request, or forged.
+                    continue;
+                }
+                return match reply[0] {
+                    ACCESS_ACCEPT => Ok(true),
+                    ACCESS_REJECT => Ok(false),
+                    code => Err(Error::Handshake(format!("unexpected RADIUS code {}", code))),
+                };
+            }
+        }
+        Err(Error::Handshake(format!("no answer from RADIUS server {}", self.config.server)))
+    }
+}
+
+fn attribute(packet: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    packet.push(kind);
+    packet.push(value.len() as u8 + 2);
+    packet.extend_from_slice(value);
+}
+
+/// Hides `password` as described in RFC 2865, section 5.2.
+fn hide_password(password: &[u8], authenticator: &[u8; 16], secret: &str) -> Vec<u8> {
+    let len = cmp::max(16, (password.len() + 15) / 16 * 16);
+    let mut hidden = password.to_vec();
+    hidden.resize(len, 0);
+    let mut last = authenticator.to_vec();
+    for chunk in hidden.chunks_mut(16) {
+        let pad = Md5::new().chain_update(secret).chain_update(&last).finalize();
+        for (byte, key) in chunk.iter_mut().zip(pad.iter()) {
+            *byte ^= *key;
+        }
+        last = chunk.to_vec();
+    }
+    hidden
+}
+
+fn access_request(id: u8,
+                  authenticator: &[u8; 16],
+                  secret: &str,
+                  nas_identifier: &str,
+                  user: &str,
+                  password: &str)
+                  -> Vec<u8> {
+    let mut packet = vec![ACCESS_REQUEST, id, 0, 0];
+    packet.extend_from_slice(authenticator);
+    attribute(&mut packet, USER_NAME, &user.as_bytes()[..cmp::min(user.len(), 253)]);
+    let hidden = hide_password(&password.as_bytes()[..cmp::min(password.len(), 128)],
+                               authenticator,
+                               secret);
+    attribute(&mut packet, USER_PASSWORD, &hidden);
+    attribute(&mut packet, NAS_IDENTIFIER, nas_identifier.as_bytes());
+    let mac_at = packet.len() + 2;
+    attribute(&mut packet, MESSAGE_AUTHENTICATOR, &[0; 16]);
+    let len = packet.len();
+    packet[2] = (len >> 8) as u8;
+    packet[3] = len as u8;
+    let mac = message_mac(&packet, mac_at, authenticator, secret).finalize().into_bytes();
+    packet[mac_at..mac_at + 16].copy_from_slice(&mac);
+    packet
+}
+
+/// The HMAC a Message-Authenticator whose value starts at `at` must carry:
+/// over `packet` with `authenticator`, that of the request, in its header
+/// and the value itself zeroed (RFC 3579, section 3.2).
+fn message_mac(packet: &[u8], at: usize, authenticator: &[u8; 16], secret: &str) -> Hmac<Md5> {
+    let mut input = packet.to_vec();
+    input[4..20].copy_from_slice(authenticator);
+    for byte in &mut input[at..at + 16] {
+        *byte = 0;
+    }
+    Hmac::<Md5>::new_from_slice(secret.as_bytes())
+        .expect("HMAC takes keys of any length")
+        .chain_update(&input)
+}
+
+/// Where the value of the Message-Authenticator of `packet` starts, if it
+/// has exactly one and its attributes are well-formed.
+fn find_message_authenticator(packet: &[u8]) -> Option<usize> {
+    let mut found = None;
+    let mut at = 20;
+    while at < packet.len() {
+        if at + 2 > packet.len() {
+            return None;
+        }
+        let len = packet[at + 1] as usize;
+        if len < 2 || at + len > packet.len() {
+            return None;
+        }
+        if packet[at] == MESSAGE_AUTHENTICATOR {
+            if len != 18 || found.is_some() {
+                return None;
+            }
+            found = Some(at + 2);
+        }
+        at += len;
+    }
+    found
+}
+
+/// Checks the Response Authenticator and the Message-Authenticator of
+/// `reply` to a request that carried `authenticator`.
+fn response_valid(reply: &[u8], authenticator: &[u8; 16], secret: &str) -> bool {
+    let len = (reply[2] as usize) << 8 | reply[3] as usize;
+    if len < 20 || len > reply.len() {
+        return false;
+    }
+    let reply = &reply[..len];
+    let mac_at = match find_message_authenticator(reply) {
+        Some(at) => at,
+        None => return false,
+    };
+    if message_mac(reply, mac_at, authenticator, secret)
+        .verify_slice(&reply[mac_at..mac_at + 16])
+        .is_err() {
+        return false;
+    }
+    let expected = Md5::new()
+        .chain_update(&reply[..4])
+        .chain_update(authenticator)
+        .chain_update(&reply[20..len])
+        .chain_update(secret)
+        .finalize();
+    expected[..] == reply[4..20]
+}
+
+#[test]
+fn access_request_test() {
+    let authenticator = [7u8; 16];
+    let packet = access_request(1, &authenticator, "s3cret", "kytan", "alice", "hunter2");
+    assert_eq!(packet[0], ACCESS_REQUEST);
+    assert_eq!((packet[2] as usize) << 8 | packet[3] as usize, packet.len());
+    // The password is hidden and padded to 16 bytes.
+    let hidden = hide_password(b"hunter2", &authenticator, "s3cret");
+    assert_eq!(hidden.len(), 16);
+    assert!(!packet.windows(7).any(|w| w == b"hunter2"));
+    // A single block is hidden with the same pad both times.
+    let restored = hide_password(&hidden, &authenticator, "s3cret");
+    assert_eq!(&restored[..7], b"hunter2");
+
+    let mac_at = packet.len() - 16;
+    assert_eq!(find_message_authenticator(&packet), Some(mac_at));
+    assert!(message_mac(&packet, mac_at, &authenticator, "s3cret")
+        .verify_slice(&packet[mac_at..])
+        .is_ok());
+
+    // The Response Authenticator, which an attacker may be able to forge.
+    let answer = |mut reply: Vec<u8>| {
+        let len = reply.len();
+        reply[2] = (len >> 8) as u8;
+        reply[3] = len as u8;
+        let digest = Md5::new()
+            .chain_update(&reply[..4])
+            .chain_update(&authenticator)
+            .chain_update(&reply[20..])
+            .chain_update("s3cret")
+            .finalize();
+        reply[4..20].copy_from_slice(&digest);
+        reply
+    };
+    let mut reply = vec![ACCESS_ACCEPT, 1, 0, 0];
+    reply.extend_from_slice(&[0; 16]);
+    let bare = answer(reply.clone());
+    attribute(&mut reply, MESSAGE_AUTHENTICATOR, &[0; 16]);
+    let len = reply.len();
+    reply[3] = len as u8;
+    let mac = message_mac(&reply, 22, &authenticator, "s3cret").finalize().into_bytes();
+    reply[22..38].copy_from_slice(&mac);
+    let signed = answer(reply.clone());
+    assert!(response_valid(&signed, &authenticator, "s3cret"));
+    assert!(!response_valid(&signed, &authenticator, "guess"));
+    // A valid Response Authenticator is not enough without the HMAC.
+    assert!(!response_valid(&bare, &authenticator, "s3cret"));
+    reply[22] ^= 1;
+    assert!(!response_valid(&answer(reply), &authenticator, "s3cret"));
+}
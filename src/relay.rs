@@ -0,0 +1,294 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relay nodes for multi-hop connections.
+//!
+//! A client can reach its server through one or more relays: it wraps each
+//! frame in a `Relay` message naming a circuit and the next hop, once per
+//! relay, innermost for the last. A relay unwraps one layer and sends the
+//! rest to the next hop from a socket of its own for that circuit, then
+//! wraps whatever comes back. The exit server thus only sees the last
+//! relay, and each relay only its neighbours. Frames are not encrypted, so
+//! a relay that reads them can still tell what the session carries.
+//!
+//! A relay only forwards to the exits it is configured with, so it cannot be
+//! used as an open proxy, and forgets circuits idle for `CIRCUIT_TIMEOUT`
+//! seconds.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use mio;
+use network::*;
+use error::{Error, Result};
+
+/// Seconds of silence after which a circuit is dropped.
+pub const CIRCUIT_TIMEOUT: u64 = 300;
+/// Circuits a relay carries at once.
+pub const MAX_CIRCUITS: usize = 4096;
+
+const CIRCUIT_BASE: usize = 16;
+
+/// Wraps `frame` for a path through `hops`, each a relay and the circuit
+/// through it, ending at `exit`. Returns where to send the result.
+pub fn wrap(hops: &[(SocketAddr, u64)], exit: SocketAddr, frame: Vec<u8>) -> Result<Vec<u8>> {
+    let mut frame = frame;
+    let mut next = exit;
+    for &(relay, circuit) in hops.iter().rev() {
+        frame = try!(encode_message(&Message::Relay {
+            circuit: circuit,
+            exit: next,
+            frame: frame,
+        }));
+        next = relay;
+    }
+    Ok(frame)
+}
+
+/// Undoes `wrap()` for a frame coming back through `hops` from `exit`.
+pub fn unwrap(hops: &[(SocketAddr, u64)], exit: SocketAddr, frame: &[u8]) -> Result<Vec<u8>> {
+    let mut frame = frame.to_vec();
+    for (i, &(_, circuit)) in hops.iter().enumerate() {
+        let next = hops.get(i + 1).map_or(exit, |&(relay, _)| relay);
+        frame = match try!(decode_message(&frame)) {
+            Message::Relay { circuit: c, exit: e, frame } if c == circuit && e == next => frame,
+            _ => return Err(Error::Decode(String::from("frame from the wrong circuit"))),
+        };
+    }
+    Ok(frame)
+}
+
+pub struct RelayBuilder {
+    port: u16,
+    exits: Vec<SocketAddr>,
+}
+
+impl RelayBuilder {
+    pub fn port(mut self, port: u16) -> RelayBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Allow circuits to `exit`. A relay needs at least one.
+    pub fn exit(mut self, exit: SocketAddr) -> RelayBuilder {
+        self.exits.push(exit);
+        self
+    }
+
+    pub fn build(self) -> Result<Relay> {
+        if self.exits.is_empty() {
+            return Err(Error::Config(String::from("a relay needs at least one exit")));
+        }
+        let any = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let sockfd = try!(mio::udp::UdpSocket::bind(&SocketAddr::new(any, self.port)));
+        let poll = try!(mio::Poll::new());
+        try!(poll.register(&sockfd, SOCK, mio::Ready::readable(), mio::PollOpt::level()));
+        let (shutdown, registration) = ShutdownHandle::new();
+        try!(poll.register(&registration,
+                           SHUTDOWN,
+                           mio::Ready::readable(),
+                           mio::PollOpt::edge()));
+        try!(register_signal(&poll));
+        Ok(Relay {
+            exits: self.exits,
+            sockfd: sockfd,
+            poll: poll,
+            shutdown: shutdown,
+            _registration: registration,
+            circuits: HashMap::new(),
+            by_client: HashMap::new(),
+            next_token: CIRCUIT_BASE,
+            buf: vec![0u8; 65536],
+        })
+    }
+}
+
+struct Circuit {
+    client: SocketAddr,
+    id: u64,
+    exit: SocketAddr,
+    socket: mio::udp::UdpSocket,
+    last_active: Instant,
+}
+
+/// A relay between clients and the exits it allows.
+pub struct Relay {
+    exits: Vec<SocketAddr>,
+    sockfd: mio::udp::UdpSocket,
+    poll: mio::Poll,
+    shutdown: ShutdownHandle,
+    _registration: mio::Registration,
+    // Circuits by the poll token of their socket, and tokens by client.
+    circuits: HashMap<usize, Circuit>,
+    by_client: HashMap<(SocketAddr, u64), usize>,
+    next_token: usize,
+    buf: Vec<u8>,
+}
+
+impl Relay {
+    pub fn builder() -> RelayBuilder {
+        RelayBuilder {
+            port: 8964,
+            exits: Vec::new(),
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Relays frames until shut down.
+    pub fn run(&mut self) -> Result<()> {
+        info!("Working in relay mode, exits: {:?}.", self.exits);
+        let mut events = mio::Events::with_capacity(1024);
+        while !self.shutdown.is_shutdown() {
+            try!(self.poll.poll(&mut events, Some(Duration::from_secs(CIRCUIT_TIMEOUT / 10))));
+            for event in events.iter() {
+                match event.token() {
+                    SOCK => try!(self.handle_client()),
+                    SHUTDOWN | SIGNAL | STATUS => {}
+                    mio::Token(token) => try!(self.handle_exit(token)),
+                }
+            }
+            self.expire(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn handle_client(&mut self) -> Result<()> {
+        let (len, addr) = match try!(self.sockfd.recv_from(&mut self.buf)) {
+            Some(received) => received,
+            None => return Ok(()),
+        };
+        let (circuit, exit, frame) = match decode_message(&self.buf[..len]) {
+            Ok(Message::Relay { circuit, exit, frame }) => (circuit, exit, frame),
+            _ => {
+                debug!("Dropping a frame from {} that is not relayed.", addr);
+                return Ok(());
+            }
+        };
+        if !self.exits.contains(&exit) {
+            debug!("Refusing to relay from {} to {}.", addr, exit);
+            return Ok(());
+        }
+        let token = match self.by_client.get(&(addr, circuit)) {
+            Some(&token) => token,
+            None => {
+                match self.open(addr, circuit, exit) {
+                    Ok(token) => token,
+                    Err(e) => {
+                        warn!("Failed to open circuit {} from {}: {}", circuit, addr, e);
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        let circuit = match self.circuits.get_mut(&token) {
+            Some(circuit) if circuit.exit == exit => circuit,
+            _ => return Ok(()),
+        };
+        circuit.last_active = Instant::now();
+        match send_raw(&circuit.socket, &frame, &exit) {
+            Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    fn open(&mut self, client: SocketAddr, id: u64, exit: SocketAddr) -> Result<usize> {
+        if self.circuits.len() >= MAX_CIRCUITS {
+            return Err(Error::Config(String::from("too many circuits")));
+        }
+        let any = match exit {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            SocketAddr::V6(_) => "::".parse().unwrap(),
+        };
+        let socket = try!(mio::udp::UdpSocket::bind(&SocketAddr::new(any, 0)));
+        let token = self.next_token;
+        self.next_token += 1;
+        try!(self.poll.register(&socket,
+                                mio::Token(token),
+                                mio::Ready::readable(),
+                                mio::PollOpt::level()));
+        info!("Circuit {} from {} to {} opened.", id, client, exit);
+        self.circuits.insert(token,
+                             Circuit {
+                                 client: client,
+                                 id: id,
+                                 exit: exit,
+                                 socket: socket,
+                                 last_active: Instant::now(),
+                             });
+        self.by_client.insert((client, id), token);
+        Ok(token)
+    }
+
+    fn handle_exit(&mut self, token: usize) -> Result<()> {
+        let circuit = match self.circuits.get_mut(&token) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+        let (len, addr) = match try!(circuit.socket.recv_from(&mut self.buf)) {
+            Some(received) => received,
+            None => return Ok(()),
+        };
+        if addr != circuit.exit {
+            return Ok(());
+        }
+        circuit.last_active = Instant::now();
+        let buf = try!(encode_message(&Message::Relay {
+            circuit: circuit.id,
+            exit: circuit.exit,
+            frame: self.buf[..len].to_vec(),
+        }));
+        match send_raw(&self.sockfd, &buf, &circuit.client) {
+            Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = Duration::from_secs(CIRCUIT_TIMEOUT);
+        let idle: Vec<usize> = self.circuits
+            .iter()
+            .filter(|&(_, circuit)| now.duration_since(circuit.last_active) >= timeout)
+            .map(|(&token, _)| token)
+            .collect();
+        for token in idle {
+            if let Some(circuit) = self.circuits.remove(&token) {
+                info!("Circuit {} from {} expired.", circuit.id, circuit.client);
+                let _ = self.poll.deregister(&circuit.socket);
+                self.by_client.remove(&(circuit.client, circuit.id));
+            }
+        }
+    }
+}
+
+#[test]
+fn wrap_test() {
+    let exit: SocketAddr = "192.0.2.1:8964".parse().unwrap();
+    let hops: Vec<(SocketAddr, u64)> = vec![("192.0.2.2:8964".parse().unwrap(), 7),
+                                            ("192.0.2.3:8964".parse().unwrap(), 9)];
+    let frame = vec![1u8, 2, 3];
+    let wrapped = wrap(&hops, exit, frame.clone()).unwrap();
+    assert_eq!(unwrap(&hops, exit, &wrapped).unwrap(), frame);
+    match decode_message(&wrapped).unwrap() {
+        Message::Relay { circuit, exit: next, .. } => {
+            assert_eq!(circuit, 7);
+            assert_eq!(next, hops[1].0);
+        }
+        msg => panic!("unexpected {:?}", msg),
+    }
+    assert!(unwrap(&hops[..1], exit, &wrapped).is_err());
+    assert_eq!(wrap(&[], exit, frame.clone()).unwrap(), frame);
+}
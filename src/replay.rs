@@ -0,0 +1,143 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handshakes that prove a credential without revealing it.
+//!
+//! A stamped request carries the time it was made, a random nonce and an
+//! HMAC-SHA256 over both, keyed with the group secret. The server accepts each
+//! stamp once and only while it is fresh, so a captured handshake can
+//! neither be replayed later to take over an address nor used to learn the
+//! secret.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use network::{Id, Token};
+
+/// How far a stamp's time may be behind the server's clock.
+pub const WINDOW: u64 = 60;
+/// How far it may be ahead. Kept small, since a full cache refuses every
+/// stamp up to the newest it forgot.
+pub const SKEW: u64 = 5;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Stamp {
+    /// Seconds since the epoch.
+    pub timestamp: u64,
+    pub nonce: u64,
+    pub mac: Vec<u8>,
+}
+
+pub fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs()
+}
+
+fn mac(secret: &str, timestamp: u64, nonce: u64, resume: Option<(Id, Token)>) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(b"kytan-stamp");
+    for value in &[timestamp, nonce, resume.map_or(0, |(_, token)| token)] {
+        let bytes: Vec<u8> = (0..8).map(|i| (value >> (56 - 8 * i)) as u8).collect();
+        mac.update(&bytes);
+    }
+    mac.update(&[resume.map_or(0, |(id, _)| id)]);
+    mac
+}
+
+impl Stamp {
+    /// Stamps a request that resumes `resume`, if any, now.
+    pub fn new(secret: &str, nonce: u64, resume: Option<(Id, Token)>) -> Stamp {
+        let timestamp = unix_secs();
+        Stamp {
+            timestamp: timestamp,
+            nonce: nonce,
+            mac: mac(secret, timestamp, nonce, resume).finalize().into_bytes().to_vec(),
+        }
+    }
+
+    /// Whether the stamp was made with `secret`.
+    pub fn verify(&self, secret: &str, resume: Option<(Id, Token)>) -> bool {
+        mac(secret, self.timestamp, self.nonce, resume).verify_slice(&self.mac).is_ok()
+    }
+}
+
+/// Verified stamps seen within the window. Once it holds `capacity` stamps
+/// the oldest are forgotten, and stamps no newer than those are refused from
+/// then on, since they could be replays. Whoever fills a cache can lock out
+/// the others using it until the present passes that floor, at most `SKEW`
+/// seconds. The server therefore keeps a cache for each group, whose members
+/// share a secret anyway, and since anyone can prove a key of their own, it
+/// keeps proofs of configured devices apart from those of strangers, who
+/// only crowd out each other.
+pub struct Cache {
+    seen: BTreeSet<(u64, u64)>,
+    capacity: usize,
+    // Timestamp of the newest stamp forgotten to make room.
+    floor: u64,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Cache {
+        Cache {
+            seen: BTreeSet::new(),
+            capacity: capacity,
+            floor: 0,
+        }
+    }
+
+    /// Whether a stamp made at `timestamp` with `nonce` is fresh and new at
+    /// `now`.
+    pub fn check(&mut self, timestamp: u64, nonce: u64, now: u64) -> bool {
+        self.seen = self.seen.split_off(&(now.saturating_sub(WINDOW), 0));
+        // The timestamp comes from the peer and may be anything.
+        if timestamp.saturating_add(WINDOW) < now || timestamp > now.saturating_add(SKEW) ||
+           timestamp <= self.floor || !self.seen.insert((timestamp, nonce)) {
+            return false;
+        }
+        while self.seen.len() > self.capacity {
+            let oldest = *self.seen.iter().next().unwrap();
+            self.seen.remove(&oldest);
+            self.floor = oldest.0;
+        }
+        true
+    }
+}
+
+#[test]
+fn stamp_test() {
+    let stamp = Stamp::new("s3cret", 42, Some((3, 7)));
+    assert_eq!(stamp.mac.len(), 32);
+    assert!(stamp.verify("s3cret", Some((3, 7))));
+    assert!(!stamp.verify("s3cret", None));
+    assert!(!stamp.verify("guess", Some((3, 7))));
+
+    let mut cache = Cache::new(3);
+    assert!(cache.check(1000, 1, 1000));
+    assert!(!cache.check(1000, 1, 1010));
+    assert!(cache.check(1000, 2, 1010));
+    assert!(!cache.check(900, 3, 1010));
+    assert!(!cache.check(1100, 4, 1010));
+    assert!(!cache.check(1016, 4, 1010));
+    assert!(!cache.check(u64::max_value(), 5, 1010));
+    assert!(cache.check(1010, 5, 1010));
+    // A full cache forgets the oldest stamp and refuses any as old.
+    assert!(cache.check(1010, 6, 1010));
+    assert!(!cache.check(1000, 1, 1010));
+    assert!(!cache.check(1000, 3, 1010));
+    assert!(cache.check(1005, 7, 1010));
+    assert!(!cache.check(1000, 2, 1010));
+    assert!(!cache.check(1010, 6, 1070));
+    assert!(cache.check(1070, 8, 1070));
+}
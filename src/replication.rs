@@ -0,0 +1,262 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Session replication to a hot-standby server.
+//!
+//! The primary periodically sends a snapshot of its sessions to the standby,
+//! which installs them so that it can take over the server's address and keep
+//! serving clients without new handshakes. Snapshots are sealed with
+//! ChaCha20-Poly1305 under a key derived from a shared secret with HKDF, so
+//! the tokens and roaming keys they carry are safe on any link. Each carries
+//! the primary's epoch, drawn afresh whenever the primary starts, and a
+//! counter within it; the standby drops snapshots that do not advance the
+//! counter and those of epochs it has left, so they cannot be replayed.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use bincode::Infinite;
+use bincode::serialize as encode;
+use bincode::deserialize as decode;
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use mio;
+use rand::{self, Rng};
+use network::{Capabilities, Id, Token};
+use roaming;
+use error::{Error, Result};
+
+/// How often the primary sends a snapshot.
+pub const SYNC_INTERVAL: u64 = 1;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Epochs of earlier runs of the primary the standby remembers.
+const MAX_RETIRED_EPOCHS: usize = 64;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct Session {
+    pub id: Id,
+    pub token: Token,
+    pub addr: SocketAddr,
+    pub caps: Capabilities,
+    pub group: usize,
+    pub roaming: Option<roaming::Binding>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Snapshot {
+    epoch: u64,
+    seq: u64,
+    sessions: Vec<Session>,
+}
+
+#[derive(Clone, Copy)]
+struct Key([u8; 32]);
+
+impl Key {
+    fn derive(secret: &str) -> Key {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(&b"kytan-replication"[..]), secret.as_bytes())
+            .expand(b"snapshot key", &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Key(key)
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new_from_slice(&self.0).expect("ChaCha20-Poly1305 takes 32-byte keys")
+    }
+}
+
+/// The nonce followed by the sealed snapshot and its tag.
+fn seal(key: Key, snapshot: &Snapshot) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let body = try!(encode(snapshot, Infinite).map_err(|e| Error::Decode(e.to_string())));
+    let sealed = try!(key.cipher()
+        .encrypt(Nonce::from_slice(&nonce), &body[..])
+        .map_err(|_| Error::Decode(String::from("cannot seal snapshot"))));
+    let mut buf = nonce.to_vec();
+    buf.extend_from_slice(&sealed);
+    Ok(buf)
+}
+
+fn open(key: Key, buf: &[u8]) -> Result<Snapshot> {
+    if buf.len() < NONCE_LEN + TAG_LEN {
+        return Err(Error::Decode(String::from("truncated snapshot")));
+    }
+    let (nonce, sealed) = buf.split_at(NONCE_LEN);
+    let body = try!(key.cipher()
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| Error::Decode(String::from("bad snapshot tag"))));
+    decode(&body).map_err(|e| Error::Decode(e.to_string()))
+}
+
+pub struct Primary {
+    socket: mio::udp::UdpSocket,
+    peer: SocketAddr,
+    key: Key,
+    epoch: u64,
+    seq: u64,
+    last_sync: Option<Instant>,
+}
+
+impl Primary {
+    pub fn open(peer: SocketAddr, secret: &str) -> Result<Primary> {
+        let local = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        Ok(Primary {
+            socket: try!(mio::udp::UdpSocket::bind(&local.parse().unwrap())),
+            peer: peer,
+            key: Key::derive(secret),
+            epoch: rand::random(),
+            seq: 0,
+            last_sync: None,
+        })
+    }
+
+    /// Sends `sessions` if a snapshot is due.
+    pub fn sync(&mut self, sessions: Vec<Session>, now: Instant) -> Result<()> {
+        if let Some(last) = self.last_sync {
+            if now.duration_since(last) < Duration::from_secs(SYNC_INTERVAL) {
+                return Ok(());
+            }
+        }
+        self.last_sync = Some(now);
+        self.seq += 1;
+        let snapshot = Snapshot {
+            epoch: self.epoch,
+            seq: self.seq,
+            sessions: sessions,
+        };
+        let buf = try!(seal(self.key, &snapshot));
+        try!(self.socket.send_to(&buf, &self.peer));
+        Ok(())
+    }
+}
+
+pub struct Standby {
+    socket: mio::udp::UdpSocket,
+    key: Key,
+    epoch: Option<u64>,
+    seq: u64,
+    retired: VecDeque<u64>,
+    buf: Vec<u8>,
+}
+
+impl Standby {
+    pub fn open(poll: &mio::Poll,
+                token: mio::Token,
+                listen: SocketAddr,
+                secret: &str)
+                -> Result<Standby> {
+        let socket = try!(mio::udp::UdpSocket::bind(&listen));
+        try!(poll.register(&socket, token, mio::Ready::readable(), mio::PollOpt::level()));
+        Ok(Standby {
+            socket: socket,
+            key: Key::derive(secret),
+            epoch: None,
+            seq: 0,
+            retired: VecDeque::new(),
+            buf: vec![0u8; 65536],
+        })
+    }
+
+    /// Reads a snapshot. Forged, garbled and replayed ones are dropped.
+    pub fn receive(&mut self) -> Result<Option<Vec<Session>>> {
+        let (len, addr) = match try!(self.socket.recv_from(&mut self.buf)) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let snapshot = match open(self.key, &self.buf[0..len]) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("Dropping snapshot from {}: {}", addr, e);
+                return Ok(None);
+            }
+        };
+        if !self.advance(snapshot.epoch, snapshot.seq) {
+            warn!("Dropping replayed snapshot from {}.", addr);
+            return Ok(None);
+        }
+        Ok(Some(snapshot.sessions))
+    }
+
+    /// Whether a snapshot numbered `seq` in `epoch` is new. A new epoch means
+    /// the primary restarted and numbers from the start again.
+    fn advance(&mut self, epoch: u64, seq: u64) -> bool {
+        if self.epoch == Some(epoch) {
+            if seq <= self.seq {
+                return false;
+            }
+        } else if self.retired.contains(&epoch) {
+            return false;
+        } else if let Some(old) = self.epoch.take() {
+            info!("The primary restarted; following its new epoch.");
+            if self.retired.len() == MAX_RETIRED_EPOCHS {
+                self.retired.pop_front();
+            }
+            self.retired.push_back(old);
+        }
+        self.epoch = Some(epoch);
+        self.seq = seq;
+        true
+    }
+}
+
+#[test]
+fn seal_test() {
+    let key = Key::derive("secret");
+    let snapshot = Snapshot {
+        epoch: 3,
+        seq: 7,
+        sessions: vec![Session {
+                           id: 2,
+                           token: 42,
+                           addr: "192.0.2.1:1234".parse().unwrap(),
+                           caps: Capabilities::new(0),
+                           group: 1,
+                           roaming: None,
+                       }],
+    };
+    let mut buf = seal(key, &snapshot).unwrap();
+    assert_eq!(open(key, &buf).unwrap(), snapshot);
+    // Tokens do not appear in the clear.
+    let plain = encode(&snapshot, Infinite).unwrap();
+    assert!(!buf.windows(plain.len()).any(|window| window == &plain[..]));
+    assert!(seal(key, &snapshot).unwrap() != buf);
+    assert!(open(Key::derive("other"), &buf).is_err());
+    buf[0] ^= 1;
+    assert!(open(key, &buf).is_err());
+    assert!(open(key, &buf[0..4]).is_err());
+}
+
+#[test]
+fn advance_test() {
+    let poll = mio::Poll::new().unwrap();
+    let mut standby = Standby::open(&poll,
+                                    mio::Token(0),
+                                    "127.0.0.1:0".parse().unwrap(),
+                                    "secret")
+        .unwrap();
+    assert!(standby.advance(5, 1));
+    assert!(standby.advance(5, 2));
+    assert!(!standby.advance(5, 2));
+    // The primary restarted, whatever its clock says.
+    assert!(standby.advance(9, 1));
+    assert!(standby.advance(9, 2));
+    assert!(!standby.advance(5, 3));
+}
@@ -0,0 +1,166 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Name lookups that pick an address family and remember their answers.
+//!
+//! The system resolver returns a name's addresses in its own order and
+//! hides the records' TTLs, so answers are kept for a fixed time instead. A
+//! client shares one `Resolver` between connecting, failing over and latency
+//! probes, and asks the system again when the network changes.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use dns_lookup;
+use error::{Error, Result};
+
+/// How long answers are kept unless configured otherwise, in seconds.
+pub const DEFAULT_TTL: u64 = 60;
+/// Names remembered at most.
+const MAX_ENTRIES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    /// Whatever the system lists first.
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    OnlyIpv4,
+    OnlyIpv6,
+}
+
+impl Family {
+    pub fn parse(s: &str) -> Result<Family> {
+        match s {
+            "any" => Ok(Family::Any),
+            "prefer-ipv4" => Ok(Family::PreferIpv4),
+            "prefer-ipv6" => Ok(Family::PreferIpv6),
+            "only-ipv4" => Ok(Family::OnlyIpv4),
+            "only-ipv6" => Ok(Family::OnlyIpv6),
+            _ => Err(Error::Config(format!("unknown address family {:?}", s))),
+        }
+    }
+
+    /// The address to use among `addrs`, which are in the system's order.
+    pub fn pick(&self, addrs: &[IpAddr]) -> Option<IpAddr> {
+        let v4 = addrs.iter().find(|addr| addr.is_ipv4()).cloned();
+        let v6 = addrs.iter().find(|addr| addr.is_ipv6()).cloned();
+        match *self {
+            Family::Any => addrs.first().cloned(),
+            Family::PreferIpv4 => v4.or(v6),
+            Family::PreferIpv6 => v6.or(v4),
+            Family::OnlyIpv4 => v4,
+            Family::OnlyIpv6 => v6,
+        }
+    }
+}
+
+/// Every address `host` resolves to, in the system's order.
+pub fn lookup(host: &str) -> Result<Vec<IpAddr>> {
+    let ip_list = try!(dns_lookup::lookup_host(host)
+        .map_err(|e| Error::Dns(format!("{}: {:?}", host, e))));
+    let mut addrs = Vec::new();
+    for ip in ip_list {
+        addrs.push(try!(ip.map_err(|e| Error::Dns(format!("{}: {}", host, e)))));
+    }
+    Ok(addrs)
+}
+
+pub struct Resolver {
+    family: Family,
+    ttl: Duration,
+    cache: HashMap<String, (Vec<IpAddr>, Instant)>,
+}
+
+impl Resolver {
+    /// Answers are kept for `ttl`; zero disables the cache.
+    pub fn new(family: Family, ttl: Duration) -> Resolver {
+        Resolver {
+            family: family,
+            ttl: ttl,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// An address of `host` in the configured family, looked up unless a
+    /// fresh answer is at hand.
+    pub fn resolve(&mut self, host: &str, now: Instant) -> Result<IpAddr> {
+        let fresh = self.cache
+            .get(host)
+            .map_or(false, |&(_, at)| now.duration_since(at) < self.ttl);
+        if !fresh {
+            let addrs = try!(lookup(host));
+            if self.ttl > Duration::from_secs(0) {
+                let ttl = self.ttl;
+                self.cache.retain(|_, &mut (_, at)| now.duration_since(at) < ttl);
+                if self.cache.len() < MAX_ENTRIES {
+                    self.cache.insert(String::from(host), (addrs.clone(), now));
+                }
+            }
+            return self.pick(host, &addrs);
+        }
+        let addrs = self.cache[host].0.clone();
+        self.pick(host, &addrs)
+    }
+
+    /// Forgets every answer, for instance because the network changed.
+    pub fn flush(&mut self) {
+        self.cache.clear();
+    }
+
+    fn pick(&self, host: &str, addrs: &[IpAddr]) -> Result<IpAddr> {
+        match self.family.pick(addrs) {
+            Some(addr) => Ok(addr),
+            None if addrs.is_empty() => Err(Error::Dns(format!("{}: no address found", host))),
+            None => Err(Error::Dns(format!("{}: no address of the requested family", host))),
+        }
+    }
+}
+
+#[test]
+fn pick_test() {
+    let v4: IpAddr = "192.0.2.1".parse().unwrap();
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    let both = [v6, v4];
+    let cases = [(Family::Any, Some(v6)),
+                 (Family::PreferIpv4, Some(v4)),
+                 (Family::PreferIpv6, Some(v6)),
+                 (Family::OnlyIpv4, Some(v4)),
+                 (Family::OnlyIpv6, Some(v6))];
+    for &(family, expected) in &cases {
+        assert_eq!(family.pick(&both), expected);
+    }
+    assert_eq!(Family::PreferIpv6.pick(&[v4]), Some(v4));
+    assert_eq!(Family::OnlyIpv6.pick(&[v4]), None);
+    assert_eq!(Family::parse("prefer-ipv6").unwrap(), Family::PreferIpv6);
+    assert!(Family::parse("ipv5").is_err());
+}
+
+#[test]
+fn resolver_test() {
+    let now = Instant::now();
+    let mut resolver = Resolver::new(Family::OnlyIpv4, Duration::from_secs(DEFAULT_TTL));
+    let cached: IpAddr = "192.0.2.1".parse().unwrap();
+    resolver.cache.insert(String::from("kytan.invalid"), (vec![cached], now));
+    assert_eq!(resolver.resolve("kytan.invalid", now).unwrap(), cached);
+    // The answer expires, and the name does not resolve.
+    let later = now + Duration::from_secs(DEFAULT_TTL);
+    assert!(resolver.resolve("kytan.invalid", later).is_err());
+    assert!(resolver.resolve("::1", now).is_err());
+    assert_eq!(resolver.resolve("127.0.0.1", now).unwrap(),
+               "127.0.0.1".parse::<IpAddr>().unwrap());
+    assert!(resolver.cache.contains_key("127.0.0.1"));
+    resolver.flush();
+    assert!(resolver.cache.is_empty());
+}
@@ -0,0 +1,197 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Roaming that only the client itself can ask for.
+//!
+//! The session token travels in every frame, so anyone on the path knows it
+//! and it cannot authorize moving a session to another address. Instead the
+//! client puts an ephemeral X25519 key in its request and the server answers
+//! with its own in the response. The exchange is part of the handshake, so
+//! nobody can slip in a key for a session that already exists. Both ends
+//! derive a roaming key from it with HKDF, and that key never crosses the
+//! wire. A `Roam` carries a sequence number and an HMAC-SHA256 over it keyed
+//! with the roaming key, and the server moves the session only for a valid
+//! MAC with a sequence number above any it accepted before, so a roam can be
+//! neither forged nor replayed. A client that offered no key cannot roam.
+
+use std::fmt;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{self, Rng};
+use sha2::Sha256;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use network::{Id, Token};
+
+const KEY_LEN: usize = 32;
+
+/// A secret both ends of a session derive and never send.
+type Key = [u8; KEY_LEN];
+
+/// An ephemeral key pair, made for one handshake.
+struct Exchange {
+    secret: [u8; KEY_LEN],
+    public: [u8; KEY_LEN],
+}
+
+impl Exchange {
+    fn new() -> Exchange {
+        let mut secret = [0; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Exchange {
+            secret: secret,
+            public: x25519(secret, X25519_BASEPOINT_BYTES),
+        }
+    }
+
+    /// The key shared with the holder of `peer`, or `None` if `peer` is not
+    /// a usable public key. Both public keys go into the derivation, client
+    /// first.
+    fn key(&self, peer: &[u8], client: &[u8], server: &[u8]) -> Option<Key> {
+        if peer.len() != KEY_LEN {
+            return None;
+        }
+        let mut point = [0; KEY_LEN];
+        point.copy_from_slice(peer);
+        let shared = x25519(self.secret, point);
+        // Low-order points give everyone the same secret.
+        if shared == [0; KEY_LEN] {
+            return None;
+        }
+        let mut info = client.to_vec();
+        info.extend_from_slice(server);
+        let mut key = [0; KEY_LEN];
+        Hkdf::<Sha256>::new(Some(&b"kytan-roam"[..]), &shared)
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Some(key)
+    }
+}
+
+fn mac(key: &Key, id: Id, token: Token, sequence: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&[id]);
+    for value in &[token, sequence] {
+        let bytes: Vec<u8> = (0..8).map(|i| (value >> (56 - 8 * i)) as u8).collect();
+        mac.update(&bytes);
+    }
+    mac
+}
+
+/// The client's half of the exchange, kept until the server answers.
+pub struct Offer {
+    exchange: Exchange,
+}
+
+impl Offer {
+    pub fn new() -> Offer {
+        Offer { exchange: Exchange::new() }
+    }
+
+    /// The public key to send in the request.
+    pub fn public(&self) -> Vec<u8> {
+        self.exchange.public.to_vec()
+    }
+
+    /// The roaming key agreed with a server that answered with `public`, or
+    /// `None` if it sent no usable key.
+    pub fn accept(&self, public: &[u8]) -> Option<Roamer> {
+        self.exchange.key(public, &self.exchange.public, public).map(|key| {
+            Roamer {
+                key: key,
+                sequence: 0,
+            }
+        })
+    }
+}
+
+/// The client's side once the key is agreed.
+pub struct Roamer {
+    key: Key,
+    sequence: u64,
+}
+
+impl Roamer {
+    /// The sequence number and MAC of the next roam.
+    pub fn roam(&mut self, id: Id, token: Token) -> (u64, Vec<u8>) {
+        self.sequence += 1;
+        let tag = mac(&self.key, id, token, self.sequence).finalize().into_bytes();
+        (self.sequence, tag.to_vec())
+    }
+}
+
+/// The server's side, kept with the session.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub struct Binding {
+    key: Key,
+    sequence: u64,
+}
+
+impl Binding {
+    /// Answers the client's public key `peer` with the server's own, or
+    /// `None` if `peer` is not a usable key.
+    pub fn answer(peer: &[u8]) -> Option<(Binding, Vec<u8>)> {
+        let exchange = Exchange::new();
+        exchange.key(peer, peer, &exchange.public).map(|key| {
+            (Binding {
+                key: key,
+                sequence: 0,
+            },
+             exchange.public.to_vec())
+        })
+    }
+
+    /// Whether a roam with `sequence` and `tag` is genuine and new. Records
+    /// the sequence number if it is.
+    pub fn accept(&mut self, id: Id, token: Token, sequence: u64, tag: &[u8]) -> bool {
+        if sequence <= self.sequence ||
+           mac(&self.key, id, token, sequence).verify_slice(tag).is_err() {
+            return false;
+        }
+        self.sequence = sequence;
+        true
+    }
+}
+
+impl fmt::Debug for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The key stays out of the logs.
+        write!(f, "Binding {{ sequence: {} }}", self.sequence)
+    }
+}
+
+#[test]
+fn roaming_test() {
+    let offer = Offer::new();
+    assert!(Binding::answer(&[0; 31]).is_none());
+    assert!(Binding::answer(&[0; 32]).is_none());
+    let (mut binding, public) = Binding::answer(&offer.public()).unwrap();
+    assert!(offer.accept(&[]).is_none());
+    assert!(Offer::new().accept(&public).is_some());
+
+    // Only the client that made the offer agrees on the server's key.
+    let mut stranger = Offer::new().accept(&public).unwrap();
+    let (sequence, tag) = stranger.roam(3, 7);
+    assert!(!binding.accept(3, 7, sequence, &tag));
+
+    let mut roamer = offer.accept(&public).unwrap();
+    let (sequence, tag) = roamer.roam(3, 7);
+    assert_eq!(tag.len(), 32);
+    assert!(!binding.accept(3, 8, sequence, &tag));
+    assert!(!binding.accept(3, 7, sequence + 1, &tag));
+    assert!(binding.accept(3, 7, sequence, &tag));
+    // A captured roam cannot be replayed.
+    assert!(!binding.accept(3, 7, sequence, &tag));
+    let (sequence, tag) = roamer.roam(3, 7);
+    assert!(binding.accept(3, 7, sequence, &tag));
+}
@@ -0,0 +1,146 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! `kytan selftest`: an end-to-end check of the server on this host.
+//!
+//! A server runs on a thread of its own while the test plays a minimal
+//! client over localhost: it handshakes, sends a UDP packet into the tunnel
+//! and expects it on the server's TUN address, then sends one back and
+//! expects it inside a data frame. It needs root, like the server, and fails
+//! if another kytan server already owns 10.10.10.1.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use packet;
+use network::*;
+use server::Server;
+use error::{Error, Result};
+
+/// How long to wait for each step.
+const STEP_TIMEOUT: u64 = 3;
+
+const OUTBOUND: &'static [u8] = b"kytan selftest outbound";
+const INBOUND: &'static [u8] = b"kytan selftest inbound";
+
+fn failed(step: &str) -> Error {
+    Error::Handshake(format!("selftest failed: {}", step))
+}
+
+/// Runs the test against a server listening on `port`.
+pub fn run(port: u16) -> Result<()> {
+    let (ready, started) = mpsc::channel();
+    let server = thread::spawn(move || -> Result<()> {
+        let mut server = match Server::builder().port(port).userspace_nat(true).build() {
+            Ok(server) => server,
+            Err(e) => {
+                let _ = ready.send(None);
+                return Err(e);
+            }
+        };
+        let _ = ready.send(Some(server.shutdown_handle()));
+        server.run()
+    });
+    let handle = match started.recv() {
+        Ok(Some(handle)) => handle,
+        _ => {
+            return match server.join() {
+                Ok(result) => result,
+                Err(_) => Err(failed("server panicked")),
+            }
+        }
+    };
+
+    let result = exchange(port);
+    handle.shutdown();
+    let stopped = match server.join() {
+        Ok(stopped) => stopped,
+        Err(_) => Err(failed("server panicked")),
+    };
+    result.and(stopped)
+}
+
+/// Receives from `socket` until `accept` takes a datagram or time runs out.
+fn receive<F>(socket: &UdpSocket, step: &str, mut accept: F) -> Result<()>
+    where F: FnMut(&[u8], SocketAddr) -> bool
+{
+    let deadline = Instant::now() + Duration::from_secs(STEP_TIMEOUT);
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(failed(step));
+        }
+        try!(socket.set_read_timeout(Some(deadline - now)));
+        match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => {
+                if accept(&buf[0..len], addr) {
+                    info!("Selftest: {} ok.", step);
+                    return Ok(());
+                }
+            }
+            Err(_) => return Err(failed(step)),
+        }
+    }
+}
+
+fn exchange(port: u16) -> Result<()> {
+    let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    let outer = try!(UdpSocket::bind("127.0.0.1:0"));
+    let request = try!(encode_message(&Message::Request {
+        caps: Capabilities::new(0),
+        resume: None,
+        credential: None,
+        roam_key: Vec::new(),
+    }));
+    try!(outer.send_to(&request, &server));
+
+    let mut session = None;
+    try!(receive(&outer, "handshake", |buf, _| {
+        match decode_message(buf) {
+            Ok(Message::Response { id, token, .. }) => {
+                session = Some((id, token));
+                true
+            }
+            _ => false,
+        }
+    }));
+    let (id, token) = session.unwrap();
+    let client = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 10, 10, id)), 40000);
+
+    let inner = try!(UdpSocket::bind("10.10.10.1:0"));
+    let target = try!(inner.local_addr());
+    let data = try!(encode_message(&Message::Data {
+        id: id,
+        token: token,
+        data: packet::build_udp(&client, &target, OUTBOUND),
+    }));
+    try!(outer.send_to(&data, &server));
+    try!(receive(&inner, "client to server", |buf, from| buf == OUTBOUND && from == client));
+
+    try!(inner.send_to(INBOUND, &client));
+    receive(&outer, "server to client", |buf, _| {
+        match decode_message(buf) {
+            Ok(Message::Data { data, .. }) => {
+                match packet::parse_udp(&data) {
+                    Ok((src, dst, payload)) => src == target && dst == client && payload == INBOUND,
+                    Err(_) => false,
+                }
+            }
+            _ => false,
+        }
+    })
+}
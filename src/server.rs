@@ -0,0 +1,3856 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(feature = "admin")]
+use std::path::{Path, PathBuf};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{self, Write, Read};
+use mio;
+use device;
+use roaming;
+use utils;
+use acl;
+use iroute;
+use delegation;
+use firewall;
+use nat;
+use dns;
+use push;
+use discovery;
+use replication;
+use telemetry;
+use stats;
+#[cfg(feature = "metrics")]
+use health;
+#[cfg(feature = "metrics")]
+use netflow;
+#[cfg(feature = "dashboard")]
+use dashboard;
+use netem;
+use shaper;
+use batch;
+use qos;
+use stream;
+use netwatch;
+use forward;
+use lockout;
+use loglimit;
+#[cfg(any(feature = "admin", feature = "dashboard"))]
+use verbosity;
+use limits::Limits;
+use history;
+use cidr;
+use geoip;
+use auth;
+use handshake;
+use workers;
+use identity;
+use allocation;
+#[cfg(feature = "obfuscation")]
+use cover;
+use adaptive;
+#[cfg(feature = "obfuscation")]
+use obfs;
+#[cfg(feature = "admin")]
+use control;
+use signal;
+use packet;
+use pcap;
+use mirror;
+use rand::{StdRng, Rng};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+use clock::{Clock, SystemClock};
+use network::*;
+use error::{Code, Error, Result};
+
+pub struct ServerBuilder {
+    port: u16,
+    bind_address: Option<Ipv4Addr>,
+    session_port: Option<u16>,
+    subnet: device::Subnet,
+    device_name: Option<String>,
+    tap: bool,
+    tun_fd: Option<RawFd>,
+    compression: bool,
+    compression_threshold: usize,
+    adaptive_compression: bool,
+    clamp_mss: bool,
+    verify_checksums: bool,
+    drop_corrupt: bool,
+    propagate_dscp: bool,
+    outer: OuterOptions,
+    userspace_nat: bool,
+    relay_only: bool,
+    dns_upstreams: Vec<SocketAddr>,
+    pushed: Vec<push::PushOption>,
+    restore_sysctls: bool,
+    masquerade: Option<String>,
+    firewall: Option<firewall::Backend>,
+    discovery_proxy: bool,
+    groups: Vec<(String, String)>,
+    require_stamps: Option<bool>,
+    group_compression: Vec<(String, bool)>,
+    group_mtu: Vec<(String, u16)>,
+    acl: acl::Acl,
+    iroutes: Vec<iroute::Iroute>,
+    forwards: Vec<forward::Forward>,
+    delegation: Option<(cidr::Cidr, u8)>,
+    devices: Vec<(identity::Key, Ipv4Addr)>,
+    limits: Limits,
+    workers: usize,
+    replicate_to: Option<(SocketAddr, String)>,
+    standby: Option<(SocketAddr, String)>,
+    backend_id: Option<u8>,
+    tracer: Option<telemetry::Tracer>,
+    #[cfg(feature = "metrics")]
+    statsd: Option<stats::StatsdConfig>,
+    #[cfg(feature = "metrics")]
+    health: Option<SocketAddr>,
+    #[cfg(feature = "metrics")]
+    netflow: Option<netflow::Config>,
+    #[cfg(feature = "dashboard")]
+    dashboard: Option<(SocketAddr, bool)>,
+    netem: Option<netem::Config>,
+    max_bandwidth: Option<u64>,
+    coalesce: Option<Duration>,
+    qos: Option<qos::Classifier>,
+    drop_policy: qos::DropPolicy,
+    streams: bool,
+    local_streams: bool,
+    max_lifetime: Option<Duration>,
+    rtt_interval: Duration,
+    lockout: Option<lockout::Config>,
+    history: Option<history::Config>,
+    sources: cidr::Filter,
+    geoip: Option<geoip::Policy>,
+    #[cfg(feature = "admin")]
+    control: Option<PathBuf>,
+    authenticator: Option<Box<auth::Authenticator>>,
+    allocator: Box<allocation::Allocator>,
+    rng: Option<Box<Rng + Send>>,
+    clock: Box<Clock>,
+    callback: Option<Callback>,
+    pcap: Option<pcap::Config>,
+    mirror: Option<String>,
+}
+
+impl ServerBuilder {
+    pub fn port(mut self, port: u16) -> ServerBuilder {
+        self.port = port;
+        self
+    }
+
+    /// Listen on `addr` only. By default the server listens on every
+    /// address and, in Linux, answers each client from the one it sent to,
+    /// which NATs that check the source of replies require.
+    pub fn bind_address(mut self, addr: Ipv4Addr) -> ServerBuilder {
+        self.bind_address = Some(addr);
+        self
+    }
+
+    /// Also listen on `port` for session maintenance: handshakes, liveness
+    /// probes and roaming. Clients that are told to send these there get
+    /// them through with their own socket buffer, read ahead of data, so a
+    /// saturated data path cannot get sessions expired.
+    pub fn session_port(mut self, port: u16) -> ServerBuilder {
+        self.session_port = Some(port);
+        self
+    }
+
+    /// Addresses for clients. Defaults to 10.10.10.0/24; servers sharing a
+    /// host need one each. Only clients that support other subnets can
+    /// connect to a server using one.
+    pub fn subnet(mut self, subnet: device::Subnet) -> ServerBuilder {
+        self.subnet = subnet;
+        self
+    }
+
+    /// Name of the TUN device. Defaults to the first free `tunN`.
+    pub fn device_name(mut self, name: &str) -> ServerBuilder {
+        self.device_name = Some(String::from(name));
+        self
+    }
+
+    /// Use a TAP device, which carries Ethernet frames, instead of TUN.
+    /// Linux only.
+    pub fn tap(mut self, tap: bool) -> ServerBuilder {
+        self.tap = tap;
+        self
+    }
+
+    /// Use the already open TUN device `fd` instead of creating one. Its
+    /// owner configures it.
+    pub fn tun_fd(mut self, fd: RawFd) -> ServerBuilder {
+        self.tun_fd = Some(fd);
+        self
+    }
+
+    /// Accept snappy compression from clients that offer it. Enabled by default.
+    pub fn compression(mut self, compression: bool) -> ServerBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Send packets shorter than `bytes` uncompressed to clients that accept
+    /// raw frames.
+    pub fn compression_threshold(mut self, bytes: usize) -> ServerBuilder {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// Stop compressing while the CPU cannot keep up with the traffic, and
+    /// resume once it can.
+    pub fn adaptive_compression(mut self, adaptive: bool) -> ServerBuilder {
+        self.adaptive_compression = adaptive;
+        self
+    }
+
+    /// Rewrite the MSS option of TCP SYNs crossing the tunnel so that TCP
+    /// segments fit into the tunnel MTU.
+    pub fn clamp_mss(mut self, clamp_mss: bool) -> ServerBuilder {
+        self.clamp_mss = clamp_mss;
+        self
+    }
+
+    /// Verify the IPv4, TCP and UDP checksums of packets coming out of the
+    /// tunnel and count those that are wrong, to catch bugs in compression
+    /// or transport. Costs a pass over every packet.
+    pub fn verify_checksums(mut self, verify: bool) -> ServerBuilder {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Drop packets with wrong checksums instead of delivering them. Implies
+    /// `verify_checksums`.
+    pub fn drop_corrupt(mut self, drop: bool) -> ServerBuilder {
+        self.drop_corrupt = drop;
+        self
+    }
+
+    /// Copy the DSCP of tunneled packets onto the outer UDP packets so that
+    /// upstream QoS still applies.
+    pub fn propagate_dscp(mut self, propagate_dscp: bool) -> ServerBuilder {
+        self.propagate_dscp = propagate_dscp;
+        self
+    }
+
+    /// TTL of the outer UDP packets. Defaults to the system setting.
+    pub fn outer_ttl(mut self, ttl: u8) -> ServerBuilder {
+        self.outer.ttl = Some(ttl);
+        self
+    }
+
+    /// Set or clear the DF bit on the outer UDP packets. Defaults to the
+    /// system setting.
+    pub fn dont_fragment(mut self, df: bool) -> ServerBuilder {
+        self.outer.dont_fragment = Some(df);
+        self
+    }
+
+    /// Set this fwmark (SO_MARK) on the outer UDP packets, so that policy
+    /// routing can keep them off the tunnel. Linux only.
+    pub fn fwmark(mut self, mark: u32) -> ServerBuilder {
+        self.outer.fwmark = Some(mark);
+        self
+    }
+
+    /// Push `option` to clients in the handshake. Clients too old to take
+    /// options connect without them.
+    pub fn push(mut self, option: push::PushOption) -> ServerBuilder {
+        self.pushed.push(option);
+        self
+    }
+
+    /// Answer DNS queries on the server's tunnel address, port 53, by
+    /// relaying them to `upstreams`.
+    pub fn dns_forwarder(mut self, upstreams: Vec<SocketAddr>) -> ServerBuilder {
+        self.dns_upstreams = upstreams;
+        self
+    }
+
+    /// Translate UDP traffic leaving the tunnel with ordinary sockets instead
+    /// of enabling kernel forwarding. Other traffic can then only reach the
+    /// server and other clients.
+    pub fn userspace_nat(mut self, userspace_nat: bool) -> ServerBuilder {
+        self.userspace_nat = userspace_nat;
+        self
+    }
+
+    /// Run without a TUN device, only forwarding packets between clients by
+    /// their inner destination. Needs no privileges and works on any OS.
+    pub fn relay_only(mut self, relay_only: bool) -> ServerBuilder {
+        self.relay_only = relay_only;
+        self
+    }
+
+    /// Install firewall rules that masquerade client traffic leaving through
+    /// the interface `out`, and remove them on exit.
+    pub fn masquerade(mut self, out: &str) -> ServerBuilder {
+        self.masquerade = Some(String::from(out));
+        self
+    }
+
+    /// Firewall used by `masquerade()`. Detected from the host by default.
+    pub fn firewall(mut self, backend: firewall::Backend) -> ServerBuilder {
+        self.firewall = Some(backend);
+        self
+    }
+
+    /// Put sysctls changed by the server, such as IPv4 forwarding, back to
+    /// their previous values on shutdown. Enabled by default.
+    pub fn restore_sysctls(mut self, restore: bool) -> ServerBuilder {
+        self.restore_sysctls = restore;
+        self
+    }
+
+    /// Relay mDNS and SSDP between the server's LAN and the clients.
+    pub fn discovery_proxy(mut self, discovery_proxy: bool) -> ServerBuilder {
+        self.discovery_proxy = discovery_proxy;
+        self
+    }
+
+    /// Clients presenting `credential` join the isolation group `name`.
+    /// Clients can only reach members of their own group; those without a
+    /// known credential form a group of their own.
+    pub fn group(mut self, name: &str, credential: &str) -> ServerBuilder {
+        self.groups.push((String::from(name), String::from(credential)));
+        self
+    }
+
+    /// Refuse group credentials sent as they are, which anyone who sees a
+    /// handshake can replay, and take only stamped ones. On by default
+    /// when groups are configured.
+    pub fn require_stamps(mut self, require: bool) -> ServerBuilder {
+        self.require_stamps = Some(require);
+        self
+    }
+
+    /// Enable or disable compression for the members of group `name`,
+    /// overriding `compression()`.
+    pub fn group_compression(mut self, name: &str, compression: bool) -> ServerBuilder {
+        self.group_compression.push((String::from(name), compression));
+        self
+    }
+
+    /// Push `mtu` to the members of group `name`, e.g. clients behind PPPoE
+    /// or LTE. Only lowers the MTU a client would otherwise use.
+    pub fn group_mtu(mut self, name: &str, mtu: u16) -> ServerBuilder {
+        self.group_mtu.push((String::from(name), mtu));
+        self
+    }
+
+    /// Filter packets that clients send into the tunnel.
+    pub fn acl(mut self, acl: acl::Acl) -> ServerBuilder {
+        self.acl = acl;
+        self
+    }
+
+    /// Route a network to the client of the route's group, and the kernel's
+    /// traffic for it into the tunnel.
+    pub fn iroute(mut self, route: iroute::Iroute) -> ServerBuilder {
+        self.iroutes.push(route);
+        self
+    }
+
+    /// Delegate an IPv6 prefix of length `prefix` out of `pool` to each
+    /// client, for its downstream networks, and route the pool into the
+    /// tunnel. Needs clients that take pushed options.
+    pub fn delegate_prefixes(mut self, pool: cidr::Cidr, prefix: u8) -> ServerBuilder {
+        self.delegation = Some((pool, prefix));
+        self
+    }
+
+    /// Always give the client that proves identity `key` the address
+    /// `address`, and no other client. See the `identity` module.
+    pub fn device(mut self, key: identity::Key, address: Ipv4Addr) -> ServerBuilder {
+        self.devices.push((key, address));
+        self
+    }
+
+    /// Caps on queues, caches and tables that grow with load.
+    pub fn limits(mut self, limits: Limits) -> ServerBuilder {
+        self.limits = limits;
+        self
+    }
+
+    /// Compress and decompress data frames on `threads` worker threads
+    /// instead of the event loop. Zero, the default, keeps them inline.
+    pub fn workers(mut self, threads: usize) -> ServerBuilder {
+        self.workers = threads;
+        self
+    }
+
+    /// Accept TCP connections on a port of the server and relay them to a
+    /// service on a client, through the tunnel.
+    pub fn forward_port(mut self, forward: forward::Forward) -> ServerBuilder {
+        self.forwards.push(forward);
+        self
+    }
+
+    /// Send session state to a standby server at `peer` that shares
+    /// `secret`. Snapshots are encrypted with a key derived from it.
+    pub fn replicate_to(mut self, peer: SocketAddr, secret: &str) -> ServerBuilder {
+        self.replicate_to = Some((peer, String::from(secret)));
+        self
+    }
+
+    /// Run as a hot standby, installing the sessions a primary replicates to
+    /// `listen`. Once the server's address moves here, clients carry on
+    /// without a new handshake. Groups must be configured as on the primary.
+    pub fn standby(mut self, listen: SocketAddr, secret: &str) -> ServerBuilder {
+        self.standby = Some((listen, String::from(secret)));
+        self
+    }
+
+    /// Stamp `id` into the top byte of every token this server issues, so
+    /// that a UDP load balancer in front of several servers can route frames
+    /// by `kytan::session_token()` alone. Leaves 56 random bits per token.
+    pub fn backend_id(mut self, id: u8) -> ServerBuilder {
+        self.backend_id = Some(id);
+        self
+    }
+
+    /// Trace handshakes and client sessions.
+    pub fn tracer(mut self, tracer: telemetry::Tracer) -> ServerBuilder {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Send traffic counters to a statsd server.
+    #[cfg(feature = "metrics")]
+    pub fn statsd(mut self, config: stats::StatsdConfig) -> ServerBuilder {
+        self.statsd = Some(config);
+        self
+    }
+
+    /// Answer HTTP health checks on `addr`, usually a localhost port.
+    #[cfg(feature = "metrics")]
+    pub fn health_check(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.health = Some(addr);
+        self
+    }
+
+    /// Export flow records of tunneled traffic to a NetFlow v9 or IPFIX
+    /// collector.
+    #[cfg(feature = "metrics")]
+    pub fn netflow(mut self, config: netflow::Config) -> ServerBuilder {
+        self.netflow = Some(config);
+        self
+    }
+
+    /// Serve the web dashboard on `addr`, usually a localhost port. Visitors
+    /// may lift bans only if it is `writable`.
+    #[cfg(feature = "dashboard")]
+    pub fn dashboard(mut self, addr: SocketAddr, writable: bool) -> ServerBuilder {
+        self.dashboard = Some((addr, writable));
+        self
+    }
+
+    /// Impair outgoing datagrams to emulate a poor network. For testing.
+    pub fn netem(mut self, config: netem::Config) -> ServerBuilder {
+        self.netem = Some(config);
+        self
+    }
+
+    /// Cap the total tunnel traffic of all clients, both ways, at `rate`
+    /// bytes per second. Packets over the cap are dropped.
+    pub fn max_bandwidth(mut self, rate: u64) -> ServerBuilder {
+        self.max_bandwidth = Some(rate);
+        self
+    }
+
+    /// Queue packets that the bandwidth cap or a rate limit holds back, by
+    /// priority class, instead of dropping them.
+    pub fn qos(mut self, classifier: qos::Classifier) -> ServerBuilder {
+        self.qos = Some(classifier);
+        self
+    }
+
+    /// What to drop when a client's queues are full: the new packet, or the
+    /// oldest least urgent one.
+    pub fn drop_policy(mut self, policy: qos::DropPolicy) -> ServerBuilder {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Hold small packets for up to `window` and send those for the same
+    /// client in one datagram, to clients that support it.
+    pub fn coalesce(mut self, window: Duration) -> ServerBuilder {
+        self.coalesce = Some(window);
+        self
+    }
+
+    /// Relay streams for clients that run a SOCKS5 proxy instead of a TUN
+    /// device, connecting out from this host. The ACL applies to the target
+    /// port.
+    pub fn allow_streams(mut self, allow: bool) -> ServerBuilder {
+        self.streams = allow;
+        self
+    }
+
+    /// Let streams reach loopback, link-local and this host's own addresses
+    /// too. Without this, clients cannot get at services that only listen
+    /// there, such as the dashboard.
+    pub fn allow_local_streams(mut self, allow: bool) -> ServerBuilder {
+        self.local_streams = allow;
+        self
+    }
+
+    /// Expire sessions after `lifetime`, so that clients have to hand in
+    /// their credential again in a fresh handshake. A captured token is then
+    /// only good for so long.
+    pub fn max_session_lifetime(mut self, lifetime: Duration) -> ServerBuilder {
+        self.max_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Measure the round trip to each client every `interval` rather than
+    /// every 30 seconds, for finer latency histograms.
+    pub fn rtt_interval(mut self, interval: Duration) -> ServerBuilder {
+        self.rtt_interval = interval;
+        self
+    }
+
+    /// Ban source addresses that keep failing to authenticate from new
+    /// handshakes.
+    pub fn lockout(mut self, config: lockout::Config) -> ServerBuilder {
+        self.lockout = Some(config);
+        self
+    }
+
+    /// Accept `bans` and `unban` commands on a Unix socket at `path`.
+    #[cfg(feature = "admin")]
+    pub fn control_socket<P: AsRef<Path>>(mut self, path: P) -> ServerBuilder {
+        self.control = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Record sessions in an SQLite database for `kytan history`.
+    pub fn history(mut self, config: history::Config) -> ServerBuilder {
+        self.history = Some(config);
+        self
+    }
+
+    /// Only accept datagrams from sources in `range`. May be given several
+    /// times; without it every source is accepted.
+    pub fn allow_from(mut self, range: cidr::Cidr) -> ServerBuilder {
+        self.sources.allow.push(range);
+        self
+    }
+
+    /// Drop datagrams from sources in `range`, even if `allow_from` matches.
+    pub fn deny_from(mut self, range: cidr::Cidr) -> ServerBuilder {
+        self.sources.deny.push(range);
+        self
+    }
+
+    /// Refuse handshakes from addresses that `policy` does not admit.
+    pub fn geoip(mut self, policy: geoip::Policy) -> ServerBuilder {
+        self.geoip = Some(policy);
+        self
+    }
+
+    /// Check `USER:PASSWORD` credentials that match no group with
+    /// `authenticator`, e.g. a `radius::Radius`. Clients must then present
+    /// a credential.
+    pub fn authenticator<A>(mut self, authenticator: A) -> ServerBuilder
+        where A: auth::Authenticator + 'static
+    {
+        self.authenticator = Some(Box::new(authenticator));
+        self
+    }
+
+    /// How new clients get their id, such as an `allocation::Strategy` or
+    /// an external IPAM. Defaults to the lowest free id.
+    pub fn allocator<A>(mut self, allocator: A) -> ServerBuilder
+        where A: allocation::Allocator + 'static
+    {
+        self.allocator = Box::new(allocator);
+        self
+    }
+
+    /// Source of session tokens. Defaults to a freshly seeded `StdRng`.
+    pub fn rng<R>(mut self, rng: R) -> ServerBuilder
+        where R: Rng + Send + 'static
+    {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    /// Time source for timeouts. Defaults to the system clock.
+    pub fn clock<C>(mut self, clock: C) -> ServerBuilder
+        where C: Clock + 'static
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Record tunnel traffic to a pcap file.
+    pub fn pcap(mut self, config: pcap::Config) -> ServerBuilder {
+        self.pcap = Some(config);
+        self
+    }
+
+    /// Copy tunnel traffic to the TAP device `name` for monitoring tools.
+    pub fn mirror(mut self, name: &str) -> ServerBuilder {
+        self.mirror = Some(String::from(name));
+        self
+    }
+
+    pub fn on_event<F>(mut self, callback: F) -> ServerBuilder
+        where F: Fn(&Event) + Send + 'static
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Enables forwarding, brings up the TUN device and binds the socket.
+    pub fn build(self) -> Result<Server> {
+        if cfg!(not(target_os = "linux")) && !self.relay_only {
+            return Err(Error::Config(String::from("Server mode is only available in Linux!")));
+        }
+        if self.relay_only {
+            let kernel = if self.userspace_nat {
+                Some("userspace NAT")
+            } else if self.masquerade.is_some() {
+                Some("masquerading")
+            } else if !self.iroutes.is_empty() {
+                Some("routes behind clients")
+            } else if self.delegation.is_some() {
+                Some("prefix delegation")
+            } else if !self.dns_upstreams.is_empty() {
+                Some("DNS forwarding")
+            } else if self.discovery_proxy {
+                Some("the discovery proxy")
+            } else if self.mirror.is_some() {
+                Some("mirroring")
+            } else {
+                None
+            };
+            if let Some(what) = kernel {
+                return Err(Error::Config(format!("{} needs a TUN device, not relay-only mode",
+                                                 what)));
+            }
+        }
+
+        let forwarding = if self.userspace_nat || self.relay_only {
+            info!("Translating client traffic in userspace (UDP only).");
+            None
+        } else {
+            info!("Enabling kernel's IPv4 forwarding.");
+            let mut forwarding = try!(utils::enable_ipv4_forwarding());
+            if !self.restore_sysctls {
+                forwarding.persist();
+            }
+            Some(forwarding)
+        };
+
+        let (tun, mtu): (Box<device::VirtualInterface>, u16) = if self.relay_only {
+            info!("Relaying between clients only, without a TUN device.");
+            (Box::new(device::Null), device::MTU)
+        } else {
+            info!("Bringing up TUN device.");
+            let tun = try!(open_interface(self.tun_fd,
+                                          self.tap,
+                                          self.device_name.as_ref().map(|name| &name[..])));
+            try!(tun.up(&self.subnet, 1));
+            let mtu = try!(tun.mtu());
+            info!("TUN device {} initialized. Internal IP: {}/24. MTU: {}.",
+                  tun.name(),
+                  self.subnet.addr(1),
+                  mtu);
+            (tun, mtu)
+        };
+
+        let masquerade = match self.masquerade {
+            Some(ref out) if !self.userspace_nat => {
+                let backend = match self.firewall {
+                    Some(backend) => backend,
+                    None => try!(firewall::Backend::detect()),
+                };
+                info!("Masquerading client traffic leaving {} with {:?}.", out, backend);
+                Some(try!(firewall::Masquerade::create(backend,
+                                                       &self.subnet.to_string(),
+                                                       tun.name(),
+                                                       out)))
+            }
+            _ => None,
+        };
+
+        let mut iroutes = Vec::new();
+        let mut kernel_routes = Vec::new();
+        for route in self.iroutes {
+            let group = match self.groups.iter().position(|&(ref name, _)| *name == route.group) {
+                Some(i) => i + 1,
+                None => return Err(Error::Config(format!("no group named {}", route.group))),
+            };
+            let net = format!("{}/{}", route.net, route.prefix);
+            info!("Routing {} to the client of group {}.", net, route.group);
+            kernel_routes.push(try!(utils::InterfaceRoute::create(&net, tun.name())));
+            iroutes.push((route, group));
+        }
+        let delegation = match self.delegation {
+            Some((pool, prefix)) => {
+                let delegation = try!(delegation::Delegation::new(pool, prefix));
+                info!("Delegating /{} prefixes from {} to clients.", prefix, pool);
+                kernel_routes.push(try!(utils::InterfaceRoute::create(&pool.to_string(),
+                                                                      tun.name())));
+                Some(delegation)
+            }
+            None => None,
+        };
+        let mut devices = HashMap::new();
+        for (key, address) in self.devices {
+            let id = match self.subnet.id_of(IpAddr::V4(address)) {
+                Some(id) if id >= 2 && id < 254 && !devices.values().any(|&taken| taken == id) => {
+                    id
+                }
+                _ => {
+                    return Err(Error::Config(format!("{} is not a free client address in {}",
+                                                     address,
+                                                     self.subnet)))
+                }
+            };
+            info!("Reserving {} for device {}.", address, identity::fingerprint(&key));
+            devices.insert(key, id);
+        }
+
+        let mut compression_overrides = HashMap::new();
+        for &(ref name, compression) in &self.group_compression {
+            match self.groups.iter().position(|&(ref group, _)| group == name) {
+                Some(i) => compression_overrides.insert(i + 1, compression),
+                None => return Err(Error::Config(format!("no group named {}", name))),
+            };
+        }
+        let mut mtu_overrides = HashMap::new();
+        for &(ref name, mtu) in &self.group_mtu {
+            match self.groups.iter().position(|&(ref group, _)| group == name) {
+                Some(i) => mtu_overrides.insert(i + 1, mtu),
+                None => return Err(Error::Config(format!("no group named {}", name))),
+            };
+        }
+
+        let listen = self.bind_address.unwrap_or(Ipv4Addr::new(0, 0, 0, 0));
+        let addr = SocketAddr::new(IpAddr::V4(listen), self.port);
+        let sockfd = try!(mio::udp::UdpSocket::bind(&addr));
+        info!("Listening on: {}.", addr);
+        let local_addr = try!(sockfd.local_addr());
+        try!(configure_outer(&sockfd, &self.outer));
+        let session_sock = match self.session_port {
+            Some(port) => {
+                let addr = SocketAddr::new(IpAddr::V4(listen), port);
+                let sock = try!(mio::udp::UdpSocket::bind(&addr));
+                info!("Listening for session maintenance on: {}.", addr);
+                try!(configure_outer(&sock, &self.outer));
+                Some(sock)
+            }
+            None => None,
+        };
+        // Bound to one address, replies can only come from it anyway.
+        let pktinfo = listen.is_unspecified() && {
+            let enabled = set_pktinfo(&sockfd)
+                .and_then(|_| session_sock.as_ref().map_or(Ok(()), set_pktinfo));
+            if let Err(ref e) = enabled {
+                debug!("Replies may leave from another address than clients sent to: {}", e);
+            }
+            enabled.is_ok()
+        };
+        let capture = match self.pcap {
+            Some(config) => Some(try!(pcap::Capture::open(config))),
+            None => None,
+        };
+        let mirror = match self.mirror {
+            Some(ref name) => Some(try!(mirror::Mirror::open(name))),
+            None => None,
+        };
+
+        let poll = try!(mio::Poll::new());
+        try!(poll.register(&sockfd, SOCK, mio::Ready::readable(), mio::PollOpt::level()));
+        if let Some(ref sock) = session_sock {
+            try!(poll.register(sock, SESSION_SOCK, mio::Ready::readable(), mio::PollOpt::level()));
+        }
+        if !self.relay_only {
+            try!(poll.register(&mio::unix::EventedFd(&tun.as_raw_fd()),
+                          TUN,
+                          mio::Ready::readable(),
+                          mio::PollOpt::level()));
+        }
+
+        let (shutdown, registration) = ShutdownHandle::new();
+        try!(poll.register(&registration,
+                      SHUTDOWN,
+                      mio::Ready::readable(),
+                      mio::PollOpt::edge()));
+        try!(register_signal(&poll));
+
+        let discovery = if self.discovery_proxy {
+            info!("Relaying mDNS and SSDP between the LAN and clients.");
+            Some(try!(discovery::Proxy::open(&poll, DISCOVERY_BASE)))
+        } else {
+            None
+        };
+        let dns = if self.dns_upstreams.is_empty() {
+            None
+        } else {
+            let addr = SocketAddr::new(IpAddr::V4(self.subnet.addr(1)), 53);
+            Some(try!(dns::Forwarder::open(&poll,
+                                           DNS_LISTENER,
+                                           DNS_RELAY,
+                                           &addr,
+                                           self.dns_upstreams.clone())))
+        };
+
+        let replica = match self.replicate_to {
+            Some((peer, ref secret)) => {
+                info!("Replicating sessions to {}.", peer);
+                Some(try!(replication::Primary::open(peer, secret)))
+            }
+            None => None,
+        };
+        let standby = match self.standby {
+            Some((listen, ref secret)) => {
+                info!("Standing by for sessions replicated to {}.", listen);
+                Some(try!(replication::Standby::open(&poll, REPLICATION, listen, secret)))
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "metrics")]
+        let statsd = match self.statsd {
+            Some(config) => Some(try!(stats::Statsd::open(config, self.clock.now()))),
+            None => None,
+        };
+        #[cfg(feature = "metrics")]
+        let health = match self.health {
+            Some(addr) => Some(try!(health::Endpoint::open(&poll, HEALTH, &addr))),
+            None => None,
+        };
+        #[cfg(feature = "metrics")]
+        let netflow = match self.netflow {
+            Some(config) => Some(try!(netflow::Exporter::open(config, self.clock.now()))),
+            None => None,
+        };
+        #[cfg(feature = "dashboard")]
+        let dashboard = match self.dashboard {
+            Some((addr, writable)) => {
+                Some(try!(dashboard::Dashboard::open(&poll, DASHBOARD, &addr, writable)))
+            }
+            None => None,
+        };
+
+        let history = match self.history {
+            Some(config) => Some(try!(history::History::open(config))),
+            None => None,
+        };
+
+        #[cfg(feature = "admin")]
+        let control = match self.control {
+            Some(ref path) => Some(try!(control::Listener::open(&poll, CONTROL, path))),
+            None => None,
+        };
+
+        let rng = match self.rng {
+            Some(rng) => rng,
+            None => Box::new(try!(StdRng::new())),
+        };
+        let handshakes = try!(handshake::Worker::spawn(&poll,
+                                                       HANDSHAKE,
+                                                       self.groups.clone(),
+                                                       self.require_stamps
+                                                           .unwrap_or(!self.groups.is_empty()),
+                                                       self.authenticator,
+                                                       rng,
+                                                       self.backend_id,
+                                                       devices.keys().cloned().collect(),
+                                                       &self.limits));
+        let resolver = if self.streams {
+            Some(try!(stream::Resolver::spawn(&poll, RESOLVER)))
+        } else {
+            None
+        };
+        let workers = if self.workers > 0 {
+            Some(try!(workers::Pool::spawn(&poll,
+                                           WORKERS,
+                                           self.workers,
+                                           self.limits.worker_packets)))
+        } else {
+            None
+        };
+
+        let forwarder = if self.forwards.is_empty() {
+            None
+        } else {
+            let mut forwarder = forward::Forwarder::new(FORWARD_BASE);
+            for forward in &self.forwards {
+                try!(forwarder.listen(&poll, forward));
+            }
+            Some(forwarder)
+        };
+
+        let now = self.clock.now();
+        let shaper = self.max_bandwidth.map(|rate| {
+            info!("Capping tunnel traffic at {} bytes per second.", rate);
+            shaper::Shaper::new(rate, now)
+        });
+
+        Ok(Server {
+            caps: Capabilities::new(if self.compression {
+                CAP_COMPRESSION | CAP_KEEPALIVE | CAP_SUBNET | CAP_OPTIONS
+            } else {
+                CAP_KEEPALIVE | CAP_SUBNET | CAP_OPTIONS
+            } | if cfg!(feature = "obfuscation") { CAP_COVER } else { 0 } |
+                                    if self.coalesce.is_some() { CAP_BATCH } else { 0 } |
+                                    if self.streams { CAP_STREAMS } else { 0 }),
+            qos: self.qos,
+            drop_policy: self.drop_policy,
+            queues: HashMap::new(),
+            queue_bytes: self.limits.queue_bytes,
+            coalescer: self.coalesce.map(|window| {
+                info!("Coalescing small packets for up to {:?}.", window);
+                batch::Coalescer::new(window)
+            }),
+            subnet: self.subnet,
+            compression_threshold: self.compression_threshold,
+            compression_overrides: compression_overrides,
+            mtu_overrides: mtu_overrides,
+            governor: if self.adaptive_compression {
+                Some(adaptive::Governor::new(now, adaptive::cpu_time(), 0))
+            } else {
+                None
+            },
+            clamp_mss: if self.clamp_mss { Some(mtu) } else { None },
+            verify_checksums: if self.verify_checksums || self.drop_corrupt {
+                Some(self.drop_corrupt)
+            } else {
+                None
+            },
+            dscp: if self.propagate_dscp {
+                Some(DscpMarker::new())
+            } else {
+                None
+            },
+            groups: self.groups,
+            acl: self.acl,
+            iroutes: iroutes,
+            delegation: delegation,
+            devices: devices,
+            leases: HashMap::new(),
+            _kernel_routes: kernel_routes,
+            nat: if self.userspace_nat {
+                Some(nat::Nat::new(NAT_BASE, self.limits.nat_flows))
+            } else {
+                None
+            },
+            forwarder: forwarder,
+            exits: if self.streams {
+                Some(stream::Exits::new(STREAM_BASE,
+                                        self.limits.streams,
+                                        self.limits.client_streams,
+                                        self.limits.stream_bytes))
+            } else {
+                None
+            },
+            resolver: resolver,
+            local_streams: self.local_streams,
+            dns: dns,
+            pushed: self.pushed,
+            tun_mtu: None,
+            tun_addresses: Vec::new(),
+            discovery: discovery,
+            replica: replica,
+            standby: standby,
+            backend_id: self.backend_id,
+            tracer: self.tracer,
+            spans: HashMap::new(),
+            counters: stats::Counters::default(),
+            #[cfg(feature = "metrics")]
+            statsd: statsd,
+            #[cfg(feature = "metrics")]
+            health: health,
+            #[cfg(feature = "metrics")]
+            netflow: netflow,
+            #[cfg(feature = "dashboard")]
+            dashboard: dashboard,
+            netem: self.netem.map(netem::Emulator::new),
+            shaper: shaper,
+            max_lifetime: self.max_lifetime,
+            rtt_interval: self.rtt_interval,
+            draining: false,
+            drain_deadline: None,
+            lockout: self.lockout.map(lockout::Lockout::new),
+            warnings: loglimit::LogLimiter::default(),
+            history: history,
+            sources: self.sources,
+            geoip: self.geoip,
+            #[cfg(feature = "admin")]
+            control: control,
+            _forwarding: forwarding,
+            _masquerade: masquerade,
+            callback: self.callback,
+            poll: poll,
+            sockfd: sockfd,
+            session_sock: session_sock,
+            on_session_port: false,
+            local_addr: local_addr,
+            nested: false,
+            capture: capture,
+            mirror: mirror,
+            tun: tun,
+            relay_only: self.relay_only,
+            tap: self.tap,
+            shutdown: shutdown,
+            _registration: registration,
+            handshakes: handshakes,
+            workers: workers,
+            clock: self.clock,
+            ids: allocation::Pool::new(self.allocator),
+            client_info: HashMap::new(),
+            released: HashMap::new(),
+            stale_notices: HashMap::new(),
+            refusals: BTreeMap::new(),
+            pktinfo: pktinfo,
+            reply_from: HashMap::new(),
+            traffic: HashMap::new(),
+            rate_limits: HashMap::new(),
+            rates: HashMap::new(),
+            egress_marks: HashSet::new(),
+            no_egress: HashSet::new(),
+            // One spare byte in each buffer to detect truncation.
+            sock_buf: vec![0u8; frame_capacity(mtu) + 1],
+            tun_buf: vec![0u8; mtu as usize + 1],
+            encoder: Encoder::new(),
+        })
+    }
+}
+
+/// Poll token of the socket receiving replicated sessions.
+const REPLICATION: mio::Token = mio::Token(5);
+/// Poll token of the handshake worker's verdicts.
+const HANDSHAKE: mio::Token = mio::Token(14);
+/// Poll token of the packet workers' results.
+const WORKERS: mio::Token = mio::Token(10);
+/// Poll token of the socket on the session port.
+const SESSION_SOCK: mio::Token = mio::Token(11);
+/// Poll token of the stream resolver's lookups.
+const RESOLVER: mio::Token = mio::Token(12);
+/// Datagrams read from the session port per turn of the loop at most, so
+/// that a flood there cannot starve the data path in turn.
+const SESSION_BURST: usize = 64;
+/// Poll token of the control socket.
+#[cfg(feature = "admin")]
+const CONTROL: mio::Token = mio::Token(15);
+/// Poll tokens of the mDNS and SSDP proxy sockets.
+const DISCOVERY_BASE: usize = 8;
+/// Poll token of the web dashboard.
+#[cfg(feature = "dashboard")]
+const DASHBOARD: mio::Token = mio::Token(16);
+/// Poll tokens of the DNS forwarder's listening and relaying sockets.
+const DNS_LISTENER: mio::Token = mio::Token(17);
+const DNS_RELAY: mio::Token = mio::Token(18);
+/// First poll token handed to NAT flow sockets.
+const NAT_BASE: usize = 19;
+/// First poll token of forwarded ports and their connections, above the NAT's.
+const FORWARD_BASE: usize = 1 << 19;
+/// First poll token handed to stream sockets, above the forwards'.
+const STREAM_BASE: usize = 1 << 20;
+
+/// Destination ports listed per client in traffic reports.
+const TOP_PORTS: usize = 5;
+
+/// How long a client may stay silent before it is probed.
+const IDLE_TIMEOUT: u64 = 60;
+/// Probes sent to a silent client before its session is expired.
+const PROBE_ATTEMPTS: u32 = 3;
+const PROBE_INTERVAL: u64 = 5;
+/// How long the id of an expired session is held back for its owner.
+const RESUME_GRACE: u64 = 180;
+/// How often to measure the round trip to each client by default.
+const RTT_INTERVAL: u64 = 30;
+/// Milliseconds between attempts to send queued packets.
+const QUEUE_TICK: u64 = 5;
+/// Endpoints told at most once a second that their session is unknown.
+const MAX_STALE_NOTICES: usize = 4096;
+/// Seconds the local address an endpoint sent to is remembered once it is
+/// not a client's, long enough to answer its handshake.
+const REPLY_FROM_TTL: u64 = 10;
+/// Endpoints whose local address is remembered at once.
+const MAX_REPLY_FROM: usize = 4096;
+/// Below this, Linux drops the IPv6 addresses of the TUN device.
+#[cfg(any(feature = "admin", feature = "dashboard"))]
+const MIN_TUN_MTU: u16 = 1280;
+
+#[derive(Clone, Copy)]
+/// Whom a session belongs to: the device it proved, else the user the
+/// authenticator admitted, else its group. What operators set at runtime is
+/// kept by principal, so that it outlasts the session and its short-lived
+/// id. Clients with none of these can only be told apart by their id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Principal {
+    Device(identity::Key),
+    User(String),
+    Group(usize),
+    Anonymous(Id),
+}
+
+impl Principal {
+    fn of(id: Id, group: usize, identity: Option<identity::Key>, user: Option<String>)
+          -> Principal {
+        match (identity, user) {
+            (Some(key), _) => Principal::Device(key),
+            (None, Some(user)) => Principal::User(user),
+            (None, None) if group > 0 => Principal::Group(group),
+            (None, None) => Principal::Anonymous(id),
+        }
+    }
+}
+
+struct ClientInfo {
+    token: Token,
+    addr: SocketAddr,
+    caps: Capabilities,
+    roaming: Option<roaming::Binding>,
+    // Index into `groups` plus one; zero when ungrouped.
+    group: usize,
+    last_heard: Instant,
+    probes_sent: u32,
+    last_probe: Instant,
+    // When the newest unanswered `Ping` went out, and the last measurement.
+    ping_sent: Option<Instant>,
+    last_rtt: Instant,
+    // When the client last completed a handshake.
+    established: Instant,
+    rtt: stats::Rtt,
+    latency: stats::Histogram,
+    link: stats::Link,
+    // Whether data went to the client since its last cover frame.
+    #[cfg(feature = "obfuscation")]
+    cover_busy: bool,
+    // Tunnel bytes from and to the client this session.
+    rx_bytes: u64,
+    tx_bytes: u64,
+    // Packets for the client dropped because it could not keep up.
+    dropped: u64,
+    // The device key the client proved, if any.
+    identity: Option<identity::Key>,
+    // What the client offered in its handshake.
+    offered: Capabilities,
+    principal: Principal,
+}
+
+pub struct Server {
+    caps: Capabilities,
+    subnet: device::Subnet,
+    compression_threshold: usize,
+    // TUN MTU to clamp TCP MSS to, if enabled.
+    clamp_mss: Option<u16>,
+    // Whether corrupt packets are dropped, if checksums are verified.
+    verify_checksums: Option<bool>,
+    dscp: Option<DscpMarker>,
+    groups: Vec<(String, String)>,
+    governor: Option<adaptive::Governor>,
+    // Whether members of a group, by index plus one, may compress.
+    compression_overrides: HashMap<usize, bool>,
+    // MTUs pushed to members of a group, by index plus one.
+    mtu_overrides: HashMap<usize, u16>,
+    acl: acl::Acl,
+    // Networks behind clients, with the index of their group plus one.
+    iroutes: Vec<(iroute::Iroute, usize)>,
+    delegation: Option<delegation::Delegation>,
+    // Ids reserved for devices, and the id each device held last.
+    devices: HashMap<identity::Key, Id>,
+    leases: HashMap<identity::Key, Id>,
+    _kernel_routes: Vec<utils::InterfaceRoute>,
+    nat: Option<nat::Nat>,
+    dns: Option<dns::Forwarder>,
+    pushed: Vec<push::PushOption>,
+    // The TUN MTU set through the control socket, pushed to every client.
+    tun_mtu: Option<u16>,
+    // Addresses added to the TUN device through the control socket.
+    tun_addresses: Vec<cidr::Cidr>,
+    discovery: Option<discovery::Proxy>,
+    replica: Option<replication::Primary>,
+    standby: Option<replication::Standby>,
+    backend_id: Option<u8>,
+    tracer: Option<telemetry::Tracer>,
+    // Session spans of traced clients.
+    spans: HashMap<Id, telemetry::Span>,
+    counters: stats::Counters,
+    #[cfg(feature = "metrics")]
+    statsd: Option<stats::Statsd>,
+    #[cfg(feature = "metrics")]
+    health: Option<health::Endpoint>,
+    #[cfg(feature = "metrics")]
+    netflow: Option<netflow::Exporter>,
+    #[cfg(feature = "dashboard")]
+    dashboard: Option<dashboard::Dashboard>,
+    netem: Option<netem::Emulator>,
+    shaper: Option<shaper::Shaper>,
+    coalescer: Option<batch::Coalescer>,
+    forwarder: Option<forward::Forwarder>,
+    // Connections made for clients' streams, if allowed.
+    exits: Option<stream::Exits>,
+    // Looks up the names streams go to, if streams are allowed.
+    resolver: Option<stream::Resolver>,
+    local_streams: bool,
+    qos: Option<qos::Classifier>,
+    drop_policy: qos::DropPolicy,
+    // Packets held back by the shapers, if QoS is on.
+    queues: HashMap<Id, qos::Queues>,
+    queue_bytes: usize,
+    max_lifetime: Option<Duration>,
+    rtt_interval: Duration,
+    // While draining, handshakes are refused; sessions end at the deadline.
+    draining: bool,
+    drain_deadline: Option<Instant>,
+    lockout: Option<lockout::Lockout>,
+    // Keeps floods of junk from flooding the log with warnings too.
+    warnings: loglimit::LogLimiter,
+    history: Option<history::History>,
+    sources: cidr::Filter,
+    geoip: Option<geoip::Policy>,
+    #[cfg(feature = "admin")]
+    control: Option<control::Listener>,
+    // RAII so ignore unused variable warning
+    _forwarding: Option<utils::Sysctl>,
+    _masquerade: Option<firewall::Masquerade>,
+    callback: Option<Callback>,
+    poll: mio::Poll,
+    sockfd: mio::udp::UdpSocket,
+    session_sock: Option<mio::udp::UdpSocket>,
+    // Whether replies go out on the session port, for the datagram or
+    // handshake at hand.
+    on_session_port: bool,
+    local_addr: SocketAddr,
+    // Whether a datagram arrived through the tunnel itself.
+    nested: bool,
+    capture: Option<pcap::Capture>,
+    mirror: Option<mirror::Mirror>,
+    tun: Box<device::VirtualInterface>,
+    // Whether packets between clients bypass the (absent) TUN device.
+    relay_only: bool,
+    // Whether the device is a TAP one, which answers ARP for clients.
+    tap: bool,
+    shutdown: ShutdownHandle,
+    _registration: mio::Registration,
+    handshakes: handshake::Worker,
+    workers: Option<workers::Pool>,
+    clock: Box<Clock>,
+    ids: allocation::Pool,
+    client_info: HashMap<Id, ClientInfo>,
+    // Ids of recently expired sessions, with the token that may resume them.
+    released: HashMap<Id, (Token, Instant)>,
+    // When each endpoint was last told that its session is unknown.
+    stale_notices: HashMap<SocketAddr, Instant>,
+    // Handshakes refused since start, by code.
+    refusals: BTreeMap<&'static str, u64>,
+    // Whether the sockets report which local address a datagram arrived on.
+    pktinfo: bool,
+    // The local address each endpoint last sent to, and when, to reply from.
+    reply_from: HashMap<SocketAddr, (Ipv4Addr, Instant)>,
+    // What each client sends, for operators.
+    traffic: HashMap<Id, stats::Traffic>,
+    // Rate limits set through the control socket, in bytes per second.
+    rate_limits: HashMap<Principal, u64>,
+    // The shapers enforcing them on the clients they apply to.
+    rates: HashMap<Id, shaper::Shaper>,
+    // Whom operators keep inside the tunnel through the control socket.
+    egress_marks: HashSet<Principal>,
+    // The clients those are, kept up to date with the sessions.
+    no_egress: HashSet<Id>,
+    sock_buf: Vec<u8>,
+    tun_buf: Vec<u8>,
+    encoder: Encoder,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            port: 8964,
+            bind_address: None,
+            session_port: None,
+            subnet: device::Subnet::default(),
+            device_name: None,
+            tap: false,
+            tun_fd: None,
+            compression: true,
+            compression_threshold: COMPRESSION_THRESHOLD,
+            adaptive_compression: false,
+            clamp_mss: false,
+            verify_checksums: false,
+            drop_corrupt: false,
+            propagate_dscp: false,
+            outer: OuterOptions::default(),
+            userspace_nat: false,
+            relay_only: false,
+            dns_upstreams: Vec::new(),
+            pushed: Vec::new(),
+            restore_sysctls: true,
+            masquerade: None,
+            firewall: None,
+            discovery_proxy: false,
+            groups: Vec::new(),
+            require_stamps: None,
+            group_compression: Vec::new(),
+            group_mtu: Vec::new(),
+            acl: acl::Acl::default(),
+            iroutes: Vec::new(),
+            forwards: Vec::new(),
+            delegation: None,
+            devices: Vec::new(),
+            limits: Limits::default(),
+            workers: 0,
+            replicate_to: None,
+            standby: None,
+            backend_id: None,
+            tracer: None,
+            #[cfg(feature = "metrics")]
+            statsd: None,
+            #[cfg(feature = "metrics")]
+            health: None,
+            #[cfg(feature = "metrics")]
+            netflow: None,
+            #[cfg(feature = "dashboard")]
+            dashboard: None,
+            netem: None,
+            max_bandwidth: None,
+            coalesce: None,
+            qos: None,
+            drop_policy: qos::DropPolicy::Tail,
+            streams: false,
+            local_streams: false,
+            max_lifetime: None,
+            rtt_interval: Duration::from_secs(RTT_INTERVAL),
+            lockout: None,
+            history: None,
+            sources: cidr::Filter::default(),
+            geoip: None,
+            #[cfg(feature = "admin")]
+            control: None,
+            authenticator: None,
+            allocator: Box::new(allocation::Strategy::default()),
+            rng: None,
+            clock: Box::new(SystemClock),
+            callback: None,
+            pcap: None,
+            mirror: None,
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Traffic through the tunnel since the server started.
+    pub fn counters(&self) -> stats::Counters {
+        self.counters
+    }
+
+    /// Runs the server until it is shut down.
+    pub fn run(&mut self) -> Result<()> {
+        info!("Working in server mode.");
+        info!("Ready for transmission.");
+
+        let result = self.run_loop();
+        if let Some(ref history) = self.history {
+            for (&id, info) in &self.client_info {
+                history.end(id, "shutdown", info.rx_bytes, info.tx_bytes);
+            }
+        }
+        if let Some(ref tracer) = self.tracer {
+            for (_, mut span) in self.spans.drain() {
+                match result {
+                    Ok(()) => span.set("end.reason", "shutdown"),
+                    Err(ref e) => span.fail(e),
+                }
+                tracer.end(span);
+            }
+        }
+        result
+    }
+
+    fn run_loop(&mut self) -> Result<()> {
+        let mut events = mio::Events::with_capacity(1024);
+
+        loop {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+
+            try!(self.check_clients());
+            if let Some(ref mut nat) = self.nat {
+                nat.expire(&self.poll, self.clock.now());
+            }
+            if let Some(ref mut dns) = self.dns {
+                dns.expire(self.clock.now());
+            }
+            self.replicate();
+            if let Some(ref mut governor) = self.governor {
+                let now = self.clock.now();
+                match governor.update(now, adaptive::cpu_time(), self.counters.tx_bytes) {
+                    Some(false) => info!("CPU is saturated. Sending uncompressed frames."),
+                    Some(true) => info!("CPU has headroom again. Resuming compression."),
+                    None => {}
+                }
+            }
+            #[cfg(feature = "metrics")]
+            {
+                if let Some(ref mut statsd) = self.statsd {
+                    let mut gauges = vec![(String::from("clients"), self.client_info.len() as u64)];
+                    let mut versions = HashMap::new();
+                    let mut compressed = 0;
+                    for info in self.client_info.values() {
+                        *versions.entry(info.caps.version).or_insert(0) += 1;
+                        if info.caps.has(CAP_SNAPPY) {
+                            compressed += 1;
+                        }
+                    }
+                    for (version, count) in versions {
+                        gauges.push((format!("clients.version.{}", version), count));
+                    }
+                    gauges.push((String::from("clients.compressed"), compressed));
+                    for (id, info) in &self.client_info {
+                        if let Some(srtt) = info.rtt.srtt() {
+                            gauges.push((format!("client.{}.rtt_ms", id), stats::millis(srtt)));
+                            gauges.push((format!("client.{}.rttvar_ms", id),
+                                         stats::millis(info.rtt.rttvar())));
+                        }
+                        gauges.extend(info.latency.gauges(&format!("client.{}.", id)));
+                        if let Some(loss) = info.link.loss() {
+                            gauges.push((format!("client.{}.loss_pct", id), loss));
+                            gauges.push((format!("client.{}.jitter_ms", id),
+                                         stats::millis(info.link.jitter())));
+                        }
+                    }
+                    statsd.flush(&self.counters, &gauges, self.clock.now());
+                }
+                if let Some(ref mut netflow) = self.netflow {
+                    netflow.expire(self.clock.now());
+                }
+            }
+
+            let now = self.clock.now();
+            let deadline = self.netem.as_ref().and_then(|netem| netem.deadline());
+            let deadline = match (deadline, self.coalescer.as_ref().and_then(|c| c.deadline())) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b),
+            };
+            let timeout = match deadline {
+                Some(deadline) => cmp::min(remaining(deadline, now), Duration::from_secs(1)),
+                None => Duration::from_secs(1),
+            };
+            let timeout = if self.queues.is_empty() {
+                timeout
+            } else {
+                cmp::min(timeout, Duration::from_millis(QUEUE_TICK))
+            };
+            let timeout = if self.exits.as_ref().map_or(true, |exits| exits.is_empty()) {
+                timeout
+            } else {
+                cmp::min(timeout, Duration::from_millis(stream::RETRANSMIT))
+            };
+            try!(self.poll.poll(&mut events, Some(timeout)));
+            self.tun.set_now(self.clock.now());
+            if let Some(ref mut netem) = self.netem {
+                try!(netem.release(&self.sockfd, self.clock.now()));
+            }
+            let due = match self.coalescer {
+                Some(ref mut coalescer) => coalescer.due(self.clock.now()),
+                None => Vec::new(),
+            };
+            for (id, packets) in due {
+                try!(self.send_batch(id, packets));
+            }
+            try!(self.drain_queues());
+            let now = self.clock.now();
+            let out = match self.exits {
+                Some(ref mut exits) => exits.tick(&self.poll, now),
+                None => Vec::new(),
+            };
+            try!(self.send_streams(out));
+
+            if events.iter().any(|event| event.token() == SESSION_SOCK) {
+                // Session maintenance goes ahead of data.
+                try!(self.handle_session_socket());
+            }
+            for event in events.iter() {
+                match event.token() {
+                    SOCK => try!(self.handle_socket()),
+                    SESSION_SOCK => {}
+                    TUN => try!(self.handle_tun()),
+                    SHUTDOWN | SIGNAL => {}
+                    REPLICATION => try!(self.handle_replication()),
+                    #[cfg(feature = "admin")]
+                    CONTROL => self.answer_control(),
+                    #[cfg(feature = "dashboard")]
+                    DASHBOARD => self.serve_dashboard(),
+                    HANDSHAKE => try!(self.finish_handshakes()),
+                    RESOLVER => try!(self.finish_lookups()),
+                    WORKERS => try!(self.finish_work()),
+                    STATUS => {
+                        if signal::take_status_request() {
+                            self.log_status();
+                        }
+                    }
+                    #[cfg(feature = "metrics")]
+                    HEALTH => {
+                        if let Some(ref health) = self.health {
+                            let state = if self.draining { "draining" } else { "ok" };
+                            let mut status = vec![("status", String::from(state)),
+                                                  ("tun", String::from(self.tun.name())),
+                                                  ("clients", self.client_info.len().to_string())];
+                            for (id, info) in &self.client_info {
+                                status.push(("protocol",
+                                             format!("{} {} {}",
+                                                     id,
+                                                     info.caps.version,
+                                                     info.caps.flag_names())));
+                                if let Some(srtt) = info.rtt.srtt() {
+                                    status.push(("rtt",
+                                                 format!("{} {}ms {}ms",
+                                                         id,
+                                                         stats::millis(srtt),
+                                                         stats::millis(info.rtt.rttvar()))));
+                                }
+                                if let Some(loss) = info.link.loss() {
+                                    status.push(("link",
+                                                 format!("{} {}% {}ms",
+                                                         id,
+                                                         loss,
+                                                         stats::millis(info.link.jitter()))));
+                                }
+                            }
+                            health.respond(&status);
+                        }
+                    }
+                    DNS_LISTENER => {
+                        if let Some(ref mut dns) = self.dns {
+                            try!(dns.query(self.clock.now()));
+                        }
+                    }
+                    DNS_RELAY => {
+                        if let Some(ref mut dns) = self.dns {
+                            try!(dns.answer());
+                        }
+                    }
+                    token if token.0 >= STREAM_BASE => try!(self.handle_stream(token)),
+                    token if token.0 >= FORWARD_BASE => {
+                        if let Some(ref mut forwarder) = self.forwarder {
+                            forwarder.ready(&self.poll, token);
+                        }
+                    }
+                    token if token.0 >= NAT_BASE => try!(self.handle_nat(token)),
+                    token => try!(self.handle_discovery(token)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a snapshot of the sessions to the standby when one is due.
+    fn replicate(&mut self) {
+        let now = self.clock.now();
+        if let Some(ref mut replica) = self.replica {
+            let sessions = self.client_info
+                .iter()
+                .map(|(&id, info)| {
+                    replication::Session {
+                        id: id,
+                        token: info.token,
+                        addr: info.addr,
+                        caps: info.caps,
+                        group: info.group,
+                        roaming: info.roaming,
+                    }
+                })
+                .collect();
+            if let Err(e) = replica.sync(sessions, now) {
+                warn!("Failed to replicate sessions: {}", e);
+            }
+        }
+    }
+
+    /// Replaces the sessions with those of the primary.
+    fn handle_replication(&mut self) -> Result<()> {
+        let sessions = match self.standby {
+            Some(ref mut standby) => {
+                match try!(standby.receive()) {
+                    Some(sessions) => sessions,
+                    None => return Ok(()),
+                }
+            }
+            None => return Ok(()),
+        };
+        let now = self.clock.now();
+        let ended: Vec<Id> = self.client_info
+            .keys()
+            .filter(|id| !sessions.iter().any(|s| s.id == **id))
+            .cloned()
+            .collect();
+        for id in ended {
+            self.client_info.remove(&id);
+            self.end_streams(id);
+            self.ids.put(id);
+        }
+        debug!("Installing {} replicated sessions.", sessions.len());
+        for session in sessions {
+            self.ids.take(session.id);
+            self.released.remove(&session.id);
+            self.client_info.insert(session.id,
+                                    ClientInfo {
+                                        token: session.token,
+                                        addr: session.addr,
+                                        caps: session.caps,
+                                        roaming: session.roaming,
+                                        group: session.group,
+                                        last_heard: now,
+                                        probes_sent: 0,
+                                        last_probe: now,
+                                        ping_sent: None,
+                                        last_rtt: now,
+                                        established: now,
+                                        rtt: stats::Rtt::default(),
+                                        latency: stats::Histogram::default(),
+                                        link: stats::Link::default(),
+                                        #[cfg(feature = "obfuscation")]
+                                        cover_busy: false,
+                                        rx_bytes: 0,
+                                        tx_bytes: 0,
+                                        dropped: 0,
+                                        identity: None,
+                                        offered: session.caps,
+                                        principal: Principal::of(session.id,
+                                                                 session.group,
+                                                                 None,
+                                                                 None),
+                                    });
+            self.restrict(session.id);
+        }
+        self.sync_neighbours();
+        Ok(())
+    }
+
+    fn log_status(&self) {
+        info!("{} clients. Received {} bytes in {} packets, sent {} bytes in {} packets.",
+              self.client_info.len(),
+              self.counters.rx_bytes,
+              self.counters.rx_packets,
+              self.counters.tx_bytes,
+              self.counters.tx_packets);
+        if self.verify_checksums.is_some() {
+            info!("{} packets with wrong checksums.", self.counters.corrupt);
+        }
+        if self.counters.overflow > 0 {
+            info!("{} packets, segments or handshakes dropped at limits.",
+                  self.counters.overflow);
+        }
+        for (id, info) in &self.client_info {
+            info!("Client {} at {}: protocol version {} ({}), MTU {}, round trip {}, loss {}, \
+                   jitter {}ms, {} dropped.",
+                  id,
+                  info.addr,
+                  info.caps.version,
+                  info.caps.flag_names(),
+                  info.caps.mtu,
+                  info.rtt.srtt().map_or(String::from("unknown"),
+                                         |srtt| format!("{}ms", stats::millis(srtt))),
+                  info.link.loss().map_or(String::from("unknown"), |loss| format!("{}%", loss)),
+                  stats::millis(info.link.jitter()),
+                  info.dropped);
+            if let Some(traffic) = self.traffic.get(id) {
+                info!("Client {} sent {}.", id, traffic.summary(TOP_PORTS));
+            }
+        }
+    }
+
+    /// Ends the traced session of client `id`, if any.
+    fn end_span(&mut self, id: Id, reason: &str) {
+        if let (Some(tracer), Some(mut span)) = (self.tracer.as_ref(), self.spans.remove(&id)) {
+            span.set("end.reason", reason);
+            tracer.end(span);
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        #[cfg(feature = "dashboard")]
+        {
+            if let Some(ref mut dashboard) = self.dashboard {
+                dashboard.record(&event);
+            }
+        }
+        if let Some(ref callback) = self.callback {
+            callback(&event);
+        }
+    }
+
+    /// Probes clients that have gone quiet and expires those that never
+    /// answer, telling them so in case they can still hear us.
+    fn check_clients(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let mut probes = Vec::new();
+        let mut pings = Vec::new();
+        let mut expired = Vec::new();
+        let max_lifetime = self.max_lifetime;
+        let rtt_interval = self.rtt_interval;
+        let drained = self.drain_deadline.map_or(false, |deadline| now >= deadline);
+        for (&id, info) in self.client_info.iter_mut() {
+            if drained {
+                info!("Drain deadline passed. Ending the session of client {}.", id);
+                expired.push(id);
+                continue;
+            }
+            if max_lifetime.map_or(false, |max| now.duration_since(info.established) >= max) {
+                info!("Client {} reached the maximum session lifetime.", id);
+                expired.push(id);
+                continue;
+            }
+            if now.duration_since(info.last_heard) < Duration::from_secs(IDLE_TIMEOUT) {
+                if info.caps.has(CAP_KEEPALIVE) &&
+                   now.duration_since(info.last_rtt) >= rtt_interval {
+                    info.last_rtt = now;
+                    info.ping_sent = Some(now);
+                    info.link.ping();
+                    pings.push(id);
+                }
+                continue;
+            }
+            // A silent client is probed rather than measured, so that round
+            // trip pings never hold off its expiry.
+            let probe_due = info.probes_sent == 0 ||
+                            now.duration_since(info.last_probe) >=
+                            Duration::from_secs(PROBE_INTERVAL);
+            if !probe_due {
+                continue;
+            }
+            if !info.caps.has(CAP_KEEPALIVE) || info.probes_sent == PROBE_ATTEMPTS {
+                expired.push(id);
+            } else {
+                info.probes_sent += 1;
+                info.last_probe = now;
+                info.ping_sent = Some(now);
+                info.link.ping();
+                probes.push(id);
+            }
+        }
+
+        for id in pings {
+            let info = self.client_info[&id];
+            debug!("Measuring round trip to client {}.", id);
+            try!(self.send(&Message::Ping {
+                               id: id,
+                               token: info.token,
+                           },
+                           &info.addr));
+        }
+
+        for id in probes {
+            let info = self.client_info[&id];
+            debug!("Probing silent client {} at {} ({}/{}).",
+                   id,
+                   info.addr,
+                   info.probes_sent,
+                   PROBE_ATTEMPTS);
+            try!(self.send(&Message::Ping {
+                               id: id,
+                               token: info.token,
+                           },
+                           &info.addr));
+        }
+
+        for id in expired {
+            let info = self.client_info.remove(&id).unwrap();
+            self.traffic.remove(&id);
+            self.rates.remove(&id);
+            self.no_egress.remove(&id);
+            self.queues.remove(&id);
+            self.end_streams(id);
+            if let Some(ref history) = self.history {
+                history.end(id, "expired", info.rx_bytes, info.tx_bytes);
+            }
+            info!("Session of client {} at {} expired.", id, info.addr);
+            let notice = Message::Expired {
+                id: id,
+                token: info.token,
+            };
+            if let Err(e) = self.send(&notice, &info.addr) {
+                debug!("Failed to notify client {} of expiry: {}", id, e);
+            }
+            self.released.insert(id, (info.token, now));
+            self.end_span(id, "expired");
+            self.emit(Event::ClientExpired { id: id });
+        }
+
+        let grace = Duration::from_secs(RESUME_GRACE);
+        let stale: Vec<Id> = self.released
+            .iter()
+            .filter(|&(_, &(_, since))| now.duration_since(since) >= grace)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in stale {
+            self.released.remove(&id);
+            self.ids.put(id);
+        }
+        self.stale_notices.retain(|_, &mut sent| now.duration_since(sent) < Duration::from_secs(1));
+        let clients: HashSet<SocketAddr> =
+            self.client_info.values().map(|info| info.addr).collect();
+        let ttl = Duration::from_secs(REPLY_FROM_TTL);
+        self.reply_from.retain(|addr, &mut (_, heard)| {
+            clients.contains(addr) || now.duration_since(heard) < ttl
+        });
+        self.warnings.expire(now);
+        self.sync_neighbours();
+        Ok(())
+    }
+
+    /// Tells a TAP device the addresses of the current clients.
+    fn sync_neighbours(&mut self) {
+        if !self.tap {
+            return;
+        }
+        let mut clients = Vec::with_capacity(self.client_info.len() * 2);
+        for &id in self.client_info.keys() {
+            clients.push((IpAddr::V4(self.subnet.addr(id)), id));
+            clients.push((IpAddr::V6(self.subnet.ipv6_addr(id)), id));
+        }
+        self.tun.set_clients(&clients);
+    }
+
+    /// Tells the client at `addr` that session `id` is unknown, most likely
+    /// because the server restarted, so that it handshakes again rather than
+    /// sending into the void.
+    fn notify_stale(&mut self, id: Id, token: Token, addr: SocketAddr) {
+        if !self.notice_due(addr) {
+            return;
+        }
+        debug!("Telling {} that session {} is unknown.", addr, id);
+        let notice = Message::Expired {
+            id: id,
+            token: token,
+        };
+        if let Err(e) = self.send(&notice, &addr) {
+            debug!("Failed to notify {} of unknown session: {}", addr, e);
+        }
+    }
+
+    /// Tells the client at `addr` why it gets no session. Counted even when
+    /// too many notices went out to send another.
+    fn refuse(&mut self, code: Code, addr: SocketAddr) {
+        *self.refusals.entry(code.as_str()).or_insert(0) += 1;
+        if !self.notice_due(addr) {
+            return;
+        }
+        debug!("Refusing the handshake from {} with {}.", addr, code);
+        if let Err(e) = self.send(&Message::Refused { code: code }, &addr) {
+            debug!("Failed to refuse handshake from {}: {}", addr, e);
+        }
+    }
+
+    /// Whether a notice may go to `addr`: at most one a second, so that
+    /// spoofed datagrams cannot make the server flood someone.
+    fn notice_due(&mut self, addr: SocketAddr) -> bool {
+        let now = self.clock.now();
+        let due = match self.stale_notices.get(&addr) {
+            Some(&sent) => now.duration_since(sent) >= Duration::from_secs(1),
+            None => self.stale_notices.len() < MAX_STALE_NOTICES,
+        };
+        if due {
+            self.stale_notices.insert(addr, now);
+        }
+        due
+    }
+
+    /// Picks an id for a new session and tells the allocator about it.
+    fn allocate_id(&mut self,
+                   resume: Option<(Id, Token)>,
+                   identity: Option<identity::Key>)
+                   -> Option<Id> {
+        let id = self.choose_id(resume, identity);
+        if let Some(id) = id {
+            self.ids.assigned(id, identity.as_ref());
+        }
+        id
+    }
+
+    /// The one reserved for or last held by the device proving `identity`,
+    /// else the one named in `resume` once its session has ended, else one
+    /// the allocator picks. Ids held back for other clients are
+    /// only handed out once the pool is otherwise empty.
+    fn choose_id(&mut self,
+                 resume: Option<(Id, Token)>,
+                 identity: Option<identity::Key>)
+                 -> Option<Id> {
+        // A lease is dropped once its id goes to anyone else, so the id is
+        // free, released by the device or still held by it.
+        let claimed = identity.and_then(|key| {
+            self.devices.get(&key).or_else(|| self.leases.get(&key)).cloned()
+        });
+        if let Some(id) = claimed {
+            if let Some(info) = self.client_info.remove(&id) {
+                if let Some(ref history) = self.history {
+                    history.end(id, "resumed", info.rx_bytes, info.tx_bytes);
+                }
+                self.end_span(id, "resumed");
+            }
+            self.released.remove(&id);
+            self.ids.take(id);
+            return Some(id);
+        }
+        if let Some((id, token)) = resume {
+            // A live session is never taken over: its token travels in every
+            // frame, so anyone on the path could name it.
+            let released = match self.released.get(&id) {
+                Some(&(released_token, _)) => released_token == token,
+                None => false,
+            };
+            if released {
+                self.released.remove(&id);
+                return Some(id);
+            }
+        }
+        let devices = &self.devices;
+        let reserved = |id: Id| devices.values().any(|&device| device == id);
+        if let Some(id) = self.ids.next(identity.as_ref(), |id| !reserved(id)) {
+            return Some(id);
+        }
+        let oldest = self.released
+            .iter()
+            .filter(|&(&id, _)| !reserved(id))
+            .min_by_key(|&(_, &(_, since))| since)
+            .map(|(&id, _)| id);
+        if let Some(id) = oldest {
+            self.released.remove(&id);
+        }
+        oldest
+    }
+
+    /// The options pushed to client `id`, encoded.
+    fn options_for(&self, id: Id, mtu: Option<u16>) -> Vec<push::Raw> {
+        let mut options = vec![push::PushOption::Address(self.subnet.addr(id), 24)];
+        if self.dns.is_some() {
+            options.push(push::PushOption::Dns(IpAddr::V4(self.subnet.addr(1))));
+        }
+        options.extend(self.pushed.iter().cloned());
+        options.extend(mtu.map(push::PushOption::Mtu));
+        options.extend(self.delegation.map(|delegation| {
+            let (net, prefix) = delegation.prefix_of(id);
+            push::PushOption::Prefix(net, prefix)
+        }));
+        options.iter().map(|option| option.encode()).collect()
+    }
+
+    /// Sends the clients that take pushed options theirs again, after they
+    /// changed at runtime. Their sessions go on.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn repush(&mut self) {
+        let updates: Vec<(SocketAddr, Message)> = self.client_info
+            .iter()
+            .filter(|&(_, info)| info.caps.has(CAP_OPTIONS))
+            .map(|(&id, info)| {
+                (info.addr,
+                 Message::Configured {
+                     id: id,
+                     token: info.token,
+                     caps: info.caps,
+                     options: self.options_for(id, Some(info.caps.mtu)),
+                     // The roaming key agreed in the handshake stays.
+                     roam_key: Vec::new(),
+                 })
+            })
+            .collect();
+        for (addr, update) in updates {
+            if let Err(e) = self.send(&update, &addr) {
+                debug!("Failed to push options to {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Whether `addr` is in the tunnel subnet or a route pushed to clients,
+    /// where clients kept inside the tunnel may still go.
+    fn inside(&self, addr: IpAddr) -> bool {
+        self.subnet.id_of(addr).is_some() ||
+        self.pushed.iter().any(|option| match *option {
+            push::PushOption::Route(net, prefix) => {
+                let route = cidr::Cidr {
+                    addr: IpAddr::V4(net),
+                    prefix: prefix,
+                };
+                route.contains(addr)
+            }
+            _ => false,
+        })
+    }
+
+    /// Whether a packet from `sender` is addressed to a client in another
+    /// isolation group.
+    fn isolated(&self, sender: &ClientInfo, data: &[u8]) -> bool {
+        match route_id(&self.subnet, data) {
+            Ok(id) => {
+                self.client_info
+                    .get(&id)
+                    .map_or(false, |receiver| receiver.group != sender.group)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The client that owns the network holding a packet's destination: the
+    /// most recently heard member of the route's group.
+    fn iroute_owner(&self, data: &[u8]) -> Option<Id> {
+        let dst = match packet::destination(data) {
+            Ok(IpAddr::V4(dst)) => dst,
+            _ => return None,
+        };
+        let group = match self.iroutes.iter().find(|&&(ref route, _)| route.contains(dst)) {
+            Some(&(_, group)) => group,
+            None => return None,
+        };
+        self.client_info
+            .iter()
+            .filter(|&(_, info)| info.group == group)
+            .max_by_key(|&(_, info)| info.last_heard)
+            .map(|(&id, _)| id)
+    }
+
+    /// The client a packet's destination was delegated to, or that owns it
+    /// through an iroute.
+    fn downstream_owner(&self, data: &[u8]) -> Option<Id> {
+        match (packet::destination(data), self.delegation) {
+            (Ok(IpAddr::V6(dst)), Some(delegation)) => delegation.owner(dst),
+            _ => self.iroute_owner(data),
+        }
+    }
+
+    /// Admits the clients whose requests the handshake worker accepted.
+    fn finish_handshakes(&mut self) -> Result<()> {
+        for verdict in self.handshakes.verdicts() {
+            let addr = verdict.addr;
+            let group = match verdict.group {
+                Some(group) => group,
+                None => {
+                    if self.may_warn("credential", &addr) {
+                        warn!("[{}] Unknown credential from {}.", Code::AuthFailed, addr);
+                    }
+                    if !self.auth_failed(addr) {
+                        self.refuse(Code::AuthFailed, addr);
+                    }
+                    continue;
+                }
+            };
+            let now = self.clock.now();
+            if self.lockout.as_mut().map_or(false, |lockout| lockout.banned(addr.ip(), now)) {
+                continue;
+            }
+            if self.subnet != device::Subnet::default() && !verdict.caps.has(CAP_SUBNET) {
+                if self.may_warn("version", &addr) {
+                    warn!("[{}] Client at {} cannot use subnet {}. Refusing its request.",
+                          Code::VersionMismatch,
+                          addr,
+                          self.subnet);
+                }
+                self.refuse(Code::VersionMismatch, addr);
+                continue;
+            }
+            let client_id: Id = match self.allocate_id(verdict.resume, verdict.identity) {
+                Some(id) => id,
+                None => {
+                    if self.may_warn("pool", &addr) {
+                        warn!("[{}] Address pool exhausted. Refusing request from {}.",
+                              Code::PoolExhausted,
+                              addr);
+                    }
+                    self.refuse(Code::PoolExhausted, addr);
+                    if let Some(ref tracer) = self.tracer {
+                        let mut span = tracer.span("handshake");
+                        span.set("client.addr", addr);
+                        span.fail("address pool exhausted");
+                        tracer.end(span);
+                    }
+                    continue;
+                }
+            };
+            let client_token = verdict.token;
+            let caps = verdict.caps;
+            let resume = verdict.resume;
+            self.leases.retain(|_, &mut leased| leased != client_id);
+            if let Some(key) = verdict.identity {
+                info!("Client {} is device {}.", client_id, identity::fingerprint(&key));
+                self.leases.insert(key, client_id);
+            }
+
+            let mut offered = self.caps;
+            match self.compression_overrides.get(&group) {
+                Some(&true) => offered.flags |= CAP_COMPRESSION,
+                Some(&false) => offered.flags &= !(CAP_SNAPPY | CAP_RAW_DATA),
+                None => {}
+            }
+            let client_caps = offered.negotiate(&caps);
+            if group > 0 {
+                info!("Client {} joins group {}.", client_id, self.groups[group - 1].0);
+            }
+            // Packets above the pushed MTU get an ICMP error here rather than
+            // being dropped by the client.
+            let mtu = match (self.mtu_overrides.get(&group).cloned(), self.tun_mtu) {
+                (Some(group_mtu), Some(tun_mtu)) => Some(cmp::min(group_mtu, tun_mtu)),
+                (group_mtu, tun_mtu) => group_mtu.or(tun_mtu),
+            };
+            let mut session_caps = client_caps;
+            session_caps.mtu = session_mtu(caps.mtu,
+                                           self.tun_mtu.unwrap_or(self.caps.mtu),
+                                           self.mtu_overrides.get(&group).cloned());
+
+            self.traffic.remove(&client_id);
+            self.rates.remove(&client_id);
+            self.queues.remove(&client_id);
+            self.end_streams(client_id);
+            let principal = Principal::of(client_id, group, verdict.identity, verdict.user);
+            self.client_info.insert(client_id,
+                                    ClientInfo {
+                                        token: client_token,
+                                        addr: addr,
+                                        caps: session_caps,
+                                        roaming: verdict.roaming,
+                                        group: group,
+                                        last_heard: now,
+                                        probes_sent: 0,
+                                        last_probe: now,
+                                        ping_sent: None,
+                                        last_rtt: now,
+                                        established: now,
+                                        rtt: stats::Rtt::default(),
+                                        latency: stats::Histogram::default(),
+                                        link: stats::Link::default(),
+                                        #[cfg(feature = "obfuscation")]
+                                        cover_busy: false,
+                                        rx_bytes: 0,
+                                        tx_bytes: 0,
+                                        dropped: 0,
+                                        identity: verdict.identity,
+                                        offered: caps,
+                                        principal: principal,
+                                    });
+            self.restrict(client_id);
+
+            info!("Got request from {}. Assigning IP address: {}.",
+                  addr,
+                  self.subnet.addr(client_id));
+            debug!("Client {} offered {:?}. Agreed on {:?}.",
+                   client_id,
+                   caps,
+                   client_caps);
+
+            let reply = if client_caps.has(CAP_OPTIONS) {
+                Message::Configured {
+                    id: client_id,
+                    token: client_token,
+                    caps: client_caps,
+                    options: self.options_for(client_id, mtu.map(|_| session_caps.mtu)),
+                    roam_key: verdict.roam_key,
+                }
+            } else if client_caps.has(CAP_SUBNET) {
+                Message::Assigned {
+                    id: client_id,
+                    token: client_token,
+                    caps: client_caps,
+                    subnet: self.subnet,
+                    roam_key: verdict.roam_key,
+                }
+            } else {
+                Message::Response {
+                    id: client_id,
+                    token: client_token,
+                    caps: client_caps,
+                    roam_key: verdict.roam_key,
+                }
+            };
+            if let Some(ref tracer) = self.tracer {
+                let mut session = tracer.span("session");
+                session.set("client.id", client_id);
+                session.set("client.addr", addr);
+                session.set("group", group);
+                let mut handshake = session.child("handshake");
+                handshake.set("resumed", resume.map_or(false, |(id, _)| id == client_id));
+                tracer.end(handshake);
+                self.spans.insert(client_id, session);
+            }
+            self.sync_neighbours();
+            #[cfg_attr(not(feature = "obfuscation"), allow(unused_mut))]
+            let mut reply = try!(encode_message(&reply));
+            #[cfg(feature = "obfuscation")]
+            {
+                if verdict.padded {
+                    obfs::pad(&mut reply, obfs::Config::default().max_padding);
+                }
+            }
+            self.on_session_port = verdict.session_port;
+            let sent = self.send_buf(reply, &addr);
+            self.on_session_port = false;
+            try!(sent);
+            if let Some(ref history) = self.history {
+                let identity = match verdict.identity {
+                    _ if group > 0 => self.groups[group - 1].0.clone(),
+                    Some(key) => identity::fingerprint(&key),
+                    None => String::new(),
+                };
+                history.start(client_id, &identity, &addr);
+            }
+            self.emit(Event::ClientConnected {
+                id: client_id,
+                addr: addr,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether a warning of `category` about a datagram from `addr` may be
+    /// logged, or has been said often enough lately.
+    fn may_warn(&mut self, category: &'static str, addr: &SocketAddr) -> bool {
+        let now = self.clock.now();
+        self.warnings.allow(category, addr.ip(), now)
+    }
+
+    /// Counts a credential that failed to check out in a handshake from
+    /// `addr`. Whether its source is now banned. Anyone can forge the source
+    /// of a datagram, so nothing else counts and bans only keep out new
+    /// handshakes, never the traffic of sessions.
+    fn auth_failed(&mut self, addr: SocketAddr) -> bool {
+        let now = self.clock.now();
+        let banned = self.lockout.as_mut().map_or(false, |lockout| lockout.fail(addr.ip(), now));
+        if banned {
+            warn!("Banning {} after repeated authentication failures.", addr.ip());
+        }
+        banned
+    }
+
+    #[cfg(feature = "admin")]
+    fn answer_control(&mut self) {
+        let requests = match self.control {
+            Some(ref control) => control.accept(),
+            None => return,
+        };
+        for (mut stream, command) in requests {
+            let reply = self.control(&command);
+            if let Err(e) = stream.write_all(reply.as_bytes()) {
+                debug!("Failed to answer control request: {}", e);
+            }
+        }
+    }
+
+    /// Carries out a control socket command and returns the answer.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn control(&mut self, command: &str) -> String {
+        match command.split_whitespace().next() {
+            Some("acl") => return self.edit_acl(command),
+            Some("sources") => return self.edit_sources(command),
+            Some("rate") => return self.edit_rate(command),
+            Some("egress") => return self.edit_egress(command),
+            Some("tun") => return self.edit_tun(command),
+            Some("drain") => return self.drain(command),
+            Some("log") => return verbosity::control(command),
+            Some("clients") => return self.list_clients(),
+            Some("refusals") => {
+                return self.refusals
+                    .iter()
+                    .map(|(code, count)| format!("{} {}\n", code, count))
+                    .collect()
+            }
+            _ => {}
+        }
+        let now = self.clock.now();
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next(), self.lockout.as_mut()) {
+            (Some("bans"), None, Some(lockout)) => {
+                lockout.bans(now)
+                    .iter()
+                    .map(|&(ip, left)| format!("{} {}s\n", ip, left.as_secs()))
+                    .collect()
+            }
+            (Some("unban"), Some(target), Some(lockout)) => {
+                let ip = if target == "all" {
+                    None
+                } else {
+                    match target.parse() {
+                        Ok(ip) => Some(ip),
+                        Err(_) => return String::from("Invalid address.\n"),
+                    }
+                };
+                let lifted = lockout.unban(ip);
+                info!("Lifted {} ban(s) on request.", lifted);
+                format!("Lifted {} ban(s).\n", lifted)
+            }
+            (Some("traffic"), None, _) => {
+                let mut ids: Vec<&Id> = self.traffic.keys().collect();
+                ids.sort();
+                ids.iter()
+                    .map(|id| format!("{} {}\n", id, self.traffic[*id].summary(TOP_PORTS)))
+                    .collect()
+            }
+            (Some("latency"), None, _) => {
+                let mut ids: Vec<&Id> = self.client_info.keys().collect();
+                ids.sort();
+                ids.iter()
+                    .map(|id| {
+                        let latency = self.client_info[*id].latency;
+                        let quantiles: Vec<String> = latency.gauges("")
+                            .iter()
+                            .map(|&(ref name, ms)| format!("{}={}", name, ms))
+                            .collect();
+                        format!("{} samples={} {}\n", id, latency.count(), quantiles.join(" "))
+                    })
+                    .collect()
+            }
+            (Some("bans"), _, None) |
+            (Some("unban"), _, None) => String::from("Lockout is disabled.\n"),
+            _ => format!("Unknown command: {}\n", command),
+        }
+    }
+
+    /// One line per client with what it negotiated, so that clients still
+    /// on an old protocol version or without compression stand out.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn list_clients(&self) -> String {
+        let mut ids: Vec<&Id> = self.client_info.keys().collect();
+        ids.sort();
+        ids.iter()
+            .map(|id| {
+                let info = &self.client_info[*id];
+                format!("{} {} version={} compression={} flags={} mtu={}\n",
+                        id,
+                        info.addr,
+                        info.caps.version,
+                        info.caps.compression(),
+                        info.caps.flag_names(),
+                        info.caps.mtu)
+            })
+            .collect()
+    }
+
+    /// `drain` stops taking new sessions, `drain SECONDS` also ends the
+    /// remaining ones after that long, and `drain off` takes sessions again.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn drain(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let deadline = match words.get(1).cloned() {
+            None => None,
+            Some("off") if words.len() == 2 => {
+                self.draining = false;
+                self.drain_deadline = None;
+                info!("Taking new sessions again on request.");
+                return String::from("Taking new sessions.\n");
+            }
+            Some(seconds) if words.len() == 2 => {
+                match seconds.parse() {
+                    Ok(seconds) => Some(self.clock.now() + Duration::from_secs(seconds)),
+                    Err(_) => return String::from("Invalid number of seconds.\n"),
+                }
+            }
+            _ => return format!("Unknown command: {}\n", command),
+        };
+        self.draining = true;
+        self.drain_deadline = deadline;
+        let sessions = self.client_info.len();
+        match words.get(1) {
+            Some(seconds) => {
+                info!("Draining on request. Ending {} session(s) in {}s.", sessions, seconds);
+                format!("Draining. Ending {} session(s) in {}s.\n", sessions, seconds)
+            }
+            None => {
+                info!("Draining on request. {} session(s) remain.", sessions);
+                format!("Draining. {} session(s) remain.\n", sessions)
+            }
+        }
+    }
+
+    /// `acl`, `acl add RULE`, `acl insert N RULE` and `acl del N`. Rules are
+    /// numbered from zero in the order they are checked.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn edit_acl(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let (index, rule) = match words.get(1).cloned() {
+            None => {
+                return self.acl
+                    .rules()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rule)| format!("{} {}\n", i, rule))
+                    .collect()
+            }
+            Some("add") => (None, words[2..].join(" ")),
+            Some("insert") if words.len() > 2 => (words[2].parse().ok(), words[3..].join(" ")),
+            Some("del") if words.len() == 3 => {
+                return match words[2].parse().ok().and_then(|i| self.acl.remove(i)) {
+                    Some(rule) => {
+                        info!("Removed ACL rule {:?} on request.", rule.to_string());
+                        format!("Removed {}.\n", rule)
+                    }
+                    None => String::from("No such rule.\n"),
+                }
+            }
+            _ => return format!("Unknown command: {}\n", command),
+        };
+        if words[1] == "insert" && index.is_none() {
+            return String::from("Invalid rule number.\n");
+        }
+        match rule.parse::<acl::Rule>() {
+            Ok(rule) => {
+                info!("Added ACL rule {:?} on request.", rule.to_string());
+                self.acl.insert(index, rule);
+                format!("Added {}.\n", rule)
+            }
+            Err(e) => format!("{}.\n", e),
+        }
+    }
+
+    /// `sources`, `sources allow CIDR`, `sources deny CIDR` and
+    /// `sources del CIDR`.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn edit_sources(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        if words.len() == 1 {
+            let allow = self.sources.allow.iter().map(|cidr| format!("allow {}\n", cidr));
+            let deny = self.sources.deny.iter().map(|cidr| format!("deny {}\n", cidr));
+            return allow.chain(deny).collect();
+        }
+        let range: cidr::Cidr = match words.get(2).map(|range| range.parse()) {
+            Some(Ok(range)) if words.len() == 3 => range,
+            Some(Err(e)) => return format!("{}.\n", e),
+            _ => return format!("Unknown command: {}\n", command),
+        };
+        let done = match words[1] {
+            "allow" => {
+                self.sources.allow.push(range);
+                format!("Allowing {}.\n", range)
+            }
+            "deny" => {
+                self.sources.deny.push(range);
+                format!("Denying {}.\n", range)
+            }
+            "del" if self.sources.remove(&range) => format!("Removed {}.\n", range),
+            "del" => return format!("{} is not listed.\n", range),
+            _ => return format!("Unknown command: {}\n", command),
+        };
+        info!("Source filter changed on request: {}", done.trim());
+        done
+    }
+
+    /// `rate` lists the limits; `rate ID RATE` limits a client, both ways,
+    /// and with it its device, user or group across reconnects, and `rate ID
+    /// off` lifts the limit.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn edit_rate(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        if words.len() == 1 {
+            let mut ids: Vec<&Id> = self.rates.keys().collect();
+            ids.sort();
+            return ids.iter()
+                .map(|id| format!("{} {}bit\n", id, self.rates[*id].rate() * 8))
+                .collect();
+        }
+        let id: Id = match words.get(1).and_then(|id| id.parse().ok()) {
+            Some(id) if words.len() == 3 && self.client_info.contains_key(&id) => id,
+            Some(_) if words.len() == 3 => return String::from("No such client.\n"),
+            _ => return format!("Unknown command: {}\n", command),
+        };
+        let principal = self.client_info[&id].principal.clone();
+        let who = self.describe(&principal);
+        let reply = if words[2] == "off" {
+            self.rate_limits.remove(&principal);
+            info!("Lifted the rate limit of client {} ({}) on request.", id, who);
+            format!("Lifted the rate limit of client {} ({}).\n", id, who)
+        } else {
+            match shaper::parse_rate(words[2]) {
+                Ok(rate) => {
+                    info!("Limiting client {} ({}) to {} bytes per second on request.",
+                          id,
+                          who,
+                          rate);
+                    self.rate_limits.insert(principal, rate);
+                    format!("Limited client {} ({}) to {}bit.\n", id, who, rate * 8)
+                }
+                Err(e) => return format!("{}.\n", e),
+            }
+        };
+        let ids: Vec<Id> = self.client_info.keys().cloned().collect();
+        for id in ids {
+            self.restrict(id);
+        }
+        reply
+    }
+
+    /// `tun` shows what can change on the TUN device without a restart;
+    /// `tun mtu BYTES`, `tun address add|del ADDR/PREFIX` and `tun route
+    /// add|del NET/PREFIX` change it. MTUs and routes reach connected clients
+    /// that take pushed options, which raise their MTU up to what they
+    /// offered.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn edit_tun(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        if words.len() == 1 {
+            let mut shown = match self.tun.mtu() {
+                Ok(mtu) if !self.relay_only => format!("mtu {}\n", mtu),
+                _ => String::new(),
+            };
+            for address in &self.tun_addresses {
+                shown.push_str(&format!("address {}\n", address));
+            }
+            for option in &self.pushed {
+                if let push::PushOption::Route(net, prefix) = *option {
+                    shown.push_str(&format!("route {}/{}\n", net, prefix));
+                }
+            }
+            return shown;
+        }
+        if self.relay_only && words[1] != "route" {
+            return String::from("No TUN device to change.\n");
+        }
+        let name = self.tun.name().to_owned();
+        match (words[1], words.get(2), words.get(3), words.len()) {
+            ("mtu", Some(mtu), None, 3) => {
+                let mtu: u16 = match mtu.parse() {
+                    Ok(mtu) if mtu >= MIN_TUN_MTU => mtu,
+                    _ => return format!("Invalid MTU: {}\n", mtu),
+                };
+                if let Err(e) = utils::set_interface_mtu(&name, mtu) {
+                    return format!("Failed to set the MTU: {}\n", e);
+                }
+                info!("Set the MTU of {} to {} on request.", name, mtu);
+                self.tun_mtu = Some(mtu);
+                self.tun_buf = vec![0u8; mtu as usize + 1];
+                let capacity = frame_capacity(cmp::max(mtu, device::MTU)) + 1;
+                if self.sock_buf.len() < capacity {
+                    self.sock_buf = vec![0u8; capacity];
+                }
+                if self.clamp_mss.is_some() {
+                    self.clamp_mss = Some(mtu);
+                }
+                // Recomputed from each offer, so raising the MTU gives back
+                // what an earlier lowering took away.
+                let mtu_overrides = &self.mtu_overrides;
+                for info in self.client_info.values_mut() {
+                    info.caps.mtu = session_mtu(info.offered.mtu,
+                                                mtu,
+                                                mtu_overrides.get(&info.group).cloned());
+                }
+                self.repush();
+                format!("Set the MTU of {} to {}.\n", name, mtu)
+            }
+            ("address", Some(&action), Some(address), 4) => {
+                let address: cidr::Cidr = match address.parse() {
+                    Ok(address) => address,
+                    Err(e) => return format!("{}.\n", e),
+                };
+                let known = self.tun_addresses.contains(&address);
+                let result = match action {
+                    "add" if !known => {
+                        utils::add_interface_address(&name, address.addr, address.prefix)
+                    }
+                    "del" if known => {
+                        utils::delete_interface_address(&name, address.addr, address.prefix)
+                    }
+                    "add" => return format!("{} is already added.\n", address),
+                    "del" => return format!("{} was not added here.\n", address),
+                    _ => return format!("Unknown command: {}\n", command),
+                };
+                if let Err(e) = result {
+                    return format!("Failed to change {}: {}\n", address, e);
+                }
+                if action == "add" {
+                    info!("Added {} to {} on request.", address, name);
+                    self.tun_addresses.push(address);
+                    format!("Added {} to {}.\n", address, name)
+                } else {
+                    info!("Removed {} from {} on request.", address, name);
+                    self.tun_addresses.retain(|&added| added != address);
+                    format!("Removed {} from {}.\n", address, name)
+                }
+            }
+            ("route", Some(&action), Some(net), 4) => {
+                let route = match net.parse::<cidr::Cidr>() {
+                    Ok(cidr::Cidr { addr: IpAddr::V4(net), prefix }) => {
+                        push::PushOption::Route(net, prefix)
+                    }
+                    Ok(_) => return String::from("Only IPv4 routes can be pushed.\n"),
+                    Err(e) => return format!("{}.\n", e),
+                };
+                let known = self.pushed.contains(&route);
+                match action {
+                    "add" if !known => {
+                        info!("Pushing a route to {} on request.", net);
+                        self.pushed.push(route);
+                    }
+                    "del" if known => {
+                        info!("No longer pushing the route to {} on request.", net);
+                        self.pushed.retain(|option| *option != route);
+                    }
+                    "add" => return format!("{} is already pushed.\n", net),
+                    "del" => return format!("{} is not pushed.\n", net),
+                    _ => return format!("Unknown command: {}\n", command),
+                }
+                self.repush();
+                let told = self.client_info.values().filter(|info| info.caps.has(CAP_OPTIONS));
+                format!("Pushed the routes to {} client(s).\n", told.count())
+            }
+            _ => format!("Unknown command: {}\n", command),
+        }
+    }
+
+    /// `egress` lists the clients kept inside the tunnel; `egress ID off`
+    /// keeps a client there, and with it its device, user or group across
+    /// reconnects, and `egress ID on` lets them out again.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn edit_egress(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        if words.len() == 1 {
+            let mut ids: Vec<&Id> = self.no_egress.iter().collect();
+            ids.sort();
+            return ids.iter().map(|id| format!("{} off\n", id)).collect();
+        }
+        let id: Id = match words.get(1).and_then(|id| id.parse().ok()) {
+            Some(id) if words.len() == 3 && self.client_info.contains_key(&id) => id,
+            Some(_) if words.len() == 3 => return String::from("No such client.\n"),
+            _ => return format!("Unknown command: {}\n", command),
+        };
+        let principal = self.client_info[&id].principal.clone();
+        let who = self.describe(&principal);
+        let reply = match words[2] {
+            "off" => {
+                self.egress_marks.insert(principal);
+                info!("Keeping client {} ({}) inside the tunnel on request.", id, who);
+                format!("Client {} ({}) may only reach the tunnel and pushed routes.\n", id, who)
+            }
+            "on" => {
+                self.egress_marks.remove(&principal);
+                info!("Letting client {} ({}) out of the tunnel again on request.", id, who);
+                format!("Client {} ({}) may reach the internet again.\n", id, who)
+            }
+            _ => return format!("Unknown command: {}\n", command),
+        };
+        let ids: Vec<Id> = self.client_info.keys().cloned().collect();
+        for id in ids {
+            self.restrict(id);
+        }
+        reply
+    }
+
+    /// How operators know `principal`.
+    #[cfg(any(feature = "admin", feature = "dashboard"))]
+    fn describe(&self, principal: &Principal) -> String {
+        match *principal {
+            Principal::Device(ref key) => format!("device {}", identity::fingerprint(key)),
+            Principal::User(ref user) => format!("user {}", user),
+            Principal::Group(group) => format!("group {}", self.groups[group - 1].0),
+            Principal::Anonymous(id) => format!("client {}", id),
+        }
+    }
+
+    #[cfg(feature = "dashboard")]
+    fn serve_dashboard(&mut self) {
+        let requests = match self.dashboard {
+            Some(ref dashboard) => dashboard.accept(),
+            None => return,
+        };
+        for (mut stream, request) in requests {
+            let (status, content_type, body) = match request {
+                dashboard::Request::Page => ("200 OK", "text/html", String::from(dashboard::PAGE)),
+                dashboard::Request::Status => {
+                    ("200 OK", "application/json", self.dashboard_status())
+                }
+                dashboard::Request::Command(command) => {
+                    ("200 OK", "text/plain", self.control(&command))
+                }
+                dashboard::Request::Forbidden => {
+                    ("403 Forbidden", "text/plain", String::from("The dashboard is read-only.\n"))
+                }
+                dashboard::Request::NotFound => {
+                    ("404 Not Found", "text/plain", String::from("Not found.\n"))
+                }
+            };
+            dashboard::reply(&mut stream, status, content_type, &body);
+        }
+    }
+
+    /// What the dashboard page shows, as JSON.
+    #[cfg(feature = "dashboard")]
+    fn dashboard_status(&self) -> String {
+        let now = self.clock.now();
+        let clients: Vec<String> = self.client_info
+            .iter()
+            .map(|(id, info)| {
+                let group = match info.group {
+                    0 => String::from("null"),
+                    group => telemetry::quote(&self.groups[group - 1].0),
+                };
+                let rtt = info.rtt
+                    .srtt()
+                    .map_or(String::from("null"), |rtt| stats::millis(rtt).to_string());
+                let loss = info.link.loss().map_or(String::from("null"), |loss| loss.to_string());
+                let traffic = self.traffic
+                    .get(id)
+                    .map_or(String::new(), |traffic| traffic.summary(TOP_PORTS));
+                format!("{{\"id\":{},\"addr\":{},\"group\":{},\"connected_s\":{},\
+                         \"rtt_ms\":{},\"loss_pct\":{},\"dropped\":{},\"traffic\":{}}}",
+                        id,
+                        telemetry::quote(&info.addr.to_string()),
+                        group,
+                        now.duration_since(info.established).as_secs(),
+                        rtt,
+                        loss,
+                        info.dropped,
+                        telemetry::quote(&traffic))
+            })
+            .collect();
+        let bans: Vec<String> = self.lockout
+            .as_ref()
+            .map_or(Vec::new(), |lockout| lockout.bans(now))
+            .iter()
+            .map(|&(ip, left)| {
+                format!("{{\"ip\":{},\"left_s\":{}}}",
+                        telemetry::quote(&ip.to_string()),
+                        left.as_secs())
+            })
+            .collect();
+        let (events, writable) = match self.dashboard {
+            Some(ref dashboard) => (dashboard.events_json(), dashboard.writable()),
+            None => (String::from("[]"), false),
+        };
+        format!("{{\"tun\":{},\"rx_bytes\":{},\"tx_bytes\":{},\"writable\":{},\
+                 \"clients\":[{}],\"bans\":[{}],\"events\":{}}}",
+                telemetry::quote(self.tun.name()),
+                self.counters.rx_bytes,
+                self.counters.tx_bytes,
+                writable,
+                clients.join(","),
+                bans.join(","),
+                events)
+    }
+
+    fn submit_handshake(&mut self, job: handshake::Job) {
+        let addr = job.addr;
+        let now = self.clock.now();
+        if self.lockout.as_mut().map_or(false, |lockout| lockout.banned(addr.ip(), now)) {
+            trace_packet!("handshake from {} dropped: banned", addr);
+        } else if !self.geoip.as_ref().map_or(true, |policy| policy.admits(addr.ip())) {
+            info!("Refusing handshake from {} by GeoIP policy.", addr);
+            self.refuse(Code::Refused, addr);
+        } else if self.draining {
+            info!("Refusing handshake from {} while draining.", addr);
+            self.refuse(Code::Refused, addr);
+        } else if !self.handshakes.submit(job) {
+            if self.may_warn("handshake", &addr) {
+                warn!("Too many pending handshakes. Ignoring request from {}.", addr);
+            }
+            self.counters.overflow();
+        }
+    }
+
+    /// Whether the bandwidth cap and the rate limit of client `id` let a
+    /// packet of `len` bytes through.
+    fn admit(&mut self, id: Id, len: usize) -> bool {
+        let now = self.clock.now();
+        if !self.rates.get_mut(&id).map_or(true, |shaper| shaper.admit(len, now)) {
+            return false;
+        }
+        if self.shaper.as_mut().map_or(true, |shaper| shaper.admit(len, now)) {
+            return true;
+        }
+        if let Some(shaper) = self.rates.get_mut(&id) {
+            shaper.refund(len);
+        }
+        false
+    }
+
+    /// Applies what operators set for the principal of client `id` to its
+    /// session.
+    fn restrict(&mut self, id: Id) {
+        let (kept_inside, rate) = match self.client_info.get(&id) {
+            Some(info) => {
+                (self.egress_marks.contains(&info.principal),
+                 self.rate_limits.get(&info.principal).cloned())
+            }
+            None => return,
+        };
+        if kept_inside {
+            self.no_egress.insert(id);
+        } else {
+            self.no_egress.remove(&id);
+        }
+        match rate {
+            // A shaper already at the rate keeps its state.
+            Some(rate) if self.rates.get(&id).map_or(false, |shaper| shaper.rate() == rate) => {}
+            Some(rate) => {
+                self.rates.insert(id, shaper::Shaper::new(rate, self.clock.now()));
+            }
+            None => {
+                self.rates.remove(&id);
+            }
+        }
+    }
+
+    /// Notes that a client proved it is alive.
+    fn touch(&mut self, id: Id) {
+        if let Some(info) = self.client_info.get_mut(&id) {
+            info.last_heard = self.clock.now();
+            info.probes_sent = 0;
+        }
+    }
+
+    fn send(&mut self, msg: &Message, addr: &SocketAddr) -> Result<()> {
+        let buf = try!(encode_message(msg));
+        self.send_buf(buf, addr)
+    }
+
+    fn send_buf(&mut self, buf: Vec<u8>, addr: &SocketAddr) -> Result<()> {
+        if let Some(ref mut capture) = self.capture {
+            capture.outer(&self.local_addr, addr, &buf);
+        }
+        match self.netem {
+            Some(ref mut netem) => {
+                netem.submit(buf, *addr, self.clock.now());
+                Ok(())
+            }
+            None => {
+                let sock = match self.session_sock {
+                    Some(ref sock) if self.on_session_port => sock,
+                    _ => &self.sockfd,
+                };
+                match self.reply_from.get(addr) {
+                    Some(&(src, _)) => send_from(sock, &buf, addr, src),
+                    None => send_raw(sock, &buf, addr),
+                }
+            }
+        }
+    }
+
+    fn handle_socket(&mut self) -> Result<()> {
+        let (len, addr) = match try!(self.receive_from(false)) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        self.handle_datagram(len, addr)
+    }
+
+    /// Reads a datagram from the main or the session socket and notes the
+    /// local address it was sent to.
+    fn receive_from(&mut self, session: bool) -> Result<Option<(usize, SocketAddr)>> {
+        let sock = match self.session_sock {
+            Some(ref sock) if session => sock,
+            _ if session => return Ok(None),
+            _ => &self.sockfd,
+        };
+        if !self.pktinfo {
+            return Ok(try!(sock.recv_from(&mut self.sock_buf)));
+        }
+        let (len, addr, dst) = match try!(recv_with_dst(sock, &mut self.sock_buf)) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if let Some(dst) = dst {
+            let now = self.clock.now();
+            if self.reply_from.len() < MAX_REPLY_FROM || self.reply_from.contains_key(&addr) {
+                self.reply_from.insert(addr, (dst, now));
+            }
+        }
+        Ok(Some((len, addr)))
+    }
+
+    /// Reads what is waiting on the session port, up to `SESSION_BURST`
+    /// datagrams, and answers it there.
+    fn handle_session_socket(&mut self) -> Result<()> {
+        for _ in 0..SESSION_BURST {
+            let (len, addr) = match try!(self.receive_from(true)) {
+                Some(r) => r,
+                None => break,
+            };
+            self.on_session_port = true;
+            let result = self.handle_datagram(len, addr);
+            self.on_session_port = false;
+            try!(result);
+        }
+        Ok(())
+    }
+
+    fn handle_datagram(&mut self, len: usize, addr: SocketAddr) -> Result<()> {
+        if len == self.sock_buf.len() {
+            if self.may_warn("oversized", &addr) {
+                warn!("Dropping oversized datagram from {}.", addr);
+            }
+            self.counters.error();
+            return Ok(());
+        }
+        if !self.nested && self.subnet.id_of(addr.ip()).is_some() {
+            // A client's tunnel carries another tunnel to this server.
+            warn!("Datagram from {} came through the tunnel itself; kytan runs inside kytan.",
+                  addr);
+            self.nested = true;
+        }
+        if !self.sources.admits(addr.ip()) {
+            trace_packet!("sock len={} from {} dropped: source filtered", len, addr);
+            return Ok(());
+        }
+        if let Some(ref mut capture) = self.capture {
+            capture.outer(&addr, &self.local_addr, &self.sock_buf[0..len]);
+        }
+        let msg: Message = match decode_message(&self.sock_buf[0..len]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                if self.may_warn("undecodable", &addr) {
+                    warn!("Undecodable message from {}: {}", addr, e);
+                }
+                self.counters.error();
+                return Ok(());
+            }
+        };
+        // A raw frame is a data frame whose payload skipped compression.
+        let (msg, raw) = match msg {
+            Message::RawData { id, token, data } => {
+                (Message::Data {
+                    id: id,
+                    token: token,
+                    data: data,
+                },
+                 true)
+            }
+            msg => (msg, false),
+        };
+        match msg {
+            Message::RawData { .. } => unreachable!(),
+            Message::Request { caps, resume, credential, roam_key } => {
+                // Trailing bytes mean the client obfuscates its handshake.
+                let padded = encode_message(&Message::Request {
+                        caps: caps,
+                        resume: resume,
+                        credential: credential.clone(),
+                        roam_key: roam_key.clone(),
+                    })
+                    .map(|frame| frame.len() < len)
+                    .unwrap_or(false);
+                self.submit_handshake(handshake::Job {
+                    padded: padded,
+                    session_port: self.on_session_port,
+                    addr: addr,
+                    caps: caps,
+                    resume: resume,
+                    credential: credential,
+                    stamp: None,
+                    proof: None,
+                    roam_key: roam_key,
+                });
+            }
+            Message::Stamped { caps, resume, stamp, roam_key } => {
+                let padded = encode_message(&Message::Stamped {
+                        caps: caps,
+                        resume: resume,
+                        stamp: stamp.clone(),
+                        roam_key: roam_key.clone(),
+                    })
+                    .map(|frame| frame.len() < len)
+                    .unwrap_or(false);
+                self.submit_handshake(handshake::Job {
+                    padded: padded,
+                    session_port: self.on_session_port,
+                    addr: addr,
+                    caps: caps,
+                    resume: resume,
+                    credential: None,
+                    stamp: Some(stamp),
+                    proof: None,
+                    roam_key: roam_key,
+                });
+            }
+            Message::Identified { caps, resume, credential, stamp, proof, roam_key } => {
+                let padded = encode_message(&Message::Identified {
+                        caps: caps,
+                        resume: resume,
+                        credential: credential.clone(),
+                        stamp: stamp.clone(),
+                        proof: proof.clone(),
+                        roam_key: roam_key.clone(),
+                    })
+                    .map(|frame| frame.len() < len)
+                    .unwrap_or(false);
+                self.submit_handshake(handshake::Job {
+                    padded: padded,
+                    session_port: self.on_session_port,
+                    addr: addr,
+                    caps: caps,
+                    resume: resume,
+                    credential: credential,
+                    stamp: stamp,
+                    proof: Some(proof),
+                    roam_key: roam_key,
+                });
+            }
+            Message::Response { .. } |
+            Message::Assigned { .. } |
+            Message::Configured { .. } |
+            Message::Batch { .. } |
+            Message::StreamReply { .. } |
+            Message::Relay { .. } |
+            Message::Refused { .. } |
+            Message::Expired { .. } => {
+                if self.may_warn("invalid", &addr) {
+                    warn!("Invalid message {:?} from {}", msg, addr);
+                }
+            }
+            Message::StreamOpen { id, token, stream, target } => {
+                if self.valid_stream(id, token, &addr) {
+                    self.touch(id);
+                    let reply = self.open_stream(id, token, stream, &target);
+                    try!(self.send_streams(reply.into_iter().map(|out| (id, out)).collect()));
+                }
+            }
+            Message::Segment { id, token, segment } => {
+                if self.valid_stream(id, token, &addr) {
+                    self.touch(id);
+                    let now = self.clock.now();
+                    let out = match self.exits {
+                        Some(ref mut exits) => {
+                            let out = exits.segment(&self.poll, id, segment, now);
+                            self.counters.overflow += exits.take_dropped();
+                            out
+                        }
+                        None => Vec::new(),
+                    };
+                    try!(self.send_streams(out));
+                }
+            }
+            Message::StreamClose { id, token, stream } => {
+                if self.valid_stream(id, token, &addr) {
+                    if let Some(ref mut exits) = self.exits {
+                        exits.close(&self.poll, id, stream);
+                    }
+                }
+            }
+            Message::Probe { nonce } => {
+                debug!("Answering latency probe from {}.", addr);
+                // Anyone can probe from any address, so answering may fail.
+                if let Err(e) = self.send(&Message::Probe { nonce: nonce }, &addr) {
+                    debug!("Failed to answer latency probe from {}: {}", addr, e);
+                }
+            }
+            Message::Ping { id, token } => {
+                let valid = match self.client_info.get(&id) {
+                    Some(info) => info.token == token && info.addr == addr,
+                    None => false,
+                };
+                if valid {
+                    debug!("Answering liveness probe from client {}.", id);
+                    self.touch(id);
+                    try!(self.send(&Message::Pong {
+                                       id: id,
+                                       token: token,
+                                   },
+                                   &addr));
+                } else {
+                    if self.may_warn("unknown-session", &addr) {
+                        warn!("Probe for unknown session {} from {}.", id, addr);
+                    }
+                    match self.client_info.get(&id).map(|info| info.token != token) {
+                        Some(false) => {}
+                        Some(true) | None => self.notify_stale(id, token, addr),
+                    }
+                }
+            }
+            Message::Pong { id, token } => {
+                let valid = match self.client_info.get(&id) {
+                    Some(info) => info.token == token && info.addr == addr,
+                    None => false,
+                };
+                if valid {
+                    debug!("Client {} answered liveness probe.", id);
+                    self.touch(id);
+                    let now = self.clock.now();
+                    if let Some(info) = self.client_info.get_mut(&id) {
+                        if let Some(sent) = info.ping_sent.take() {
+                            info.rtt.sample(now.duration_since(sent));
+                            info.latency.record(now.duration_since(sent));
+                            info.link.pong(now.duration_since(sent));
+                        }
+                    }
+                } else {
+                    if self.may_warn("unknown-session", &addr) {
+                        warn!("Unexpected probe answer for id {} from {}.", id, addr);
+                    }
+                }
+            }
+            Message::Data { id, token, data } => {
+                match self.client_info.get(&id) {
+                    None => {
+                        if self.may_warn("unknown-session", &addr) {
+                            warn!("Unknown data with token {} from id {}.", token, id);
+                        }
+                        self.counters.error();
+                        trace_packet!("sock->tun id={} compressed={} dropped: unknown id",
+                                      id,
+                                      data.len());
+                        self.notify_stale(id, token, addr);
+                    }
+                    Some(&info) => {
+                        if info.token != token {
+                            if self.may_warn("unknown-session", &addr) {
+                                warn!("Unknown data with mismatched token {} from id {}. \
+                                       Expected: {}",
+                                      token,
+                                      id,
+                                      info.token);
+                            }
+                            self.counters.error();
+                            trace_packet!("sock->tun id={} compressed={} dropped: token mismatch",
+                                          id,
+                                          data.len());
+                            self.notify_stale(id, token, addr);
+                        } else if info.addr != addr {
+                            if self.may_warn("endpoint", &addr) {
+                                warn!("Data for id {} from unregistered endpoint {}. \
+                                       Expected: {}",
+                                      id,
+                                      addr,
+                                      info.addr);
+                            }
+                            self.counters.error();
+                            trace_packet!("sock->tun id={} compressed={} dropped: endpoint \
+                                           mismatch",
+                                          id,
+                                          data.len());
+                        } else {
+                            self.touch(id);
+                            let compressed = info.caps.has(CAP_SNAPPY) && !raw;
+                            if self.workers.is_some() {
+                                let job = workers::Job::Decode {
+                                    id: id,
+                                    token: token,
+                                    compressed: compressed,
+                                    data: data,
+                                };
+                                if !self.submit_work(job) {
+                                    trace_packet!("sock->tun id={} dropped: workers busy", id);
+                                    self.counters.overflow();
+                                }
+                                return Ok(());
+                            }
+                            let _compressed_len = data.len();
+                            let decompressed_data = if compressed {
+                                match decompress(&data) {
+                                    Ok(data) => data,
+                                    Err(e) => {
+                                        if self.may_warn("undecompressable", &addr) {
+                                            warn!("Undecompressable data from {}: {}", addr, e);
+                                        }
+                                        self.counters.error();
+                                        trace_packet!("sock->tun id={} compressed={} dropped: \
+                                                       {}",
+                                                      id,
+                                                      data.len(),
+                                                      e);
+                                        return Ok(());
+                                    }
+                                }
+                            } else {
+                                data
+                            };
+                            try!(self.receive(id, info, decompressed_data, _compressed_len));
+                        }
+                    }
+                }
+            }
+            #[cfg(feature = "obfuscation")]
+            Message::Cover { id, token, padding } => {
+                let answer = match self.client_info.get_mut(&id) {
+                    Some(info) if info.token == token && info.addr == addr => {
+                        let busy = info.cover_busy;
+                        info.cover_busy = false;
+                        !busy && info.caps.has(CAP_COVER)
+                    }
+                    _ => return Ok(()),
+                };
+                if answer {
+                    trace_packet!("sock->sock id={} len={} cover answered", id, len);
+                    try!(self.send(&Message::Cover {
+                                       id: id,
+                                       token: token,
+                                       padding: cover::padding(padding.len() + cover::OVERHEAD),
+                                   },
+                                   &addr));
+                }
+                self.touch(id);
+            }
+            #[cfg(not(feature = "obfuscation"))]
+            Message::Cover { id, token, .. } => {
+                let valid = self.client_info
+                    .get(&id)
+                    .map_or(false, |info| info.token == token && info.addr == addr);
+                if valid {
+                    self.touch(id);
+                }
+            }
+            Message::Roam { id, token, sequence, mac } => {
+                // The token travels in every frame, so an endpoint change needs
+                // the roaming key as well and is never implied by a data frame
+                // arriving from somewhere else.
+                let roamed = match self.client_info.get(&id) {
+                    Some(&info) if info.token == token => {
+                        match info.roaming {
+                            Some(mut binding) => {
+                                if binding.accept(id, token, sequence, &mac) {
+                                    Some(ClientInfo { roaming: Some(binding), ..info })
+                                } else {
+                                    None
+                                }
+                            }
+                            None => None,
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some(info) = roamed {
+                    self.client_info.insert(id, ClientInfo { addr: addr, ..info });
+                    self.touch(id);
+                    info!("Client {} roamed to {}.", id, addr);
+                    if let (Some(tracer), Some(session)) = (self.tracer.as_ref(),
+                                                            self.spans.get(&id)) {
+                        let mut roam = session.child("roam");
+                        roam.set("client.addr", addr);
+                        tracer.end(roam);
+                    }
+                    if let Some(ref history) = self.history {
+                        history.roam(id, &addr);
+                    }
+                    self.emit(Event::ClientRoamed { id: id, addr: addr });
+                } else {
+                    if self.may_warn("roaming", &addr) {
+                        warn!("Rejected roaming request for id {} from {}.", id, addr);
+                    }
+                    self.notify_stale(id, token, addr);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_tun(&mut self) -> Result<()> {
+        let len: usize = try!(self.tun.read(&mut self.tun_buf));
+        if len == 0 {
+            // The device swallowed the frame, such as ARP on a TAP device.
+            return Ok(());
+        }
+        if len == self.tun_buf.len() {
+            warn!("Dropping truncated packet from TUN (MTU {}).",
+                  self.tun_buf.len() - 1);
+            trace_packet!("tun->sock len={} dropped: truncated", len);
+            return Ok(());
+        }
+        let mut buf = mem::replace(&mut self.tun_buf, Vec::new());
+        let result = self.forward(&mut buf[0..len]);
+        self.tun_buf = buf;
+        result
+    }
+
+    /// Whether stream traffic comes from a session that agreed on streams.
+    fn valid_stream(&self, id: Id, token: Token, addr: &SocketAddr) -> bool {
+        match self.client_info.get(&id) {
+            Some(info) => info.token == token && info.addr == *addr && info.caps.has(CAP_STREAMS),
+            None => false,
+        }
+    }
+
+    /// Connects a client's stream to `target`, looking up its name first
+    /// if it has one. Returns the reply if it is already known.
+    fn open_stream(&mut self, id: Id, token: Token, stream: stream::StreamId, target: &str)
+                   -> Option<stream::Outgoing> {
+        if let Ok(addr) = target.parse() {
+            return self.connect_stream(id, stream, addr);
+        }
+        let queued = match self.resolver {
+            Some(ref mut resolver) => resolver.submit(id, token, stream, target),
+            None => false,
+        };
+        if queued {
+            None
+        } else {
+            Some(stream::Outgoing::Reply(stream, Some(String::from("too many lookups"))))
+        }
+    }
+
+    /// Opens the streams whose names were looked up, if their clients are
+    /// still there.
+    fn finish_lookups(&mut self) -> Result<()> {
+        let done = match self.resolver {
+            Some(ref mut resolver) => resolver.done(),
+            None => Vec::new(),
+        };
+        for (id, token, stream, target, addr) in done {
+            if self.client_info.get(&id).map_or(true, |info| info.token != token) {
+                continue;
+            }
+            let reply = match addr {
+                Ok(addr) => self.connect_stream(id, stream, addr),
+                Err(e) => {
+                    debug!("Stream of client {} to {} failed: {}", id, target, e);
+                    Some(stream::Outgoing::Reply(stream, Some(e.to_string())))
+                }
+            };
+            try!(self.send_streams(reply.into_iter().map(|out| (id, out)).collect()));
+        }
+        Ok(())
+    }
+
+    /// Whether `addr` belongs to this host, or is loopback or link-local.
+    fn on_server(&self, addr: IpAddr) -> bool {
+        stream::local(addr) || addr == self.local_addr.ip() ||
+        netwatch::fingerprint(None)
+            .map(|addrs| addrs.iter().any(|&(_, own)| own == addr))
+            // Better refuse a stream than let it reach the host.
+            .unwrap_or(true)
+    }
+
+    /// Connects a client's stream to `addr` unless the ACL denies it.
+    fn connect_stream(&mut self, id: Id, stream: stream::StreamId, addr: SocketAddr)
+                      -> Option<stream::Outgoing> {
+        let refuse = |why: String| Some(stream::Outgoing::Reply(stream, Some(why)));
+        if !self.local_streams && self.on_server(addr.ip()) {
+            debug!("Stream of client {} to {} stays on the server.", id, addr);
+            return refuse(String::from("denied"));
+        }
+        if self.acl.decide(id, packet::IPPROTO_TCP, Some(addr.port())) == acl::Action::Deny {
+            debug!("Stream of client {} to {} denied by ACL.", id, addr);
+            return refuse(String::from("denied"));
+        }
+        if self.no_egress.contains(&id) && !self.inside(addr.ip()) {
+            debug!("Stream of client {} to {} leaves the tunnel.", id, addr);
+            return refuse(String::from("no internet access"));
+        }
+        let now = self.clock.now();
+        match self.exits {
+            Some(ref mut exits) => exits.open(&self.poll, id, stream, addr, now),
+            None => None,
+        }
+    }
+
+    /// Relays between a stream socket and its client.
+    fn handle_stream(&mut self, token: mio::Token) -> Result<()> {
+        let now = self.clock.now();
+        let out = match self.exits {
+            Some(ref mut exits) => exits.ready(&self.poll, token, now),
+            None => Vec::new(),
+        };
+        self.send_streams(out)
+    }
+
+    fn send_streams(&mut self, out: Vec<(Id, stream::Outgoing)>) -> Result<()> {
+        for (id, out) in out {
+            if let Some(&info) = self.client_info.get(&id) {
+                try!(self.send(&stream_message(id, info.token, out), &info.addr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the streams of a client whose session ended.
+    fn end_streams(&mut self, id: Id) {
+        if let Some(ref mut exits) = self.exits {
+            exits.end(&self.poll, id);
+        }
+        if let Some(ref mut resolver) = self.resolver {
+            resolver.end(id);
+        }
+    }
+
+    /// Relays a reply received on one of the NAT's flow sockets.
+    fn handle_nat(&mut self, token: mio::Token) -> Result<()> {
+        let now = self.clock.now();
+        let mut buf = mem::replace(&mut self.tun_buf, Vec::new());
+        let reply = match self.nat {
+            // Leave room for the IPv6 and UDP headers wrapped around the payload.
+            Some(ref mut nat) => {
+                let room = buf.len().saturating_sub(48);
+                nat.inbound(token, &mut buf[0..room], now)
+            }
+            None => Ok(None),
+        };
+        self.tun_buf = buf;
+        match reply {
+            Ok(Some(mut reply)) => self.forward(&mut reply),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                debug!("Failed to read NAT reply: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Relays an mDNS or SSDP datagram heard on the LAN to the clients.
+    fn handle_discovery(&mut self, token: mio::Token) -> Result<()> {
+        let mut buf = mem::replace(&mut self.tun_buf, Vec::new());
+        let relayed = match self.discovery {
+            // Leave room for the IPv4 and UDP headers wrapped around the payload.
+            Some(ref mut proxy) if proxy.owns(token) => {
+                let room = buf.len().saturating_sub(28);
+                proxy.inbound(token, &mut buf[0..room])
+            }
+            _ => Ok(None),
+        };
+        self.tun_buf = buf;
+        match relayed {
+            Ok(Some(mut relayed)) => self.forward(&mut relayed),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                debug!("Failed to read discovery datagram: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    /// Passes a packet from client `id` through the checks and on to the
+    /// TUN device, another client or the userspace NAT.
+    fn receive(&mut self,
+               id: Id,
+               info: ClientInfo,
+               mut data: Vec<u8>,
+               _compressed_len: usize)
+               -> Result<()> {
+        if !self.admit(id, data.len()) {
+            trace_packet!("sock->tun id={} len={} dropped: bandwidth cap", id, data.len());
+            return Ok(());
+        }
+        self.counters.rx(data.len());
+        if let Some(info) = self.client_info.get_mut(&id) {
+            info.rx_bytes += data.len() as u64;
+        }
+        self.traffic.entry(id).or_insert_with(stats::Traffic::default).add(&data);
+        if let Some(drop) = self.verify_checksums {
+            if let Some(what) = packet::bad_checksum(&data) {
+                warn!("Packet from client {} fails {} checksum.", id, what);
+                self.counters.corrupt();
+                if drop {
+                    trace_packet!("sock->tun id={} len={} dropped: corrupt", id, data.len());
+                    return Ok(());
+                }
+            }
+        }
+        if self.isolated(&info, &data) {
+            debug!("Packet from client {} crosses isolation groups.", id);
+            trace_packet!("sock->tun id={} len={} dropped: isolated", id, data.len());
+            return Ok(());
+        }
+        if self.acl.check(id, &data) == acl::Action::Deny {
+            debug!("Packet from client {} denied by ACL.", id);
+            trace_packet!("sock->tun id={} len={} dropped: acl", id, data.len());
+            return Ok(());
+        }
+        if self.no_egress.contains(&id) && !is_broadcast(&self.subnet, &data) &&
+           self.downstream_owner(&data).is_none() &&
+           !packet::destination(&data).map_or(false, |dst| self.inside(dst)) {
+            debug!("Packet from client {} leaves the tunnel.", id);
+            trace_packet!("sock->tun id={} len={} dropped: no egress", id, data.len());
+            return Ok(());
+        }
+        if let Some(mtu) = self.clamp_mss {
+            packet::clamp_mss(&mut data, cmp::min(mtu, info.caps.mtu));
+        }
+        if let Some(ref mut capture) = self.capture {
+            capture.inner(&data);
+        }
+        if let Some(ref mut mirror) = self.mirror {
+            mirror.copy(&data);
+        }
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(ref mut netflow) = self.netflow {
+                netflow.record(id, true, &data, self.clock.now());
+            }
+        }
+        if is_broadcast(&self.subnet, &data) {
+            try!(self.broadcast(&mut data, Some(id)));
+            if let Some(ref mut proxy) = self.discovery {
+                if let Err(e) = proxy.outbound(&data) {
+                    debug!("Failed to relay discovery query: {}", e);
+                }
+            }
+        } else if self.nat.is_some() && route_id(&self.subnet, &data).is_err() &&
+                  self.downstream_owner(&data).is_none() {
+            let now = self.clock.now();
+            let nat = self.nat.as_mut().unwrap();
+            match nat.outbound(&self.poll, &data, now) {
+                Ok(true) => {}
+                Ok(false) => self.counters.overflow(),
+                Err(e) => debug!("Not translating packet from client {}: {}", id, e),
+            }
+            return Ok(());
+        } else if self.relay_only {
+            return self.deliver(&mut data);
+        }
+        let data_len = data.len();
+        let mut sent_len = 0;
+        while sent_len < data_len {
+            match self.tun.write(&data[sent_len..data_len]) {
+                Ok(len) => sent_len += len,
+                Err(e) => {
+                    trace_packet!("sock->tun id={} len={} dropped: {}", id, data_len, e);
+                    self.counters.error();
+                    return Ok(());
+                }
+            }
+        }
+        trace_packet!("sock->tun id={} compressed={} len={} forwarded",
+                      id,
+                      _compressed_len,
+                      data_len);
+        Ok(())
+    }
+
+    /// Sends a packet bound for the tunnel to the client that owns its
+    /// destination address.
+    fn forward(&mut self, data: &mut [u8]) -> Result<()> {
+        if let Some(ref mut capture) = self.capture {
+            capture.inner(data);
+        }
+        if let Some(ref mut mirror) = self.mirror {
+            mirror.copy(data);
+        }
+        self.deliver(data)
+    }
+
+    /// Routes a packet to the client that owns its destination address, or
+    /// to every client if it is a broadcast.
+    fn deliver(&mut self, data: &mut [u8]) -> Result<()> {
+        let len = data.len();
+        if is_broadcast(&self.subnet, data) {
+            trace_packet!("tun->sock len={} broadcast", len);
+            return self.broadcast(data, None);
+        }
+        let client_id = match route_id(&self.subnet, data) {
+            Ok(id) => id,
+            Err(e) => {
+                match self.downstream_owner(data) {
+                    Some(id) => id,
+                    None => {
+                        warn!("Dropping packet from TUN: {}.", e);
+                        trace_packet!("tun->sock len={} dropped: {}", len, e);
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        match self.client_info.get(&client_id) {
+            None => {
+                warn!("Unknown IP packet from TUN for client {}.", client_id);
+                trace_packet!("tun->sock id={} len={} dropped: unknown id", client_id, len);
+            }
+            Some(&info) => try!(self.send_data(client_id, info, data)),
+        }
+        Ok(())
+    }
+
+    /// Replicates a broadcast or multicast packet to every client. Packets
+    /// from a client only reach the other members of its group.
+    fn broadcast(&mut self, data: &mut [u8], sender: Option<Id>) -> Result<()> {
+        let group = sender.and_then(|id| self.client_info.get(&id)).map(|info| info.group);
+        let receivers: Vec<(Id, ClientInfo)> = self.client_info
+            .iter()
+            .filter(|&(&id, info)| Some(id) != sender && group.map_or(true, |g| g == info.group))
+            .map(|(&id, &info)| (id, info))
+            .collect();
+        for (id, info) in receivers {
+            try!(self.send_data(id, info, data));
+        }
+        Ok(())
+    }
+
+    fn send_data(&mut self, id: Id, info: ClientInfo, data: &mut [u8]) -> Result<()> {
+        if data.len() > info.caps.mtu as usize {
+            let router = match packet::ip_version(data) {
+                Some(6) => IpAddr::V6(self.subnet.ipv6_addr(1)),
+                _ => IpAddr::V4(self.subnet.addr(1)),
+            };
+            trace_packet!("tun->sock id={} len={} dropped: exceeds MTU {}",
+                          id,
+                          data.len(),
+                          info.caps.mtu);
+            if let Some(icmp) = packet::too_big(data, info.caps.mtu, router) {
+                if let Err(e) = self.tun.write(&icmp) {
+                    trace_packet!("icmp->tun len={} dropped: {}", icmp.len(), e);
+                    self.counters.error();
+                }
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(ref mut netflow) = self.netflow {
+                netflow.record(id, false, data, self.clock.now());
+            }
+        }
+        match self.qos.as_ref().map(|classifier| classifier.classify(id, data)) {
+            Some(class) => {
+                let waiting = self.queues.get(&id).map_or(false, |queues| !queues.is_empty());
+                if waiting || !self.admit(id, data.len()) {
+                    let dropped = self.queues
+                        .entry(id)
+                        .or_insert_with(qos::Queues::default)
+                        .push(class, data, self.queue_bytes, self.drop_policy);
+                    if dropped > 0 {
+                        trace_packet!("tun->sock id={} len={} queue full: dropped {}",
+                                      id,
+                                      data.len(),
+                                      dropped);
+                        self.count_drops(id, dropped as u64);
+                    }
+                    return Ok(());
+                }
+            }
+            None => {
+                if !self.admit(id, data.len()) {
+                    trace_packet!("tun->sock id={} len={} dropped: bandwidth cap",
+                                  id,
+                                  data.len());
+                    return Ok(());
+                }
+            }
+        }
+        self.transmit(id, info, data)
+    }
+
+    /// Sends queued packets as far as the bandwidth cap and rate limits
+    /// allow, the most urgent first.
+    fn drain_queues(&mut self) -> Result<()> {
+        let ids: Vec<Id> = self.queues.keys().cloned().collect();
+        for id in ids {
+            loop {
+                let len = match self.queues.get(&id).and_then(|queues| queues.front()) {
+                    Some(packet) => packet.len(),
+                    None => break,
+                };
+                if !self.admit(id, len) {
+                    break;
+                }
+                let mut packet = self.queues.get_mut(&id).and_then(|queues| queues.pop()).unwrap();
+                if let Some(&info) = self.client_info.get(&id) {
+                    try!(self.transmit(id, info, &mut packet));
+                }
+            }
+            if self.queues.get(&id).map_or(false, |queues| queues.is_empty()) {
+                self.queues.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a packet the shapers have admitted.
+    fn transmit(&mut self, id: Id, info: ClientInfo, data: &mut [u8]) -> Result<()> {
+        if let Some(mtu) = self.clamp_mss {
+            packet::clamp_mss(data, cmp::min(mtu, info.caps.mtu));
+        }
+        if info.caps.has(CAP_BATCH) {
+            let now = self.clock.now();
+            let (queued, flushed) = match self.coalescer {
+                Some(ref mut coalescer) if data.len() < batch::SMALL => {
+                    (true, coalescer.push(id, data, info.caps.mtu as usize, now))
+                }
+                // Whatever is queued goes first, so packets stay in order.
+                Some(ref mut coalescer) => (false, coalescer.take(id)),
+                None => (false, None),
+            };
+            if let Some(packets) = flushed {
+                try!(self.send_batch(id, packets));
+            }
+            if queued {
+                trace_packet!("tun->sock id={} len={} queued", id, data.len());
+                self.counters.tx(data.len());
+                if let Some(info) = self.client_info.get_mut(&id) {
+                    #[cfg(feature = "obfuscation")]
+                    info.cover_busy = true;
+                    info.tx_bytes += data.len() as u64;
+                }
+                return Ok(());
+            }
+        }
+        let threshold = match self.governor {
+            Some(ref governor) if !governor.compress() => usize::max_value(),
+            _ => self.compression_threshold,
+        };
+        self.counters.tx(data.len());
+        if let Some(info) = self.client_info.get_mut(&id) {
+            #[cfg(feature = "obfuscation")]
+            info.cover_busy = true;
+            info.tx_bytes += data.len() as u64;
+        }
+        if self.workers.is_some() {
+            let job = workers::Job::Encode {
+                id: id,
+                token: info.token,
+                caps: info.caps,
+                threshold: threshold,
+                addr: info.addr,
+                packet: data.to_vec(),
+            };
+            if !self.submit_work(job) {
+                trace_packet!("tun->sock id={} len={} dropped: workers busy", id, data.len());
+                self.count_drops(id, 1);
+            }
+            return Ok(());
+        }
+        let (msg, compressed) = try!(data_message(&mut self.encoder,
+                                                  id,
+                                                  info.token,
+                                                  &info.caps,
+                                                  threshold,
+                                                  data));
+        trace_packet!("tun->sock id={} len={} compressed={} forwarded",
+                      id,
+                      data.len(),
+                      compressed);
+        if let Some(ref mut dscp) = self.dscp {
+            try!(dscp.mark(&self.sockfd, data));
+        }
+        self.counters.frame(compressed);
+        self.send_frame(id, &msg, &info.addr)
+    }
+
+    /// Sends a data frame to client `id`. A frame the socket cannot take is
+    /// dropped and counted against the client instead of failing the loop.
+    fn send_frame(&mut self, id: Id, msg: &Message, addr: &SocketAddr) -> Result<()> {
+        let buf = try!(encode_message(msg));
+        self.send_encoded(id, buf, addr)
+    }
+
+    /// Like `send_frame()`, for a frame that is already encoded.
+    fn send_encoded(&mut self, id: Id, buf: Vec<u8>, addr: &SocketAddr) -> Result<()> {
+        match self.send_buf(buf, addr) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                trace_packet!("tun->sock id={} dropped: socket full", id);
+                self.count_drops(id, 1);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Queues a job with the packet workers. False if the worker of its
+    /// client is backed up.
+    fn submit_work(&mut self, job: workers::Job) -> bool {
+        self.workers.as_ref().map_or(false, |workers| workers.submit(job))
+    }
+
+    /// Sends and delivers what the packet workers finished.
+    fn finish_work(&mut self) -> Result<()> {
+        let done = match self.workers {
+            Some(ref workers) => workers.done(),
+            None => return Ok(()),
+        };
+        for done in done {
+            match done {
+                workers::Done::Encoded { id, addr, compressed, dscp, frame } => {
+                    trace_packet!("tun->sock id={} frame={} compressed={:?} forwarded",
+                                  id,
+                                  frame.len(),
+                                  compressed);
+                    if let Some(compressed) = compressed {
+                        self.counters.frame(compressed);
+                    }
+                    if let Some(ref mut marker) = self.dscp {
+                        try!(marker.mark_dscp(&self.sockfd, dscp));
+                    }
+                    try!(self.send_encoded(id, frame, &addr));
+                }
+                workers::Done::Decoded { id, token, len, packet } => {
+                    // The session may have ended while the frame was queued.
+                    match self.client_info.get(&id) {
+                        Some(&info) if info.token == token => {
+                            try!(self.receive(id, info, packet, len))
+                        }
+                        _ => trace_packet!("sock->tun id={} dropped: session ended", id),
+                    }
+                }
+                workers::Done::Failed { id, error } => {
+                    // The frame's endpoint is not kept, so the client's tunnel
+                    // address stands in for it.
+                    let now = self.clock.now();
+                    if self.warnings.allow("frame", IpAddr::V4(self.subnet.addr(id)), now) {
+                        warn!("Failed to process a frame of client {}: {}", id, error);
+                    }
+                    self.counters.error();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts packets for a client that could not keep up.
+    fn count_drops(&mut self, id: Id, dropped: u64) {
+        self.counters.overflow += dropped;
+        if let Some(info) = self.client_info.get_mut(&id) {
+            info.dropped += dropped;
+        }
+    }
+
+    /// Sends packets held back by the coalescer, which are already counted.
+    /// A lone packet goes out as an ordinary data frame.
+    fn send_batch(&mut self, id: Id, mut packets: Vec<Vec<u8>>) -> Result<()> {
+        let info = match self.client_info.get(&id) {
+            Some(&info) => info,
+            None => return Ok(()),
+        };
+        trace_packet!("tun->sock id={} packets={} coalesced", id, packets.len());
+        if self.workers.is_some() {
+            // Through the workers too, or it could overtake earlier frames.
+            let job = if packets.len() == 1 {
+                workers::Job::Encode {
+                    id: id,
+                    token: info.token,
+                    caps: info.caps,
+                    threshold: self.compression_threshold,
+                    addr: info.addr,
+                    packet: packets.pop().unwrap(),
+                }
+            } else {
+                self.counters.frame(false);
+                workers::Job::Frame {
+                    id: id,
+                    addr: info.addr,
+                    msg: Message::Batch {
+                        id: id,
+                        token: info.token,
+                        packets: packets,
+                    },
+                }
+            };
+            if !self.submit_work(job) {
+                trace_packet!("tun->sock id={} dropped: workers busy", id);
+                self.count_drops(id, 1);
+            }
+            return Ok(());
+        }
+        let msg = if packets.len() == 1 {
+            let (msg, compressed) = try!(data_message(&mut self.encoder,
+                                                      id,
+                                                      info.token,
+                                                      &info.caps,
+                                                      self.compression_threshold,
+                                                      &packets.pop().unwrap()));
+            self.counters.frame(compressed);
+            msg
+        } else {
+            self.counters.frame(false);
+            Message::Batch {
+                id: id,
+                token: info.token,
+                packets: packets,
+            }
+        };
+        self.send_frame(id, &msg, &info.addr)
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Caps on the throughput of the tunnel.
+//!
+//! A token bucket covering both directions, either shared by all clients or
+//! limiting a single one. Packets over the rate are dropped rather than
+//! queued, which TCP reads as congestion.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+use error::{Error, Result};
+
+/// How much traffic above the rate may pass at once.
+const BURST: u64 = 100;
+
+/// Parses a rate such as `200mbit`, `1.5gbit` or `800kbit` into bytes per
+/// second. A bare number is bits per second.
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let invalid = || Error::Config(format!("invalid rate {:?}", s));
+    let s = s.trim().to_lowercase();
+    let (number, scale) = if s.ends_with("gbit") {
+        (&s[..s.len() - 4], 1e9)
+    } else if s.ends_with("mbit") {
+        (&s[..s.len() - 4], 1e6)
+    } else if s.ends_with("kbit") {
+        (&s[..s.len() - 4], 1e3)
+    } else if s.ends_with("bit") {
+        (&s[..s.len() - 3], 1.0)
+    } else {
+        (&s[..], 1.0)
+    };
+    let number: f64 = try!(number.parse().map_err(|_| invalid()));
+    let rate = (number * scale / 8.0) as u64;
+    if rate == 0 {
+        return Err(invalid());
+    }
+    Ok(rate)
+}
+
+pub struct Shaper {
+    // Bytes per second.
+    rate: u64,
+    capacity: u64,
+    tokens: u64,
+    last: Instant,
+}
+
+impl Shaper {
+    /// A shaper passing `rate` bytes per second, with a burst of a tenth of
+    /// a second's worth.
+    pub fn new(rate: u64, now: Instant) -> Shaper {
+        let capacity = rate / 10 + BURST * 1500;
+        Shaper {
+            rate: rate,
+            capacity: capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last {
+            return;
+        }
+        let elapsed = now.duration_since(self.last);
+        let micros = elapsed.as_secs() * 1000000 + elapsed.subsec_nanos() as u64 / 1000;
+        let added = self.rate.saturating_mul(micros) / 1000000;
+        if self.tokens + added >= self.capacity {
+            self.tokens = self.capacity;
+            self.last = now;
+        } else if added > 0 {
+            self.tokens += added;
+            // Carry over the time that has not produced a whole token yet.
+            let used = added * 1000000 / self.rate;
+            self.last = self.last + Duration::new(used / 1000000, (used % 1000000 * 1000) as u32);
+        }
+    }
+
+    /// Whether a packet of `len` bytes may pass now. Passing packets use up
+    /// the allowance.
+    pub fn admit(&mut self, len: usize, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < len as u64 {
+            return false;
+        }
+        self.tokens -= len as u64;
+        true
+    }
+
+    /// Returns the allowance of an admitted packet that was not sent after
+    /// all.
+    pub fn refund(&mut self, len: usize) {
+        self.tokens = cmp::min(self.tokens + len as u64, self.capacity);
+    }
+}
+
+#[test]
+fn parse_rate_test() {
+    assert_eq!(parse_rate("200mbit").unwrap(), 25000000);
+    assert_eq!(parse_rate("1.5gbit").unwrap(), 187500000);
+    assert_eq!(parse_rate("800kbit").unwrap(), 100000);
+    assert_eq!(parse_rate("8000").unwrap(), 1000);
+    assert!(parse_rate("fast").is_err());
+    assert!(parse_rate("0mbit").is_err());
+}
+
+#[test]
+fn shaper_test() {
+    let start = Instant::now();
+    let mut shaper = Shaper::new(1000000, start);
+    let mut passed = 0;
+    while shaper.admit(1000, start) {
+        passed += 1000;
+    }
+    assert_eq!(passed, 1000000 / 10 + BURST * 1500);
+    assert!(!shaper.admit(1500, start + Duration::from_millis(1)));
+    assert!(shaper.admit(1000, start + Duration::from_millis(1)));
+    assert!(shaper.admit(1500, start + Duration::from_millis(3)));
+    assert!(!shaper.admit(1500, start + Duration::from_millis(3)));
+    shaper.refund(1500);
+    assert!(shaper.admit(1500, start + Duration::from_millis(3)));
+}
@@ -0,0 +1,135 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SIGINT/SIGTERM handling that wakes up the event loops.
+//!
+//! The handler raises `INTERRUPTED` and writes a byte into a self-pipe. Every
+//! client and server registers the read end with its poll, so a signal ends
+//! `poll()` immediately instead of waiting for the next packet. The pipe is
+//! never drained: it stays readable and every loop in the process sees it.
+//!
+//! SIGUSR1 asks for a status report through a second pipe, which the loop
+//! that reports drains. SIGHUP only raises a flag for the log file watcher,
+//! and SIGUSR2 steps through log levels (see `verbosity`).
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering, ATOMIC_BOOL_INIT};
+use libc;
+use nix::sys::signal;
+use error::{Error, Result};
+use network::INTERRUPTED;
+use verbosity;
+
+static PIPE_READ: AtomicIsize = AtomicIsize::new(-1);
+static PIPE_WRITE: AtomicIsize = AtomicIsize::new(-1);
+static STATUS_READ: AtomicIsize = AtomicIsize::new(-1);
+static STATUS_WRITE: AtomicIsize = AtomicIsize::new(-1);
+static REOPEN: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_signal(signum: i32) {
+    if signum == libc::SIGHUP {
+        REOPEN.store(true, Ordering::Relaxed);
+        return;
+    }
+    if signum == libc::SIGUSR2 {
+        verbosity::cycle();
+        return;
+    }
+    let fd = if signum == libc::SIGUSR1 {
+        STATUS_WRITE.load(Ordering::Relaxed)
+    } else {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        PIPE_WRITE.load(Ordering::Relaxed)
+    };
+    if fd >= 0 {
+        let byte = 1u8;
+        // write() is async-signal-safe. If the pipe is full a wakeup is
+        // already pending, so the result does not matter.
+        unsafe {
+            libc::write(fd as RawFd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+}
+
+fn pipe(read: &AtomicIsize, write: &AtomicIsize) -> Result<()> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    for fd in &fds {
+        unsafe {
+            libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    read.store(fds[0] as isize, Ordering::Relaxed);
+    write.store(fds[1] as isize, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Installs handlers for SIGINT, SIGTERM, SIGUSR1, SIGUSR2 and SIGHUP. Call once, before
+/// building any `Client` or `Server`.
+pub fn install() -> Result<()> {
+    try!(pipe(&PIPE_READ, &PIPE_WRITE));
+    try!(pipe(&STATUS_READ, &STATUS_WRITE));
+
+    let sig_action = signal::SigAction::new(signal::SigHandler::Handler(handle_signal),
+                                            signal::SaFlags::empty(),
+                                            signal::SigSet::empty());
+    unsafe {
+        try!(signal::sigaction(signal::SIGINT, &sig_action)
+            .map_err(|e| Error::Config(format!("sigaction: {}", e))));
+        try!(signal::sigaction(signal::SIGTERM, &sig_action)
+            .map_err(|e| Error::Config(format!("sigaction: {}", e))));
+        try!(signal::sigaction(signal::SIGUSR1, &sig_action)
+            .map_err(|e| Error::Config(format!("sigaction: {}", e))));
+        try!(signal::sigaction(signal::SIGHUP, &sig_action)
+            .map_err(|e| Error::Config(format!("sigaction: {}", e))));
+        try!(signal::sigaction(signal::SIGUSR2, &sig_action)
+            .map_err(|e| Error::Config(format!("sigaction: {}", e))));
+    }
+    Ok(())
+}
+
+/// Read end of the self-pipe, if `install()` has been called.
+pub fn wakeup_fd() -> Option<RawFd> {
+    let fd = PIPE_READ.load(Ordering::Relaxed);
+    if fd >= 0 { Some(fd as RawFd) } else { None }
+}
+
+/// Read end of the status pipe, if `install()` has been called.
+pub fn status_fd() -> Option<RawFd> {
+    let fd = STATUS_READ.load(Ordering::Relaxed);
+    if fd >= 0 { Some(fd as RawFd) } else { None }
+}
+
+/// Consumes pending status requests. Whether there were any.
+pub fn take_status_request() -> bool {
+    let fd = match status_fd() {
+        Some(fd) => fd,
+        None => return false,
+    };
+    let mut buf = [0u8; 64];
+    let mut requested = false;
+    while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {
+        requested = true;
+    }
+    requested
+}
+
+/// Whether SIGHUP arrived since the last call.
+pub fn take_reopen_request() -> bool {
+    REOPEN.swap(false, Ordering::Relaxed)
+}
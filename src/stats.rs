@@ -0,0 +1,498 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Traffic counters and their export to statsd.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use packet;
+use error::Result;
+
+/// Running totals of tunnel traffic. Packets and bytes are counted inside
+/// the tunnel, before compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counters {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames dropped as malformed, unauthenticated or undeliverable.
+    pub errors: u64,
+    /// Packets out of the tunnel with wrong checksums, if verified.
+    pub corrupt: u64,
+    /// Data frames sent with and without compression.
+    pub tx_compressed: u64,
+    pub tx_raw: u64,
+    /// Packets, segments and handshakes dropped because a queue, buffer or
+    /// table was full. See the `limits` module.
+    pub overflow: u64,
+}
+
+impl Counters {
+    pub fn rx(&mut self, len: usize) {
+        self.rx_packets += 1;
+        self.rx_bytes += len as u64;
+    }
+
+    pub fn tx(&mut self, len: usize) {
+        self.tx_packets += 1;
+        self.tx_bytes += len as u64;
+    }
+
+    /// Notes how a data frame went out.
+    pub fn frame(&mut self, compressed: bool) {
+        if compressed {
+            self.tx_compressed += 1;
+        } else {
+            self.tx_raw += 1;
+        }
+    }
+
+    pub fn error(&mut self) {
+        self.errors += 1;
+    }
+
+    pub fn corrupt(&mut self) {
+        self.corrupt += 1;
+    }
+
+    pub fn overflow(&mut self) {
+        self.overflow += 1;
+    }
+}
+
+/// Smoothed round-trip time and its variation, estimated as in RFC 6298.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rtt {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl Rtt {
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// `None` until the first sample.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+}
+
+/// Upper bounds, in milliseconds, of the buckets of a `Histogram`. Slower
+/// samples land in a last, open bucket.
+pub const BUCKETS: [u64; 11] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000];
+
+/// Distribution of round-trip times, so that tail latency shows rather than
+/// only the smoothed average.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Histogram {
+    counts: [u64; 12],
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, rtt: Duration) {
+        let ms = millis(rtt);
+        let bucket = BUCKETS.iter().position(|&bound| ms <= bound).unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.max = cmp::max(self.max, rtt);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Samples in each bucket, the open one last.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Milliseconds below which the fraction `q` of the samples fall, as the
+    /// bound of their bucket but never above the slowest sample. `None`
+    /// without samples.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let rank = (q * self.count() as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if count > 0 && seen >= rank {
+                let max = millis(self.max);
+                return Some(BUCKETS.get(i).map_or(max, |&bound| cmp::min(bound, max)));
+            }
+        }
+        None
+    }
+
+    /// Gauges for statsd, named after `prefix`.
+    pub fn gauges(&self, prefix: &str) -> Vec<(String, u64)> {
+        [("p50", 0.5), ("p90", 0.9), ("p99", 0.99)]
+            .iter()
+            .filter_map(|&(name, q)| {
+                self.quantile(q).map(|ms| (format!("{}rtt_{}_ms", prefix, name), ms))
+            })
+            .collect()
+    }
+}
+
+/// Loss and jitter of a client's link, from its answers to keepalive pings.
+/// A ping still unanswered when the next one goes out counts as lost.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Link {
+    // Outcomes of the last `count` pings, newest in the lowest bit, set if
+    // answered.
+    history: u32,
+    count: u32,
+    outstanding: bool,
+    last_rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Link {
+    pub fn ping(&mut self) {
+        if self.outstanding {
+            self.record(false);
+        }
+        self.outstanding = true;
+    }
+
+    pub fn pong(&mut self, rtt: Duration) {
+        if !self.outstanding {
+            return;
+        }
+        self.outstanding = false;
+        self.record(true);
+        // Smoothed as the interarrival jitter of RFC 3550.
+        if let Some(last) = self.last_rtt {
+            let delta = if last > rtt { last - rtt } else { rtt - last };
+            self.jitter = if delta > self.jitter {
+                self.jitter + (delta - self.jitter) / 16
+            } else {
+                self.jitter - (self.jitter - delta) / 16
+            };
+        }
+        self.last_rtt = Some(rtt);
+    }
+
+    fn record(&mut self, answered: bool) {
+        self.history = self.history << 1 | answered as u32;
+        self.count = cmp::min(self.count + 1, 32);
+    }
+
+    /// Percentage of the last 32 pings that went unanswered, `None` before
+    /// the first outcome.
+    pub fn loss(&self) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let mask = if self.count == 32 { !0 } else { (1 << self.count) - 1 };
+        let lost = self.count - (self.history & mask).count_ones();
+        Some(lost as u64 * 100 / self.count as u64)
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+}
+
+/// Distinct ports tracked per client; traffic to further ports only counts
+/// towards its protocol.
+const TRACKED_PORTS: usize = 64;
+
+/// What a client sends into the tunnel, by protocol and destination port.
+#[derive(Debug, Clone, Default)]
+pub struct Traffic {
+    // Packets and bytes.
+    protocols: HashMap<u8, (u64, u64)>,
+    ports: HashMap<(u8, u16), (u64, u64)>,
+}
+
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        packet::IPPROTO_TCP => String::from("tcp"),
+        packet::IPPROTO_UDP => String::from("udp"),
+        packet::IPPROTO_ICMP => String::from("icmp"),
+        packet::IPPROTO_ICMPV6 => String::from("icmpv6"),
+        p => format!("proto{}", p),
+    }
+}
+
+impl Traffic {
+    pub fn add(&mut self, data: &[u8]) {
+        let (protocol, port) = match packet::transport(data) {
+            Ok(transport) => transport,
+            Err(_) => return,
+        };
+        let len = data.len() as u64;
+        let count = self.protocols.entry(protocol).or_insert((0, 0));
+        *count = (count.0 + 1, count.1 + len);
+        if let Some(port) = port {
+            let key = (protocol, port);
+            if self.ports.len() < TRACKED_PORTS || self.ports.contains_key(&key) {
+                let count = self.ports.entry(key).or_insert((0, 0));
+                *count = (count.0 + 1, count.1 + len);
+            }
+        }
+    }
+
+    /// Packets and bytes per protocol, busiest first.
+    pub fn protocols(&self) -> Vec<(String, u64, u64)> {
+        let mut protocols: Vec<(String, u64, u64)> = self.protocols
+            .iter()
+            .map(|(&p, &(packets, bytes))| (protocol_name(p), packets, bytes))
+            .collect();
+        protocols.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        protocols
+    }
+
+    /// The `n` destinations such as `tcp:443` that got the most bytes.
+    pub fn top_ports(&self, n: usize) -> Vec<(String, u64, u64)> {
+        let mut ports: Vec<(String, u64, u64)> = self.ports
+            .iter()
+            .map(|(&(p, port), &(packets, bytes))| {
+                (format!("{}:{}", protocol_name(p), port), packets, bytes)
+            })
+            .collect();
+        ports.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        ports.truncate(n);
+        ports
+    }
+
+    /// One line such as `tcp 1200B/3p udp 80B/1p; top tcp:443 1200B`.
+    pub fn summary(&self, ports: usize) -> String {
+        let protocols: Vec<String> = self.protocols()
+            .iter()
+            .map(|&(ref name, packets, bytes)| format!("{} {}B/{}p", name, bytes, packets))
+            .collect();
+        let top: Vec<String> = self.top_ports(ports)
+            .iter()
+            .map(|&(ref name, _, bytes)| format!("{} {}B", name, bytes))
+            .collect();
+        if top.is_empty() {
+            protocols.join(" ")
+        } else {
+            format!("{}; top {}", protocols.join(" "), top.join(", "))
+        }
+    }
+}
+
+pub fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000
+}
+
+/// Where and how often to send metrics.
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    pub addr: SocketAddr,
+    /// Prepended to every metric name, followed by a dot.
+    pub prefix: String,
+    pub interval: Duration,
+    /// DogStatsD tags such as `env:prod`. Plain statsd servers reject tags,
+    /// so leave this empty for them.
+    pub tags: Vec<String>,
+}
+
+impl StatsdConfig {
+    pub fn new(addr: SocketAddr) -> StatsdConfig {
+        StatsdConfig {
+            addr: addr,
+            prefix: String::from("kytan"),
+            interval: Duration::from_secs(10),
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// Sends the growth of the counters since the last flush as statsd counters,
+/// along with gauges such as the number of clients.
+pub struct Statsd {
+    config: StatsdConfig,
+    socket: UdpSocket,
+    flushed: Counters,
+    last_flush: Instant,
+}
+
+impl Statsd {
+    pub fn open(config: StatsdConfig, now: Instant) -> Result<Statsd> {
+        let local = if config.addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = try!(UdpSocket::bind(local));
+        try!(socket.set_nonblocking(true));
+        Ok(Statsd {
+            config: config,
+            socket: socket,
+            flushed: Counters::default(),
+            last_flush: now,
+        })
+    }
+
+    /// When the next flush is due.
+    pub fn deadline(&self) -> Instant {
+        self.last_flush + self.config.interval
+    }
+
+    /// Sends metrics if a flush is due. Metrics that cannot be sent are
+    /// lost; the counters carry on.
+    pub fn flush(&mut self, counters: &Counters, gauges: &[(String, u64)], now: Instant) {
+        if now < self.deadline() {
+            return;
+        }
+        self.last_flush = now;
+        let payload = format(&self.config, counters, &self.flushed, gauges);
+        self.flushed = *counters;
+        if let Err(e) = self.socket.send_to(payload.as_bytes(), &self.config.addr) {
+            debug!("Failed to send metrics to {}: {}", self.config.addr, e);
+        }
+    }
+}
+
+fn format(config: &StatsdConfig,
+          counters: &Counters,
+          flushed: &Counters,
+          gauges: &[(String, u64)])
+          -> String {
+    let tags = if config.tags.is_empty() {
+        String::new()
+    } else {
+        format!("|#{}", config.tags.join(","))
+    };
+    let mut lines = vec![("rx_packets", counters.rx_packets - flushed.rx_packets, "c"),
+                         ("rx_bytes", counters.rx_bytes - flushed.rx_bytes, "c"),
+                         ("tx_packets", counters.tx_packets - flushed.tx_packets, "c"),
+                         ("tx_bytes", counters.tx_bytes - flushed.tx_bytes, "c"),
+                         ("errors", counters.errors - flushed.errors, "c"),
+                         ("corrupt", counters.corrupt - flushed.corrupt, "c"),
+                         ("tx_compressed", counters.tx_compressed - flushed.tx_compressed, "c"),
+                         ("tx_raw", counters.tx_raw - flushed.tx_raw, "c"),
+                         ("overflow", counters.overflow - flushed.overflow, "c")];
+    for &(ref name, value) in gauges {
+        lines.push((&name[..], value, "g"));
+    }
+    lines.iter()
+        .map(|&(name, value, kind)| format!("{}.{}:{}|{}{}", config.prefix, name, value, kind, tags))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[test]
+fn format_test() {
+    let mut config = StatsdConfig::new("127.0.0.1:8125".parse().unwrap());
+    let mut counters = Counters::default();
+    counters.rx(100);
+    counters.tx(40);
+    counters.tx(60);
+    counters.frame(true);
+    let mut flushed = Counters::default();
+    flushed.tx(40);
+    assert_eq!(format(&config, &counters, &flushed, &[(String::from("clients"), 3)]),
+               "kytan.rx_packets:1|c\nkytan.rx_bytes:100|c\nkytan.tx_packets:1|c\n\
+                kytan.tx_bytes:60|c\nkytan.errors:0|c\nkytan.corrupt:0|c\n\
+                kytan.tx_compressed:1|c\nkytan.tx_raw:0|c\nkytan.overflow:0|c\n\
+                kytan.clients:3|g");
+    config.tags = vec![String::from("env:test")];
+    assert!(format(&config, &counters, &flushed, &[]).ends_with("kytan.overflow:0|c|#env:test"));
+}
+
+#[test]
+fn rtt_test() {
+    let mut rtt = Rtt::default();
+    assert_eq!(rtt.srtt(), None);
+    rtt.sample(Duration::from_millis(100));
+    assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+    assert_eq!(rtt.rttvar(), Duration::from_millis(50));
+    rtt.sample(Duration::from_millis(180));
+    assert_eq!(rtt.srtt(), Some(Duration::from_millis(110)));
+    assert_eq!(rtt.rttvar(), Duration::new(0, 57500000));
+}
+
+#[test]
+fn histogram_test() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.quantile(0.5), None);
+    for ms in 1..91 {
+        histogram.record(Duration::from_millis(ms % 9 + 3));
+    }
+    for _ in 0..9 {
+        histogram.record(Duration::from_millis(40));
+    }
+    histogram.record(Duration::from_millis(2500));
+    assert_eq!(histogram.count(), 100);
+    assert_eq!(histogram.quantile(0.5), Some(10));
+    assert_eq!(histogram.quantile(0.99), Some(50));
+    assert_eq!(histogram.quantile(1.0), Some(2500));
+    assert_eq!(histogram.counts()[11], 1);
+    assert_eq!(histogram.gauges("client.2.")[0], (String::from("client.2.rtt_p50_ms"), 10));
+}
+
+#[test]
+fn link_test() {
+    let mut link = Link::default();
+    assert_eq!(link.loss(), None);
+    link.ping();
+    link.pong(Duration::from_millis(100));
+    link.ping();
+    link.ping();
+    link.pong(Duration::from_millis(260));
+    assert_eq!(link.loss(), Some(33));
+    assert_eq!(link.jitter(), Duration::from_millis(10));
+    // Late answers to a ping already counted as lost are ignored.
+    link.pong(Duration::from_millis(900));
+    assert_eq!(link.loss(), Some(33));
+    for _ in 0..40 {
+        link.ping();
+        link.pong(Duration::from_millis(260));
+    }
+    assert_eq!(link.loss(), Some(0));
+}
+
+#[test]
+fn traffic_test() {
+    let mut tcp = vec![0u8; 40];
+    tcp[0] = 0x45;
+    tcp[3] = 40;
+    tcp[9] = packet::IPPROTO_TCP;
+    tcp[22] = 1;
+    tcp[23] = 187;
+    let mut udp = tcp.clone();
+    udp.truncate(28);
+    udp[3] = 28;
+    udp[9] = packet::IPPROTO_UDP;
+    udp[22] = 0;
+    udp[23] = 53;
+    let mut traffic = Traffic::default();
+    traffic.add(&tcp);
+    traffic.add(&tcp);
+    traffic.add(&udp);
+    traffic.add(&[]);
+    assert_eq!(traffic.protocols(),
+               vec![(String::from("tcp"), 2, 80), (String::from("udp"), 1, 28)]);
+    assert_eq!(traffic.top_ports(1), vec![(String::from("tcp:443"), 2, 80)]);
+    assert_eq!(traffic.summary(2), "tcp 80B/2p udp 28B/1p; top tcp:443 80B, udp:53 28B");
+}
@@ -0,0 +1,704 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reliable byte streams over the tunnel, for clients that run a SOCKS5
+//! proxy or port forwards instead of a TUN device.
+//!
+//! The client opens a stream with `StreamOpen`, naming the `HOST:PORT` the
+//! server should connect to, and the server answers with `StreamReply`.
+//! Both sides then exchange `Segment`s: numbered chunks of the stream that
+//! also acknowledge the chunks received so far. Chunks go out again until
+//! they are acknowledged, and a chunk with `fin` set ends its direction.
+//! `StreamClose` aborts a stream. There is no congestion control beyond a
+//! fixed window.
+//!
+//! Names are looked up by a `Resolver` on a thread of its own, so that a
+//! slow name cannot hold up the server's event loop.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use mio;
+use network::{Id, Token};
+use error::Result;
+
+pub type StreamId = u32;
+
+/// Most bytes in one chunk, so that segments fit any tunnel MTU.
+pub const CHUNK: usize = 1024;
+/// Chunks in flight per stream and direction.
+const WINDOW: usize = 64;
+/// Milliseconds a chunk may go unacknowledged before it is sent again.
+pub const RETRANSMIT: u64 = 300;
+/// Seconds without any acknowledgement after which a stream is given up.
+pub const GIVE_UP: u64 = 30;
+/// Bytes from the peer a relay holds for its socket by default.
+pub const BUFFER: usize = 256 * 1024;
+/// Names waiting to be looked up.
+const LOOKUPS: usize = 64;
+/// Names of one client being looked up at once.
+const CLIENT_LOOKUPS: usize = 4;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Segment {
+    pub stream: StreamId,
+    /// Number of the chunk. A segment without data and `fin` is a bare
+    /// acknowledgement and carries no chunk.
+    pub seq: u32,
+    /// Number of the next chunk expected from the other side.
+    pub ack: u32,
+    pub fin: bool,
+    pub data: Vec<u8>,
+}
+
+/// Stream traffic for the peer, wrapped into messages by the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outgoing {
+    Open(StreamId, String),
+    /// The outcome of an `Open`: `None` once connected, or why it failed.
+    Reply(StreamId, Option<String>),
+    Segment(Segment),
+    Close(StreamId),
+}
+
+struct Sent {
+    seq: u32,
+    data: Vec<u8>,
+    fin: bool,
+    at: Instant,
+}
+
+/// Sequencing and retransmission of one stream.
+pub struct Channel {
+    stream: StreamId,
+    next_seq: u32,
+    unacked: VecDeque<Sent>,
+    expected: u32,
+    early: BTreeMap<u32, (Vec<u8>, bool)>,
+    ack_due: bool,
+    sent_fin: bool,
+    received_fin: bool,
+    last_progress: Instant,
+}
+
+impl Channel {
+    pub fn new(stream: StreamId, now: Instant) -> Channel {
+        Channel {
+            stream: stream,
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            expected: 0,
+            early: BTreeMap::new(),
+            ack_due: false,
+            sent_fin: false,
+            received_fin: false,
+            last_progress: now,
+        }
+    }
+
+    /// Whether another chunk may go out now.
+    pub fn writable(&self) -> bool {
+        !self.sent_fin && self.unacked.len() < WINDOW
+    }
+
+    /// Sends `data`, at most `CHUNK` bytes, and ends the direction if `fin`.
+    pub fn send(&mut self, data: Vec<u8>, fin: bool, now: Instant) -> Segment {
+        if self.unacked.is_empty() {
+            self.last_progress = now;
+        }
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        self.sent_fin |= fin;
+        self.ack_due = false;
+        self.unacked.push_back(Sent {
+            seq: seq,
+            data: data.clone(),
+            fin: fin,
+            at: now,
+        });
+        Segment {
+            stream: self.stream,
+            seq: seq,
+            ack: self.expected,
+            fin: fin,
+            data: data,
+        }
+    }
+
+    /// Takes a segment from the peer and returns the data that is now in
+    /// order.
+    pub fn receive(&mut self, segment: Segment, now: Instant) -> Vec<u8> {
+        // Everything before `ack` arrived; compare with wrapping arithmetic.
+        let ack = segment.ack;
+        let acked = |sent: &Sent| (ack.wrapping_sub(sent.seq) as i32) > 0;
+        while self.unacked.front().map_or(false, &acked) {
+            self.unacked.pop_front();
+            self.last_progress = now;
+        }
+        if segment.data.is_empty() && !segment.fin {
+            return Vec::new();
+        }
+        // Duplicates must be acknowledged again; the first ack may be lost.
+        self.ack_due = true;
+        if segment.seq.wrapping_sub(self.expected) as usize >= WINDOW {
+            return Vec::new();
+        }
+        self.early.insert(segment.seq, (segment.data, segment.fin));
+        let mut delivered = Vec::new();
+        while let Some((data, fin)) = self.early.remove(&self.expected) {
+            delivered.extend_from_slice(&data);
+            self.received_fin |= fin;
+            self.expected = self.expected.wrapping_add(1);
+        }
+        delivered
+    }
+
+    /// Chunks unacknowledged for too long, or else a bare acknowledgement
+    /// if one is owed.
+    pub fn poll(&mut self, now: Instant) -> Vec<Segment> {
+        let (stream, ack) = (self.stream, self.expected);
+        let mut due: Vec<Segment> = self.unacked
+            .iter_mut()
+            .filter(|sent| now.duration_since(sent.at) >= Duration::from_millis(RETRANSMIT))
+            .map(|sent| {
+                sent.at = now;
+                Segment {
+                    stream: stream,
+                    seq: sent.seq,
+                    ack: ack,
+                    fin: sent.fin,
+                    data: sent.data.clone(),
+                }
+            })
+            .collect();
+        if due.is_empty() && self.ack_due {
+            due.push(Segment {
+                stream: stream,
+                seq: self.next_seq,
+                ack: ack,
+                fin: false,
+                data: Vec::new(),
+            });
+        }
+        self.ack_due = false;
+        due
+    }
+
+    /// Whether the peer has stopped acknowledging.
+    pub fn stalled(&self, now: Instant) -> bool {
+        !self.unacked.is_empty() &&
+        now.duration_since(self.last_progress) >= Duration::from_secs(GIVE_UP)
+    }
+
+    pub fn received_fin(&self) -> bool {
+        self.received_fin
+    }
+
+    /// Whether both directions ended and everything was acknowledged.
+    pub fn finished(&self) -> bool {
+        self.sent_fin && self.received_fin && self.unacked.is_empty()
+    }
+}
+
+/// A TCP connection relayed over a stream. The socket is registered
+/// edge-triggered for reading and writing.
+pub struct Relay {
+    pub socket: mio::tcp::TcpStream,
+    pub channel: Channel,
+    // Data from the peer the socket has not taken yet, up to `limit` bytes.
+    outgoing: Vec<u8>,
+    limit: usize,
+    eof: bool,
+    shut: bool,
+}
+
+impl Relay {
+    pub fn new(socket: mio::tcp::TcpStream, stream: StreamId, limit: usize, now: Instant)
+               -> Relay {
+        Relay {
+            socket: socket,
+            channel: Channel::new(stream, now),
+            outgoing: Vec::new(),
+            limit: limit,
+            eof: false,
+            shut: false,
+        }
+    }
+
+    /// Writes what the socket takes, then reads what the window allows.
+    pub fn ready(&mut self, now: Instant) -> io::Result<Vec<Segment>> {
+        try!(self.flush());
+        let mut segments = Vec::new();
+        let mut buf = [0u8; CHUNK];
+        while self.channel.writable() && !self.eof {
+            match self.socket.read(&mut buf) {
+                Ok(0) => {
+                    self.eof = true;
+                    segments.push(self.channel.send(Vec::new(), true, now));
+                }
+                Ok(len) => segments.push(self.channel.send(buf[..len].to_vec(), false, now)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(segments)
+    }
+
+    /// Whether the data of `segment` fits the buffer for the socket.
+    pub fn fits(&self, segment: &Segment) -> bool {
+        segment.data.len() <= CHUNK && self.outgoing.len() + segment.data.len() <= self.limit
+    }
+
+    /// Takes a segment from the peer. Acknowledgements may open the window,
+    /// so this reads from the socket too. The data of a segment that does not
+    /// fit is dropped; the peer sends it again.
+    pub fn receive(&mut self, mut segment: Segment, now: Instant) -> io::Result<Vec<Segment>> {
+        if !self.fits(&segment) {
+            segment.data.clear();
+            segment.fin = false;
+        }
+        let data = self.channel.receive(segment, now);
+        self.outgoing.extend_from_slice(&data);
+        self.ready(now)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.socket.write(&self.outgoing) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "socket closed")),
+                Ok(len) => {
+                    self.outgoing.drain(..len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        if self.channel.received_fin() && !self.shut {
+            self.shut = true;
+            try!(self.socket.shutdown(Shutdown::Write));
+        }
+        Ok(())
+    }
+
+    pub fn finished(&self) -> bool {
+        self.channel.finished() && self.outgoing.is_empty()
+    }
+}
+
+struct Exit {
+    relay: Relay,
+    token: mio::Token,
+    connected: bool,
+}
+
+/// The server's end of the streams: outgoing TCP connections on behalf of
+/// clients.
+pub struct Exits {
+    base: usize,
+    next: usize,
+    max_exits: usize,
+    max_client_exits: usize,
+    buffer: usize,
+    // Segments dropped because a relay's buffer was full.
+    dropped: u64,
+    exits: HashMap<(Id, StreamId), Exit>,
+    tokens: HashMap<mio::Token, (Id, StreamId)>,
+    // Streams relayed for each client.
+    per_client: HashMap<Id, usize>,
+}
+
+impl Exits {
+    /// Sockets are registered with tokens from `base` upwards. Up to
+    /// `max_exits` streams are relayed, at most `max_client_exits` of them
+    /// for one client, each holding up to `buffer` bytes for its socket.
+    pub fn new(base: usize, max_exits: usize, max_client_exits: usize, buffer: usize) -> Exits {
+        Exits {
+            base: base,
+            next: 0,
+            max_exits: max_exits,
+            max_client_exits: max_client_exits,
+            buffer: buffer,
+            dropped: 0,
+            exits: HashMap::new(),
+            tokens: HashMap::new(),
+            per_client: HashMap::new(),
+        }
+    }
+
+    pub fn owns(&self, token: mio::Token) -> bool {
+        token.0 >= self.base
+    }
+
+    /// Connects stream `stream` of `client` to `addr`. Returns a reply if
+    /// the outcome is already known.
+    pub fn open(&mut self,
+                poll: &mio::Poll,
+                client: Id,
+                stream: StreamId,
+                addr: SocketAddr,
+                now: Instant)
+                -> Option<Outgoing> {
+        if let Some(exit) = self.exits.get(&(client, stream)) {
+            // The client did not hear the reply.
+            return if exit.connected { Some(Outgoing::Reply(stream, None)) } else { None };
+        }
+        if self.exits.len() >= self.max_exits ||
+           self.per_client.get(&client).map_or(false, |&count| count >= self.max_client_exits) {
+            return Some(Outgoing::Reply(stream, Some(String::from("too many streams"))));
+        }
+        let socket = match mio::tcp::TcpStream::connect(&addr) {
+            Ok(socket) => socket,
+            Err(e) => return Some(Outgoing::Reply(stream, Some(e.to_string()))),
+        };
+        let token = self.allocate_token();
+        if let Err(e) = poll.register(&socket,
+                                      token,
+                                      mio::Ready::readable() | mio::Ready::writable(),
+                                      mio::PollOpt::edge()) {
+            return Some(Outgoing::Reply(stream, Some(e.to_string())));
+        }
+        debug!("Client {} opened stream {} to {}.", client, stream, addr);
+        self.tokens.insert(token, (client, stream));
+        *self.per_client.entry(client).or_insert(0) += 1;
+        self.exits.insert((client, stream),
+                          Exit {
+                              relay: Relay::new(socket, stream, self.buffer, now),
+                              token: token,
+                              connected: false,
+                          });
+        None
+    }
+
+    fn allocate_token(&mut self) -> mio::Token {
+        // There are more tokens than streams, so a free one always exists.
+        loop {
+            let token = mio::Token(self.base + self.next);
+            self.next = (self.next + 1) % (self.max_exits * 2);
+            if !self.tokens.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+
+    /// Handles readiness of the socket behind `token`.
+    pub fn ready(&mut self, poll: &mio::Poll, token: mio::Token, now: Instant)
+                 -> Vec<(Id, Outgoing)> {
+        let key = match self.tokens.get(&token) {
+            Some(&key) => key,
+            None => return Vec::new(),
+        };
+        let mut out = Vec::new();
+        let failed = {
+            let exit = self.exits.get_mut(&key).unwrap();
+            if exit.connected {
+                None
+            } else {
+                match exit.relay.socket.take_error() {
+                    Ok(None) if exit.relay.socket.peer_addr().is_ok() => {
+                        exit.connected = true;
+                        out.push((key.0, Outgoing::Reply(key.1, None)));
+                        None
+                    }
+                    // Not connected yet.
+                    Ok(None) => return out,
+                    Ok(Some(e)) | Err(e) => Some(e),
+                }
+            }
+        };
+        if let Some(e) = failed {
+            out.push((key.0, Outgoing::Reply(key.1, Some(e.to_string()))));
+            self.remove(poll, key);
+            return out;
+        }
+        let result = self.exits.get_mut(&key).unwrap().relay.ready(now);
+        self.settle(poll, key, result, &mut out);
+        out
+    }
+
+    /// Takes a segment from `client`.
+    pub fn segment(&mut self, poll: &mio::Poll, client: Id, segment: Segment, now: Instant)
+                   -> Vec<(Id, Outgoing)> {
+        let key = (client, segment.stream);
+        let mut out = Vec::new();
+        let result = match self.exits.get_mut(&key) {
+            Some(exit) => {
+                if !exit.connected {
+                    return out;
+                }
+                if !exit.relay.fits(&segment) {
+                    self.dropped += 1;
+                }
+                exit.relay.receive(segment, now)
+            }
+            None => {
+                out.push((client, Outgoing::Close(segment.stream)));
+                return out;
+            }
+        };
+        self.settle(poll, key, result, &mut out);
+        out
+    }
+
+    fn settle(&mut self,
+              poll: &mio::Poll,
+              key: (Id, StreamId),
+              result: io::Result<Vec<Segment>>,
+              out: &mut Vec<(Id, Outgoing)>) {
+        match result {
+            Ok(segments) => {
+                out.extend(segments.into_iter().map(|segment| (key.0, Outgoing::Segment(segment))));
+                if self.exits.get(&key).map_or(false, |exit| exit.relay.finished()) {
+                    self.remove(poll, key);
+                }
+            }
+            Err(e) => {
+                debug!("Stream {} of client {} failed: {}", key.1, key.0, e);
+                out.push((key.0, Outgoing::Close(key.1)));
+                self.remove(poll, key);
+            }
+        }
+    }
+
+    /// Drops a stream the client aborted.
+    pub fn close(&mut self, poll: &mio::Poll, client: Id, stream: StreamId) {
+        self.remove(poll, (client, stream));
+    }
+
+    /// Drops every stream of a client that went away.
+    pub fn end(&mut self, poll: &mio::Poll, client: Id) {
+        let keys: Vec<(Id, StreamId)> =
+            self.exits.keys().filter(|key| key.0 == client).cloned().collect();
+        for key in keys {
+            self.remove(poll, key);
+        }
+    }
+
+    fn remove(&mut self, poll: &mio::Poll, key: (Id, StreamId)) {
+        if let Some(exit) = self.exits.remove(&key) {
+            let _ = poll.deregister(&exit.relay.socket);
+            self.tokens.remove(&exit.token);
+            let left = self.per_client.get_mut(&key.0).map_or(0, |count| {
+                *count -= 1;
+                *count
+            });
+            if left == 0 {
+                self.per_client.remove(&key.0);
+            }
+            debug!("Stream {} of client {} closed.", key.1, key.0);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exits.is_empty()
+    }
+
+    /// Segments dropped since the last call.
+    pub fn take_dropped(&mut self) -> u64 {
+        let dropped = self.dropped;
+        self.dropped = 0;
+        dropped
+    }
+
+    /// Retransmissions and acknowledgements that are due, and aborts of
+    /// streams whose client stopped answering.
+    pub fn tick(&mut self, poll: &mio::Poll, now: Instant) -> Vec<(Id, Outgoing)> {
+        let mut out = Vec::new();
+        let mut stalled = Vec::new();
+        for (&key, exit) in self.exits.iter_mut() {
+            if exit.relay.channel.stalled(now) {
+                stalled.push(key);
+                continue;
+            }
+            let segments = exit.relay.channel.poll(now);
+            out.extend(segments.into_iter().map(|segment| (key.0, Outgoing::Segment(segment))));
+        }
+        for key in stalled {
+            out.push((key.0, Outgoing::Close(key.1)));
+            self.remove(poll, key);
+        }
+        out
+    }
+}
+
+/// Whether `addr` is on this host or its links: loopback, link-local or
+/// unspecified. Streams go there only if the server allows it.
+pub fn local(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_loopback() || addr.is_link_local() || addr.is_unspecified(),
+        IpAddr::V6(addr) => {
+            match addr.to_ipv4() {
+                // Mapped IPv4 addresses reach the same hosts.
+                Some(v4) if addr.segments()[5] == 0xffff => local(IpAddr::V4(v4)),
+                _ => {
+                    addr.is_loopback() || addr.is_unspecified() ||
+                    addr.segments()[0] & 0xffc0 == 0xfe80
+                }
+            }
+        }
+    }
+}
+
+/// A name looked up for a stream: the client, its session token, the
+/// stream, the name and the outcome.
+pub type Lookup = (Id, Token, StreamId, String, io::Result<SocketAddr>);
+
+/// Looks up the `HOST:PORT`s clients open streams to.
+pub struct Resolver {
+    jobs: SyncSender<(Id, Token, StreamId, String)>,
+    done: Receiver<Lookup>,
+    // Lookups queued or running.
+    pending: HashSet<(Id, StreamId)>,
+    readiness: mio::SetReadiness,
+    _registration: mio::Registration,
+}
+
+impl Resolver {
+    /// Starts the lookup thread. `poll` becomes readable on `token` when
+    /// lookups are done.
+    pub fn spawn(poll: &mio::Poll, token: mio::Token) -> Result<Resolver> {
+        let (registration, readiness) = mio::Registration::new2();
+        try!(poll.register(&registration, token, mio::Ready::readable(), mio::PollOpt::level()));
+        let (jobs, queue) = mpsc::sync_channel::<(Id, Token, StreamId, String)>(LOOKUPS);
+        let (results, done) = mpsc::channel();
+        let wakeup = readiness.clone();
+        try!(thread::Builder::new()
+            .name(String::from("kytan-resolver"))
+            .spawn(move || for (client, token, stream, target) in queue {
+                let addr = target.to_socket_addrs().and_then(|mut addrs| {
+                    addrs.next().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound,
+                                       format!("{} does not resolve", target))
+                    })
+                });
+                if results.send((client, token, stream, target, addr)).is_err() {
+                    break;
+                }
+                let _ = wakeup.set_readiness(mio::Ready::readable());
+            }));
+        Ok(Resolver {
+            jobs: jobs,
+            done: done,
+            pending: HashSet::new(),
+            readiness: readiness,
+            _registration: registration,
+        })
+    }
+
+    /// Queues a lookup for stream `stream` of `client`, whose session has
+    /// `token`. True if it is queued, or was already. False if too many
+    /// lookups are waiting, for everyone or for this client.
+    pub fn submit(&mut self, client: Id, token: Token, stream: StreamId, target: &str) -> bool {
+        if self.pending.contains(&(client, stream)) {
+            // The client sent the open again while it waits.
+            return true;
+        }
+        if self.pending.iter().filter(|key| key.0 == client).count() >= CLIENT_LOOKUPS {
+            return false;
+        }
+        match self.jobs.try_send((client, token, stream, String::from(target))) {
+            Ok(()) => {
+                self.pending.insert((client, stream));
+                true
+            }
+            Err(TrySendError::Full(_)) |
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Forgets the lookups of a client that went away. Their outcomes are
+    /// still delivered, for the caller to drop.
+    pub fn end(&mut self, client: Id) {
+        self.pending.retain(|key| key.0 != client);
+    }
+
+    /// Lookups done since the last call.
+    pub fn done(&mut self) -> Vec<Lookup> {
+        // Clear first: a lookup finishing meanwhile sets it again.
+        let _ = self.readiness.set_readiness(mio::Ready::empty());
+        let done: Vec<Lookup> = self.done.try_iter().collect();
+        for lookup in &done {
+            self.pending.remove(&(lookup.0, lookup.2));
+        }
+        done
+    }
+}
+
+#[test]
+fn exits_test() {
+    let poll = mio::Poll::new().unwrap();
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let now = Instant::now();
+    let mut exits = Exits::new(100, 3, 2, BUFFER);
+    assert_eq!(exits.open(&poll, 1, 1, addr, now), None);
+    assert_eq!(exits.open(&poll, 1, 2, addr, now), None);
+    let full = Some(Outgoing::Reply(3, Some(String::from("too many streams"))));
+    // One client cannot take every stream.
+    assert_eq!(exits.open(&poll, 1, 3, addr, now), full);
+    assert_eq!(exits.open(&poll, 2, 3, addr, now), None);
+    assert_eq!(exits.open(&poll, 3, 3, addr, now), full);
+    exits.end(&poll, 1);
+    assert_eq!(exits.open(&poll, 3, 3, addr, now), None);
+    exits.close(&poll, 2, 3);
+    exits.close(&poll, 3, 3);
+    assert!(exits.is_empty());
+}
+
+#[test]
+fn local_test() {
+    for addr in &["127.0.0.1", "127.8.0.1", "169.254.1.1", "0.0.0.0", "::1", "::", "fe80::1",
+                  "::ffff:127.0.0.1"] {
+        assert!(local(addr.parse().unwrap()), "{}", addr);
+    }
+    for addr in &["10.0.0.1", "192.0.2.1", "2001:db8::1", "fec0::1", "::ffff:192.0.2.1"] {
+        assert!(!local(addr.parse().unwrap()), "{}", addr);
+    }
+}
+
+#[test]
+fn channel_test() {
+    let now = Instant::now();
+    let mut a = Channel::new(7, now);
+    let mut b = Channel::new(7, now);
+    let first = a.send(b"hello ".to_vec(), false, now);
+    let second = a.send(b"world".to_vec(), true, now);
+    assert!(!a.writable());
+
+    // Out of order: nothing is delivered until the gap is filled.
+    assert!(b.receive(second.clone(), now).is_empty());
+    assert_eq!(b.receive(first.clone(), now), b"hello world".to_vec());
+    assert!(b.received_fin());
+    assert!(b.receive(first, now).is_empty());
+
+    let ack = b.poll(now);
+    assert_eq!(ack.len(), 1);
+    assert!(ack[0].data.is_empty() && ack[0].ack == 2);
+    assert!(a.poll(now).is_empty());
+    let later = now + Duration::from_millis(RETRANSMIT);
+    let resent: Vec<u32> = a.poll(later).iter().map(|segment| segment.seq).collect();
+    assert_eq!(resent, vec![0, 1]);
+    assert!(a.poll(later).is_empty());
+    assert!(a.receive(ack[0].clone(), later).is_empty());
+    assert!(a.poll(later + Duration::from_secs(1)).is_empty());
+    assert!(!a.stalled(later + Duration::from_secs(GIVE_UP)));
+    let fin = b.send(Vec::new(), true, now);
+    assert!(a.receive(fin, later).is_empty());
+    assert!(a.finished() && !b.finished());
+    let ack = a.poll(later);
+    b.receive(ack[0].clone(), later);
+    assert!(b.finished());
+}
@@ -0,0 +1,224 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Distributed tracing of handshakes and sessions.
+//!
+//! Spans are exported in batches to an OpenTelemetry collector using OTLP
+//! over HTTP with JSON bodies. Exporting happens on a thread of its own, so
+//! a slow or missing collector never stalls the tunnel; spans that cannot be
+//! delivered are dropped.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand;
+
+/// How often finished spans are sent to the collector.
+const FLUSH_INTERVAL: u64 = 5;
+/// Spans sent in one request at most.
+const BATCH_SIZE: usize = 64;
+
+fn unix_nanos() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    elapsed.as_secs() * 1000000000 + elapsed.subsec_nanos() as u64
+}
+
+/// Escapes `s` as a JSON string literal.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// An operation in progress. Hand it back to `Tracer::end()` when done.
+#[derive(Debug, Clone)]
+pub struct Span {
+    trace_id: (u64, u64),
+    span_id: u64,
+    parent: Option<u64>,
+    name: String,
+    start: u64,
+    end: u64,
+    attributes: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    fn new(name: &str, trace_id: (u64, u64), parent: Option<u64>) -> Span {
+        Span {
+            trace_id: trace_id,
+            span_id: rand::random(),
+            parent: parent,
+            name: String::from(name),
+            start: unix_nanos(),
+            end: 0,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Starts a span nested in this one.
+    pub fn child(&self, name: &str) -> Span {
+        Span::new(name, self.trace_id, Some(self.span_id))
+    }
+
+    pub fn set<T: ToString>(&mut self, key: &str, value: T) {
+        self.attributes.push((String::from(key), value.to_string()));
+    }
+
+    /// Marks the operation as failed.
+    pub fn fail<T: ToString>(&mut self, message: T) {
+        self.error = Some(message.to_string());
+    }
+
+    fn to_json(&self) -> String {
+        let attributes: Vec<String> = self.attributes
+            .iter()
+            .map(|&(ref key, ref value)| {
+                format!("{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+                        quote(key),
+                        quote(value))
+            })
+            .collect();
+        let status = match self.error {
+            Some(ref message) => format!("{{\"code\":2,\"message\":{}}}", quote(message)),
+            None => String::from("{\"code\":1}"),
+        };
+        let parent = match self.parent {
+            Some(parent) => format!("\"parentSpanId\":\"{:016x}\",", parent),
+            None => String::new(),
+        };
+        format!("{{\"traceId\":\"{:016x}{:016x}\",\"spanId\":\"{:016x}\",{}\"name\":{},\
+                 \"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\
+                 \"attributes\":[{}],\"status\":{}}}",
+                self.trace_id.0,
+                self.trace_id.1,
+                self.span_id,
+                parent,
+                quote(&self.name),
+                self.start,
+                self.end,
+                attributes.join(","),
+                status)
+    }
+}
+
+/// Starts spans and queues finished ones for export.
+pub struct Tracer {
+    sender: mpsc::Sender<Span>,
+}
+
+impl Tracer {
+    /// Exports to the OTLP/HTTP collector at `endpoint` (`host:port`),
+    /// reporting as `service`.
+    pub fn start(endpoint: &str, service: &str) -> Tracer {
+        let (sender, receiver) = mpsc::channel();
+        let endpoint = String::from(endpoint);
+        let service = String::from(service);
+        thread::spawn(move || export(receiver, &endpoint, &service));
+        Tracer { sender: sender }
+    }
+
+    /// Starts the root span of a new trace.
+    pub fn span(&self, name: &str) -> Span {
+        Span::new(name, (rand::random(), rand::random()), None)
+    }
+
+    pub fn end(&self, mut span: Span) {
+        span.end = unix_nanos();
+        // The exporter only goes away with the tracer.
+        let _ = self.sender.send(span);
+    }
+}
+
+fn export(receiver: mpsc::Receiver<Span>, endpoint: &str, service: &str) {
+    let mut batch = Vec::new();
+    loop {
+        let open = match receiver.recv_timeout(Duration::from_secs(FLUSH_INTERVAL)) {
+            Ok(span) => {
+                batch.push(span);
+                if batch.len() < BATCH_SIZE {
+                    continue;
+                }
+                true
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => true,
+            Err(mpsc::RecvTimeoutError::Disconnected) => false,
+        };
+        if !batch.is_empty() {
+            if let Err(e) = post(endpoint, service, &batch) {
+                warn!("Dropping {} spans: {}", batch.len(), e);
+            }
+            batch.clear();
+        }
+        if !open {
+            return;
+        }
+    }
+}
+
+fn post(endpoint: &str, service: &str, spans: &[Span]) -> io::Result<()> {
+    let spans: Vec<String> = spans.iter().map(|span| span.to_json()).collect();
+    let body = format!("{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{{\"key\":\
+                        \"service.name\",\"value\":{{\"stringValue\":{}}}}}]}},\
+                        \"scopeSpans\":[{{\"scope\":{{\"name\":\"kytan\"}},\"spans\":[{}]}}]}}]}}",
+                       quote(service),
+                       spans.join(","));
+    let addr = match try!(endpoint.to_socket_addrs()).next() {
+        Some(addr) => addr,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "collector not found")),
+    };
+    let mut stream = try!(TcpStream::connect_timeout(&addr, Duration::from_secs(2)));
+    try!(stream.set_read_timeout(Some(Duration::from_secs(5))));
+    try!(write!(stream,
+                "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                endpoint,
+                body.len(),
+                body));
+    let mut status = [0u8; 12];
+    try!(stream.read_exact(&mut status));
+    if &status[9..10] != b"2" {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("collector answered {}",
+                                          String::from_utf8_lossy(&status[9..12]))));
+    }
+    Ok(())
+}
+
+#[test]
+fn span_json_test() {
+    let tracer = Tracer { sender: mpsc::channel().0 };
+    let mut root = tracer.span("session");
+    root.set("client.id", 2);
+    let mut child = root.child("handshake");
+    child.fail("no \"answer\"");
+    assert_eq!(child.trace_id, root.trace_id);
+    assert_eq!(child.parent, Some(root.span_id));
+    let json = child.to_json();
+    assert!(json.contains(&format!("\"parentSpanId\":\"{:016x}\"", root.span_id)));
+    assert!(json.contains("\"status\":{\"code\":2,\"message\":\"no \\\"answer\\\"\"}"));
+    assert!(root.to_json().contains("{\"key\":\"client.id\",\"value\":{\"stringValue\":\"2\"}}"));
+}
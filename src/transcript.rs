@@ -0,0 +1,165 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A record of a session's control plane, for bug reports.
+//!
+//! Each line has a Unix timestamp in milliseconds, a direction, the peer
+//! and what was said, or a note about what the client did:
+//!
+//! ```text
+//! 1700000000.125 > 203.0.113.5:9527 Request version=1 flags=snappy mtu=1500 resume=none auth=none
+//! 1700000000.168 < 203.0.113.5:9527 Response id=2 version=1 flags=snappy mtu=1432
+//! 1700000030.170 - Host network changed.
+//! ```
+//!
+//! Data frames are left out, and so are tokens, credentials, stamps and
+//! proofs, so the file can be attached to an issue as it is.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use network::{Capabilities, Id, Message, Token};
+use error::Result;
+
+pub struct Transcript {
+    file: File,
+}
+
+impl Transcript {
+    /// Appends to the file at `path`, so that reconnects stay in one place.
+    pub fn open(path: &Path) -> Result<Transcript> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        Ok(Transcript { file: file })
+    }
+
+    pub fn sent(&mut self, to: &SocketAddr, msg: &Message) {
+        if let Some(line) = describe(msg) {
+            self.write(&format!("> {} {}", to, line));
+        }
+    }
+
+    pub fn received(&mut self, from: &SocketAddr, msg: &Message) {
+        if let Some(line) = describe(msg) {
+            self.write(&format!("< {} {}", from, line));
+        }
+    }
+
+    /// Records something the client did on its own, such as failing over.
+    pub fn note(&mut self, what: &str) {
+        self.write(&format!("- {}", what));
+    }
+
+    fn write(&mut self, line: &str) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        let stamped = format!("{}.{:03} {}\n",
+                              now.as_secs(),
+                              now.subsec_nanos() / 1000000,
+                              line);
+        if let Err(e) = self.file.write_all(stamped.as_bytes()) {
+            debug!("Failed to write the session transcript: {}", e);
+        }
+    }
+}
+
+fn caps(caps: &Capabilities) -> String {
+    format!("version={} flags={} mtu={}",
+            caps.version,
+            caps.flag_names(),
+            caps.mtu)
+}
+
+fn resume(resume: &Option<(Id, Token)>) -> String {
+    match *resume {
+        Some((id, _)) => id.to_string(),
+        None => String::from("none"),
+    }
+}
+
+/// One line about a control message, or `None` for data frames.
+pub fn describe(msg: &Message) -> Option<String> {
+    let line = match *msg {
+        Message::Request { caps: ref c, resume: ref r, ref credential, .. } => {
+            format!("Request {} resume={} auth={}",
+                    caps(c),
+                    resume(r),
+                    if credential.is_some() { "credential" } else { "none" })
+        }
+        Message::Stamped { caps: ref c, resume: ref r, .. } => {
+            format!("Stamped {} resume={} auth=stamp", caps(c), resume(r))
+        }
+        Message::Identified { caps: ref c, resume: ref r, ref credential, ref stamp, .. } => {
+            let auth = if stamp.is_some() {
+                "identity,stamp"
+            } else if credential.is_some() {
+                "identity,credential"
+            } else {
+                "identity"
+            };
+            format!("Identified {} resume={} auth={}", caps(c), resume(r), auth)
+        }
+        Message::Response { id, caps: ref c, .. } => format!("Response id={} {}", id, caps(c)),
+        Message::Assigned { id, caps: ref c, ref subnet, .. } => {
+            format!("Assigned id={} {} subnet={}", id, caps(c), subnet)
+        }
+        Message::Configured { id, caps: ref c, ref options, .. } => {
+            format!("Configured id={} {} options={}", id, caps(c), options.len())
+        }
+        Message::Roam { id, .. } => format!("Roam id={}", id),
+        Message::Ping { id, .. } => format!("Ping id={}", id),
+        Message::Pong { id, .. } => format!("Pong id={}", id),
+        Message::Expired { id, .. } => format!("Expired id={}", id),
+        Message::Probe { .. } => String::from("Probe"),
+        Message::Refused { code } => format!("Refused code={}", code),
+        Message::StreamOpen { id, stream, .. } => format!("StreamOpen id={} stream={}", id, stream),
+        Message::StreamReply { id, stream, ref error, .. } => {
+            format!("StreamReply id={} stream={} error={}",
+                    id,
+                    stream,
+                    error.as_ref().map_or("none", |e| &e[..]))
+        }
+        Message::StreamClose { id, stream, .. } => {
+            format!("StreamClose id={} stream={}", id, stream)
+        }
+        Message::Data { .. } |
+        Message::RawData { .. } |
+        Message::Cover { .. } |
+        Message::Batch { .. } |
+        Message::Segment { .. } |
+        Message::Relay { .. } => return None,
+    };
+    Some(line)
+}
+
+#[test]
+fn describe_test() {
+    let request = Message::Request {
+        caps: Capabilities::new(0),
+        resume: Some((3, 0x5ec2e7)),
+        credential: Some(String::from("hunter2")),
+        roam_key: vec![7; 32],
+    };
+    let line = describe(&request).unwrap();
+    assert!(line.starts_with("Request version="));
+    assert!(line.ends_with("resume=3 auth=credential"));
+    assert!(!line.contains("hunter2") && !line.contains(&0x5ec2e7.to_string()));
+    assert_eq!(describe(&Message::Expired { id: 3, token: 7 }).unwrap(), "Expired id=3");
+    let data = Message::Data {
+        id: 3,
+        token: 7,
+        data: vec![0x45; 20],
+    };
+    assert_eq!(describe(&data), None);
+}
@@ -12,28 +12,103 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::net::IpAddr;
+#[cfg(any(target_os = "linux", windows))]
+use std::net::Ipv4Addr;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::Ipv6Addr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::process::Command;
+#[cfg(target_os = "linux")]
+use netlink::{self, Netlink, Route};
+#[cfg(windows)]
+use iphlpapi;
+#[cfg(target_os = "macos")]
+use std::io::Write;
+#[cfg(target_os = "macos")]
+use std::process::Stdio;
+use device;
+use keyring;
+use libc;
+use error::{Error, Result};
 
-pub fn enable_ipv4_forwarding() -> Result<(), String> {
-    let sysctl_arg = if cfg!(target_os = "linux") {
-        "net.ipv4.ip_forward=1"
-    } else if cfg!(target_os = "macos") {
-        "net.inet.ip.forwarding=1"
+fn read_sysctl(name: &str) -> Result<String> {
+    let output = try!(Command::new("sysctl")
+        .arg("-n")
+        .arg(name)
+        .output());
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
     } else {
-        unimplemented!()
-    };
-    let status = Command::new("sysctl")
+        Err(Error::Route(format!("sysctl: {}", output.status)))
+    }
+}
+
+fn write_sysctl(name: &str, value: &str) -> Result<()> {
+    let status = try!(Command::new("sysctl")
         .arg("-w")
-        .arg(sysctl_arg)
-        .status()
-        .unwrap();
+        .arg(format!("{}={}", name, value))
+        .status());
     if status.success() {
         Ok(())
     } else {
-        Err(format!("sysctl: {}", status))
+        Err(Error::Route(format!("sysctl: {}", status)))
+    }
+}
+
+/// A sysctl changed by kytan. The previous value is put back on drop unless
+/// `persist()` was called.
+pub struct Sysctl {
+    name: &'static str,
+    original: String,
+    restore: bool,
+}
+
+impl Sysctl {
+    pub fn set(name: &'static str, value: &str) -> Result<Sysctl> {
+        let original = try!(read_sysctl(name));
+        if original != value {
+            try!(write_sysctl(name, value));
+        }
+        Ok(Sysctl {
+            name: name,
+            original: original,
+            restore: true,
+        })
+    }
+
+    /// Keeps the new value after kytan exits.
+    pub fn persist(&mut self) {
+        self.restore = false;
+    }
+}
+
+impl Drop for Sysctl {
+    fn drop(&mut self) {
+        if !self.restore {
+            return;
+        }
+        info!("Restoring {} to {}.", self.name, self.original);
+        if let Err(e) = write_sysctl(self.name, &self.original) {
+            error!("Failed to restore {}: {}", self.name, e);
+        }
     }
 }
 
+pub fn enable_ipv4_forwarding() -> Result<Sysctl> {
+    let name = if cfg!(target_os = "linux") {
+        "net.ipv4.ip_forward"
+    } else if cfg!(target_os = "macos") {
+        "net.inet.ip.forwarding"
+    } else {
+        unimplemented!()
+    };
+    Sysctl::set(name, "1")
+}
+
 #[test]
 fn enable_ipv4_forwarding_test() {
     enable_ipv4_forwarding().unwrap();
@@ -44,130 +119,759 @@ pub enum RouteType {
     Host,
 }
 
+/// Changes made by `DefaultGateway::create()` so far, in order.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum GatewayStep {
+    Nothing,
+    HostRoute,
+    DefaultRemoved,
+    DefaultRoute,
+    HostRoute6,
+    DefaultRoute6,
+}
+
 pub struct DefaultGateway {
     origin: String,
+    origin6: Option<Gateway6>,
     remote: String,
+    done: GatewayStep,
 }
 
 impl DefaultGateway {
-    pub fn create(gateway: &str, remote: &str) -> DefaultGateway {
-        let origin = get_default_gateway().unwrap();
-        add_route(RouteType::Host, remote, &origin).unwrap();
-        delete_default_gateway().unwrap();
-        set_default_gateway(gateway).unwrap();
-        DefaultGateway {
-            origin: origin,
+    /// Sends all traffic to the server's address in `subnet` except for the
+    /// tunnel itself, which keeps using the current gateway to reach
+    /// `remote`. On dual-stack hosts the IPv6 default route is moved into the
+    /// tunnel as well. If a step fails, the steps before it are undone.
+    pub fn create(subnet: &device::Subnet, remote: &str) -> Result<DefaultGateway> {
+        let remote6 = remote.parse::<Ipv6Addr>().ok();
+        let mut guard = DefaultGateway {
+            origin: try!(get_default_gateway()),
+            origin6: try!(get_default_gateway6()),
             remote: String::from(remote),
+            done: GatewayStep::Nothing,
+        };
+        if remote6.is_none() {
+            try!(add_route(RouteType::Host, remote, &guard.origin));
+            guard.done = GatewayStep::HostRoute;
+        }
+        if cfg!(any(target_os = "macos", windows)) {
+            try!(delete_default_gateway());
+            guard.done = GatewayStep::DefaultRemoved;
+        }
+        try!(set_default_gateway(&subnet.addr(1).to_string()));
+        guard.done = GatewayStep::DefaultRoute;
+        if let Some(origin6) = guard.origin6.clone() {
+            if let Some(remote6) = remote6 {
+                try!(add_host_route6(remote6, &origin6));
+                guard.done = GatewayStep::HostRoute6;
+            }
+            try!(set_default_gateway6(&tunnel_gateway6(subnet)));
+            guard.done = GatewayStep::DefaultRoute6;
+        }
+        Ok(guard)
+    }
+
+    /// Sets the MTU of the default routes into the tunnel, and the TCP MSS
+    /// that goes with it, so that local applications send segments that fit
+    /// from the start.
+    #[cfg(target_os = "linux")]
+    pub fn set_mtu(&self, subnet: &device::Subnet, mtu: u16) -> Result<()> {
+        let mut netlink = try!(Netlink::open());
+        let mut route = Route::default(IpAddr::V4(subnet.addr(1)));
+        route.mtu = Some(mtu);
+        try!(netlink.replace_route(&route));
+        if self.done >= GatewayStep::DefaultRoute6 {
+            let mut route = tunnel_gateway6(subnet);
+            route.mtu = Some(mtu);
+            try!(netlink.replace_route(&route));
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_mtu(&self, subnet: &device::Subnet, mtu: u16) -> Result<()> {
+        let mtu = mtu.to_string();
+        try!(route6(&["change", "default", &subnet.addr(1).to_string(), "-mtu", &mtu]));
+        if self.done >= GatewayStep::DefaultRoute6 {
+            try!(route6(&["change", "-inet6", "default", &tunnel_gateway6(subnet), "-mtu", &mtu]));
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    pub fn set_mtu(&self, _: &device::Subnet, _: u16) -> Result<()> {
+        Err(Error::Route(String::from("route MTUs need Linux or macOS")))
+    }
+
+    /// Adds the host route to the server again, for when something on the
+    /// host removed it and the tunnel's own traffic loops into the tunnel.
+    pub fn repair(&self) -> Result<()> {
+        match (self.remote.parse::<Ipv6Addr>(), self.origin6.as_ref()) {
+            (Ok(remote6), Some(origin6)) => add_host_route6(remote6, origin6),
+            (Ok(_), None) => Ok(()),
+            (Err(_), _) => add_route(RouteType::Host, &self.remote, &self.origin),
         }
     }
 }
 
 impl Drop for DefaultGateway {
     fn drop(&mut self) {
-        delete_default_gateway().unwrap();
-        set_default_gateway(&self.origin).unwrap();
-        delete_route(RouteType::Host, &self.remote).unwrap();
+        let remote6 = self.remote.parse::<Ipv6Addr>().ok();
+        if self.done >= GatewayStep::DefaultRoute6 {
+            if let Some(ref origin6) = self.origin6 {
+                if let Err(e) = set_default_gateway6(origin6) {
+                    error!("Failed to restore IPv6 default gateway {:?}: {}", origin6, e);
+                }
+            }
+        }
+        if self.done >= GatewayStep::HostRoute6 {
+            if let Some(remote6) = remote6 {
+                if let Err(e) = delete_host_route6(remote6) {
+                    error!("Failed to remove host route to {}: {}", remote6, e);
+                }
+            }
+        }
+        if self.done >= GatewayStep::DefaultRoute && cfg!(any(target_os = "macos", windows)) {
+            if let Err(e) = delete_default_gateway() {
+                error!("Failed to remove the tunnel default gateway: {}", e);
+            }
+        }
+        if self.done >= GatewayStep::DefaultRemoved {
+            if let Err(e) = set_default_gateway(&self.origin) {
+                error!("Failed to restore default gateway {}: {}", self.origin, e);
+            }
+        }
+        if self.done >= GatewayStep::HostRoute && remote6.is_none() {
+            if let Err(e) = delete_route(RouteType::Host, &self.remote) {
+                error!("Failed to remove host route to {}: {}", self.remote, e);
+            }
+        }
     }
 }
 
-pub fn delete_route(route_type: RouteType, route: &str) -> Result<(), String> {
+#[cfg(target_os = "macos")]
+pub fn delete_route(route_type: RouteType, route: &str) -> Result<()> {
     let mode = match route_type {
         RouteType::Net => "-net",
         RouteType::Host => "-host",
     };
-    let status = if cfg!(target_os = "linux") {
-        Command::new("route")
-            .arg("-n")
-            .arg("del")
-            .arg(mode)
-            .arg(route)
-            .status()
-            .unwrap()
-    } else if cfg!(target_os = "macos") {
-        Command::new("route")
-            .arg("-n")
-            .arg("delete")
-            .arg(mode)
-            .arg(route)
-            .status()
-            .unwrap()
-    } else {
-        unimplemented!()
-    };
+    let status = try!(Command::new("route")
+        .arg("-n")
+        .arg("delete")
+        .arg(mode)
+        .arg(route)
+        .status());
     if status.success() {
         Ok(())
     } else {
-        Err(format!("route: {}", status))
+        Err(Error::Route(format!("route: {}", status)))
     }
 }
 
-pub fn add_route(route_type: RouteType, route: &str, gateway: &str) -> Result<(), String> {
+#[cfg(target_os = "macos")]
+pub fn add_route(route_type: RouteType, route: &str, gateway: &str) -> Result<()> {
     let mode = match route_type {
         RouteType::Net => "-net",
         RouteType::Host => "-host",
     };
-    let status = if cfg!(target_os = "linux") {
-        Command::new("route")
-            .arg("-n")
-            .arg("add")
-            .arg(mode)
-            .arg(route)
-            .arg("gw")
-            .arg(gateway)
-            .status()
-            .unwrap()
-    } else if cfg!(target_os = "macos") {
-        Command::new("route")
-            .arg("-n")
-            .arg("add")
-            .arg(mode)
-            .arg(route)
-            .arg(gateway)
-            .status()
-            .unwrap()
+    let status = try!(Command::new("route")
+        .arg("-n")
+        .arg("add")
+        .arg(mode)
+        .arg(route)
+        .arg(gateway)
+        .status());
+    if status.success() {
+        Ok(())
     } else {
-        unimplemented!()
+        Err(Error::Route(format!("route: {}", status)))
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn set_default_gateway(gateway: &str) -> Result<()> {
+    add_route(RouteType::Net, "default", gateway)
+}
+
+#[cfg(target_os = "macos")]
+pub fn delete_default_gateway() -> Result<()> {
+    delete_route(RouteType::Net, "default")
+}
+
+/// The gateway of the default route of `family`, "-inet" or "-inet6", as
+/// `route -n get` reports it. `None` if the default route has no gateway,
+/// as on point-to-point links, or there is no default route.
+#[cfg(target_os = "macos")]
+fn route_get_gateway(family: &str) -> Result<Option<String>> {
+    let output = try!(Command::new("route").args(&["-n", "get", family, "default"]).output());
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with("gateway:"))
+        .map(|line| line["gateway:".len()..].trim().to_owned())
+        .next())
+}
+
+#[cfg(target_os = "macos")]
+pub fn get_default_gateway() -> Result<String> {
+    match try!(route_get_gateway("-inet")) {
+        Some(gateway) => Ok(gateway),
+        None => Err(Error::Route(String::from("no IPv4 default gateway"))),
+    }
+}
+
+/// Splits a `route`-style destination into an address and prefix length.
+#[cfg(any(target_os = "linux", windows))]
+fn parse_destination(route_type: RouteType, route: &str) -> Result<(IpAddr, u8)> {
+    let invalid = || Error::Route(format!("invalid destination {:?}", route));
+    if route == "default" {
+        return Ok((IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+    }
+    let mut parts = route.splitn(2, '/');
+    let addr: IpAddr = try!(parts.next().unwrap().parse().map_err(|_| invalid()));
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match (parts.next(), route_type) {
+        (Some(prefix), _) => try!(prefix.parse().map_err(|_| invalid())),
+        (None, RouteType::Host) => max,
+        (None, RouteType::Net) => return Err(invalid()),
     };
+    if prefix > max {
+        return Err(invalid());
+    }
+    Ok((addr, prefix))
+}
+
+#[cfg(target_os = "linux")]
+fn parse_gateway(gateway: &str) -> Result<IpAddr> {
+    gateway.parse().map_err(|_| Error::Route(format!("invalid gateway {:?}", gateway)))
+}
+
+#[cfg(target_os = "linux")]
+pub fn delete_route(route_type: RouteType, route: &str) -> Result<()> {
+    let (dst, prefix) = try!(parse_destination(route_type, route));
+    try!(Netlink::open()).delete_route(&Route {
+        dst: dst,
+        prefix: prefix,
+        gateway: None,
+        oif: None,
+        mtu: None,
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub fn add_route(route_type: RouteType, route: &str, gateway: &str) -> Result<()> {
+    let (dst, prefix) = try!(parse_destination(route_type, route));
+    try!(Netlink::open()).add_route(&Route {
+        dst: dst,
+        prefix: prefix,
+        gateway: Some(try!(parse_gateway(gateway))),
+        oif: None,
+        mtu: None,
+    })
+}
+
+/// Points the default route at `gateway`, replacing any existing default
+/// route in one step.
+#[cfg(target_os = "linux")]
+pub fn set_default_gateway(gateway: &str) -> Result<()> {
+    let gateway = try!(parse_gateway(gateway));
+    try!(Netlink::open()).replace_route(&Route::default(gateway))
+}
+
+#[cfg(target_os = "linux")]
+pub fn delete_default_gateway() -> Result<()> {
+    delete_route(RouteType::Net, "default")
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_default_gateway() -> Result<String> {
+    match try!(try!(Netlink::open()).default_route(false)).and_then(|route| route.gateway) {
+        Some(gateway) => Ok(gateway.to_string()),
+        None => Err(Error::Route(String::from("no IPv4 default gateway"))),
+    }
+}
+
+/// The IPv6 default route, kept whole so that link-local gateways keep their
+/// interface.
+#[cfg(target_os = "linux")]
+pub type Gateway6 = Route;
+
+#[cfg(target_os = "linux")]
+pub fn get_default_gateway6() -> Result<Option<Gateway6>> {
+    try!(Netlink::open()).default_route(true)
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_default_gateway6(gateway: &Gateway6) -> Result<()> {
+    try!(Netlink::open()).replace_route(gateway)
+}
+
+#[cfg(target_os = "linux")]
+fn tunnel_gateway6(subnet: &device::Subnet) -> Gateway6 {
+    Route::default(IpAddr::V6(subnet.ipv6_addr(1)))
+}
+
+#[cfg(target_os = "linux")]
+pub fn add_host_route6(remote: Ipv6Addr, via: &Gateway6) -> Result<()> {
+    try!(Netlink::open()).add_route(&Route::host(IpAddr::V6(remote), via))
+}
+
+#[cfg(target_os = "linux")]
+pub fn delete_host_route6(remote: Ipv6Addr) -> Result<()> {
+    delete_route(RouteType::Host, &remote.to_string())
+}
+
+/// The IPv6 default gateway as printed by `route`, including any `%iface`
+/// scope.
+#[cfg(target_os = "macos")]
+pub type Gateway6 = String;
+
+#[cfg(target_os = "macos")]
+pub fn get_default_gateway6() -> Result<Option<Gateway6>> {
+    route_get_gateway("-inet6")
+}
+
+#[cfg(target_os = "macos")]
+fn route6(args: &[&str]) -> Result<()> {
+    let status = try!(Command::new("route").arg("-n").args(args).status());
     if status.success() {
         Ok(())
     } else {
-        Err(format!("route: {}", status))
+        Err(Error::Route(format!("route: {}", status)))
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn set_default_gateway6(gateway: &Gateway6) -> Result<()> {
+    let _ = route6(&["delete", "-inet6", "default"]);
+    route6(&["add", "-inet6", "default", gateway])
+}
+
+#[cfg(target_os = "macos")]
+fn tunnel_gateway6(subnet: &device::Subnet) -> Gateway6 {
+    subnet.ipv6_addr(1).to_string()
+}
+
+#[cfg(target_os = "macos")]
+pub fn add_host_route6(remote: Ipv6Addr, via: &Gateway6) -> Result<()> {
+    route6(&["add", "-inet6", "-host", &remote.to_string(), via])
+}
+
+#[cfg(target_os = "macos")]
+pub fn delete_host_route6(remote: Ipv6Addr) -> Result<()> {
+    route6(&["delete", "-inet6", "-host", &remote.to_string()])
+}
+
+#[cfg(windows)]
+fn parse_destination4(route_type: RouteType, route: &str) -> Result<(Ipv4Addr, u8)> {
+    match try!(parse_destination(route_type, route)) {
+        (IpAddr::V4(dst), prefix) => Ok((dst, prefix)),
+        (IpAddr::V6(_), _) => Err(Error::Route(String::from("IPv6 routes need Linux or macOS"))),
     }
 }
 
-pub fn set_default_gateway(gateway: &str) -> Result<(), String> {
+#[cfg(windows)]
+pub fn delete_route(route_type: RouteType, route: &str) -> Result<()> {
+    let (dst, prefix) = try!(parse_destination4(route_type, route));
+    iphlpapi::delete_routes(dst, prefix)
+}
+
+/// Windows wants the interface and metric of every route, so they are taken
+/// from the route to `gateway`.
+#[cfg(windows)]
+pub fn add_route(route_type: RouteType, route: &str, gateway: &str) -> Result<()> {
+    let (dst, prefix) = try!(parse_destination4(route_type, route));
+    let gateway = try!(gateway.parse()
+        .map_err(|_| Error::Route(format!("invalid gateway {:?}", gateway))));
+    let via = try!(iphlpapi::best_route(gateway));
+    iphlpapi::add_route(&iphlpapi::Route {
+        dst: dst,
+        prefix: prefix,
+        gateway: gateway,
+        if_index: via.if_index,
+        metric: via.metric,
+    })
+}
+
+#[cfg(windows)]
+pub fn set_default_gateway(gateway: &str) -> Result<()> {
     add_route(RouteType::Net, "default", gateway)
 }
 
-pub fn delete_default_gateway() -> Result<(), String> {
+#[cfg(windows)]
+pub fn delete_default_gateway() -> Result<()> {
     delete_route(RouteType::Net, "default")
 }
 
-pub fn get_default_gateway() -> Result<String, String> {
-    let cmd = if cfg!(target_os = "linux") {
-        "ip -4 route list 0/0 | awk '{print $3}'"
-    } else if cfg!(target_os = "macos") {
-        "route -n get default | grep gateway | awk '{print $2}'"
+#[cfg(windows)]
+pub fn get_default_gateway() -> Result<String> {
+    let route = try!(iphlpapi::best_route(Ipv4Addr::new(0, 0, 0, 0)));
+    if route.prefix != 0 || route.gateway.is_unspecified() {
+        return Err(Error::Route(String::from("no IPv4 default gateway")));
+    }
+    Ok(route.gateway.to_string())
+}
+
+/// IPv6 routes are left alone on Windows, so IPv6 traffic bypasses the
+/// tunnel there.
+#[cfg(windows)]
+pub type Gateway6 = String;
+
+#[cfg(windows)]
+pub fn get_default_gateway6() -> Result<Option<Gateway6>> {
+    Ok(None)
+}
+
+#[cfg(windows)]
+pub fn set_default_gateway6(_: &Gateway6) -> Result<()> {
+    Err(Error::Route(String::from("IPv6 routes need Linux or macOS")))
+}
+
+#[cfg(windows)]
+fn tunnel_gateway6(subnet: &device::Subnet) -> Gateway6 {
+    subnet.ipv6_addr(1).to_string()
+}
+
+#[cfg(windows)]
+pub fn add_host_route6(_: Ipv6Addr, _: &Gateway6) -> Result<()> {
+    Err(Error::Route(String::from("IPv6 routes need Linux or macOS")))
+}
+
+#[cfg(windows)]
+pub fn delete_host_route6(_: Ipv6Addr) -> Result<()> {
+    Err(Error::Route(String::from("IPv6 routes need Linux or macOS")))
+}
+
+/// Points the system resolver at other DNS servers until dropped, by
+/// replacing those of the primary network service in the dynamic store.
+/// macOS only.
+pub struct DnsOverride {
+    key: String,
+    original: Vec<String>,
+}
+
+impl DnsOverride {
+    #[cfg(target_os = "macos")]
+    pub fn create(servers: &[IpAddr]) -> Result<DnsOverride> {
+        let global = try!(scutil("show State:/Network/Global/IPv4\n"));
+        let service = try!(primary_service(&global)
+            .ok_or_else(|| Error::Route(String::from("no primary network service"))));
+        let key = format!("State:/Network/Service/{}/DNS", service);
+        let original = server_addresses(&try!(scutil(&format!("show {}\n", key))));
+        let servers: Vec<String> = servers.iter().map(|server| server.to_string()).collect();
+        try!(set_dns_servers(&key, &servers));
+        Ok(DnsOverride {
+            key: key,
+            original: original,
+        })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn create(_: &[IpAddr]) -> Result<DnsOverride> {
+        Err(Error::Config(String::from("setting DNS servers needs macOS")))
+    }
+}
+
+impl Drop for DnsOverride {
+    fn drop(&mut self) {
+        if let Err(e) = set_dns_servers(&self.key, &self.original) {
+            error!("Failed to restore DNS servers {:?}: {}", self.original, e);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn scutil(commands: &str) -> Result<String> {
+    let mut child = try!(Command::new("scutil")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn());
+    try!(child.stdin.take().unwrap().write_all(commands.as_bytes()));
+    let output = try!(child.wait_with_output());
+    if !output.status.success() {
+        return Err(Error::Route(format!("scutil: {}", output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(target_os = "macos")]
+fn set_dns_servers(key: &str, servers: &[String]) -> Result<()> {
+    let change = if servers.is_empty() {
+        String::from("d.remove ServerAddresses")
     } else {
-        unimplemented!()
+        format!("d.add ServerAddresses * {}", servers.join(" "))
     };
-    let output = Command::new("bash")
-        .arg("-c")
-        .arg(cmd)
-        .output()
-        .unwrap();
-    if output.status.success() {
-        Ok(String::from_utf8(output.stdout).unwrap())
+    scutil(&format!("open\nd.init\nget {}\n{}\nset {}\nclose\n", key, change, key)).map(|_| ())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn set_dns_servers(_: &str, _: &[String]) -> Result<()> {
+    Ok(())
+}
+
+/// The `PrimaryService` in scutil's dump of State:/Network/Global/IPv4.
+#[cfg(target_os = "macos")]
+fn primary_service(show: &str) -> Option<String> {
+    show.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, " : ");
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.trim() == "PrimaryService" => {
+                    Some(value.trim().to_owned())
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// The entries of the `ServerAddresses` array in scutil's dump of a DNS
+/// dictionary.
+#[cfg(target_os = "macos")]
+fn server_addresses(show: &str) -> Vec<String> {
+    show.lines()
+        .skip_while(|line| !line.trim().starts_with("ServerAddresses"))
+        .skip(1)
+        .take_while(|line| !line.trim().starts_with('}'))
+        .filter_map(|line| line.splitn(2, " : ").nth(1))
+        .map(|address| address.trim().to_owned())
+        .collect()
+}
+
+/// Reads a secret, such as a pre-shared key, from `path`, without its
+/// trailing newline. Files other users can read or write are refused.
+pub fn read_secret(path: &Path) -> Result<String> {
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    let mut file = try!(fs::File::open(path));
+    if try!(file.metadata()).permissions().mode() & 0o007 != 0 {
+        return Err(Error::Config(format!("{} is accessible to other users; chmod o-rwx it",
+                                         path.display())));
+    }
+    let mut secret = String::new();
+    try!(file.read_to_string(&mut secret));
+    Ok(String::from(secret.trim_right_matches(|c| c == '\n' || c == '\r')))
+}
+
+/// Resolves a secret given as an option value: `file:PATH` is read with
+/// `read_secret()`, `keyring:ACCOUNT` is looked up in the platform keyring,
+/// and anything else is the secret itself.
+pub fn secret(value: &str) -> Result<String> {
+    if value.starts_with("file:") {
+        read_secret(Path::new(&value["file:".len()..]))
+    } else if value.starts_with("keyring:") {
+        let account = &value["keyring:".len()..];
+        try!(keyring::get(account))
+            .ok_or_else(|| Error::Config(format!("no secret for {} in the keyring", account)))
     } else {
-        Err(String::from_utf8(output.stderr).unwrap())
+        Ok(String::from(value))
     }
 }
 
+/// Renames `path` to `path.1`, shifting older files up to `path.N` and
+/// dropping the oldest. With `max_files` zero the file is just removed.
+pub fn rotate_files(path: &Path, max_files: usize) -> io::Result<()> {
+    let path = path.to_string_lossy().into_owned();
+    if max_files == 0 {
+        return fs::remove_file(&path);
+    }
+    for i in (1..max_files).rev() {
+        let from = format!("{}.{}", path, i);
+        if fs::metadata(&from).is_ok() {
+            try!(fs::rename(&from, format!("{}.{}", path, i + 1)));
+        }
+    }
+    fs::rename(&path, format!("{}.1", path))
+}
+
+/// A route that sends a network into an interface, such as a network behind
+/// a client sent into the TUN device. Removed on drop.
+pub struct InterfaceRoute {
+    net: String,
+}
+
+impl InterfaceRoute {
+    #[cfg(target_os = "linux")]
+    pub fn create(net: &str, interface: &str) -> Result<InterfaceRoute> {
+        try!(try!(Netlink::open()).add_route(&try!(interface_route(net, interface))));
+        Ok(InterfaceRoute { net: String::from(net) })
+    }
+
+    /// Sets the MTU of the route, and the TCP MSS that goes with it.
+    #[cfg(target_os = "linux")]
+    pub fn set_mtu(&self, interface: &str, mtu: u16) -> Result<()> {
+        let mut route = try!(interface_route(&self.net, interface));
+        route.mtu = Some(mtu);
+        try!(Netlink::open()).replace_route(&route)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn create(net: &str, interface: &str) -> Result<InterfaceRoute> {
+        let status = try!(Command::new("route")
+            .args(&["-n", "add", "-net", net, "-interface", interface])
+            .status());
+        if !status.success() {
+            return Err(Error::Route(format!("route: {}", status)));
+        }
+        Ok(InterfaceRoute { net: String::from(net) })
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_mtu(&self, interface: &str, mtu: u16) -> Result<()> {
+        route6(&["change", "-net", &self.net, "-interface", interface, "-mtu", &mtu.to_string()])
+    }
+
+    /// `interface` is the adapter's alias, as `netsh interface show
+    /// interface` lists it.
+    #[cfg(windows)]
+    pub fn create(net: &str, interface: &str) -> Result<InterfaceRoute> {
+        let (dst, prefix) = try!(parse_destination4(RouteType::Net, net));
+        let index = try!(iphlpapi::interface_index(interface));
+        try!(iphlpapi::add_route(&iphlpapi::Route {
+            dst: dst,
+            prefix: prefix,
+            gateway: Ipv4Addr::new(0, 0, 0, 0),
+            if_index: index,
+            metric: try!(iphlpapi::interface_metric(index)),
+        }));
+        Ok(InterfaceRoute { net: String::from(net) })
+    }
+
+    #[cfg(windows)]
+    pub fn set_mtu(&self, _: &str, _: u16) -> Result<()> {
+        Err(Error::Route(String::from("route MTUs need Linux or macOS")))
+    }
+}
+
+/// The route sending `net` into `interface`.
+#[cfg(target_os = "linux")]
+fn interface_route(net: &str, interface: &str) -> Result<Route> {
+    let (dst, prefix) = try!(parse_destination(RouteType::Net, net));
+    Ok(Route {
+        dst: dst,
+        prefix: prefix,
+        gateway: None,
+        oif: Some(try!(netlink::interface_index(interface))),
+        mtu: None,
+    })
+}
+
+/// Sets the MTU of `interface` while it is up.
+#[cfg(target_os = "linux")]
+pub fn set_interface_mtu(interface: &str, mtu: u16) -> Result<()> {
+    let index = try!(netlink::interface_index(interface));
+    try!(Netlink::open()).set_mtu(index, mtu)
+}
+
+/// Adds an address to `interface`, next to those it has.
+#[cfg(target_os = "linux")]
+pub fn add_interface_address(interface: &str, addr: IpAddr, prefix: u8) -> Result<()> {
+    let index = try!(netlink::interface_index(interface));
+    try!(Netlink::open()).add_address(index, &addr, prefix)
+}
+
+#[cfg(target_os = "linux")]
+pub fn delete_interface_address(interface: &str, addr: IpAddr, prefix: u8) -> Result<()> {
+    let index = try!(netlink::interface_index(interface));
+    try!(Netlink::open()).delete_address(index, &addr, prefix)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_interface_mtu(_: &str, _: u16) -> Result<()> {
+    Err(Error::Route(String::from("changing an interface at runtime needs Linux")))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn add_interface_address(_: &str, _: IpAddr, _: u8) -> Result<()> {
+    Err(Error::Route(String::from("changing an interface at runtime needs Linux")))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn delete_interface_address(_: &str, _: IpAddr, _: u8) -> Result<()> {
+    Err(Error::Route(String::from("changing an interface at runtime needs Linux")))
+}
+
+impl Drop for InterfaceRoute {
+    fn drop(&mut self) {
+        if let Err(e) = delete_route(RouteType::Net, &self.net) {
+            error!("Failed to remove route to {}: {}", self.net, e);
+        }
+    }
+}
+
+/// Detaches from the terminal: the parent exits and the child carries on in a
+/// new session with its standard streams on /dev/null. Call before starting
+/// any threads.
+pub fn daemonize() -> Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => return Err(Error::from(io::Error::last_os_error())),
+        0 => {}
+        pid => {
+            println!("kytan is running in the background (pid {}).", pid);
+            unsafe { libc::_exit(0) };
+        }
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(Error::from(io::Error::last_os_error()));
+    }
+    try!(env::set_current_dir("/"));
+    let null = try!(OpenOptions::new().read(true).write(true).open("/dev/null"));
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn read_secret_test() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::io::Write;
+    let path = env::temp_dir().join("kytan-read-secret-test");
+    fs::File::create(&path).unwrap().write_all(b"s3cret\n").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(read_secret(&path).is_err());
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    assert_eq!(read_secret(&path).unwrap(), "s3cret");
+    assert_eq!(secret(&format!("file:{}", path.display())).unwrap(), "s3cret");
+    assert_eq!(secret("s3cret").unwrap(), "s3cret");
+    fs::remove_file(&path).unwrap();
+}
+
 #[test]
 fn get_default_gateway_test() {
     get_default_gateway().unwrap();
 }
 
+#[cfg(target_os = "linux")]
+#[test]
+fn parse_destination_test() {
+    assert_eq!(parse_destination(RouteType::Host, "1.1.1.1").unwrap(),
+               (IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 32));
+    assert_eq!(parse_destination(RouteType::Net, "10.0.0.0/8").unwrap(),
+               (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8));
+    assert_eq!(parse_destination(RouteType::Net, "default").unwrap(),
+               (IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+    assert_eq!(parse_destination(RouteType::Host, "2001:db8::1").unwrap().1, 128);
+    assert!(parse_destination(RouteType::Net, "10.0.0.0/33").is_err());
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn scutil_parse_test() {
+    assert_eq!(primary_service("<dictionary> {\n  PrimaryInterface : en0\n  \
+                                PrimaryService : 8A0B9F3C-1D\n}\n"),
+               Some(String::from("8A0B9F3C-1D")));
+    let dns = "<dictionary> {\n  DomainName : lan\n  ServerAddresses : <array> {\n    \
+               0 : 192.168.1.1\n    1 : fd00::1\n  }\n}\n";
+    assert_eq!(server_addresses(dns), vec!["192.168.1.1", "fd00::1"]);
+    assert!(server_addresses("No such key\n").is_empty());
+}
+
 #[test]
 fn route_test() {
     let gw = get_default_gateway().unwrap();
@@ -0,0 +1,181 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changing how much is logged while running.
+//!
+//! `init()` wraps the logger configured from `RUST_LOG`. The control socket
+//! (`log LEVEL|default|packets on|off`) and SIGUSR2 can then replace its
+//! filter with a plain level, and switch the per-packet trace of
+//! `trace_packet!` on and off, without restarting the tunnel. SIGUSR2 steps
+//! from the default to debug, then to trace with the packet trace, and back.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+use error::{Error, Result};
+
+const PACKET_TRACE: &'static str = "kytan::packet_trace";
+const FILTERS: [LogLevelFilter; 6] = [LogLevelFilter::Off,
+                                      LogLevelFilter::Error,
+                                      LogLevelFilter::Warn,
+                                      LogLevelFilter::Info,
+                                      LogLevelFilter::Debug,
+                                      LogLevelFilter::Trace];
+
+// The wrapped logger's own filter, and the level replacing it plus one, or
+// 0 while it applies.
+static DEFAULT: AtomicUsize = ATOMIC_USIZE_INIT;
+static OVERRIDE: AtomicUsize = ATOMIC_USIZE_INIT;
+static PACKETS: AtomicBool = ATOMIC_BOOL_INIT;
+// Set once by `init()`, before there are other threads; only read later.
+static mut MAX_LEVEL: Option<MaxLogLevelFilter> = None;
+
+struct Logger<L> {
+    inner: L,
+}
+
+impl<L: Log> Log for Logger<L> {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        if metadata.target() == PACKET_TRACE && PACKETS.load(Ordering::Relaxed) {
+            return true;
+        }
+        match OVERRIDE.load(Ordering::Relaxed) {
+            0 => self.inner.enabled(metadata),
+            n => metadata.level() <= FILTERS[n - 1],
+        }
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        } else {
+            // What the wrapped logger filters out, in env_logger's format.
+            let _ = writeln!(io::stderr(),
+                             "{}:{}: {}",
+                             record.level(),
+                             record.location().module_path(),
+                             record.args());
+        }
+    }
+}
+
+/// Installs `inner`, whose own filter lets through at most `filter`, as
+/// the logger. Call once, before starting any threads.
+pub fn init<L: Log + 'static>(inner: L, filter: LogLevelFilter) -> Result<()> {
+    DEFAULT.store(filter as usize, Ordering::Relaxed);
+    log::set_logger(|max| {
+            max.set(filter);
+            unsafe {
+                MAX_LEVEL = Some(max);
+            }
+            Box::new(Logger { inner: inner })
+        })
+        .map_err(|e| Error::Config(format!("logger: {}", e)))
+}
+
+/// Logs at most `level` from now on, or goes back to the filter of
+/// `RUST_LOG` with `None`.
+pub fn set_level(level: Option<LogLevelFilter>) {
+    OVERRIDE.store(level.map_or(0, |level| level as usize + 1), Ordering::Relaxed);
+    update();
+}
+
+/// Switches the per-packet trace on or off. Only builds with the
+/// `packet-trace` feature have one.
+pub fn set_packet_trace(on: bool) {
+    PACKETS.store(on, Ordering::Relaxed);
+    update();
+}
+
+/// Steps from the default to debug, to trace with the packet trace, and
+/// back to the default. Only touches atomics, so signal handlers may call
+/// it.
+pub fn cycle() {
+    match OVERRIDE.load(Ordering::Relaxed) {
+        0 => set_level(Some(LogLevelFilter::Debug)),
+        n if FILTERS[n - 1] < LogLevelFilter::Trace => {
+            PACKETS.store(true, Ordering::Relaxed);
+            set_level(Some(LogLevelFilter::Trace));
+        }
+        _ => {
+            PACKETS.store(false, Ordering::Relaxed);
+            set_level(None);
+        }
+    }
+}
+
+fn level() -> LogLevelFilter {
+    match OVERRIDE.load(Ordering::Relaxed) {
+        0 => FILTERS[DEFAULT.load(Ordering::Relaxed)],
+        n => FILTERS[n - 1],
+    }
+}
+
+fn update() {
+    let max = if PACKETS.load(Ordering::Relaxed) {
+        LogLevelFilter::Trace
+    } else {
+        level()
+    };
+    unsafe {
+        if let Some(ref filter) = MAX_LEVEL {
+            filter.set(max);
+        }
+    }
+}
+
+/// Carries out a `log` control socket command and describes the outcome.
+pub fn control(command: &str) -> String {
+    let mut words = command.split_whitespace().skip(1);
+    match (words.next(), words.next(), words.next()) {
+        (None, None, None) => {}
+        (Some("default"), None, None) => set_level(None),
+        (Some("packets"), Some("on"), None) => set_packet_trace(true),
+        (Some("packets"), Some("off"), None) => set_packet_trace(false),
+        (Some(level), None, None) => {
+            match level.parse() {
+                Ok(level) => set_level(Some(level)),
+                Err(_) => return format!("Unknown log level {}.\n", level),
+            }
+        }
+        _ => return String::from("Usage: log [LEVEL|default|packets on|packets off]\n"),
+    }
+    let packets = match (PACKETS.load(Ordering::Relaxed), cfg!(feature = "packet-trace")) {
+        (_, false) => "not built in",
+        (true, true) => "on",
+        (false, true) => "off",
+    };
+    format!("Log level: {} (default: {}). Packet trace: {}.\n",
+            level().to_string().to_lowercase(),
+            FILTERS[DEFAULT.load(Ordering::Relaxed)].to_string().to_lowercase(),
+            packets)
+}
+
+#[test]
+fn control_test() {
+    DEFAULT.store(LogLevelFilter::Info as usize, Ordering::Relaxed);
+    assert!(control("log").starts_with("Log level: info (default: info)."));
+    assert!(control("log debug").starts_with("Log level: debug (default: info)."));
+    assert_eq!(control("log loud"), "Unknown log level loud.\n");
+    cycle();
+    assert!(PACKETS.load(Ordering::Relaxed));
+    assert_eq!(level(), LogLevelFilter::Trace);
+    cycle();
+    assert!(!PACKETS.load(Ordering::Relaxed));
+    assert!(control("log").starts_with("Log level: info (default: info)."));
+    assert!(control("log packets maybe").starts_with("Usage:"));
+}
@@ -0,0 +1,238 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compression and decompression of data frames off the event loop.
+//!
+//! With several workers, a busy client no longer caps the server at one
+//! core. Every data frame of a client goes through the same worker, in turn
+//! with frames that need no work, so its packets stay in order. The loop
+//! still does everything else: shaping and ACLs before a frame is queued,
+//! and sending or writing to the TUN device once it is done.
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::thread;
+use mio;
+use packet;
+use network::{self, Capabilities, Encoder, Id, Message, Token};
+use error::{Error, Result};
+
+pub enum Job {
+    /// Wraps a packet from the TUN device for client `id`, compressed as
+    /// agreed unless it is shorter than `threshold`.
+    Encode {
+        id: Id,
+        token: Token,
+        caps: Capabilities,
+        threshold: usize,
+        addr: SocketAddr,
+        packet: Vec<u8>,
+    },
+    /// Serializes a frame built on the loop, such as a batch.
+    Frame {
+        id: Id,
+        addr: SocketAddr,
+        msg: Message,
+    },
+    /// Unwraps a data frame from client `id`.
+    Decode {
+        id: Id,
+        token: Token,
+        compressed: bool,
+        data: Vec<u8>,
+    },
+}
+
+impl Job {
+    fn id(&self) -> Id {
+        match *self {
+            Job::Encode { id, .. } |
+            Job::Frame { id, .. } |
+            Job::Decode { id, .. } => id,
+        }
+    }
+}
+
+pub enum Done {
+    /// A frame ready to send. `compressed` is `None` for a `Frame` job, and
+    /// `dscp` carries the bits of the packet inside.
+    Encoded {
+        id: Id,
+        addr: SocketAddr,
+        compressed: Option<bool>,
+        dscp: Option<u8>,
+        frame: Vec<u8>,
+    },
+    /// A packet from client `id`, and the length of the frame it came in.
+    Decoded {
+        id: Id,
+        token: Token,
+        len: usize,
+        packet: Vec<u8>,
+    },
+    Failed { id: Id, error: Error },
+}
+
+pub struct Pool {
+    jobs: Vec<SyncSender<Job>>,
+    done: Receiver<Done>,
+    readiness: mio::SetReadiness,
+    _registration: mio::Registration,
+}
+
+fn process(encoder: &mut Encoder, job: Job) -> Done {
+    match job {
+        Job::Encode { id, token, caps, threshold, addr, packet } => {
+            let encoded = network::data_message(encoder, id, token, &caps, threshold, &packet)
+                .and_then(|(msg, compressed)| {
+                    network::encode_message(&msg).map(|frame| (frame, compressed))
+                });
+            match encoded {
+                Ok((frame, compressed)) => {
+                    Done::Encoded {
+                        id: id,
+                        addr: addr,
+                        compressed: Some(compressed),
+                        dscp: packet::dscp(&packet),
+                        frame: frame,
+                    }
+                }
+                Err(e) => Done::Failed { id: id, error: e },
+            }
+        }
+        Job::Frame { id, addr, msg } => {
+            match network::encode_message(&msg) {
+                Ok(frame) => {
+                    Done::Encoded {
+                        id: id,
+                        addr: addr,
+                        compressed: None,
+                        dscp: None,
+                        frame: frame,
+                    }
+                }
+                Err(e) => Done::Failed { id: id, error: e },
+            }
+        }
+        Job::Decode { id, token, compressed, data } => {
+            let len = data.len();
+            let packet = if compressed {
+                network::decompress(&data)
+            } else {
+                Ok(data)
+            };
+            match packet {
+                Ok(packet) => {
+                    Done::Decoded {
+                        id: id,
+                        token: token,
+                        len: len,
+                        packet: packet,
+                    }
+                }
+                Err(e) => Done::Failed { id: id, error: e },
+            }
+        }
+    }
+}
+
+impl Pool {
+    /// Starts `threads` workers, each taking up to `queue` jobs. `poll`
+    /// becomes readable on `token` when results are waiting.
+    pub fn spawn(poll: &mio::Poll,
+                 token: mio::Token,
+                 threads: usize,
+                 queue: usize)
+                 -> Result<Pool> {
+        let (registration, readiness) = mio::Registration::new2();
+        try!(poll.register(&registration, token, mio::Ready::readable(), mio::PollOpt::level()));
+        let (results, done): (Sender<Done>, Receiver<Done>) = mpsc::channel();
+        let mut jobs = Vec::new();
+        for i in 0..threads {
+            let (sender, queue) = mpsc::sync_channel::<Job>(queue);
+            let results = results.clone();
+            let wakeup = readiness.clone();
+            try!(thread::Builder::new()
+                .name(format!("kytan-worker-{}", i))
+                .spawn(move || {
+                    let mut encoder = Encoder::new();
+                    for job in queue {
+                        if results.send(process(&mut encoder, job)).is_err() {
+                            break;
+                        }
+                        let _ = wakeup.set_readiness(mio::Ready::readable());
+                    }
+                }));
+            jobs.push(sender);
+        }
+        info!("Started {} packet workers.", threads);
+        Ok(Pool {
+            jobs: jobs,
+            done: done,
+            readiness: readiness,
+            _registration: registration,
+        })
+    }
+
+    /// Queues a job with the worker of its client. False if that worker's
+    /// queue is full.
+    pub fn submit(&self, job: Job) -> bool {
+        let worker = job.id() as usize % self.jobs.len();
+        match self.jobs[worker].try_send(job) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) |
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Results finished since the last call, in order for each client.
+    pub fn done(&self) -> Vec<Done> {
+        // Clear first: a result arriving meanwhile sets it again.
+        let _ = self.readiness.set_readiness(mio::Ready::empty());
+        self.done.try_iter().collect()
+    }
+}
+
+#[test]
+fn process_test() {
+    let mut encoder = Encoder::new();
+    let addr = "192.0.2.1:4000".parse().unwrap();
+    let packet = vec![0x45, 0xb8, 0, 20];
+    let frame = match process(&mut encoder,
+                              Job::Encode {
+                                  id: 2,
+                                  token: 7,
+                                  caps: Capabilities::new(0),
+                                  threshold: 0,
+                                  addr: addr,
+                                  packet: packet.clone(),
+                              }) {
+        Done::Encoded { id: 2, compressed: Some(false), dscp: Some(46), frame, .. } => frame,
+        _ => panic!("packet not encoded"),
+    };
+    let data = match network::decode_message(&frame).unwrap() {
+        Message::Data { id: 2, token: 7, data } => data,
+        msg => panic!("unexpected {:?}", msg),
+    };
+    match process(&mut encoder,
+                  Job::Decode {
+                      id: 2,
+                      token: 7,
+                      compressed: false,
+                      data: data,
+                  }) {
+        Done::Decoded { id: 2, token: 7, len: 4, packet: decoded } => assert_eq!(decoded, packet),
+        _ => panic!("frame not decoded"),
+    }
+}
@@ -0,0 +1,661 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protocol conformance suite. `TestPeer` is a minimal client written as pure
+//! functions from server messages to replies. It runs against a scripted
+//! server through the in-memory `Memory` transport, and against a relay-only
+//! `Server` on loopback, which needs neither root nor a TUN device:
+//!
+//! ```text
+//! $ cargo test --test conformance
+//! ```
+
+extern crate kytan;
+
+use kytan::clock::ManualClock;
+use kytan::{Capabilities, Code, Id, Message, Offer, Roamer, Server, ShutdownHandle, Token,
+            CAP_KEEPALIVE};
+use kytan::{decode_message, encode_message};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a reply the server owes.
+const REPLY_TIMEOUT: u64 = 2000;
+/// How long to listen before concluding that the server stays silent.
+const SILENCE: u64 = 300;
+/// Mirrors the server's idle timeout and round-trip probe interval.
+const IDLE_TIMEOUT: u64 = 60;
+const RTT_INTERVAL: u64 = 30;
+/// Longer than any other scenario winds the clock, so only one runs into it.
+const SESSION_LIFETIME: u64 = 600;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    Idle,
+    Connected(Id, Token),
+    Expired(Id, Token),
+    Refused,
+}
+
+/// The client side of the protocol, without any I/O.
+struct TestPeer {
+    caps: Capabilities,
+    state: State,
+    offer: Offer,
+    roamer: Option<Roamer>,
+}
+
+impl TestPeer {
+    fn new(flags: u32) -> TestPeer {
+        TestPeer {
+            caps: Capabilities::new(flags),
+            state: State::Idle,
+            offer: Offer::new(),
+            roamer: None,
+        }
+    }
+
+    /// A handshake request, resuming the last session if there was one.
+    fn request(&self) -> Message {
+        let resume = match self.state {
+            State::Connected(id, token) |
+            State::Expired(id, token) => Some((id, token)),
+            State::Idle | State::Refused => None,
+        };
+        Message::Request {
+            caps: self.caps,
+            resume: resume,
+            credential: None,
+            roam_key: self.offer.public(),
+        }
+    }
+
+    fn session(&self) -> (Id, Token) {
+        match self.state {
+            State::Connected(id, token) => (id, token),
+            state => panic!("no session in state {:?}", state),
+        }
+    }
+
+    fn ping(&self) -> Message {
+        let (id, token) = self.session();
+        Message::Ping {
+            id: id,
+            token: token,
+        }
+    }
+
+    fn roam(&mut self) -> Message {
+        let (id, token) = self.session();
+        let (sequence, mac) = self.roamer.as_mut().expect("no roaming key").roam(id, token);
+        Message::Roam {
+            id: id,
+            token: token,
+            sequence: sequence,
+            mac: mac,
+        }
+    }
+
+    /// Applies a message from the server and returns the reply it calls for.
+    fn receive(&mut self, msg: Message) -> Option<Message> {
+        match (self.state, msg) {
+            (_, Message::Response { id, token, roam_key, .. }) |
+            (_, Message::Assigned { id, token, roam_key, .. }) |
+            (_, Message::Configured { id, token, roam_key, .. }) => {
+                self.state = State::Connected(id, token);
+                self.roamer = self.offer.accept(&roam_key);
+                None
+            }
+            (State::Connected(..), Message::Refused { .. }) => None,
+            (_, Message::Refused { .. }) => {
+                self.state = State::Refused;
+                None
+            }
+            (State::Connected(own, current), Message::Ping { id, token }) => {
+                if (id, token) == (own, current) {
+                    Some(Message::Pong {
+                        id: id,
+                        token: token,
+                    })
+                } else {
+                    None
+                }
+            }
+            (State::Connected(own, current), Message::Expired { id, token }) => {
+                if (id, token) == (own, current) {
+                    self.state = State::Expired(id, token);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+trait Transport {
+    fn send(&mut self, msg: &Message);
+    /// The next message to arrive within `timeout`.
+    fn recv(&mut self, timeout: Duration) -> Option<Message>;
+}
+
+type Queue = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+/// One end of an in-memory link. Messages are encoded on the way in and
+/// decoded on the way out, so the wire format is exercised too.
+struct Memory {
+    tx: Queue,
+    rx: Queue,
+}
+
+impl Memory {
+    fn pair() -> (Memory, Memory) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (Memory {
+             tx: a.clone(),
+             rx: b.clone(),
+         },
+         Memory { tx: b, rx: a })
+    }
+}
+
+impl Transport for Memory {
+    fn send(&mut self, msg: &Message) {
+        self.tx.borrow_mut().push_back(encode_message(msg).unwrap());
+    }
+
+    fn recv(&mut self, _: Duration) -> Option<Message> {
+        self.rx.borrow_mut().pop_front().map(|frame| decode_message(&frame).unwrap())
+    }
+}
+
+/// A UDP socket on loopback that only listens to the server.
+struct Loopback {
+    socket: UdpSocket,
+    server: SocketAddr,
+}
+
+impl Loopback {
+    fn send_raw(&self, frame: &[u8]) {
+        self.socket.send_to(frame, &self.server).unwrap();
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, msg: &Message) {
+        self.send_raw(&encode_message(msg).unwrap());
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Option<Message> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; 2048];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            self.socket.set_read_timeout(Some(deadline - now)).unwrap();
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) if addr == self.server => {
+                    return Some(decode_message(&buf[..len]).unwrap());
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return None,
+                Err(e) => panic!("recv: {}", e),
+            }
+        }
+    }
+}
+
+/// A relay-only server running on its own thread with a manual clock.
+struct Harness {
+    server: SocketAddr,
+    clock: ManualClock,
+    shutdown: ShutdownHandle,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Harness {
+    fn start() -> Harness {
+        // Reserve an ephemeral port and hand it to the server.
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let clock = ManualClock::new();
+        let server_clock = clock.clone();
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut server = Server::builder()
+                .port(port)
+                .relay_only(true)
+                .clock(server_clock)
+                .max_session_lifetime(Duration::from_secs(SESSION_LIFETIME))
+                .build()
+                .expect("failed to build relay-only server");
+            tx.send(server.shutdown_handle()).unwrap();
+            server.run().expect("server failed");
+        });
+        let shutdown = rx.recv().expect("server did not start");
+        Harness {
+            server: format!("127.0.0.1:{}", port).parse().unwrap(),
+            clock: clock,
+            shutdown: shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    fn connect(&self) -> Loopback {
+        Loopback {
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            server: self.server,
+        }
+    }
+
+    /// Moves the server's clock forward. The server only looks at it when it
+    /// wakes up, so a latency probe is sent along and its echo discarded.
+    fn advance(&self, link: &mut Loopback, secs: u64) {
+        self.clock.advance(Duration::from_secs(secs));
+        link.send(&Message::Probe { nonce: secs });
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Feeds the peer whatever arrives, answering as it asks, until a message
+/// matching `pred` shows up.
+fn wait_for<T, P>(peer: &mut TestPeer, link: &mut T, what: &str, pred: P)
+    where T: Transport,
+          P: Fn(&Message) -> bool
+{
+    let deadline = Instant::now() + Duration::from_millis(REPLY_TIMEOUT);
+    loop {
+        let now = Instant::now();
+        let remaining = if now < deadline {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        };
+        let msg = match link.recv(remaining) {
+            Some(msg) => msg,
+            None => panic!("timed out waiting for {}", what),
+        };
+        let matched = pred(&msg);
+        if let Some(reply) = peer.receive(msg) {
+            link.send(&reply);
+        }
+        if matched {
+            return;
+        }
+    }
+}
+
+fn expect_silence<T: Transport>(link: &mut T) {
+    if let Some(msg) = link.recv(Duration::from_millis(SILENCE)) {
+        panic!("unexpected {:?}", msg);
+    }
+}
+
+fn handshake<T: Transport>(peer: &mut TestPeer, link: &mut T) -> (Id, Token) {
+    link.send(&peer.request());
+    wait_for(peer, link, "handshake response", |msg| match *msg {
+        Message::Response { .. } => true,
+        _ => false,
+    });
+    peer.session()
+}
+
+fn probe_echoed(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    link.send(&Message::Probe { nonce: 42 });
+    wait_for(&mut peer,
+             &mut link,
+             "probe echo",
+             |msg| *msg == Message::Probe { nonce: 42 });
+    assert_eq!(peer.state, State::Idle);
+}
+
+fn ping_answered(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    let (id, token) = handshake(&mut peer, &mut link);
+    link.send(&peer.ping());
+    wait_for(&mut peer,
+             &mut link,
+             "pong",
+             |msg| *msg == Message::Pong { id: id, token: token });
+}
+
+fn keepalive_pinged(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(CAP_KEEPALIVE);
+    let (id, token) = handshake(&mut peer, &mut link);
+    harness.advance(&mut link, RTT_INTERVAL);
+    wait_for(&mut peer,
+             &mut link,
+             "round-trip probe",
+             |msg| *msg == Message::Ping { id: id, token: token });
+    assert_eq!(peer.state, State::Connected(id, token));
+}
+
+fn unknown_session_expired(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    link.send(&Message::Ping {
+        id: 200,
+        token: 1,
+    });
+    wait_for(&mut peer,
+             &mut link,
+             "stale session notice",
+             |msg| *msg == Message::Expired { id: 200, token: 1 });
+}
+
+fn wrong_token_rejected(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    let (id, token) = handshake(&mut peer, &mut link);
+    link.send(&Message::Ping {
+        id: id,
+        token: token ^ 1,
+    });
+    wait_for(&mut peer,
+             &mut link,
+             "stale session notice",
+             |msg| *msg == Message::Expired { id: id, token: token ^ 1 });
+    assert_eq!(peer.state, State::Connected(id, token));
+}
+
+fn roaming_accepted(harness: &Harness) {
+    let mut old = harness.connect();
+    let mut peer = TestPeer::new(0);
+    let (id, token) = handshake(&mut peer, &mut old);
+    let mut new = harness.connect();
+    let roam = peer.roam();
+    new.send(&roam);
+    new.send(&peer.ping());
+    wait_for(&mut peer,
+             &mut new,
+             "pong at the new endpoint",
+             |msg| *msg == Message::Pong { id: id, token: token });
+    // The old endpoint no longer speaks for the session.
+    old.send(&peer.ping());
+    expect_silence(&mut old);
+    // Nor can anyone who saw the roam repeat it.
+    let mut other = harness.connect();
+    other.send(&roam);
+    wait_for(&mut TestPeer::new(0),
+             &mut other,
+             "stale session notice",
+             |msg| *msg == Message::Expired { id: id, token: token });
+    new.send(&peer.ping());
+    wait_for(&mut peer,
+             &mut new,
+             "pong at the new endpoint",
+             |msg| *msg == Message::Pong { id: id, token: token });
+}
+
+fn roaming_rejected(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    let (id, token) = handshake(&mut peer, &mut link);
+    // The token travels in the clear, so it alone does not move a session.
+    let mut other = harness.connect();
+    other.send(&Message::Roam {
+        id: id,
+        token: token,
+        sequence: 1,
+        mac: vec![0; 32],
+    });
+    wait_for(&mut TestPeer::new(0),
+             &mut other,
+             "stale session notice",
+             |msg| *msg == Message::Expired { id: id, token: token });
+    link.send(&peer.ping());
+    wait_for(&mut peer,
+             &mut link,
+             "pong at the original endpoint",
+             |msg| *msg == Message::Pong { id: id, token: token });
+}
+
+fn idle_session_expired(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    let (id, token) = handshake(&mut peer, &mut link);
+    harness.advance(&mut link, IDLE_TIMEOUT + 1);
+    wait_for(&mut peer,
+             &mut link,
+             "expiry notice",
+             |msg| *msg == Message::Expired { id: id, token: token });
+    assert_eq!(peer.state, State::Expired(id, token));
+
+    // An expired session can be resumed for a while, under a new token.
+    let (resumed, fresh) = handshake(&mut peer, &mut link);
+    assert_eq!(resumed, id);
+    assert!(fresh != token);
+}
+
+/// A session that keeps talking still ends once it reaches its lifetime.
+fn session_lifetime_expired(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    let (id, token) = handshake(&mut peer, &mut link);
+    let step = IDLE_TIMEOUT / 2;
+    let mut elapsed = 0;
+    while elapsed + step < SESSION_LIFETIME {
+        harness.advance(&mut link, step);
+        elapsed += step;
+        link.send(&peer.ping());
+        wait_for(&mut peer,
+                 &mut link,
+                 "pong before the lifetime",
+                 |msg| *msg == Message::Pong { id: id, token: token });
+    }
+    harness.advance(&mut link, step);
+    wait_for(&mut peer,
+             &mut link,
+             "expiry notice",
+             |msg| *msg == Message::Expired { id: id, token: token });
+    assert_eq!(peer.state, State::Expired(id, token));
+}
+
+/// The token of a live session travels in every frame, so a handshake that
+/// names it gets a session of its own and the live one carries on.
+fn live_session_kept(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    let (id, token) = handshake(&mut peer, &mut link);
+    let mut other = harness.connect();
+    let mut intruder = TestPeer { state: State::Connected(id, token), ..TestPeer::new(0) };
+    let (taken, _) = handshake(&mut intruder, &mut other);
+    assert!(taken != id);
+    link.send(&peer.ping());
+    wait_for(&mut peer,
+             &mut link,
+             "pong for the live session",
+             |msg| *msg == Message::Pong { id: id, token: token });
+}
+
+fn malformed_ignored(harness: &Harness) {
+    let mut link = harness.connect();
+    let mut peer = TestPeer::new(0);
+    link.send_raw(&[0xff; 16]);
+    link.send_raw(&[]);
+    expect_silence(&mut link);
+    // Messages only a server may send are dropped as well.
+    link.send(&Message::Refused { code: Code::Refused });
+    link.send(&Message::Response {
+        id: 1,
+        token: 1,
+        caps: Capabilities::new(0),
+        roam_key: vec![],
+    });
+    expect_silence(&mut link);
+    handshake(&mut peer, &mut link);
+}
+
+const SCENARIOS: &'static [(&'static str, fn(&Harness))] =
+    &[("probe echoed", probe_echoed),
+      ("ping answered", ping_answered),
+      ("keepalive pinged", keepalive_pinged),
+      ("unknown session expired", unknown_session_expired),
+      ("wrong token rejected", wrong_token_rejected),
+      ("roaming accepted", roaming_accepted),
+      ("roaming rejected", roaming_rejected),
+      ("idle session expired", idle_session_expired),
+      ("session lifetime expired", session_lifetime_expired),
+      ("live session kept", live_session_kept),
+      ("malformed ignored", malformed_ignored)];
+
+#[test]
+fn server_conformance_test() {
+    for &(name, scenario) in SCENARIOS {
+        // A thread named after the scenario puts the name in its panic.
+        let run = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || scenario(&Harness::start()))
+            .unwrap();
+        assert!(run.join().is_ok(), "scenario failed: {}", name);
+    }
+}
+
+/// Messages from a scripted server, and the state and replies they must
+/// leave the peer with.
+struct Case {
+    name: &'static str,
+    script: Vec<Message>,
+    state: State,
+    replies: Vec<Message>,
+}
+
+fn response(id: Id, token: Token) -> Message {
+    Message::Response {
+        id: id,
+        token: token,
+        caps: Capabilities::new(0),
+        roam_key: vec![],
+    }
+}
+
+fn peer_cases() -> Vec<Case> {
+    vec![Case {
+             name: "handshake",
+             script: vec![response(3, 7)],
+             state: State::Connected(3, 7),
+             replies: vec![],
+         },
+         Case {
+             name: "handshake with options",
+             script: vec![Message::Configured {
+                              id: 3,
+                              token: 7,
+                              caps: Capabilities::new(0),
+                              options: vec![],
+                              roam_key: vec![],
+                          }],
+             state: State::Connected(3, 7),
+             replies: vec![],
+         },
+         Case {
+             name: "refused",
+             script: vec![Message::Refused { code: Code::PoolExhausted }],
+             state: State::Refused,
+             replies: vec![],
+         },
+         Case {
+             name: "refusal after the handshake",
+             script: vec![response(3, 7), Message::Refused { code: Code::Refused }],
+             state: State::Connected(3, 7),
+             replies: vec![],
+         },
+         Case {
+             name: "ping",
+             script: vec![response(3, 7), Message::Ping { id: 3, token: 7 }],
+             state: State::Connected(3, 7),
+             replies: vec![Message::Pong { id: 3, token: 7 }],
+         },
+         Case {
+             name: "ping for another session",
+             script: vec![response(3, 7), Message::Ping { id: 3, token: 8 }],
+             state: State::Connected(3, 7),
+             replies: vec![],
+         },
+         Case {
+             name: "expiry",
+             script: vec![response(3, 7), Message::Expired { id: 3, token: 7 }],
+             state: State::Expired(3, 7),
+             replies: vec![],
+         },
+         Case {
+             name: "expiry of another session",
+             script: vec![response(3, 7), Message::Expired { id: 4, token: 7 }],
+             state: State::Connected(3, 7),
+             replies: vec![],
+         },
+         Case {
+             name: "new session",
+             script: vec![response(3, 7), response(3, 9)],
+             state: State::Connected(3, 9),
+             replies: vec![],
+         }]
+}
+
+#[test]
+fn peer_conformance_test() {
+    let timeout = Duration::from_secs(0);
+    for case in peer_cases() {
+        let (mut server, mut client) = Memory::pair();
+        let mut peer = TestPeer::new(0);
+        client.send(&peer.request());
+        assert_eq!(server.recv(timeout), Some(peer.request()), "{}", case.name);
+        for msg in case.script {
+            server.send(&msg);
+        }
+        while let Some(msg) = client.recv(timeout) {
+            if let Some(reply) = peer.receive(msg) {
+                client.send(&reply);
+            }
+        }
+        assert_eq!(peer.state, case.state, "{}", case.name);
+        let mut replies = Vec::new();
+        while let Some(msg) = server.recv(timeout) {
+            replies.push(msg);
+        }
+        assert_eq!(replies, case.replies, "{}", case.name);
+    }
+}
+
+#[test]
+fn malformed_frame_test() {
+    let mut truncated = encode_message(&response(3, 7)).unwrap();
+    truncated.pop();
+    let frames: Vec<(&str, Vec<u8>)> = vec![("empty", vec![]),
+                                            ("unknown variant", vec![0xff; 8]),
+                                            ("truncated", truncated)];
+    for (name, frame) in frames {
+        assert!(decode_message(&frame).is_err(), "{}", name);
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright 2016-2017 Chang Lan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end tests that run a server and a client in two network namespaces
+//! joined by a veth pair. They need root, iproute2 and a built `kytan`
+//! binary, so they are ignored by default:
+//!
+//! ```text
+//! $ cargo build && sudo cargo test --test netns -- --ignored
+//! ```
+
+#![cfg(target_os = "linux")]
+
+extern crate libc;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+const SERVER_ADDR: &'static str = "172.31.0.1";
+const CLIENT_ADDR: &'static str = "172.31.0.2";
+const TUNNEL_SERVER_ADDR: &'static str = "10.10.10.1";
+const PORT: &'static str = "9527";
+
+fn run(args: &[&str]) {
+    let status = Command::new(args[0]).args(&args[1..]).status().unwrap();
+    assert!(status.success(), "`{}` failed: {}", args.join(" "), status);
+}
+
+fn kytan_bin() -> PathBuf {
+    // target/debug/deps/netns-<hash> -> target/debug/kytan
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.push("kytan");
+    assert!(path.exists(),
+            "{} not found; run `cargo build` first",
+            path.display());
+    path
+}
+
+struct Netns {
+    name: String,
+}
+
+impl Netns {
+    fn new(name: &str) -> Netns {
+        // Leftovers from an aborted run would make `ip netns add` fail.
+        let _ = Command::new("ip").args(&["netns", "del", name]).status();
+        run(&["ip", "netns", "add", name]);
+        let ns = Netns { name: String::from(name) };
+        ns.exec(&["ip", "link", "set", "lo", "up"]);
+        ns
+    }
+
+    fn exec(&self, args: &[&str]) {
+        let mut full = vec!["ip", "netns", "exec", &self.name];
+        full.extend_from_slice(args);
+        run(&full);
+    }
+
+    fn spawn(&self, args: &[&str]) -> Daemon {
+        let child = Command::new("ip")
+            .args(&["netns", "exec", &self.name])
+            .args(args)
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        Daemon(child)
+    }
+
+    /// Runs `f` on a thread that has joined this namespace.
+    fn enter<F, T>(&self, f: F) -> thread::JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let path = format!("/var/run/netns/{}", self.name);
+        thread::spawn(move || {
+            let file = File::open(&path).unwrap();
+            // setns() only moves the calling thread.
+            assert_eq!(unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) },
+                       0);
+            f()
+        })
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        let _ = Command::new("ip").args(&["netns", "del", &self.name]).status();
+    }
+}
+
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        unsafe {
+            libc::kill(self.0.id() as libc::pid_t, libc::SIGTERM);
+        }
+        let _ = self.0.wait();
+    }
+}
+
+/// A server and a client namespace with a running tunnel between them.
+/// Fields are dropped in order: daemons first, then namespaces.
+struct Tunnel {
+    _client: Daemon,
+    _server: Daemon,
+    client_ns: Netns,
+    server_ns: Netns,
+}
+
+impl Tunnel {
+    fn new(prefix: &str) -> Tunnel {
+        let server_ns = Netns::new(&format!("{}-s", prefix));
+        let client_ns = Netns::new(&format!("{}-c", prefix));
+
+        let server_veth = format!("{}-vs", prefix);
+        let client_veth = format!("{}-vc", prefix);
+        run(&["ip", "link", "add", &server_veth, "netns", &server_ns.name, "type", "veth",
+              "peer", "name", &client_veth, "netns", &client_ns.name]);
+        server_ns.exec(&["ip", "addr", "add", &format!("{}/24", SERVER_ADDR), "dev",
+                         &server_veth]);
+        server_ns.exec(&["ip", "link", "set", &server_veth, "up"]);
+        client_ns.exec(&["ip", "addr", "add", &format!("{}/24", CLIENT_ADDR), "dev",
+                         &client_veth]);
+        client_ns.exec(&["ip", "link", "set", &client_veth, "up"]);
+        // Full-tunnel mode replaces the default route, so there has to be one.
+        client_ns.exec(&["ip", "route", "add", "default", "via", SERVER_ADDR]);
+
+        let bin = kytan_bin();
+        let bin = bin.to_str().unwrap();
+        let server = server_ns.spawn(&[bin, "-m", "s", "-p", PORT]);
+        thread::sleep(Duration::from_secs(1));
+        let client = client_ns.spawn(&[bin, "-m", "c", "-p", PORT, "-h", SERVER_ADDR]);
+
+        let tunnel = Tunnel {
+            _client: client,
+            _server: server,
+            client_ns: client_ns,
+            server_ns: server_ns,
+        };
+        tunnel.wait_until_up();
+        tunnel
+    }
+
+    fn ping(&self) -> bool {
+        Command::new("ip")
+            .args(&["netns", "exec", &self.client_ns.name, "ping", "-c", "1", "-W", "1",
+                    TUNNEL_SERVER_ADDR])
+            .stdout(Stdio::null())
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    fn wait_until_up(&self) {
+        for _ in 0..10 {
+            if self.ping() {
+                return;
+            }
+        }
+        panic!("Tunnel did not come up.");
+    }
+}
+
+#[test]
+#[ignore]
+fn netns_ping_test() {
+    let tunnel = Tunnel::new("kyt-ping");
+    for _ in 0..3 {
+        assert!(tunnel.ping());
+    }
+}
+
+#[test]
+#[ignore]
+fn netns_tcp_test() {
+    let tunnel = Tunnel::new("kyt-tcp");
+
+    let addr = format!("{}:5555", TUNNEL_SERVER_ADDR);
+    let listener = {
+        let addr = addr.clone();
+        tunnel.server_ns.enter(move || TcpListener::bind(&addr[..]).unwrap()).join().unwrap()
+    };
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+
+    let payload: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    let sent = payload.clone();
+    tunnel.client_ns
+        .enter(move || {
+            let mut stream = TcpStream::connect(&addr[..]).unwrap();
+            stream.write_all(&sent).unwrap();
+        })
+        .join()
+        .unwrap();
+
+    assert_eq!(server.join().unwrap(), payload);
+}